@@ -0,0 +1,92 @@
+#![no_main]
+
+//
+// Game logic with arbitrary inputs at arbitrary steps, see invariants of the server. Input
+// starts with the game seed, then each pair of bytes is an input and the number of steps
+// after it. Invariants are checked after every input and step, and the replay of the game
+// must reproduce it. Run with cargo fuzz run game_inputs
+//
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/difficulty.rs"]
+mod difficulty;
+// Error of the server, game logic only refuses invalid input
+#[allow(dead_code)]
+mod error {
+    #[derive(Debug)]
+    pub enum Error {
+        InvalidInputError(String),
+    }
+}
+#[allow(dead_code)]
+#[path = "../../src/event_regulator.rs"]
+mod event_regulator;
+#[allow(dead_code)]
+#[path = "../../src/game_rng.rs"]
+mod game_rng;
+#[allow(dead_code)]
+#[path = "../../src/scoring.rs"]
+mod scoring;
+#[allow(dead_code)]
+#[path = "../../src/tetris.rs"]
+mod tetris;
+
+use difficulty::Difficulty;
+use game_rng::RngKind;
+use tetris::{Action, PieceRules, Randomizer, ReplayPlayer, Tetris};
+
+fn action(input: u8, cols: usize) -> Action {
+    match input % 9 {
+        0 => Action::MoveLeft,
+        1 => Action::MoveRight,
+        2 => Action::MoveDown,
+        3 => Action::RotateLeft,
+        4 => Action::RotateRight,
+        5 => Action::Drop,
+        6 => Action::Hold,
+        7 => Action::BottomRefill,
+        _ => Action::Garbage {
+            hole: (input / 9) as usize % cols,
+        },
+    }
+}
+
+fn check(tetris: &Tetris) {
+    if let Err(violation) = tetris.check_invariants() {
+        panic!("{} after step {}", violation, tetris.get_ticks());
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((seed, inputs)) = data.split_first_chunk::<8>() else {
+        return;
+    };
+    let mut tetris = Tetris::new_with_seed(
+        10,
+        20,
+        u64::from_le_bytes(*seed),
+        Randomizer::SevenBag,
+        RngKind::default(),
+        Some(Difficulty::default()),
+    )
+    .with_pieces(PieceRules {
+        hold: true,
+        next_queue: 5,
+    });
+    for input in inputs.chunks_exact(2) {
+        tetris.add_action(action(input[0], tetris.get_cols()));
+        check(&tetris);
+        for _ in 0..input[1] % 16 {
+            tetris.step();
+            check(&tetris);
+        }
+    }
+    let replayed = match ReplayPlayer::new(tetris.get_replay()).check() {
+        Ok(replayed) => replayed,
+        Err((tick, violation)) => panic!("{} after step {} of replay", violation, tick),
+    };
+    assert_eq!(replayed.get_field(), tetris.get_field());
+    assert_eq!(replayed.get_score(), tetris.get_score());
+});
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+//
+// Envelopes of multiplexed event streams. GET /events?channels=<channels> sends events of
+// all subscribed channels as default events with Envelope data, GET /poll returns them
+// as NDJSON lines of PolledEvent. Payload is the data of the channel's own stream event:
+// json of the types in game, or a string
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    // User's versus game, see /sse
+    Game,
+    // Featured game, see /spotlight
+    Spotlight,
+    // New notifications of the user
+    Notifications,
+}
+
+impl Channel {
+    pub fn parse(name: &str) -> Option<Channel> {
+        match name {
+            "game" => Some(Channel::Game),
+            "spotlight" => Some(Channel::Spotlight),
+            "notifications" => Some(Channel::Notifications),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Game => "game",
+            Channel::Spotlight => "spotlight",
+            Channel::Notifications => "notifications",
+        }
+    }
+}
+
+// Event of one channel, "message" for default events of the channel's stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub channel: Channel,
+    pub event: String,
+    pub payload: Value,
+}
+
+// Line of long poll response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolledEvent {
+    // Passed as since to the next poll
+    pub version: u64,
+    #[serde(flatten)]
+    pub envelope: Envelope,
+}
@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+
+//
+// Inputs of games and events of game streams. Input is POST to the action's path with
+// epoch of the stream's "input_epoch" event and sequence number in the query. Game streams
+// send "rules" with PieceRules, "countdown", "latency", "pause", "maintenance", "ack",
+// "correction" and "attack_incoming" events with the types below, game states are sent as
+// default events
+//
+
+// Enum with all possible user actions
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    MoveDown,
+    RotateLeft,
+    RotateRight,
+    Drop,
+    BottomRefill,
+    // Garbage line with empty cell in given column
+    Garbage { hole: usize },
+    // Swap falling piece with the held one, once per piece
+    Hold,
+}
+
+impl Action {
+    // Path of the player's input request, None for actions of the server
+    pub fn path(&self) -> Option<&'static str> {
+        match self {
+            Action::MoveLeft => Some("/left"),
+            Action::MoveRight => Some("/right"),
+            Action::MoveDown => Some("/down"),
+            Action::RotateLeft => Some("/rotate_left"),
+            Action::RotateRight => Some("/rotate_right"),
+            Action::Drop => Some("/drop"),
+            Action::BottomRefill => Some("/bottom_refill"),
+            Action::Hold => Some("/hold"),
+            Action::Garbage { .. } => None,
+        }
+    }
+}
+
+// Longest next queue a ruleset may show
+pub const MAX_NEXT_QUEUE: usize = 6;
+
+// Piece hold availability and number of next pieces shown, part of the mode or room ruleset.
+// Pieces of the queue are taken from the generator ahead, so the length is part of the game
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PieceRules {
+    pub hold: bool,
+    pub next_queue: usize,
+}
+
+impl PieceRules {
+    // Rules of the original game: no hold, one preview
+    pub fn classic() -> PieceRules {
+        PieceRules {
+            hold: false,
+            next_queue: 1,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.next_queue == 0 || self.next_queue > MAX_NEXT_QUEUE {
+            return Err(format!(
+                "Next queue length must be from 1 to {}",
+                MAX_NEXT_QUEUE
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for PieceRules {
+    fn default() -> Self {
+        PieceRules::classic()
+    }
+}
+
+// Synchronized start of the match, sent to players as "countdown" events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Countdown {
+    // Start instant and time of the event on server clock, milliseconds since unix epoch.
+    // Clients schedule the start from the difference, not from the event arrival
+    pub starts_at_ms: u64,
+    pub server_time_ms: u64,
+    // Whole seconds left, 0 when the match has started
+    pub seconds_left: u64,
+}
+
+// Rolling average round trips of the player and opponent, milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyReport {
+    pub rtt_ms: Option<u64>,
+    pub opponent_rtt_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PauseStatus {
+    pub paused: bool,
+    pub pauses_left: u32,
+    pub pause_secs_left: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceStatus {
+    pub message: String,
+    // Announced shutdown time, seconds since unix epoch
+    pub shutdown_at: u64,
+    pub seconds_left: u64,
+}
+
+// Garbage attack waiting to be received by the player, with it's arrival delay. Game
+// streams send it as "attack_incoming" event when it's sent, and pending attacks are part of
+// versus states. Lines clearing before arrival cancel pending attacks, oldest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncomingAttack {
+    // Number of the attack in the match, increasing for each player
+    pub id: u64,
+    pub lines: usize,
+    // Time until the attack is received with the next lock without clear, 0 when it's due
+    pub arrives_in_ms: u64,
+}
+
+// Acknowledgement of accepted input, with the version of the state it's applied to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputAck {
+    pub seq: u64,
+    pub version: u64,
+}
+
+// Correction of rejected input: the player's state at the version the client
+// predicted it at, or the latest one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Correction<S> {
+    pub seq: u64,
+    pub version: u64,
+    pub state: S,
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+//
+// Typed identifiers, so ids of users and matches can't be mixed up. They're (de)serialized
+// as the values they wrap. With "rocket" feature ids are accepted as route parameters and
+// form fields
+//
+
+// Player, assigned on first visit and kept in user_id cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UserId(pub u32);
+
+// Versus match in memory, while it's played. Ids are not reused after match removal
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct MatchId(pub usize);
+
+impl MatchId {
+    pub fn next(&self) -> MatchId {
+        MatchId(self.0 + 1)
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for MatchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for UserId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(UserId)
+    }
+}
+
+impl FromStr for MatchId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(MatchId)
+    }
+}
+
+#[cfg(feature = "rocket")]
+mod rocket_impls {
+    use std::num::ParseIntError;
+
+    use rocket::{
+        form::{self, FromFormField, ValueField},
+        request::FromParam,
+    };
+
+    use super::{MatchId, UserId};
+
+    impl<'a> FromParam<'a> for UserId {
+        type Error = ParseIntError;
+
+        fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+            param.parse()
+        }
+    }
+
+    impl<'a> FromParam<'a> for MatchId {
+        type Error = ParseIntError;
+
+        fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+            param.parse()
+        }
+    }
+
+    impl<'v> FromFormField<'v> for UserId {
+        fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+            u32::from_value(field).map(UserId)
+        }
+    }
+
+    impl<'v> FromFormField<'v> for MatchId {
+        fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+            usize::from_value(field).map(MatchId)
+        }
+    }
+}
@@ -0,0 +1,11 @@
+//
+// Wire types of the gameserver: ids, inputs, json responses and events of game streams.
+// The server serializes exactly these types, so Rust clients and bots depending on this
+// crate can't drift from it. States of games are the server's own types, clients read them
+// as json
+//
+
+pub mod events;
+pub mod game;
+pub mod ids;
+pub mod session;
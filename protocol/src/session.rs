@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+//
+// Responses of session transfer to another device, see /session/transfer
+//
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferCode {
+    pub code: String,
+    // Seconds since unix epoch
+    pub expires: u64,
+    // Url for QR code, opens the claim page
+    pub claim_url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferStatus {
+    pub claimed: bool,
+    pub expires: u64,
+    // Spectator stream of the transferred game, for spectating old device
+    pub watch_url: Option<String>,
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::{self, time::Duration},
+    Data, Request,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    storage::{self, Database},
+};
+
+//
+// Last access time of each user. Times are updated in memory on every request with user
+// cookie and stored periodically and on shutdown, see lifecycle, so after a restart warmup preloads users
+// by their latest activity instead of the order of stored records
+//
+
+const ACCESS_SEGMENT: &str = "user_access";
+const BY_USER_INDEX: &str = "user_access_by_user";
+
+// Interval of storing changed access times
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct UserAccess {
+    pub user: UserId,
+    // Seconds since unix epoch
+    pub last_access: u64,
+}
+
+#[derive(Default)]
+struct AccessInner {
+    times: HashMap<UserId, u64>,
+    // Record ids of stored times and users whose time changed since the last flush
+    ids: HashMap<UserId, PersyId>,
+    changed: HashSet<UserId>,
+}
+
+// Cloned handles refer to the same times
+#[derive(Clone, Default)]
+pub struct AccessTimes(Arc<RwLock<AccessInner>>);
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, ACCESS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+impl AccessTimes {
+    pub fn load(persy: &Persy) -> Result<AccessTimes, Error> {
+        let mut inner = AccessInner::default();
+        for (id, access) in storage::scan::<UserAccess>(persy, ACCESS_SEGMENT)? {
+            inner.times.insert(access.user, access.last_access);
+            inner.ids.insert(access.user, id);
+        }
+        Ok(AccessTimes(Arc::new(RwLock::new(inner))))
+    }
+
+    pub fn touch(&self, user: UserId) {
+        let now = crate::unix_time();
+        if self.0.read().unwrap().times.get(&user) == Some(&now) {
+            return;
+        }
+        let mut inner = self.0.write().unwrap();
+        inner.times.insert(user, now);
+        inner.changed.insert(user);
+    }
+
+    // Most recently active users, latest first
+    pub fn recent(&self, users: usize) -> Vec<UserId> {
+        let inner = self.0.read().unwrap();
+        let mut times = inner.times.iter().collect::<Vec<_>>();
+        times.sort_unstable_by(|a, b| b.1.cmp(a.1));
+        times
+            .into_iter()
+            .take(users)
+            .map(|(user, _)| *user)
+            .collect()
+    }
+
+    // Store times changed since the last flush in one transaction
+    pub fn flush(&self, persy: &Persy) -> Result<usize, Error> {
+        let mut inner = self.0.write().unwrap();
+        if inner.changed.is_empty() {
+            return Ok(0);
+        }
+        let changed = std::mem::take(&mut inner.changed);
+        let mut tx = persy.begin()?;
+        let mut inserted = Vec::new();
+        for user in &changed {
+            let access = UserAccess {
+                user: *user,
+                last_access: inner.times[user],
+            };
+            match inner.ids.get(user) {
+                Some(id) => storage::update_in_tx(&mut tx, ACCESS_SEGMENT, id, &access)?,
+                None => {
+                    let id = storage::insert_in_tx(&mut tx, ACCESS_SEGMENT, &access)?;
+                    tx.put(BY_USER_INDEX, user.0, id)?;
+                    inserted.push((*user, id));
+                }
+            }
+        }
+        tx.prepare()?.commit()?;
+        inner.ids.extend(inserted);
+        Ok(changed.len())
+    }
+}
+
+// Background job storing changed access times
+pub async fn flush_job(db: Database, access: AccessTimes) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (job_db, job_access) = (db.clone(), access.clone());
+        match tokio::task::spawn_blocking(move || job_access.flush(&job_db.read())).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => println!("Storing access times failed: {}", e),
+            Err(e) => println!("Storing access times task failed: {}", e),
+        }
+    }
+}
+
+// Store changed access times, before shutdown
+pub async fn store(db: Database, access: AccessTimes) {
+    match tokio::task::spawn_blocking(move || access.flush(&db.read())).await {
+        Ok(Ok(users)) => println!("Access times of {} users stored", users),
+        Ok(Err(e)) => println!("Storing access times failed: {}", e),
+        Err(e) => println!("Storing access times task failed: {}", e),
+    }
+}
+
+// Updates access time of the request's user
+pub struct AccessFairing;
+
+#[rocket::async_trait]
+impl Fairing for AccessFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "User access times",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(user) = request
+            .cookies()
+            .get("user_id")
+            .and_then(|c| c.value().parse::<UserId>().ok())
+        else {
+            return;
+        };
+        if let Some(access) = request.rocket().state::<AccessTimes>() {
+            access.touch(user);
+        }
+    }
+}
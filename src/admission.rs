@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rocket::{
+    get,
+    http::{CookieJar, Status},
+    post,
+    request::{FromRequest, Outcome},
+    routes,
+    serde::json::Json,
+    Config, Request, Route, State,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    bans, error::Error, ids::UserId, sessions, settings::SettingsWatch, storage::Database,
+    TetrisMatches,
+};
+
+//
+// Admission of new players. User ids are free to create, so creation of new ones is
+// throttled per client network: requests starting games or submitting puzzles without
+// user id are refused with 429 after new_identity_limit ids were created from the network
+// within new_identity_window seconds. With admission_challenge_bits set, a session must
+// solve a proof-of-work challenge before it may start games: client gets a challenge from
+// /challenge, finds a nonce so that SHA-256 of "<challenge>:<nonce>" starts with that many
+// zero bits and posts it to /challenge/<challenge>/<nonce>. The session is admitted
+// then, until then game routes answer 403. Sessions of banned users are not admitted either,
+// see bans
+//
+
+// New user ids per client network and window, unless configured, see settings
+pub const DEFAULT_IDENTITY_LIMIT: usize = 20;
+const DEFAULT_IDENTITY_WINDOW: u64 = 3600;
+// Challenge is to be solved within this time, seconds
+const CHALLENGE_TTL: u64 = 300;
+// Challenges given out and not solved yet, the oldest ones are dropped above it. Each user
+// has one challenge at a time, so one client can't push challenges of others out
+const MAX_CHALLENGES: usize = 10000;
+
+#[derive(Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    // Required leading zero bits of the hash
+    pub bits: u32,
+    // Seconds since unix epoch
+    pub expires: u64,
+}
+
+#[derive(Serialize)]
+pub struct AdmissionStatus {
+    pub required: bool,
+    pub admitted: bool,
+}
+
+struct PendingChallenge {
+    user: UserId,
+    expires: u64,
+}
+
+pub struct Admission {
+    // New user ids limit, see settings
+    settings: SettingsWatch,
+    identity_window: u64,
+    // Challenge difficulty, 0 when challenges are off
+    bits: u32,
+    // Window start and ids created in it by client network
+    created: Mutex<HashMap<String, (u64, usize)>>,
+    challenges: Mutex<HashMap<String, PendingChallenge>>,
+}
+
+// Number of leading zero bits
+fn zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+pub fn is_solution(challenge: &str, nonce: &str, bits: u32) -> bool {
+    zero_bits(&Sha256::digest(
+        format!("{}:{}", challenge, nonce).as_bytes(),
+    )) >= bits
+}
+
+impl Admission {
+    pub fn from_config(settings: SettingsWatch) -> Admission {
+        let figment = Config::figment();
+        Admission {
+            settings,
+            identity_window: figment
+                .extract_inner::<u64>("new_identity_window")
+                .map_or(DEFAULT_IDENTITY_WINDOW, |window| window.max(1)),
+            bits: figment
+                .extract_inner::<u32>("admission_challenge_bits")
+                .map_or(0, |bits| bits.min(32)),
+            created: Mutex::new(HashMap::new()),
+            challenges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn challenge_bits(&self) -> u32 {
+        self.bits
+    }
+
+    // Count new user id of the client network, returns false when it's over the limit
+    fn allow_new_identity(&self, request: &Request<'_>) -> bool {
+        let Some(network) = crate::proxies::client_ip(request).map(sessions::ip_prefix) else {
+            return true;
+        };
+        let now = crate::unix_time();
+        let mut created = self.created.lock().unwrap();
+        created.retain(|_, (start, _)| now < *start + self.identity_window);
+        let (_, count) = created.entry(network.clone()).or_insert((now, 0));
+        if *count >= self.settings.borrow().new_identity_limit {
+            println!("New user ids of {} throttled", network);
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    fn new_challenge(&self, user: UserId) -> Challenge {
+        let now = crate::unix_time();
+        let challenge = format!("{:032x}", rand::random::<u128>());
+        let mut challenges = self.challenges.lock().unwrap();
+        // New challenge replaces the previous one of the user
+        challenges.retain(|_, pending| pending.user != user && now < pending.expires);
+        while challenges.len() >= MAX_CHALLENGES {
+            let Some(oldest) = challenges
+                .iter()
+                .min_by_key(|(_, pending)| pending.expires)
+                .map(|(challenge, _)| challenge.clone())
+            else {
+                break;
+            };
+            challenges.remove(&oldest);
+        }
+        let expires = now + CHALLENGE_TTL;
+        challenges.insert(challenge.clone(), PendingChallenge { user, expires });
+        Challenge {
+            challenge,
+            bits: self.bits,
+            expires,
+        }
+    }
+
+    // Check solution of user's challenge, the challenge is used up either way
+    fn solve(&self, user: UserId, challenge: &str, nonce: &str) -> Result<(), Error> {
+        let pending = self
+            .challenges
+            .lock()
+            .unwrap()
+            .remove(challenge)
+            .filter(|pending| pending.user == user && crate::unix_time() < pending.expires)
+            .ok_or_else(|| Error::NotFoundError("Challenge not found or expired".to_string()))?;
+        if !is_solution(challenge, nonce, self.bits) {
+            return Err(Error::InvalidInputError(format!(
+                "Hash of the solution doesn't start with {} zero bits",
+                self.bits
+            )));
+        }
+        println!("Session of user {} admitted", pending.user);
+        Ok(())
+    }
+}
+
+// Request may create a new user id, see Admission
+pub struct NewIdentity;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NewIdentity {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let has_user = request
+            .cookies()
+            .get_pending("user_id")
+            .is_some_and(|cookie| cookie.value().parse::<UserId>().is_ok());
+        let Some(admission) = request.rocket().state::<Admission>() else {
+            return Outcome::Success(NewIdentity);
+        };
+        if has_user || admission.allow_new_identity(request) {
+            Outcome::Success(NewIdentity)
+        } else {
+            Outcome::Error((Status::TooManyRequests, ()))
+        }
+    }
+}
+
+// Request of admitted session, required to start games and submit puzzles
+pub struct Admitted;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admitted {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let (Some(admission), Some(db)) = (
+            request.rocket().state::<Admission>(),
+            request.rocket().state::<Database>(),
+        ) else {
+            return Outcome::Success(Admitted);
+        };
+        let user = request
+            .cookies()
+            .get_pending("user_id")
+            .and_then(|cookie| cookie.value().parse::<UserId>().ok());
+        if let Some(user) = user {
+            match bans::active_ban(&db.read(), user) {
+                Ok(None) => {}
+                Ok(Some(_)) => return Outcome::Error((Status::Forbidden, ())),
+                Err(e) => {
+                    println!("Ban check failed: {}", e);
+                    return Outcome::Error((Status::InternalServerError, ()));
+                }
+            }
+        }
+        // Admitted session has user id already, new ids are throttled by challenges then
+        if admission.bits == 0 {
+            return request.guard::<NewIdentity>().await.map(|_| Admitted);
+        }
+        match sessions::is_admitted(&db.read(), request.cookies()) {
+            Ok(true) => Outcome::Success(Admitted),
+            Ok(false) => Outcome::Error((Status::Forbidden, ())),
+            Err(e) => {
+                println!("Admission check failed: {}", e);
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+// Whether the user's session has to solve a challenge and whether it's solved
+#[get("/challenge/status")]
+fn status(
+    cookie_jar: &CookieJar,
+    admission: &State<Admission>,
+    db: &State<Database>,
+) -> Result<Json<AdmissionStatus>, Error> {
+    let required = admission.bits > 0;
+    Ok(Json(AdmissionStatus {
+        required,
+        admitted: !required || sessions::is_admitted(&db.read(), cookie_jar)?,
+    }))
+}
+
+// New challenge for the user's session
+#[get("/challenge")]
+fn challenge(
+    _identity: NewIdentity,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    admission: &State<Admission>,
+) -> Result<Json<Challenge>, Error> {
+    if admission.bits == 0 {
+        return Err(Error::NotFoundError(
+            "Challenges are not required".to_string(),
+        ));
+    }
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(admission.new_challenge(user_id)))
+}
+
+// Solution of the challenge, the session is admitted
+#[post("/challenge/<challenge>/<nonce>")]
+fn solve(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    admission: &State<Admission>,
+    db: &State<Database>,
+    challenge: &str,
+    nonce: &str,
+) -> Result<Json<AdmissionStatus>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    admission.solve(user_id, challenge, nonce)?;
+    sessions::set_admitted(&db.read(), cookie_jar)?;
+    Ok(Json(AdmissionStatus {
+        required: true,
+        admitted: true,
+    }))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![status, challenge, solve]
+}
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+
+use rocket::futures::{future, StreamExt};
+use rocket::{
+    figment::value::Dict,
+    get,
+    http::{uri::Origin, ContentType, CookieJar},
+    post,
+    response::stream::{Event, EventStream, TextStream},
+    routes,
+    serde::json::{serde_json, Json},
+    Config, Route, State,
+};
+use rocket_dyn_templates::Template;
+use serde::Serialize;
+
+use crate::{
+    admission::Admitted,
+    cache::ResponseCache,
+    connections::Connections,
+    difficulty::Difficulty,
+    dropped_games::DroppedGames,
+    error::Error,
+    events::ChannelEvent,
+    friends::{self, Friends},
+    game_events::{self, EventsFormat},
+    game_rng::RngKind,
+    garbage_rules::GarbageRulebook,
+    gravity_curves::{self, GravityTuning},
+    handicap::HandicapRules,
+    ids::GameId,
+    input_sequence::{InputSeq, InputSequences, InputTarget},
+    latency::Latency,
+    leaderboard::{self, LeaderboardQuery},
+    maintenance::{Maintenance, MaintenanceRefusal},
+    match_history::{self, MatchSummary, MatchesQuery},
+    metrics::GameMetrics,
+    pagination::Page,
+    quarantine::Quarantine,
+    ratings::{self, Ratings},
+    regions,
+    replays::{self, ReplayVerifier},
+    rng_audit::{self, RngAuditReport},
+    scoring::ScoringRulebook,
+    settings::RuntimeSettings,
+    storage::Database,
+    tetris::{Action, PieceRules},
+    visibility::Pauses,
+    write_queue::WriteQueue,
+    TetrisMatches,
+};
+
+//
+// Arenas: isolated namespaces hosted by one server, e.g. for several communities with
+// own frontends. Each arena has own database file with leaderboard, replays and match
+// history, own versus matchmaking and may override versus rules. Arenas are configured
+// as [default.arenas.<name>] tables, which take the same keys as versus rules of the
+// server: garbage_rules, scoring_rules, afk_timeout, afk_grace, rng, difficulty,
+// versus_countdown, hold, next_queue, shared_pieces and handicap keys.
+// Arenas keep own ratings.
+// Routes of an arena are served under /arena/<name>/, so game client connects to it
+// with "/arena/<name>" url
+//
+
+#[derive(Clone)]
+pub struct Arena {
+    pub name: String,
+    pub db: Database,
+    pub matches: TetrisMatches,
+    pub writes: WriteQueue,
+    // Leaderboard responses, invalidated when results are recorded or verified
+    pub leaderboard: ResponseCache,
+}
+
+#[derive(Serialize)]
+pub struct ArenaInfo {
+    pub name: String,
+    pub garbage_rules: String,
+    pub scoring_rules: String,
+    pub rng: RngKind,
+    pub difficulty: Difficulty,
+    pub pieces: PieceRules,
+    pub shared_pieces: bool,
+    pub handicap: HandicapRules,
+}
+
+#[derive(Clone)]
+pub struct Arenas(BTreeMap<String, Arena>);
+
+// Arena names are used in urls and database file names
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+impl Arenas {
+    // Start arenas configured in "arenas" table. Database of arena is stored next to
+    // the server's one as <db_stem>.<name>.db
+    pub fn start(
+        db_stem: &str,
+        rulebook: &GarbageRulebook,
+        scoring_rulebook: &ScoringRulebook,
+        settings: &RuntimeSettings,
+        metrics: &GameMetrics,
+        quarantine: &Quarantine,
+        dropped: &DroppedGames,
+    ) -> Result<Arenas, Error> {
+        let names = Config::figment()
+            .extract_inner::<Dict>("arenas")
+            .map(|arenas| arenas.into_keys().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut arenas = BTreeMap::new();
+        for name in names {
+            if !valid_name(&name) {
+                return Err(Error::InvalidInputError(format!(
+                    "Invalid arena name {}",
+                    name
+                )));
+            }
+            // Arena keys override server-wide ones
+            let figment =
+                Config::figment().merge(Config::figment().focus(&format!("arenas.{}", name)));
+            let mut rules = crate::versus_rules(&figment, rulebook, scoring_rulebook)?;
+            let db_name = format!("{}.{}.db", db_stem, name);
+            println!(
+                "Arena {}: database {}, garbage rules {}, scoring rules {}, random source {:?}, \
+                 difficulty {:?}",
+                name, db_name, rules.garbage.name, rules.scoring.name, rules.rng, rules.difficulty
+            );
+            let db = Database::open(db_name)?;
+            {
+                let persy = db.read();
+                leaderboard::init(&persy)?;
+                replays::init(&persy)?;
+                match_history::init(&persy)?;
+                rng_audit::init(&persy)?;
+                ratings::init(&persy)?;
+                gravity_curves::init(&persy)?;
+                rules.gravity = GravityTuning::load(&persy)?;
+            }
+            let leaderboard =
+                ResponseCache::new(crate::cache::LEADERBOARD_TTL, settings.subscribe());
+            let verifier = ReplayVerifier::start(db.clone(), leaderboard.clone(), None, None)?;
+            let writes = WriteQueue::start(db.clone(), verifier, metrics.clone());
+            let matches = TetrisMatches::new(
+                rules,
+                Ratings::load(&db.read())?,
+                settings.subscribe(),
+                quarantine.for_arena(&name),
+                dropped.for_arena(&name),
+            );
+            matches.scheduler.start(matches.clone());
+            arenas.insert(
+                name.clone(),
+                Arena {
+                    name,
+                    db,
+                    matches,
+                    writes,
+                    leaderboard,
+                },
+            );
+        }
+        Ok(Arenas(arenas))
+    }
+
+    // Flush queued writes of arenas and wait for their databases, before shutdown
+    pub async fn stop(&self) {
+        for arena in self.0.values() {
+            arena.writes.flush().await;
+            arena.db.drain().await;
+        }
+    }
+
+    // Remove finished matches of arenas periodically
+    pub async fn cleanup_job(self) {
+        future::join_all(
+            self.0
+                .values()
+                .map(|arena| crate::cleanup_job(arena.matches.clone())),
+        )
+        .await;
+    }
+
+    // Running matches of all arenas
+    pub fn running(&self) -> usize {
+        self.0.values().map(|arena| arena.matches.running()).sum()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Arena, Error> {
+        self.0
+            .get(name)
+            .ok_or_else(|| Error::NotFoundError(format!("Arena {} not found", name)))
+    }
+}
+
+// Input commands of game client, the same as of the server's own game routes
+pub fn command_action(command: &str) -> Option<Action> {
+    match command {
+        "down" => Some(Action::MoveDown),
+        "left" => Some(Action::MoveLeft),
+        "right" => Some(Action::MoveRight),
+        "rotate_right" => Some(Action::RotateRight),
+        "rotate_left" => Some(Action::RotateLeft),
+        "drop" => Some(Action::Drop),
+        "bottom_refill" => Some(Action::BottomRefill),
+        "hold" => Some(Action::Hold),
+        _ => None,
+    }
+}
+
+// Configured arenas with their versus rules
+#[get("/arenas")]
+fn list_arenas(arenas: &State<Arenas>) -> Json<Vec<ArenaInfo>> {
+    Json(
+        arenas
+            .0
+            .values()
+            .map(|arena| ArenaInfo {
+                name: arena.name.clone(),
+                garbage_rules: arena.matches.rules.garbage.name.clone(),
+                scoring_rules: arena.matches.rules.scoring.name.clone(),
+                rng: arena.matches.rules.rng,
+                difficulty: arena.matches.rules.difficulty,
+                pieces: arena.matches.rules.pieces,
+                shared_pieces: arena.matches.rules.shared_pieces,
+                handicap: arena.matches.rules.handicap.clone(),
+            })
+            .collect(),
+    )
+}
+
+// Versus game stream of the arena, see /sse
+#[get("/arena/<name>/sse")]
+#[allow(clippy::too_many_arguments)]
+fn arena_sse<'b>(
+    _admitted: Admitted,
+    cookie_jar: &CookieJar,
+    arenas: &'b State<Arenas>,
+    latency: &'b State<Latency>,
+    sequences: &'b State<InputSequences>,
+    maintenance: &'b State<Maintenance>,
+    pauses: &'b State<Pauses>,
+    connections: &State<Connections>,
+    name: &str,
+) -> Result<Result<EventStream![Event + 'b], MaintenanceRefusal>, Error> {
+    let arena = arenas.get(name)?;
+    let user_id = crate::user_id(cookie_jar, &arena.matches);
+    if !arena.matches.has_match(user_id) {
+        if let Err(refusal) = maintenance.check() {
+            return Ok(Err(refusal));
+        }
+    }
+    let epoch = sequences.new_epoch(user_id, InputTarget::Arena);
+    let events = crate::game_stream(
+        user_id,
+        epoch,
+        &arena.matches,
+        &arena.writes,
+        latency,
+        sequences,
+        maintenance,
+        pauses,
+    );
+    let events = connections.track(Some(user_id), format!("arena/{}", name), events);
+    Ok(Ok(EventStream::from(events.map(ChannelEvent::into_event))))
+}
+
+// Input command to user's match in the arena
+#[post("/arena/<name>/<command>?<input..>")]
+fn arena_input(
+    cookie_jar: &CookieJar,
+    arenas: &State<Arenas>,
+    sequences: &State<InputSequences>,
+    name: &str,
+    command: &str,
+    input: InputSeq,
+) -> Result<(), Error> {
+    let arena = arenas.get(name)?;
+    let action = command_action(command)
+        .ok_or_else(|| Error::NotFoundError(format!("Unknown command {}", command)))?;
+    let user_id = crate::user_id(cookie_jar, &arena.matches);
+    let seq = sequences
+        .accept(user_id, &input)
+        .and_then(|(seq, target)| match target {
+            InputTarget::Arena => Ok(seq),
+            _ => Err(Error::InvalidInputError(
+                "Input epoch is not of an arena game".to_string(),
+            )),
+        })
+        .inspect_err(|_| sequences.reject(user_id, &input))?;
+    if let Ok(Some(version)) = arena.matches.add_action(user_id, action) {
+        sequences.ack(user_id, seq, version);
+    }
+    Ok(())
+}
+
+// Leave the arena's matchmaking queue, see /match/cancel
+#[post("/arena/<name>/match/cancel")]
+fn arena_cancel(cookie_jar: &CookieJar, arenas: &State<Arenas>, name: &str) -> Result<(), Error> {
+    let arena = arenas.get(name)?;
+    let user_id = crate::user_id(cookie_jar, &arena.matches);
+    if !arena.matches.cancel(user_id) {
+        return Err(Error::NotFoundError("Not in matchmaking queue".to_string()));
+    }
+    Ok(())
+}
+
+// Answer to ping event of arena's game stream
+#[post("/arena/<name>/pong/<nonce>")]
+fn arena_pong(
+    cookie_jar: &CookieJar,
+    arenas: &State<Arenas>,
+    latency: &State<Latency>,
+    connections: &State<Connections>,
+    name: &str,
+    nonce: u64,
+) -> Result<(), Error> {
+    let arena = arenas.get(name)?;
+    let user_id = crate::user_id(cookie_jar, &arena.matches);
+    let (_, connection) = latency
+        .pong(user_id, nonce)
+        .ok_or_else(|| Error::NotFoundError("Ping not found".to_string()))?;
+    if let Some(connection) = connection {
+        connections.pong(connection);
+    }
+    Ok(())
+}
+
+// Countries and friends of users are kept by the server, see regions and friends
+#[get("/arena/<name>/leaderboard?<query..>")]
+#[allow(clippy::too_many_arguments)]
+fn arena_leaderboard(
+    cookie_jar: &CookieJar,
+    arenas: &State<Arenas>,
+    db: &State<Database>,
+    friends: &State<Friends>,
+    uri: &Origin,
+    name: &str,
+    query: LeaderboardQuery,
+) -> Result<(ContentType, String), Error> {
+    let arena = arenas.get(name)?;
+    let persy = &*arena.db.read();
+    let friends = friends.filter(cookie_jar, query.friends);
+    let page = || {
+        let users = friends::both(
+            regions::users_of(&db.read(), query.country)?,
+            friends.clone(),
+        );
+        Ok(serde_json::to_string(&leaderboard::list_of_users(
+            persy,
+            &query,
+            users.as_ref(),
+        )?)?)
+    };
+    let page = match friends {
+        Some(_) => page()?,
+        None => arena
+            .leaderboard
+            .get_or_insert_with(&uri.to_string(), page)?,
+    };
+    Ok((ContentType::JSON, page))
+}
+
+#[get("/arena/<name>/matches?<query..>")]
+fn arena_matches(
+    cookie_jar: &CookieJar,
+    arenas: &State<Arenas>,
+    friends: &State<Friends>,
+    name: &str,
+    query: MatchesQuery,
+) -> Result<Json<Page<MatchSummary>>, Error> {
+    let arena = arenas.get(name)?;
+    let friends = friends.filter(cookie_jar, query.friends);
+    Ok(Json(match_history::list(
+        &arena.db.read(),
+        &query,
+        friends.as_ref(),
+    )?))
+}
+
+#[get("/arena/<name>/match/<id>")]
+fn arena_match(arenas: &State<Arenas>, name: &str, id: &str) -> Result<Template, Error> {
+    let arena = arenas.get(name)?;
+    match_history::page(&arena.db.read(), id)
+}
+
+#[get("/arena/<name>/game/<id>/events?<format>")]
+fn arena_game_events(
+    arenas: &State<Arenas>,
+    name: &str,
+    id: GameId,
+    format: Option<EventsFormat>,
+) -> Result<(ContentType, TextStream![String]), Error> {
+    let arena = arenas.get(name)?;
+    game_events::export(&arena.db.read(), id, format)
+}
+
+#[get("/arena/<name>/game/<id>/rng_audit")]
+fn arena_rng_audit(
+    arenas: &State<Arenas>,
+    name: &str,
+    id: GameId,
+) -> Result<Json<RngAuditReport>, Error> {
+    let arena = arenas.get(name)?;
+    Ok(Json(rng_audit::report(&arena.db.read(), id)?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        list_arenas,
+        arena_sse,
+        arena_input,
+        arena_cancel,
+        arena_pong,
+        arena_leaderboard,
+        arena_matches,
+        arena_match,
+        arena_game_events,
+        arena_rng_audit
+    ]
+}
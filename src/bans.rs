@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{
+    form::Form, get, http::CookieJar, post, response::Redirect, routes, serde::json::Json,
+    FromForm, Route, State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    roles::{Moderator, Viewer},
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Bans of users and their appeals. Admin bans a user with a reason, for a time or until
+// lifted, in /admin/appeals. Banned users aren't admitted, see admission, so they can't
+// start games or submit puzzles, and read their ban in /account/ban. A banned user may
+// appeal the ban once, with one message. Appeals wait in the moderation queue of
+// /admin/appeals, approval lifts the ban, denial keeps it. Bans are stored with their
+// appeals in "bans" segment, every ban, appeal and decision is appended to the audit log
+// in "ban_audit" segment
+//
+
+const BANS_SEGMENT: &str = "bans";
+const BY_USER_INDEX: &str = "bans_by_user";
+const AUDIT_SEGMENT: &str = "ban_audit";
+
+const MAX_REASON_LEN: usize = 500;
+const MAX_APPEAL_LEN: usize = 2000;
+// Audit entries listed in the admin page, the latest
+const AUDIT_PAGE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BanStatus {
+    Active,
+    // Lifted by approved appeal
+    Lifted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AppealDecision {
+    Approved,
+    Denied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Appeal {
+    pub message: String,
+    // Seconds since unix epoch
+    pub submitted: u64,
+    // None while it's in the queue
+    pub decision: Option<AppealDecision>,
+    pub decided: Option<u64>,
+    // Admin's note to the decision
+    pub note: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub user: UserId,
+    pub reason: String,
+    // Seconds since unix epoch
+    pub created: u64,
+    // None bans until lifted
+    pub expires: Option<u64>,
+    pub status: BanStatus,
+    pub appeal: Option<Appeal>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditAction {
+    Banned,
+    Appealed,
+    Approved,
+    Denied,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    // Id of the ban
+    pub ban: String,
+    pub user: UserId,
+    pub action: AuditAction,
+    // Reason, appeal message or note of the decision
+    pub text: String,
+    // Seconds since unix epoch
+    pub time: u64,
+}
+
+#[derive(Serialize)]
+pub struct BanItem {
+    pub id: String,
+    pub ban: Ban,
+}
+
+#[derive(FromForm)]
+pub struct BanForm {
+    user: UserId,
+    reason: String,
+    // Ban for this time, until lifted otherwise
+    seconds: Option<u64>,
+}
+
+#[derive(FromForm)]
+pub struct AppealForm {
+    message: String,
+}
+
+#[derive(FromForm)]
+pub struct DecisionForm {
+    note: Option<String>,
+}
+
+impl Ban {
+    fn is_active(&self, now: u64) -> bool {
+        self.status == BanStatus::Active && self.expires.is_none_or(|expires| now < expires)
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, BANS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    storage::ensure_segment(persy, AUDIT_SEGMENT)?;
+    Ok(())
+}
+
+fn check_len(text: &str, name: &str, max: usize) -> Result<(), Error> {
+    if text.is_empty() {
+        return Err(Error::InvalidInputError(format!("{} is required", name)));
+    }
+    if text.chars().count() > max {
+        return Err(Error::InvalidInputError(format!(
+            "{} must not be longer than {} characters",
+            name, max
+        )));
+    }
+    Ok(())
+}
+
+fn audit(
+    tx: &mut Transaction,
+    ban: &PersyId,
+    user: UserId,
+    action: AuditAction,
+    text: &str,
+) -> Result<(), Error> {
+    storage::insert_in_tx(
+        tx,
+        AUDIT_SEGMENT,
+        &AuditEntry {
+            ban: ban.to_string(),
+            user,
+            action,
+            text: text.to_string(),
+            time: crate::unix_time(),
+        },
+    )?;
+    println!("Ban {} of user {}: {:?} {}", ban, user, action, text);
+    Ok(())
+}
+
+// The latest ban of the user, lifted and expired ones included
+fn latest_ban(persy: &Persy, user: UserId) -> Result<Option<(PersyId, Ban)>, Error> {
+    let Some(id) = persy.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? else {
+        return Ok(None);
+    };
+    Ok(storage::read(persy, BANS_SEGMENT, &id)?.map(|ban| (id, ban)))
+}
+
+// Rewrite ban references of audit entries after bans got new ids (see compaction)
+pub fn remap_ids(persy: &Persy, ids: &HashMap<PersyId, PersyId>) -> Result<(), Error> {
+    for (id, mut entry) in storage::scan::<AuditEntry>(persy, AUDIT_SEGMENT)? {
+        if let Some(new) = ids.get(&storage::parse_id(&entry.ban)?) {
+            entry.ban = new.to_string();
+            storage::update(persy, AUDIT_SEGMENT, &id, &entry)?;
+        }
+    }
+    Ok(())
+}
+
+// Ban of the user in force now
+pub fn active_ban(persy: &Persy, user: UserId) -> Result<Option<(PersyId, Ban)>, Error> {
+    Ok(latest_ban(persy, user)?.filter(|(_, ban)| ban.is_active(crate::unix_time())))
+}
+
+fn ban(persy: &Persy, form: &BanForm) -> Result<PersyId, Error> {
+    let reason = form.reason.trim();
+    check_len(reason, "Reason", MAX_REASON_LEN)?;
+    if active_ban(persy, form.user)?.is_some() {
+        return Err(Error::InvalidInputError(format!(
+            "User {} is banned already",
+            form.user
+        )));
+    }
+    let created = crate::unix_time();
+    let ban = Ban {
+        user: form.user,
+        reason: reason.to_string(),
+        created,
+        expires: form.seconds.map(|seconds| created + seconds),
+        status: BanStatus::Active,
+        appeal: None,
+    };
+    storage::insert_with(persy, BANS_SEGMENT, &ban, |tx, id| {
+        tx.put(BY_USER_INDEX, ban.user.0, *id)?;
+        audit(tx, id, ban.user, AuditAction::Banned, reason)
+    })
+}
+
+fn appeal(persy: &Persy, user: UserId, message: &str) -> Result<Ban, Error> {
+    let message = message.trim();
+    check_len(message, "Appeal", MAX_APPEAL_LEN)?;
+    let (id, mut ban) = active_ban(persy, user)?
+        .ok_or_else(|| Error::NotFoundError("You are not banned".to_string()))?;
+    let mut tx = persy.begin()?;
+    // Read again in the transaction, so concurrent appeals conflict
+    if let Some(stored) = storage::read_in_tx::<Ban>(&mut tx, BANS_SEGMENT, &id)? {
+        ban = stored;
+    }
+    if ban.appeal.is_some() {
+        return Err(Error::InvalidInputError(
+            "The ban was appealed already".to_string(),
+        ));
+    }
+    ban.appeal = Some(Appeal {
+        message: message.to_string(),
+        submitted: crate::unix_time(),
+        decision: None,
+        decided: None,
+        note: String::new(),
+    });
+    storage::update_in_tx(&mut tx, BANS_SEGMENT, &id, &ban)?;
+    audit(&mut tx, &id, user, AuditAction::Appealed, message)?;
+    tx.prepare()?.commit()?;
+    Ok(ban)
+}
+
+// Decide the appeal waiting in the queue, approval lifts the ban
+fn decide(persy: &Persy, id: &str, decision: AppealDecision, note: &str) -> Result<(), Error> {
+    let id = storage::parse_id(id)?;
+    let mut tx = persy.begin()?;
+    let mut ban = storage::read_in_tx::<Ban>(&mut tx, BANS_SEGMENT, &id)?
+        .ok_or_else(|| Error::NotFoundError("Ban not found".to_string()))?;
+    let appeal = ban
+        .appeal
+        .as_mut()
+        .filter(|appeal| appeal.decision.is_none())
+        .ok_or_else(|| Error::NotFoundError("Appeal not found or decided".to_string()))?;
+    appeal.decision = Some(decision);
+    appeal.decided = Some(crate::unix_time());
+    appeal.note = note.trim().to_string();
+    let action = match decision {
+        AppealDecision::Approved => {
+            ban.status = BanStatus::Lifted;
+            AuditAction::Approved
+        }
+        AppealDecision::Denied => AuditAction::Denied,
+    };
+    storage::update_in_tx(&mut tx, BANS_SEGMENT, &id, &ban)?;
+    audit(&mut tx, &id, ban.user, action, note.trim())?;
+    tx.prepare()?.commit()?;
+    Ok(())
+}
+
+// The user's ban in force, with it's appeal
+#[get("/account/ban")]
+fn account_ban(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<Option<Ban>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(active_ban(&db.read(), user_id)?.map(|(_, ban)| ban)))
+}
+
+// Appeal the user's ban, once per ban
+#[post("/account/ban/appeal", data = "<form>")]
+fn submit_appeal(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    form: Form<AppealForm>,
+) -> Result<Json<Ban>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(appeal(&db.read(), user_id, &form.message)?))
+}
+
+// Appeals queue with bans and the latest audit entries
+#[get("/admin/appeals")]
+fn admin_appeals(_admin: Viewer, db: &State<Database>) -> Result<Template, Error> {
+    let persy = &*db.read();
+    let now = crate::unix_time();
+    let mut bans = storage::scan::<Ban>(persy, BANS_SEGMENT)?;
+    bans.sort_by_key(|(_, ban)| std::cmp::Reverse(ban.created));
+    let item = |(id, ban): (PersyId, Ban)| BanItem {
+        id: id.to_string(),
+        ban,
+    };
+    let (pending, bans) = bans.into_iter().partition::<Vec<_>, _>(|(_, ban)| {
+        ban.appeal
+            .as_ref()
+            .is_some_and(|appeal| appeal.decision.is_none())
+    });
+    let mut pending = pending.into_iter().map(item).collect::<Vec<_>>();
+    // Queue in order of submission
+    pending.sort_by_key(|item| item.ban.appeal.as_ref().map(|appeal| appeal.submitted));
+    let active = bans
+        .into_iter()
+        .filter(|(_, ban)| ban.is_active(now))
+        .map(item)
+        .collect::<Vec<_>>();
+    let mut audit = storage::scan::<AuditEntry>(persy, AUDIT_SEGMENT)?
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect::<Vec<_>>();
+    // Entries of the same second stay in order of insertion
+    audit.reverse();
+    audit.sort_by_key(|entry| std::cmp::Reverse(entry.time));
+    audit.truncate(AUDIT_PAGE);
+    Ok(Template::render(
+        "admin/appeals",
+        context! { pending, active, audit },
+    ))
+}
+
+#[post("/admin/bans", data = "<form>")]
+fn admin_ban(
+    _admin: Moderator,
+    db: &State<Database>,
+    form: Form<BanForm>,
+) -> Result<Redirect, Error> {
+    ban(&db.read(), &form)?;
+    Ok(Redirect::to("/admin/appeals"))
+}
+
+#[post("/admin/appeals/<id>/approve", data = "<form>")]
+fn approve_appeal(
+    _admin: Moderator,
+    db: &State<Database>,
+    id: &str,
+    form: Form<DecisionForm>,
+) -> Result<Redirect, Error> {
+    let note = form.note.as_deref().unwrap_or_default();
+    decide(&db.read(), id, AppealDecision::Approved, note)?;
+    Ok(Redirect::to("/admin/appeals"))
+}
+
+#[post("/admin/appeals/<id>/deny", data = "<form>")]
+fn deny_appeal(
+    _admin: Moderator,
+    db: &State<Database>,
+    id: &str,
+    form: Form<DecisionForm>,
+) -> Result<Redirect, Error> {
+    let note = form.note.as_deref().unwrap_or_default();
+    decide(&db.read(), id, AppealDecision::Denied, note)?;
+    Ok(Redirect::to("/admin/appeals"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        account_ban,
+        submit_appeal,
+        admin_appeals,
+        admin_ban,
+        approve_appeal,
+        deny_appeal
+    ]
+}
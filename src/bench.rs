@@ -0,0 +1,190 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Instant;
+
+use rand::Rng;
+use rocket::tokio::{
+    self,
+    time::{self, Duration},
+};
+
+use crate::{
+    cache::ResponseCache, dropped_games::DroppedGames, error::Error, metrics::GameMetrics,
+    quarantine::Quarantine, ratings::Ratings, recovery, replays::ReplayVerifier, settings,
+    storage::Database, tetris::Action, tetris_pair::VersusRules, write_queue::WriteQueue,
+    TetrisMatches,
+};
+
+//
+// Headless simulation benchmark, run as `gameserver bench [games] [seconds] [ticks]`.
+// Simulated players run the loop of game event streams: every 10 ms they step their versus
+// match with random inputs, and results of finished games are queued to the write queue.
+// Storage is a fresh database in the temporary directory with the recovery journal running.
+// Reports steps per second, latency of steps and how often the matches lock was taken.
+// With ticks players wait for steps by the tick scheduler, as streams do, and tick latency
+// of the scheduler is reported, workers are set by ROCKET_TICK_WORKERS
+//
+
+const DEFAULT_GAMES: usize = 1000;
+const DEFAULT_SECONDS: u64 = 10;
+const STEP_INTERVAL: Duration = Duration::from_millis(10);
+// Chance of an input before each step
+const INPUT_CHANCE: f64 = 0.1;
+
+#[derive(Default)]
+struct Counters {
+    steps: AtomicU64,
+    // Total and maximum time of step calls, microseconds
+    step_micros: AtomicU64,
+    max_step_micros: AtomicU64,
+    // Steps which found the matches lock taken by another task
+    contended: AtomicU64,
+    inputs: AtomicU64,
+    results: AtomicU64,
+}
+
+fn random_action(rng: &mut impl Rng) -> Action {
+    match rng.gen_range(0..7) {
+        0 => Action::MoveLeft,
+        1 => Action::MoveRight,
+        2 => Action::MoveDown,
+        3 => Action::RotateLeft,
+        4 => Action::RotateRight,
+        5 => Action::Hold,
+        _ => Action::Drop,
+    }
+}
+
+async fn player(matches: TetrisMatches, writes: WriteQueue, counters: Arc<Counters>, ticks: bool) {
+    let user_id = matches.get_free_user_id();
+    let mut interval = time::interval(STEP_INTERVAL);
+    loop {
+        if !ticks {
+            interval.tick().await;
+        }
+        if let Some(write) = matches.take_results(user_id) {
+            writes.push(write).await;
+            counters.results.fetch_add(1, Ordering::Relaxed);
+        }
+        if rand::thread_rng().gen_bool(INPUT_CHANCE)
+            && matches
+                .add_action(user_id, random_action(&mut rand::thread_rng()))
+                .is_ok()
+        {
+            counters.inputs.fetch_add(1, Ordering::Relaxed);
+        }
+        if matches.matches.try_write().is_err() {
+            counters.contended.fetch_add(1, Ordering::Relaxed);
+        }
+        let started = Instant::now();
+        if ticks {
+            matches.tick(user_id).await;
+        } else {
+            matches.step(user_id);
+        }
+        let micros = started.elapsed().as_micros() as u64;
+        counters.steps.fetch_add(1, Ordering::Relaxed);
+        counters.step_micros.fetch_add(micros, Ordering::Relaxed);
+        counters
+            .max_step_micros
+            .fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+pub async fn run(args: &[String]) -> Result<(), Error> {
+    let games = args
+        .first()
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_GAMES);
+    let seconds = args
+        .get(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_SECONDS);
+    let ticks = args.get(2).is_some_and(|arg| arg == "ticks");
+    let db_path = std::env::temp_dir().join(format!("gameserver-bench-{}.db", std::process::id()));
+    println!(
+        "Benchmark: {} games for {} s, database {}",
+        games,
+        seconds,
+        db_path.display()
+    );
+    let db = Database::open(&db_path)?;
+    crate::init_storage(&db.read())?;
+    let cache = ResponseCache::new(Duration::from_secs(1), settings::fixed());
+    let verifier = ReplayVerifier::start(db.clone(), cache, None, None)?;
+    let writes = WriteQueue::start(db.clone(), verifier, GameMetrics::new());
+    let rules = VersusRules {
+        countdown: Duration::ZERO,
+        ..VersusRules::default()
+    };
+    let matches = TetrisMatches::new(
+        rules,
+        Ratings::default(),
+        settings::fixed(),
+        Quarantine::new(),
+        DroppedGames::new(),
+    );
+    if ticks {
+        matches.scheduler.start(matches.clone());
+    }
+    let counters = Arc::new(Counters::default());
+    let mut tasks = vec![tokio::spawn(recovery::recovery_job(
+        db.clone(),
+        matches.clone(),
+    ))];
+    for _ in 0..games * 2 {
+        tasks.push(tokio::spawn(player(
+            matches.clone(),
+            writes.clone(),
+            counters.clone(),
+            ticks,
+        )));
+    }
+    let started = Instant::now();
+    time::sleep(Duration::from_secs(seconds)).await;
+    let elapsed = started.elapsed().as_secs_f64();
+    for task in &tasks {
+        task.abort();
+    }
+    writes.flush().await;
+
+    let steps = counters.steps.load(Ordering::Relaxed);
+    println!(
+        "Steps: {} ({:.0} per second)",
+        steps,
+        steps as f64 / elapsed
+    );
+    if ticks {
+        let metrics = matches.scheduler.metrics();
+        println!(
+            "Tick latency: {} us p50, {} us p99, {} us max, {} workers, {} missed ticks",
+            metrics.p50_latency_us,
+            metrics.p99_latency_us,
+            metrics.max_latency_us,
+            metrics.workers,
+            metrics.missed_ticks
+        );
+    } else {
+        println!(
+            "Step latency: {:.1} us average, {} us max",
+            counters.step_micros.load(Ordering::Relaxed) as f64 / steps.max(1) as f64,
+            counters.max_step_micros.load(Ordering::Relaxed)
+        );
+    }
+    println!(
+        "Lock contention: {:.1}% of steps found matches lock taken",
+        counters.contended.load(Ordering::Relaxed) as f64 * 100.0 / steps.max(1) as f64
+    );
+    println!(
+        "Inputs: {}, finished games written: {}",
+        counters.inputs.load(Ordering::Relaxed),
+        counters.results.load(Ordering::Relaxed)
+    );
+    drop(db);
+    if let Err(e) = std::fs::remove_file(&db_path) {
+        println!("Failed to remove benchmark database: {}", e);
+    }
+    Ok(())
+}
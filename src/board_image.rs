@@ -0,0 +1,135 @@
+use persy::Persy;
+use rocket::{get, http::ContentType, routes, Config, Route, State};
+
+use crate::{
+    error::Error,
+    ids::GameId,
+    leaderboard, replays,
+    storage::{self, Database},
+    tetris::{CellType, Tetris},
+};
+
+//
+// Final boards of finished games rendered as images, so shared results get previews in
+// chats and social networks. Board is restored from the game's replay. SVG is drawn as
+// cell rectangles, PNG is rasterized from the same cells. Colors match the match page
+//
+
+// Cell side and gap between cells, pixels
+const CELL_SIZE: usize = 24;
+const CELL_GAP: usize = 1;
+// Background showing between cells
+const GAP_COLOR: [u8; 3] = [0x44, 0x44, 0x44];
+
+fn cell_color(cell: CellType) -> [u8; 3] {
+    match cell {
+        CellType::Empty => [0xee, 0xee, 0xee],
+        CellType::Blasted => [0x88, 0x88, 0x88],
+        CellType::I => [0x00, 0xff, 0xff],
+        CellType::J => [0x00, 0x00, 0xff],
+        CellType::L => [0xff, 0xa5, 0x00],
+        CellType::O => [0xff, 0xff, 0x00],
+        CellType::S => [0x00, 0x80, 0x00],
+        CellType::T => [0x80, 0x00, 0x80],
+        CellType::Z => [0xff, 0x00, 0x00],
+    }
+}
+
+// Final board of leaderboard entry's game, rows from top to bottom
+pub fn final_board(persy: &Persy, id: GameId) -> Result<Vec<Vec<CellType>>, Error> {
+    let entry = leaderboard::read(persy, &id.0)?
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    let replay_id = entry
+        .replay
+        .ok_or_else(|| Error::NotFoundError("Game has no replay".to_string()))?;
+    let replay = replays::read(persy, &storage::parse_id(&replay_id)?)?
+        .ok_or_else(|| Error::NotFoundError("Replay not found".to_string()))?;
+    Ok(Tetris::from_replay(&replay).get_field().clone())
+}
+
+// Image size for board, pixels
+fn image_size(board: &[Vec<CellType>]) -> (usize, usize) {
+    let columns = board.first().map_or(0, |row| row.len());
+    (
+        columns * (CELL_SIZE + CELL_GAP) + CELL_GAP,
+        board.len() * (CELL_SIZE + CELL_GAP) + CELL_GAP,
+    )
+}
+
+pub fn svg(board: &[Vec<CellType>]) -> String {
+    let (width, height) = image_size(board);
+    let hex = |[r, g, b]: [u8; 3]| format!("#{:02x}{:02x}{:02x}", r, g, b);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\
+         <rect width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+        width,
+        height,
+        width,
+        height,
+        width,
+        height,
+        hex(GAP_COLOR)
+    );
+    for (y, row) in board.iter().enumerate() {
+        for (x, cell) in row.iter().enumerate() {
+            svg += &format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                CELL_GAP + x * (CELL_SIZE + CELL_GAP),
+                CELL_GAP + y * (CELL_SIZE + CELL_GAP),
+                CELL_SIZE,
+                CELL_SIZE,
+                hex(cell_color(*cell))
+            );
+        }
+    }
+    svg + "</svg>"
+}
+
+pub fn png(board: &[Vec<CellType>]) -> Result<Vec<u8>, Error> {
+    let (width, height) = image_size(board);
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for py in 0..height {
+        for px in 0..width {
+            // Cell under the pixel, None on gaps
+            let cell = |p: usize| {
+                (p % (CELL_SIZE + CELL_GAP) >= CELL_GAP).then_some(p / (CELL_SIZE + CELL_GAP))
+            };
+            let color = match (cell(px), cell(py)) {
+                (Some(x), Some(y)) => board
+                    .get(y)
+                    .and_then(|row| row.get(x))
+                    .map_or(GAP_COLOR, |cell| cell_color(*cell)),
+                _ => GAP_COLOR,
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(data)
+}
+
+// Absolute url of board image for link previews, relative when public_url isn't configured
+pub fn image_url(id: GameId) -> String {
+    let public_url = Config::figment()
+        .extract_inner::<String>("public_url")
+        .unwrap_or_default();
+    format!("{}/game/{}/board.png", public_url.trim_end_matches('/'), id)
+}
+
+#[get("/game/<id>/board.svg")]
+fn board_svg(db: &State<Database>, id: GameId) -> Result<(ContentType, String), Error> {
+    Ok((ContentType::SVG, svg(&final_board(&db.read(), id)?)))
+}
+
+#[get("/game/<id>/board.png")]
+fn board_png(db: &State<Database>, id: GameId) -> Result<(ContentType, Vec<u8>), Error> {
+    Ok((ContentType::PNG, png(&final_board(&db.read(), id)?)?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![board_svg, board_png]
+}
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    delete,
+    form::Form,
+    get,
+    http::{ContentType, Status},
+    post,
+    request::{FromRequest, Outcome},
+    routes,
+    serde::json::{serde_json, Json},
+    tokio::{
+        task,
+        time::{self, Duration},
+    },
+    Config, FromForm, FromFormField, Request, Route, State,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    arenas,
+    difficulty::Difficulty,
+    error::Error,
+    game_rng::RngKind,
+    quarantine::{self, Quarantine, QuarantinedGame, QuarantinedMode},
+    roles::{Operator, Viewer},
+    storage::{self, Database},
+    tetris::{PieceRules, Randomizer, Tetris},
+    views::{Projection, View},
+};
+
+//
+// Bot API for AI agents. Admin creates bots in /admin/bots, each one gets an API key which
+// is sent in X-Bot-Key header, only hashes of keys are stored. Bots play in the sandbox:
+// solo games which advance only when the bot steps them, so agents train as fast as they
+// can compute. Sandbox games are never recorded, they don't reach leaderboards, replays or
+// ratings of players. Requests of a bot are limited to bot_rate_limit per second, well above
+// input rate limit of players. States are binary by default, see Tetris::get_binary_state,
+// or json as players get them with format=json. Game whose logic panics is quarantined
+// with it's replay. Each game has own lock and games are stepped on blocking threads, so
+// long step requests of one bot hold neither other games nor async workers
+//
+
+const BOTS_SEGMENT: &str = "bots";
+const BY_KEY_INDEX: &str = "bots_by_key_hash";
+
+// Requests per second of each bot unless configured
+const DEFAULT_RATE_LIMIT: usize = 1000;
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+// Sandbox games of one bot at a time
+const MAX_GAMES: usize = 16;
+// Steps of one step request
+const MAX_STEPS: u64 = 10_000;
+// Games not stepped for this time are dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BotAccount {
+    name: String,
+    // Hex SHA-256 of the API key
+    key_hash: String,
+    created: u64,
+    revoked: bool,
+}
+
+#[derive(Serialize)]
+pub struct BotItem {
+    pub id: String,
+    pub name: String,
+    pub created: u64,
+    pub revoked: bool,
+    // Sandbox games in progress
+    pub games: usize,
+}
+
+// New bot with it's API key, the key is shown only once
+#[derive(Serialize)]
+pub struct NewBot {
+    pub id: String,
+    pub name: String,
+    pub key: String,
+}
+
+#[derive(FromForm)]
+pub struct BotForm {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct SandboxGameInfo {
+    pub id: u64,
+    pub seed: u64,
+    pub steps: u64,
+    pub game_over: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromFormField)]
+pub enum StateFormat {
+    #[default]
+    Binary,
+    Json,
+}
+
+struct SandboxGame {
+    seed: u64,
+    tetris: Tetris,
+    last_step: Instant,
+    // Seconds since unix epoch
+    started: u64,
+}
+
+// Request rate of a bot
+struct Rate {
+    period_start: Instant,
+    requests: usize,
+}
+
+// Game of a bot, locked while it's stepped
+#[derive(Clone)]
+struct SandboxEntry {
+    bot: PersyId,
+    game: Arc<Mutex<SandboxGame>>,
+}
+
+// Bot authenticated by API key, within it's rate limit
+pub struct Bot(PersyId);
+
+// Games of bots and their request rates
+#[derive(Clone)]
+pub struct BotSandbox {
+    // Map is locked only to look games up, add and remove them
+    games: Arc<RwLock<HashMap<u64, SandboxEntry>>>,
+    next_id: Arc<AtomicU64>,
+    rates: Arc<Mutex<HashMap<PersyId, Rate>>>,
+    rate_limit: usize,
+    quarantine: Quarantine,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, BOTS_SEGMENT)?;
+    storage::ensure_index::<String, PersyId>(persy, BY_KEY_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn new_key() -> String {
+    format!(
+        "bot_{:032x}{:032x}",
+        rand::random::<u128>(),
+        rand::random::<u128>()
+    )
+}
+
+// Active bot of the key
+fn find_by_key(persy: &Persy, key: &str) -> Result<Option<PersyId>, Error> {
+    let Some(id) = persy.one::<String, PersyId>(BY_KEY_INDEX, &hash(key))? else {
+        return Ok(None);
+    };
+    Ok(storage::read::<BotAccount>(persy, BOTS_SEGMENT, &id)?
+        .filter(|bot| !bot.revoked)
+        .map(|_| id))
+}
+
+impl BotSandbox {
+    pub fn from_config(quarantine: Quarantine) -> BotSandbox {
+        BotSandbox {
+            games: Arc::new(RwLock::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            rates: Arc::new(Mutex::new(HashMap::new())),
+            rate_limit: Config::figment()
+                .extract_inner("bot_rate_limit")
+                .unwrap_or(DEFAULT_RATE_LIMIT),
+            quarantine,
+        }
+    }
+
+    // Count request of the bot, false when it's over the limit
+    fn allow(&self, bot: PersyId) -> bool {
+        let mut rates = self.rates.lock().unwrap();
+        let rate = rates.entry(bot).or_insert_with(|| Rate {
+            period_start: Instant::now(),
+            requests: 0,
+        });
+        if rate.period_start.elapsed() >= RATE_PERIOD {
+            rate.period_start = Instant::now();
+            rate.requests = 0;
+        }
+        rate.requests += 1;
+        rate.requests <= self.rate_limit
+    }
+
+    fn create(&self, bot: PersyId, seed: Option<u64>, pieces: PieceRules) -> Result<u64, Error> {
+        pieces.validate().map_err(Error::InvalidInputError)?;
+        let mut games = self.games.write().unwrap();
+        if games.values().filter(|entry| entry.bot == bot).count() >= MAX_GAMES {
+            return Err(Error::InvalidInputError(format!(
+                "Bot may have at most {} sandbox games",
+                MAX_GAMES
+            )));
+        }
+        let seed = seed.unwrap_or_else(rand::random);
+        let tetris = Tetris::new_with_seed(
+            10,
+            20,
+            seed,
+            Randomizer::default(),
+            RngKind::default(),
+            Some(Difficulty::default()),
+        )
+        .with_pieces(pieces);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        games.insert(
+            id,
+            SandboxEntry {
+                bot,
+                game: Arc::new(Mutex::new(SandboxGame {
+                    seed,
+                    tetris,
+                    last_step: Instant::now(),
+                    started: crate::unix_time(),
+                })),
+            },
+        );
+        Ok(id)
+    }
+
+    fn game(&self, bot: PersyId, id: u64) -> Result<Arc<Mutex<SandboxGame>>, Error> {
+        self.games
+            .read()
+            .unwrap()
+            .get(&id)
+            .filter(|entry| entry.bot == bot)
+            .map(|entry| entry.game.clone())
+            .ok_or_else(|| Error::NotFoundError(format!("Sandbox game {} not found", id)))
+    }
+
+    // Run f on the bot's game on a blocking thread, game is quarantined when f panics
+    async fn with_game<T: Send + 'static>(
+        &self,
+        bot: PersyId,
+        id: u64,
+        f: impl FnOnce(&mut SandboxGame) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let game = self.game(bot, id)?;
+        let sandbox = self.clone();
+        task::spawn_blocking(move || {
+            let mut game = game.lock().unwrap();
+            match quarantine::guard(|| f(&mut game)) {
+                Ok(result) => result,
+                Err(panic) => {
+                    sandbox.games.write().unwrap().remove(&id);
+                    sandbox.quarantine(bot, id, &game, panic);
+                    Err(Error::NotFoundError(format!(
+                        "Sandbox game {} was quarantined",
+                        id
+                    )))
+                }
+            }
+        })
+        .await
+        .map_err(std::io::Error::from)?
+    }
+
+    // Keep replay of the game which panicked, it's inputs are in the replay as games of
+    // bots have no players
+    fn quarantine(&self, bot: PersyId, id: u64, game: &SandboxGame, panic: String) {
+        // Game state may be broken by the panic
+        let replays = quarantine::guard(|| vec![game.tetris.get_replay()]).unwrap_or_default();
+        self.quarantine.add_answered(QuarantinedGame {
+            mode: QuarantinedMode::BotSandbox,
+            arena: None,
+            players: Vec::new(),
+            started: game.started,
+            quarantined: crate::unix_time(),
+            replays,
+            trigger: Vec::new(),
+            state: Some(serde_json::json!({
+                "bot": bot.to_string(),
+                "game": id,
+                "seed": game.seed,
+            })),
+            trigger_move: None,
+            panic,
+        });
+    }
+
+    fn remove(&self, bot: PersyId, id: u64) -> bool {
+        let mut games = self.games.write().unwrap();
+        if games.get(&id).is_some_and(|entry| entry.bot == bot) {
+            games.remove(&id);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Games of the bot, waits for the bot's games being stepped
+    fn list(&self, bot: PersyId) -> Vec<SandboxGameInfo> {
+        let entries = self
+            .games
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.bot == bot)
+            .map(|(id, entry)| (*id, entry.game.clone()))
+            .collect::<Vec<_>>();
+        let mut games = entries
+            .into_iter()
+            .map(|(id, game)| {
+                let game = game.lock().unwrap();
+                SandboxGameInfo {
+                    id,
+                    seed: game.seed,
+                    steps: game.tetris.get_ticks(),
+                    game_over: game.tetris.is_game_over(),
+                }
+            })
+            .collect::<Vec<_>>();
+        games.sort_by_key(|game| game.id);
+        games
+    }
+
+    fn count(&self, bot: PersyId) -> usize {
+        self.games
+            .read()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.bot == bot)
+            .count()
+    }
+
+    // Drop games of a revoked bot
+    fn remove_bot(&self, bot: PersyId) {
+        self.games
+            .write()
+            .unwrap()
+            .retain(|_, entry| entry.bot != bot);
+        self.rates.lock().unwrap().remove(&bot);
+    }
+
+    // Drop games not stepped for IDLE_TIMEOUT periodically
+    pub async fn reaper_job(self) {
+        let mut interval = time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            // Game locked by a step isn't idle
+            self.games.write().unwrap().retain(|_, entry| {
+                entry
+                    .game
+                    .try_lock()
+                    .map_or(true, |game| game.last_step.elapsed() < IDLE_TIMEOUT)
+            });
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Bot {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let (Some(db), Some(sandbox)) = (
+            request.rocket().state::<Database>(),
+            request.rocket().state::<BotSandbox>(),
+        ) else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let Some(key) = request.headers().get_one("X-Bot-Key") else {
+            return Outcome::Error((Status::Unauthorized, ()));
+        };
+        match find_by_key(&db.read(), key) {
+            Ok(Some(bot)) if sandbox.allow(bot) => Outcome::Success(Bot(bot)),
+            Ok(Some(_)) => Outcome::Error((Status::TooManyRequests, ())),
+            Ok(None) => Outcome::Error((Status::Unauthorized, ())),
+            Err(e) => {
+                println!("Bot key check failed: {}", e);
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+fn state_response(tetris: &Tetris, format: Option<StateFormat>) -> (ContentType, Vec<u8>) {
+    match format.unwrap_or_default() {
+        StateFormat::Binary => (ContentType::Binary, tetris.get_binary_state()),
+        StateFormat::Json => (
+            ContentType::JSON,
+            serde_json::to_vec(&tetris.get_game_state().project(View::Player)).unwrap(),
+        ),
+    }
+}
+
+// New sandbox game, with given seed to reproduce games and piece rules
+#[post("/bot/games?<seed>&<hold>&<next_queue>")]
+async fn create_game(
+    bot: Bot,
+    sandbox: &State<BotSandbox>,
+    seed: Option<u64>,
+    hold: Option<bool>,
+    next_queue: Option<usize>,
+) -> Result<Json<SandboxGameInfo>, Error> {
+    let defaults = PieceRules::classic();
+    let pieces = PieceRules {
+        hold: hold.unwrap_or(defaults.hold),
+        next_queue: next_queue.unwrap_or(defaults.next_queue),
+    };
+    let id = sandbox.create(bot.0, seed, pieces)?;
+    sandbox
+        .with_game(bot.0, id, move |game| {
+            Ok(Json(SandboxGameInfo {
+                id,
+                seed: game.seed,
+                steps: 0,
+                game_over: false,
+            }))
+        })
+        .await
+}
+
+#[get("/bot/games")]
+async fn list_games(
+    bot: Bot,
+    sandbox: &State<BotSandbox>,
+) -> Result<Json<Vec<SandboxGameInfo>>, Error> {
+    let sandbox = sandbox.inner().clone();
+    let games = task::spawn_blocking(move || sandbox.list(bot.0))
+        .await
+        .map_err(std::io::Error::from)?;
+    Ok(Json(games))
+}
+
+#[get("/bot/games/<id>?<format>")]
+async fn game_state(
+    bot: Bot,
+    sandbox: &State<BotSandbox>,
+    id: u64,
+    format: Option<StateFormat>,
+) -> Result<(ContentType, Vec<u8>), Error> {
+    sandbox
+        .with_game(bot.0, id, move |game| {
+            Ok(state_response(&game.tetris, format))
+        })
+        .await
+}
+
+// Queue comma separated input commands, the same as of game routes, and advance the game
+// by given number of steps, 1 by default. Returns the state after the last step
+#[post("/bot/games/<id>/step?<actions>&<steps>&<format>")]
+async fn step_game(
+    bot: Bot,
+    sandbox: &State<BotSandbox>,
+    id: u64,
+    actions: Option<&str>,
+    steps: Option<u64>,
+    format: Option<StateFormat>,
+) -> Result<(ContentType, Vec<u8>), Error> {
+    let steps = steps.unwrap_or(1);
+    if steps > MAX_STEPS {
+        return Err(Error::InvalidInputError(format!(
+            "At most {} steps per request",
+            MAX_STEPS
+        )));
+    }
+    let actions = actions
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+        .map(|command| {
+            arenas::command_action(command)
+                .ok_or_else(|| Error::InvalidInputError(format!("Unknown command {}", command)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    sandbox
+        .with_game(bot.0, id, move |game| {
+            for action in actions {
+                game.tetris.add_action(action);
+            }
+            for _ in 0..steps {
+                game.tetris.step();
+            }
+            game.last_step = Instant::now();
+            Ok(state_response(&game.tetris, format))
+        })
+        .await
+}
+
+#[delete("/bot/games/<id>")]
+fn delete_game(bot: Bot, sandbox: &State<BotSandbox>, id: u64) -> Result<(), Error> {
+    if !sandbox.remove(bot.0, id) {
+        return Err(Error::NotFoundError(format!(
+            "Sandbox game {} not found",
+            id
+        )));
+    }
+    Ok(())
+}
+
+#[get("/admin/bots")]
+fn admin_bots(
+    _admin: Viewer,
+    db: &State<Database>,
+    sandbox: &State<BotSandbox>,
+) -> Result<Json<Vec<BotItem>>, Error> {
+    let mut bots = storage::scan::<BotAccount>(&db.read(), BOTS_SEGMENT)?
+        .into_iter()
+        .map(|(id, bot)| BotItem {
+            id: id.to_string(),
+            name: bot.name,
+            created: bot.created,
+            revoked: bot.revoked,
+            games: sandbox.count(id),
+        })
+        .collect::<Vec<_>>();
+    bots.sort_by_key(|bot| bot.created);
+    Ok(Json(bots))
+}
+
+// New bot, it's key is returned only here
+#[post("/admin/bots", data = "<form>")]
+fn admin_create_bot(
+    _admin: Operator,
+    db: &State<Database>,
+    form: Form<BotForm>,
+) -> Result<Json<NewBot>, Error> {
+    let name = form.name.trim().to_string();
+    if name.is_empty() {
+        return Err(Error::InvalidInputError("Bot name is required".to_string()));
+    }
+    let key = new_key();
+    let bot = BotAccount {
+        name: name.clone(),
+        key_hash: hash(&key),
+        created: crate::unix_time(),
+        revoked: false,
+    };
+    let id = storage::insert_with(&db.read(), BOTS_SEGMENT, &bot, |tx, id| {
+        tx.put(BY_KEY_INDEX, bot.key_hash.clone(), *id)?;
+        Ok(())
+    })?;
+    println!("Bot {} created: {}", id, name);
+    Ok(Json(NewBot {
+        id: id.to_string(),
+        name,
+        key,
+    }))
+}
+
+// Revoke bot's key and drop it's games
+#[delete("/admin/bots/<id>")]
+fn admin_revoke_bot(
+    _admin: Operator,
+    db: &State<Database>,
+    sandbox: &State<BotSandbox>,
+    id: &str,
+) -> Result<(), Error> {
+    let persy = &*db.read();
+    let id = storage::parse_id(id)?;
+    let mut bot = storage::read::<BotAccount>(persy, BOTS_SEGMENT, &id)?
+        .ok_or_else(|| Error::NotFoundError("Bot not found".to_string()))?;
+    bot.revoked = true;
+    let mut tx = persy.begin()?;
+    storage::update_in_tx(&mut tx, BOTS_SEGMENT, &id, &bot)?;
+    tx.remove::<String, PersyId>(BY_KEY_INDEX, bot.key_hash.clone(), None)?;
+    tx.prepare()?.commit()?;
+    sandbox.remove_bot(id);
+    println!("Bot {} revoked", id);
+    Ok(())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        create_game,
+        list_games,
+        game_state,
+        step_game,
+        delete_game,
+        admin_bots,
+        admin_create_bot,
+        admin_revoke_bot
+    ]
+}
@@ -0,0 +1,194 @@
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    form::Form, get, http::CookieJar, post, routes, serde::json::serde_json, serde::json::Json,
+    FromForm, Route, State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admission::Admitted,
+    error::Error,
+    game_mode::GameMode,
+    ids::UserId,
+    quotas::Quotas,
+    roles::Viewer,
+    sprint::TetrisSprints,
+    storage::{self, Database},
+    tetris::{Action, Replay},
+    version, TetrisMatches,
+};
+
+//
+// Bug reports of players. POST /game/report captures the user's current game as the server
+// sees it: full state with internals, replays with seeds and all inputs, which reproduce the
+// game, the latest inputs of all players and the server version. The bundle is stored in
+// "bug_reports" segment, the user gets it's id to paste into an issue. Admin views reports
+// in /admin/reports. Reports per user are limited by quota_bug_reports, see quotas
+//
+
+const REPORTS_SEGMENT: &str = "bug_reports";
+const BY_USER_INDEX: &str = "bug_reports_by_user";
+
+// Latest inputs of the game listed in report
+const RECENT_INPUTS: usize = 50;
+const MAX_DESCRIPTION_LEN: usize = 2000;
+
+// Game as captured by the server, given by the game modes
+pub struct GameTrace {
+    pub mode: GameMode,
+    pub players: Vec<UserId>,
+    pub started: u64,
+    // Full state of the reporter's game
+    pub state: serde_json::Value,
+    // In order of the players
+    pub replays: Vec<Replay>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BugReport {
+    pub user: UserId,
+    // Seconds since unix epoch
+    pub created: u64,
+    pub server_version: String,
+    pub description: String,
+    pub mode: GameMode,
+    pub players: Vec<UserId>,
+    pub started: u64,
+    pub state: serde_json::Value,
+    pub replays: Vec<Replay>,
+    // Player, step number and action, the latest last
+    pub recent_inputs: Vec<(UserId, u64, Action)>,
+}
+
+#[derive(Serialize)]
+pub struct BugReportItem {
+    pub id: String,
+    pub user: UserId,
+    pub created: u64,
+    pub server_version: String,
+    pub mode: GameMode,
+    pub description: String,
+}
+
+#[derive(Serialize)]
+pub struct ReportId {
+    pub id: String,
+}
+
+#[derive(FromForm)]
+pub struct ReportForm {
+    description: String,
+}
+
+impl BugReport {
+    fn new(user: UserId, description: String, trace: GameTrace) -> BugReport {
+        let mut recent_inputs = trace
+            .players
+            .iter()
+            .zip(&trace.replays)
+            .flat_map(|(player, replay)| {
+                replay
+                    .inputs
+                    .iter()
+                    .map(|(tick, action)| (*player, *tick, *action))
+            })
+            .collect::<Vec<_>>();
+        recent_inputs.sort_by_key(|(_, tick, _)| *tick);
+        let from = recent_inputs.len().saturating_sub(RECENT_INPUTS);
+        BugReport {
+            user,
+            created: crate::unix_time(),
+            server_version: version::full_version(),
+            description,
+            mode: trace.mode,
+            players: trace.players,
+            started: trace.started,
+            state: trace.state,
+            replays: trace.replays,
+            recent_inputs: recent_inputs.split_off(from),
+        }
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, REPORTS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+pub fn count_of_user(persy: &Persy, user: UserId) -> Result<usize, Error> {
+    Ok(persy.get::<u32, PersyId>(BY_USER_INDEX, &user.0)?.count())
+}
+
+// Reports, the latest first
+fn list(persy: &Persy) -> Result<Vec<BugReportItem>, Error> {
+    let mut reports = storage::scan::<BugReport>(persy, REPORTS_SEGMENT)?
+        .into_iter()
+        .map(|(id, report)| BugReportItem {
+            id: id.to_string(),
+            user: report.user,
+            created: report.created,
+            server_version: report.server_version,
+            mode: report.mode,
+            description: report.description,
+        })
+        .collect::<Vec<_>>();
+    reports.sort_by_key(|item| std::cmp::Reverse(item.created));
+    Ok(reports)
+}
+
+// Capture user's current game, versus match first, then sprint
+#[post("/game/report", data = "<form>")]
+fn report(
+    _admitted: Admitted,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    db: &State<Database>,
+    quotas: &State<Quotas>,
+    form: Option<Form<ReportForm>>,
+) -> Result<Json<ReportId>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let description = form
+        .map(|form| form.description.trim().to_string())
+        .unwrap_or_default();
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(Error::InvalidInputError(format!(
+            "Description must not be longer than {} characters",
+            MAX_DESCRIPTION_LEN
+        )));
+    }
+    let trace = matches
+        .debug_trace(user_id)
+        .or_else(|| sprints.debug_trace(user_id))
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    let persy = &*db.read();
+    quotas.check_bug_reports(persy, user_id)?;
+    let report = BugReport::new(user_id, description, trace);
+    let id = storage::insert_with(persy, REPORTS_SEGMENT, &report, |tx, id| {
+        tx.put(BY_USER_INDEX, report.user.0, *id)?;
+        Ok(())
+    })?;
+    println!("Bug report {} of user {} stored", id, user_id);
+    Ok(Json(ReportId { id: id.to_string() }))
+}
+
+#[get("/admin/reports")]
+fn admin_reports(_admin: Viewer, db: &State<Database>) -> Result<Template, Error> {
+    let reports = list(&db.read())?;
+    Ok(Template::render("admin/reports", context! { reports }))
+}
+
+// Full bundle of the report
+#[get("/admin/reports/<id>")]
+fn admin_report(_admin: Viewer, db: &State<Database>, id: &str) -> Result<Json<BugReport>, Error> {
+    let id = storage::parse_id(id)?;
+    storage::read::<BugReport>(&db.read(), REPORTS_SEGMENT, &id)?
+        .map(Json)
+        .ok_or_else(|| Error::NotFoundError("Report not found".to_string()))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![report, admin_reports, admin_report]
+}
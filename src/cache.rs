@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use rocket::{get, routes, serde::json::Json, Route, State};
+use serde::Serialize;
+
+use crate::{error::Error, roles::Viewer, settings::SettingsWatch};
+
+//
+// In-memory cache of rendered responses for read-mostly endpoints. Entries expire after TTL
+// and can be invalidated explicitly when underlying data changes. Full cache evicts
+// the least recently accessed half of entries
+//
+
+// TTL of leaderboard responses
+pub const LEADERBOARD_TTL: Duration = Duration::from_secs(60);
+
+// Entries count after which expired entries are purged, unless configured, see settings
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+struct CacheEntry {
+    created: Instant,
+    // Milliseconds since cache creation
+    last_access: AtomicU64,
+    value: String,
+}
+
+struct CacheInner {
+    ttl: Duration,
+    // Capacity of the cache
+    settings: SettingsWatch,
+    created: Instant,
+    entries: RwLock<HashMap<String, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// Cloned handles refer to the same cache
+#[derive(Clone)]
+pub struct ResponseCache(Arc<CacheInner>);
+
+#[derive(Serialize)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub ttl_ms: u64,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, settings: SettingsWatch) -> Self {
+        ResponseCache(Arc::new(CacheInner {
+            ttl,
+            settings,
+            created: Instant::now(),
+            entries: RwLock::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }))
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.0.created.elapsed().as_millis() as u64
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entries = self.0.entries.read().unwrap();
+        entries
+            .get(key)
+            .filter(|entry| entry.created.elapsed() < self.0.ttl)
+            .map(|entry| {
+                entry.last_access.store(self.now_ms(), Ordering::Relaxed);
+                entry.value.clone()
+            })
+    }
+
+    pub fn insert(&self, key: String, value: String) {
+        let mut entries = self.0.entries.write().unwrap();
+        let capacity = self.0.settings.borrow().cache_capacity.max(1);
+        if entries.len() >= capacity {
+            let ttl = self.0.ttl;
+            entries.retain(|_, entry| entry.created.elapsed() < ttl);
+            if entries.len() >= capacity {
+                let mut accessed = entries
+                    .values()
+                    .map(|entry| entry.last_access.load(Ordering::Relaxed))
+                    .collect::<Vec<_>>();
+                let (_, median, _) = accessed.select_nth_unstable(entries.len() / 2);
+                let median = *median;
+                entries.retain(|_, entry| entry.last_access.load(Ordering::Relaxed) > median);
+            }
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                created: Instant::now(),
+                last_access: AtomicU64::new(self.now_ms()),
+                value,
+            },
+        );
+    }
+
+    // Return cached value or compute and cache it. Errors are not cached
+    pub fn get_or_insert_with(
+        &self,
+        key: &str,
+        compute: impl FnOnce() -> Result<String, Error>,
+    ) -> Result<String, Error> {
+        if let Some(value) = self.get(key) {
+            self.0.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+        self.0.misses.fetch_add(1, Ordering::Relaxed);
+        let value = compute()?;
+        self.insert(key.to_string(), value.clone());
+        Ok(value)
+    }
+
+    pub fn invalidate(&self) {
+        self.0.entries.write().unwrap().clear();
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            hits: self.0.hits.load(Ordering::Relaxed),
+            misses: self.0.misses.load(Ordering::Relaxed),
+            entries: self.0.entries.read().unwrap().len(),
+            ttl_ms: self.0.ttl.as_millis() as u64,
+        }
+    }
+}
+
+// Caches of public endpoints
+pub struct Caches {
+    // Invalidated when results are recorded or verified
+    pub leaderboard: ResponseCache,
+    // Live games change constantly, so only short TTL is used
+    pub live: ResponseCache,
+}
+
+impl Caches {
+    pub fn new(settings: SettingsWatch) -> Self {
+        Caches {
+            leaderboard: ResponseCache::new(LEADERBOARD_TTL, settings.clone()),
+            live: ResponseCache::new(Duration::from_secs(1), settings),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CachesMetrics {
+    leaderboard: CacheMetrics,
+    live: CacheMetrics,
+}
+
+// Hit/miss counters of response caches
+#[get("/admin/cache")]
+fn admin_cache(_admin: Viewer, caches: &State<Caches>) -> Json<CachesMetrics> {
+    Json(CachesMetrics {
+        leaderboard: caches.leaderboard.metrics(),
+        live: caches.live.metrics(),
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_cache]
+}
@@ -0,0 +1,127 @@
+use rocket::{
+    catch, catchers,
+    fairing::{Fairing, Info, Kind},
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+    Catcher, Request, Response,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+
+//
+// Error pages for requests no route answers: unknown pages, invalid forms, refused and
+// failed requests. Clients preferring json get error body as json, others get error page.
+// Every request gets an id, sent in X-Request-Id header of all responses and
+// shown on error pages, caught errors are logged with it
+//
+
+// Id of the request, assigned on first use
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request
+            .local_cache(|| RequestId(format!("{:016x}", rand::random::<u64>())))
+            .0
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub status: u16,
+    pub reason: &'static str,
+    pub path: String,
+    pub request_id: String,
+}
+
+// Json or html error by the request's Accept header
+pub struct ErrorResponse(Status, ErrorBody);
+
+impl<'r> Responder<'r, 'static> for ErrorResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let ErrorResponse(status, body) = self;
+        let json = request
+            .accept()
+            .is_some_and(|accept| accept.preferred().is_json());
+        let mut response = if json {
+            Json(body).respond_to(request)?
+        } else {
+            Template::render("error", context! { error: body }).respond_to(request)?
+        };
+        response.set_status(status);
+        Ok(response)
+    }
+}
+
+fn caught(status: Status, request: &Request<'_>) -> ErrorResponse {
+    let request_id = RequestId::of(request).to_string();
+    println!(
+        "Request {}: {} {} {}",
+        request_id,
+        request.method(),
+        request.uri(),
+        status
+    );
+    ErrorResponse(
+        status,
+        ErrorBody {
+            status: status.code,
+            reason: status.reason_lossy(),
+            path: request.uri().path().to_string(),
+            request_id,
+        },
+    )
+}
+
+#[catch(403)]
+fn forbidden(request: &Request) -> ErrorResponse {
+    caught(Status::Forbidden, request)
+}
+
+#[catch(404)]
+fn not_found(request: &Request) -> ErrorResponse {
+    caught(Status::NotFound, request)
+}
+
+#[catch(422)]
+fn unprocessable(request: &Request) -> ErrorResponse {
+    caught(Status::UnprocessableEntity, request)
+}
+
+#[catch(429)]
+fn too_many_requests(request: &Request) -> ErrorResponse {
+    caught(Status::TooManyRequests, request)
+}
+
+#[catch(500)]
+fn internal_error(request: &Request) -> ErrorResponse {
+    caught(Status::InternalServerError, request)
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        forbidden,
+        not_found,
+        unprocessable,
+        too_many_requests,
+        internal_error
+    ]
+}
+
+// Sets X-Request-Id header of responses
+pub struct RequestIdHeader;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdHeader {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id header",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_raw_header("X-Request-Id", RequestId::of(request).to_string());
+    }
+}
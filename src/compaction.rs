@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use persy::{IndexType, IndexTypeId, Persy, PersyId, ValueMode};
+use rocket::{
+    post, routes,
+    serde::json::Json,
+    tokio::{self, time::Duration},
+    Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    bans,
+    cache::{Caches, ResponseCache},
+    email_login,
+    error::Error,
+    leaderboard, match_history,
+    roles::Operator,
+    storage::{self, Database},
+};
+
+//
+// Database compaction. Persy reuses freed pages but never shrinks the file, so live records
+// are rewritten into a fresh file which then replaces the old one. Database is locked
+// exclusively while compacting, so all requests using it wait until the swap is done.
+// Records get new ids in the fresh file, indexes and known id references are remapped.
+// Records of older formats are rewritten in the current one, see storage
+//
+
+// Interval of scheduled compaction
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Serialize)]
+pub struct CompactionReport {
+    pub old_size: u64,
+    pub new_size: u64,
+    pub reclaimed: u64,
+    pub records: usize,
+    // Records rewritten from older formats
+    pub recoded: usize,
+    pub duration_ms: u64,
+}
+
+fn compact_path(db: &Database) -> PathBuf {
+    let mut path = db.path().as_os_str().to_owned();
+    path.push(".compact");
+    PathBuf::from(path)
+}
+
+// Copy all segments records in the current format. Returns map of old record ids to new
+// ones and number of records rewritten from older formats
+fn copy_segments(from: &Persy, to: &Persy) -> Result<(HashMap<PersyId, PersyId>, usize), Error> {
+    let mut ids = HashMap::new();
+    let mut recoded = 0;
+    for (segment, _) in from.list_segments()? {
+        let mut tx = to.begin()?;
+        tx.create_segment(&segment)?;
+        for (id, record) in from.scan(&segment)? {
+            let new_id = match storage::recode(&record)? {
+                Some(record) => {
+                    recoded += 1;
+                    tx.insert(&segment, &record)?
+                }
+                None => tx.insert(&segment, &record)?,
+            };
+            ids.insert(id, new_id);
+        }
+        tx.prepare()?.commit()?;
+    }
+    Ok((ids, recoded))
+}
+
+fn copy_index<K: IndexType>(
+    from: &Persy,
+    to: &Persy,
+    index: &str,
+    value_mode: ValueMode,
+    ids: &HashMap<PersyId, PersyId>,
+) -> Result<(), Error> {
+    let mut tx = to.begin()?;
+    tx.create_index::<K, PersyId>(index, value_mode)?;
+    for (key, values) in from.range::<K, PersyId, _>(index, ..)? {
+        // Values referring to missing records are dropped
+        for id in values.filter_map(|id| ids.get(&id)) {
+            tx.put(index, key.clone(), *id)?;
+        }
+    }
+    tx.prepare()?.commit()?;
+    Ok(())
+}
+
+// Copy index with values other than record ids as they are
+fn copy_values_index<K: IndexType, V: IndexType>(
+    from: &Persy,
+    to: &Persy,
+    index: &str,
+    value_mode: ValueMode,
+) -> Result<(), Error> {
+    let mut tx = to.begin()?;
+    tx.create_index::<K, V>(index, value_mode)?;
+    for (key, values) in from.range::<K, V, _>(index, ..)? {
+        for value in values {
+            tx.put(index, key.clone(), value)?;
+        }
+    }
+    tx.prepare()?.commit()?;
+    Ok(())
+}
+
+// Copy all indexes with values pointing to the new record ids
+fn copy_indexes(from: &Persy, to: &Persy, ids: &HashMap<PersyId, PersyId>) -> Result<(), Error> {
+    for (index, info) in from.list_indexes()? {
+        let mode = info.value_mode;
+        match (info.key_type, info.value_type) {
+            (IndexTypeId::U32, IndexTypeId::PersyId) => {
+                copy_index::<u32>(from, to, &index, mode, ids)?
+            }
+            (IndexTypeId::U64, IndexTypeId::PersyId) => {
+                copy_index::<u64>(from, to, &index, mode, ids)?
+            }
+            (IndexTypeId::String, IndexTypeId::PersyId) => {
+                copy_index::<String>(from, to, &index, mode, ids)?
+            }
+            // User ids by key, e.g. users of countries
+            (IndexTypeId::String, IndexTypeId::U32) => {
+                copy_values_index::<String, u32>(from, to, &index, mode)?
+            }
+            _ => {
+                return Err(Error::InvalidInputError(format!(
+                    "Index {} has unsupported key or value type",
+                    index
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+// Copy records and indexes into the fresh database and remap references to record ids.
+// Returns numbers of records and of records rewritten from older formats
+fn copy_database(from: &Persy, to: &Persy) -> Result<(usize, usize), Error> {
+    let (ids, recoded) = copy_segments(from, to)?;
+    copy_indexes(from, to, &ids)?;
+    leaderboard::remap_replays(to, &ids)?;
+    match_history::remap_ids(to, &ids)?;
+    bans::remap_ids(to, &ids)?;
+    Ok((ids.len(), recoded))
+}
+
+// Rewrite database into fresh file and swap it with the current one.
+// On failure the current database stays untouched
+pub fn compact(db: &Database) -> Result<CompactionReport, Error> {
+    let mut persy = db.write();
+    let started = Instant::now();
+    let old_size = fs::metadata(db.path())?.len();
+    let path = compact_path(db);
+    if path.exists() {
+        // Left by interrupted compaction
+        fs::remove_file(&path)?;
+    }
+    // Login tokens are not needed once they're used or expired
+    email_login::remove_expired(&persy)?;
+    Persy::create(&path)?;
+    let compacted = Persy::open(&path, persy::Config::default())?;
+    let (records, recoded) = match copy_database(&persy, &compacted) {
+        Ok(records) => records,
+        Err(e) => {
+            drop(compacted);
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+    };
+    // Rename is atomic, database file is either old or compacted one
+    fs::rename(&path, db.path())?;
+    *persy = compacted;
+    let new_size = fs::metadata(db.path())?.len();
+    Ok(CompactionReport {
+        old_size,
+        new_size,
+        reclaimed: old_size.saturating_sub(new_size),
+        records,
+        recoded,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+// Compact database in blocking task. Cached leaderboard refers to old record ids,
+// so it's invalidated after the swap
+async fn compact_in_background(
+    db: Database,
+    cache: &ResponseCache,
+) -> Result<CompactionReport, Error> {
+    let report = tokio::task::spawn_blocking(move || compact(&db))
+        .await
+        .map_err(|e| Error::IoError(std::io::Error::other(e)))??;
+    cache.invalidate();
+    Ok(report)
+}
+
+// Background job: compact database once per interval
+pub async fn compaction_job(db: Database, cache: ResponseCache) {
+    loop {
+        tokio::time::sleep(COMPACTION_INTERVAL).await;
+        match compact_in_background(db.clone(), &cache).await {
+            Ok(report) => println!("Database compacted: {:?}", report),
+            Err(e) => println!("Database compaction failed: {}", e),
+        }
+    }
+}
+
+// Compact database now and report reclaimed space
+#[post("/admin/compact")]
+async fn admin_compact(
+    _admin: Operator,
+    db: &State<Database>,
+    caches: &State<Caches>,
+) -> Result<Json<CompactionReport>, Error> {
+    let report = compact_in_background(db.inner().clone(), &caches.leaderboard).await?;
+    println!("Database compacted: {:?}", report);
+    Ok(Json(report))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_compact]
+}
+
+#[cfg(test)]
+mod tests {
+    use persy::OpenOptions;
+
+    use super::*;
+    use crate::{
+        bans::{AuditAction, AuditEntry, Ban, BanStatus},
+        game_mode::GameMode,
+        ids::{GameId, UserId},
+        leaderboard::{LeaderboardEntry, Verification},
+        match_history::{MatchPlayer, MatchRecord},
+        replays, rng_audit,
+        rng_audit::RngAudit,
+        tetris::{Action, Replay, Tetris},
+    };
+
+    fn database() -> Persy {
+        let persy = OpenOptions::new().memory().unwrap();
+        crate::init_storage(&persy).unwrap();
+        persy
+    }
+
+    // Game of the user with a few placed pieces
+    fn game(user: UserId) -> (LeaderboardEntry, Replay, RngAudit) {
+        let mut tetris = Tetris::new(10, 20);
+        for _ in 0..5 {
+            tetris.add_action(Action::Drop);
+            tetris.step();
+        }
+        let replay = tetris.get_replay();
+        let audit = RngAudit::of(&replay, tetris.get_piece_chain());
+        let entry = LeaderboardEntry {
+            user,
+            mode: GameMode::Versus,
+            difficulty: None,
+            score: tetris.get_score() as u64,
+            lines: tetris.get_lines() as u64,
+            ticks: tetris.get_ticks(),
+            pieces: 5,
+            finished: 1000 + user.0 as u64,
+            opponent: None,
+            replay: None,
+            verification: Verification::Unverified,
+            rng_audit: None,
+        };
+        (entry, replay, audit)
+    }
+
+    fn player(user: UserId) -> MatchPlayer {
+        MatchPlayer {
+            user,
+            score: 0,
+            lines: 0,
+            attack_sent: 0,
+            garbage_received: 0,
+            forfeited: false,
+            board: Vec::new(),
+            handicap: Default::default(),
+            rating: None,
+            entry: None,
+            replay: None,
+        }
+    }
+
+    // Match of two users stored with their games, returns ids of the games
+    fn record_match(persy: &Persy, users: [UserId; 2]) -> Vec<PersyId> {
+        let record = MatchRecord {
+            started: 900,
+            finished: 1000,
+            ticks: 100,
+            garbage_rules: "classic".to_string(),
+            shared_pieces: false,
+            players: users.iter().map(|user| player(*user)).collect(),
+        };
+        let (games, audits) = users.iter().map(|user| game(*user)).fold(
+            (Vec::new(), Vec::new()),
+            |(mut games, mut audits), (entry, replay, audit)| {
+                games.push((entry, replay));
+                audits.push(audit);
+                (games, audits)
+            },
+        );
+        let mut tx = persy.begin().unwrap();
+        let entries = match_history::record_in_tx(&mut tx, record, games, audits).unwrap();
+        tx.prepare().unwrap().commit().unwrap();
+        entries
+    }
+
+    // Database with a deleted record before the match, so that copied records get other ids
+    fn compacted() -> (Persy, Persy) {
+        let from = database();
+        let (entry, _, _) = game(UserId(9));
+        let deleted = storage::insert(&from, "leaderboard", &entry).unwrap();
+        let mut tx = from.begin().unwrap();
+        tx.delete("leaderboard", &deleted).unwrap();
+        tx.prepare().unwrap().commit().unwrap();
+        record_match(&from, [UserId(1), UserId(2)]);
+        let to = OpenOptions::new().memory().unwrap();
+        copy_database(&from, &to).unwrap();
+        (from, to)
+    }
+
+    #[test]
+    fn copies_all_records() {
+        let (from, to) = compacted();
+        for (segment, _) in from.list_segments().unwrap() {
+            assert_eq!(
+                from.scan(&segment).unwrap().count(),
+                to.scan(&segment).unwrap().count(),
+                "segment {}",
+                segment
+            );
+        }
+    }
+
+    #[test]
+    fn copied_records_get_other_ids() {
+        let (from, to) = compacted();
+        let ids = |persy: &Persy| {
+            storage::scan::<LeaderboardEntry>(persy, "leaderboard")
+                .unwrap()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(ids(&from), ids(&to));
+    }
+
+    #[test]
+    fn remaps_match_references() {
+        let (_, to) = compacted();
+        let (id, record) = storage::scan::<MatchRecord>(&to, "match_history")
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert!(match_history::read(&to, &id).unwrap().is_some());
+        for player in &record.players {
+            let entry = leaderboard::read(&to, &player.entry.unwrap().0)
+                .unwrap()
+                .unwrap();
+            assert_eq!(entry.user, player.user);
+            assert_eq!(entry.replay, player.replay);
+            let replay = storage::parse_id(player.replay.as_ref().unwrap()).unwrap();
+            assert!(replays::read(&to, &replay).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn remaps_replays_and_audits_of_entries() {
+        let (_, to) = compacted();
+        let entries = storage::scan::<LeaderboardEntry>(&to, "leaderboard").unwrap();
+        assert_eq!(entries.len(), 2);
+        for (id, _) in entries {
+            let report = rng_audit::report(&to, GameId(id)).unwrap();
+            assert_eq!(report.matches, Some(true));
+        }
+    }
+
+    #[test]
+    fn remaps_bans_of_audit_entries() {
+        let from = database();
+        let ban = |user: UserId| Ban {
+            user,
+            reason: "Spam".to_string(),
+            created: 1000,
+            expires: None,
+            status: BanStatus::Active,
+            appeal: None,
+        };
+        // Deleted ban before the audited one, so that it's copied with another id
+        let deleted = storage::insert(&from, "bans", &ban(UserId(9))).unwrap();
+        let mut tx = from.begin().unwrap();
+        tx.delete("bans", &deleted).unwrap();
+        tx.prepare().unwrap().commit().unwrap();
+        let id = storage::insert(&from, "bans", &ban(UserId(1))).unwrap();
+        let entry = AuditEntry {
+            ban: id.to_string(),
+            user: UserId(1),
+            action: AuditAction::Banned,
+            text: "Spam".to_string(),
+            time: 1000,
+        };
+        storage::insert(&from, "ban_audit", &entry).unwrap();
+        let to = OpenOptions::new().memory().unwrap();
+        copy_database(&from, &to).unwrap();
+        let (_, entry) = storage::scan::<AuditEntry>(&to, "ban_audit")
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_ne!(entry.ban, id.to_string());
+        let ban = storage::read::<Ban>(&to, "bans", &storage::parse_id(&entry.ban).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(ban.user, UserId(1));
+    }
+
+    #[test]
+    fn indexes_point_to_copied_records() {
+        let (_, to) = compacted();
+        let ids = to
+            .range::<u64, PersyId, _>("leaderboard_by_score", ..)
+            .unwrap()
+            .flat_map(|(_, ids)| ids)
+            .collect::<Vec<_>>();
+        assert_eq!(ids.len(), 2);
+        for id in ids {
+            assert!(leaderboard::read(&to, &id).unwrap().is_some());
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use rocket::{
+    futures::StreamExt,
+    get,
+    http::CookieJar,
+    post,
+    response::stream::EventStream,
+    routes,
+    serde::json::{serde_json, Json},
+    Config, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    admission::Admitted,
+    connections::Connections,
+    error::Error,
+    events::ChannelEvent,
+    games::{GamePlugin, GameType, SessionStatus},
+    matches::PlayerSide,
+    quarantine::{Quarantine, QuarantinedMode},
+    turn_based::{self, Outcome, TurnBasedGame, TurnMatches, TurnState},
+    TetrisMatches,
+};
+
+//
+// Connect four, the reference turn-based game: players drop discs into columns of
+// an upright grid, the first to line up four discs wins. Player A moves first, turn
+// timeout is configured in seconds with connect_four_turn_timeout key
+//
+
+pub const COLUMNS: usize = 7;
+pub const ROWS: usize = 6;
+
+const LINE: usize = 4;
+
+// Row 0 is the top one
+#[derive(Default)]
+pub struct ConnectFour {
+    grid: [[Option<PlayerSide>; COLUMNS]; ROWS],
+    winner: Option<PlayerSide>,
+    last_move: Option<(usize, usize)>,
+}
+
+#[derive(Serialize)]
+pub struct ConnectFourState {
+    // 0 is empty cell, 1 and 2 are discs of players A and B
+    pub grid: Vec<Vec<u8>>,
+    // Row and column of the last dropped disc
+    pub last_move: Option<(usize, usize)>,
+}
+
+pub type ConnectFourMatches = TurnMatches<ConnectFour>;
+
+impl ConnectFour {
+    fn is_full(&self) -> bool {
+        self.grid[0].iter().all(Option::is_some)
+    }
+
+    // Discs of side in line through the cell, counted in direction and opposite one
+    fn line_length(
+        &self,
+        side: PlayerSide,
+        row: usize,
+        column: usize,
+        step: (isize, isize),
+    ) -> usize {
+        let mut length = 1;
+        for direction in [1, -1] {
+            let (mut r, mut c) = (row as isize, column as isize);
+            loop {
+                r += step.0 * direction;
+                c += step.1 * direction;
+                if r < 0 || c < 0 || r >= ROWS as isize || c >= COLUMNS as isize {
+                    break;
+                }
+                if self.grid[r as usize][c as usize] != Some(side) {
+                    break;
+                }
+                length += 1;
+            }
+        }
+        length
+    }
+}
+
+impl TurnBasedGame for ConnectFour {
+    // Column to drop the disc into
+    type Move = usize;
+    type State = ConnectFourState;
+
+    const MODE: QuarantinedMode = QuarantinedMode::ConnectFour;
+
+    fn play(&mut self, side: PlayerSide, column: usize) -> Result<(), String> {
+        if column >= COLUMNS {
+            return Err(format!("Column must be less than {}", COLUMNS));
+        }
+        let row = (0..ROWS)
+            .rev()
+            .find(|row| self.grid[*row][column].is_none())
+            .ok_or_else(|| format!("Column {} is full", column))?;
+        self.grid[row][column] = Some(side);
+        self.last_move = Some((row, column));
+        let won = [(0, 1), (1, 0), (1, 1), (1, -1)]
+            .into_iter()
+            .any(|step| self.line_length(side, row, column, step) >= LINE);
+        if won {
+            self.winner = Some(side);
+        }
+        Ok(())
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        match self.winner {
+            Some(side) => Some(Outcome::Won(side)),
+            None if self.is_full() => Some(Outcome::Draw),
+            None => None,
+        }
+    }
+
+    fn state(&self) -> ConnectFourState {
+        ConnectFourState {
+            grid: self
+                .grid
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|cell| match cell {
+                            None => 0,
+                            Some(PlayerSide::A) => 1,
+                            Some(PlayerSide::B) => 2,
+                        })
+                        .collect()
+                })
+                .collect(),
+            last_move: self.last_move,
+        }
+    }
+}
+
+// Join a connect four match and stream it, see turn_based::turn_stream
+#[get("/connect_four/sse")]
+fn connect_four_sse(
+    _admitted: Admitted,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<ConnectFourMatches>,
+    connections: &State<Connections>,
+) -> EventStream![] {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let events = turn_based::turn_stream(ConnectFourMatches::clone(games), user_id);
+    let events = connections.track(Some(user_id), "connect_four", events);
+    EventStream::from(events.map(ChannelEvent::into_event))
+}
+
+// State of user's connect four match
+#[get("/connect_four/state")]
+fn connect_four_state(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<ConnectFourMatches>,
+) -> Result<Json<TurnState<ConnectFourState>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    games
+        .state(user_id)
+        .map(Json)
+        .ok_or_else(|| Error::NotFoundError("Match not found".to_string()))
+}
+
+// Drop disc into the column on user's turn
+#[post("/connect_four/move/<column>")]
+fn connect_four_move(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<ConnectFourMatches>,
+    column: usize,
+) -> Result<Json<TurnState<ConnectFourState>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(games.play(user_id, column)?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![connect_four_sse, connect_four_state, connect_four_move]
+}
+
+pub fn plugin() -> GamePlugin {
+    GamePlugin {
+        game_type: GameType {
+            id: "connect_four",
+            name: "Connect four",
+            description: "Turn-based, line up four discs before the opponent",
+            entry: "/connect_four/sse",
+        },
+        init: |_| Ok(()),
+        routes,
+        attach: |rocket| {
+            let quarantine = rocket.state::<Quarantine>().cloned().unwrap_or_default();
+            let games =
+                ConnectFourMatches::from_config(&Config::figment(), "connect_four_", quarantine);
+            Ok(rocket.manage(games))
+        },
+        session: |rocket, user_id| {
+            let games = rocket.state::<ConnectFourMatches>()?;
+            SessionStatus::of(games.is_waiting(user_id), games.is_playing(user_id))
+        },
+        state: |rocket, user_id| {
+            let state = rocket.state::<ConnectFourMatches>()?.state(user_id)?;
+            Some(serde_json::to_string(&state).unwrap())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: PlayerSide = PlayerSide::A;
+    const B: PlayerSide = PlayerSide::B;
+
+    // Play columns in turns starting with player A
+    fn played(columns: &[usize]) -> ConnectFour {
+        let mut game = ConnectFour::default();
+        let mut side = A;
+        for column in columns {
+            assert_eq!(game.outcome(), None);
+            game.play(side, *column).unwrap();
+            side = side.opponent();
+        }
+        game
+    }
+
+    #[test]
+    fn horizontal_line_wins() {
+        let game = played(&[0, 0, 1, 1, 2, 2, 3]);
+        assert_eq!(game.outcome(), Some(Outcome::Won(A)));
+        assert_eq!(game.last_move, Some((ROWS - 1, 3)));
+        // Line completed in the middle
+        let game = played(&[6, 0, 5, 0, 3, 1, 4]);
+        assert_eq!(game.outcome(), Some(Outcome::Won(A)));
+    }
+
+    #[test]
+    fn vertical_line_wins() {
+        let game = played(&[6, 0, 1, 0, 1, 0, 2, 0]);
+        assert_eq!(game.outcome(), Some(Outcome::Won(B)));
+    }
+
+    #[test]
+    fn diagonal_lines_win() {
+        // Rising to the right
+        let game = played(&[0, 1, 1, 2, 2, 3, 2, 3, 3, 6, 3]);
+        assert_eq!(game.outcome(), Some(Outcome::Won(A)));
+        // Rising to the left
+        let game = played(&[6, 5, 5, 4, 4, 3, 4, 3, 3, 0, 3]);
+        assert_eq!(game.outcome(), Some(Outcome::Won(A)));
+    }
+
+    #[test]
+    fn three_in_line_doesnt_win() {
+        let game = played(&[0, 6, 1, 6, 2]);
+        assert_eq!(game.outcome(), None);
+        // Line broken by the opponent's disc
+        let game = played(&[0, 2, 1, 6, 3, 6, 4]);
+        assert_eq!(game.outcome(), None);
+    }
+
+    #[test]
+    fn full_and_missing_columns_are_rejected() {
+        let mut game = ConnectFour::default();
+        for i in 0..ROWS {
+            let side = if i % 2 == 0 { A } else { B };
+            game.play(side, 2).unwrap();
+        }
+        assert!(game.play(A, 2).is_err());
+        assert!(game.play(A, COLUMNS).is_err());
+        assert_eq!(game.last_move, Some((0, 2)));
+        assert_eq!(game.outcome(), None);
+    }
+
+    #[test]
+    fn full_grid_without_line_is_draw() {
+        // Pairs of columns alternate and so do rows, no line is longer than two
+        let side = |row: usize, column: usize| {
+            if (column / 2 + row).is_multiple_of(2) {
+                A
+            } else {
+                B
+            }
+        };
+        let mut game = ConnectFour::default();
+        for row in 0..ROWS {
+            for column in 0..COLUMNS {
+                game.grid[row][column] = Some(side(row, column));
+            }
+        }
+        game.grid[0][COLUMNS - 1] = None;
+        assert_eq!(game.outcome(), None);
+        game.play(side(0, COLUMNS - 1), COLUMNS - 1).unwrap();
+        assert_eq!(game.outcome(), Some(Outcome::Draw));
+        let state = game.state();
+        assert!(state.grid.iter().flatten().all(|cell| *cell != 0));
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use rocket::{
+    delete,
+    futures::{pin_mut, Stream, StreamExt},
+    get,
+    response::stream::stream,
+    routes,
+    serde::json::Json,
+    tokio::{self, sync::Notify, time},
+    Config, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    events::ChannelEvent,
+    ids::{MatchId, UserId},
+    latency::Latency,
+    roles::{Operator, Viewer},
+};
+
+//
+// Registry of open event stream connections, for the admin connection map. Stream handlers
+// wrap their streams with Connections::track, the connection is registered while the stream
+// is alive and counts data bytes of sent events. Lag is the time since the stream last handed
+// an event to the connection: stream is polled only when the client reads, so it grows for
+// stalled clients. Disconnected client is noticed on the next write, at the latest with
+// the stream heartbeat. Admin can force-close a connection, it's stream ends then.
+// Spectator streams register the matches they watch, for viewer counts of live games.
+// Connections are reaped when they're stale for stale_connection_secs: the client didn't
+// take the event handed to it, or didn't answer pings sent on the connection (see latency).
+// Reaped connection is dropped from the registry, so it's not counted as a viewer anymore,
+// and is closed, it's stream ends with it's subscriptions when it's polled again. Reaped
+// connections are counted in /admin/metrics
+//
+
+// Connections are stale after this time without client's answer unless configured,
+// 0 turns the reaper off
+const DEFAULT_STALE_SECS: u64 = 60;
+// Interval of looking for stale connections
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+// Open connection, shared by it's stream and the registry
+struct Connection {
+    user: Option<UserId>,
+    channel: String,
+    // Spectated matches
+    watching: Vec<MatchId>,
+    // Seconds since unix epoch
+    connected: u64,
+    bytes_sent: AtomicU64,
+    events_sent: AtomicU64,
+    last_sent: Mutex<Instant>,
+    // Event is handed to the client and not taken yet
+    handed: AtomicBool,
+    // Sent ping the client didn't answer yet, the earliest one
+    unanswered_ping: Mutex<Option<Instant>>,
+    closed: AtomicBool,
+    close: Notify,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub user: Option<UserId>,
+    pub channel: String,
+    pub watching: Vec<MatchId>,
+    pub connected: u64,
+    pub bytes_sent: u64,
+    pub events_sent: u64,
+    // Milliseconds since the last event was sent
+    pub lag_ms: u64,
+    // Rolling average round trip of the user's game streams, when measured
+    pub rtt_ms: Option<u64>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: AtomicU64,
+    connections: RwLock<BTreeMap<u64, Arc<Connection>>>,
+    // None when stale connections are not reaped
+    stale_after: Option<Duration>,
+    reaped: AtomicU64,
+    // Pings of the streams are registered with their connection
+    latency: Latency,
+}
+
+#[derive(Clone, Default)]
+pub struct Connections(Arc<Registry>);
+
+// Removes connection from the registry when it's stream is dropped
+struct Registration {
+    registry: Arc<Registry>,
+    id: u64,
+    connection: Arc<Connection>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.write().unwrap().remove(&self.id);
+    }
+}
+
+impl Connection {
+    fn sent(&self, event: &ChannelEvent) {
+        self.bytes_sent
+            .fetch_add(event.data.len() as u64, Ordering::Relaxed);
+        self.events_sent.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        *self.last_sent.lock().unwrap() = now;
+        if event.event == Some("ping") {
+            self.unanswered_ping.lock().unwrap().get_or_insert(now);
+        }
+    }
+
+    fn is_stale(&self, stale_after: Duration) -> bool {
+        let not_taken = self.handed.load(Ordering::Relaxed)
+            && self.last_sent.lock().unwrap().elapsed() >= stale_after;
+        not_taken
+            || self
+                .unanswered_ping
+                .lock()
+                .unwrap()
+                .is_some_and(|sent| sent.elapsed() >= stale_after)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.close.notify_one();
+    }
+
+    // Resolves when the connection is force-closed
+    async fn closed(&self) {
+        while !self.closed.load(Ordering::Relaxed) {
+            self.close.notified().await;
+        }
+    }
+}
+
+impl Connections {
+    pub fn from_config(latency: Latency) -> Connections {
+        let stale_secs = Config::figment()
+            .extract_inner::<u64>("stale_connection_secs")
+            .unwrap_or(DEFAULT_STALE_SECS);
+        Connections(Arc::new(Registry {
+            stale_after: (stale_secs > 0).then(|| Duration::from_secs(stale_secs)),
+            latency,
+            ..Registry::default()
+        }))
+    }
+
+    fn register(
+        &self,
+        user: Option<UserId>,
+        channel: String,
+        watching: Vec<MatchId>,
+    ) -> Registration {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            user,
+            channel,
+            watching,
+            connected: crate::unix_time(),
+            bytes_sent: AtomicU64::new(0),
+            events_sent: AtomicU64::new(0),
+            last_sent: Mutex::new(Instant::now()),
+            handed: AtomicBool::new(false),
+            unanswered_ping: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            close: Notify::new(),
+        });
+        self.0
+            .connections
+            .write()
+            .unwrap()
+            .insert(id, connection.clone());
+        Registration {
+            registry: self.0.clone(),
+            id,
+            connection,
+        }
+    }
+
+    // Stream registered as connection of the channel until it ends, is dropped with
+    // disconnected client or is force-closed
+    pub fn track<'b>(
+        &self,
+        user: Option<UserId>,
+        channel: impl Into<String>,
+        events: impl Stream<Item = ChannelEvent> + Send + 'b,
+    ) -> impl Stream<Item = ChannelEvent> + Send + 'b {
+        self.track_watching(user, channel, Vec::new(), events)
+    }
+
+    // Same as track, for spectator streams of the matches
+    pub fn track_watching<'b>(
+        &self,
+        user: Option<UserId>,
+        channel: impl Into<String>,
+        watching: Vec<MatchId>,
+        events: impl Stream<Item = ChannelEvent> + Send + 'b,
+    ) -> impl Stream<Item = ChannelEvent> + Send + 'b {
+        let registration = self.register(user, channel.into(), watching);
+        stream! {
+            let connection = &registration.connection;
+            pin_mut!(events);
+            loop {
+                let event = tokio::select! {
+                    event = events.next() => event,
+                    _ = connection.closed() => None,
+                };
+                let Some(event) = event else {
+                    break;
+                };
+                connection.sent(&event);
+                if event.event == Some("ping") {
+                    if let Ok(nonce) = event.data.parse() {
+                        registration.registry.latency.sent_on(nonce, registration.id);
+                    }
+                }
+                connection.handed.store(true, Ordering::Relaxed);
+                yield event;
+                connection.handed.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn list(&self, latency: &Latency) -> Vec<ConnectionInfo> {
+        let connections = self.0.connections.read().unwrap();
+        connections
+            .iter()
+            .map(|(id, connection)| ConnectionInfo {
+                id: *id,
+                user: connection.user,
+                channel: connection.channel.clone(),
+                watching: connection.watching.clone(),
+                connected: connection.connected,
+                bytes_sent: connection.bytes_sent.load(Ordering::Relaxed),
+                events_sent: connection.events_sent.load(Ordering::Relaxed),
+                lag_ms: connection.last_sent.lock().unwrap().elapsed().as_millis() as u64,
+                rtt_ms: connection.user.and_then(|user| latency.average(user)),
+            })
+            .collect()
+    }
+
+    // Number of connections watching each match
+    pub fn viewers(&self) -> HashMap<MatchId, usize> {
+        let mut viewers = HashMap::new();
+        for connection in self.0.connections.read().unwrap().values() {
+            for match_id in &connection.watching {
+                *viewers.entry(*match_id).or_insert(0) += 1;
+            }
+        }
+        viewers
+    }
+
+    // Close connections of the user, returns number of closed ones
+    pub fn close_user(&self, user: UserId) -> usize {
+        let connections = self.0.connections.read().unwrap();
+        let mut closed = 0;
+        for connection in connections.values() {
+            if connection.user == Some(user) {
+                connection.close();
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    // Close all connections, clients reconnect e.g. to the next server process. Returns
+    // number of closed ones
+    pub fn close_all(&self) -> usize {
+        let connections = self.0.connections.read().unwrap();
+        for connection in connections.values() {
+            connection.close();
+        }
+        connections.len()
+    }
+
+    // Close connection, returns false if there is no such connection
+    pub fn close(&self, id: u64) -> bool {
+        let connections = self.0.connections.read().unwrap();
+        let Some(connection) = connections.get(&id) else {
+            return false;
+        };
+        connection.close();
+        true
+    }
+
+    // Pings sent on the connection are answered
+    pub fn pong(&self, id: u64) {
+        if let Some(connection) = self.0.connections.read().unwrap().get(&id) {
+            *connection.unanswered_ping.lock().unwrap() = None;
+        }
+    }
+
+    // Open connections and stale connections reaped since start
+    pub fn counts(&self) -> (usize, u64) {
+        (
+            self.0.connections.read().unwrap().len(),
+            self.0.reaped.load(Ordering::Relaxed),
+        )
+    }
+
+    // Close stale connections and drop them from the registry, returns their number
+    fn reap(&self, stale_after: Duration) -> usize {
+        let mut connections = self.0.connections.write().unwrap();
+        let before = connections.len();
+        connections.retain(|id, connection| {
+            if !connection.is_stale(stale_after) {
+                return true;
+            }
+            println!(
+                "Stale connection {} of {:?} to {} reaped",
+                id, connection.user, connection.channel
+            );
+            connection.close();
+            false
+        });
+        let reaped = before - connections.len();
+        self.0.reaped.fetch_add(reaped as u64, Ordering::Relaxed);
+        reaped
+    }
+
+    // Background job reaping stale connections
+    pub async fn reaper_job(self) {
+        let Some(stale_after) = self.0.stale_after else {
+            return;
+        };
+        let mut interval = time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.reap(stale_after);
+        }
+    }
+}
+
+// Open event stream connections
+#[get("/admin/connections")]
+fn admin_connections(
+    _admin: Viewer,
+    connections: &State<Connections>,
+    latency: &State<Latency>,
+) -> Json<Vec<ConnectionInfo>> {
+    Json(connections.list(latency))
+}
+
+// Force-close connection, client may reconnect
+#[delete("/admin/connections/<id>")]
+fn close_connection(
+    _admin: Operator,
+    connections: &State<Connections>,
+    id: u64,
+) -> Result<(), Error> {
+    if !connections.close(id) {
+        return Err(Error::NotFoundError(format!("Connection {} not found", id)));
+    }
+    println!("Connection {} closed by admin", id);
+    Ok(())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_connections, close_connection]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALE_AFTER: Duration = Duration::from_secs(60);
+
+    fn long_ago() -> Instant {
+        Instant::now() - 2 * STALE_AFTER
+    }
+
+    fn register(connections: &Connections, user: u32) -> Registration {
+        connections.register(Some(UserId(user)), "game".to_string(), Vec::new())
+    }
+
+    #[test]
+    fn idle_connection_is_not_stale() {
+        let connections = Connections::default();
+        let registration = register(&connections, 1);
+        *registration.connection.last_sent.lock().unwrap() = long_ago();
+        assert!(!registration.connection.is_stale(STALE_AFTER));
+        assert_eq!(connections.reap(STALE_AFTER), 0);
+        assert_eq!(connections.counts(), (1, 0));
+    }
+
+    #[test]
+    fn event_not_taken_makes_connection_stale() {
+        let connections = Connections::default();
+        let registration = register(&connections, 1);
+        registration
+            .connection
+            .handed
+            .store(true, Ordering::Relaxed);
+        assert!(!registration.connection.is_stale(STALE_AFTER));
+        *registration.connection.last_sent.lock().unwrap() = long_ago();
+        assert!(registration.connection.is_stale(STALE_AFTER));
+        assert_eq!(connections.reap(STALE_AFTER), 1);
+        assert!(registration.connection.closed.load(Ordering::Relaxed));
+        assert_eq!(connections.counts(), (0, 1));
+    }
+
+    #[test]
+    fn unanswered_ping_makes_connection_stale() {
+        let connections = Connections::default();
+        let registration = register(&connections, 1);
+        registration
+            .connection
+            .sent(&ChannelEvent::named("ping", "0".to_string()));
+        assert!(!registration.connection.is_stale(STALE_AFTER));
+        *registration.connection.unanswered_ping.lock().unwrap() = Some(long_ago());
+        assert!(registration.connection.is_stale(STALE_AFTER));
+        connections.pong(registration.id);
+        assert!(!registration.connection.is_stale(STALE_AFTER));
+    }
+
+    #[test]
+    fn pong_clears_ping_of_its_connection_only() {
+        let latency = Latency::new();
+        let connections = Connections(Arc::new(Registry {
+            latency: latency.clone(),
+            ..Registry::default()
+        }));
+        let answered = register(&connections, 1);
+        let other = register(&connections, 1);
+        let nonce = latency.ping(UserId(1));
+        latency.sent_on(nonce, answered.id);
+        for registration in [&answered, &other] {
+            *registration.connection.unanswered_ping.lock().unwrap() = Some(long_ago());
+        }
+        let (_, connection) = latency.pong(UserId(1), nonce).unwrap();
+        assert_eq!(connection, Some(answered.id));
+        connections.pong(answered.id);
+        assert!(!answered.connection.is_stale(STALE_AFTER));
+        assert!(other.connection.is_stale(STALE_AFTER));
+        assert_eq!(connections.reap(STALE_AFTER), 1);
+        assert_eq!(connections.counts(), (1, 1));
+    }
+}
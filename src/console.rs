@@ -0,0 +1,250 @@
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::{
+        self,
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+    },
+    Config, Orbit, Rocket,
+};
+
+use crate::{
+    connections::Connections, dropped_games::DroppedGames, error::Error, ids::MatchId,
+    lifecycle::Lifecycle, maintenance::Maintenance, metrics::GameMetrics, motd::Motd,
+    settings::RuntimeSettings, spotlight::Spotlight, storage::Database, TetrisMatches,
+};
+
+//
+// Admin console on a local unix socket, for when the web admin is unreachable. It's off
+// unless admin_socket path is configured, the socket is accessible to the server's user
+// only. Commands are lines, e.g. `echo games | nc -U <path>`, each answered with it's
+// output and an empty line, see HELP. They call the same methods as the admin routes, so
+// evicting, announcing and settings behave the same, and are logged. A stale socket
+// file is replaced on start and left in place on shutdown, as the next process of a
+// handover may be listening on it already
+//
+
+const HELP: &str = "\
+games                               matches in memory
+evict <match id>                    remove match from memory
+announce [text]                     set message of the day, clear it without text
+maintenance on [seconds] [message]  turn maintenance mode on
+maintenance off                     turn maintenance mode off
+set <setting> [value]               override runtime setting, clear it without value
+stats                               metrics of /admin/metrics
+quit                                close the console";
+
+// Socket path, None when console is off
+pub struct Console(Option<PathBuf>);
+
+// Handles the commands act on
+#[derive(Clone)]
+struct Services {
+    matches: TetrisMatches,
+    db: Database,
+    motd: Motd,
+    maintenance: Maintenance,
+    settings: RuntimeSettings,
+    metrics: GameMetrics,
+    dropped: DroppedGames,
+    connections: Connections,
+    spotlight: Spotlight,
+}
+
+impl Services {
+    fn from_rocket(rocket: &Rocket<Orbit>) -> Option<Services> {
+        Some(Services {
+            matches: rocket.state::<TetrisMatches>()?.clone(),
+            db: rocket.state::<Database>()?.clone(),
+            motd: rocket.state::<Motd>()?.clone(),
+            maintenance: rocket.state::<Maintenance>()?.clone(),
+            settings: rocket.state::<RuntimeSettings>()?.clone(),
+            metrics: rocket.state::<GameMetrics>()?.clone(),
+            dropped: rocket.state::<DroppedGames>()?.clone(),
+            connections: rocket.state::<Connections>()?.clone(),
+            spotlight: rocket.state::<Spotlight>()?.clone(),
+        })
+    }
+
+    // Output of the command line
+    fn run(&self, line: &str) -> Result<String, Error> {
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match command {
+            "help" => Ok(HELP.to_string()),
+            "games" => {
+                let (stored, pins) = self.matches.snapshot();
+                let mut out = format!(
+                    "{} matches, {} pinned of {}",
+                    stored.len(),
+                    pins.pinned,
+                    pins.limit
+                );
+                for stored in stored {
+                    out.push_str(&format!(
+                        "\n{} {} vs {} {:?} idle {}s{}",
+                        stored.match_id,
+                        stored.players[0],
+                        stored.players[1],
+                        stored.status,
+                        stored.idle_secs,
+                        if stored.pinned { " pinned" } else { "" }
+                    ));
+                }
+                Ok(out)
+            }
+            "evict" => {
+                let match_id = args
+                    .parse::<MatchId>()
+                    .map_err(|_| Error::InvalidInputError(format!("Invalid match id {}", args)))?;
+                self.matches.evict(match_id)?;
+                Ok(format!("Match {} evicted", match_id))
+            }
+            "announce" => {
+                self.motd.announce(&self.db.read(), args, None)?;
+                if args.is_empty() {
+                    Ok("Message of the day cleared".to_string())
+                } else {
+                    Ok("Message of the day set".to_string())
+                }
+            }
+            "maintenance" => {
+                let (switch, args) = args.split_once(' ').unwrap_or((args, ""));
+                match switch {
+                    "on" => {
+                        // Countdown is optional, the rest is the message
+                        let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+                        let (countdown, message) = match first.parse::<u64>() {
+                            Ok(countdown) => (Some(countdown), rest),
+                            Err(_) => (None, args),
+                        };
+                        self.maintenance
+                            .set(true, countdown, Some(message.trim().to_string()));
+                    }
+                    "off" => self.maintenance.set(false, None, None),
+                    _ => {
+                        return Err(Error::InvalidInputError(
+                            "Maintenance is turned on or off".to_string(),
+                        ))
+                    }
+                }
+                Ok(format!("{:?}", self.maintenance.status()))
+            }
+            "set" => {
+                let (name, value) = args.split_once(' ').unwrap_or((args, ""));
+                let settings = self.settings.set_one(&self.db.read(), name, value.trim())?;
+                Ok(format!("{:?}", settings))
+            }
+            "stats" => Ok(self
+                .metrics
+                .export(
+                    self.dropped.count(),
+                    &self.connections,
+                    &self.spotlight.queue_metrics(),
+                    &self.matches.scheduler.metrics(),
+                )
+                .trim_end()
+                .to_string()),
+            _ => Err(Error::InvalidInputError(format!(
+                "Unknown command {}, see help",
+                command
+            ))),
+        }
+    }
+}
+
+impl Console {
+    pub fn from_config() -> Console {
+        Console(
+            Config::figment()
+                .extract_inner::<PathBuf>("admin_socket")
+                .ok(),
+        )
+    }
+
+    fn bind(path: &PathBuf) -> std::io::Result<UnixListener> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+}
+
+// Answer commands of one console connection until it's closed
+async fn serve(services: Services, stream: UnixStream) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "quit" {
+            break;
+        }
+        println!("Admin console: {}", line);
+        let services = services.clone();
+        let out = tokio::task::spawn_blocking(move || services.run(&line))
+            .await
+            .unwrap_or_else(|e| Ok(format!("Command failed: {}", e)))
+            .unwrap_or_else(|e| format!("Error: {}", e));
+        writer.write_all(format!("{}\n\n", out).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl Fairing for Console {
+    fn info(&self) -> Info {
+        Info {
+            name: "Admin console",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let Some(path) = &self.0 else {
+            return;
+        };
+        let (Some(services), Some(lifecycle)) =
+            (Services::from_rocket(rocket), rocket.state::<Lifecycle>())
+        else {
+            println!("Admin console is off, server state is missing");
+            return;
+        };
+        let listener = match Console::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Failed to open admin console at {}: {}", path.display(), e);
+                return;
+            }
+        };
+        println!("Admin console listening at {}", path.display());
+        let accepted = lifecycle.spawn("console", &[], async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let services = services.clone();
+                        // Not a lifecycle job, a connection is served until it's closed
+                        tokio::spawn(async move {
+                            if let Err(e) = serve(services, stream).await {
+                                println!("Admin console connection failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => println!("Admin console failed to accept: {}", e),
+                }
+            }
+        });
+        if let Err(e) = accepted {
+            println!("Admin console is off: {}", e);
+        }
+    }
+}
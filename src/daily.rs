@@ -0,0 +1,22 @@
+use rocket::Config;
+use sha2::{Digest, Sha256};
+
+//
+// Daily seeds: random seed shared by all players of a game's daily variant, derived from
+// the day number and the game name, so every daily board is the same for everyone and
+// changes at midnight UTC. Seeds are salted with daily_seed_salt config key, boards of
+// future days can't be computed without it. Seeds are never sent to clients
+//
+
+// Seed of the game's daily variant, day is number of days since unix epoch
+pub fn seed(game: &str, day: u64) -> u64 {
+    let salt = Config::figment()
+        .extract_inner::<String>("daily_seed_salt")
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(game.as_bytes());
+    hasher.update(day.to_le_bytes());
+    let hash = hasher.finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
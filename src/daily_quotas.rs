@@ -0,0 +1,297 @@
+use std::sync::Mutex;
+
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    request::{FromRequest, Outcome},
+    tokio::{
+        self,
+        time::{self, Duration},
+    },
+    Config, Request, Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    stats::{self, SECONDS_PER_DAY},
+    storage::{self, Database},
+};
+
+//
+// Daily quotas of each user for actions abused by scripts: games started by routes
+// starting single player games and puzzles submitted. Counters are kept in
+// "daily_quota_counters" segment, so restarts don't reset them, and reset at midnight UTC.
+// Routes consume the quota through DailyQuota guard, over quota they're refused with 429.
+// Responses of these routes tell the quota in X-Quota-Name, X-Quota-Limit,
+// X-Quota-Remaining and X-Quota-Reset, seconds until reset, headers. Quotas are
+// daily_quota_games and daily_quota_puzzles, 0 disables the quota. Counters of past days
+// are deleted periodically
+//
+
+const COUNTERS_SEGMENT: &str = "daily_quota_counters";
+const BY_KEY_INDEX: &str = "daily_quota_counters_by_key";
+
+// Quotas unless configured
+const DEFAULT_GAMES: u64 = 500;
+const DEFAULT_PUZZLES: u64 = 10;
+// Interval of deleting counters of past days
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKind {
+    Games,
+    Puzzles,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct QuotaCounter {
+    pub user: UserId,
+    pub kind: QuotaKind,
+    // Days since unix epoch
+    pub day: u64,
+    pub used: u64,
+}
+
+// Quota of the request, sent in response headers
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QuotaStatus {
+    pub kind: QuotaKind,
+    pub limit: u64,
+    pub remaining: u64,
+    // Seconds until the quota resets
+    pub reset: u64,
+}
+
+pub struct DailyQuotas {
+    games: u64,
+    puzzles: u64,
+    // Counters are read and updated by one request at a time
+    lock: Mutex<()>,
+}
+
+// Consumes quotas of the request's route, the status is sent in headers of the response
+pub struct DailyQuota<'r> {
+    quotas: Option<&'r DailyQuotas>,
+    status: &'r Mutex<Option<QuotaStatus>>,
+}
+
+// Sets quota headers of responses which consumed a quota
+pub struct QuotaHeaders;
+
+impl QuotaKind {
+    fn label(&self) -> &'static str {
+        match self {
+            QuotaKind::Games => "games",
+            QuotaKind::Puzzles => "puzzles",
+        }
+    }
+}
+
+// Seconds until midnight UTC
+fn reset_in(now: u64) -> u64 {
+    (now / SECONDS_PER_DAY + 1) * SECONDS_PER_DAY - now
+}
+
+fn counter_key(kind: QuotaKind, user: UserId, day: u64) -> String {
+    format!("{}/{}/{}", kind.label(), user, day)
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, COUNTERS_SEGMENT)?;
+    storage::ensure_index::<String, PersyId>(persy, BY_KEY_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+impl DailyQuotas {
+    pub fn from_config() -> DailyQuotas {
+        let figment = Config::figment();
+        let quota = |key: &str, default: u64| figment.extract_inner::<u64>(key).unwrap_or(default);
+        DailyQuotas {
+            games: quota("daily_quota_games", DEFAULT_GAMES),
+            puzzles: quota("daily_quota_puzzles", DEFAULT_PUZZLES),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn limit(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::Games => self.games,
+            QuotaKind::Puzzles => self.puzzles,
+        }
+    }
+
+    // Count one use of the user's quota in the transaction, refused when none is left
+    fn count(
+        &self,
+        tx: &mut Transaction,
+        user: UserId,
+        kind: QuotaKind,
+        now: u64,
+    ) -> Result<QuotaStatus, Error> {
+        let limit = self.limit(kind);
+        let day = now / SECONDS_PER_DAY;
+        let reset = reset_in(now);
+        if limit == 0 {
+            return Ok(QuotaStatus {
+                kind,
+                limit,
+                remaining: 0,
+                reset,
+            });
+        }
+        let key = counter_key(kind, user, day);
+        let found = match tx.one::<String, PersyId>(BY_KEY_INDEX, &key)? {
+            Some(id) => storage::read_in_tx::<QuotaCounter>(tx, COUNTERS_SEGMENT, &id)?
+                .map(|counter| (id, counter)),
+            None => None,
+        };
+        let used = found.as_ref().map_or(0, |(_, counter)| counter.used);
+        let mut status = QuotaStatus {
+            kind,
+            limit,
+            remaining: limit.saturating_sub(used),
+            reset,
+        };
+        if status.remaining == 0 {
+            return Err(Error::RateLimitError(format!(
+                "Daily quota of {} {} reached",
+                limit,
+                kind.label()
+            )));
+        }
+        let counter = QuotaCounter {
+            user,
+            kind,
+            day,
+            used: used + 1,
+        };
+        match found {
+            Some((id, _)) => storage::update_in_tx(tx, COUNTERS_SEGMENT, &id, &counter)?,
+            None => {
+                let id = storage::insert_in_tx(tx, COUNTERS_SEGMENT, &counter)?;
+                tx.put(BY_KEY_INDEX, key, id)?;
+            }
+        }
+        status.remaining -= 1;
+        Ok(status)
+    }
+}
+
+impl DailyQuota<'_> {
+    // Count one use of the user's quota, the error is sent with quota headers as well
+    pub fn consume(&self, persy: &Persy, user: UserId, kind: QuotaKind) -> Result<(), Error> {
+        self.consume_with(persy, user, kind, |_| Ok(()))
+    }
+
+    // Count one use of the user's quota in the transaction storing what it's used for,
+    // nothing is counted when storing fails
+    pub fn consume_with<R>(
+        &self,
+        persy: &Persy,
+        user: UserId,
+        kind: QuotaKind,
+        store: impl FnOnce(&mut Transaction) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let mut tx = persy.begin()?;
+        let Some(quotas) = self.quotas else {
+            let stored = store(&mut tx)?;
+            tx.prepare()?.commit()?;
+            return Ok(stored);
+        };
+        let _lock = quotas.lock.lock().unwrap();
+        let now = crate::unix_time();
+        let status = match quotas.count(&mut tx, user, kind, now) {
+            Ok(status) => status,
+            Err(e) => {
+                if matches!(e, Error::RateLimitError(_)) {
+                    *self.status.lock().unwrap() = Some(QuotaStatus {
+                        kind,
+                        limit: quotas.limit(kind),
+                        remaining: 0,
+                        reset: reset_in(now),
+                    });
+                }
+                return Err(e);
+            }
+        };
+        let stored = store(&mut tx)?;
+        tx.prepare()?.commit()?;
+        if status.limit != 0 {
+            *self.status.lock().unwrap() = Some(status);
+        }
+        Ok(stored)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DailyQuota<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(DailyQuota {
+            quotas: request.rocket().state::<DailyQuotas>(),
+            status: request.local_cache(|| Mutex::new(None)),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for QuotaHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Daily quota headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let status = *request
+            .local_cache(|| Mutex::<Option<QuotaStatus>>::new(None))
+            .lock()
+            .unwrap();
+        if let Some(status) = status {
+            response.set_raw_header("X-Quota-Name", status.kind.label());
+            response.set_raw_header("X-Quota-Limit", status.limit.to_string());
+            response.set_raw_header("X-Quota-Remaining", status.remaining.to_string());
+            response.set_raw_header("X-Quota-Reset", status.reset.to_string());
+        }
+    }
+}
+
+// Delete counters of past days periodically
+pub async fn cleanup_job(db: Database) {
+    let mut interval = time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        let cleaned = tokio::task::spawn_blocking(move || -> Result<usize, Error> {
+            let persy = db.read();
+            let today = stats::today();
+            let past = storage::scan::<QuotaCounter>(&persy, COUNTERS_SEGMENT)?
+                .into_iter()
+                .filter(|(_, counter)| counter.day < today)
+                .collect::<Vec<_>>();
+            if past.is_empty() {
+                return Ok(0);
+            }
+            let mut tx = persy.begin()?;
+            for (id, counter) in &past {
+                tx.delete(COUNTERS_SEGMENT, id)?;
+                let key = counter_key(counter.kind, counter.user, counter.day);
+                tx.remove(BY_KEY_INDEX, key, Some(*id))?;
+            }
+            tx.prepare()?.commit()?;
+            Ok(past.len())
+        })
+        .await;
+        match cleaned {
+            Ok(Ok(0)) => {}
+            Ok(Ok(cleaned)) => println!("Deleted {} daily quota counters of past days", cleaned),
+            Ok(Err(e)) => println!("Failed to delete daily quota counters: {}", e),
+            Err(e) => println!("Daily quota cleanup task failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use rocket::{get, routes, serde::json::Json, FromFormField, Route};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+//
+// Difficulty presets. Preset sets starting level, gravity curve and lock delay of the game,
+// and how much garbage is sent in versus. Level grows every LINES_PER_LEVEL cleared lines.
+// Preset is stored in replays and game results, games recorded before presets were
+// introduced have no preset: constant gravity of one row per second and no lock delay.
+// Gravity curve of the preset may be replaced by a GravityCurve, see gravity_curves
+//
+
+pub const LINES_PER_LEVEL: usize = 10;
+// Gravity is measured in rows per GRAVITY_STEPS steps, i.e. rows per second
+pub const GRAVITY_STEPS: usize = 100;
+// Soft drop speed, rows per GRAVITY_STEPS. Drop is never slower than gravity
+pub const DROP_SPEED: usize = 10;
+// Levels of a gravity curve, higher levels keep gravity of the last one
+pub const MAX_CURVE_LEVELS: usize = 50;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, FromFormField,
+)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+    Master,
+}
+
+// Gravity of levels from the first one, rows per GRAVITY_STEPS. Levels past the end keep
+// gravity of the last one
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct GravityCurve(pub Vec<usize>);
+
+#[derive(Serialize)]
+pub struct DifficultyPreset {
+    pub difficulty: Difficulty,
+    pub start_level: usize,
+    // Gravity at starting level, rows per second
+    pub start_gravity: usize,
+    pub max_gravity: usize,
+    // Steps a piece rests on the stack before it's locked
+    pub lock_delay: u64,
+    // Garbage sent in versus, percent of the garbage rules' attack
+    pub garbage_percent: usize,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 4] = [
+        Difficulty::Easy,
+        Difficulty::Normal,
+        Difficulty::Hard,
+        Difficulty::Master,
+    ];
+
+    pub fn start_level(&self) -> usize {
+        match self {
+            Difficulty::Easy => 1,
+            Difficulty::Normal => 1,
+            Difficulty::Hard => 3,
+            Difficulty::Master => 5,
+        }
+    }
+
+    // Level after clearing given number of lines
+    pub fn level(&self, lines: usize) -> usize {
+        self.start_level() + lines / LINES_PER_LEVEL
+    }
+
+    // Gravity at the level, rows per GRAVITY_STEPS
+    pub fn gravity(&self, level: usize) -> usize {
+        let gravity = match self {
+            Difficulty::Easy => 1 + (level - 1) / 2,
+            Difficulty::Normal => level,
+            Difficulty::Hard => level * 3 / 2,
+            Difficulty::Master => level * 4,
+        };
+        gravity.min(self.max_gravity())
+    }
+
+    pub fn max_gravity(&self) -> usize {
+        match self {
+            Difficulty::Easy => 6,
+            Difficulty::Normal => 15,
+            Difficulty::Hard => 30,
+            // One row per step
+            Difficulty::Master => GRAVITY_STEPS,
+        }
+    }
+
+    pub fn lock_delay(&self) -> u64 {
+        match self {
+            Difficulty::Easy => 50,
+            Difficulty::Normal => 30,
+            Difficulty::Hard => 20,
+            Difficulty::Master => 10,
+        }
+    }
+
+    pub fn garbage_percent(&self) -> usize {
+        match self {
+            Difficulty::Easy => 50,
+            Difficulty::Normal => 100,
+            Difficulty::Hard => 150,
+            Difficulty::Master => 200,
+        }
+    }
+
+    // Gravity of the preset by level, up to the level reaching max gravity
+    pub fn curve(&self) -> GravityCurve {
+        let levels = (1..=MAX_CURVE_LEVELS)
+            .find(|level| self.gravity(*level) == self.max_gravity())
+            .unwrap_or(MAX_CURVE_LEVELS);
+        GravityCurve((1..=levels).map(|level| self.gravity(level)).collect())
+    }
+
+    // Garbage lines sent for attack of the garbage rules, rounded down
+    pub fn scale_attack(&self, attack: usize) -> usize {
+        attack * self.garbage_percent() / 100
+    }
+
+    fn preset(&self) -> DifficultyPreset {
+        DifficultyPreset {
+            difficulty: *self,
+            start_level: self.start_level(),
+            start_gravity: self.gravity(self.start_level()),
+            max_gravity: self.max_gravity(),
+            lock_delay: self.lock_delay(),
+            garbage_percent: self.garbage_percent(),
+        }
+    }
+}
+
+impl GravityCurve {
+    pub fn gravity(&self, level: usize) -> usize {
+        let index = level.saturating_sub(1);
+        self.0.get(index).or(self.0.last()).copied().unwrap_or(1)
+    }
+
+    // Gravities of levels separated by commas or spaces
+    pub fn parse(text: &str) -> Result<GravityCurve, Error> {
+        let mut gravity = Vec::new();
+        for value in text
+            .split([',', ' ', '\n', '\r'])
+            .filter(|value| !value.is_empty())
+        {
+            match value.parse::<usize>() {
+                Ok(parsed) if (1..=GRAVITY_STEPS).contains(&parsed) => gravity.push(parsed),
+                _ => {
+                    return Err(Error::InvalidInputError(format!(
+                        "Gravity must be from 1 to {} rows per second, got {}",
+                        GRAVITY_STEPS, value
+                    )))
+                }
+            }
+        }
+        if gravity.is_empty() || gravity.len() > MAX_CURVE_LEVELS {
+            return Err(Error::InvalidInputError(format!(
+                "Gravity curve must have from 1 to {} levels",
+                MAX_CURVE_LEVELS
+            )));
+        }
+        Ok(GravityCurve(gravity))
+    }
+}
+
+impl std::fmt::Display for GravityCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let values = self.0.iter().map(usize::to_string).collect::<Vec<_>>();
+        write!(f, "{}", values.join(", "))
+    }
+}
+
+// Available difficulty presets
+#[get("/difficulties")]
+fn difficulties() -> Json<Vec<DifficultyPreset>> {
+    Json(Difficulty::ALL.iter().map(Difficulty::preset).collect())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![difficulties]
+}
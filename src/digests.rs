@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    get,
+    http::CookieJar,
+    post, routes,
+    serde::json::Json,
+    tokio::{
+        self,
+        time::{self, Duration},
+    },
+    Config, Route, State,
+};
+use rocket_dyn_templates::{
+    context,
+    handlebars::{self, Handlebars},
+    Template,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    email_login::{self, EmailLogin},
+    error::Error,
+    game_mode::GameMode,
+    ids::{GameId, UserId},
+    leaderboard::{self, LeaderboardEntry, Verification},
+    notifications::{self, NotificationKind},
+    roles::Viewer,
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Weekly email digests. Players with an address linked by email login opt in with POST
+// /digest/subscribe. Once a week after subscribing a digest is rendered from
+// templates/email/digest.txt.hbs: best game of the week, leaderboard rank change and
+// achievements of players met in versus matches, and sent through the smtp of email login.
+// Each digest links /digest/unsubscribe/<token>, the token of the subscription is checked
+// on the server, so unsubscribing doesn't need a login on the device reading the mail.
+// The link opens a confirmation page which POSTs the token: mail scanners and link
+// prefetchers follow links with GET and must not unsubscribe anybody. The same POST url
+// is sent in List-Unsubscribe header for one-click unsubscribe of mail clients (RFC 8058)
+//
+
+const SUBSCRIPTIONS_SEGMENT: &str = "digest_subscriptions";
+const BY_USER_INDEX: &str = "digest_subscriptions_by_user";
+const BY_TOKEN_INDEX: &str = "digest_subscriptions_by_token";
+
+const TEMPLATE: &str = "digest";
+const TEMPLATE_FILE: &str = "email/digest.txt.hbs";
+// Digest is sent this long after the previous one, seconds
+const PERIOD: u64 = 7 * 24 * 60 * 60;
+// Due digests are looked for with this interval
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Subscription {
+    user: UserId,
+    email: String,
+    // Secret of unsubscribe links
+    token: String,
+    // Seconds since unix epoch
+    created: u64,
+    last_sent: u64,
+}
+
+#[derive(Serialize)]
+pub struct DigestStatus {
+    pub subscribed: bool,
+    pub email: Option<String>,
+    // Seconds since unix epoch
+    pub next_digest: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SubscriptionItem {
+    pub user: UserId,
+    pub email: String,
+    pub created: u64,
+    pub last_sent: u64,
+}
+
+#[derive(Serialize)]
+struct BestGame {
+    id: GameId,
+    mode: GameMode,
+    score: u64,
+    lines: u64,
+}
+
+#[derive(Serialize)]
+struct Achievements {
+    user: UserId,
+    achievements: Vec<String>,
+}
+
+// Values of the digest template
+#[derive(Serialize)]
+struct DigestContext {
+    user: UserId,
+    public_url: String,
+    games: usize,
+    best: Option<BestGame>,
+    // Leaderboard rank by personal best now and a week before
+    rank: Option<usize>,
+    previous_rank: Option<usize>,
+    // Places gained, negative when lost
+    rank_change: i64,
+    rank_up: bool,
+    rank_down: bool,
+    friends: Vec<Achievements>,
+    unsubscribe_url: String,
+}
+
+// Leaderboard ranks of players by their best scores
+struct Standings {
+    now: HashMap<UserId, usize>,
+    before: HashMap<UserId, usize>,
+}
+
+// Renders digests, sends them when smtp is configured
+#[derive(Clone)]
+pub struct Digests {
+    mailer: Option<EmailLogin>,
+    templates: Arc<Handlebars<'static>>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, SUBSCRIPTIONS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    storage::ensure_index::<String, PersyId>(persy, BY_TOKEN_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+fn subscription_of_user(
+    persy: &Persy,
+    user: UserId,
+) -> Result<Option<(PersyId, Subscription)>, Error> {
+    let Some(id) = persy.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? else {
+        return Ok(None);
+    };
+    Ok(storage::read(persy, SUBSCRIPTIONS_SEGMENT, &id)?.map(|subscription| (id, subscription)))
+}
+
+fn remove(persy: &Persy, id: &PersyId, subscription: &Subscription) -> Result<(), Error> {
+    let mut tx = persy.begin()?;
+    tx.remove(BY_USER_INDEX, subscription.user.0, Some(*id))?;
+    tx.remove(BY_TOKEN_INDEX, subscription.token.clone(), Some(*id))?;
+    tx.delete(SUBSCRIPTIONS_SEGMENT, id)?;
+    tx.prepare()?.commit()?;
+    println!("User {} unsubscribed from digests", subscription.user);
+    Ok(())
+}
+
+// Rank is 1 + number of players with better personal best, rejected scores don't count
+fn ranks<'a>(entries: impl Iterator<Item = &'a LeaderboardEntry>) -> HashMap<UserId, usize> {
+    let mut bests = HashMap::new();
+    for entry in entries.filter(|entry| entry.verification != Verification::Rejected) {
+        let best = bests.entry(entry.user).or_insert(0);
+        *best = entry.score.max(*best);
+    }
+    let mut scores = bests.values().copied().collect::<Vec<_>>();
+    scores.sort_unstable_by(|a, b| b.cmp(a));
+    bests
+        .into_iter()
+        .map(|(user, best)| (user, 1 + scores.partition_point(|score| *score > best)))
+        .collect()
+}
+
+impl Standings {
+    fn load(persy: &Persy, since: u64) -> Result<Standings, Error> {
+        let entries = leaderboard::finished_between(persy, 0, u64::MAX)?;
+        Ok(Standings {
+            now: ranks(entries.iter()),
+            before: ranks(entries.iter().filter(|entry| entry.finished < since)),
+        })
+    }
+}
+
+impl Digests {
+    pub fn from_config(mailer: Option<EmailLogin>) -> Result<Digests, Error> {
+        let dir = Config::figment()
+            .extract_inner::<PathBuf>("template_dir")
+            .unwrap_or_else(|_| PathBuf::from("templates"));
+        let mut templates = Handlebars::new();
+        // Digests are plain text
+        templates.register_escape_fn(handlebars::no_escape);
+        templates
+            .register_template_file(TEMPLATE, dir.join(TEMPLATE_FILE))
+            .map_err(|e| Error::InvalidInputError(format!("Digest template: {}", e)))?;
+        Ok(Digests {
+            mailer,
+            templates: Arc::new(templates),
+        })
+    }
+
+    fn public_url(&self) -> String {
+        self.mailer
+            .as_ref()
+            .map_or_else(String::new, |mailer| mailer.public_url().to_string())
+    }
+
+    fn context(
+        &self,
+        persy: &Persy,
+        standings: &Standings,
+        subscription: &Subscription,
+        since: u64,
+    ) -> Result<DigestContext, Error> {
+        let user = subscription.user;
+        let games = leaderboard::entries_of_user(persy, user)?
+            .into_iter()
+            .filter(|(_, entry)| entry.finished >= since)
+            .collect::<Vec<_>>();
+        let best = games
+            .iter()
+            .filter(|(_, entry)| entry.verification != Verification::Rejected)
+            .max_by_key(|(_, entry)| entry.score)
+            .map(|(id, entry)| BestGame {
+                id: GameId(*id),
+                mode: entry.mode,
+                score: entry.score,
+                lines: entry.lines,
+            });
+        let rank = standings.now.get(&user).copied();
+        let previous_rank = standings.before.get(&user).copied();
+        let rank_change = match (rank, previous_rank) {
+            (Some(rank), Some(previous)) => previous as i64 - rank as i64,
+            _ => 0,
+        };
+        // Players met in versus matches of the week
+        let opponents = games
+            .iter()
+            .filter_map(|(_, entry)| entry.opponent)
+            .collect::<HashSet<_>>();
+        let mut friends = Vec::new();
+        for opponent in opponents {
+            let achievements = notifications::notifications_of_user(persy, opponent)?
+                .into_iter()
+                .map(|(_, notification)| notification)
+                .filter(|notification| {
+                    notification.kind == NotificationKind::Achievement
+                        && notification.created >= since
+                })
+                .map(|notification| notification.text)
+                .collect::<Vec<_>>();
+            if !achievements.is_empty() {
+                friends.push(Achievements {
+                    user: opponent,
+                    achievements,
+                });
+            }
+        }
+        friends.sort_by_key(|friend| friend.user.0);
+        Ok(DigestContext {
+            user,
+            unsubscribe_url: self.unsubscribe_url(subscription),
+            public_url: self.public_url(),
+            games: games.len(),
+            best,
+            rank,
+            previous_rank,
+            rank_change,
+            rank_up: rank_change > 0,
+            rank_down: rank_change < 0,
+            friends,
+        })
+    }
+
+    fn render(
+        &self,
+        persy: &Persy,
+        standings: &Standings,
+        subscription: &Subscription,
+        since: u64,
+    ) -> Result<String, Error> {
+        let context = self.context(persy, standings, subscription, since)?;
+        self.templates
+            .render(TEMPLATE, &context)
+            .map_err(|e| Error::InvalidInputError(format!("Digest template: {}", e)))
+    }
+
+    // Confirmation page of unsubscribing, also the one-click POST url
+    fn unsubscribe_url(&self, subscription: &Subscription) -> String {
+        format!(
+            "{}/digest/unsubscribe/{}",
+            self.public_url(),
+            subscription.token
+        )
+    }
+
+    // Render digests which are due and mark them sent. Returns addresses, unsubscribe urls
+    // and bodies
+    fn due(&self, persy: &Persy) -> Result<Vec<(String, String, String)>, Error> {
+        let now = crate::unix_time();
+        let due = storage::scan::<Subscription>(persy, SUBSCRIPTIONS_SEGMENT)?
+            .into_iter()
+            .filter(|(_, subscription)| subscription.last_sent + PERIOD <= now)
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            return Ok(Vec::new());
+        }
+        let standings = Standings::load(persy, now.saturating_sub(PERIOD))?;
+        let mut digests = Vec::new();
+        for (id, mut subscription) in due {
+            match self.render(persy, &standings, &subscription, subscription.last_sent) {
+                Ok(body) => digests.push((
+                    subscription.email.clone(),
+                    self.unsubscribe_url(&subscription),
+                    body,
+                )),
+                Err(e) => println!("Digest of user {} failed: {}", subscription.user, e),
+            }
+            subscription.last_sent = now;
+            storage::update(persy, SUBSCRIPTIONS_SEGMENT, &id, &subscription)?;
+        }
+        Ok(digests)
+    }
+
+    // Send due digests periodically
+    pub async fn digest_job(self, db: Database) {
+        let Some(mailer) = self.mailer.clone() else {
+            return;
+        };
+        let mut interval = time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let (digests, job_db) = (self.clone(), db.clone());
+            let due = match tokio::task::spawn_blocking(move || digests.due(&job_db.read())).await {
+                Ok(Ok(due)) => due,
+                Ok(Err(e)) => {
+                    println!("Digests failed: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    println!("Digest task failed: {}", e);
+                    continue;
+                }
+            };
+            for (email, unsubscribe_url, body) in due {
+                let headers = vec![
+                    ("List-Unsubscribe", format!("<{}>", unsubscribe_url)),
+                    (
+                        "List-Unsubscribe-Post",
+                        "List-Unsubscribe=One-Click".to_string(),
+                    ),
+                ];
+                let message =
+                    mailer.message_with_headers(&email, "Your week in Tetris", body, headers);
+                let sent = match message {
+                    Ok(message) => mailer.send(message).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    println!("Digest to {} failed: {}", email, e);
+                }
+            }
+        }
+    }
+}
+
+// Opt in to weekly digests, the player needs an address linked by email login
+#[post("/digest/subscribe")]
+fn subscribe(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    digests: &State<Digests>,
+) -> Result<Json<DigestStatus>, Error> {
+    if digests.mailer.is_none() {
+        return Err(Error::NotFoundError(
+            "Email digests are not enabled".to_string(),
+        ));
+    }
+    let user_id = crate::user_id(cookie_jar, matches);
+    let persy = &*db.read();
+    let account = email_login::account_of_user(persy, user_id)?.ok_or_else(|| {
+        Error::InvalidInputError("Log in by email to subscribe to digests".to_string())
+    })?;
+    let subscription = match subscription_of_user(persy, user_id)? {
+        Some((_, subscription)) => subscription,
+        None => {
+            let now = crate::unix_time();
+            let subscription = Subscription {
+                user: user_id,
+                email: account.email,
+                token: format!("{:032x}", rand::random::<u128>()),
+                created: now,
+                last_sent: now,
+            };
+            storage::insert_with(persy, SUBSCRIPTIONS_SEGMENT, &subscription, |tx, id| {
+                tx.put(BY_USER_INDEX, user_id.0, *id)?;
+                tx.put(BY_TOKEN_INDEX, subscription.token.clone(), *id)?;
+                Ok(())
+            })?;
+            println!("User {} subscribed to digests", user_id);
+            subscription
+        }
+    };
+    Ok(Json(DigestStatus {
+        subscribed: true,
+        email: Some(subscription.email),
+        next_digest: Some(subscription.last_sent + PERIOD),
+    }))
+}
+
+#[get("/digest")]
+fn status(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<DigestStatus>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let subscription = subscription_of_user(&db.read(), user_id)?.map(|(_, s)| s);
+    Ok(Json(DigestStatus {
+        subscribed: subscription.is_some(),
+        next_digest: subscription.as_ref().map(|s| s.last_sent + PERIOD),
+        email: subscription.map(|s| s.email),
+    }))
+}
+
+// Opt out of digests on this device
+#[post("/digest/unsubscribe")]
+fn unsubscribe(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<DigestStatus>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let persy = &*db.read();
+    if let Some((id, subscription)) = subscription_of_user(persy, user_id)? {
+        remove(persy, &id, &subscription)?;
+    }
+    Ok(Json(DigestStatus {
+        subscribed: false,
+        email: None,
+        next_digest: None,
+    }))
+}
+
+fn subscription_of_token(persy: &Persy, token: &str) -> Result<(PersyId, Subscription), Error> {
+    let invalid = || Error::NotFoundError("Unsubscribe link is invalid".to_string());
+    let id = persy
+        .one::<String, PersyId>(BY_TOKEN_INDEX, &token.to_string())?
+        .ok_or_else(invalid)?;
+    let subscription =
+        storage::read::<Subscription>(persy, SUBSCRIPTIONS_SEGMENT, &id)?.ok_or_else(invalid)?;
+    Ok((id, subscription))
+}
+
+// Unsubscribe link of digests, works without login. Only asks for confirmation, as links
+// are followed by mail scanners too
+#[get("/digest/unsubscribe/<token>")]
+fn unsubscribe_link(db: &State<Database>, token: &str) -> Result<Template, Error> {
+    let (_, subscription) = subscription_of_token(&db.read(), token)?;
+    Ok(Template::render(
+        "digest_unsubscribe",
+        context! { email: subscription.email, token },
+    ))
+}
+
+// Confirmed unsubscribe link, also one-click unsubscribe of mail clients
+#[post("/digest/unsubscribe/<token>")]
+fn unsubscribe_token(db: &State<Database>, token: &str) -> Result<String, Error> {
+    let persy = &*db.read();
+    let (id, subscription) = subscription_of_token(persy, token)?;
+    remove(persy, &id, &subscription)?;
+    Ok("You won't get digests anymore.".to_string())
+}
+
+#[get("/admin/digests")]
+fn admin_digests(
+    _admin: Viewer,
+    db: &State<Database>,
+) -> Result<Json<Vec<SubscriptionItem>>, Error> {
+    let mut subscriptions = storage::scan::<Subscription>(&db.read(), SUBSCRIPTIONS_SEGMENT)?
+        .into_iter()
+        .map(|(_, subscription)| SubscriptionItem {
+            user: subscription.user,
+            email: subscription.email,
+            created: subscription.created,
+            last_sent: subscription.last_sent,
+        })
+        .collect::<Vec<_>>();
+    subscriptions.sort_by_key(|item| item.created);
+    Ok(Json(subscriptions))
+}
+
+// Digest the subscriber would get now, it's not sent
+#[get("/admin/digests/<user>/preview")]
+fn admin_preview(
+    _admin: Viewer,
+    db: &State<Database>,
+    digests: &State<Digests>,
+    user: UserId,
+) -> Result<String, Error> {
+    let persy = &*db.read();
+    let (_, subscription) = subscription_of_user(persy, user)?
+        .ok_or_else(|| Error::NotFoundError("Subscription not found".to_string()))?;
+    let since = crate::unix_time().saturating_sub(PERIOD);
+    let standings = Standings::load(persy, since)?;
+    digests.render(persy, &standings, &subscription, since)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        subscribe,
+        status,
+        unsubscribe,
+        unsubscribe_link,
+        unsubscribe_token,
+        admin_digests,
+        admin_preview
+    ]
+}
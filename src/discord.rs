@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use rocket::{
+    serde::json::{serde_json, Value},
+    tokio::{self, sync::mpsc},
+    Config,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, leaderboard::LeaderboardItem};
+
+//
+// Announcements to a Discord channel through the channel's webhook, configured with
+// [default.discord] table. Messages are formatted here and posted one by one by
+// a background worker, waiting out Discord's rate limits. Announcements which can't be
+// posted are dropped, they're not worth holding up the next ones
+//
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Posting attempts of rate limited message
+const MAX_ATTEMPTS: u32 = 3;
+// Embed color of record announcements
+const RECORD_COLOR: u32 = 0xffa500;
+// Game steps per second, for game durations
+const STEPS_PER_SECOND: u64 = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscordConfig {
+    // Channel webhook url, https://discord.com/api/webhooks/<id>/<token>
+    pub webhook_url: String,
+    // Name shown as message author, the webhook's name by default
+    pub username: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EmbedField {
+    name: String,
+    value: String,
+    inline: bool,
+}
+
+#[derive(Serialize)]
+struct Embed {
+    title: String,
+    color: u32,
+    fields: Vec<EmbedField>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    content: String,
+    embeds: Vec<Embed>,
+}
+
+fn field(name: &str, value: String) -> EmbedField {
+    EmbedField {
+        name: name.to_string(),
+        value,
+        inline: true,
+    }
+}
+
+// Record score message, e.g. "New Sprint record: 12000 points by player 7"
+fn record_message(item: &LeaderboardItem) -> Message {
+    let entry = &item.entry;
+    let difficulty = entry
+        .difficulty
+        .map_or(String::new(), |difficulty| format!(" ({:?})", difficulty));
+    let mut fields = vec![
+        field("Score", entry.score.to_string()),
+        field("Lines", entry.lines.to_string()),
+        field(
+            "Time",
+            format!(
+                "{}.{:02}s",
+                entry.ticks / STEPS_PER_SECOND,
+                entry.ticks % STEPS_PER_SECOND
+            ),
+        ),
+    ];
+    if let Some(opponent) = entry.opponent {
+        fields.push(field("Opponent", format!("Player {}", opponent)));
+    }
+    Message {
+        username: None,
+        content: format!(
+            "New {:?}{} record: {} points by player {}",
+            entry.mode, difficulty, entry.score, entry.user
+        ),
+        embeds: vec![Embed {
+            title: format!("Game {}", item.id),
+            color: RECORD_COLOR,
+            fields,
+        }],
+    }
+}
+
+// Handle to post announcements, present when Discord is configured
+#[derive(Clone)]
+pub struct Discord(mpsc::UnboundedSender<Message>);
+
+impl Discord {
+    // Start posting worker if discord table is configured
+    pub fn from_config() -> Result<Option<Discord>, Error> {
+        let Ok(config) = Config::figment().extract_inner::<DiscordConfig>("discord") else {
+            return Ok(None);
+        };
+        if !config.webhook_url.starts_with("https://") {
+            return Err(Error::InvalidInputError(
+                "Discord webhook_url must be https url".to_string(),
+            ));
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        // Not a lifecycle job, the worker ends with the last handle
+        tokio::spawn(Self::worker(client, config, receiver));
+        println!("Discord announcements enabled");
+        Ok(Some(Discord(sender)))
+    }
+
+    // Announce new record score
+    pub fn record(&self, item: &LeaderboardItem) {
+        let _ = self.0.send(record_message(item));
+    }
+
+    async fn worker(
+        client: reqwest::Client,
+        config: DiscordConfig,
+        mut receiver: mpsc::UnboundedReceiver<Message>,
+    ) {
+        while let Some(mut message) = receiver.recv().await {
+            message.username = config.username.clone();
+            if let Err(e) = post(&client, &config.webhook_url, &message).await {
+                println!("Discord announcement failed: {}", e);
+            }
+        }
+    }
+}
+
+// Post message, waiting when Discord asks to slow down
+async fn post(client: &reqwest::Client, url: &str, message: &Message) -> Result<(), String> {
+    let body = serde_json::to_string(message).map_err(|e| e.to_string())?;
+    for _ in 0..MAX_ATTEMPTS {
+        let response = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(format!("Status {}", status));
+        }
+        // Rate limited, body tells how many seconds to wait
+        let retry_after = response
+            .text()
+            .await
+            .ok()
+            .and_then(|body| serde_json::from_str::<Value>(&body).ok())
+            .and_then(|body| body["retry_after"].as_f64())
+            .unwrap_or(1.0);
+        tokio::time::sleep(Duration::from_secs_f64(retry_after.clamp(0.0, 60.0))).await;
+    }
+    Err("Rate limited".to_string())
+}
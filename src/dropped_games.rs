@@ -0,0 +1,186 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use persy::Persy;
+use rocket::{
+    get, routes,
+    tokio::{
+        self,
+        time::{self, Duration},
+    },
+    Route, State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    roles::Viewer,
+    storage::{self, Database},
+};
+
+//
+// Incidents of active games dropped from memory. A match with an input within ACTIVE_WINDOW
+// which is evicted or removed after it's players' games diverged is logged to
+// "dropped_games" segment, so capacity problems are visible to operators instead of players
+// silently losing their games. Each incident is printed as an alert and counted in
+// gameserver_dropped_active_games of /admin/metrics, the latest MAX_INCIDENTS are kept and
+// listed in /admin/dropped. Counter starts at zero on each restart
+//
+
+const DROPPED_SEGMENT: &str = "dropped_games";
+
+// Games with an input within this time are active
+pub const ACTIVE_WINDOW: Duration = Duration::from_secs(30);
+// Incidents kept, older ones are deleted
+const MAX_INCIDENTS: usize = 1000;
+// Interval of writes of incidents
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DropReason {
+    // Removed from memory by admin or to free it
+    Evicted,
+    // Games of the players diverged too far to go on
+    Diverged,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DroppedGame {
+    // Arena of the game, None for games of the server
+    pub arena: Option<String>,
+    pub players: Vec<UserId>,
+    pub scores: Vec<usize>,
+    pub reason: DropReason,
+    // Start time and time of the drop, seconds since unix epoch
+    pub started: u64,
+    pub dropped: u64,
+    // Time since the latest input of the players, milliseconds
+    pub idle_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct DroppedItem {
+    pub id: String,
+    #[serde(flatten)]
+    pub game: DroppedGame,
+}
+
+#[derive(Clone, Default)]
+pub struct DroppedGames {
+    // Incidents not written yet
+    pending: Arc<Mutex<Vec<DroppedGame>>>,
+    // Incidents since start
+    count: Arc<AtomicU64>,
+    arena: Option<String>,
+}
+
+impl DroppedGames {
+    pub fn new() -> DroppedGames {
+        DroppedGames::default()
+    }
+
+    // Log sharing storage and counter of this one, labelling games with the arena
+    pub fn for_arena(&self, name: &str) -> DroppedGames {
+        DroppedGames {
+            pending: self.pending.clone(),
+            count: self.count.clone(),
+            arena: Some(name.to_string()),
+        }
+    }
+
+    // Log active game removed from memory
+    pub fn add(&self, mut game: DroppedGame) {
+        game.arena = self.arena.clone();
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        println!(
+            "ALERT: active game of {:?} dropped ({:?}), last input {} ms ago, {} dropped since start",
+            game.players, game.reason, game.idle_ms, count
+        );
+        self.pending.lock().unwrap().push(game);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn take_games(&self) -> Vec<DroppedGame> {
+        std::mem::take(&mut self.pending.lock().unwrap())
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, DROPPED_SEGMENT)
+}
+
+// Write incidents periodically
+pub async fn persist_job(db: Database, dropped: DroppedGames) {
+    let mut interval = time::interval(PERSIST_INTERVAL);
+    loop {
+        interval.tick().await;
+        persist(db.clone(), &dropped).await;
+    }
+}
+
+// Write incidents since the last write and delete the oldest above the limit, also before
+// shutdown
+pub async fn persist(db: Database, dropped: &DroppedGames) {
+    let games = dropped.take_games();
+    if games.is_empty() {
+        return;
+    }
+    let written = tokio::task::spawn_blocking(move || -> Result<(), Error> {
+        let persy = db.read();
+        let mut existing = storage::scan::<DroppedGame>(&persy, DROPPED_SEGMENT)?;
+        existing.sort_by_key(|(id, game)| (game.dropped, *id));
+        let overflow = (existing.len() + games.len()).saturating_sub(MAX_INCIDENTS);
+        let mut tx = persy.begin()?;
+        for (id, _) in existing.iter().take(overflow) {
+            tx.delete(DROPPED_SEGMENT, id)?;
+        }
+        for game in games.iter().skip(games.len().saturating_sub(MAX_INCIDENTS)) {
+            storage::insert_in_tx(&mut tx, DROPPED_SEGMENT, game)?;
+        }
+        tx.prepare()?.commit()?;
+        Ok(())
+    })
+    .await;
+    match written {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => println!("Failed to store dropped games: {}", e),
+        Err(e) => println!("Dropped games task failed: {}", e),
+    }
+}
+
+pub fn list(persy: &Persy) -> Result<Vec<DroppedItem>, Error> {
+    let mut games = storage::scan::<DroppedGame>(persy, DROPPED_SEGMENT)?
+        .into_iter()
+        .map(|(id, game)| DroppedItem {
+            id: id.to_string(),
+            game,
+        })
+        .collect::<Vec<_>>();
+    games.sort_by_key(|item| std::cmp::Reverse(item.game.dropped));
+    Ok(games)
+}
+
+// Dropped active games, the latest first
+#[get("/admin/dropped")]
+fn admin_dropped(
+    _admin: Viewer,
+    db: &State<Database>,
+    dropped: &State<DroppedGames>,
+) -> Result<Template, Error> {
+    let games = list(&db.read())?;
+    Ok(Template::render(
+        "admin/dropped",
+        context! { games, count: dropped.count(), limit: MAX_INCIDENTS },
+    ))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_dropped]
+}
@@ -0,0 +1,468 @@
+use std::time::Duration;
+
+use lettre::{
+    message::{
+        header::{ContentType, HeaderName, HeaderValue},
+        Mailbox,
+    },
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{
+    form::Form, get, http::CookieJar, post, response::Redirect, routes, serde::json::Json, tokio,
+    Config, FromForm, Route, State,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    sessions::{self, Device},
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Passwordless login by email. POST /auth/email sends a login link to the address,
+// following the link logs the device in as the player the address belongs to. Address
+// that isn't linked yet is linked to the player of the device following the link, not to
+// the one who requested it, so nobody can bind somebody else's address to own account by
+// requesting a link to it. Link tokens are single use and expire, only their hashes are
+// stored. Enabled by [default.smtp] table
+//
+
+const ACCOUNTS_SEGMENT: &str = "email_accounts";
+const ACCOUNTS_BY_EMAIL_INDEX: &str = "email_accounts_by_email";
+const TOKENS_SEGMENT: &str = "login_tokens";
+const TOKENS_BY_HASH_INDEX: &str = "login_tokens_by_hash";
+
+// Lifetime of login link, seconds
+const TOKEN_TTL: u64 = 15 * 60;
+// Links for one address are sent not more often than this, seconds
+const RESEND_INTERVAL: u64 = 60;
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    // Submission port, STARTTLS is required
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    // Sender address, e.g. "Tetris <login@tetris.example.com>"
+    pub from: String,
+}
+
+fn default_port() -> u16 {
+    587
+}
+
+// Email address linked to a player
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailAccount {
+    pub email: String,
+    pub user: UserId,
+    pub created: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LoginToken {
+    // Hex SHA-256 of the token sent in the link
+    hash: String,
+    email: String,
+    // Player who requested the link and hash of their session. The address is linked to
+    // them only when the link is followed in the same session
+    requested_by: UserId,
+    #[serde(default)]
+    requested_session: Option<String>,
+    created: u64,
+    expires: u64,
+    used: bool,
+}
+
+#[derive(FromForm)]
+pub struct EmailLoginForm {
+    email: String,
+}
+
+#[derive(Serialize)]
+pub struct LinkSent {
+    pub email: String,
+    pub expires: u64,
+}
+
+// Mail transport and sender, present when smtp is configured. Also sends digests
+#[derive(Clone)]
+pub struct EmailLogin {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    // Login links are absolute urls under public_url
+    public_url: String,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, ACCOUNTS_SEGMENT)?;
+    storage::ensure_index::<String, PersyId>(persy, ACCOUNTS_BY_EMAIL_INDEX, ValueMode::Replace)?;
+    storage::ensure_segment(persy, TOKENS_SEGMENT)?;
+    storage::ensure_index::<String, PersyId>(persy, TOKENS_BY_HASH_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn new_token() -> String {
+    format!(
+        "{:032x}{:032x}",
+        rand::random::<u128>(),
+        rand::random::<u128>()
+    )
+}
+
+// Address in the form it's stored, so the same mailbox is found regardless of it's case
+fn normalize(email: &str) -> Result<String, Error> {
+    let email = email.trim().to_lowercase();
+    email
+        .parse::<Mailbox>()
+        .map_err(|_| Error::InvalidInputError("Invalid email address".to_string()))?;
+    Ok(email)
+}
+
+// Address linked to the player
+pub fn account_of_user(persy: &Persy, user: UserId) -> Result<Option<EmailAccount>, Error> {
+    Ok(storage::scan::<EmailAccount>(persy, ACCOUNTS_SEGMENT)?
+        .into_iter()
+        .map(|(_, account)| account)
+        .find(|account| account.user == user))
+}
+
+// Address account, read as part of the transaction redeeming a link
+fn account(tx: &mut Transaction, email: &str) -> Result<Option<EmailAccount>, Error> {
+    let Some(id) = tx.one::<String, PersyId>(ACCOUNTS_BY_EMAIL_INDEX, &email.to_string())? else {
+        return Ok(None);
+    };
+    storage::read_in_tx(tx, ACCOUNTS_SEGMENT, &id)
+}
+
+fn tokens_of_email(persy: &Persy, email: &str) -> Result<Vec<LoginToken>, Error> {
+    Ok(storage::scan::<LoginToken>(persy, TOKENS_SEGMENT)?
+        .into_iter()
+        .map(|(_, token)| token)
+        .filter(|token| token.email == email)
+        .collect())
+}
+
+impl EmailLogin {
+    pub fn from_config() -> Result<Option<EmailLogin>, Error> {
+        let figment = Config::figment();
+        let Ok(config) = figment.extract_inner::<SmtpConfig>("smtp") else {
+            return Ok(None);
+        };
+        let public_url = figment.extract_inner::<String>("public_url").map_err(|_| {
+            Error::InvalidInputError("Email login needs public_url for links".to_string())
+        })?;
+        let from = config
+            .from
+            .parse()
+            .map_err(|_| Error::InvalidInputError(format!("Invalid smtp from {}", config.from)))?;
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+            .port(config.port)
+            .credentials(Credentials::new(config.username, config.password))
+            .timeout(Some(SMTP_TIMEOUT))
+            .build();
+        println!("Email login enabled, smtp {}:{}", config.host, config.port);
+        Ok(Some(EmailLogin {
+            transport,
+            from,
+            public_url: public_url.trim_end_matches('/').to_string(),
+        }))
+    }
+
+    // Plain text email from the configured sender
+    pub fn message(&self, to: &str, subject: &str, body: String) -> Result<Message, Error> {
+        self.message_with_headers(to, subject, body, Vec::new())
+    }
+
+    // Plain text email with additional headers, e.g. List-Unsubscribe of digests
+    pub fn message_with_headers(
+        &self,
+        to: &str,
+        subject: &str,
+        body: String,
+        headers: Vec<(&'static str, String)>,
+    ) -> Result<Message, Error> {
+        let to = to
+            .parse()
+            .map_err(|_| Error::InvalidInputError("Invalid email address".to_string()))?;
+        headers
+            .into_iter()
+            .fold(Message::builder(), |builder, (name, value)| {
+                builder.raw_header(HeaderValue::new(
+                    HeaderName::new_from_ascii_str(name),
+                    value,
+                ))
+            })
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| Error::InvalidInputError(e.to_string()))
+    }
+
+    pub async fn send(&self, message: Message) -> Result<(), Error> {
+        self.transport.send(message).await?;
+        Ok(())
+    }
+
+    pub fn public_url(&self) -> &str {
+        &self.public_url
+    }
+
+    // Store token of new login link and send the link in background
+    fn send_link(
+        &self,
+        persy: &Persy,
+        email: String,
+        requested_by: UserId,
+        requested_session: Option<String>,
+    ) -> Result<LinkSent, Error> {
+        let now = crate::unix_time();
+        if tokens_of_email(persy, &email)?
+            .iter()
+            .any(|token| token.created + RESEND_INTERVAL > now)
+        {
+            return Err(Error::RateLimitError(
+                "Login link was sent recently, check your inbox".to_string(),
+            ));
+        }
+        let token = new_token();
+        let record = LoginToken {
+            hash: hash(&token),
+            email: email.clone(),
+            requested_by,
+            requested_session,
+            created: now,
+            expires: now + TOKEN_TTL,
+            used: false,
+        };
+        storage::insert_with(persy, TOKENS_SEGMENT, &record, |tx, id| {
+            tx.put(TOKENS_BY_HASH_INDEX, record.hash.clone(), *id)?;
+            Ok(())
+        })?;
+        let message = self.message(
+            &email,
+            "Your login link",
+            format!(
+                "Follow this link to log in, it works once within {} minutes:\n\n{}/auth/email/{}\n\n\
+                 If you didn't ask for it, ignore this email.\n",
+                TOKEN_TTL / 60,
+                self.public_url,
+                token
+            ),
+        )?;
+        let login = self.clone();
+        // Not a lifecycle job, sending ends within SMTP_TIMEOUT. Link lost on shutdown is
+        // asked for again
+        tokio::spawn(async move {
+            if let Err(e) = login.send(message).await {
+                println!("Login link to {} failed: {}", email, e);
+            }
+        });
+        Ok(LinkSent {
+            email: record.email,
+            expires: record.expires,
+        })
+    }
+}
+
+// Use token of login link followed by the device of the user in the session, returns the
+// player to log in as
+fn redeem(
+    persy: &Persy,
+    token: &str,
+    user: UserId,
+    session: Option<&str>,
+) -> Result<UserId, Error> {
+    let invalid = || Error::NotFoundError("Login link is invalid or expired".to_string());
+    let mut tx = persy.begin()?;
+    // Read and mark used in one transaction, so concurrent clicks conflict
+    let id = tx
+        .one::<String, PersyId>(TOKENS_BY_HASH_INDEX, &hash(token))?
+        .ok_or_else(invalid)?;
+    let mut record = storage::read_in_tx::<LoginToken>(&mut tx, TOKENS_SEGMENT, &id)?
+        .filter(|record| !record.used && record.expires > crate::unix_time())
+        .ok_or_else(invalid)?;
+    record.used = true;
+    storage::update_in_tx(&mut tx, TOKENS_SEGMENT, &id, &record)?;
+    if let Some(account) = account(&mut tx, &record.email)? {
+        tx.prepare()?.commit()?;
+        return Ok(account.user);
+    }
+    let same_session = session
+        .map(hash)
+        .is_some_and(|session| record.requested_session.as_ref() == Some(&session));
+    let account = EmailAccount {
+        email: record.email,
+        user: if same_session {
+            record.requested_by
+        } else {
+            user
+        },
+        created: crate::unix_time(),
+    };
+    let account_id = storage::insert_in_tx(&mut tx, ACCOUNTS_SEGMENT, &account)?;
+    tx.put(ACCOUNTS_BY_EMAIL_INDEX, account.email.clone(), account_id)?;
+    tx.prepare()?.commit()?;
+    println!("Email account of user {} created", account.user);
+    Ok(account.user)
+}
+
+// Remove expired and used tokens, called by compaction
+pub fn remove_expired(persy: &Persy) -> Result<usize, Error> {
+    let now = crate::unix_time();
+    let expired: Vec<_> = storage::scan::<LoginToken>(persy, TOKENS_SEGMENT)?
+        .into_iter()
+        .filter(|(_, token)| token.used || token.expires <= now)
+        .collect();
+    let mut tx = persy.begin()?;
+    for (id, token) in &expired {
+        tx.remove(TOKENS_BY_HASH_INDEX, token.hash.clone(), Some(*id))?;
+        tx.delete(TOKENS_SEGMENT, id)?;
+    }
+    tx.prepare()?.commit()?;
+    Ok(expired.len())
+}
+
+// Send login link to the address
+#[post("/auth/email", data = "<form>")]
+fn request_link(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    login: &State<Option<EmailLogin>>,
+    form: Form<EmailLoginForm>,
+) -> Result<Json<LinkSent>, Error> {
+    let login = login
+        .as_ref()
+        .ok_or_else(|| Error::NotFoundError("Email login is not enabled".to_string()))?;
+    let email = normalize(&form.email)?;
+    let user_id = crate::user_id(cookie_jar, matches);
+    let session = sessions::current_token(cookie_jar).map(|token| hash(&token));
+    Ok(Json(login.send_link(
+        &db.read(),
+        email,
+        user_id,
+        session,
+    )?))
+}
+
+// Login link, logs the device in and redirects to the game
+#[get("/auth/email/<token>")]
+fn follow_link(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    device: Device,
+    token: &str,
+) -> Result<Redirect, Error> {
+    let persy = &*db.read();
+    let user_id = crate::user_id(cookie_jar, matches);
+    let session = sessions::current_token(cookie_jar);
+    let user = redeem(persy, token, user_id, session.as_deref())?;
+    sessions::login(persy, cookie_jar, user, device)?;
+    Ok(Redirect::to("/"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![request_link, follow_link]
+}
+
+#[cfg(test)]
+mod tests {
+    use persy::OpenOptions;
+
+    use super::*;
+
+    fn database() -> Persy {
+        let persy = OpenOptions::new().memory().unwrap();
+        init(&persy).unwrap();
+        persy
+    }
+
+    // Token of link requested by the user in the session
+    fn link(persy: &Persy, email: &str, requested_by: UserId, session: &str) -> String {
+        let token = new_token();
+        let now = crate::unix_time();
+        let record = LoginToken {
+            hash: hash(&token),
+            email: email.to_string(),
+            requested_by,
+            requested_session: Some(hash(session)),
+            created: now,
+            expires: now + TOKEN_TTL,
+            used: false,
+        };
+        storage::insert_with(persy, TOKENS_SEGMENT, &record, |tx, id| {
+            tx.put(TOKENS_BY_HASH_INDEX, record.hash.clone(), *id)?;
+            Ok(())
+        })
+        .unwrap();
+        token
+    }
+
+    #[test]
+    fn address_is_linked_to_player_following_link() {
+        let persy = database();
+        let (attacker, victim) = (UserId(1), UserId(2));
+        // Link requested for somebody else's address is followed in their own session
+        let token = link(&persy, "victim@example.com", attacker, "attacker-session");
+        let user = redeem(&persy, &token, victim, Some("victim-session")).unwrap();
+        assert_eq!(user, victim);
+        assert_eq!(
+            account_of_user(&persy, victim).unwrap().unwrap().email,
+            "victim@example.com"
+        );
+        assert!(account_of_user(&persy, attacker).unwrap().is_none());
+        // Linked address logs in as it's player whoever requested the link
+        let token = link(&persy, "victim@example.com", attacker, "attacker-session");
+        let user = redeem(&persy, &token, attacker, Some("attacker-session")).unwrap();
+        assert_eq!(user, victim);
+    }
+
+    #[test]
+    fn address_is_linked_to_requester_in_same_session() {
+        let persy = database();
+        let token = link(&persy, "player@example.com", UserId(1), "session");
+        assert_eq!(
+            redeem(&persy, &token, UserId(1), Some("session")).unwrap(),
+            UserId(1)
+        );
+        assert_eq!(
+            account_of_user(&persy, UserId(1)).unwrap().unwrap().email,
+            "player@example.com"
+        );
+    }
+
+    #[test]
+    fn link_works_once() {
+        let persy = database();
+        let token = link(&persy, "player@example.com", UserId(1), "session");
+        redeem(&persy, &token, UserId(1), Some("session")).unwrap();
+        assert!(matches!(
+            redeem(&persy, &token, UserId(1), Some("session")),
+            Err(Error::NotFoundError(_))
+        ));
+        assert!(matches!(
+            redeem(&persy, "unknown", UserId(1), None),
+            Err(Error::NotFoundError(_))
+        ));
+    }
+}
@@ -0,0 +1,82 @@
+use rocket::{
+    get,
+    response::{self, Responder},
+    routes,
+    serde::json::Json,
+    Config, Request, Route, State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+
+use crate::{
+    ids::UserId,
+    tetris::CompactGameState,
+    views::{Projection, View},
+    TetrisMatches,
+};
+
+//
+// Embeddable widget showing live board of a player, for streamers and blogs. /embed/<user>
+// is a minimal page to be put into an iframe of any site, it polls /embed/<user>/state,
+// a compact read-only state of the player's versus game. Both are allowed to be framed by
+// sites of embed_frame_ancestors config key and the state can be fetched cross-origin.
+// Live boards are public already, widget shows the same ones
+//
+
+// Sources allowed to frame the widget, Content-Security-Policy syntax
+const DEFAULT_FRAME_ANCESTORS: &str = "*";
+
+#[derive(Serialize)]
+pub struct EmbedState {
+    pub user: UserId,
+    // Player is in a versus match now
+    pub live: bool,
+    pub board: Option<CompactGameState>,
+    pub opponent: Option<UserId>,
+    pub opponent_board: Option<CompactGameState>,
+}
+
+// Response which may be framed and fetched by other sites. Browsers ignore
+// X-Frame-Options of Rocket's shield when frame-ancestors is present
+pub struct Embeddable<R>(R);
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for Embeddable<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let frame_ancestors = Config::figment()
+            .extract_inner::<String>("embed_frame_ancestors")
+            .unwrap_or_else(|_| DEFAULT_FRAME_ANCESTORS.to_string());
+        let mut response = self.0.respond_to(request)?;
+        response.set_raw_header(
+            "Content-Security-Policy",
+            format!("frame-ancestors {}", frame_ancestors),
+        );
+        response.set_raw_header("Access-Control-Allow-Origin", "*");
+        Ok(response)
+    }
+}
+
+#[get("/embed/<user_id>")]
+fn widget(user_id: UserId) -> Embeddable<Template> {
+    Embeddable(Template::render("embed", context! { user_id }))
+}
+
+// Published state of the match, read without locks of the matches, see published
+#[get("/embed/<user_id>/state")]
+fn state(matches: &State<TetrisMatches>, user_id: UserId) -> Embeddable<Json<EmbedState>> {
+    let published = matches
+        .published_state(user_id)
+        .map(|(opponent, state)| (opponent, state.project(View::spectator(false))));
+    Embeddable(Json(EmbedState {
+        user: user_id,
+        live: published.is_some(),
+        board: published.as_ref().map(|(_, state)| state.player.compact()),
+        opponent: published.as_ref().map(|(opponent, _)| *opponent),
+        opponent_board: published
+            .as_ref()
+            .map(|(_, state)| state.opponent.compact()),
+    }))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![widget, state]
+}
@@ -1,14 +1,39 @@
 use persy::PersyError;
+use rocket::{
+    http::Status,
+    response::{self, status, Responder},
+    serde::json::serde_json,
+    Request,
+};
 
 // Define own error type
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     // Error type for Persy database errors
     PersyDatabaseError(persy::PersyError),
     // Error type for rocket errors
-    RocketError(rocket::Error),
+    RocketError(Box<rocket::Error>),
     // Error type for io::Result errors
     IoError(std::io::Error),
+    // Error type for json (de)serialization errors
+    SerdeJsonError(serde_json::Error),
+    // Request data is rejected by validation
+    InvalidInputError(String),
+    // Requested object doesn't exist
+    NotFoundError(String),
+    // Client sends requests too often
+    RateLimitError(String),
+    // Error type for outgoing http requests errors
+    HttpClientError(reqwest::Error),
+    // Error type for png encoding errors
+    ImageEncodingError(png::EncodingError),
+    // Error type for smtp errors of sent emails
+    SmtpError(lettre::transport::smtp::Error),
+    // Error type for server key errors of result signatures
+    SigningError(String),
+    // Error type for certificate provisioning errors of ACME client
+    AcmeError(String),
 }
 
 impl<T: Into<PersyError>> From<persy::PE<T>> for Error {
@@ -27,7 +52,7 @@ impl From<persy::PersyError> for Error {
 
 impl From<rocket::Error> for Error {
     fn from(err: rocket::Error) -> Self {
-        Error::RocketError(err)
+        Error::RocketError(Box::new(err))
     }
 }
 
@@ -37,6 +62,42 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::SerdeJsonError(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::HttpClientError(err)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for Error {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        Error::SmtpError(err)
+    }
+}
+
+impl From<ring::error::Unspecified> for Error {
+    fn from(err: ring::error::Unspecified) -> Self {
+        Error::SigningError(err.to_string())
+    }
+}
+
+impl From<ring::error::KeyRejected> for Error {
+    fn from(err: ring::error::KeyRejected) -> Self {
+        Error::SigningError(err.to_string())
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(err: png::EncodingError) -> Self {
+        Error::ImageEncodingError(err)
+    }
+}
+
 // Implement display trait for error type
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -44,6 +105,28 @@ impl std::fmt::Display for Error {
             Error::PersyDatabaseError(err) => write!(f, "Persy database error: {}", err),
             Error::RocketError(err) => write!(f, "Rocket error: {}", err),
             Error::IoError(err) => write!(f, "Io error: {}", err),
+            Error::SerdeJsonError(err) => write!(f, "Json error: {}", err),
+            Error::InvalidInputError(msg) => write!(f, "Invalid input: {}", msg),
+            Error::NotFoundError(msg) => write!(f, "Not found: {}", msg),
+            Error::RateLimitError(msg) => write!(f, "Rate limit exceeded: {}", msg),
+            Error::HttpClientError(err) => write!(f, "Http client error: {}", err),
+            Error::ImageEncodingError(err) => write!(f, "Image encoding error: {}", err),
+            Error::SmtpError(err) => write!(f, "Smtp error: {}", err),
+            Error::SigningError(msg) => write!(f, "Signing error: {}", msg),
+            Error::AcmeError(msg) => write!(f, "Acme error: {}", msg),
         }
     }
 }
+
+// Allow handlers to return Result<_, Error>, map error kind to HTTP status
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = match self {
+            Error::InvalidInputError(_) => Status::BadRequest,
+            Error::NotFoundError(_) => Status::NotFound,
+            Error::RateLimitError(_) => Status::TooManyRequests,
+            _ => Status::InternalServerError,
+        };
+        status::Custom(status, self.to_string()).respond_to(request)
+    }
+}
@@ -32,7 +32,7 @@ impl EventRegulator {
 
     pub fn step(&mut self) -> usize {
         let events_to_generate_this_step =
-            (self.m * (self.current_step + 1) + self.n - 1) / self.n - self.events_generated;
+            (self.m * (self.current_step + 1)).div_ceil(self.n) - self.events_generated;
 
         self.events_generated += events_to_generate_this_step;
 
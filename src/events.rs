@@ -0,0 +1,216 @@
+use gameserver_protocol::events::Envelope;
+use rocket::{
+    futures::stream::{self, BoxStream, StreamExt},
+    get,
+    http::CookieJar,
+    response::stream::{Event, EventStream},
+    routes,
+    serde::json::{serde_json, Value},
+    Route, State,
+};
+
+use crate::{
+    admission::Admitted,
+    connections::Connections,
+    error::Error,
+    ids::UserId,
+    input_sequence::{InputSequences, InputTarget},
+    latency::Latency,
+    maintenance::Maintenance,
+    notifications::Notifications,
+    spotlight::{self, Spotlight},
+    views::View,
+    visibility::Pauses,
+    write_queue::WriteQueue,
+    TetrisMatches,
+};
+
+//
+// Multiplexed event stream. Client subscribes to several channels with one connection,
+// each event is sent as envelope with channel, event name and payload. Payload is json data
+// of the event as it's sent by the channel's own stream, or a string for non-json data
+//
+
+pub use gameserver_protocol::events::Channel;
+
+// Event of one channel, before it's sent as separate stream's event or in envelope
+pub struct ChannelEvent {
+    // Event name, None for default "message" events
+    pub event: Option<&'static str>,
+    pub data: String,
+}
+
+// State of game streams, shared by channels of event stream and long polls
+pub struct Sources<'b> {
+    pub matches: &'b TetrisMatches,
+    pub writes: &'b WriteQueue,
+    pub latency: &'b Latency,
+    pub sequences: &'b InputSequences,
+    pub maintenance: &'b Maintenance,
+    pub pauses: &'b Pauses,
+    pub spotlight: &'b Spotlight,
+    pub notifications: &'b Notifications,
+}
+
+impl ChannelEvent {
+    pub fn message(data: String) -> ChannelEvent {
+        ChannelEvent { event: None, data }
+    }
+
+    pub fn named(event: &'static str, data: String) -> ChannelEvent {
+        ChannelEvent {
+            event: Some(event),
+            data,
+        }
+    }
+
+    // Event of channel's own stream
+    pub fn into_event(self) -> Event {
+        match self.event {
+            Some(event) => Event::data(self.data).event(event),
+            None => Event::data(self.data),
+        }
+    }
+
+    // Default event with envelope of the channel's event as data
+    pub fn into_envelope(self, channel: Channel) -> ChannelEvent {
+        let payload = serde_json::from_str(&self.data).unwrap_or(Value::String(self.data));
+        ChannelEvent::message(
+            serde_json::to_string(&Envelope {
+                channel,
+                event: self.event.unwrap_or("message").to_string(),
+                payload,
+            })
+            .unwrap(),
+        )
+    }
+}
+
+// Parse comma separated list of channels, duplicates are skipped
+pub fn parse_channels(channels: &str) -> Result<Vec<Channel>, Error> {
+    let mut subscribed = Vec::new();
+    for name in channels.split(',').map(str::trim) {
+        let channel = Channel::parse(name)
+            .ok_or_else(|| Error::InvalidInputError(format!("Unknown channel {}", name)))?;
+        if !subscribed.contains(&channel) {
+            subscribed.push(channel);
+        }
+    }
+    Ok(subscribed)
+}
+
+// Channels joined into connection name
+pub fn connection_name(channels: &[Channel]) -> String {
+    channels
+        .iter()
+        .map(Channel::name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Events of the channel, user is taken when the channel needs one. Sessions not admitted
+// get "admission" event instead of the game. New games are not started during maintenance,
+// "maintenance" event is sent instead, running game goes on
+pub fn channel_stream<'b>(
+    sources: &Sources<'b>,
+    channel: Channel,
+    mut user: impl FnMut() -> UserId,
+    admitted: bool,
+    hide_queue: bool,
+) -> Result<BoxStream<'b, ChannelEvent>, Error> {
+    Ok(match channel {
+        Channel::Game => {
+            if !admitted {
+                let refusal = ChannelEvent::named("admission", String::new());
+                return Ok(stream::once(async move { refusal }).boxed());
+            }
+            let user_id = user();
+            let matches = sources.matches;
+            if let (false, Some(status)) =
+                (matches.has_match(user_id), sources.maintenance.status())
+            {
+                let refusal = ChannelEvent::named("maintenance", serde_json::to_string(&status)?);
+                return Ok(stream::once(async move { refusal }).boxed());
+            }
+            let epoch = sources.sequences.new_epoch(user_id, InputTarget::Versus);
+            crate::game_stream(
+                user_id,
+                epoch,
+                matches,
+                sources.writes,
+                sources.latency,
+                sources.sequences,
+                sources.maintenance,
+                sources.pauses,
+            )
+            .boxed()
+        }
+        Channel::Spotlight => {
+            spotlight::spotlight_stream(sources.spotlight, View::spectator(hide_queue)).boxed()
+        }
+        Channel::Notifications => sources.notifications.stream(user()).boxed(),
+    })
+}
+
+// Events of subscribed channels, given as comma separated list. Spotlight channel hides
+// upcoming pieces with hide_queue
+#[get("/events?<channels>&<hide_queue>")]
+#[allow(clippy::too_many_arguments)]
+fn events<'b>(
+    cookie_jar: &CookieJar,
+    matches: &'b State<TetrisMatches>,
+    writes: &'b State<WriteQueue>,
+    latency: &'b State<Latency>,
+    sequences: &'b State<InputSequences>,
+    maintenance: &'b State<Maintenance>,
+    pauses: &'b State<Pauses>,
+    spotlight: &'b State<Spotlight>,
+    notifications: &'b State<Notifications>,
+    connections: &State<Connections>,
+    admitted: Option<Admitted>,
+    channels: &str,
+    hide_queue: Option<bool>,
+) -> Result<EventStream![Event + 'b], Error> {
+    let subscribed = parse_channels(channels)?;
+    let sources = Sources {
+        matches,
+        writes,
+        latency,
+        sequences,
+        maintenance,
+        pauses,
+        spotlight,
+        notifications,
+    };
+    let mut streams = Vec::new();
+    let mut user = None;
+    for channel in subscribed.iter().copied() {
+        // Connection is listed with the user of channels which need one
+        let stream = channel_stream(
+            &sources,
+            channel,
+            || {
+                let user_id = crate::user_id(cookie_jar, matches);
+                user = Some(user_id);
+                user_id
+            },
+            admitted.is_some(),
+            hide_queue.unwrap_or(false),
+        )?;
+        streams.push(
+            stream
+                .map(move |event| event.into_envelope(channel))
+                .boxed(),
+        );
+    }
+    let events = connections.track(
+        user,
+        format!("events/{}", connection_name(&subscribed)),
+        stream::select_all(streams),
+    );
+    Ok(EventStream::from(events.map(ChannelEvent::into_event)))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![events]
+}
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+
+use rocket::{get, routes, serde::json::Json, Route, State};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    game_mode::GameMode,
+    game_rng::RngKind,
+    ids::{GameId, UserId},
+    leaderboard, replays,
+    roles::Viewer,
+    storage::{self, Database},
+    tetris::{Randomizer, Tetris, TetrominoType},
+};
+
+//
+// Fairness audit: pieces dealt in recent stored games, recounted by re-simulating replays.
+// Games are grouped by randomizer and random source, each group gets chi-squared statistic
+// of piece counts against uniform distribution
+//
+
+const DEFAULT_GAMES: usize = 100;
+const MAX_GAMES: usize = 1000;
+
+// Chi-squared value for 6 degrees of freedom, exceeded by unbiased source with 5% probability
+const CHI_SQUARED_CRITICAL: f64 = 12.592;
+
+#[derive(Serialize)]
+pub struct AuditedGame {
+    pub entry: GameId,
+    pub user: UserId,
+    pub mode: GameMode,
+    pub seed: u64,
+    pub randomizer: Randomizer,
+    pub rng: RngKind,
+    pub pieces: u64,
+}
+
+#[derive(Serialize)]
+pub struct PieceDistribution {
+    pub randomizer: Randomizer,
+    pub rng: RngKind,
+    pub games: usize,
+    pub pieces: u64,
+    pub counts: BTreeMap<String, u64>,
+    pub chi_squared: f64,
+    // Distribution differs from uniform with 95% confidence
+    pub biased: bool,
+}
+
+#[derive(Serialize)]
+pub struct FairnessReport {
+    pub chi_squared_critical: f64,
+    pub distributions: Vec<PieceDistribution>,
+    pub games: Vec<AuditedGame>,
+}
+
+fn distribution(
+    randomizer: Randomizer,
+    rng: RngKind,
+    games: usize,
+    counts: [u64; 7],
+) -> PieceDistribution {
+    let pieces = counts.iter().sum::<u64>();
+    let expected = pieces as f64 / counts.len() as f64;
+    let chi_squared = if pieces == 0 {
+        0.
+    } else {
+        counts
+            .iter()
+            .map(|count| (*count as f64 - expected).powi(2) / expected)
+            .sum()
+    };
+    PieceDistribution {
+        randomizer,
+        rng,
+        games,
+        pieces,
+        counts: TetrominoType::ALL
+            .iter()
+            .zip(counts)
+            .map(|(piece, count)| (format!("{:?}", piece), count))
+            .collect(),
+        chi_squared,
+        biased: chi_squared > CHI_SQUARED_CRITICAL,
+    }
+}
+
+// Piece distribution statistics of the latest games with replays
+#[get("/admin/fairness?<games>")]
+fn admin_fairness(
+    _admin: Viewer,
+    db: &State<Database>,
+    games: Option<usize>,
+) -> Result<Json<FairnessReport>, Error> {
+    let persy = &*db.read();
+    let limit = games.unwrap_or(DEFAULT_GAMES).clamp(1, MAX_GAMES);
+    let mut groups = BTreeMap::<(String, String), (Randomizer, RngKind, usize, [u64; 7])>::new();
+    let mut audited = Vec::new();
+    for (id, entry) in leaderboard::recent(persy, limit)? {
+        let Some(replay_id) = &entry.replay else {
+            continue;
+        };
+        let Some(replay) = replays::read(persy, &storage::parse_id(replay_id)?)? else {
+            continue;
+        };
+        let counts = Tetris::from_replay(&replay).get_piece_counts();
+        let key = (
+            format!("{:?}", replay.randomizer),
+            format!("{:?}", replay.rng),
+        );
+        let group = groups
+            .entry(key)
+            .or_insert((replay.randomizer, replay.rng, 0, [0; 7]));
+        group.2 += 1;
+        for (total, count) in group.3.iter_mut().zip(counts) {
+            *total += count;
+        }
+        audited.push(AuditedGame {
+            entry: GameId(id),
+            user: entry.user,
+            mode: entry.mode,
+            seed: replay.seed,
+            randomizer: replay.randomizer,
+            rng: replay.rng,
+            pieces: counts.iter().sum(),
+        });
+    }
+    Ok(Json(FairnessReport {
+        chi_squared_critical: CHI_SQUARED_CRITICAL,
+        distributions: groups
+            .into_values()
+            .map(|(randomizer, rng, games, counts)| distribution(randomizer, rng, games, counts))
+            .collect(),
+        games: audited,
+    }))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_fairness]
+}
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{delete, get, http::CookieJar, post, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    notifications::{NotificationKind, Notifications},
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Friends of users. A user adds another one with POST /friends/<user>, who gets a friend
+// request notification, they are friends once both added each other. Listings take
+// friends=true to show friends of the user only. Links are stored in "friends" segment
+// and kept in memory
+//
+
+const FRIENDS_SEGMENT: &str = "friends";
+const BY_PAIR_INDEX: &str = "friends_by_pair";
+
+// User added friend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FriendLink {
+    pub user: UserId,
+    pub friend: UserId,
+    // Seconds since unix epoch
+    pub created: u64,
+}
+
+#[derive(Serialize)]
+pub struct FriendList {
+    pub friends: Vec<UserId>,
+    // Users who added the user, not added back yet
+    pub requests: Vec<UserId>,
+    // Users added by the user, who didn't add the user yet
+    pub pending: Vec<UserId>,
+}
+
+// Users added by each user
+pub struct Friends(RwLock<HashMap<UserId, HashSet<UserId>>>);
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, FRIENDS_SEGMENT)?;
+    storage::ensure_index::<u64, PersyId>(persy, BY_PAIR_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+// Users passing both filters, None when neither of them filters users
+pub fn both(
+    users: Option<HashSet<UserId>>,
+    others: Option<HashSet<UserId>>,
+) -> Option<HashSet<UserId>> {
+    match (users, others) {
+        (Some(users), Some(others)) => Some(users.intersection(&others).copied().collect()),
+        (users, others) => users.or(others),
+    }
+}
+
+fn pair_key(user: UserId, friend: UserId) -> u64 {
+    (user.0 as u64) << 32 | friend.0 as u64
+}
+
+impl Friends {
+    pub fn load(persy: &Persy) -> Result<Friends, Error> {
+        let mut added = HashMap::<UserId, HashSet<UserId>>::new();
+        for (_, link) in storage::scan::<FriendLink>(persy, FRIENDS_SEGMENT)? {
+            added.entry(link.user).or_default().insert(link.friend);
+        }
+        Ok(Friends(RwLock::new(added)))
+    }
+
+    fn added(&self, user: UserId, friend: UserId) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .get(&user)
+            .is_some_and(|added| added.contains(&friend))
+    }
+
+    // Users who added each other with the user
+    pub fn friends_of(&self, user: UserId) -> HashSet<UserId> {
+        let added = self.0.read().unwrap();
+        added
+            .get(&user)
+            .into_iter()
+            .flatten()
+            .filter(|friend| added.get(friend).is_some_and(|added| added.contains(&user)))
+            .copied()
+            .collect()
+    }
+
+    // Friends of the signed in user when listing is limited to friends. User without
+    // user id cookie has no friends
+    pub fn filter(&self, cookie_jar: &CookieJar, friends: Option<bool>) -> Option<HashSet<UserId>> {
+        if friends != Some(true) {
+            return None;
+        }
+        let user = cookie_jar
+            .get_pending("user_id")
+            .and_then(|cookie| cookie.value().parse::<UserId>().ok());
+        Some(user.map(|user| self.friends_of(user)).unwrap_or_default())
+    }
+
+    fn list(&self, user: UserId) -> FriendList {
+        let added = self.0.read().unwrap();
+        let by_user = added.get(&user);
+        let mut friends = Vec::new();
+        let mut pending = Vec::new();
+        for friend in by_user.into_iter().flatten() {
+            if added.get(friend).is_some_and(|added| added.contains(&user)) {
+                friends.push(*friend);
+            } else {
+                pending.push(*friend);
+            }
+        }
+        let mut requests = added
+            .iter()
+            .filter(|(other, added)| {
+                added.contains(&user) && !by_user.is_some_and(|by_user| by_user.contains(other))
+            })
+            .map(|(other, _)| *other)
+            .collect::<Vec<_>>();
+        friends.sort();
+        pending.sort();
+        requests.sort();
+        FriendList {
+            friends,
+            requests,
+            pending,
+        }
+    }
+
+    // Add friend of the user, the friend is notified unless the user accepts their request
+    fn add(
+        &self,
+        persy: &Persy,
+        notifications: &Notifications,
+        user: UserId,
+        friend: UserId,
+    ) -> Result<(), Error> {
+        if user == friend {
+            return Err(Error::InvalidInputError(
+                "Users can't add themselves as friends".to_string(),
+            ));
+        }
+        if self.added(user, friend) {
+            return Ok(());
+        }
+        let link = FriendLink {
+            user,
+            friend,
+            created: crate::unix_time(),
+        };
+        let mut tx = persy.begin()?;
+        let id = storage::insert_in_tx(&mut tx, FRIENDS_SEGMENT, &link)?;
+        tx.put(BY_PAIR_INDEX, pair_key(user, friend), id)?;
+        tx.prepare()?.commit()?;
+        self.0
+            .write()
+            .unwrap()
+            .entry(user)
+            .or_default()
+            .insert(friend);
+        if !self.added(friend, user) {
+            notifications.notify(
+                persy,
+                friend,
+                NotificationKind::FriendRequest,
+                format!("User {} wants to be friends", user),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, persy: &Persy, user: UserId, friend: UserId) -> Result<(), Error> {
+        let key = pair_key(user, friend);
+        if let Some(id) = persy.one::<u64, PersyId>(BY_PAIR_INDEX, &key)? {
+            let mut tx = persy.begin()?;
+            tx.delete(FRIENDS_SEGMENT, &id)?;
+            tx.remove(BY_PAIR_INDEX, key, Some(id))?;
+            tx.prepare()?.commit()?;
+        }
+        if let Some(added) = self.0.write().unwrap().get_mut(&user) {
+            added.remove(&friend);
+        }
+        Ok(())
+    }
+}
+
+// Friends of the user, friend requests to answer and requests of the user
+#[get("/friends")]
+fn friends(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    friends: &State<Friends>,
+) -> Json<FriendList> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Json(friends.list(user_id))
+}
+
+// Add the friend or accept their request
+#[post("/friends/<friend>")]
+fn add_friend(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    friends: &State<Friends>,
+    notifications: &State<Notifications>,
+    friend: UserId,
+) -> Result<(), Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    friends.add(&db.read(), notifications, user_id, friend)
+}
+
+// Remove the friend or cancel the request to them
+#[delete("/friends/<friend>")]
+fn remove_friend(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    friends: &State<Friends>,
+    friend: UserId,
+) -> Result<(), Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    friends.remove(&db.read(), user_id, friend)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![friends, add_friend, remove_friend]
+}
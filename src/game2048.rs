@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    get,
+    http::CookieJar,
+    post,
+    request::FromParam,
+    routes,
+    serde::json::{serde_json, Json},
+    Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admission::Admitted,
+    daily_quotas::{DailyQuota, QuotaKind},
+    error::Error,
+    game_rng::{GameRng, RngKind},
+    games::{GamePlugin, GameType, SessionStatus},
+    ids::UserId,
+    pagination::{self, Page, SortOrder},
+    quarantine::{self, Quarantine, QuarantinedGame, QuarantinedMode},
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// 2048: single player slides tiles of a 4x4 grid, equal tiles meeting on the way merge
+// into their sum, which is added to the score. A new tile spawns after every move which
+// changes the grid, game is over when no move does. Spawns take randomness only from
+// the game's seeded random source and there is no undo, so the seed and moves reproduce
+// the game: finished games are re-played before they are recorded in the 2048 leaderboard
+// and stored with seed and moves for later audits. Running game of a user is kept in memory,
+// game whose move panics is quarantined with it's seed and moves
+//
+
+pub const SIZE: usize = 4;
+
+const RESULTS_SEGMENT: &str = "game2048_results";
+const BY_SCORE_INDEX: &str = "game2048_results_by_score";
+
+// Chance of spawned tile being 4 instead of 2, in percents
+const FOUR_CHANCE: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl<'a> FromParam<'a> for Direction {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        match param {
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            "left" => Ok(Direction::Left),
+            "right" => Ok(Direction::Right),
+            _ => Err(param),
+        }
+    }
+}
+
+pub struct Game2048 {
+    // Rows from top to bottom, 0 is empty cell
+    grid: [[u32; SIZE]; SIZE],
+    score: u64,
+    seed: u64,
+    rng_kind: RngKind,
+    rng: Box<dyn GameRng>,
+    moves: Vec<Direction>,
+    // Start time, seconds since unix epoch
+    started: u64,
+}
+
+#[derive(Serialize)]
+pub struct Game2048State {
+    pub grid: [[u32; SIZE]; SIZE],
+    pub score: u64,
+    pub moves: usize,
+    pub max_tile: u32,
+    pub game_over: bool,
+    // Leaderboard id of the result, once game is over and recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+}
+
+// Finished game, reproducible from seed and moves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Game2048Result {
+    pub user: UserId,
+    pub score: u64,
+    pub max_tile: u32,
+    pub seed: u64,
+    pub rng: RngKind,
+    pub moves: Vec<Direction>,
+    // Seconds since unix epoch
+    pub started: u64,
+    pub finished: u64,
+}
+
+#[derive(Serialize)]
+pub struct Game2048Item {
+    pub id: String,
+    pub user: UserId,
+    pub score: u64,
+    pub max_tile: u32,
+    pub moves: usize,
+    pub finished: u64,
+}
+
+impl Game2048 {
+    pub fn new(seed: u64, rng_kind: RngKind) -> Game2048 {
+        let mut game = Game2048 {
+            grid: [[0; SIZE]; SIZE],
+            score: 0,
+            seed,
+            rng_kind,
+            rng: rng_kind.seeded(seed),
+            moves: Vec::new(),
+            started: crate::unix_time(),
+        };
+        game.spawn();
+        game.spawn();
+        game
+    }
+
+    // Game with random seed. Seed is logged, so the game can be audited
+    pub fn random(rng_kind: RngKind) -> Game2048 {
+        let seed = rand::random();
+        println!("New 2048 game: seed {}, {:?} random source", seed, rng_kind);
+        Game2048::new(seed, rng_kind)
+    }
+
+    // Play moves of a stored game again from it's seed
+    pub fn replay(seed: u64, rng_kind: RngKind, moves: &[Direction]) -> Game2048 {
+        let mut game = Game2048::new(seed, rng_kind);
+        for direction in moves {
+            game.play(*direction);
+        }
+        game
+    }
+
+    // New tile in a random empty cell
+    fn spawn(&mut self) {
+        let empty = (0..SIZE * SIZE)
+            .filter(|cell| self.grid[cell / SIZE][cell % SIZE] == 0)
+            .collect::<Vec<_>>();
+        if empty.is_empty() {
+            return;
+        }
+        let cell = empty[self.rng.next_u32() as usize % empty.len()];
+        let value = if self.rng.next_u32() % 100 < FOUR_CHANCE {
+            4
+        } else {
+            2
+        };
+        self.grid[cell / SIZE][cell % SIZE] = value;
+    }
+
+    // Grid cell of i-th position of a line, lines are walked in direction of the move
+    // starting from the edge tiles slide to
+    fn cell(direction: Direction, line: usize, i: usize) -> (usize, usize) {
+        match direction {
+            Direction::Left => (line, i),
+            Direction::Right => (line, SIZE - 1 - i),
+            Direction::Up => (i, line),
+            Direction::Down => (SIZE - 1 - i, line),
+        }
+    }
+
+    // Slide tiles to the start of the line, merging each tile at most once per move.
+    // Returns points of merges
+    fn slide(line: &mut [u32; SIZE]) -> u64 {
+        let tiles = line.iter().copied().filter(|tile| *tile != 0);
+        let mut slid = [0; SIZE];
+        let mut len = 0;
+        let mut merged = false;
+        let mut points = 0;
+        for tile in tiles {
+            if len > 0 && !merged && slid[len - 1] == tile {
+                slid[len - 1] *= 2;
+                points += slid[len - 1] as u64;
+                merged = true;
+            } else {
+                slid[len] = tile;
+                len += 1;
+                merged = false;
+            }
+        }
+        *line = slid;
+        points
+    }
+
+    // Returns false when the move doesn't change the grid, it's not counted then
+    pub fn play(&mut self, direction: Direction) -> bool {
+        let mut changed = false;
+        for line in 0..SIZE {
+            let mut tiles = [0; SIZE];
+            for (i, tile) in tiles.iter_mut().enumerate() {
+                let (row, col) = Self::cell(direction, line, i);
+                *tile = self.grid[row][col];
+            }
+            let before = tiles;
+            self.score += Self::slide(&mut tiles);
+            if tiles != before {
+                changed = true;
+                for (i, tile) in tiles.iter().enumerate() {
+                    let (row, col) = Self::cell(direction, line, i);
+                    self.grid[row][col] = *tile;
+                }
+            }
+        }
+        if changed {
+            self.moves.push(direction);
+            self.spawn();
+        }
+        changed
+    }
+
+    pub fn is_game_over(&self) -> bool {
+        (0..SIZE).all(|row| {
+            (0..SIZE).all(|col| {
+                let tile = self.grid[row][col];
+                tile != 0
+                    && (col + 1 == SIZE || self.grid[row][col + 1] != tile)
+                    && (row + 1 == SIZE || self.grid[row + 1][col] != tile)
+            })
+        })
+    }
+
+    pub fn max_tile(&self) -> u32 {
+        self.grid.iter().flatten().copied().max().unwrap_or(0)
+    }
+
+    pub fn state(&self) -> Game2048State {
+        Game2048State {
+            grid: self.grid,
+            score: self.score,
+            moves: self.moves.len(),
+            max_tile: self.max_tile(),
+            game_over: self.is_game_over(),
+            result: None,
+        }
+    }
+
+    pub fn result(&self, user: UserId) -> Game2048Result {
+        Game2048Result {
+            user,
+            score: self.score,
+            max_tile: self.max_tile(),
+            seed: self.seed,
+            rng: self.rng_kind,
+            moves: self.moves.clone(),
+            started: self.started,
+            finished: crate::unix_time(),
+        }
+    }
+}
+
+// Running games by user and quarantine of games which panicked
+#[derive(Clone, Default)]
+pub struct Games2048(Arc<RwLock<HashMap<UserId, Game2048>>>, Quarantine);
+
+impl Games2048 {
+    pub fn new(quarantine: Quarantine) -> Games2048 {
+        Games2048(Arc::default(), quarantine)
+    }
+
+    // Remove game which panicked, keeping it's seed and moves and the failing move
+    fn quarantine(
+        &self,
+        games: &mut HashMap<UserId, Game2048>,
+        user_id: UserId,
+        panic: String,
+        direction: Direction,
+    ) {
+        let Some(game) = games.remove(&user_id) else {
+            return;
+        };
+        self.1.add_answered(QuarantinedGame::without_replays(
+            QuarantinedMode::Game2048,
+            vec![user_id],
+            game.started,
+            || serde_json::to_value(game.result(user_id)).ok(),
+            Some(format!("{:?}", direction)),
+            panic,
+        ));
+    }
+
+    pub fn is_running(&self, user_id: UserId) -> bool {
+        self.0.read().unwrap().contains_key(&user_id)
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, RESULTS_SEGMENT)?;
+    storage::ensure_index::<u64, PersyId>(persy, BY_SCORE_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+// Store result of finished game after checking it's reproduced by the seed and moves
+pub fn record(persy: &Persy, result: &Game2048Result) -> Result<PersyId, Error> {
+    let replayed = Game2048::replay(result.seed, result.rng, &result.moves);
+    if replayed.score != result.score || !replayed.is_game_over() {
+        return Err(Error::InvalidInputError(
+            "Game is not reproduced by it's moves".to_string(),
+        ));
+    }
+    storage::insert_with(persy, RESULTS_SEGMENT, result, |tx, id| {
+        tx.put(BY_SCORE_INDEX, result.score, *id)?;
+        Ok(())
+    })
+}
+
+// Start new game, user's running game is dropped. Games count to the daily quota
+#[post("/game2048/new?<rng>")]
+fn new_game(
+    _admitted: Admitted,
+    quota: DailyQuota,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<Games2048>,
+    db: &State<Database>,
+    rng: Option<RngKind>,
+) -> Result<Json<Game2048State>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    quota.consume(&db.read(), user_id, QuotaKind::Games)?;
+    let game = Game2048::random(rng.unwrap_or_default());
+    let state = game.state();
+    games.0.write().unwrap().insert(user_id, game);
+    Ok(Json(state))
+}
+
+// State of user's running game
+#[get("/game2048/state")]
+fn game_state(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<Games2048>,
+) -> Result<Json<Game2048State>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let games = games.0.read().unwrap();
+    let game = games
+        .get(&user_id)
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    Ok(Json(game.state()))
+}
+
+// Slide tiles in direction: up, down, left or right. Game is recorded in the leaderboard
+// and removed when the move ends it
+#[post("/game2048/move/<direction>")]
+fn play_move(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    running: &State<Games2048>,
+    db: &State<Database>,
+    direction: Direction,
+) -> Result<Json<Game2048State>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let mut games = running.0.write().unwrap();
+    let game = games
+        .get_mut(&user_id)
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    let played = quarantine::guard(|| {
+        game.play(direction);
+        let state = game.state();
+        let result = state.game_over.then(|| game.result(user_id));
+        (state, result)
+    });
+    let (mut state, result) = match played {
+        Ok(played) => played,
+        Err(panic) => {
+            running.quarantine(&mut games, user_id, panic, direction);
+            return Err(Error::NotFoundError("Game was quarantined".to_string()));
+        }
+    };
+    if let Some(result) = result {
+        games.remove(&user_id);
+        drop(games);
+        let id = record(&db.read(), &result)?;
+        state.result = Some(id.to_string());
+    }
+    Ok(Json(state))
+}
+
+// Best 2048 scores
+#[get("/game2048/leaderboard?<cursor>&<limit>")]
+fn leaderboard(
+    db: &State<Database>,
+    cursor: Option<&str>,
+    limit: Option<usize>,
+) -> Result<Json<Page<Game2048Item>>, Error> {
+    Ok(Json(pagination::page_by_index(
+        &db.read(),
+        BY_SCORE_INDEX,
+        RESULTS_SEGMENT,
+        SortOrder::Desc,
+        (Bound::<u64>::Unbounded, Bound::Unbounded),
+        cursor,
+        pagination::limit(limit),
+        |_: &Game2048Result| true,
+        |id, result| Game2048Item {
+            id: id.to_string(),
+            user: result.user,
+            score: result.score,
+            max_tile: result.max_tile,
+            moves: result.moves.len(),
+            finished: result.finished,
+        },
+    )?))
+}
+
+// Stored game with seed and moves, for audits
+#[get("/game2048/results/<id>")]
+fn result(db: &State<Database>, id: &str) -> Result<Json<Game2048Result>, Error> {
+    storage::read(&db.read(), RESULTS_SEGMENT, &storage::parse_id(id)?)?
+        .map(Json)
+        .ok_or_else(|| Error::NotFoundError(format!("Result {} not found", id)))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![new_game, game_state, play_move, leaderboard, result]
+}
+
+pub fn plugin() -> GamePlugin {
+    GamePlugin {
+        game_type: GameType {
+            id: "game2048",
+            name: "2048",
+            description: "Slide and merge tiles to reach 2048",
+            entry: "/game2048/new",
+        },
+        init,
+        routes,
+        attach: |rocket| {
+            let quarantine = rocket.state::<Quarantine>().cloned().unwrap_or_default();
+            Ok(rocket.manage(Games2048::new(quarantine)))
+        },
+        session: |rocket, user_id| {
+            let games = rocket.state::<Games2048>()?;
+            SessionStatus::of(false, games.is_running(user_id))
+        },
+        state: |rocket, user_id| {
+            let games = rocket.state::<Games2048>()?.0.read().unwrap();
+            Some(serde_json::to_string(&games.get(&user_id)?.state()).unwrap())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slid(mut line: [u32; SIZE]) -> ([u32; SIZE], u64) {
+        let points = Game2048::slide(&mut line);
+        (line, points)
+    }
+
+    // Game with the grid set, seed and spawns as of a new game
+    fn game(grid: [[u32; SIZE]; SIZE]) -> Game2048 {
+        let mut game = Game2048::new(1, RngKind::Std);
+        game.grid = grid;
+        game
+    }
+
+    fn tiles(game: &Game2048) -> usize {
+        game.grid
+            .iter()
+            .flatten()
+            .filter(|tile| **tile != 0)
+            .count()
+    }
+
+    #[test]
+    fn tiles_slide_and_merge_once_per_move() {
+        assert_eq!(slid([0, 2, 0, 4]), ([2, 4, 0, 0], 0));
+        assert_eq!(slid([2, 2, 0, 0]), ([4, 0, 0, 0], 4));
+        assert_eq!(slid([2, 0, 0, 2]), ([4, 0, 0, 0], 4));
+        assert_eq!(slid([2, 2, 2, 0]), ([4, 2, 0, 0], 4));
+        assert_eq!(slid([2, 2, 2, 2]), ([4, 4, 0, 0], 8));
+        // Merged tile doesn't merge again in the same move
+        assert_eq!(slid([2, 2, 4, 0]), ([4, 4, 0, 0], 4));
+        assert_eq!(slid([4, 2, 2, 0]), ([4, 4, 0, 0], 4));
+        assert_eq!(slid([4, 4, 8, 8]), ([8, 16, 0, 0], 24));
+        assert_eq!(slid([0, 0, 0, 0]), ([0, 0, 0, 0], 0));
+    }
+
+    #[test]
+    fn moves_slide_towards_their_edge() {
+        let grid = [[2, 2, 0, 4], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 4]];
+        let mut left = game(grid);
+        assert!(left.play(Direction::Left));
+        assert_eq!(left.grid[0], [4, 4, 0, 0]);
+        let mut right = game(grid);
+        assert!(right.play(Direction::Right));
+        assert_eq!(right.grid[0], [0, 0, 4, 4]);
+        let mut down = game(grid);
+        assert!(down.play(Direction::Down));
+        assert_eq!(down.grid[3], [2, 2, 0, 8]);
+        let mut up = game(grid);
+        assert!(up.play(Direction::Up));
+        assert_eq!(up.grid[0], [2, 2, 0, 8]);
+        assert_eq!(
+            (left.score, right.score, down.score, up.score),
+            (4, 4, 8, 8)
+        );
+    }
+
+    #[test]
+    fn move_changing_nothing_spawns_no_tile() {
+        let grid = [[2, 4, 0, 0], [4, 2, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]];
+        let mut game = game(grid);
+        assert!(!game.play(Direction::Left));
+        assert!(!game.play(Direction::Up));
+        assert_eq!(game.grid, grid);
+        assert!(game.moves.is_empty());
+        assert!(game.play(Direction::Right));
+        assert_eq!(tiles(&game), 5);
+        assert_eq!(game.moves, [Direction::Right]);
+        assert_eq!(game.score, 0);
+    }
+
+    #[test]
+    fn merge_spawns_one_tile() {
+        let mut game = game([[2, 2, 0, 0], [0; SIZE], [0; SIZE], [0; SIZE]]);
+        assert!(game.play(Direction::Left));
+        assert_eq!(tiles(&game), 2);
+        assert_eq!(game.score, 4);
+        assert_eq!(game.max_tile(), 4);
+    }
+
+    #[test]
+    fn game_is_over_without_moves() {
+        let full = [[2, 4, 2, 4], [4, 2, 4, 2], [2, 4, 2, 4], [4, 2, 4, 2]];
+        let mut over = game(full);
+        assert!(over.is_game_over());
+        assert!(!over.play(Direction::Left));
+        let mut mergeable = full;
+        mergeable[3][3] = 4;
+        assert!(!game(mergeable).is_game_over());
+        let mut empty_cell = full;
+        empty_cell[0][0] = 0;
+        assert!(!game(empty_cell).is_game_over());
+    }
+
+    #[test]
+    fn seed_and_moves_reproduce_game() {
+        let directions = [
+            Direction::Left,
+            Direction::Down,
+            Direction::Right,
+            Direction::Up,
+        ];
+        let mut game = Game2048::new(42, RngKind::Std);
+        for i in 0..200 {
+            game.play(directions[i % directions.len()]);
+        }
+        let replayed = Game2048::replay(42, RngKind::Std, &game.moves);
+        assert_eq!(replayed.grid, game.grid);
+        assert_eq!(replayed.score, game.score);
+        assert_eq!(replayed.moves, game.moves);
+    }
+}
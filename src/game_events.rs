@@ -0,0 +1,225 @@
+use std::collections::VecDeque;
+
+use persy::Persy;
+use rocket::{
+    get, http::ContentType, response::stream::TextStream, routes, serde::json::serde_json,
+    FromFormField, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    ids::GameId,
+    leaderboard, replays,
+    storage::{self, Database},
+    tetris::{Action, Replay, ReplayPlayer},
+    tetris_pair::STEP_MS,
+};
+
+//
+// Export of event history of stored games for analysis outside of the server. Events are
+// produced by re-simulating the game's replay and streamed as they are produced, so long
+// games are not buffered in memory
+//
+
+const CSV_HEADER: &str = "tick,time_ms,timestamp_ms,event,action,cleared,combo,score,lines\n";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromFormField)]
+pub enum EventsFormat {
+    // JSON Lines, one event object per line
+    #[default]
+    Jsonl,
+    Csv,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GameEvent {
+    pub tick: u64,
+    // Time since game start, milliseconds
+    pub time_ms: u64,
+    // Estimated time of the event, milliseconds since unix epoch
+    pub timestamp_ms: u64,
+    // "input", "lock", "score" or "end"
+    pub event: &'static str,
+    // Input action
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
+    // Lines cleared by locked piece and number of clearing locks before it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cleared: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub combo: Option<usize>,
+    // Totals after score change and at the end of the game
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<usize>,
+}
+
+impl GameEvent {
+    fn to_line(&self, format: EventsFormat) -> String {
+        match format {
+            EventsFormat::Jsonl => {
+                let mut line = serde_json::to_string(self).unwrap_or_default();
+                line.push('\n');
+                line
+            }
+            EventsFormat::Csv => {
+                fn field<T: ToString>(value: Option<T>) -> String {
+                    value.map(|value| value.to_string()).unwrap_or_default()
+                }
+                format!(
+                    "{},{},{},{},{},{},{},{},{}\n",
+                    self.tick,
+                    self.time_ms,
+                    self.timestamp_ms,
+                    self.event,
+                    field(self.action.map(|action| format!("{:?}", action))),
+                    field(self.cleared),
+                    field(self.combo),
+                    field(self.score),
+                    field(self.lines),
+                )
+            }
+        }
+    }
+}
+
+// Events of replayed game, simulated step by step as they are requested
+pub struct GameEvents {
+    player: ReplayPlayer,
+    // Time of game start, milliseconds since unix epoch
+    started_ms: u64,
+    // Number of inputs already reported
+    inputs: usize,
+    score: usize,
+    lines: usize,
+    pending: VecDeque<GameEvent>,
+    ended: bool,
+}
+
+impl GameEvents {
+    pub fn new(replay: Replay, finished: u64) -> GameEvents {
+        GameEvents {
+            started_ms: (finished * 1000).saturating_sub(replay.ticks * STEP_MS),
+            player: ReplayPlayer::new(replay),
+            inputs: 0,
+            score: 0,
+            lines: 0,
+            pending: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    fn event(&self, tick: u64, event: &'static str) -> GameEvent {
+        GameEvent {
+            tick,
+            time_ms: tick * STEP_MS,
+            timestamp_ms: self.started_ms + tick * STEP_MS,
+            event,
+            action: None,
+            cleared: None,
+            combo: None,
+            score: None,
+            lines: None,
+        }
+    }
+
+    // Perform one step and queue the events it produced
+    fn step(&mut self) -> bool {
+        if !self.player.step() {
+            return false;
+        }
+        let tetris = self.player.get_tetris();
+        let tick = tetris.get_ticks();
+        let (score, lines) = (tetris.get_score(), tetris.get_lines());
+        let inputs = tetris.get_inputs()[self.inputs..].to_vec();
+        self.inputs += inputs.len();
+        for (input_tick, action) in inputs {
+            self.pending.push_back(GameEvent {
+                action: Some(action),
+                ..self.event(input_tick, "input")
+            });
+        }
+        if let Some(lock) = self.player.take_lock() {
+            self.pending.push_back(GameEvent {
+                cleared: Some(lock.lines),
+                combo: Some(lock.combo),
+                ..self.event(tick, "lock")
+            });
+        }
+        if (score, lines) != (self.score, self.lines) {
+            (self.score, self.lines) = (score, lines);
+            self.pending.push_back(GameEvent {
+                score: Some(score),
+                lines: Some(lines),
+                ..self.event(tick, "score")
+            });
+        }
+        true
+    }
+}
+
+impl Iterator for GameEvents {
+    type Item = GameEvent;
+
+    fn next(&mut self) -> Option<GameEvent> {
+        while self.pending.is_empty() && !self.ended {
+            if !self.step() {
+                self.ended = true;
+                let tick = self.player.get_tetris().get_ticks();
+                self.pending.push_back(GameEvent {
+                    score: Some(self.score),
+                    lines: Some(self.lines),
+                    ..self.event(tick, "end")
+                });
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+// Event history of leaderboard entry's game
+pub fn export(
+    persy: &Persy,
+    id: GameId,
+    format: Option<EventsFormat>,
+) -> Result<(ContentType, TextStream![String]), Error> {
+    let entry = leaderboard::read(persy, &id.0)?
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    let replay_id = entry
+        .replay
+        .ok_or_else(|| Error::NotFoundError("Game has no replay".to_string()))?;
+    let replay = replays::read(persy, &storage::parse_id(&replay_id)?)?
+        .ok_or_else(|| Error::NotFoundError("Replay not found".to_string()))?;
+    let format = format.unwrap_or_default();
+    let content_type = match format {
+        EventsFormat::Jsonl => ContentType::new("application", "jsonl"),
+        EventsFormat::Csv => ContentType::CSV,
+    };
+    let events = GameEvents::new(replay, entry.finished);
+    Ok((
+        content_type,
+        TextStream! {
+            if format == EventsFormat::Csv {
+                yield CSV_HEADER.to_string();
+            }
+            for event in events {
+                yield event.to_line(format);
+            }
+        },
+    ))
+}
+
+#[get("/game/<id>/events?<format>")]
+fn game_events(
+    db: &State<Database>,
+    id: GameId,
+    format: Option<EventsFormat>,
+) -> Result<(ContentType, TextStream![String]), Error> {
+    export(&db.read(), id, format)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![game_events]
+}
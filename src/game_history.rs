@@ -0,0 +1,240 @@
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{get, http::CookieJar, post, routes, serde::json::Json, FromForm, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admission::Admitted,
+    difficulty::Difficulty,
+    error::Error,
+    game_mode::GameMode,
+    ids::UserId,
+    pagination::{self, Page, SortOrder},
+    quotas::Quotas,
+    recording, splits,
+    sprint::{self, ReplacedSprint, TetrisSprints},
+    storage::{self, Database},
+    tetris::Replay,
+    TetrisMatches,
+};
+
+//
+// Games replaced by new ones. Starting a new sprint replaces the previous game of the user,
+// which is kept here instead of being lost: finished games for reference, abandoned ones
+// also to be resumed for some time. Only the latest games within user's quota are kept,
+// see quotas
+//
+
+const HISTORY_SEGMENT: &str = "game_history";
+const BY_USER_INDEX: &str = "game_history_by_user";
+
+// Abandoned game can be resumed within this time after it was replaced, seconds
+const RESUME_WINDOW: u64 = 15 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchivedStatus {
+    Finished,
+    // Replaced before it was finished
+    Abandoned,
+    // Abandoned game which was resumed, it's continued as current game
+    Resumed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedGame {
+    pub user: UserId,
+    pub mode: GameMode,
+    pub difficulty: Option<Difficulty>,
+    pub status: ArchivedStatus,
+    // Start and replacement time, seconds since unix epoch
+    pub started: u64,
+    pub archived: u64,
+    pub score: u64,
+    pub lines: u64,
+    pub ticks: u64,
+    pub replay: Replay,
+}
+
+// Archived game without replay, for listings
+#[derive(Serialize)]
+pub struct ArchivedItem {
+    pub id: String,
+    pub mode: GameMode,
+    pub difficulty: Option<Difficulty>,
+    pub status: ArchivedStatus,
+    pub started: u64,
+    pub archived: u64,
+    pub score: u64,
+    pub lines: u64,
+    pub ticks: u64,
+    // Time left to resume the game, seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resumable_for: Option<u64>,
+}
+
+#[derive(FromForm)]
+pub struct HistoryQuery<'r> {
+    // Replacement time order, latest first by default
+    order: Option<SortOrder>,
+    cursor: Option<&'r str>,
+    limit: Option<usize>,
+}
+
+impl ArchivedGame {
+    // Time left to resume the game, None if it can't be resumed
+    fn resumable_for(&self, now: u64) -> Option<u64> {
+        let deadline = self.archived + RESUME_WINDOW;
+        (self.status == ArchivedStatus::Abandoned && now < deadline).then(|| deadline - now)
+    }
+
+    fn item(&self, id: PersyId, now: u64) -> ArchivedItem {
+        ArchivedItem {
+            id: id.to_string(),
+            mode: self.mode,
+            difficulty: self.difficulty,
+            status: self.status,
+            started: self.started,
+            archived: self.archived,
+            score: self.score,
+            lines: self.lines,
+            ticks: self.ticks,
+            resumable_for: self.resumable_for(now),
+        }
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, HISTORY_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+pub fn games_of_user(persy: &Persy, user: UserId) -> Result<Vec<(PersyId, ArchivedGame)>, Error> {
+    let mut games = Vec::new();
+    for id in persy.get::<u32, PersyId>(BY_USER_INDEX, &user.0)? {
+        if let Some(game) = storage::read(persy, HISTORY_SEGMENT, &id)? {
+            games.push((id, game));
+        }
+    }
+    Ok(games)
+}
+
+// Keep replaced sprint of the user, dropping the oldest games over the quota. Finished games
+// of users who opted out of recording are not kept, abandoned ones are kept to be resumed
+pub fn archive(
+    persy: &Persy,
+    user: UserId,
+    sprint: ReplacedSprint,
+) -> Result<Option<PersyId>, Error> {
+    if sprint.finished && !recording::is_enabled(persy, user)? {
+        return Ok(None);
+    }
+    let game = ArchivedGame {
+        user,
+        mode: GameMode::Sprint,
+        difficulty: sprint.replay.difficulty,
+        status: if sprint.finished {
+            ArchivedStatus::Finished
+        } else {
+            ArchivedStatus::Abandoned
+        },
+        started: sprint.started,
+        archived: crate::unix_time(),
+        score: sprint.score as u64,
+        lines: sprint.lines as u64,
+        ticks: sprint.replay.ticks,
+        replay: sprint.replay,
+    };
+    let mut existing = games_of_user(persy, user)?;
+    existing.sort_by_key(|(id, game)| (game.archived, *id));
+    let quota = Quotas::from_config().archived_games;
+    let overflow = (existing.len() + 1).saturating_sub(quota);
+    let mut tx = persy.begin()?;
+    for (id, _) in existing.iter().take(overflow) {
+        tx.delete(HISTORY_SEGMENT, id)?;
+        tx.remove(BY_USER_INDEX, user.0, Some(*id))?;
+    }
+    let id = storage::insert_in_tx(&mut tx, HISTORY_SEGMENT, &game)?;
+    tx.put(BY_USER_INDEX, user.0, id)?;
+    tx.prepare()?.commit()?;
+    Ok(Some(id))
+}
+
+// Delete all archived games of the user, returns number of deleted games
+pub fn delete_in_tx(tx: &mut Transaction, persy: &Persy, user: UserId) -> Result<usize, Error> {
+    let games = games_of_user(persy, user)?;
+    for (id, _) in &games {
+        tx.delete(HISTORY_SEGMENT, id)?;
+        tx.remove(BY_USER_INDEX, user.0, Some(*id))?;
+    }
+    Ok(games.len())
+}
+
+// Page of user's replaced games
+pub fn history(
+    persy: &Persy,
+    user: UserId,
+    query: &HistoryQuery,
+) -> Result<Page<ArchivedItem>, Error> {
+    let now = crate::unix_time();
+    let games = games_of_user(persy, user)?
+        .into_iter()
+        .map(|(id, game)| (game.archived, id, game.item(id, now)))
+        .collect();
+    pagination::page_in_memory(
+        games,
+        query.order.unwrap_or(SortOrder::Desc),
+        query.cursor,
+        pagination::limit(query.limit),
+    )
+}
+
+// User's games replaced by new ones, latest first
+#[get("/games/history?<query..>")]
+fn games_history(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    query: HistoryQuery,
+) -> Result<Json<Page<ArchivedItem>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(history(&db.read(), user_id, &query)?))
+}
+
+// Make abandoned game the current one of the user, current game is kept in history.
+// Game is continued by connecting to /sprint/sse?resume=true
+#[post("/games/<id>/resume")]
+fn resume_game(
+    _admitted: Admitted,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    db: &State<Database>,
+    id: &str,
+) -> Result<Json<ArchivedItem>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let id = storage::parse_id(id)?;
+    let persy = &*db.read();
+    let mut game = storage::read::<ArchivedGame>(persy, HISTORY_SEGMENT, &id)?
+        .filter(|game| game.user == user_id)
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    if game.resumable_for(crate::unix_time()).is_none() {
+        return Err(Error::InvalidInputError(
+            "Game can't be resumed anymore".to_string(),
+        ));
+    }
+    let difficulty = game.difficulty.unwrap_or_default();
+    let ghost = sprint::personal_best(persy, user_id, difficulty)?;
+    let best_splits =
+        splits::personal_best(persy, user_id, GameMode::Sprint, difficulty, ghost.as_ref())?;
+    game.status = ArchivedStatus::Resumed;
+    storage::update(persy, HISTORY_SEGMENT, &id, &game)?;
+    if let Some(replaced) = sprints.resume(user_id, &game.replay, game.started, ghost, best_splits)
+    {
+        archive(persy, user_id, replaced)?;
+    }
+    Ok(Json(game.item(id, crate::unix_time())))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![games_history, resume_game]
+}
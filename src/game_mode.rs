@@ -0,0 +1,35 @@
+use rocket::FromFormField;
+use serde::{Deserialize, Serialize};
+
+use crate::tetris::{PieceRules, Randomizer};
+
+// Game modes available on server. Mode is stored with game results so listings can be filtered by it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, FromFormField)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum GameMode {
+    // Two players on separate fields, removed lines are sent to opponent
+    Versus,
+    // Single player clears fixed number of lines as fast as possible
+    Sprint,
+}
+
+impl GameMode {
+    // Randomizer used by the mode unless configured otherwise
+    pub fn randomizer(&self) -> Randomizer {
+        match self {
+            GameMode::Versus => Randomizer::Uniform,
+            GameMode::Sprint => Randomizer::SevenBag,
+        }
+    }
+
+    // Hold and next queue of the mode unless configured otherwise
+    pub fn pieces(&self) -> PieceRules {
+        match self {
+            GameMode::Versus => PieceRules::classic(),
+            GameMode::Sprint => PieceRules {
+                hold: true,
+                next_queue: 5,
+            },
+        }
+    }
+}
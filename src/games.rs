@@ -0,0 +1,134 @@
+use persy::Persy;
+use rocket::{
+    get,
+    http::{ContentType, CookieJar},
+    request::{FromRequest, Outcome},
+    routes, Build, Orbit, Request, Rocket, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    connect_four, error::Error, game2048, ids::UserId, minesweeper, sprint, tutorial, TetrisMatches,
+};
+
+//
+// Registry of game types. Each game module describes itself with a GamePlugin: lobby
+// entry, storage, routes, managed state with background tasks, user's session and it's
+// serialized state. Plugins are listed in plugins(), the server initializes their storage,
+// attaches and mounts them generically, lobby and /games/<id>/state look them up here.
+// Adding a game type is a module with a plugin and a line in plugins()
+//
+
+#[derive(Serialize)]
+pub struct GameType {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+    // Where the game starts: client page or the route starting a game
+    pub entry: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum SessionStatus {
+    // Waiting for an opponent
+    Waiting,
+    Playing,
+}
+
+impl SessionStatus {
+    pub fn of(waiting: bool, playing: bool) -> Option<SessionStatus> {
+        if waiting {
+            Some(SessionStatus::Waiting)
+        } else if playing {
+            Some(SessionStatus::Playing)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct GamePlugin {
+    pub game_type: GameType,
+    // Create segments and indexes of the game
+    pub init: fn(&Persy) -> Result<(), Error>,
+    pub routes: fn() -> Vec<Route>,
+    // Manage state of the game and start it's tick or cleanup tasks
+    pub attach: fn(Rocket<Build>) -> Result<Rocket<Build>, Error>,
+    // Session of the user, looked up in managed state
+    pub session: fn(&Rocket<Orbit>, UserId) -> Option<SessionStatus>,
+    // User's current game state as json
+    pub state: fn(&Rocket<Orbit>, UserId) -> Option<String>,
+}
+
+// Running server, plugins look up their managed state in it
+pub struct Server<'r>(pub &'r Rocket<Orbit>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Server<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(Server(request.rocket()))
+    }
+}
+
+pub fn plugins() -> Vec<GamePlugin> {
+    vec![
+        crate::versus_plugin(),
+        sprint::plugin(),
+        connect_four::plugin(),
+        game2048::plugin(),
+        minesweeper::plugin(),
+        tutorial::plugin(),
+    ]
+}
+
+pub struct GameRegistry(Vec<GamePlugin>);
+
+impl GameRegistry {
+    pub fn new() -> GameRegistry {
+        GameRegistry(plugins())
+    }
+
+    pub fn init(persy: &Persy) -> Result<(), Error> {
+        for plugin in plugins() {
+            (plugin.init)(persy)?;
+        }
+        Ok(())
+    }
+
+    // Attach and mount all games, the registry is managed by the server then
+    pub fn attach(self, rocket: Rocket<Build>) -> Result<Rocket<Build>, Error> {
+        let mut rocket = rocket;
+        for plugin in &self.0 {
+            rocket = (plugin.attach)(rocket)?.mount("/", (plugin.routes)());
+        }
+        Ok(rocket.manage(self).mount("/", routes![game_state]))
+    }
+
+    pub fn plugins(&self) -> &[GamePlugin] {
+        &self.0
+    }
+
+    pub fn get(&self, id: &str) -> Option<&GamePlugin> {
+        self.0.iter().find(|plugin| plugin.game_type.id == id)
+    }
+}
+
+// User's current game of the game type
+#[get("/games/<id>/state")]
+fn game_state(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    registry: &State<GameRegistry>,
+    server: Server,
+    id: &str,
+) -> Result<(ContentType, String), Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let plugin = registry
+        .get(id)
+        .ok_or_else(|| Error::NotFoundError(format!("Game type {} not found", id)))?;
+    let state = (plugin.state)(server.0, user_id)
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    Ok((ContentType::JSON, state))
+}
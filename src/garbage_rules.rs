@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use rocket::{get, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, tetris::LineClear};
+
+//
+// Versus garbage rules. Ruleset defines how many lines are sent for a clear and how
+// garbage lines look. Built-in rulesets can be extended or overridden by json files
+// in rules directory, one ruleset per file
+//
+
+pub const RULES_DIR: &str = "garbage_rules";
+pub const DEFAULT_RULES: &str = "classic";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GarbageRules {
+    pub name: String,
+    // Lines sent for clearing 1, 2, 3 and 4 lines at once
+    pub attack: [usize; 4],
+    // Extra lines by combo length (number of preceding consecutive clears),
+    // last value is used for longer combos
+    #[serde(default)]
+    pub combo_bonus: Vec<usize>,
+    // Sent lines cancel own pending garbage first. Pending garbage is received on the next
+    // lock without clear once it's delay is over. Without cancellation garbage is received
+    // immediately
+    #[serde(default)]
+    pub cancellation: bool,
+    // Steps pending garbage waits before it may be received, the window to cancel it
+    #[serde(default)]
+    pub delay: u64,
+    // Chance for each garbage line to have hole in a different column than previous one,
+    // 0 gives single clean column
+    #[serde(default)]
+    pub messiness: f64,
+    // Garbage lines are randomly filled, without guaranteed hole
+    #[serde(default)]
+    pub random_lines: bool,
+}
+
+impl GarbageRules {
+    // Each cleared line adds random line to opponent
+    pub fn classic() -> GarbageRules {
+        GarbageRules {
+            name: "classic".to_string(),
+            attack: [1, 2, 3, 4],
+            combo_bonus: Vec::new(),
+            cancellation: false,
+            delay: 0,
+            messiness: 0.,
+            random_lines: true,
+        }
+    }
+
+    // Guideline-like attack table with combos and cancellation
+    pub fn modern() -> GarbageRules {
+        GarbageRules {
+            name: "modern".to_string(),
+            attack: [0, 1, 2, 4],
+            combo_bonus: vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5],
+            cancellation: true,
+            delay: 30,
+            messiness: 0.3,
+            random_lines: false,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("Ruleset name is empty".to_string());
+        }
+        if !(0. ..=1.).contains(&self.messiness) {
+            return Err("Messiness must be in range 0..1".to_string());
+        }
+        Ok(())
+    }
+
+    // Number of lines sent for the clear
+    pub fn attack(&self, clear: &LineClear) -> usize {
+        if clear.lines == 0 {
+            return 0;
+        }
+        let base = self.attack[clear.lines.min(4) - 1];
+        let bonus = self
+            .combo_bonus
+            .get(clear.combo)
+            .or(self.combo_bonus.last())
+            .copied()
+            .unwrap_or(0);
+        base + bonus
+    }
+}
+
+// Available rulesets by name
+pub struct GarbageRulebook(HashMap<String, Arc<GarbageRules>>);
+
+impl GarbageRulebook {
+    // Built-in rulesets and rulesets from json files of the directory, if it exists
+    pub fn load(dir: &Path) -> Result<GarbageRulebook, Error> {
+        let mut rulesets = HashMap::new();
+        for rules in [GarbageRules::classic(), GarbageRules::modern()] {
+            rulesets.insert(rules.name.clone(), Arc::new(rules));
+        }
+        if dir.is_dir() {
+            for file in fs::read_dir(dir)? {
+                let path = file?.path();
+                if path.extension().is_none_or(|ext| ext != "json") {
+                    continue;
+                }
+                let rules: GarbageRules =
+                    rocket::serde::json::serde_json::from_slice(&fs::read(&path)?)?;
+                rules
+                    .validate()
+                    .map_err(|e| Error::InvalidInputError(format!("{}: {}", path.display(), e)))?;
+                rulesets.insert(rules.name.clone(), Arc::new(rules));
+            }
+        }
+        Ok(GarbageRulebook(rulesets))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<GarbageRules>> {
+        self.0.get(name).cloned()
+    }
+}
+
+// Available garbage rulesets
+#[get("/garbage_rules")]
+fn garbage_rules(rulebook: &State<GarbageRulebook>) -> Json<Vec<GarbageRules>> {
+    let mut rulesets = rulebook
+        .0
+        .values()
+        .map(|rules| GarbageRules::clone(rules))
+        .collect::<Vec<_>>();
+    rulesets.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(rulesets)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![garbage_rules]
+}
@@ -0,0 +1,125 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use rocket::{
+    request::{FromRequest, Outcome},
+    Config, Request,
+};
+
+use crate::error::Error;
+
+//
+// Country of client addresses from a local database of address ranges, configured by
+// geoip_database path. Lines of the file are `<first address>,<last address>,<country>`
+// ranges, as in free country CSV downloads, or `<network>/<prefix>,<country>`, of IPv4 and
+// IPv6 addresses. Lines starting with # are skipped. The file is loaded in memory on start,
+// lookups are a binary search. Without the database countries are not inferred
+//
+
+// Ranges of addresses as IPv6, IPv4 ones mapped, sorted by the first address
+#[derive(Clone, Default)]
+pub struct GeoIp(Option<Arc<Vec<(u128, u128, String)>>>);
+
+// Country of the request's client, None when it's unknown
+pub struct InferredCountry(pub Option<String>);
+
+fn address_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+// First and last address of the range, as bits
+fn parse_range(first: &str, last: Option<&str>) -> Option<(u128, u128)> {
+    match last {
+        Some(last) => {
+            let first = address_bits(first.trim().parse().ok()?);
+            let last = address_bits(last.trim().parse().ok()?);
+            (first <= last).then_some((first, last))
+        }
+        None => {
+            let (network, prefix) = first.split_once('/')?;
+            let network = network.trim().parse::<IpAddr>().ok()?;
+            let prefix = prefix.trim().parse::<u32>().ok()?;
+            // Prefix of IPv4 network within the mapped address
+            let prefix = match network {
+                IpAddr::V4(_) if prefix <= 32 => prefix + 96,
+                IpAddr::V6(_) if prefix <= 128 => prefix,
+                _ => return None,
+            };
+            let host = u128::MAX.checked_shr(prefix).unwrap_or(0);
+            let first = address_bits(network) & !host;
+            Some((first, first | host))
+        }
+    }
+}
+
+// Two letter country code in upper case
+pub fn country_code(code: &str) -> Option<String> {
+    let code = code.trim().trim_matches('"');
+    (code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()))
+        .then(|| code.to_ascii_uppercase())
+}
+
+impl GeoIp {
+    pub fn from_config() -> Result<GeoIp, Error> {
+        let Ok(path) = Config::figment().extract_inner::<String>("geoip_database") else {
+            return Ok(GeoIp(None));
+        };
+        let mut ranges = Vec::new();
+        let mut skipped = 0;
+        for line in std::fs::read_to_string(&path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line
+                .split(',')
+                .map(|field| field.trim_matches('"'))
+                .collect::<Vec<_>>();
+            let parsed = match fields.as_slice() {
+                [network, country] => parse_range(network, None).zip(country_code(country)),
+                [first, last, country, ..] => {
+                    parse_range(first, Some(last)).zip(country_code(country))
+                }
+                _ => None,
+            };
+            match parsed {
+                Some(((first, last), country)) => ranges.push((first, last, country)),
+                None => skipped += 1,
+            }
+        }
+        ranges.sort_by_key(|(first, _, _)| *first);
+        println!(
+            "Geo ip database {}: {} ranges, {} invalid lines skipped",
+            path,
+            ranges.len(),
+            skipped
+        );
+        Ok(GeoIp(Some(Arc::new(ranges))))
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let ranges = self.0.as_ref()?;
+        let bits = address_bits(ip);
+        // Last range starting at or before the address
+        let index = ranges.partition_point(|(first, _, _)| *first <= bits);
+        let (_, last, country) = ranges.get(index.checked_sub(1)?)?;
+        (bits <= *last).then(|| country.clone())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for InferredCountry {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let country = request
+            .rocket()
+            .state::<GeoIp>()
+            .zip(crate::proxies::client_ip(request))
+            .and_then(|(geoip, ip)| geoip.lookup(ip));
+        Outcome::Success(InferredCountry(country))
+    }
+}
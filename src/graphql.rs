@@ -0,0 +1,398 @@
+use async_graphql::{
+    http::GraphiQLSource, Context, EmptyMutation, EmptySubscription, Object, Schema,
+};
+use async_graphql_rocket::{GraphQLQuery, GraphQLRequest, GraphQLResponse};
+use rocket::{get, post, response::content::RawHtml, routes, Route, State};
+
+use crate::{
+    difficulty::Difficulty,
+    game_mode::GameMode,
+    ids::{GameId, UserId},
+    leaderboard::{self, LeaderboardEntry, LeaderboardQuery, LeaderboardSort, Verification},
+    match_history::{self, MatchPlayer, MatchRecord, MatchesQuery},
+    pagination::{self, Page, SortOrder},
+    regions,
+    stats::{self, DailyStats},
+    storage::{self, Database},
+};
+
+//
+// Read only GraphQL view of stored data for dashboards: users with their games and matches,
+// leaderboard, match history and daily statistics. Listings are paginated with the same
+// cursors and limits as REST listings. Only built with "graphql" feature
+//
+
+pub type GameSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+// Query nesting and cost limits, so one request can't walk the whole database
+const MAX_DEPTH: usize = 10;
+const MAX_COMPLEXITY: usize = 1000;
+
+pub fn schema(db: Database) -> GameSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(db)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+fn database<'c>(ctx: &Context<'c>) -> &'c Database {
+    ctx.data_unchecked::<Database>()
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn user(&self, id: u32) -> User {
+        User(UserId(id))
+    }
+
+    // Leaderboard games, filtered as /leaderboard
+    #[allow(clippy::too_many_arguments)]
+    async fn leaderboard(
+        &self,
+        ctx: &Context<'_>,
+        mode: Option<GameMode>,
+        difficulty: Option<Difficulty>,
+        verification: Option<Verification>,
+        sort: Option<LeaderboardSort>,
+        order: Option<SortOrder>,
+        from: Option<u64>,
+        to: Option<u64>,
+        country: Option<String>,
+        cursor: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<GamePage> {
+        let query = LeaderboardQuery {
+            mode,
+            difficulty,
+            verification,
+            sort,
+            order,
+            from,
+            to,
+            country: country.as_deref(),
+            friends: None,
+            cursor: cursor.as_deref(),
+            limit,
+        };
+        let persy = database(ctx).read();
+        let users = regions::users_of(&persy, query.country)?;
+        let page = leaderboard::list_of_users(&persy, &query, users.as_ref())?;
+        Ok(GamePage {
+            items: page
+                .items
+                .into_iter()
+                .map(|item| Game {
+                    id: item.id,
+                    entry: item.entry,
+                })
+                .collect(),
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    async fn game(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<Option<Game>> {
+        game(database(ctx), id.parse()?)
+    }
+
+    // Completed matches, latest first by default
+    async fn matches(
+        &self,
+        ctx: &Context<'_>,
+        player: Option<u32>,
+        order: Option<SortOrder>,
+        cursor: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<MatchPage> {
+        matches(database(ctx), player.map(UserId), order, cursor, limit)
+    }
+
+    #[graphql(name = "match")]
+    async fn match_(&self, ctx: &Context<'_>, id: String) -> async_graphql::Result<Option<Match>> {
+        let id = storage::parse_id(&id)?;
+        Ok(
+            match_history::read(&database(ctx).read(), &id)?.map(|record| Match {
+                id: id.to_string(),
+                record,
+            }),
+        )
+    }
+
+    // Daily statistics for days range (days since unix epoch), last 30 days by default
+    async fn stats(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> async_graphql::Result<Vec<DailyStats>> {
+        let (from, to) = stats::days_range(from, to)?;
+        Ok(stats::days(&database(ctx).read(), from, to)?)
+    }
+}
+
+fn game(db: &Database, id: GameId) -> async_graphql::Result<Option<Game>> {
+    Ok(leaderboard::read(&db.read(), &id.0)?.map(|entry| Game { id, entry }))
+}
+
+fn matches(
+    db: &Database,
+    player: Option<UserId>,
+    order: Option<SortOrder>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+) -> async_graphql::Result<MatchPage> {
+    let query = MatchesQuery {
+        player,
+        friends: None,
+        order,
+        cursor: cursor.as_deref(),
+        limit,
+    };
+    let page = match_history::records(&db.read(), &query, None)?;
+    Ok(MatchPage {
+        items: page
+            .items
+            .into_iter()
+            .map(|item| Match {
+                id: item.id,
+                record: item.record,
+            })
+            .collect(),
+        next_cursor: page.next_cursor,
+    })
+}
+
+pub struct User(UserId);
+
+#[Object]
+impl User {
+    async fn id(&self) -> u32 {
+        self.0 .0
+    }
+
+    // Games of the user, latest first by default
+    async fn games(
+        &self,
+        ctx: &Context<'_>,
+        mode: Option<GameMode>,
+        difficulty: Option<Difficulty>,
+        order: Option<SortOrder>,
+        cursor: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<GamePage> {
+        let games = leaderboard::entries_of_user(&database(ctx).read(), self.0)?
+            .into_iter()
+            .filter(|(_, entry)| {
+                mode.is_none_or(|mode| entry.mode == mode)
+                    && difficulty.is_none_or(|difficulty| entry.difficulty == Some(difficulty))
+            })
+            .map(|(id, entry)| {
+                let game = Game {
+                    id: GameId(id),
+                    entry,
+                };
+                (game.entry.finished, id, game)
+            })
+            .collect();
+        let page: Page<Game> = pagination::page_in_memory(
+            games,
+            order.unwrap_or(SortOrder::Desc),
+            cursor.as_deref(),
+            pagination::limit(limit),
+        )?;
+        Ok(GamePage {
+            items: page.items,
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    async fn matches(
+        &self,
+        ctx: &Context<'_>,
+        order: Option<SortOrder>,
+        cursor: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<MatchPage> {
+        matches(database(ctx), Some(self.0), order, cursor, limit)
+    }
+
+    // Best score of the user's games which are not rejected
+    async fn best_score(
+        &self,
+        ctx: &Context<'_>,
+        mode: Option<GameMode>,
+        difficulty: Option<Difficulty>,
+    ) -> async_graphql::Result<Option<u64>> {
+        Ok(leaderboard::entries_of_user(&database(ctx).read(), self.0)?
+            .into_iter()
+            .filter(|(_, entry)| {
+                entry.verification != Verification::Rejected
+                    && mode.is_none_or(|mode| entry.mode == mode)
+                    && difficulty.is_none_or(|difficulty| entry.difficulty == Some(difficulty))
+            })
+            .map(|(_, entry)| entry.score)
+            .max())
+    }
+}
+
+// Leaderboard entry of a finished game
+pub struct Game {
+    id: GameId,
+    entry: LeaderboardEntry,
+}
+
+#[Object]
+impl Game {
+    async fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    async fn user(&self) -> User {
+        User(self.entry.user)
+    }
+
+    async fn mode(&self) -> GameMode {
+        self.entry.mode
+    }
+
+    async fn difficulty(&self) -> Option<Difficulty> {
+        self.entry.difficulty
+    }
+
+    async fn score(&self) -> u64 {
+        self.entry.score
+    }
+
+    async fn lines(&self) -> u64 {
+        self.entry.lines
+    }
+
+    async fn ticks(&self) -> u64 {
+        self.entry.ticks
+    }
+
+    async fn finished(&self) -> u64 {
+        self.entry.finished
+    }
+
+    async fn opponent(&self) -> Option<User> {
+        self.entry.opponent.map(User)
+    }
+
+    // Replay id, replay itself is served by /replays/<id>
+    async fn replay(&self) -> Option<&str> {
+        self.entry.replay.as_deref()
+    }
+
+    async fn verification(&self) -> Verification {
+        self.entry.verification
+    }
+}
+
+pub struct Match {
+    id: String,
+    record: MatchRecord,
+}
+
+#[Object]
+impl Match {
+    async fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn started(&self) -> u64 {
+        self.record.started
+    }
+
+    async fn finished(&self) -> u64 {
+        self.record.finished
+    }
+
+    async fn ticks(&self) -> u64 {
+        self.record.ticks
+    }
+
+    async fn garbage_rules(&self) -> &str {
+        &self.record.garbage_rules
+    }
+
+    async fn shared_pieces(&self) -> bool {
+        self.record.shared_pieces
+    }
+
+    async fn players(&self) -> Vec<Player> {
+        self.record.players.iter().cloned().map(Player).collect()
+    }
+}
+
+// Player of a match, final boards are only shown on match page
+pub struct Player(MatchPlayer);
+
+#[Object]
+impl Player {
+    async fn user(&self) -> User {
+        User(self.0.user)
+    }
+
+    async fn score(&self) -> u64 {
+        self.0.score
+    }
+
+    async fn lines(&self) -> u64 {
+        self.0.lines
+    }
+
+    async fn attack_sent(&self) -> u64 {
+        self.0.attack_sent
+    }
+
+    async fn garbage_received(&self) -> u64 {
+        self.0.garbage_received
+    }
+
+    async fn forfeited(&self) -> bool {
+        self.0.forfeited
+    }
+
+    // Leaderboard entry of the player's game
+    async fn game(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Game>> {
+        match &self.0.entry {
+            Some(id) => game(database(ctx), *id),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct GamePage {
+    items: Vec<Game>,
+    next_cursor: Option<String>,
+}
+
+#[derive(async_graphql::SimpleObject)]
+pub struct MatchPage {
+    items: Vec<Match>,
+    next_cursor: Option<String>,
+}
+
+#[get("/graphql?<query..>")]
+async fn graphql_query(schema: &State<GameSchema>, query: GraphQLQuery) -> GraphQLResponse {
+    query.execute(schema.inner()).await
+}
+
+#[post("/graphql", data = "<request>", format = "application/json")]
+async fn graphql_request(schema: &State<GameSchema>, request: GraphQLRequest) -> GraphQLResponse {
+    request.execute(schema.inner()).await
+}
+
+// In-browser query editor
+#[get("/graphiql")]
+fn graphiql() -> RawHtml<String> {
+    RawHtml(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![graphql_query, graphql_request, graphiql]
+}
@@ -0,0 +1,168 @@
+use std::sync::{Arc, RwLock};
+
+use persy::Persy;
+use rocket::{form::Form, get, post, response::Redirect, routes, FromForm, Route, State};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arenas::Arenas,
+    difficulty::{Difficulty, GravityCurve},
+    error::Error,
+    roles::{Operator, Viewer},
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Gravity curves tuned live, e.g. during events. Admin replaces the gravity by level of
+// the difficulty preset of versus games from /admin/gravity, for the server and each arena
+// separately. Curve is stored in "gravity_curves" segment of the server's or arena's
+// database and applies to matches created from then on. Optionally running matches switch
+// to it from their next level, games started at higher levels change at their next level
+// up as well. Curves in effect are recorded in replays, so replays play back with them
+//
+
+const CURVES_SEGMENT: &str = "gravity_curves";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredCurve {
+    pub curve: GravityCurve,
+    // Seconds since unix epoch
+    pub updated: u64,
+}
+
+// Curve of new matches, None for the difficulty preset's one
+#[derive(Debug, Clone, Default)]
+pub struct GravityTuning(Arc<RwLock<Option<StoredCurve>>>);
+
+#[derive(FromForm)]
+pub struct CurveForm {
+    // Empty for the server's own matches
+    arena: String,
+    // Gravity of levels, rows per second, empty resets to the difficulty's curve
+    curve: String,
+    // Running matches switch at their next level
+    apply_running: bool,
+}
+
+// Curve of the server or an arena for admin page
+#[derive(Serialize)]
+pub struct CurveItem {
+    // None for the server
+    pub arena: Option<String>,
+    pub difficulty: Difficulty,
+    pub preset: String,
+    // Tuned curve, None while the preset's one is used
+    pub curve: Option<String>,
+    pub updated: Option<u64>,
+    pub running: usize,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, CURVES_SEGMENT)
+}
+
+impl GravityTuning {
+    // Load curve stored by previous run
+    pub fn load(persy: &Persy) -> Result<GravityTuning, Error> {
+        let stored = storage::scan::<StoredCurve>(persy, CURVES_SEGMENT)?
+            .into_iter()
+            .map(|(_, stored)| stored)
+            .max_by_key(|stored| stored.updated);
+        Ok(GravityTuning(Arc::new(RwLock::new(stored))))
+    }
+
+    pub fn current(&self) -> Option<GravityCurve> {
+        Some(self.0.read().unwrap().as_ref()?.curve.clone())
+    }
+
+    // Replace stored curve, None resets to the difficulty's one
+    fn set(&self, persy: &Persy, curve: Option<GravityCurve>) -> Result<(), Error> {
+        let stored = curve.map(|curve| StoredCurve {
+            curve,
+            updated: crate::unix_time(),
+        });
+        let mut tx = persy.begin()?;
+        for (id, _) in storage::scan::<StoredCurve>(persy, CURVES_SEGMENT)? {
+            tx.delete(CURVES_SEGMENT, &id)?;
+        }
+        if let Some(stored) = &stored {
+            storage::insert_in_tx(&mut tx, CURVES_SEGMENT, stored)?;
+        }
+        tx.prepare()?.commit()?;
+        *self.0.write().unwrap() = stored;
+        Ok(())
+    }
+}
+
+fn item(arena: Option<String>, matches: &TetrisMatches) -> CurveItem {
+    let rules = &matches.rules;
+    let stored = rules.gravity.0.read().unwrap().clone();
+    CurveItem {
+        arena,
+        difficulty: rules.difficulty,
+        preset: rules.difficulty.curve().to_string(),
+        curve: stored.as_ref().map(|stored| stored.curve.to_string()),
+        updated: stored.map(|stored| stored.updated),
+        running: matches.running(),
+    }
+}
+
+// Curves of the server and arenas
+#[get("/admin/gravity")]
+fn admin_gravity(
+    _admin: Viewer,
+    matches: &State<TetrisMatches>,
+    arenas: &State<Arenas>,
+) -> Result<Template, Error> {
+    let mut curves = vec![item(None, matches)];
+    for name in arenas.names() {
+        curves.push(item(Some(name.clone()), &arenas.get(&name)?.matches));
+    }
+    Ok(Template::render("admin/gravity", context! { curves }))
+}
+
+// Tune curve of the server or an arena
+#[post("/admin/gravity", data = "<form>")]
+fn set_gravity(
+    _admin: Operator,
+    db: &State<Database>,
+    matches: &State<TetrisMatches>,
+    arenas: &State<Arenas>,
+    form: Form<CurveForm>,
+) -> Result<Redirect, Error> {
+    let (db, matches) = match form.arena.as_str() {
+        "" => (db.inner(), matches.inner()),
+        name => {
+            let arena = arenas.get(name)?;
+            (&arena.db, &arena.matches)
+        }
+    };
+    let curve = match form.curve.trim() {
+        "" => None,
+        text => Some(GravityCurve::parse(text)?),
+    };
+    matches.rules.gravity.set(&db.read(), curve.clone())?;
+    let running = if form.apply_running {
+        let preset = || matches.rules.difficulty.curve();
+        matches.queue_gravity_curve(&curve.clone().unwrap_or_else(preset))
+    } else {
+        0
+    };
+    println!(
+        "Gravity curve of {} set to {}, {} running matches switch at their next level",
+        if form.arena.is_empty() {
+            "server"
+        } else {
+            &form.arena
+        },
+        curve.map_or_else(|| "difficulty's one".to_string(), |curve| curve.to_string()),
+        running
+    );
+    Ok(Redirect::to("/admin/gravity"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_gravity, set_gravity]
+}
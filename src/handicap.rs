@@ -0,0 +1,245 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rocket::figment::Figment;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, ids::UserId, ratings::Rating};
+
+//
+// Versus handicaps for mismatched players: starting garbage lines, reduced attack and
+// slower gravity. Handicaps are set with handicap config key of the server or an arena:
+// "Manual" gives players the handicaps set for them in handicap_players table, by user
+// id, whatever their ratings are, "Auto" computes them from rating difference: the
+// stronger player starts with garbage lines and sends reduced attack, the weaker one plays
+// with slower gravity, scaled up to full handicaps at handicap_rating_difference. Starting
+// garbage and gravity are stored in replays, handicaps of both players are recorded in
+// match history
+//
+
+// Full handicaps, unless configured
+const DEFAULT_GARBAGE: usize = 4;
+const DEFAULT_ATTACK_PERCENT: usize = 75;
+const DEFAULT_GRAVITY_PERCENT: usize = 75;
+// Rating difference of full handicaps in auto mode
+const DEFAULT_RATING_DIFFERENCE: i32 = 400;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HandicapMode {
+    #[default]
+    Off,
+    Manual,
+    Auto,
+}
+
+// Handicap of one player, missing fields are no handicap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Handicap {
+    // Garbage lines added before the match start
+    pub starting_garbage: usize,
+    // Sent garbage, percent of the rules' attack
+    pub attack_percent: usize,
+    // Gravity, percent of the difficulty's gravity
+    pub gravity_percent: usize,
+}
+
+impl Default for Handicap {
+    fn default() -> Self {
+        Handicap {
+            starting_garbage: 0,
+            attack_percent: 100,
+            gravity_percent: 100,
+        }
+    }
+}
+
+impl Handicap {
+    // Sent garbage lines for the attack, rounded down
+    pub fn scale_attack(&self, attack: usize) -> usize {
+        attack * self.attack_percent / 100
+    }
+
+    // Percents in the ranges of configured full handicaps
+    fn clamped(self) -> Handicap {
+        Handicap {
+            attack_percent: self.attack_percent.min(100),
+            gravity_percent: self.gravity_percent.clamp(1, 100),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HandicapRules {
+    pub mode: HandicapMode,
+    // Full handicaps: garbage and attack of the stronger player, gravity of the weaker one
+    pub starting_garbage: usize,
+    pub attack_percent: usize,
+    pub gravity_percent: usize,
+    pub rating_difference: i32,
+    // Handicaps of players in manual mode, others play without
+    pub players: BTreeMap<UserId, Handicap>,
+}
+
+impl Default for HandicapRules {
+    fn default() -> Self {
+        HandicapRules {
+            mode: HandicapMode::Off,
+            starting_garbage: DEFAULT_GARBAGE,
+            attack_percent: DEFAULT_ATTACK_PERCENT,
+            gravity_percent: DEFAULT_GRAVITY_PERCENT,
+            rating_difference: DEFAULT_RATING_DIFFERENCE,
+            players: BTreeMap::new(),
+        }
+    }
+}
+
+impl HandicapRules {
+    pub fn from_config(figment: &Figment) -> Result<HandicapRules, Error> {
+        let defaults = HandicapRules::default();
+        // Table keys are strings
+        let players = figment
+            .extract_inner::<HashMap<String, Handicap>>("handicap_players")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(user, handicap)| match user.parse() {
+                Ok(user) => Ok((UserId(user), handicap.clamped())),
+                Err(_) => Err(Error::InvalidInputError(format!(
+                    "Invalid user id {} in handicap_players",
+                    user
+                ))),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(HandicapRules {
+            mode: figment.extract_inner("handicap").unwrap_or(defaults.mode),
+            starting_garbage: figment
+                .extract_inner("handicap_garbage")
+                .unwrap_or(defaults.starting_garbage),
+            attack_percent: figment
+                .extract_inner::<usize>("handicap_attack_percent")
+                .map_or(defaults.attack_percent, |percent| percent.min(100)),
+            gravity_percent: figment
+                .extract_inner::<usize>("handicap_gravity_percent")
+                .map_or(defaults.gravity_percent, |percent| percent.clamp(1, 100)),
+            rating_difference: figment
+                .extract_inner::<i32>("handicap_rating_difference")
+                .map_or(defaults.rating_difference, |difference| difference.max(1)),
+            players,
+        })
+    }
+
+    // Handicaps of both players, set for them in manual mode or by their ratings
+    pub fn handicaps(&self, ratings: [Rating; 2]) -> [Handicap; 2] {
+        let difference = ratings[0].rating - ratings[1].rating;
+        let scale = match self.mode {
+            HandicapMode::Off => 0.,
+            HandicapMode::Manual => {
+                return ratings
+                    .map(|rating| self.players.get(&rating.user).copied().unwrap_or_default())
+            }
+            HandicapMode::Auto => (difference.abs() as f64 / self.rating_difference as f64).min(1.),
+        };
+        // Moves value towards the full handicap by the scale
+        let scaled = |none: usize, full: usize| {
+            (none as f64 + (full as f64 - none as f64) * scale).round() as usize
+        };
+        let stronger = Handicap {
+            starting_garbage: scaled(0, self.starting_garbage),
+            attack_percent: scaled(100, self.attack_percent),
+            gravity_percent: 100,
+        };
+        let weaker = Handicap {
+            gravity_percent: scaled(100, self.gravity_percent),
+            ..Handicap::default()
+        };
+        if difference > 0 {
+            [stronger, weaker]
+        } else {
+            [weaker, stronger]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(user: u32, rating: i32) -> Rating {
+        Rating {
+            user: UserId(user),
+            rating,
+            matches: 0,
+        }
+    }
+
+    fn rules(mode: HandicapMode) -> HandicapRules {
+        HandicapRules {
+            mode,
+            players: BTreeMap::from([(
+                UserId(2),
+                Handicap {
+                    starting_garbage: 3,
+                    ..Handicap::default()
+                },
+            )]),
+            ..HandicapRules::default()
+        }
+    }
+
+    #[test]
+    fn manual_handicaps_dont_depend_on_ratings() {
+        let rules = rules(HandicapMode::Manual);
+        let set = Handicap {
+            starting_garbage: 3,
+            ..Handicap::default()
+        };
+        for ratings in [[1500, 1500], [1800, 1200], [1200, 1800]] {
+            let handicaps = rules.handicaps([rating(1, ratings[0]), rating(2, ratings[1])]);
+            assert_eq!(handicaps, [Handicap::default(), set]);
+            let handicaps = rules.handicaps([rating(2, ratings[0]), rating(1, ratings[1])]);
+            assert_eq!(handicaps, [set, Handicap::default()]);
+        }
+    }
+
+    #[test]
+    fn player_handicaps_are_configured_by_user_id() {
+        use rocket::figment::providers::{Format, Toml};
+
+        let figment = Figment::from(Toml::string(
+            r#"
+            handicap = "Manual"
+            [handicap_players]
+            7 = { starting_garbage = 2, attack_percent = 150 }
+            "#,
+        ));
+        let rules = HandicapRules::from_config(&figment).unwrap();
+        assert_eq!(rules.mode, HandicapMode::Manual);
+        assert_eq!(
+            rules.players[&UserId(7)],
+            Handicap {
+                starting_garbage: 2,
+                attack_percent: 100,
+                gravity_percent: 100,
+            }
+        );
+        let figment = Figment::from(Toml::string("[handicap_players]\nplayer = {}"));
+        assert!(HandicapRules::from_config(&figment).is_err());
+    }
+
+    #[test]
+    fn auto_handicaps_scale_with_rating_difference() {
+        let rules = rules(HandicapMode::Auto);
+        let none = [Handicap::default(); 2];
+        assert_eq!(rules.handicaps([rating(1, 1500), rating(2, 1500)]), none);
+        let [weaker, stronger] = rules.handicaps([rating(1, 1300), rating(2, 1500)]);
+        assert_eq!(stronger.starting_garbage, 2);
+        assert_eq!(stronger.attack_percent, 88);
+        assert_eq!(weaker.gravity_percent, 88);
+        let [stronger, weaker] = rules.handicaps([rating(1, 2500), rating(2, 1500)]);
+        assert_eq!(stronger.starting_garbage, DEFAULT_GARBAGE);
+        assert_eq!(stronger.attack_percent, DEFAULT_ATTACK_PERCENT);
+        assert_eq!(weaker.gravity_percent, DEFAULT_GRAVITY_PERCENT);
+        let off = HandicapRules::default();
+        assert_eq!(off.handicaps([rating(1, 2500), rating(2, 1500)]), none);
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpListener as StdListener};
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, Mutex};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    figment::Figment,
+    get, post, routes,
+    serde::json::Json,
+    tokio::{
+        self,
+        io::copy_bidirectional,
+        net::{TcpListener, TcpStream},
+        signal::unix::{signal, SignalKind},
+        task::JoinHandle,
+        time::{self, Duration, Instant},
+    },
+    Config, Orbit, Rocket, Route, Shutdown, State,
+};
+use serde::Serialize;
+use socket2::{Domain, Socket, Type};
+
+use crate::{
+    arenas::Arenas,
+    connections::Connections,
+    error::Error,
+    roles::{Operator, Viewer},
+    storage::Database,
+    TetrisMatches,
+};
+
+//
+// Deploys without dropping live games. The public socket is either inherited from systemd
+// socket activation (LISTEN_FDS) or bound on handover_address with SO_REUSEPORT, so the
+// next server process binds the same address while this one still runs. Rocket then listens
+// on a loopback port and connections of the public socket are forwarded to it, client
+// addresses are looked up by the forwarded connection, see proxies.
+// The database is opened by one process at a time, so the next process binds the socket
+// first and waits for the database, connections wait in it's socket backlog meanwhile.
+// SIGUSR2 or POST /admin/handover/drain starts draining of the old process: it's socket is
+// closed, so new connections go to the next process, running games get drain_timeout_secs
+// to finish. Then event streams are closed, clients reconnect to the next process, and the
+// server shuts down flushing it's state as on any shutdown. Games still running are restored
+// by the next process from the recovery journal, see recovery. Without either socket the
+// server listens as usual
+//
+
+// Drain time unless configured, seconds
+const DEFAULT_DRAIN_TIMEOUT: u64 = 10;
+// Check interval of running games while draining
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Time the next process waits for the database beyond the drain of the old one
+const DATABASE_WAIT_MARGIN: Duration = Duration::from_secs(60);
+const DATABASE_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+// First file descriptor passed by systemd
+const LISTEN_FDS_START: i32 = 3;
+const BACKLOG: i32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ListenMode {
+    // Socket passed by systemd socket activation
+    Inherited,
+    // Socket bound with SO_REUSEPORT, shared with the next process
+    ReusePort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HandoverState {
+    Accepting,
+    // Public socket is closed, waiting for running games
+    Draining,
+    // Streams are closed, server is shutting down
+    Stopping,
+}
+
+struct Inner {
+    mode: ListenMode,
+    address: Option<SocketAddr>,
+    drain_timeout: Duration,
+    // Taken by the accept loop on liftoff
+    listener: Mutex<Option<StdListener>>,
+    accept: Mutex<Option<JoinHandle<()>>>,
+    // Client addresses by loopback port of forwarded connections
+    clients: Mutex<HashMap<u16, SocketAddr>>,
+    state: Mutex<HandoverState>,
+    // Seconds since unix epoch
+    drain_started: Mutex<Option<u64>>,
+}
+
+// Public socket forwarded to Rocket, None when the server listens as usual
+#[derive(Clone)]
+pub struct Handover(Option<Arc<Inner>>);
+
+#[derive(Serialize)]
+pub struct HandoverStatus {
+    pub mode: Option<ListenMode>,
+    pub address: Option<SocketAddr>,
+    pub state: HandoverState,
+    pub drain_started: Option<u64>,
+    pub drain_timeout_secs: u64,
+    pub forwarded_connections: usize,
+    pub running_games: usize,
+}
+
+// Subsystems draining waits for, taken from managed state on liftoff
+#[derive(Clone)]
+struct Drained {
+    matches: TetrisMatches,
+    arenas: Arenas,
+    connections: Connections,
+    shutdown: Shutdown,
+}
+
+impl Drained {
+    fn of(rocket: &Rocket<Orbit>) -> Option<Drained> {
+        Some(Drained {
+            matches: rocket.state::<TetrisMatches>()?.clone(),
+            arenas: rocket.state::<Arenas>()?.clone(),
+            connections: rocket.state::<Connections>()?.clone(),
+            shutdown: rocket.shutdown(),
+        })
+    }
+
+    fn running(&self) -> usize {
+        self.matches.running() + self.arenas.running()
+    }
+}
+
+// Socket passed by systemd to this process, see sd_listen_fds(3)
+fn inherited_listener() -> Option<StdListener> {
+    let pid = std::env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let fds = std::env::var("LISTEN_FDS").ok()?.parse::<i32>().ok()?;
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+    // Descriptor is owned by this process from now on, nothing else uses it
+    Some(unsafe { StdListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+fn reuse_port_listener(address: SocketAddr) -> Result<StdListener, Error> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+impl Handover {
+    // Take inherited socket or bind handover_address, before Rocket starts
+    pub fn from_config() -> Result<Handover, Error> {
+        let figment = Config::figment();
+        let (mode, listener) = match inherited_listener() {
+            Some(listener) => (ListenMode::Inherited, listener),
+            None => match figment.extract_inner::<String>("handover_address") {
+                Ok(address) => {
+                    let address = address.parse::<SocketAddr>().map_err(|e| {
+                        Error::InvalidInputError(format!("Invalid handover_address: {}", e))
+                    })?;
+                    (ListenMode::ReusePort, reuse_port_listener(address)?)
+                }
+                Err(_) => return Ok(Handover(None)),
+            },
+        };
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr().ok();
+        println!(
+            "Handover: listening on {:?} with {:?} socket",
+            address, mode
+        );
+        Ok(Handover(Some(Arc::new(Inner {
+            mode,
+            address,
+            drain_timeout: Duration::from_secs(
+                figment
+                    .extract_inner::<u64>("drain_timeout_secs")
+                    .unwrap_or(DEFAULT_DRAIN_TIMEOUT),
+            ),
+            listener: Mutex::new(Some(listener)),
+            accept: Mutex::new(None),
+            clients: Mutex::new(HashMap::new()),
+            state: Mutex::new(HandoverState::Accepting),
+            drain_started: Mutex::new(None),
+        }))))
+    }
+
+    // Open the database, waiting for the old process to release it
+    pub async fn open_database(&self, path: &str) -> Result<Database, Error> {
+        let Some(inner) = &self.0 else {
+            return Database::open(path);
+        };
+        let deadline = Instant::now() + inner.drain_timeout + DATABASE_WAIT_MARGIN;
+        let mut waiting = false;
+        loop {
+            match Database::open(path) {
+                Ok(db) => return Ok(db),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => {
+                    if !waiting {
+                        println!("Handover: waiting for the old process to release the database");
+                        waiting = true;
+                    }
+                    time::sleep(DATABASE_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    // Rocket behind the public socket listens on a loopback port of it's own
+    pub fn figment(&self, figment: Figment) -> Figment {
+        match self.0 {
+            Some(_) => figment
+                .merge(("address", IpAddr::from([127, 0, 0, 1])))
+                .merge(("port", 0)),
+            None => figment,
+        }
+    }
+
+    // Client address of connection forwarded from the public socket
+    pub fn client_of(&self, peer: SocketAddr) -> Option<SocketAddr> {
+        let inner = self.0.as_ref()?;
+        if !peer.ip().is_loopback() {
+            return None;
+        }
+        inner.clients.lock().unwrap().get(&peer.port()).copied()
+    }
+
+    fn start(&self, rocket: &Rocket<Orbit>) {
+        let Some(inner) = self.0.clone() else {
+            return;
+        };
+        let Some(listener) = inner.listener.lock().unwrap().take() else {
+            return;
+        };
+        let target = SocketAddr::new(rocket.config().address, rocket.config().port);
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Handover: failed to listen: {}", e);
+                return;
+            }
+        };
+        let accepting = inner.clone();
+        // Accepting and forwarding aren't lifecycle jobs: accepting is aborted when draining
+        // begins, then forwarded connections end with their clients
+        *inner.accept.lock().unwrap() = Some(tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((client, address)) => {
+                        tokio::spawn(forward(accepting.clone(), client, address, target));
+                    }
+                    Err(e) => println!("Handover: accept failed: {}", e),
+                }
+            }
+        }));
+        let Some(drained) = Drained::of(rocket) else {
+            return;
+        };
+        // Deploy scripts signal the old process once the next one is listening. Not a
+        // lifecycle job, draining notifies shutdown which would abort it
+        let handover = self.clone();
+        tokio::spawn(async move {
+            let Ok(mut signals) = signal(SignalKind::user_defined2()) else {
+                return;
+            };
+            if signals.recv().await.is_some() && handover.begin_drain(&drained) {
+                handover.drain(drained).await;
+            }
+        });
+    }
+
+    // Stop accepting, returns false unless the server was accepting
+    fn begin_drain(&self, drained: &Drained) -> bool {
+        let Some(inner) = &self.0 else {
+            return false;
+        };
+        {
+            let mut state = inner.state.lock().unwrap();
+            if *state != HandoverState::Accepting {
+                return false;
+            }
+            *state = HandoverState::Draining;
+        }
+        // Dropping the socket sends new connections to the next process
+        if let Some(accept) = inner.accept.lock().unwrap().take() {
+            accept.abort();
+        }
+        *inner.drain_started.lock().unwrap() = Some(crate::unix_time());
+        println!(
+            "Handover: draining, {} running games, shutdown within {:?}",
+            drained.running(),
+            inner.drain_timeout
+        );
+        true
+    }
+
+    // Shut down once running games are over
+    async fn drain(self, drained: Drained) {
+        let Some(inner) = &self.0 else {
+            return;
+        };
+        let deadline = Instant::now() + inner.drain_timeout;
+        let mut interval = time::interval(DRAIN_CHECK_INTERVAL);
+        while drained.running() > 0 && Instant::now() < deadline {
+            interval.tick().await;
+        }
+        *inner.state.lock().unwrap() = HandoverState::Stopping;
+        let running = drained.running();
+        let closed = drained.connections.close_all();
+        println!(
+            "Handover: drained, {} games handed over, {} streams closed",
+            running, closed
+        );
+        // Let closed streams end before connections are cut
+        time::sleep(DRAIN_CHECK_INTERVAL).await;
+        drained.shutdown.notify();
+    }
+
+    fn status(&self, running: usize) -> HandoverStatus {
+        match &self.0 {
+            Some(inner) => HandoverStatus {
+                mode: Some(inner.mode),
+                address: inner.address,
+                state: *inner.state.lock().unwrap(),
+                drain_started: *inner.drain_started.lock().unwrap(),
+                drain_timeout_secs: inner.drain_timeout.as_secs(),
+                forwarded_connections: inner.clients.lock().unwrap().len(),
+                running_games: running,
+            },
+            None => HandoverStatus {
+                mode: None,
+                address: None,
+                state: HandoverState::Accepting,
+                drain_started: None,
+                drain_timeout_secs: 0,
+                forwarded_connections: 0,
+                running_games: running,
+            },
+        }
+    }
+}
+
+// Forward connection of the public socket to Rocket until either side closes it
+async fn forward(
+    inner: Arc<Inner>,
+    mut client: TcpStream,
+    address: SocketAddr,
+    target: SocketAddr,
+) {
+    let mut server = match TcpStream::connect(target).await {
+        Ok(server) => server,
+        Err(e) => {
+            println!(
+                "Handover: failed to forward connection of {}: {}",
+                address, e
+            );
+            return;
+        }
+    };
+    let _ = client.set_nodelay(true);
+    let _ = server.set_nodelay(true);
+    let port = server.local_addr().map(|local| local.port()).ok();
+    if let Some(port) = port {
+        inner.clients.lock().unwrap().insert(port, address);
+    }
+    let _ = copy_bidirectional(&mut client, &mut server).await;
+    if let Some(port) = port {
+        inner.clients.lock().unwrap().remove(&port);
+    }
+}
+
+// Start forwarding once Rocket listens on it's loopback port
+#[rocket::async_trait]
+impl Fairing for Handover {
+    fn info(&self) -> Info {
+        Info {
+            name: "Socket handover",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        self.start(rocket);
+    }
+}
+
+#[get("/admin/handover")]
+fn handover_status(
+    _admin: Viewer,
+    handover: &State<Handover>,
+    matches: &State<TetrisMatches>,
+    arenas: &State<Arenas>,
+) -> Json<HandoverStatus> {
+    Json(handover.status(matches.running() + arenas.running()))
+}
+
+// Hand connections over to the next server process listening on the same socket
+#[post("/admin/handover/drain")]
+fn start_drain(
+    _admin: Operator,
+    handover: &State<Handover>,
+    matches: &State<TetrisMatches>,
+    arenas: &State<Arenas>,
+    connections: &State<Connections>,
+    shutdown: Shutdown,
+) -> Result<Json<HandoverStatus>, Error> {
+    if handover.0.is_none() {
+        return Err(Error::NotFoundError(
+            "Server doesn't listen on a handover socket".to_string(),
+        ));
+    }
+    let drained = Drained {
+        matches: matches.inner().clone(),
+        arenas: arenas.inner().clone(),
+        connections: connections.inner().clone(),
+        shutdown,
+    };
+    if !handover.begin_drain(&drained) {
+        return Err(Error::InvalidInputError(
+            "Server is draining already".to_string(),
+        ));
+    }
+    let status = handover.status(drained.running());
+    // Not a lifecycle job, see Handover::start
+    tokio::spawn(handover.inner().clone().drain(drained));
+    Ok(Json(status))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![handover_status, start_drain]
+}
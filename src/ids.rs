@@ -0,0 +1,76 @@
+use std::fmt;
+use std::str::FromStr;
+
+use persy::PersyId;
+use rocket::{
+    form::{self, FromFormField, ValueField},
+    request::FromParam,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::Error;
+
+//
+// Typed identifiers, so ids of users, games and matches can't be mixed up. They're
+// (de)serialized as the values they wrap, stored records and json responses keep the same
+// format. Ids are accepted as route parameters and form fields. Ids of users and matches
+// are part of the wire protocol, see gameserver_protocol
+//
+
+pub use gameserver_protocol::ids::{MatchId, UserId};
+
+// Finished game, id of it's leaderboard entry. Serialized as string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GameId(pub PersyId);
+
+impl fmt::Display for GameId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for GameId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(GameId)
+            .map_err(|_| Error::NotFoundError(format!("Invalid game id {}", s)))
+    }
+}
+
+impl From<PersyId> for GameId {
+    fn from(id: PersyId) -> Self {
+        GameId(id)
+    }
+}
+
+impl Serialize for GameId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for GameId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'a> FromParam<'a> for GameId {
+    type Error = Error;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        param.parse()
+    }
+}
+
+impl<'v> FromFormField<'v> for GameId {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        field
+            .value
+            .parse()
+            .map_err(|e: Error| form::Error::validation(e.to_string()).into())
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use rocket::FromForm;
+
+use crate::error::Error;
+
+use crate::ids::UserId;
+use crate::settings::SettingsWatch;
+use crate::tetris::TetrisGameState;
+
+//
+// Input sequence numbers. Each game stream starts a new input epoch, sent to the client
+// as "input_epoch" event. Inputs of the epoch go to the game of the stream only. Client numbers inputs of the epoch with increasing sequence
+// numbers starting from 1. Inputs may arrive out of order within a window of recent numbers,
+// duplicates, numbers older than the window and inputs of previous epochs are rejected.
+// Number of inputs per second is limited as well.
+//
+// Accepted inputs are acknowledged in the game stream with "ack" events {"seq", "version"}:
+// version is the step of the player's game at which the input was put into the game.
+// Game states carry their version too, and a state with version greater than the ack's
+// includes the input. Clients predicting inputs locally replay inputs not acknowledged or
+// newer than the received state on top of it. Inputs refused before the match start are
+// not acknowledged.
+//
+// Rejected inputs of the current epoch are answered with "correction" events instead, so
+// that predicting client doesn't wait for a divergence to show up in full states. Input
+// may name the version client has predicted it at with "version" parameter, game streams
+// keep HISTORY recent states of the player and the correction is the state at that version:
+// {"seq", "version", "state"}. Client drops it's prediction after that version and replays
+// inputs still pending from the correction. Without version, or when the version is not in
+// the history any more, the correction is the latest state
+//
+
+// Number of recent sequence numbers accepted out of order
+const WINDOW: u64 = 64;
+// Recent states kept by game streams for corrections, steps
+const HISTORY: usize = 64;
+// Maximal gap between the highest accepted number and the next one
+const MAX_JUMP: u64 = 1024;
+// Maximal number of inputs per user in one second, unless configured, see settings
+pub const DEFAULT_RATE_LIMIT: usize = 60;
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+
+// Acknowledgement of accepted input, see above
+pub use gameserver_protocol::game::InputAck;
+
+// Correction of rejected input, see above
+pub type Correction = gameserver_protocol::game::Correction<TetrisGameState>;
+
+// Sequence parameters of input request, with version predicted by client
+#[derive(FromForm)]
+pub struct InputSeq {
+    epoch: Option<u32>,
+    seq: Option<u64>,
+    version: Option<u64>,
+}
+
+// Rejected input waiting for correction
+#[derive(Debug, Clone, Copy)]
+pub struct Rejection {
+    seq: u64,
+    version: Option<u64>,
+}
+
+// Recent player's states of a game stream, oldest first
+#[derive(Default)]
+pub struct StateHistory(VecDeque<TetrisGameState>);
+
+// Game inputs of an epoch are put into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputTarget {
+    // Versus match of the server
+    Versus,
+    Sprint,
+    // Versus match of an arena
+    Arena,
+}
+
+struct SeqWindow {
+    epoch: u32,
+    target: InputTarget,
+    // Highest accepted number and bitmask of accepted numbers below it,
+    // bit n is number highest - n
+    highest: u64,
+    seen: u64,
+    // Start of current rate period and number of inputs in it
+    period_start: Instant,
+    period_inputs: usize,
+    // Acknowledgements and rejections not sent yet, the oldest are dropped above WINDOW
+    acks: Vec<InputAck>,
+    rejections: Vec<Rejection>,
+}
+
+// Windows of users and settings with the rate limit
+#[derive(Clone)]
+pub struct InputSequences(Arc<RwLock<HashMap<UserId, SeqWindow>>>, SettingsWatch);
+
+impl InputSequences {
+    pub fn new(settings: SettingsWatch) -> InputSequences {
+        InputSequences(Arc::new(RwLock::new(HashMap::new())), settings)
+    }
+
+    // Start new epoch for the user's game, inputs of previous epochs are rejected from now
+    pub fn new_epoch(&self, user: UserId, target: InputTarget) -> u32 {
+        let epoch = rand::random();
+        self.0.write().unwrap().insert(
+            user,
+            SeqWindow {
+                epoch,
+                target,
+                highest: 0,
+                seen: 1,
+                period_start: Instant::now(),
+                period_inputs: 0,
+                acks: Vec::new(),
+                rejections: Vec::new(),
+            },
+        );
+        epoch
+    }
+
+    // Accept input with given sequence parameters or tell why it's rejected.
+    // Returns sequence number of the input and the game it's for
+    pub fn accept(&self, user: UserId, input: &InputSeq) -> Result<(u64, InputTarget), Error> {
+        let (Some(epoch), Some(seq)) = (input.epoch, input.seq) else {
+            return Err(Error::InvalidInputError(
+                "Input epoch and sequence number are required".to_string(),
+            ));
+        };
+        let mut windows = self.0.write().unwrap();
+        let window = windows
+            .get_mut(&user)
+            .filter(|window| window.epoch == epoch)
+            .ok_or_else(|| Error::InvalidInputError(format!("Unknown input epoch {}", epoch)))?;
+        if window.period_start.elapsed() >= RATE_PERIOD {
+            window.period_start = Instant::now();
+            window.period_inputs = 0;
+        }
+        if window.period_inputs >= self.1.borrow().input_rate_limit {
+            return Err(Error::RateLimitError("Too many inputs".to_string()));
+        }
+        if seq > window.highest {
+            let shift = seq - window.highest;
+            if shift > MAX_JUMP {
+                return Err(Error::InvalidInputError(format!(
+                    "Sequence number {} is too far ahead",
+                    seq
+                )));
+            }
+            window.seen = if shift >= WINDOW {
+                1
+            } else {
+                (window.seen << shift) | 1
+            };
+            window.highest = seq;
+        } else {
+            let offset = window.highest - seq;
+            if offset >= WINDOW {
+                return Err(Error::InvalidInputError(format!(
+                    "Sequence number {} is too old",
+                    seq
+                )));
+            }
+            if window.seen & (1 << offset) != 0 {
+                return Err(Error::InvalidInputError(format!(
+                    "Duplicate sequence number {}",
+                    seq
+                )));
+            }
+            window.seen |= 1 << offset;
+        }
+        window.period_inputs += 1;
+        Ok((seq, window.target))
+    }
+
+    // Acknowledge input put into the user's game at given version
+    pub fn ack(&self, user: UserId, seq: u64, version: u64) {
+        if let Some(window) = self.0.write().unwrap().get_mut(&user) {
+            if window.acks.len() as u64 >= WINDOW {
+                window.acks.remove(0);
+            }
+            window.acks.push(InputAck { seq, version });
+        }
+    }
+
+    // Remember rejected input of the current epoch for correction
+    pub fn reject(&self, user: UserId, input: &InputSeq) {
+        let (Some(epoch), Some(seq)) = (input.epoch, input.seq) else {
+            return;
+        };
+        let mut windows = self.0.write().unwrap();
+        let Some(window) = windows
+            .get_mut(&user)
+            .filter(|window| window.epoch == epoch)
+        else {
+            return;
+        };
+        if window.rejections.len() as u64 >= WINDOW {
+            window.rejections.remove(0);
+        }
+        window.rejections.push(Rejection {
+            seq,
+            version: input.version,
+        });
+    }
+
+    // Acknowledgements and rejections to send in the user's game stream
+    pub fn take_feedback(&self, user: UserId) -> (Vec<InputAck>, Vec<Rejection>) {
+        // Read lock is enough for streams without new inputs
+        let pending = self
+            .0
+            .read()
+            .unwrap()
+            .get(&user)
+            .is_some_and(|window| !window.acks.is_empty() || !window.rejections.is_empty());
+        if !pending {
+            return (Vec::new(), Vec::new());
+        }
+        match self.0.write().unwrap().get_mut(&user) {
+            Some(window) => (
+                std::mem::take(&mut window.acks),
+                std::mem::take(&mut window.rejections),
+            ),
+            None => (Vec::new(), Vec::new()),
+        }
+    }
+}
+
+impl StateHistory {
+    pub fn push(&mut self, state: TetrisGameState) {
+        if self.0.len() == HISTORY {
+            self.0.pop_front();
+        }
+        self.0.push_back(state);
+    }
+
+    // Corrections of rejected inputs, the latest state for unknown versions
+    pub fn corrections(&self, rejections: &[Rejection]) -> Vec<Correction> {
+        rejections
+            .iter()
+            .filter_map(|rejection| {
+                let state = rejection
+                    .version
+                    .and_then(|version| self.0.iter().find(|state| state.version() == version))
+                    .or(self.0.back())?;
+                Some(Correction {
+                    seq: rejection.seq,
+                    version: state.version(),
+                    state: state.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::tokio::sync::watch;
+
+    use super::*;
+    use crate::settings::Settings;
+    use crate::tetris::Tetris;
+
+    const USER: UserId = UserId(1);
+
+    fn sequences(rate_limit: usize) -> InputSequences {
+        let settings = Settings {
+            input_rate_limit: rate_limit,
+            ..Settings::from_config()
+        };
+        InputSequences::new(watch::channel(settings).1)
+    }
+
+    fn input(epoch: u32, seq: u64) -> InputSeq {
+        InputSeq {
+            epoch: Some(epoch),
+            seq: Some(seq),
+            version: None,
+        }
+    }
+
+    fn accepted(sequences: &InputSequences, epoch: u32, seq: u64) -> bool {
+        sequences.accept(USER, &input(epoch, seq)).is_ok()
+    }
+
+    #[test]
+    fn inputs_are_accepted_out_of_order_once() {
+        let sequences = sequences(1000);
+        let epoch = sequences.new_epoch(USER, InputTarget::Sprint);
+        assert_eq!(
+            sequences.accept(USER, &input(epoch, 3)).unwrap(),
+            (3, InputTarget::Sprint)
+        );
+        assert!(accepted(&sequences, epoch, 1));
+        assert!(accepted(&sequences, epoch, 2));
+        assert!(!accepted(&sequences, epoch, 3));
+        assert!(!accepted(&sequences, epoch, 1));
+        assert!(accepted(&sequences, epoch, 5));
+        assert!(accepted(&sequences, epoch, 4));
+        assert!(!accepted(&sequences, epoch, 4));
+    }
+
+    #[test]
+    fn zero_is_never_accepted() {
+        let sequences = sequences(1000);
+        let epoch = sequences.new_epoch(USER, InputTarget::Versus);
+        assert!(!accepted(&sequences, epoch, 0));
+        assert!(accepted(&sequences, epoch, 1));
+        assert!(!accepted(&sequences, epoch, 0));
+    }
+
+    #[test]
+    fn numbers_older_than_window_are_rejected() {
+        let sequences = sequences(1000);
+        let epoch = sequences.new_epoch(USER, InputTarget::Versus);
+        assert!(accepted(&sequences, epoch, 100));
+        assert!(!accepted(&sequences, epoch, 100 - WINDOW));
+        assert!(accepted(&sequences, epoch, 100 - WINDOW + 1));
+        assert!(!accepted(&sequences, epoch, 100 - WINDOW + 1));
+    }
+
+    #[test]
+    fn shift_by_window_or_more_forgets_older_numbers() {
+        let sequences = sequences(1000);
+        let epoch = sequences.new_epoch(USER, InputTarget::Versus);
+        assert!(accepted(&sequences, epoch, 10));
+        // Numbers seen before the shift fall out of the window
+        assert!(accepted(&sequences, epoch, 10 + WINDOW));
+        assert!(!accepted(&sequences, epoch, 10));
+        assert!(accepted(&sequences, epoch, 11));
+        assert!(!accepted(&sequences, epoch, 10 + WINDOW));
+        // Shift by exactly the window keeps only the new number
+        assert!(accepted(&sequences, epoch, 10 + 2 * WINDOW));
+        assert!(accepted(&sequences, epoch, 11 + WINDOW));
+        assert!(!accepted(&sequences, epoch, 11 + WINDOW));
+        // Shift within the window keeps numbers seen before
+        assert!(accepted(&sequences, epoch, 11 + 2 * WINDOW));
+        assert!(!accepted(&sequences, epoch, 10 + 2 * WINDOW));
+        assert!(!accepted(&sequences, epoch, 11 + WINDOW));
+    }
+
+    #[test]
+    fn jumps_over_max_jump_are_rejected() {
+        let sequences = sequences(1000);
+        let epoch = sequences.new_epoch(USER, InputTarget::Versus);
+        assert!(!accepted(&sequences, epoch, MAX_JUMP + 1));
+        assert!(accepted(&sequences, epoch, MAX_JUMP));
+        assert!(!accepted(&sequences, epoch, 2 * MAX_JUMP + 1));
+        assert!(accepted(&sequences, epoch, 2 * MAX_JUMP));
+    }
+
+    #[test]
+    fn inputs_of_other_epochs_are_rejected() {
+        let sequences = sequences(1000);
+        let old = sequences.new_epoch(USER, InputTarget::Versus);
+        assert!(accepted(&sequences, old, 1));
+        let epoch = sequences.new_epoch(USER, InputTarget::Arena);
+        assert!(!accepted(&sequences, epoch.wrapping_add(1), 2));
+        // Epochs are random, the new one may repeat the old one
+        assert!(old == epoch || !accepted(&sequences, old, 2));
+        assert_eq!(
+            sequences.accept(USER, &input(epoch, 1)).unwrap(),
+            (1, InputTarget::Arena)
+        );
+        assert!(!accepted(&sequences, epoch, 1));
+        // Inputs without sequence parameters and of users without epoch
+        let unnumbered = InputSeq {
+            epoch: Some(epoch),
+            seq: None,
+            version: None,
+        };
+        assert!(sequences.accept(USER, &unnumbered).is_err());
+        assert!(sequences.accept(UserId(2), &input(epoch, 2)).is_err());
+    }
+
+    #[test]
+    fn rate_limit_resets_each_period() {
+        let sequences = sequences(3);
+        let epoch = sequences.new_epoch(USER, InputTarget::Versus);
+        assert!((1..=3).all(|seq| accepted(&sequences, epoch, seq)));
+        assert!(matches!(
+            sequences.accept(USER, &input(epoch, 4)),
+            Err(Error::RateLimitError(_))
+        ));
+        // Rejected input didn't use up it's number
+        let expired = Instant::now() - RATE_PERIOD;
+        sequences
+            .0
+            .write()
+            .unwrap()
+            .get_mut(&USER)
+            .unwrap()
+            .period_start = expired;
+        assert!(accepted(&sequences, epoch, 4));
+        assert!(accepted(&sequences, epoch, 5));
+        assert!(accepted(&sequences, epoch, 6));
+        assert!(!accepted(&sequences, epoch, 7));
+    }
+
+    // History of a game at versions 0 to steps
+    fn history(steps: usize) -> StateHistory {
+        let mut tetris = Tetris::new(10, 20);
+        let mut history = StateHistory::default();
+        history.push(tetris.get_game_state());
+        for _ in 0..steps {
+            tetris.step();
+            history.push(tetris.get_game_state());
+        }
+        history
+    }
+
+    fn rejection(seq: u64, version: Option<u64>) -> Rejection {
+        Rejection { seq, version }
+    }
+
+    #[test]
+    fn corrections_are_states_at_predicted_versions() {
+        let history = history(5);
+        let corrections = history.corrections(&[rejection(7, Some(2)), rejection(8, Some(0))]);
+        let versions = corrections
+            .iter()
+            .map(|correction| {
+                (
+                    correction.seq,
+                    correction.version,
+                    correction.state.version(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(versions, [(7, 2, 2), (8, 0, 0)]);
+    }
+
+    #[test]
+    fn corrections_fall_back_to_latest_state() {
+        let history = history(HISTORY + 10);
+        let latest = (HISTORY + 10) as u64;
+        // Without version, with version dropped from the history and with future version
+        let corrections = history.corrections(&[
+            rejection(1, None),
+            rejection(2, Some(3)),
+            rejection(3, Some(latest + 1)),
+        ]);
+        assert!(
+            corrections
+                .iter()
+                .all(|correction| correction.version == latest
+                    && correction.state.version() == latest)
+        );
+        assert_eq!(corrections.len(), 3);
+        assert!(StateHistory::default()
+            .corrections(&[rejection(1, None)])
+            .is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rocket::{post, routes, serde::json::Json, tokio, FromForm, Route};
+use serde::Serialize;
+
+use crate::{
+    difficulty::Difficulty,
+    error::Error,
+    game_mode::GameMode,
+    game_rng::RngKind,
+    roles::Operator,
+    tetris::{Action, InvariantViolation, Randomizer, Replay, Tetris},
+};
+
+//
+// Invariant checks of game logic, so it's bugs are found without playing. Fuzzing plays
+// games with random inputs at random steps and checks Tetris::check_invariants after every
+// step. Games are seeded from the run seed, a run is reproduced by passing it again, and
+// each violating game is reported with it's replay. Replay verification checks the same
+// invariants on played games
+//
+
+const DEFAULT_GAMES: usize = 100;
+const MAX_GAMES: usize = 1000;
+const DEFAULT_TICKS: u64 = 2000;
+const MAX_TICKS: u64 = 20000;
+// Chance of an input before each step
+const INPUT_CHANCE: f64 = 0.3;
+
+#[derive(FromForm)]
+pub struct FuzzQuery {
+    games: Option<usize>,
+    // Steps per game, games ending earlier are over
+    ticks: Option<u64>,
+    seed: Option<u64>,
+    randomizer: Option<Randomizer>,
+    difficulty: Option<Difficulty>,
+    // Garbage and bottom refill inputs change the field under the falling piece
+    #[field(default = true)]
+    field_inputs: bool,
+    // Games have hold and next queue of sprint mode
+    #[field(default = true)]
+    hold: bool,
+}
+
+#[derive(Serialize)]
+pub struct FuzzFailure {
+    // Step after which the violation was found
+    pub tick: u64,
+    pub violation: InvariantViolation,
+    pub message: String,
+    pub replay: Replay,
+}
+
+#[derive(Serialize)]
+pub struct FuzzReport {
+    pub seed: u64,
+    pub games: usize,
+    pub ticks: u64,
+    pub failures: Vec<FuzzFailure>,
+}
+
+fn random_action(rng: &mut impl Rng, cols: usize, field_inputs: bool) -> Action {
+    let actions = if field_inputs { 9 } else { 7 };
+    match rng.gen_range(0..actions) {
+        0 => Action::MoveLeft,
+        1 => Action::MoveRight,
+        2 => Action::MoveDown,
+        3 => Action::RotateLeft,
+        4 => Action::RotateRight,
+        5 => Action::Drop,
+        6 => Action::Hold,
+        7 => Action::BottomRefill,
+        _ => Action::Garbage {
+            hole: rng.gen_range(0..cols),
+        },
+    }
+}
+
+// Play one game with random inputs, stops at the first violation
+fn fuzz_game(rng: &mut StdRng, query: &FuzzQuery, ticks: u64) -> Option<FuzzFailure> {
+    let mut tetris = Tetris::new_with_seed(
+        10,
+        20,
+        rng.gen(),
+        query.randomizer.unwrap_or_default(),
+        RngKind::default(),
+        Some(query.difficulty.unwrap_or_default()),
+    );
+    if query.hold {
+        tetris = tetris.with_pieces(GameMode::Sprint.pieces());
+    }
+    for _ in 0..ticks {
+        if rng.gen_bool(INPUT_CHANCE) {
+            tetris.add_action(random_action(rng, tetris.get_cols(), query.field_inputs));
+        }
+        tetris.step();
+        if let Err(violation) = tetris.check_invariants() {
+            return Some(FuzzFailure {
+                tick: tetris.get_ticks(),
+                violation,
+                message: violation.to_string(),
+                replay: tetris.get_replay(),
+            });
+        }
+        if tetris.is_game_over() {
+            break;
+        }
+    }
+    None
+}
+
+pub fn fuzz(query: &FuzzQuery) -> FuzzReport {
+    let seed = query.seed.unwrap_or_else(rand::random);
+    let games = query.games.unwrap_or(DEFAULT_GAMES).min(MAX_GAMES);
+    let ticks = query.ticks.unwrap_or(DEFAULT_TICKS).min(MAX_TICKS);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let failures = (0..games)
+        .filter_map(|_| fuzz_game(&mut rng, query, ticks))
+        .collect();
+    FuzzReport {
+        seed,
+        games,
+        ticks,
+        failures,
+    }
+}
+
+// Run fuzzing of game logic, games are simulated in a blocking task
+#[post("/admin/invariants/fuzz?<query..>")]
+async fn run_fuzz(_admin: Operator, query: FuzzQuery) -> Result<Json<FuzzReport>, Error> {
+    let report = tokio::task::spawn_blocking(move || fuzz(&query))
+        .await
+        .map_err(|e| Error::IoError(std::io::Error::other(e)))?;
+    println!(
+        "Invariant fuzzing: seed {}, {} games, {} failures",
+        report.seed,
+        report.games,
+        report.failures.len()
+    );
+    Ok(Json(report))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![run_fuzz]
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::tetris::ReplayPlayer;
+
+    // Input of the game, index of move, rotation, drop, hold, bottom refill or garbage
+    // with the hole, and steps after it
+    fn inputs() -> impl Strategy<Value = Vec<(u8, usize, u8)>> {
+        prop::collection::vec((0u8..9, 0usize..10, 0u8..8), 0..300)
+    }
+
+    fn action(index: u8, hole: usize) -> Action {
+        match index {
+            0 => Action::MoveLeft,
+            1 => Action::MoveRight,
+            2 => Action::MoveDown,
+            3 => Action::RotateLeft,
+            4 => Action::RotateRight,
+            5 => Action::Drop,
+            6 => Action::Hold,
+            7 => Action::BottomRefill,
+            _ => Action::Garbage { hole },
+        }
+    }
+
+    fn game(seed: u64, randomizer: Randomizer) -> Tetris {
+        Tetris::new_with_seed(
+            10,
+            20,
+            seed,
+            randomizer,
+            RngKind::default(),
+            Some(Difficulty::default()),
+        )
+        .with_pieces(GameMode::Sprint.pieces())
+    }
+
+    // Play inputs checking invariants after every input and step
+    fn play(
+        tetris: &mut Tetris,
+        inputs: &[(u8, usize, u8)],
+    ) -> Result<(), (u64, InvariantViolation)> {
+        let check = |tetris: &Tetris| {
+            tetris
+                .check_invariants()
+                .map_err(|violation| (tetris.get_ticks(), violation))
+        };
+        for &(index, hole, steps) in inputs {
+            tetris.add_action(action(index, hole));
+            check(tetris)?;
+            for _ in 0..steps {
+                tetris.step();
+                check(tetris)?;
+            }
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn inputs_keep_invariants(seed: u64, inputs in inputs()) {
+            let mut tetris = game(seed, Randomizer::SevenBag);
+            prop_assert_eq!(play(&mut tetris, &inputs), Ok(()));
+        }
+
+        #[test]
+        fn uniform_pieces_keep_invariants(seed: u64, inputs in inputs()) {
+            let mut tetris = game(seed, Randomizer::Uniform);
+            prop_assert_eq!(play(&mut tetris, &inputs), Ok(()));
+        }
+
+        #[test]
+        fn replay_reproduces_game(seed: u64, inputs in inputs()) {
+            let mut tetris = game(seed, Randomizer::SevenBag);
+            let _ = play(&mut tetris, &inputs);
+            let replayed = ReplayPlayer::new(tetris.get_replay()).check();
+            prop_assert!(replayed.is_ok(), "{:?}", replayed.err());
+            let replayed = replayed.unwrap();
+            prop_assert_eq!(replayed.get_field(), tetris.get_field());
+            prop_assert_eq!(replayed.get_score(), tetris.get_score());
+        }
+    }
+
+    #[test]
+    fn fuzzing_with_field_inputs_finds_nothing() {
+        let report = fuzz(&FuzzQuery {
+            games: Some(50),
+            ticks: Some(2000),
+            seed: Some(1),
+            randomizer: None,
+            difficulty: None,
+            field_inputs: true,
+            hold: true,
+        });
+        let failures = report
+            .failures
+            .iter()
+            .map(|failure| (failure.tick, failure.violation))
+            .collect::<Vec<_>>();
+        assert_eq!(failures, Vec::new());
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use rocket::{http::CookieJar, post, routes, Route, State};
+
+use crate::{connections::Connections, error::Error, ids::UserId, TetrisMatches};
+
+//
+// Round-trip latency of game streams. Stream sends "ping" event with a nonce, client answers
+// with POST /pong/<nonce>. Rolling average of the last round trips is reported back
+// in the stream and used to equalize input delay in versus matches. Connection registry
+// records the connection each ping is sent on, the answer clears that connection's
+// unanswered ping only (see connections)
+//
+
+// Interval between pings of one stream
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+// Number of round trips in rolling average
+const WINDOW: usize = 10;
+// Unanswered pings are dropped after this time
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct UserLatency {
+    // Round trip times, milliseconds
+    samples: VecDeque<u64>,
+}
+
+struct PendingPing {
+    user: UserId,
+    sent: Instant,
+    // Connection the ping is sent on, once it's known
+    connection: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+pub struct Latency {
+    next_nonce: Arc<AtomicU64>,
+    // Sent pings by nonce
+    pending: Arc<RwLock<HashMap<u64, PendingPing>>>,
+    users: Arc<RwLock<HashMap<UserId, UserLatency>>>,
+}
+
+impl Latency {
+    pub fn new() -> Latency {
+        Latency::default()
+    }
+
+    // Register ping sent to the user, returns it's nonce
+    pub fn ping(&self, user: UserId) -> u64 {
+        let nonce = self.next_nonce.fetch_add(1, Ordering::Relaxed);
+        let mut pending = self.pending.write().unwrap();
+        pending.retain(|_, ping| ping.sent.elapsed() < PING_TIMEOUT);
+        pending.insert(
+            nonce,
+            PendingPing {
+                user,
+                sent: Instant::now(),
+                connection: None,
+            },
+        );
+        nonce
+    }
+
+    // Register connection the ping is sent on
+    pub fn sent_on(&self, nonce: u64, connection: u64) {
+        if let Some(ping) = self.pending.write().unwrap().get_mut(&nonce) {
+            ping.connection = Some(connection);
+        }
+    }
+
+    // Register answer to the ping. Returns measured round trip, milliseconds, and the
+    // connection the ping was sent on
+    pub fn pong(&self, user: UserId, nonce: u64) -> Option<(u64, Option<u64>)> {
+        let mut pending = self.pending.write().unwrap();
+        match pending.get(&nonce) {
+            Some(ping) if ping.user == user => (),
+            _ => return None,
+        }
+        let ping = pending.remove(&nonce)?;
+        let rtt = ping.sent.elapsed().as_millis() as u64;
+        let mut users = self.users.write().unwrap();
+        let samples = &mut users.entry(user).or_default().samples;
+        samples.push_back(rtt);
+        if samples.len() > WINDOW {
+            samples.pop_front();
+        }
+        Some((rtt, ping.connection))
+    }
+
+    // Rolling average round trip of the user, milliseconds
+    pub fn average(&self, user: UserId) -> Option<u64> {
+        let users = self.users.read().unwrap();
+        let samples = &users.get(&user)?.samples;
+        if samples.is_empty() {
+            return None;
+        }
+        Some(samples.iter().sum::<u64>() / samples.len() as u64)
+    }
+}
+
+// Answer to ping event of the game stream
+#[post("/pong/<nonce>")]
+fn pong(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    latency: &State<Latency>,
+    connections: &State<Connections>,
+    nonce: u64,
+) -> Result<(), Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let (_, connection) = latency
+        .pong(user_id, nonce)
+        .ok_or_else(|| Error::NotFoundError("Ping not found".to_string()))?;
+    // Client reads the stream of the ping, it's not stale
+    if let Some(connection) = connection {
+        connections.pong(connection);
+    }
+    Ok(())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![pong]
+}
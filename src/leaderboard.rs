@@ -0,0 +1,462 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{
+    get,
+    http::{uri::Origin, ContentType, CookieJar},
+    response::stream::TextStream,
+    routes,
+    serde::json::serde_json,
+    FromForm, FromFormField, Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::Caches,
+    difficulty::Difficulty,
+    error::Error,
+    friends::{self, Friends},
+    game_mode::GameMode,
+    ids::{GameId, UserId},
+    pagination::{self, Page, SortOrder},
+    regions,
+    roles::Viewer,
+    storage::{self, Database},
+};
+
+//
+// Results of finished games, indexed by score and by finish time
+//
+
+const LEADERBOARD_SEGMENT: &str = "leaderboard";
+const BY_SCORE_INDEX: &str = "leaderboard_by_score";
+const BY_TIME_INDEX: &str = "leaderboard_by_time";
+const BY_USER_INDEX: &str = "leaderboard_by_user";
+
+// Entries read from database at once by export
+const EXPORT_BATCH: usize = 500;
+const CSV_HEADER: &str =
+    "id,user,mode,difficulty,score,lines,ticks,finished,opponent,replay,verification\n";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub user: UserId,
+    pub mode: GameMode,
+    // Difficulty preset, None for games played before presets
+    #[serde(default)]
+    pub difficulty: Option<Difficulty>,
+    pub score: u64,
+    pub lines: u64,
+    // Game duration in steps
+    #[serde(default)]
+    pub ticks: u64,
+    // Pieces placed, 0 for games finished before pieces were counted
+    #[serde(default)]
+    pub pieces: u64,
+    // Time when game was finished, seconds since unix epoch
+    pub finished: u64,
+    pub opponent: Option<UserId>,
+    // Replay of the game, when recorded
+    #[serde(default)]
+    pub replay: Option<String>,
+    // Result of replay re-simulation
+    #[serde(default)]
+    pub verification: Verification,
+    // Audit of the game's pieces, for ranked games, see rng_audit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rng_audit: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum Verification {
+    // Replay was not checked yet
+    #[default]
+    Unverified,
+    // Replay reproduces the claimed score
+    Verified,
+    // Replay doesn't reproduce the claimed score
+    Rejected,
+    // Player opted out of recording, there is no replay to verify the score
+    Unrecorded,
+}
+
+// Entry with it's database id for listings
+#[derive(Serialize)]
+pub struct LeaderboardItem {
+    pub id: GameId,
+    #[serde(flatten)]
+    pub entry: LeaderboardEntry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum LeaderboardSort {
+    // Best scores first
+    Score,
+    // Latest games first
+    Recent,
+}
+
+#[derive(FromForm)]
+pub struct LeaderboardQuery<'r> {
+    pub mode: Option<GameMode>,
+    pub difficulty: Option<Difficulty>,
+    // Rejected entries are hidden unless requested explicitly
+    pub verification: Option<Verification>,
+    pub sort: Option<LeaderboardSort>,
+    pub order: Option<SortOrder>,
+    // Finish time range, seconds since unix epoch
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // Entries of users of the country, see regions
+    pub country: Option<&'r str>,
+    // Entries of friends of the signed in user, see friends
+    pub friends: Option<bool>,
+    pub cursor: Option<&'r str>,
+    pub limit: Option<usize>,
+}
+
+// Filters of leaderboard export, all entries including rejected ones by default
+#[derive(FromForm)]
+pub struct ExportQuery {
+    mode: Option<GameMode>,
+    difficulty: Option<Difficulty>,
+    verification: Option<Verification>,
+    // Finish time range, seconds since unix epoch
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, LEADERBOARD_SEGMENT)?;
+    storage::ensure_index::<u64, PersyId>(persy, BY_SCORE_INDEX, ValueMode::Cluster)?;
+    storage::ensure_index::<u64, PersyId>(persy, BY_TIME_INDEX, ValueMode::Cluster)?;
+    if storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Cluster)? {
+        // Index entries stored before index was introduced
+        let mut tx = persy.begin()?;
+        for (id, entry) in storage::scan::<LeaderboardEntry>(persy, LEADERBOARD_SEGMENT)? {
+            tx.put(BY_USER_INDEX, entry.user.0, id)?;
+        }
+        tx.prepare()?.commit()?;
+    }
+    Ok(())
+}
+
+// Store result of finished game
+pub fn record_in_tx(tx: &mut Transaction, entry: &LeaderboardEntry) -> Result<PersyId, Error> {
+    let id = storage::insert_in_tx(tx, LEADERBOARD_SEGMENT, entry)?;
+    tx.put(BY_SCORE_INDEX, entry.score, id)?;
+    tx.put(BY_TIME_INDEX, entry.finished, id)?;
+    tx.put(BY_USER_INDEX, entry.user.0, id)?;
+    Ok(id)
+}
+
+pub fn read(persy: &Persy, id: &PersyId) -> Result<Option<LeaderboardEntry>, Error> {
+    storage::read(persy, LEADERBOARD_SEGMENT, id)
+}
+
+// Replace entry keeping it's score, finish time and user, which are indexed
+pub fn update_in_tx(
+    tx: &mut Transaction,
+    id: &PersyId,
+    entry: &LeaderboardEntry,
+) -> Result<(), Error> {
+    storage::update_in_tx(tx, LEADERBOARD_SEGMENT, id, entry)
+}
+
+pub fn set_verification(
+    persy: &Persy,
+    id: &PersyId,
+    verification: Verification,
+) -> Result<(), Error> {
+    if let Some(mut entry) = read(persy, id)? {
+        entry.verification = verification;
+        storage::update(persy, LEADERBOARD_SEGMENT, id, &entry)?;
+    }
+    Ok(())
+}
+
+// All entries of the user
+pub fn entries_of_user(
+    persy: &Persy,
+    user: UserId,
+) -> Result<Vec<(PersyId, LeaderboardEntry)>, Error> {
+    let mut entries = Vec::new();
+    for id in persy.get::<u32, PersyId>(BY_USER_INDEX, &user.0)? {
+        if let Some(entry) = read(persy, &id)? {
+            entries.push((id, entry));
+        }
+    }
+    Ok(entries)
+}
+
+// Rewrite replay and RNG audit references after they got new ids (see compaction)
+pub fn remap_replays(persy: &Persy, ids: &HashMap<PersyId, PersyId>) -> Result<(), Error> {
+    let remap = |id: &mut Option<String>| -> Result<bool, Error> {
+        let Some(old) = id else {
+            return Ok(false);
+        };
+        let Some(new) = ids.get(&storage::parse_id(old)?) else {
+            return Ok(false);
+        };
+        *id = Some(new.to_string());
+        Ok(true)
+    };
+    for (id, mut entry) in storage::scan::<LeaderboardEntry>(persy, LEADERBOARD_SEGMENT)? {
+        let replay = remap(&mut entry.replay)?;
+        if remap(&mut entry.rng_audit)? || replay {
+            storage::update(persy, LEADERBOARD_SEGMENT, &id, &entry)?;
+        }
+    }
+    Ok(())
+}
+
+// Entries with replay which were not verified yet
+pub fn unverified(persy: &Persy) -> Result<Vec<PersyId>, Error> {
+    Ok(
+        storage::scan::<LeaderboardEntry>(persy, LEADERBOARD_SEGMENT)?
+            .into_iter()
+            .filter(|(_, entry)| {
+                entry.replay.is_some() && entry.verification == Verification::Unverified
+            })
+            .map(|(id, _)| id)
+            .collect(),
+    )
+}
+
+// Latest results, newest first
+pub fn recent(persy: &Persy, limit: usize) -> Result<Vec<(PersyId, LeaderboardEntry)>, Error> {
+    let mut entries = Vec::new();
+    for (_, ids) in persy.range::<u64, PersyId, _>(BY_TIME_INDEX, ..)?.rev() {
+        for id in ids.rev() {
+            if entries.len() == limit {
+                return Ok(entries);
+            }
+            if let Some(entry) = read(persy, &id)? {
+                entries.push((id, entry));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Finish time of the oldest stored result
+pub fn first_finished(persy: &Persy) -> Result<Option<u64>, Error> {
+    Ok(persy
+        .range::<u64, PersyId, _>(BY_TIME_INDEX, ..)?
+        .next()
+        .map(|(finished, _)| finished))
+}
+
+// All results of games finished in time range [from, to)
+pub fn finished_between(persy: &Persy, from: u64, to: u64) -> Result<Vec<LeaderboardEntry>, Error> {
+    let mut entries = Vec::new();
+    for (_, ids) in persy.range::<u64, PersyId, _>(BY_TIME_INDEX, from..to)? {
+        for id in ids {
+            if let Some(entry) = storage::read(persy, LEADERBOARD_SEGMENT, &id)? {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn list(persy: &Persy, query: &LeaderboardQuery) -> Result<Page<LeaderboardItem>, Error> {
+    list_of_users(persy, query, None)
+}
+
+// Entries of the users only, users of the query's country are looked up by regions
+pub fn list_of_users(
+    persy: &Persy,
+    query: &LeaderboardQuery,
+    users: Option<&HashSet<UserId>>,
+) -> Result<Page<LeaderboardItem>, Error> {
+    let sort = query.sort.unwrap_or(LeaderboardSort::Score);
+    let order = query.order.unwrap_or(SortOrder::Desc);
+    let from = query.from.map_or(Bound::Unbounded, Bound::Included);
+    let to = query.to.map_or(Bound::Unbounded, Bound::Included);
+    // Time range is index bounds when sorting by time and a filter otherwise
+    let (index, bounds) = match sort {
+        LeaderboardSort::Score => (BY_SCORE_INDEX, (Bound::Unbounded, Bound::Unbounded)),
+        LeaderboardSort::Recent => (BY_TIME_INDEX, (from, to)),
+    };
+    let in_time_range = |entry: &LeaderboardEntry| {
+        query.from.is_none_or(|from| entry.finished >= from)
+            && query.to.is_none_or(|to| entry.finished <= to)
+    };
+    pagination::page_by_index(
+        persy,
+        index,
+        LEADERBOARD_SEGMENT,
+        order,
+        bounds,
+        query.cursor,
+        pagination::limit(query.limit),
+        |entry: &LeaderboardEntry| {
+            query.mode.is_none_or(|mode| entry.mode == mode)
+                && query
+                    .difficulty
+                    .is_none_or(|difficulty| entry.difficulty == Some(difficulty))
+                && match query.verification {
+                    Some(verification) => entry.verification == verification,
+                    None => entry.verification != Verification::Rejected,
+                }
+                && in_time_range(entry)
+                && users.is_none_or(|users| users.contains(&entry.user))
+        },
+        |id, entry| LeaderboardItem {
+            id: GameId(id),
+            entry,
+        },
+    )
+}
+
+// Verified entry has the best verified score of it's mode and difficulty, ties are not records
+pub fn is_record(persy: &Persy, id: GameId, entry: &LeaderboardEntry) -> Result<bool, Error> {
+    let best = list(
+        persy,
+        &LeaderboardQuery {
+            mode: Some(entry.mode),
+            difficulty: entry.difficulty,
+            verification: Some(Verification::Verified),
+            sort: Some(LeaderboardSort::Score),
+            order: Some(SortOrder::Desc),
+            from: None,
+            to: None,
+            country: None,
+            friends: None,
+            cursor: None,
+            limit: Some(2),
+        },
+    )?;
+    Ok(match best.items.as_slice() {
+        [first] => first.id == id,
+        [first, second, ..] => first.id == id && second.entry.score < entry.score,
+        [] => false,
+    })
+}
+
+// Batch of export entries in finish time order, starting after cursor
+fn export_batch(
+    persy: &Persy,
+    query: &ExportQuery,
+    cursor: Option<&str>,
+) -> Result<Page<LeaderboardItem>, Error> {
+    let from = query.from.map_or(Bound::Unbounded, Bound::Included);
+    let to = query.to.map_or(Bound::Unbounded, Bound::Included);
+    pagination::page_by_index(
+        persy,
+        BY_TIME_INDEX,
+        LEADERBOARD_SEGMENT,
+        SortOrder::Asc,
+        (from, to),
+        cursor,
+        EXPORT_BATCH,
+        |entry: &LeaderboardEntry| {
+            query.mode.is_none_or(|mode| entry.mode == mode)
+                && query
+                    .difficulty
+                    .is_none_or(|difficulty| entry.difficulty == Some(difficulty))
+                && query
+                    .verification
+                    .is_none_or(|verification| entry.verification == verification)
+        },
+        |id, entry| LeaderboardItem {
+            id: GameId(id),
+            entry,
+        },
+    )
+}
+
+fn csv_line(item: &LeaderboardItem) -> String {
+    let entry = &item.entry;
+    format!(
+        "{},{},{:?},{},{},{},{},{},{},{},{:?}\n",
+        item.id,
+        entry.user,
+        entry.mode,
+        entry
+            .difficulty
+            .map_or(String::new(), |difficulty| format!("{:?}", difficulty)),
+        entry.score,
+        entry.lines,
+        entry.ticks,
+        entry.finished,
+        entry
+            .opponent
+            .map_or(String::new(), |opponent| opponent.to_string()),
+        entry.replay.as_deref().unwrap_or(""),
+        entry.verification,
+    )
+}
+
+// Leaderboard entries as CSV in finish time order, for analysis outside of the server.
+// Entries are read in batches while the response is streamed, so large exports
+// are not buffered in memory
+#[get("/admin/leaderboard/export?<query..>")]
+fn export(
+    _admin: Viewer,
+    db: &State<Database>,
+    query: ExportQuery,
+) -> (ContentType, TextStream![String + '_]) {
+    (
+        ContentType::CSV,
+        TextStream! {
+            yield CSV_HEADER.to_string();
+            let mut cursor: Option<String> = None;
+            loop {
+                let batch = export_batch(&db.read(), &query, cursor.as_deref());
+                let page = match batch {
+                    Ok(page) => page,
+                    Err(e) => {
+                        println!("Leaderboard export failed: {}", e);
+                        break;
+                    }
+                };
+                for item in &page.items {
+                    yield csv_line(item);
+                }
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        },
+    )
+}
+
+// Leaderboard with filtering by mode, difficulty, finish time, country and friends, sorted
+// by score or time. Friends only pages differ by user and aren't cached
+#[get("/leaderboard?<query..>")]
+fn leaderboard(
+    cookie_jar: &CookieJar,
+    db: &State<Database>,
+    caches: &State<Caches>,
+    friends: &State<Friends>,
+    uri: &Origin,
+    query: LeaderboardQuery,
+) -> Result<(ContentType, String), Error> {
+    let persy = &*db.read();
+    let friends = friends.filter(cookie_jar, query.friends);
+    let page = || {
+        let users = friends::both(regions::users_of(persy, query.country)?, friends.clone());
+        Ok(serde_json::to_string(&list_of_users(
+            persy,
+            &query,
+            users.as_ref(),
+        )?)?)
+    };
+    let page = match friends {
+        Some(_) => page()?,
+        None => caches
+            .leaderboard
+            .get_or_insert_with(&uri.to_string(), page)?,
+    };
+    Ok((ContentType::JSON, page))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![leaderboard, export]
+}
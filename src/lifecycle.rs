@@ -0,0 +1,222 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    futures::future::BoxFuture,
+    get, routes,
+    serde::json::Json,
+    tokio::{
+        self,
+        task::JoinHandle,
+        time::{self, Duration},
+    },
+    Config, Orbit, Rocket, Route, State,
+};
+use serde::Serialize;
+
+use crate::{error::Error, roles::Viewer};
+
+//
+// Lifecycle of background subsystems. Subsystems are added in dependency order: each one
+// names subsystems it depends on, which must be added before it. Jobs spawned through the
+// lifecycle are aborted on stop, stop actions flush what subsystems hold. On shutdown
+// subsystems stop in reverse order, each within shutdown_timeout_ms, so e.g. write queue
+// is flushed before the database is closed, and the database outlives all it's users.
+// Tasks owned by something else than the lifecycle are spawned directly, e.g. workers of
+// queues which end with their handles, tasks of requests and connections, and their spawn
+// sites say why. Subsystems and their states are listed in /admin/lifecycle
+//
+
+// Time given to each subsystem to stop unless configured
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+type StopAction = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum SubsystemState {
+    Running,
+    Stopping,
+    Stopped,
+    // Didn't stop within timeout, shutdown went on without it
+    TimedOut,
+}
+
+struct Subsystem {
+    name: &'static str,
+    after: Vec<&'static str>,
+    // Job aborted on stop
+    task: Option<JoinHandle<()>>,
+    stop: Option<StopAction>,
+    state: SubsystemState,
+    // Seconds since unix epoch
+    started: u64,
+}
+
+#[derive(Serialize)]
+pub struct SubsystemInfo {
+    pub name: &'static str,
+    pub after: Vec<&'static str>,
+    pub state: SubsystemState,
+    pub started: u64,
+}
+
+#[derive(Clone)]
+pub struct Lifecycle {
+    // In start order
+    subsystems: Arc<Mutex<Vec<Subsystem>>>,
+    stop_timeout: Duration,
+}
+
+impl Lifecycle {
+    pub fn from_config() -> Lifecycle {
+        Lifecycle {
+            subsystems: Arc::new(Mutex::new(Vec::new())),
+            stop_timeout: Config::figment()
+                .extract_inner::<u64>("shutdown_timeout_ms")
+                .map_or(DEFAULT_STOP_TIMEOUT, Duration::from_millis),
+        }
+    }
+
+    fn push(
+        &self,
+        name: &'static str,
+        after: &[&'static str],
+        start: impl FnOnce() -> Option<JoinHandle<()>>,
+    ) -> Result<(), Error> {
+        let mut subsystems = self.subsystems.lock().unwrap();
+        if let Some(missing) = after
+            .iter()
+            .find(|dependency| !subsystems.iter().any(|s| s.name == **dependency))
+        {
+            return Err(Error::InvalidInputError(format!(
+                "Subsystem {} depends on {} which isn't started",
+                name, missing
+            )));
+        }
+        subsystems.push(Subsystem {
+            name,
+            after: after.to_vec(),
+            task: start(),
+            stop: None,
+            state: SubsystemState::Running,
+            started: crate::unix_time(),
+        });
+        Ok(())
+    }
+
+    // Subsystem started by the caller
+    pub fn add(&self, name: &'static str, after: &[&'static str]) -> Result<(), Error> {
+        self.push(name, after, || None)
+    }
+
+    // Start background job, it's aborted on stop. Dependencies are checked before it starts
+    pub fn spawn(
+        &self,
+        name: &'static str,
+        after: &[&'static str],
+        job: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<(), Error> {
+        self.push(name, after, || Some(tokio::spawn(job)))
+    }
+
+    // Action run when the subsystem stops, after it's job is aborted
+    pub fn on_stop<F, Fut>(&self, name: &'static str, stop: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut subsystems = self.subsystems.lock().unwrap();
+        if let Some(subsystem) = subsystems.iter_mut().find(|s| s.name == name) {
+            subsystem.stop = Some(Box::new(move || Box::pin(stop())));
+        }
+    }
+
+    fn set_state(&self, name: &'static str, state: SubsystemState) {
+        let mut subsystems = self.subsystems.lock().unwrap();
+        if let Some(subsystem) = subsystems.iter_mut().find(|s| s.name == name) {
+            subsystem.state = state;
+        }
+    }
+
+    // Stop subsystems in reverse start order
+    pub async fn stop(&self) {
+        loop {
+            let next = {
+                let mut subsystems = self.subsystems.lock().unwrap();
+                subsystems
+                    .iter_mut()
+                    .rev()
+                    .find(|s| s.state == SubsystemState::Running)
+                    .map(|subsystem| {
+                        subsystem.state = SubsystemState::Stopping;
+                        (subsystem.name, subsystem.task.take(), subsystem.stop.take())
+                    })
+            };
+            let Some((name, task, stop)) = next else {
+                break;
+            };
+            let stopping = async move {
+                if let Some(task) = task {
+                    task.abort();
+                    let _ = task.await;
+                }
+                if let Some(stop) = stop {
+                    stop().await;
+                }
+            };
+            match time::timeout(self.stop_timeout, stopping).await {
+                Ok(()) => {
+                    println!("Subsystem {} stopped", name);
+                    self.set_state(name, SubsystemState::Stopped);
+                }
+                Err(_) => {
+                    println!(
+                        "Subsystem {} didn't stop within {:?}",
+                        name, self.stop_timeout
+                    );
+                    self.set_state(name, SubsystemState::TimedOut);
+                }
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<SubsystemInfo> {
+        self.subsystems
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|subsystem| SubsystemInfo {
+                name: subsystem.name,
+                after: subsystem.after.clone(),
+                state: subsystem.state,
+                started: subsystem.started,
+            })
+            .collect()
+    }
+}
+
+// Stop subsystems before server shuts down
+#[rocket::async_trait]
+impl Fairing for Lifecycle {
+    fn info(&self) -> Info {
+        Info {
+            name: "Subsystems shutdown",
+            kind: Kind::Shutdown,
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        self.stop().await;
+    }
+}
+
+// Subsystems in start order
+#[get("/admin/lifecycle")]
+fn admin_lifecycle(_admin: Viewer, lifecycle: &State<Lifecycle>) -> Json<Vec<SubsystemInfo>> {
+    Json(lifecycle.list())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_lifecycle]
+}
@@ -0,0 +1,84 @@
+use rocket::{get, http::CookieJar, routes, serde::json::Json, Orbit, Rocket, Route, State};
+use rocket_dyn_templates::Template;
+use serde::Serialize;
+
+use crate::{
+    arenas::Arenas,
+    games::{GameRegistry, GameType, Server, SessionStatus},
+    ids::UserId,
+    TetrisMatches,
+};
+
+//
+// Lobby: entry point listing game types of the server, user's active sessions in them
+// and featured arenas. Served as json by /lobby and rendered as the root page
+//
+
+#[derive(Serialize)]
+pub struct ActiveSession {
+    // Id of the game type
+    pub game: &'static str,
+    pub status: SessionStatus,
+}
+
+#[derive(Serialize)]
+pub struct Lobby<'r> {
+    pub user: UserId,
+    pub game_types: Vec<&'r GameType>,
+    pub sessions: Vec<ActiveSession>,
+    // Arenas hosted by the server, served under /arena/<name>/
+    pub arenas: Vec<String>,
+}
+
+pub fn lobby_of<'r>(
+    registry: &'r GameRegistry,
+    arenas: &Arenas,
+    rocket: &Rocket<Orbit>,
+    user_id: UserId,
+) -> Lobby<'r> {
+    let plugins = registry.plugins();
+    Lobby {
+        user: user_id,
+        game_types: plugins.iter().map(|plugin| &plugin.game_type).collect(),
+        sessions: plugins
+            .iter()
+            .filter_map(|plugin| {
+                Some(ActiveSession {
+                    game: plugin.game_type.id,
+                    status: (plugin.session)(rocket, user_id)?,
+                })
+            })
+            .collect(),
+        arenas: arenas.names(),
+    }
+}
+
+// Game types, user's active sessions and featured arenas
+#[get("/lobby")]
+fn lobby<'r>(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    registry: &'r State<GameRegistry>,
+    arenas: &State<Arenas>,
+    server: Server,
+) -> Json<Lobby<'r>> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Json(lobby_of(registry, arenas, server.0, user_id))
+}
+
+// Root page, lobby rendered with lobby.html.hbs template
+#[get("/")]
+fn index(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    registry: &State<GameRegistry>,
+    arenas: &State<Arenas>,
+    server: Server,
+) -> Template {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Template::render("lobby", lobby_of(registry, arenas, server.0, user_id))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![index, lobby]
+}
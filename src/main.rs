@@ -1,91 +1,1042 @@
+mod access;
+mod acme;
+mod admission;
+mod arenas;
+mod bans;
+mod bench;
+mod board_image;
+mod bots;
+mod bug_reports;
+mod cache;
+mod catchers;
+mod compaction;
+mod connect_four;
+mod connections;
+mod console;
+mod daily;
+mod daily_quotas;
+mod difficulty;
+mod digests;
+mod discord;
+mod dropped_games;
+mod email_login;
+mod embed;
 mod error;
 mod event_regulator;
+mod events;
+mod fairness;
+mod friends;
+mod game2048;
+mod game_events;
+mod game_history;
+mod game_mode;
+mod game_rng;
+mod games;
+mod garbage_rules;
+mod geoip;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod gravity_curves;
+mod handicap;
+mod handover;
+mod ids;
+mod input_sequence;
+mod invariants;
+mod latency;
+mod leaderboard;
+mod lifecycle;
+mod lobby;
+mod maintenance;
+mod match_history;
 mod matches;
+mod matchmaking;
+mod metrics;
+mod minesweeper;
+mod moderation;
+mod motd;
+mod multiview;
+mod notifications;
+mod pagination;
+mod polling;
+mod proxies;
+mod published;
+mod puzzles;
+mod quarantine;
+mod quotas;
+mod ratings;
+mod recording;
+mod recovery;
+mod regions;
+mod replays;
+mod rng_audit;
+mod roles;
+mod scheduler;
+mod scoring;
+mod send_queue;
+mod session_transfer;
+mod sessions;
+mod settings;
+mod signatures;
+mod splits;
+mod spotlight;
+mod sprint;
+mod stats;
+mod storage;
+mod storage_browser;
+mod synthetic;
 mod tetris;
 mod tetris_pair;
+mod themes;
+mod tournaments;
+mod turn_based;
+mod tutorial;
+mod version;
+mod views;
+mod visibility;
+mod warmup;
+mod webhooks;
+mod write_queue;
 
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
 
+use gameserver_protocol::game::LatencyReport;
+
+use access::{AccessFairing, AccessTimes};
+use acme::{AcmeChallenges, AcmeProvisioner};
+use admission::{Admission, Admitted};
+use arenas::Arenas;
+use bots::BotSandbox;
+use bug_reports::GameTrace;
+use cache::Caches;
+use catchers::RequestIdHeader;
+use connections::Connections;
+use console::Console;
+use daily_quotas::{DailyQuotas, QuotaHeaders};
+use difficulty::{Difficulty, GravityCurve};
+use digests::Digests;
+use discord::Discord;
+use dropped_games::{DropReason, DroppedGame, DroppedGames};
+use email_login::EmailLogin;
 use error::Error;
-use matches::{MatchId, Matches, PlayerStatus};
-use persy::Persy;
-use rocket::tokio::select;
+use events::ChannelEvent;
+use friends::Friends;
+use game_mode::GameMode;
+use game_rng::RngKind;
+use games::{GamePlugin, GameRegistry, GameType, SessionStatus};
+use garbage_rules::GarbageRulebook;
+use geoip::GeoIp;
+use gravity_curves::GravityTuning;
+use handicap::HandicapRules;
+use handover::Handover;
+use ids::{MatchId, UserId};
+use input_sequence::{InputSeq, InputSequences, InputTarget, StateHistory};
+use latency::Latency;
+use leaderboard::LeaderboardEntry;
+use lifecycle::Lifecycle;
+use maintenance::{Maintenance, MaintenanceRefusal};
+use match_history::{MatchPlayer, MatchRecord};
+use matches::{Match, Matches, PinStats, PlayerSide, PlayerStatus};
+use matchmaking::{MatchQueue, QueueStatus};
+use metrics::GameMetrics;
+use moderation::Moderation;
+use motd::Motd;
+use multiview::Board;
+use notifications::Notifications;
+use pagination::{Page, SortOrder};
+use polling::Polls;
+use proxies::TrustedProxies;
+use published::PublishedMatches;
+use quarantine::{Quarantine, QuarantinedGame, QuarantinedMode};
+use quotas::Quotas;
+use ratings::{MatchOutcome, Ratings};
+use recovery::{InputLogEntry, JournalEvent, MatchSnapshot};
+use replays::ReplayVerifier;
+use rng_audit::RngAudit;
+use rocket::futures::{Stream, StreamExt};
 use rocket::tokio::time::{self, Duration};
+use rocket::{figment::Figment, post, Config};
 use rocket::{
     get,
-    http::{Cookie, CookieJar},
+    http::{uri::Origin, ContentType, Cookie, CookieJar},
     response::{
         status,
-        stream::{Event, EventStream},
+        stream::{stream, Event, EventStream},
     },
     routes,
     serde::json::serde_json,
-    Ignite, Rocket, State,
+    FromForm, FromFormField, Ignite, Rocket, State,
+};
+use rocket_dyn_templates::{context, Template};
+use roles::{AdminRoles, Viewer};
+use scheduler::TickScheduler;
+use scoring::{ScoringRulebook, ScoringRules};
+use serde::Serialize;
+use session_transfer::SessionTransfers;
+use sessions::{SessionFairing, Sessions};
+use settings::{RuntimeSettings, SettingsWatch};
+use signatures::ResultSigner;
+use spotlight::{Spotlight, SpotlightFrame};
+use sprint::TetrisSprints;
+use storage_browser::{StoredMatch, StoredMatchStatus};
+use synthetic::SyntheticLoad;
+use tetris::{Action, PieceRules};
+use tetris_pair::{
+    AfkRules, AfkStatus, Countdown, TetrisPair, TetrisPairState, VersusRules, STEP_MS,
 };
-use rocket::{post, Config, Shutdown};
-use rocket_dyn_templates::Template;
-use tetris::Action;
-use tetris_pair::{TetrisPair, TetrisPairState};
+use themes::Themes;
+use tournaments::{BracketProgress, Tournaments};
+use version::{Uptime, VersionHeader};
+use views::{Projection, View};
+use visibility::Pauses;
+use webhooks::Webhooks;
+use write_queue::{Write, WriteQueue};
+
+// Versus match, it's game has own lock, so games are stepped under the read lock of matches
+type VersusMatch = Match<UserId, Mutex<TetrisPair>>;
+type VersusMatches = Matches<UserId, Mutex<TetrisPair>, MatchQueue>;
+
+// Versus matches with state shared by them
+#[derive(Clone)]
+struct TetrisMatches {
+    matches: Arc<RwLock<VersusMatches>>,
+    // Rules used for new matches
+    rules: VersusRules,
+    // Players' ratings
+    ratings: Ratings,
+    // Scheduler of ticks
+    scheduler: TickScheduler,
+    // Matches which panicked
+    quarantine: Quarantine,
+    // Log of active matches dropped from memory
+    dropped: DroppedGames,
+    // States of matches read by spectators without locking matches
+    published: PublishedMatches,
+}
+
+// Finished matches are kept for some time to show final state
+const FINISHED_MATCH_TTL: Duration = Duration::from_secs(10);
 
-struct TetrisMatches(Arc<RwLock<Matches<u32, TetrisPair>>>);
+// Active game summary for live games listing
+#[derive(Serialize)]
+struct LiveGame {
+    match_id: MatchId,
+    players: [UserId; 2],
+    scores: [usize; 2],
+    started: u64,
+    // Garbage rules of the match
+    rules: String,
+    // Spectator connections watching the match
+    viewers: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+enum LiveSort {
+    // Sum of players scores
+    Score,
+    // Game start time
+    Started,
+    // Spectators watching the game
+    Viewers,
+}
+
+#[derive(FromForm)]
+struct LiveQuery<'r> {
+    // Games of the garbage rules only
+    rules: Option<&'r str>,
+    // Games with sum of players scores at least min_score
+    min_score: Option<usize>,
+    // Games of friends of the user only
+    friends: Option<bool>,
+    sort: Option<LiveSort>,
+    order: Option<SortOrder>,
+    cursor: Option<&'r str>,
+    limit: Option<usize>,
+}
 
 impl TetrisMatches {
-    fn new() -> Self {
-        TetrisMatches(Arc::new(RwLock::new(Matches::new())))
+    fn new(
+        rules: VersusRules,
+        ratings: Ratings,
+        settings: SettingsWatch,
+        quarantine: Quarantine,
+        dropped: DroppedGames,
+    ) -> Self {
+        let queue = MatchQueue::new(ratings.clone());
+        TetrisMatches {
+            matches: Arc::new(RwLock::new(Matches::with_wait_list(queue))),
+            rules,
+            ratings,
+            scheduler: TickScheduler::new(settings),
+            quarantine,
+            dropped,
+            published: PublishedMatches::new(),
+        }
     }
-    fn get_free_user_id(&self) -> u32 {
-        let mut user_id = rand::random::<u32>();
-        let matches = self.0.read().unwrap();
+    fn get_free_user_id(&self) -> UserId {
+        let mut user_id = UserId(rand::random());
+        let matches = self.matches.read().unwrap();
         while matches.get_player_status(&user_id) != PlayerStatus::NotFound {
-            user_id = rand::random::<u32>();
+            user_id = UserId(rand::random());
         }
         user_id
     }
-    fn game_state(&self, user_id: u32) -> Option<TetrisPairState> {
-        let matches = self.0.read().unwrap();
+    fn game_state(&self, user_id: UserId) -> Option<TetrisPairState> {
+        let matches = self.matches.read().unwrap();
         matches
             .get_match_for_player(&user_id)
             .and_then(|(_, tetris_match)| {
                 let player_side = tetris_match.get_player_side(&user_id)?;
-                Some(tetris_match.field.get_player_game_state(player_side))
+                Some(
+                    tetris_match
+                        .field
+                        .lock()
+                        .unwrap()
+                        .get_player_game_state(player_side),
+                )
             })
     }
-    fn add_action(&self, user_id: u32, action: Action) {
-        let mut matches = self.0.write().unwrap();
-        if let Some((_, tetris_match)) = matches.get_mut_match_for_player(&user_id) {
-            if let Some(player_side) = tetris_match.get_player_side(&user_id) {
-                tetris_match.field.add_player_action(player_side, action);
+    // Full state and replays of user's match for bug report
+    fn debug_trace(&self, user_id: UserId) -> Option<GameTrace> {
+        let matches = self.matches.read().unwrap();
+        let (_, tetris_match) = matches.get_match_for_player(&user_id)?;
+        let player_side = tetris_match.get_player_side(&user_id)?;
+        let field = tetris_match.field.lock().unwrap();
+        let state = field
+            .get_player_game_state(player_side)
+            .project(View::Admin);
+        Some(GameTrace {
+            mode: GameMode::Versus,
+            players: vec![tetris_match.player_a, tetris_match.player_b],
+            started: field.get_started(),
+            state: serde_json::to_value(state).ok()?,
+            replays: field.get_replays().to_vec(),
+        })
+    }
+    // Returns version of the player's game the input is put in, None without match.
+    // User's match refuses the input before it starts
+    // Input is added under the read lock of matches, as in steps
+    fn add_action(&self, user_id: UserId, action: Action) -> Result<Option<u64>, Error> {
+        let (match_id, added) = {
+            let matches = self.matches.read().unwrap();
+            let Some((match_id, tetris_match)) = matches.get_match_for_player(&user_id) else {
+                return Ok(None);
+            };
+            let Some(player_side) = tetris_match.get_player_side(&user_id) else {
+                return Ok(None);
+            };
+            let mut field = tetris_match.field.lock().unwrap();
+            let added = quarantine::guard(|| field.add_player_action(player_side, action));
+            (match_id, added)
+        };
+        match added {
+            Ok(Some(version)) => Ok(Some(version)),
+            Ok(None) => Err(Error::InvalidInputError(
+                "Match hasn't started yet".to_string(),
+            )),
+            Err(panic) => {
+                let mut matches = self.matches.write().unwrap();
+                self.quarantine(&mut matches, match_id, panic, Some((user_id, action)));
+                Err(Error::NotFoundError("Match was quarantined".to_string()))
             }
         }
     }
-    fn step(&self, user_id: u32) -> Option<TetrisPairState> {
-        let mut matches = self.0.write().unwrap();
-        if matches.find_match(&user_id) {
-            if let Some((match_id, tetris_match)) = matches.get_mut_match_for_player(&user_id) {
-                if let Some(player_side) = tetris_match.get_player_side(&user_id) {
-                    let divergence = tetris_match.field.step_player(player_side);
-                    if divergence < 100 {
-                        return Some(tetris_match.field.get_player_game_state(player_side));
+    // Start countdown of user's match
+    fn countdown(&self, user_id: UserId) -> Option<Countdown> {
+        let matches = self.matches.read().unwrap();
+        let (_, tetris_match) = matches.get_match_for_player(&user_id)?;
+        let field = tetris_match.field.lock().unwrap();
+        field.countdown()
+    }
+    // Take final results of user's match when game is over. Results are given out once per match.
+    // Match is over then, it's unpinned
+    fn take_results(&self, user_id: UserId) -> Option<Write> {
+        let mut matches = self.matches.write().unwrap();
+        let (match_id, tetris_match) = matches.get_mut_match_for_player(&user_id)?;
+        let results = Self::match_results(tetris_match, &self.ratings)?;
+        matches.unpin(match_id);
+        Some(results)
+    }
+    // Same as take_results, by match id
+    fn take_match_results(&self, match_id: MatchId) -> Option<Write> {
+        let mut matches = self.matches.write().unwrap();
+        let results = Self::match_results(matches.get_mut_match(&match_id)?, &self.ratings)?;
+        matches.unpin(match_id);
+        Some(results)
+    }
+    fn match_results(tetris_match: &mut VersusMatch, ratings: &Ratings) -> Option<Write> {
+        let results = tetris_match.field.lock().unwrap().take_results()?;
+        let field = tetris_match.field.lock().unwrap();
+        let finished = unix_time();
+        let outcome = match (results[0].lost, results[1].lost) {
+            (false, true) => MatchOutcome::Won,
+            (true, false) => MatchOutcome::Lost,
+            _ => MatchOutcome::Draw,
+        };
+        let ratings = ratings.record_match([tetris_match.player_a, tetris_match.player_b], outcome);
+        let mut players = Vec::new();
+        let mut games = Vec::new();
+        let mut audits = Vec::new();
+        for (result, rating) in results.into_iter().zip(ratings) {
+            let user = *tetris_match.get_player(result.side);
+            players.push(MatchPlayer {
+                user,
+                score: result.score as u64,
+                lines: result.lines as u64,
+                attack_sent: result.attack_sent as u64,
+                garbage_received: result.garbage_received as u64,
+                forfeited: result.forfeited,
+                board: result.board,
+                handicap: result.handicap,
+                rating: Some(rating),
+                entry: None,
+                replay: None,
+            });
+            let entry = LeaderboardEntry {
+                user,
+                mode: GameMode::Versus,
+                difficulty: result.replay.difficulty,
+                score: result.score as u64,
+                lines: result.lines as u64,
+                ticks: result.replay.ticks,
+                pieces: result.pieces,
+                finished,
+                opponent: Some(*tetris_match.get_player(result.side.opponent())),
+                replay: None,
+                verification: Default::default(),
+                rng_audit: None,
+            };
+            audits.push(RngAudit::of(&result.replay, &result.piece_chain));
+            games.push((entry, result.replay));
+        }
+        let record = MatchRecord {
+            started: field.get_started(),
+            finished,
+            ticks: games
+                .iter()
+                .map(|(entry, _)| entry.ticks)
+                .max()
+                .unwrap_or(0),
+            garbage_rules: field.get_rules().garbage.name.clone(),
+            shared_pieces: field.get_rules().shared_pieces,
+            players,
+        };
+        Some(Write::Match {
+            record,
+            games,
+            audits,
+        })
+    }
+    // Switch running matches to the gravity curve from their next level, returns number of
+    // matches switched
+    fn queue_gravity_curve(&self, curve: &GravityCurve) -> usize {
+        let mut matches = self.matches.write().unwrap();
+        let mut switched = 0;
+        for (_, tetris_match) in matches.iter_mut() {
+            let field = tetris_match.field.get_mut().unwrap();
+            if !field.is_game_over() {
+                field.queue_gravity_curve(curve);
+                switched += 1;
+            }
+        }
+        switched
+    }
+    // Matches whose game isn't over yet
+    fn running(&self) -> usize {
+        let matches = self.matches.read().unwrap();
+        matches
+            .iter()
+            .filter(|(_, tetris_match)| !tetris_match.field.lock().unwrap().is_game_over())
+            .count()
+    }
+    // Viewers are counted by spectating connections
+    fn live_games(
+        &self,
+        query: &LiveQuery,
+        viewers: &HashMap<MatchId, usize>,
+        players: Option<&HashSet<UserId>>,
+    ) -> Result<Page<LiveGame>, Error> {
+        let sort = query.sort.unwrap_or(LiveSort::Score);
+        let games = self
+            .published
+            .all()
+            .into_iter()
+            .filter_map(|(match_id, published)| {
+                let [score_a, score_b] = published.scores;
+                let rules = &published.rules;
+                if query.rules.is_some_and(|name| name != rules)
+                    || query.min_score.is_some_and(|min| score_a + score_b < min)
+                    || players.is_some_and(|players| {
+                        !published
+                            .players
+                            .iter()
+                            .any(|player| players.contains(player))
+                    })
+                {
+                    return None;
+                }
+                let started = published.started;
+                let viewers = viewers.get(&match_id).copied().unwrap_or(0);
+                let key = match sort {
+                    LiveSort::Score => (score_a + score_b) as u64,
+                    LiveSort::Started => started,
+                    LiveSort::Viewers => viewers as u64,
+                };
+                let game = LiveGame {
+                    match_id,
+                    players: published.players,
+                    scores: published.scores,
+                    started,
+                    rules: rules.clone(),
+                    viewers,
+                };
+                Some((key, match_id, game))
+            })
+            .collect();
+        pagination::page_in_memory(
+            games,
+            query.order.unwrap_or(SortOrder::Desc),
+            query.cursor,
+            pagination::limit(query.limit),
+        )
+    }
+    // Frame of game for spotlight: current featured game while it's active,
+    // otherwise the highest-scoring active game
+    fn featured_game(&self, current: Option<MatchId>) -> Option<SpotlightFrame> {
+        let (match_id, published) = current
+            .and_then(|match_id| Some((match_id, self.published.get(match_id)?)))
+            .filter(|(_, published)| !published.game_over)
+            .or_else(|| {
+                self.published
+                    .all()
+                    .into_iter()
+                    .filter(|(_, published)| !published.game_over)
+                    .max_by_key(|(_, published)| published.scores[0] + published.scores[1])
+            })?;
+        Some(SpotlightFrame {
+            hash: published.hash,
+            match_id,
+            players: published.players,
+            scores: published.scores,
+            state: published.state.clone(),
+        })
+    }
+    // Hash of spectated state of match, changes when the board changes
+    fn board_hash(&self, match_id: MatchId) -> Option<u64> {
+        Some(self.published.get(match_id)?.hash)
+    }
+    // Spectated state of match, see multiview
+    fn board(&self, match_id: MatchId) -> Option<Board> {
+        let published = self.published.get(match_id)?;
+        Some(Board {
+            players: published.players,
+            scores: published.scores,
+            game_over: published.game_over,
+            state: published.state.clone(),
+        })
+    }
+    // Published state of user's match as of the user and the opponent, see embed. Running
+    // match is preferred to finished one kept in memory
+    fn published_state(&self, user_id: UserId) -> Option<(UserId, TetrisPairState)> {
+        let (_, published) = self
+            .published
+            .all()
+            .into_iter()
+            .filter(|(_, published)| published.players.contains(&user_id))
+            .max_by_key(|(_, published)| (!published.game_over, published.started))?;
+        let state = published.state.clone();
+        match published.players {
+            [player_a, player_b] if player_a == user_id => Some((player_b, state)),
+            [player_a, _] => Some((player_a, state.swapped())),
+        }
+    }
+    // AFK statuses of the user and his opponent
+    fn afk_status(&self, user_id: UserId) -> Option<(AfkStatus, AfkStatus)> {
+        let matches = self.matches.read().unwrap();
+        let (_, tetris_match) = matches.get_match_for_player(&user_id)?;
+        let side = tetris_match.get_player_side(&user_id)?;
+        let field = tetris_match.field.lock().unwrap();
+        Some((field.afk_status(side), field.afk_status(side.opponent())))
+    }
+    // Read-only snapshot of matches held in memory
+    fn snapshot(&self) -> (Vec<StoredMatch>, PinStats) {
+        let matches = self.matches.read().unwrap();
+        let stored = matches
+            .iter()
+            .map(|(match_id, tetris_match)| {
+                let field = tetris_match.field.lock().unwrap();
+                StoredMatch {
+                    match_id,
+                    players: [tetris_match.player_a, tetris_match.player_b],
+                    idle_secs: field.get_last_step().elapsed().as_secs(),
+                    status: if field.are_results_taken() {
+                        StoredMatchStatus::Finished
+                    } else if field.is_game_over() {
+                        StoredMatchStatus::GameOver
                     } else {
-                        matches.remove_match(match_id);
+                        StoredMatchStatus::Active
+                    },
+                    approx_size: field.approx_size(),
+                    pinned: matches.is_pinned(&match_id),
+                }
+            })
+            .collect();
+        (stored, matches.pin_stats())
+    }
+    // Remove match from memory, pinned matches have to be unpinned first
+    fn evict(&self, match_id: MatchId) -> Result<(), Error> {
+        let mut matches = self.matches.write().unwrap();
+        if matches.get_match(&match_id).is_none() {
+            return Err(Error::NotFoundError("Match not found".to_string()));
+        }
+        let dropped = self.dropped_game(&matches, match_id, DropReason::Evicted);
+        if !matches.evict_match(match_id) {
+            return Err(Error::InvalidInputError(
+                "Match is pinned, unpin it first".to_string(),
+            ));
+        }
+        self.published.remove(match_id);
+        if let Some(dropped) = dropped {
+            self.dropped.add(dropped);
+        }
+        Ok(())
+    }
+    // Incident of the match if it's being played, recent inputs tell it apart from abandoned
+    fn dropped_game(
+        &self,
+        matches: &VersusMatches,
+        match_id: MatchId,
+        reason: DropReason,
+    ) -> Option<DroppedGame> {
+        let tetris_match = matches.get_match(&match_id)?;
+        let field = tetris_match.field.lock().unwrap();
+        let idle_ms = field.idle_steps() * STEP_MS;
+        if field.is_game_over() || idle_ms > dropped_games::ACTIVE_WINDOW.as_millis() as u64 {
+            return None;
+        }
+        let (score_a, score_b) = field.get_scores();
+        Some(DroppedGame {
+            arena: None,
+            players: vec![tetris_match.player_a, tetris_match.player_b],
+            scores: vec![score_a, score_b],
+            reason,
+            started: field.get_started(),
+            dropped: unix_time(),
+            idle_ms,
+        })
+    }
+    // Pin or unpin match, returns false if there is no such match or pin limit is reached
+    fn set_pinned(&self, match_id: MatchId, pinned: bool) -> bool {
+        let mut matches = self.matches.write().unwrap();
+        if !pinned {
+            matches.unpin(match_id);
+            return matches.get_match(&match_id).is_some();
+        }
+        matches.pin(match_id)
+    }
+    // Replays of running matches for recovery journal snapshot, events restart after them
+    fn snapshots(&self) -> Vec<MatchSnapshot> {
+        let mut matches = self.matches.write().unwrap();
+        let ids = matches
+            .iter()
+            .map(|(match_id, _)| match_id)
+            .collect::<Vec<_>>();
+        ids.into_iter()
+            .filter_map(|match_id| {
+                let tetris_match = matches.get_mut_match(&match_id)?;
+                let players = tetris_match.players();
+                let field = tetris_match.field.get_mut().unwrap();
+                if field.is_game_over() {
+                    field.mark_journal_ended();
+                    return None;
+                }
+                field.mark_inputs_logged();
+                Some(Self::match_snapshot(players, field))
+            })
+            .collect()
+    }
+    fn match_snapshot(players: [UserId; 2], field: &TetrisPair) -> MatchSnapshot {
+        MatchSnapshot {
+            players,
+            started: field.get_started(),
+            garbage_rules: field.get_rules().garbage.name.clone(),
+            handicaps: field.get_handicaps(),
+            replays: field.get_replays(),
+        }
+    }
+    // Journal events of running matches since previous call: new matches, their inputs
+    // and ends. Journaled keeps players and start time of matches in the journal, matches
+    // removed from memory without game over are ended as well
+    fn journal_events(&self, journaled: &mut HashSet<([UserId; 2], u64)>) -> Vec<JournalEvent> {
+        let mut matches = self.matches.write().unwrap();
+        let ids = matches
+            .iter()
+            .map(|(match_id, _)| match_id)
+            .collect::<Vec<_>>();
+        let mut events = Vec::new();
+        let mut running = HashSet::new();
+        for match_id in ids {
+            let Some(tetris_match) = matches.get_mut_match(&match_id) else {
+                continue;
+            };
+            let players = tetris_match.players();
+            let field = tetris_match.field.get_mut().unwrap();
+            let key = (players, field.get_started());
+            if !field.is_journaled() {
+                if !field.is_game_over() {
+                    events.push(JournalEvent::MatchStarted(Box::new(Self::match_snapshot(
+                        players, field,
+                    ))));
+                    field.mark_inputs_logged();
+                    running.insert(key);
+                }
+                continue;
+            }
+            for (side, player_side) in [PlayerSide::A, PlayerSide::B].into_iter().enumerate() {
+                if let Some(new_inputs) = field.take_new_inputs(player_side) {
+                    events.push(JournalEvent::Inputs(InputLogEntry {
+                        players,
+                        started: field.get_started(),
+                        side,
+                        from: new_inputs.from,
+                        ticks: new_inputs.ticks,
+                        inputs: new_inputs.inputs,
+                    }));
+                }
+            }
+            if field.is_game_over() {
+                field.mark_journal_ended();
+            } else {
+                running.insert(key);
+            }
+        }
+        for (players, started) in journaled.difference(&running) {
+            events.push(JournalEvent::MatchEnded {
+                players: *players,
+                started: *started,
+            });
+        }
+        *journaled = running;
+        events
+    }
+    // Add match restored from recovery data
+    fn restore(&self, snapshot: &MatchSnapshot, rulebook: &GarbageRulebook) {
+        let rules = VersusRules {
+            garbage: rulebook
+                .get(&snapshot.garbage_rules)
+                .unwrap_or_else(|| self.rules.garbage.clone()),
+            afk: self.rules.afk,
+            rng: snapshot.replays[0].rng,
+            difficulty: snapshot.replays[0].difficulty.unwrap_or_default(),
+            countdown: self.rules.countdown,
+            scoring: Arc::new(
+                snapshot.replays[0]
+                    .scoring
+                    .clone()
+                    .unwrap_or_else(ScoringRules::classic),
+            ),
+            pieces: snapshot.replays[0].pieces.unwrap_or_default(),
+            shared_pieces: snapshot.replays[0].seed == snapshot.replays[1].seed,
+            gravity: self.rules.gravity.clone(),
+            handicap: self.rules.handicap.clone(),
+        };
+        let [replay_a, replay_b] = &snapshot.replays;
+        let field = TetrisPair::restore(
+            [replay_a, replay_b],
+            rules,
+            snapshot.started,
+            snapshot.handicaps,
+        );
+        let [player_a, player_b] = snapshot.players;
+        let mut matches = self.matches.write().unwrap();
+        let match_id = matches.insert_match(player_a, player_b, Mutex::new(field));
+        self.publish(&matches, match_id);
+    }
+    // Start match between the users without matchmaking, for synthetic players
+    fn start_match(&self, [player_a, player_b]: [UserId; 2]) -> MatchId {
+        let field = self.new_pair(&player_a, &player_b);
+        let mut matches = self.matches.write().unwrap();
+        let match_id = matches.insert_match(player_a, player_b, Mutex::new(field));
+        self.publish(&matches, match_id);
+        match_id
+    }
+    // Progress of match started for tournament bracket
+    fn bracket_progress(&self, match_id: MatchId) -> BracketProgress {
+        let matches = self.matches.read().unwrap();
+        let Some(tetris_match) = matches.get_match(&match_id) else {
+            return BracketProgress::Gone;
+        };
+        let field = tetris_match.field.lock().unwrap();
+        if !field.is_game_over() {
+            return BracketProgress::Playing;
+        }
+        let (score_a, score_b) = field.get_scores();
+        BracketProgress::Over {
+            lost: field.get_lost(),
+            scores: [score_a, score_b],
+        }
+    }
+    // Inputs of the placement the bot chooses for user's current piece, with number of
+    // pieces the user placed so far
+    fn plan_placement(&self, user_id: UserId) -> Option<(u64, Vec<Action>)> {
+        let matches = self.matches.read().unwrap();
+        let (_, tetris_match) = matches.get_match_for_player(&user_id)?;
+        let player_side = tetris_match.get_player_side(&user_id)?;
+        let field = tetris_match.field.lock().unwrap();
+        Some(field.plan_placement(player_side))
+    }
+    // Start time of user's match
+    fn started(&self, user_id: UserId) -> Option<u64> {
+        let matches = self.matches.read().unwrap();
+        let (_, tetris_match) = matches.get_match_for_player(&user_id)?;
+        let field = tetris_match.field.lock().unwrap();
+        Some(field.get_started())
+    }
+    fn set_paused(&self, user_id: UserId, paused: bool) {
+        let matches = self.matches.read().unwrap();
+        if let Some((_, tetris_match)) = matches.get_match_for_player(&user_id) {
+            if let Some(player_side) = tetris_match.get_player_side(&user_id) {
+                tetris_match
+                    .field
+                    .lock()
+                    .unwrap()
+                    .set_paused(player_side, paused);
+            }
+        }
+    }
+    fn match_id(&self, user_id: UserId) -> Option<MatchId> {
+        let matches = self.matches.read().unwrap();
+        matches
+            .get_match_for_player(&user_id)
+            .map(|(match_id, _)| match_id)
+    }
+    fn has_match(&self, user_id: UserId) -> bool {
+        let matches = self.matches.read().unwrap();
+        matches.get_match_for_player(&user_id).is_some()
+    }
+    fn is_waiting(&self, user_id: UserId) -> bool {
+        let matches = self.matches.read().unwrap();
+        matches.get_player_status(&user_id) == PlayerStatus::WaitList
+    }
+    fn queue_status(&self, user_id: UserId) -> Option<QueueStatus> {
+        self.matches.read().unwrap().wait_list().status(&user_id)
+    }
+    // Leave matchmaking queue, returns false when user wasn't waiting
+    fn cancel(&self, user_id: UserId) -> bool {
+        self.matches
+            .write()
+            .unwrap()
+            .wait_list_mut()
+            .cancel(&user_id)
+    }
+    fn is_cancelled(&self, user_id: UserId) -> bool {
+        self.matches
+            .read()
+            .unwrap()
+            .wait_list()
+            .is_cancelled(&user_id)
+    }
+    // New game stream of the user joins the queue again
+    fn resume(&self, user_id: UserId) {
+        self.matches
+            .write()
+            .unwrap()
+            .wait_list_mut()
+            .resume(&user_id)
+    }
+    // Opponent of the user in current match
+    fn opponent(&self, user_id: UserId) -> Option<UserId> {
+        let matches = self.matches.read().unwrap();
+        let (_, tetris_match) = matches.get_match_for_player(&user_id)?;
+        let side = tetris_match.get_player_side(&user_id)?;
+        Some(*tetris_match.get_player(side.opponent()))
+    }
+    fn set_latency(&self, user_id: UserId, rtt_ms: u64) {
+        let matches = self.matches.read().unwrap();
+        if let Some((_, tetris_match)) = matches.get_match_for_player(&user_id) {
+            if let Some(player_side) = tetris_match.get_player_side(&user_id) {
+                tetris_match
+                    .field
+                    .lock()
+                    .unwrap()
+                    .set_latency(player_side, rtt_ms);
+            }
+        }
+    }
+    // Remove matches finished long enough ago, so players can join new ones
+    fn remove_finished(&self) {
+        let mut matches = self.matches.write().unwrap();
+        let finished = matches
+            .iter()
+            .filter(|(_, tetris_match)| {
+                tetris_match
+                    .field
+                    .lock()
+                    .unwrap()
+                    .finished_for()
+                    .is_some_and(|finished_for| finished_for > FINISHED_MATCH_TTL)
+            })
+            .map(|(match_id, _)| match_id)
+            .collect::<Vec<_>>();
+        for match_id in finished {
+            matches.remove_match(match_id);
+            self.published.remove(match_id);
+        }
+    }
+    fn step(&self, user_id: UserId) -> Option<TetrisPairState> {
+        self.step_batch(&[user_id]).pop().flatten()
+    }
+    // Step games of the users. Games of users in matches are stepped under the read lock of
+    // matches and the lock of the match, so batches of tick workers run in parallel. Users
+    // without match are matched, and matches which diverged or panicked are removed, under
+    // the write lock afterwards
+    fn step_batch(&self, users: &[UserId]) -> Vec<Option<TetrisPairState>> {
+        let mut states = Vec::with_capacity(users.len());
+        let mut unfinished = Vec::new();
+        {
+            let matches = self.matches.read().unwrap();
+            for (index, user_id) in users.iter().enumerate() {
+                match self.step_in_match(&matches, *user_id) {
+                    Some((_, Ok(Some(state)))) => states.push(Some(state)),
+                    stepped => {
+                        states.push(None);
+                        unfinished.push((index, stepped));
                     }
                 }
             }
         }
+        if !unfinished.is_empty() {
+            let mut matches = self.matches.write().unwrap();
+            for (index, stepped) in unfinished {
+                states[index] = match stepped {
+                    Some((match_id, stepped)) => self.finish_step(&mut matches, match_id, stepped),
+                    None => self.step_locked(&mut matches, users[index]),
+                };
+            }
+        }
+        states
+    }
+    // Step user's game at the next tick of the scheduler
+    async fn tick(&self, user_id: UserId) -> Option<TetrisPairState> {
+        self.scheduler.step(self, user_id).await
+    }
+    // Versus game of new match with handicaps of the players, see handicap
+    fn new_pair(&self, player_a: &UserId, player_b: &UserId) -> TetrisPair {
+        let handicaps = self
+            .rules
+            .handicap
+            .handicaps([self.ratings.get(*player_a), self.ratings.get(*player_b)]);
+        TetrisPair::with_rules(10, 20, GameMode::Versus.randomizer(), self.rules.clone())
+            .with_handicaps(handicaps)
+    }
+    // Step user's game in it's match, the match is published when stepped. None without
+    // match, state is None when the game diverged
+    #[allow(clippy::type_complexity)]
+    fn step_in_match(
+        &self,
+        matches: &VersusMatches,
+        user_id: UserId,
+    ) -> Option<(MatchId, Result<Option<TetrisPairState>, String>)> {
+        let (match_id, tetris_match) = matches.get_match_for_player(&user_id)?;
+        let player_side = tetris_match.get_player_side(&user_id)?;
+        let mut field = tetris_match.field.lock().unwrap();
+        let stepped = quarantine::guard(|| {
+            let divergence = field.step_player(player_side);
+            (divergence < 100).then(|| field.get_player_game_state(player_side))
+        });
+        if let Ok(Some(_)) = stepped {
+            self.published
+                .publish(match_id, tetris_match.players(), &field);
+        }
+        Some((match_id, stepped))
+    }
+    // Match the user if needed and step the game
+    fn step_locked(&self, matches: &mut VersusMatches, user_id: UserId) -> Option<TetrisPairState> {
+        let create =
+            |player_a: &UserId, player_b: &UserId| Mutex::new(self.new_pair(player_a, player_b));
+        if !matches.find_match_between(&user_id, create) {
+            return None;
+        }
+        let (match_id, stepped) = self.step_in_match(matches, user_id)?;
+        self.finish_step(matches, match_id, stepped)
+    }
+    // Remove the match when the game diverged or panicked
+    fn finish_step(
+        &self,
+        matches: &mut VersusMatches,
+        match_id: MatchId,
+        stepped: Result<Option<TetrisPairState>, String>,
+    ) -> Option<TetrisPairState> {
+        match stepped {
+            Ok(Some(state)) => return Some(state),
+            Ok(None) => {
+                if let Some(dropped) = self.dropped_game(matches, match_id, DropReason::Diverged) {
+                    self.dropped.add(dropped);
+                }
+                matches.remove_match(match_id);
+                self.published.remove(match_id);
+            }
+            Err(panic) => self.quarantine(matches, match_id, panic, None),
+        }
         None
     }
+    // Remove match which panicked, keeping it's replays and the triggering input,
+    // which are the inputs of the failing step unless given
+    fn quarantine(
+        &self,
+        matches: &mut VersusMatches,
+        match_id: MatchId,
+        panic: String,
+        input: Option<(UserId, Action)>,
+    ) {
+        if let Some(tetris_match) = matches.get_match(&match_id) {
+            let players = vec![tetris_match.player_a, tetris_match.player_b];
+            // Match state may be broken by the panic
+            let field = tetris_match
+                .field
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let replays = quarantine::guard(|| field.get_replays().to_vec()).unwrap_or_default();
+            let trigger = match input {
+                Some(input) => vec![input],
+                None => quarantine::step_inputs(&players, &replays),
+            };
+            self.quarantine.add(QuarantinedGame {
+                mode: QuarantinedMode::Versus,
+                arena: None,
+                started: field.get_started(),
+                quarantined: unix_time(),
+                players,
+                replays,
+                trigger,
+                state: None,
+                trigger_move: None,
+                panic,
+            });
+        }
+        matches.remove_match(match_id);
+        self.published.remove(match_id);
+    }
+    // Publish state of the match for spectators when it changed
+    fn publish(&self, matches: &VersusMatches, match_id: MatchId) {
+        if let Some(tetris_match) = matches.get_match(&match_id) {
+            let field = tetris_match.field.lock().unwrap();
+            self.published
+                .publish(match_id, tetris_match.players(), &field);
+        }
+    }
 }
 
 // Get user id from cookie, if cookie is not set or user id is not valid, create new user id and set cookie
 fn get_or_create_user_id(
     cookie_jar: &CookieJar,
-    validate: impl FnOnce(u32) -> bool,
-    create: impl FnOnce() -> u32,
-) -> u32 {
+    validate: impl FnOnce(UserId) -> bool,
+    create: impl FnOnce() -> UserId,
+) -> UserId {
+    // Pending value, user id may be dropped by session check
     cookie_jar
-        .get("user_id")
-        .map(|v| v.value().parse::<u32>().ok())
-        .flatten()
+        .get_pending("user_id")
+        .and_then(|v| v.value().parse::<UserId>().ok())
         .filter(|user_id| validate(*user_id))
         .unwrap_or_else(|| {
             let user_id = create();
@@ -94,30 +1045,32 @@ fn get_or_create_user_id(
         })
 }
 
+// Current time as seconds since unix epoch
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Current time as milliseconds since unix epoch
+fn unix_time_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 // Get user id by CookieJar and Users storage
-fn user_id(cookie_jar: &CookieJar, tetris_matches: &TetrisMatches) -> u32 {
+fn user_id(cookie_jar: &CookieJar, tetris_matches: &TetrisMatches) -> UserId {
     get_or_create_user_id(
         cookie_jar,
-        |_| true, // TODO: check for impersonation
+        // Forged ids and ids without their session are dropped by SessionFairing, see sessions.rs
+        |_| true,
         || tetris_matches.get_free_user_id(),
     )
 }
 
-// Root page handler, returns a string with html content
-#[get("/")]
-fn index(cookie_jar: &CookieJar, tetris_matches: &State<TetrisMatches>) -> String {
-    // Access managed storage with type Tetrises
-    let user_id = user_id(cookie_jar, tetris_matches);
-    // tetrises.access_refresh_mut_with_create(&user_id, || Some(Tetris::new(10, 20)), |_| ());
-    // let _tetris = tetrises.get_mut_or_else(&user_id, || Tetris::new(10, 20));
-    // vec![user_id as usize, users.len()]
-    //     .into_iter()
-    //     .map(|v| v.to_string())
-    //     .collect::<Vec<_>>()
-    //     .join(" ")
-    user_id.to_string()
-}
-
 // Returns game state as json. Returns HTTP error 404 if user is not found
 #[get("/game_state")]
 fn game_state(
@@ -127,18 +1080,53 @@ fn game_state(
     let user_id = user_id(cookie_jar, matches);
     let game_state = matches.game_state(user_id);
     if let Some(game_state) = game_state {
-        Ok(serde_json::to_string(&game_state).unwrap())
+        Ok(serde_json::to_string(&game_state.project(View::Player)).unwrap())
     } else {
         Err(status::NotFound("Game not found".to_string()))
     }
 }
 
+// List of active games, filtered by garbage rules, minimal score and friends playing them,
+// sorted by score, start time or viewers. Viewers are spectators of multi-board streams and
+// of the spotlight while the game is featured. Friends only pages differ by user and aren't
+// cached
+#[get("/live?<query..>")]
+#[allow(clippy::too_many_arguments)]
+fn live(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    friends: &State<Friends>,
+    caches: &State<Caches>,
+    connections: &State<Connections>,
+    spotlight: &State<Spotlight>,
+    uri: &Origin,
+    query: LiveQuery,
+) -> Result<(ContentType, String), Error> {
+    let players = friends.filter(cookie_jar, query.friends);
+    let page = || {
+        let mut viewers = connections.viewers();
+        if let Some((match_id, spotlight_viewers)) = spotlight.featured() {
+            *viewers.entry(match_id).or_insert(0) += spotlight_viewers;
+        }
+        Ok(serde_json::to_string(&matches.live_games(
+            &query,
+            &viewers,
+            players.as_ref(),
+        )?)?)
+    };
+    let page = match players {
+        Some(_) => page()?,
+        None => caches.live.get_or_insert_with(&uri.to_string(), page)?,
+    };
+    Ok((ContentType::JSON, page))
+}
+
 // Admin page, returns a handlebars template
 #[get("/admin")]
-fn admin() -> Template {
-    let context = ();
+fn admin(_admin: Viewer, uptime: &State<Uptime>) -> Template {
+    let context = context! { version: uptime.info() };
     // Render admin/index.html.hbs template
-    Template::render("admin/index", &context)
+    Template::render("admin/index", context)
 }
 
 // Serve specified static file or index.html if only path is given, set rank = 2
@@ -164,75 +1152,507 @@ async fn files(file: std::path::PathBuf) -> Option<rocket::fs::NamedFile> {
     }
 }
 
-// Returns game state as EventStream
+// Returns game state as EventStream. Stream starts with "input_epoch" event with epoch
+// for sequence numbers of inputs and "rules" event with hold and next queue rules of
+// the game, to render them in client. Also sends "ping" events to be answered
+// with /pong/<nonce> and "latency" events with measured round trips. Players waiting for
+// a match get "queue" events once a second. New versus match sends "countdown" events with the common start time, inputs are refused before it.
+// Accepted inputs are acknowledged with "ack" events, rejected ones are answered with
+// "correction" events, see input_sequence.
+// During maintenance new players are refused, players of running games get
+// "maintenance" events with countdown and the stream ends when their game is over.
+// Players of a game quarantined after panic get "quarantined" event and the stream ends.
+// Garbage sent to the player with delay of the garbage rules is announced with
+// "attack_incoming" events, pending garbage is part of the state until it's received
 #[get("/sse")]
-fn sse<'a, 'b>(
-    cookie_jar: &'a CookieJar,
+#[allow(clippy::too_many_arguments)]
+fn sse<'b>(
+    _admitted: Admitted,
+    cookie_jar: &CookieJar,
     matches: &'b State<TetrisMatches>,
-) -> EventStream![Event + 'b] {
+    writes: &'b State<WriteQueue>,
+    latency: &'b State<Latency>,
+    sequences: &'b State<InputSequences>,
+    maintenance: &'b State<Maintenance>,
+    pauses: &'b State<Pauses>,
+    connections: &State<Connections>,
+) -> Result<EventStream![Event + 'b], MaintenanceRefusal> {
     let user_id = user_id(cookie_jar, matches);
-    EventStream! {
-        let mut interval = time::interval(Duration::from_millis(10));
+    if !matches.has_match(user_id) {
+        maintenance.check()?;
+    }
+    let epoch = sequences.new_epoch(user_id, InputTarget::Versus);
+    let events = game_stream(
+        user_id,
+        epoch,
+        matches,
+        writes,
+        latency,
+        sequences,
+        maintenance,
+        pauses,
+    );
+    let events = connections.track(Some(user_id), "game", events);
+    Ok(EventStream::from(events.map(ChannelEvent::into_event)))
+}
+
+// Game stream of the user in given matches, see sse
+#[allow(clippy::too_many_arguments)]
+fn game_stream<'b>(
+    user_id: UserId,
+    epoch: u32,
+    matches: &'b TetrisMatches,
+    writes: &'b WriteQueue,
+    latency: &'b Latency,
+    sequences: &'b InputSequences,
+    maintenance: &'b Maintenance,
+    pauses: &'b Pauses,
+) -> impl Stream<Item = ChannelEvent> + Send + 'b {
+    matches.resume(user_id);
+    stream! {
+        yield ChannelEvent::named("input_epoch", epoch.to_string());
+        yield ChannelEvent::named("rules", serde_json::to_string(&matches.rules.pieces).unwrap());
+        let mut next_ping = time::Instant::now();
+        let (mut own_afk, mut opponent_afk) = (AfkStatus::Active, AfkStatus::Active);
+        let mut paused = false;
+        // Seconds left of the last countdown event sent
+        let mut countdown_sent = None;
+        let mut history = StateHistory::default();
+        // Id of the last incoming attack announced
+        let mut last_attack = 0;
         loop {
-            if let Some(game_state) = matches.step(user_id) {
-                // Send game state as json
-                yield Event::data(serde_json::to_string(&game_state).unwrap());
-                interval.tick().await;
+            if time::Instant::now() >= next_ping {
+                next_ping = time::Instant::now() + latency::PING_INTERVAL;
+                let report = LatencyReport {
+                    rtt_ms: latency.average(user_id),
+                    opponent_rtt_ms: matches.opponent(user_id).and_then(|opponent| latency.average(opponent)),
+                };
+                if let Some(rtt_ms) = report.rtt_ms {
+                    matches.set_latency(user_id, rtt_ms);
+                }
+                yield ChannelEvent::named("latency", serde_json::to_string(&report).unwrap());
+                yield ChannelEvent::named("ping", latency.ping(user_id).to_string());
+                if let Some(status) = maintenance.status() {
+                    yield ChannelEvent::named("maintenance", serde_json::to_string(&status).unwrap());
+                }
+                // Apply pause reported by client, notify when it starts or ends
+                if let Some(game) = matches.started(user_id) {
+                    if pauses.is_paused(user_id, game) != paused {
+                        paused = !paused;
+                        matches.set_paused(user_id, paused);
+                        if let Some(status) = pauses.status(user_id, game) {
+                            yield ChannelEvent::named("pause", serde_json::to_string(&status).unwrap());
+                        }
+                    }
+                }
+            }
+            // Queue results of finished game for storing and verification
+            if let Some(write) = matches.take_results(user_id) {
+                writes.push(write).await;
+            }
+            // No matchmaking during maintenance
+            if maintenance.is_active() && !matches.has_match(user_id) {
+                break;
+            }
+            if let Some(game_state) = matches.tick(user_id).await {
+                // Count down to the match start, once a second until it starts
+                if let Some(countdown) = matches.countdown(user_id) {
+                    if countdown_sent != Some(countdown.seconds_left) && countdown_sent != Some(0) {
+                        countdown_sent = Some(countdown.seconds_left);
+                        yield ChannelEvent::named("countdown", serde_json::to_string(&countdown).unwrap());
+                    }
+                }
+                // Send game state as json, with acknowledgements and corrections of inputs
+                // since the last one
+                let game_state = game_state.project(View::Player);
+                let (acks, rejections) = sequences.take_feedback(user_id);
+                for ack in acks {
+                    yield ChannelEvent::named("ack", serde_json::to_string(&ack).unwrap());
+                }
+                history.push(game_state.player.clone());
+                for correction in history.corrections(&rejections) {
+                    yield ChannelEvent::named("correction", serde_json::to_string(&correction).unwrap());
+                }
+                // Announce attacks sent to the player since the last state
+                let announced = last_attack;
+                for attack in game_state.incoming.iter().filter(|attack| attack.id > announced) {
+                    last_attack = attack.id;
+                    yield ChannelEvent::named("attack_incoming", serde_json::to_string(attack).unwrap());
+                }
+                yield ChannelEvent::message(serde_json::to_string(&game_state).unwrap());
+                // Notify about AFK status changes of the player and opponent
+                if let Some((own, opponent)) = matches.afk_status(user_id) {
+                    if !own.same_kind(&own_afk) {
+                        yield ChannelEvent::named("afk", serde_json::to_string(&own).unwrap());
+                    }
+                    if !opponent.same_kind(&opponent_afk) {
+                        yield ChannelEvent::named("opponent_afk", serde_json::to_string(&opponent).unwrap());
+                    }
+                    (own_afk, opponent_afk) = (own, opponent);
+                }
             } else {
-                yield Event::data("foo".to_string());
+                if matches.is_cancelled(user_id) {
+                    yield ChannelEvent::named("cancelled", String::new());
+                    break;
+                }
+                if matches.quarantine.take_affected(user_id) {
+                    yield ChannelEvent::named("quarantined", String::new());
+                    break;
+                }
+                if let Some(status) = matches.queue_status(user_id) {
+                    yield ChannelEvent::named("queue", serde_json::to_string(&status).unwrap());
+                }
+                yield ChannelEvent::message("foo".to_string());
                 time::sleep(Duration::from_millis(1000)).await;
-                interval = time::interval(Duration::from_millis(10));
             }
         }
     }
 }
 
-// When /down url is requested, move tetris figure down
-#[post("/down")]
-fn down(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) {
+// Pass user action to user's match or sprint game, the one of the input epoch's stream.
+// Action is applied only when it's sequence number is accepted, it's acknowledged in the
+// game stream then
+fn add_action(
+    cookie_jar: &CookieJar,
+    matches: &TetrisMatches,
+    sprints: &TetrisSprints,
+    sequences: &InputSequences,
+    input: &InputSeq,
+    action: Action,
+) -> Result<(), Error> {
     let user_id = user_id(cookie_jar, matches);
-    matches.add_action(user_id, Action::MoveDown);
+    let accepted = sequences
+        .accept(user_id, input)
+        .and_then(|(seq, target)| match target {
+            InputTarget::Versus => Ok((seq, matches.add_action(user_id, action)?)),
+            InputTarget::Sprint => Ok((seq, sprints.add_action(user_id, action))),
+            InputTarget::Arena => Err(Error::InvalidInputError(
+                "Input epoch is of an arena game".to_string(),
+            )),
+        });
+    let (seq, version) = accepted.inspect_err(|_| sequences.reject(user_id, input))?;
+    if let Some(version) = version {
+        sequences.ack(user_id, seq, version);
+    }
+    Ok(())
+}
+
+// When /down url is requested, move tetris figure down
+#[post("/down?<input..>")]
+fn down(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::MoveDown,
+    )
 }
 
 // When /left url is requested, move tetris figure left
-#[post("/left")]
-fn left(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) {
-    let user_id = user_id(cookie_jar, matches);
-    matches.add_action(user_id, Action::MoveLeft);
+#[post("/left?<input..>")]
+fn left(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::MoveLeft,
+    )
 }
 
 // When /right url is requested, move tetris figure right
-#[post("/right")]
-fn right(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) {
-    let user_id = user_id(cookie_jar, matches);
-    matches.add_action(user_id, Action::MoveRight);
+#[post("/right?<input..>")]
+fn right(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::MoveRight,
+    )
 }
 
 // When /rotate_right url is requested, rotate tetris figure right
-#[post("/rotate_right")]
-fn rotate_right(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) {
-    let user_id = user_id(cookie_jar, matches);
-    matches.add_action(user_id, Action::RotateRight);
+#[post("/rotate_right?<input..>")]
+fn rotate_right(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::RotateRight,
+    )
 }
 
 // When /rotate_left url is requested, rotate tetris figure left
-#[post("/rotate_left")]
-fn rotate_left(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) {
-    let user_id = user_id(cookie_jar, matches);
-    matches.add_action(user_id, Action::RotateLeft);
+#[post("/rotate_left?<input..>")]
+fn rotate_left(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::RotateLeft,
+    )
 }
 
 // When /drop url is requested, drop tetris figure
-#[post("/drop")]
-fn drop(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) {
-    let user_id = user_id(cookie_jar, matches);
-    matches.add_action(user_id, Action::Drop);
+#[post("/drop?<input..>")]
+fn drop(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::Drop,
+    )
+}
+
+// When /hold url is requested, swap tetris figure with the held one
+#[post("/hold?<input..>")]
+fn hold(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::Hold,
+    )
 }
 
-#[post("/bottom_refill")]
-fn bottom_refill(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) {
+// Leave versus matchmaking queue, game stream of the user ends with "cancelled" event
+#[post("/match/cancel")]
+fn cancel_match(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) -> Result<(), Error> {
     let user_id = user_id(cookie_jar, matches);
-    matches.add_action(user_id, Action::BottomRefill);
+    if !matches.cancel(user_id) {
+        return Err(Error::NotFoundError("Not in matchmaking queue".to_string()));
+    }
+    Ok(())
+}
+
+// When /bottom_refill url is requested, add random line to the bottom of the field
+#[post("/bottom_refill?<input..>")]
+fn bottom_refill(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    sequences: &State<InputSequences>,
+    input: InputSeq,
+) -> Result<(), Error> {
+    add_action(
+        cookie_jar,
+        matches,
+        sprints,
+        sequences,
+        &input,
+        Action::BottomRefill,
+    )
+}
+
+// Hold and next queue of the mode configured by <prefix>hold and <prefix>next_queue keys
+fn piece_rules(figment: &Figment, prefix: &str, mode: GameMode) -> Result<PieceRules, Error> {
+    let defaults = mode.pieces();
+    let pieces = PieceRules {
+        hold: figment
+            .extract_inner::<bool>(&format!("{}hold", prefix))
+            .unwrap_or(defaults.hold),
+        next_queue: figment
+            .extract_inner::<usize>(&format!("{}next_queue", prefix))
+            .unwrap_or(defaults.next_queue),
+    };
+    pieces
+        .validate()
+        .map_err(|e| Error::InvalidInputError(format!("Invalid {:?} piece rules: {}", mode, e)))?;
+    Ok(pieces)
+}
+
+// Versus rules configured by garbage_rules, scoring_rules, afk_timeout, afk_grace, rng,
+// difficulty, versus_countdown, hold and next_queue keys
+fn versus_rules(
+    figment: &Figment,
+    rulebook: &GarbageRulebook,
+    scoring_rulebook: &ScoringRulebook,
+) -> Result<VersusRules, Error> {
+    let rules_name = figment
+        .extract_inner::<String>("garbage_rules")
+        .unwrap_or_else(|_| garbage_rules::DEFAULT_RULES.to_string());
+    let garbage = rulebook
+        .get(&rules_name)
+        .ok_or_else(|| Error::InvalidInputError(format!("Unknown garbage rules {}", rules_name)))?;
+    // AFK limits are configured in seconds, game makes 100 steps per second
+    let default_afk = AfkRules::default();
+    let afk = AfkRules {
+        timeout: figment
+            .extract_inner::<u64>("afk_timeout")
+            .map_or(default_afk.timeout, |seconds| seconds * 100),
+        grace: figment
+            .extract_inner::<u64>("afk_grace")
+            .map_or(default_afk.grace, |seconds| seconds * 100),
+    };
+    let rng = figment.extract_inner::<RngKind>("rng").unwrap_or_default();
+    let difficulty = figment
+        .extract_inner::<Difficulty>("difficulty")
+        .unwrap_or_default();
+    let countdown = figment
+        .extract_inner::<u64>("versus_countdown")
+        .map_or(tetris_pair::DEFAULT_COUNTDOWN, Duration::from_secs);
+    let scoring_name = figment
+        .extract_inner::<String>("scoring_rules")
+        .unwrap_or_else(|_| scoring::DEFAULT_RULES.to_string());
+    let scoring = scoring_rulebook.get(&scoring_name).ok_or_else(|| {
+        Error::InvalidInputError(format!("Unknown scoring rules {}", scoring_name))
+    })?;
+    Ok(VersusRules {
+        garbage,
+        afk,
+        rng,
+        difficulty,
+        countdown,
+        scoring,
+        pieces: piece_rules(figment, "", GameMode::Versus)?,
+        shared_pieces: figment
+            .extract_inner::<bool>("shared_pieces")
+            .unwrap_or(false),
+        handicap: HandicapRules::from_config(figment)?,
+        gravity: GravityTuning::default(),
+    })
+}
+
+// Versus mode. It's state is managed by init, it's shared with arenas, spotlight and
+// recovery. Input routes serve sprints too
+fn versus_plugin() -> GamePlugin {
+    GamePlugin {
+        game_type: GameType {
+            id: "versus",
+            name: "Tetris versus",
+            description: "Two players, cleared lines are sent to the opponent",
+            entry: "/tetris/",
+        },
+        init: |_| Ok(()),
+        routes: || {
+            routes![
+                sse,
+                game_state,
+                down,
+                left,
+                right,
+                rotate_right,
+                rotate_left,
+                drop,
+                bottom_refill,
+                hold,
+                cancel_match
+            ]
+        },
+        attach: Ok,
+        session: |rocket, user_id| {
+            let matches = rocket.state::<TetrisMatches>()?;
+            SessionStatus::of(matches.is_waiting(user_id), matches.has_match(user_id))
+        },
+        state: |rocket, user_id| {
+            let state = rocket.state::<TetrisMatches>()?.game_state(user_id)?;
+            Some(serde_json::to_string(&state.project(View::Player)).unwrap())
+        },
+    }
+}
+
+// Remove finished matches periodically
+async fn cleanup_job(matches: TetrisMatches) {
+    let mut interval = time::interval(FINISHED_MATCH_TTL);
+    loop {
+        interval.tick().await;
+        matches.remove_finished();
+    }
+}
+
+// Create segments missing in database
+fn init_storage(persy: &persy::Persy) -> Result<(), Error> {
+    puzzles::init(persy)?;
+    bug_reports::init(persy)?;
+    bans::init(persy)?;
+    roles::init(persy)?;
+    rng_audit::init(persy)?;
+    bots::init(persy)?;
+    leaderboard::init(persy)?;
+    stats::init(persy)?;
+    replays::init(persy)?;
+    sessions::init(persy)?;
+    match_history::init(persy)?;
+    themes::init(persy)?;
+    game_history::init(persy)?;
+    splits::init(persy)?;
+    notifications::init(persy)?;
+    friends::init(persy)?;
+    recovery::init(persy)?;
+    webhooks::init(persy)?;
+    motd::init(persy)?;
+    signatures::init(persy)?;
+    moderation::init(persy)?;
+    settings::init(persy)?;
+    email_login::init(persy)?;
+    digests::init(persy)?;
+    recording::init(persy)?;
+    ratings::init(persy)?;
+    quarantine::init(persy)?;
+    dropped_games::init(persy)?;
+    gravity_curves::init(persy)?;
+    regions::init(persy)?;
+    access::init(persy)?;
+    daily_quotas::init(persy)?;
+    GameRegistry::init(persy)?;
+    version::init(persy)?;
+    tournaments::init(persy)?;
+    Ok(())
 }
 
 // .ok_or(status::NotFound("User not found".to_string()));
@@ -240,51 +1660,448 @@ async fn init() -> Result<Rocket<Ignite>, Error> {
     // Get executable name without extension
     let exe_name = std::env::current_exe()?;
     // Remove extension
-    let db_name = exe_name
+    let db_stem = exe_name
         .file_stem()
-        .map(|s| s.to_str())
-        .flatten()
+        .and_then(|s| s.to_str())
         .unwrap_or("gameserver");
     // make db name = executable name + ".db"
-    let db_name = db_name.to_owned() + ".db";
+    let db_name = db_stem.to_owned() + ".db";
     // create or open Persy database storage
     println!("Database file: {}", db_name);
-    let config = persy::Config::default();
-    Persy::open_or_create_with(db_name, config, |_persy| Ok(()))?;
+    // Public socket shared with the next server process on deploys, Rocket listens behind it.
+    // The database is released by the old process when it's drained
+    let handover = Handover::from_config()?;
+    let db = handover.open_database(&db_name).await?;
+    // Subsystems are started in dependency order and stopped in reverse on shutdown
+    let lifecycle = Lifecycle::from_config();
+    lifecycle.add("database", &[])?;
+    let stopped_db = db.clone();
+    lifecycle.on_stop("database", move || async move { stopped_db.drain().await });
+    // Create segments missing in database
+    init_storage(&db.read())?;
+    // Record start of the server
+    let uptime = Uptime::start(&db)?;
+    // Load sessions revocation list
+    let sessions = Sessions::load(&db.read())?;
+    // Load message of the day banner
+    let motd = Motd::load(&db.read())?;
+    // Load key of result signatures, it's generated on the first start
+    let signer = ResultSigner::load(&db.read())?;
+    // Load wordlists of text moderation
+    let moderation = Moderation::load(&db.read())?;
+    let admin_roles = AdminRoles::load(&db.read())?;
+    let friends = Friends::load(&db.read())?;
+    // Load runtime overrides of configuration
+    let settings = RuntimeSettings::load(&db.read())?;
+    let input_sequences = InputSequences::new(settings.subscribe());
+    let maintenance = Maintenance::new(settings.subscribe());
 
+    // Start background statistics aggregation
+    lifecycle.spawn("stats", &["database"], stats::aggregation_job(db.clone()))?;
+    // Create response caches
+    let caches = Caches::new(settings.subscribe());
+    // Start scheduled database compaction
+    lifecycle.spawn(
+        "compaction",
+        &["database"],
+        compaction::compaction_job(db.clone(), caches.leaderboard.clone()),
+    )?;
+    // Load access times of users and store them periodically and on shutdown
+    let access = AccessTimes::load(&db.read())?;
+    lifecycle.spawn(
+        "access_times",
+        &["database"],
+        access::flush_job(db.clone(), access.clone()),
+    )?;
+    let (stopped_db, stopped_access) = (db.clone(), access.clone());
+    lifecycle.on_stop("access_times", move || {
+        access::store(stopped_db, stopped_access)
+    });
+    // Preload records of recently active players while server launches
+    lifecycle.spawn(
+        "warmup",
+        &["database", "access_times"],
+        warmup::warmup_job(db.clone(), caches.leaderboard.clone(), access.clone()),
+    )?;
+    // Login links and weekly digests are sent by email when smtp is configured
+    let email_login = EmailLogin::from_config()?;
+    let digests = Digests::from_config(email_login.clone())?;
+    lifecycle.spawn(
+        "digests",
+        &["database"],
+        digests.clone().digest_job(db.clone()),
+    )?;
+    // Start webhook deliveries
+    let webhooks = Webhooks::start(db.clone())?;
+    lifecycle.add("webhooks", &["database"])?;
+    // Start replay verification worker
+    let verifier = ReplayVerifier::start(
+        db.clone(),
+        caches.leaderboard.clone(),
+        Some(webhooks.clone()),
+        Discord::from_config()?,
+    )?;
+    lifecycle.add("replay_verification", &["database", "webhooks"])?;
+    // Distributions of finished games, shared by write queues of the server and arenas
+    let game_metrics = GameMetrics::new();
+    // Start write-behind queue, it's flushed on shutdown
+    let writes = WriteQueue::start(db.clone(), verifier.clone(), game_metrics.clone());
+    lifecycle.add("write_queue", &["database", "replay_verification"])?;
+    let stopped_writes = writes.clone();
+    lifecycle.on_stop("write_queue", move || async move {
+        stopped_writes.flush().await
+    });
+
+    // Load garbage rulesets and select one for versus matches
+    let rulebook = GarbageRulebook::load(std::path::Path::new(garbage_rules::RULES_DIR))?;
+    // Load piece skins and board themes
+    let themes = Themes::load(std::path::Path::new(themes::THEMES_DIR))?;
+    println!("Themes: {}", themes.names().join(", "));
+    // Load scoring rulesets of scoring table
+    let scoring_rulebook = ScoringRulebook::load(&Config::figment())?;
+    let mut rules = versus_rules(&Config::figment(), &rulebook, &scoring_rulebook)?;
+    rules.gravity = GravityTuning::load(&db.read())?;
+    println!("Garbage rules: {}", rules.garbage.name);
+    println!("Scoring rules: {}", rules.scoring.name);
+    println!("Random source: {:?}", rules.rng);
+    println!("Versus difficulty: {:?}", rules.difficulty);
+    println!("Versus handicap: {:?}", rules.handicap.mode);
+    let admission = Admission::from_config(settings.subscribe());
+    println!("Admission challenge: {} bits", admission.challenge_bits());
+
+    // Games which panicked are quarantined instead of taking the server down
+    quarantine::install_panic_hook();
+    let quarantine = Quarantine::new();
+    lifecycle.spawn(
+        "quarantine",
+        &["database"],
+        quarantine::persist_job(db.clone(), quarantine.clone()),
+    )?;
+    let (stopped_db, stopped_quarantine) = (db.clone(), quarantine.clone());
+    lifecycle.on_stop("quarantine", move || async move {
+        quarantine::persist(stopped_db, &stopped_quarantine).await
+    });
+    // Active games dropped from memory are logged and alerted on
+    let dropped = DroppedGames::new();
+    lifecycle.spawn(
+        "dropped_games",
+        &["database"],
+        dropped_games::persist_job(db.clone(), dropped.clone()),
+    )?;
+    let (stopped_db, stopped_dropped) = (db.clone(), dropped.clone());
+    lifecycle.on_stop("dropped_games", move || async move {
+        dropped_games::persist(stopped_db, &stopped_dropped).await
+    });
     // Create matches storage
-    let matches = TetrisMatches::new();
+    let matches = TetrisMatches::new(
+        rules.clone(),
+        Ratings::load(&db.read())?,
+        settings.subscribe(),
+        quarantine.clone(),
+        dropped.clone(),
+    );
+    // Restore matches interrupted by previous shutdown and keep journal of running ones
+    let recovered = recovery::recover(&db.read())?;
+    for snapshot in &recovered {
+        matches.restore(snapshot, &rulebook);
+    }
+    if !recovered.is_empty() {
+        println!("Recovered {} interrupted matches", recovered.len());
+    }
+    lifecycle.spawn(
+        "recovery",
+        &["database"],
+        recovery::recovery_job(db.clone(), matches.clone()),
+    )?;
+    // Remove finished matches periodically
+    lifecycle.spawn("match_cleanup", &[], cleanup_job(matches.clone()))?;
+    // Step versus games on scheduler's ticks, games write results through write queue
+    matches.scheduler.start(matches.clone());
+    lifecycle.add("scheduler", &["write_queue", "quarantine", "dropped_games"])?;
+    // Synthetic players started by admin, they're stopped before the scheduler
+    let synthetic_load = SyntheticLoad::new(matches.clone(), writes.clone());
+    lifecycle.add("synthetic_load", &["scheduler", "write_queue"])?;
+    // Tournament brackets, their matches are followed until the champion is known
+    let tournaments = Tournaments::load(&db.read())?;
+    lifecycle.spawn(
+        "tournaments",
+        &["database", "scheduler"],
+        tournaments.clone().watch_job(db.clone(), matches.clone()),
+    )?;
+    let stopped_load = synthetic_load.clone();
+    lifecycle.on_stop("synthetic_load", move || async move { stopped_load.stop() });
+    // Start arenas hosted by this server
+    let arenas = Arenas::start(
+        db_stem,
+        &rulebook,
+        &scoring_rulebook,
+        &settings,
+        &game_metrics,
+        &quarantine,
+        &dropped,
+    )?;
+    lifecycle.add("arenas", &["quarantine", "dropped_games"])?;
+    lifecycle.spawn("arena_cleanup", &["arenas"], arenas.clone().cleanup_job())?;
+    let stopped_arenas = arenas.clone();
+    lifecycle.on_stop("arenas", move || async move { stopped_arenas.stop().await });
+    // Long poll subscriptions, idle ones are dropped periodically
+    let polls = Polls::from_config();
+    lifecycle.spawn("polling", &[], polls.clone().reaper_job())?;
+    // Open event stream connections, stale ones are closed periodically
+    let latency = Latency::new();
+    let connections = Connections::from_config(latency.clone());
+    lifecycle.spawn("connections", &[], connections.clone().reaper_job())?;
+    // Daily quota counters of past days are deleted periodically
+    lifecycle.spawn(
+        "daily_quotas",
+        &["database"],
+        daily_quotas::cleanup_job(db.clone()),
+    )?;
+    let bot_sandbox = BotSandbox::from_config(quarantine.clone());
+    lifecycle.spawn(
+        "bot_sandbox",
+        &["database"],
+        bot_sandbox.clone().reaper_job(),
+    )?;
+    // Start spotlight broadcaster
+    let spotlight = Spotlight::new();
+    lifecycle.spawn(
+        "spotlight",
+        &["scheduler"],
+        spotlight.clone().broadcaster_job(matches.clone()),
+    )?;
 
+    #[cfg(feature = "graphql")]
+    let graphql_db = db.clone();
     // Start rocket server
-    let rocket = rocket::build()
+    let rocket = rocket::custom(handover.figment(Config::figment()))
         // Read config from Rocket.toml
         .manage(Config::figment())
-        // Attach Template::fairing() to rocket instance
-        .attach(Template::fairing())
+        // Attach templates fairing with {{motd}} banner helper to rocket instance
+        .attach(motd.templates())
+        // Message of the day banner
+        .manage(motd)
+        .manage(signer)
+        .manage(synthetic_load)
         // Matches
         .manage(matches)
-        // Mount index route
-        .mount("/", routes![index, admin, files, game_state])
-        .mount(
-            "/",
-            routes![
-                sse,
-                down,
-                left,
-                right,
-                rotate_right,
-                rotate_left,
-                drop,
-                bottom_refill
-            ],
-        )
-        .launch()
-        .await?;
+        // Tournaments and their brackets
+        .manage(tournaments)
+        // Featured game broadcast
+        .manage(spotlight)
+        // Available garbage rulesets
+        .manage(rulebook)
+        .manage(scoring_rulebook)
+        // Available themes
+        .manage(themes)
+        // Wordlists of text moderation
+        .manage(moderation)
+        // Configuration overridden at runtime
+        .manage(settings)
+        // Storage quotas of users
+        .manage(Quotas::from_config())
+        // Daily quotas of users, kept across restarts and told in response headers
+        .manage(DailyQuotas::from_config())
+        .attach(QuotaHeaders)
+        // Database
+        .manage(db)
+        // Replay verification queue
+        .manage(verifier)
+        // Deferred writes queue, flushed on shutdown
+        .manage(writes)
+        // Response caches
+        .manage(caches)
+        // Round trip measurements of game streams
+        .manage(latency)
+        // Open event stream connections
+        .manage(connections)
+        // Input sequence windows of game streams
+        .manage(input_sequences)
+        // Delivery of new notifications to connected users
+        .manage(Notifications::new())
+        // Friends of users for friends only listings
+        .manage(friends)
+        .mount("/", friends::routes())
+        // Webhook deliveries, server errors are counted for spike events
+        .manage(webhooks.clone())
+        .attach(webhooks)
+        // Pauses of hidden game pages
+        .manage(Pauses::new())
+        // Maintenance mode switch
+        .manage(maintenance)
+        // Arenas, their queued writes are flushed on shutdown
+        .manage(arenas)
+        // HTTP-01 challenges of built-in and external ACME clients
+        .manage(AcmeChallenges::from_config())
+        // Proxies allowed to report client addresses
+        .manage(TrustedProxies::from_config()?)
+        // Countries of client addresses and countries of users for regional leaderboards
+        .manage(GeoIp::from_config()?)
+        .mount("/", regions::routes())
+        // Sessions revocation list and session check of each request
+        .manage(sessions)
+        .attach(SessionFairing)
+        .manage(access)
+        .attach(AccessFairing)
+        // Throttling of new user ids and admission challenges
+        .manage(admission)
+        // Login links sent by email, when smtp is configured
+        .manage(email_login)
+        .manage(digests)
+        // Mount admin, static files and live games routes
+        .mount("/", routes![admin, files, live])
+        // Mount user puzzles routes
+        .mount("/", puzzles::routes())
+        // Mount bug reports routes
+        .mount("/", bug_reports::routes())
+        // Mount bans and appeals routes
+        .mount("/", bans::routes())
+        // Roles of admin accounts checked by admin routes
+        .manage(admin_roles)
+        .mount("/", roles::routes())
+        // Mount leaderboard routes
+        .mount("/", leaderboard::routes())
+        // Mount statistics routes
+        .mount("/", stats::routes())
+        // Mount cache metrics routes
+        .mount("/", cache::routes())
+        // Mount database compaction routes
+        .mount("/", compaction::routes())
+        // Mount account sessions routes
+        .mount("/", sessions::routes())
+        // Codes moving session and game to another device
+        .manage(SessionTransfers::new())
+        .mount("/", session_transfer::routes())
+        .mount("/", admission::routes())
+        .mount("/", match_history::routes())
+        .mount("/", ratings::routes())
+        .mount("/", latency::routes())
+        .mount("/", storage_browser::routes())
+        .mount("/", maintenance::routes())
+        .mount("/", fairness::routes())
+        .mount("/", game_events::routes())
+        .mount("/", rng_audit::routes())
+        .mount("/", arenas::routes())
+        .mount("/", acme::routes())
+        .mount("/", visibility::routes())
+        .mount("/", notifications::routes())
+        // Mount webhooks admin routes
+        .mount("/", webhooks::routes())
+        // Mount themes routes
+        .mount("/", themes::routes())
+        // Mount wordlists admin routes
+        .mount("/", moderation::routes())
+        .mount("/", settings::routes())
+        .mount("/", quotas::routes())
+        // Mount replaced games routes
+        .mount("/", game_history::routes())
+        // Mount spotlight stream routes
+        .mount("/", spotlight::routes())
+        .mount("/", scheduler::routes())
+        // Game distributions in OpenMetrics format
+        .manage(game_metrics)
+        .mount("/", metrics::routes())
+        // Quarantined games, shared with game types
+        .manage(quarantine)
+        .mount("/", quarantine::routes())
+        // Active games dropped from memory, shared with game types
+        .manage(dropped)
+        .mount("/", dropped_games::routes())
+        .mount("/", multiview::routes())
+        // Tournaments overview and caster stream
+        .mount("/", tournaments::routes())
+        // Mount multiplexed event stream routes
+        .mount("/", events::routes())
+        // Long polling fallback of event streams
+        .manage(polls)
+        .mount("/", polling::routes())
+        // Bot API with sandbox games
+        .manage(bot_sandbox)
+        .mount("/", bots::routes())
+        // Mount garbage rules routes
+        .mount("/", garbage_rules::routes())
+        // Mount scoring rules routes
+        .mount("/", scoring::routes())
+        // Mount difficulty presets routes
+        .mount("/", difficulty::routes())
+        // Gravity curves of the server and arenas tuned live
+        .mount("/", gravity_curves::routes())
+        // Mount message of the day routes
+        .mount("/", motd::routes())
+        .mount("/", signatures::routes())
+        .mount("/", synthetic::routes())
+        // Mount board image routes
+        .mount("/", board_image::routes())
+        // Mount embeddable widget routes
+        .mount("/", embed::routes())
+        // Mount game invariants fuzzing routes
+        .mount("/", invariants::routes())
+        // Mount email login routes
+        .mount("/", email_login::routes())
+        .mount("/", digests::routes())
+        // Mount recording settings routes
+        .mount("/", recording::routes())
+        // Mount connection map routes
+        .mount("/", connections::routes())
+        // Mount admin game view routes
+        .mount("/", views::routes())
+        // Mount lobby and root page routes
+        .mount("/", lobby::routes())
+        // Forwarding from the handover socket and draining on deploys
+        .manage(handover.clone())
+        .attach(handover)
+        .mount("/", handover::routes())
+        // Admin console on a local socket
+        .attach(Console::from_config())
+        // Subsystems stopped in reverse start order on shutdown
+        .manage(lifecycle.clone())
+        .attach(lifecycle)
+        .mount("/", lifecycle::routes())
+        // Server version and uptime, version header of responses
+        .manage(uptime)
+        .attach(VersionHeader)
+        .mount("/", version::routes())
+        // Error pages and request ids
+        .register("/", catchers::catchers())
+        .attach(RequestIdHeader);
+    // Attach and mount game types, see games
+    let rocket = GameRegistry::new().attach(rocket)?;
+    // Mount optional graphql routes
+    #[cfg(feature = "graphql")]
+    let rocket = rocket
+        .manage(graphql::schema(graphql_db))
+        .mount("/", graphql::routes());
+    let rocket = rocket.ignite().await?;
+    if rocket.config().tls_enabled() {
+        println!("TLS enabled");
+    }
+    // Plain http listener for ACME challenges of TLS server
+    let lifecycle = rocket
+        .state::<Lifecycle>()
+        .ok_or_else(|| Error::NotFoundError("Lifecycle is not managed".to_string()))?;
+    let challenges = rocket
+        .state::<AcmeChallenges>()
+        .ok_or_else(|| Error::NotFoundError("ACME challenges are not managed".to_string()))?;
+    acme::start_http_listener(rocket.config(), lifecycle, challenges)?;
+    // Certificate of TLS listener by built-in ACME client, renewed in background
+    if let Some(provisioner) = AcmeProvisioner::from_config(rocket.config(), challenges)? {
+        provisioner.provision().await?;
+        lifecycle.spawn("acme-renewal", &["acme"], provisioner.renewal_job())?;
+    }
+    let rocket = rocket.launch().await?;
     Ok(rocket)
 }
 
 #[rocket::main]
 async fn main() {
+    // Simulation benchmark instead of the server
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).is_some_and(|arg| arg == "bench") {
+        if let Err(e) = bench::run(&args[2..]).await {
+            println!("Benchmark failed: {}", e);
+        }
+        return;
+    }
     // Handle result
     match init().await {
         Ok(_rocket) => println!("Server started successfully"),
@@ -0,0 +1,142 @@
+use std::sync::{Arc, RwLock};
+
+use rocket::{
+    form::Form,
+    get,
+    http::Status,
+    post,
+    response::{self, content::RawHtml, Responder},
+    routes,
+    serde::json::Json,
+    FromForm, Request, Route, State,
+};
+
+use crate::{
+    roles::{Operator, Viewer},
+    settings::SettingsWatch,
+};
+
+//
+// Maintenance mode. While it's on, new games and matchmaking are refused and streams
+// of running games get "maintenance" events counting down to the announced shutdown,
+// so players can finish before the deploy
+//
+
+// Countdown and message used when admin doesn't set them, message is configurable,
+// see settings. Countdown in seconds
+const DEFAULT_COUNTDOWN: u64 = 300;
+pub const DEFAULT_MESSAGE: &str = "Server is going down for maintenance, please come back later";
+
+pub use gameserver_protocol::game::MaintenanceStatus;
+
+// Message and shutdown time of current maintenance, settings with default message
+#[derive(Clone)]
+pub struct Maintenance(Arc<RwLock<Option<(String, u64)>>>, SettingsWatch);
+
+#[derive(FromForm)]
+pub struct MaintenanceForm {
+    enabled: bool,
+    // Seconds until shutdown
+    countdown: Option<u64>,
+    message: Option<String>,
+}
+
+impl Maintenance {
+    pub fn new(settings: SettingsWatch) -> Maintenance {
+        Maintenance(Arc::new(RwLock::new(None)), settings)
+    }
+
+    // Current maintenance, None when server works normally
+    pub fn status(&self) -> Option<MaintenanceStatus> {
+        let maintenance = self.0.read().unwrap();
+        let (message, shutdown_at) = maintenance.as_ref()?;
+        Some(MaintenanceStatus {
+            message: message.clone(),
+            shutdown_at: *shutdown_at,
+            seconds_left: shutdown_at.saturating_sub(crate::unix_time()),
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.0.read().unwrap().is_some()
+    }
+
+    // Refuse starting new game during maintenance
+    pub fn check(&self) -> Result<(), MaintenanceRefusal> {
+        match self.status() {
+            Some(status) => Err(MaintenanceRefusal(status)),
+            None => Ok(()),
+        }
+    }
+
+    // Turn maintenance on with countdown in seconds and message, or off
+    pub fn set(&self, enabled: bool, countdown: Option<u64>, message: Option<String>) {
+        *self.0.write().unwrap() = enabled.then(|| {
+            (
+                message
+                    .filter(|message| !message.is_empty())
+                    .unwrap_or_else(|| self.1.borrow().maintenance_message.clone()),
+                crate::unix_time() + countdown.unwrap_or(DEFAULT_COUNTDOWN),
+            )
+        });
+        match self.status() {
+            Some(status) => println!("Maintenance mode on: {:?}", status),
+            None => println!("Maintenance mode off"),
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Response to requests refused due to maintenance, html page for browsers and json otherwise
+pub struct MaintenanceRefusal(MaintenanceStatus);
+
+impl<'r> Responder<'r, 'static> for MaintenanceRefusal {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let html = request
+            .accept()
+            .is_some_and(|accept| accept.preferred().is_html());
+        let mut response = if html {
+            RawHtml(format!(
+                "<!DOCTYPE html><html><head><title>Maintenance</title></head><body>\
+                 <h1>Maintenance</h1><p>{}</p></body></html>",
+                escape_html(&self.0.message)
+            ))
+            .respond_to(request)?
+        } else {
+            Json(self.0.clone()).respond_to(request)?
+        };
+        response.set_status(Status::ServiceUnavailable);
+        response.set_raw_header("Retry-After", self.0.seconds_left.max(1).to_string());
+        Ok(response)
+    }
+}
+
+#[get("/admin/maintenance")]
+fn maintenance_status(
+    _admin: Viewer,
+    maintenance: &State<Maintenance>,
+) -> Json<Option<MaintenanceStatus>> {
+    Json(maintenance.status())
+}
+
+// Turn maintenance mode on or off
+#[post("/admin/maintenance", data = "<form>")]
+fn set_maintenance(
+    _admin: Operator,
+    maintenance: &State<Maintenance>,
+    form: Form<MaintenanceForm>,
+) -> Json<Option<MaintenanceStatus>> {
+    let form = form.into_inner();
+    maintenance.set(form.enabled, form.countdown, form.message);
+    Json(maintenance.status())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![maintenance_status, set_maintenance]
+}
@@ -0,0 +1,311 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{get, http::CookieJar, routes, serde::json::Json, FromForm, Route, State};
+use rocket_dyn_templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board_image,
+    error::Error,
+    friends::Friends,
+    handicap::Handicap,
+    ids::{GameId, UserId},
+    leaderboard::LeaderboardEntry,
+    pagination::{self, Page, SortOrder},
+    ratings::{self, Rating},
+    replays,
+    rng_audit::{self, RngAudit},
+    storage::{self, Database},
+    tetris::{CellType, Replay},
+};
+
+//
+// History of completed versus matches. Match record keeps the final state of both players
+// and refers to their leaderboard entries and replays, which are written in the same
+// transaction
+//
+
+const MATCHES_SEGMENT: &str = "match_history";
+const BY_FINISHED_INDEX: &str = "match_history_by_finished";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchPlayer {
+    pub user: UserId,
+    pub score: u64,
+    pub lines: u64,
+    pub attack_sent: u64,
+    pub garbage_received: u64,
+    pub forfeited: bool,
+    pub board: Vec<Vec<CellType>>,
+    #[serde(default)]
+    pub handicap: Handicap,
+    // Player's rating after the match, absent in matches finished before ratings
+    #[serde(default)]
+    pub rating: Option<Rating>,
+    // Leaderboard entry and replay of the player's game
+    pub entry: Option<GameId>,
+    pub replay: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchRecord {
+    // Start and finish time, seconds since unix epoch
+    pub started: u64,
+    pub finished: u64,
+    // Match duration in steps
+    pub ticks: u64,
+    // Name of garbage ruleset
+    pub garbage_rules: String,
+    // Both players got the same pieces sequence
+    #[serde(default)]
+    pub shared_pieces: bool,
+    pub players: Vec<MatchPlayer>,
+}
+
+// Match with it's database id for listings
+#[derive(Serialize)]
+pub struct MatchItem {
+    pub id: String,
+    #[serde(flatten)]
+    pub record: MatchRecord,
+}
+
+// Match page context, with Open Graph tags for link previews
+#[derive(Serialize)]
+struct MatchPage {
+    #[serde(flatten)]
+    item: MatchItem,
+    og_description: String,
+    // Board image of the winner's game
+    og_image: Option<String>,
+}
+
+// Match summary for listings, boards are shown only on match page
+#[derive(Serialize)]
+pub struct MatchSummary {
+    pub id: String,
+    pub started: u64,
+    pub finished: u64,
+    pub ticks: u64,
+    pub players: Vec<UserId>,
+    pub scores: Vec<u64>,
+}
+
+#[derive(FromForm)]
+pub struct MatchesQuery<'r> {
+    pub player: Option<UserId>,
+    // Matches of friends of the signed in user, see friends
+    pub friends: Option<bool>,
+    // Finish time order, latest first by default
+    pub order: Option<SortOrder>,
+    pub cursor: Option<&'r str>,
+    pub limit: Option<usize>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, MATCHES_SEGMENT)?;
+    storage::ensure_index::<u64, PersyId>(persy, BY_FINISHED_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+// Store match together with games of it's players and their RNG audits. Games and audits
+// are given in players order. Returns ids of the leaderboard entries
+pub fn record_in_tx(
+    tx: &mut Transaction,
+    mut record: MatchRecord,
+    games: Vec<(LeaderboardEntry, Replay)>,
+    audits: Vec<RngAudit>,
+) -> Result<Vec<PersyId>, Error> {
+    let mut entries = Vec::new();
+    for ((player, (mut entry, replay)), audit) in record.players.iter_mut().zip(games).zip(audits) {
+        entry.rng_audit = Some(rng_audit::record_in_tx(tx, &audit)?.to_string());
+        let game = replays::record_game(tx, entry, &replay)?;
+        player.entry = Some(GameId(game.entry));
+        player.replay = game.replay.map(|replay| replay.to_string());
+        entries.push(game.entry);
+    }
+    for rating in record.players.iter().filter_map(|player| player.rating) {
+        ratings::store_in_tx(tx, &rating)?;
+    }
+    let id = storage::insert_in_tx(tx, MATCHES_SEGMENT, &record)?;
+    tx.put(BY_FINISHED_INDEX, record.finished, id)?;
+    Ok(entries)
+}
+
+pub fn read(persy: &Persy, id: &PersyId) -> Result<Option<MatchRecord>, Error> {
+    storage::read(persy, MATCHES_SEGMENT, id)
+}
+
+// Rewrite entry and replay references after records got new ids (see compaction)
+pub fn remap_ids(persy: &Persy, ids: &HashMap<PersyId, PersyId>) -> Result<(), Error> {
+    let remap = |id: &mut Option<String>| -> Result<bool, Error> {
+        let Some(old) = id else {
+            return Ok(false);
+        };
+        let Some(new) = ids.get(&storage::parse_id(old)?) else {
+            return Ok(false);
+        };
+        *id = Some(new.to_string());
+        Ok(true)
+    };
+    for (id, mut record) in storage::scan::<MatchRecord>(persy, MATCHES_SEGMENT)? {
+        let mut changed = false;
+        for player in &mut record.players {
+            if let Some(new) = player.entry.and_then(|entry| ids.get(&entry.0)) {
+                player.entry = Some(GameId(*new));
+                changed = true;
+            }
+            changed |= remap(&mut player.replay)?;
+        }
+        if changed {
+            storage::update(persy, MATCHES_SEGMENT, &id, &record)?;
+        }
+    }
+    Ok(())
+}
+
+// Drop references to deleted replays (see recording)
+pub fn forget_replays_in_tx(
+    tx: &mut Transaction,
+    persy: &Persy,
+    replays: &HashSet<String>,
+) -> Result<(), Error> {
+    if replays.is_empty() {
+        return Ok(());
+    }
+    for (id, mut record) in storage::scan::<MatchRecord>(persy, MATCHES_SEGMENT)? {
+        let mut changed = false;
+        for player in &mut record.players {
+            if player
+                .replay
+                .as_ref()
+                .is_some_and(|replay| replays.contains(replay))
+            {
+                player.replay = None;
+                changed = true;
+            }
+        }
+        if changed {
+            storage::update_in_tx(tx, MATCHES_SEGMENT, &id, &record)?;
+        }
+    }
+    Ok(())
+}
+
+// Page of full match records, of the players only when given
+pub fn records(
+    persy: &Persy,
+    query: &MatchesQuery,
+    players: Option<&HashSet<UserId>>,
+) -> Result<Page<MatchItem>, Error> {
+    pagination::page_by_index(
+        persy,
+        BY_FINISHED_INDEX,
+        MATCHES_SEGMENT,
+        query.order.unwrap_or(SortOrder::Desc),
+        (Bound::<u64>::Unbounded, Bound::Unbounded),
+        query.cursor,
+        pagination::limit(query.limit),
+        |record: &MatchRecord| {
+            query
+                .player
+                .is_none_or(|user| record.players.iter().any(|player| player.user == user))
+                && players.is_none_or(|players| {
+                    record
+                        .players
+                        .iter()
+                        .any(|player| players.contains(&player.user))
+                })
+        },
+        |id, record| MatchItem {
+            id: id.to_string(),
+            record,
+        },
+    )
+}
+
+pub fn list(
+    persy: &Persy,
+    query: &MatchesQuery,
+    players: Option<&HashSet<UserId>>,
+) -> Result<Page<MatchSummary>, Error> {
+    let page = records(persy, query, players)?;
+    Ok(Page {
+        items: page
+            .items
+            .into_iter()
+            .map(|item| MatchSummary {
+                started: item.record.started,
+                finished: item.record.finished,
+                ticks: item.record.ticks,
+                players: item
+                    .record
+                    .players
+                    .iter()
+                    .map(|player| player.user)
+                    .collect(),
+                scores: item
+                    .record
+                    .players
+                    .iter()
+                    .map(|player| player.score)
+                    .collect(),
+                id: item.id,
+            })
+            .collect(),
+        next_cursor: page.next_cursor,
+    })
+}
+
+// Completed matches, optionally of one player or of friends
+#[get("/matches?<query..>")]
+fn matches(
+    cookie_jar: &CookieJar,
+    db: &State<Database>,
+    friends: &State<Friends>,
+    query: MatchesQuery,
+) -> Result<Json<Page<MatchSummary>>, Error> {
+    let persy = &*db.read();
+    let friends = friends.filter(cookie_jar, query.friends);
+    Ok(Json(list(persy, &query, friends.as_ref())?))
+}
+
+// Match page with final boards and stats of both players
+pub fn page(persy: &Persy, id: &str) -> Result<Template, Error> {
+    let record = read(persy, &storage::parse_id(id)?)?
+        .ok_or_else(|| Error::NotFoundError("Match not found".to_string()))?;
+    let og_description = record
+        .players
+        .iter()
+        .map(|player| format!("Player {}: {} points", player.user, player.score))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let og_image = record
+        .players
+        .iter()
+        .max_by_key(|player| (!player.forfeited, player.score))
+        .and_then(|player| player.entry)
+        .map(board_image::image_url);
+    Ok(Template::render(
+        "match",
+        MatchPage {
+            item: MatchItem {
+                id: id.to_string(),
+                record,
+            },
+            og_description,
+            og_image,
+        },
+    ))
+}
+
+#[get("/match/<id>")]
+fn match_page(db: &State<Database>, id: &str) -> Result<Template, Error> {
+    page(&db.read(), id)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![matches, match_page]
+}
@@ -1,8 +1,13 @@
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 
+use serde::Serialize;
+
+use crate::ids::MatchId;
+
 //
-// Stores pairs of players and play fields for each pair
+// Stores pairs of players and play fields for each pair. Matches are pinned while they're
+// played, pinned match can't be evicted, only removed when it's over
 //
 pub struct Match<K: Eq, V> {
     pub player_a: K,
@@ -10,13 +15,23 @@ pub struct Match<K: Eq, V> {
     pub field: V,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize)]
 pub enum PlayerSide {
     A,
     B,
 }
 
+impl PlayerSide {
+    pub fn opponent(&self) -> PlayerSide {
+        match self {
+            PlayerSide::A => PlayerSide::B,
+            PlayerSide::B => PlayerSide::A,
+        }
+    }
+}
+
 impl<K: Eq, V> Match<K, V> {
+    #[allow(dead_code)]
     pub fn new(player_a: K, player_b: K, field: V) -> Match<K, V> {
         Match {
             player_a,
@@ -24,6 +39,12 @@ impl<K: Eq, V> Match<K, V> {
             field,
         }
     }
+    pub fn players(&self) -> [K; 2]
+    where
+        K: Copy,
+    {
+        [self.player_a, self.player_b]
+    }
     pub fn get_player(&self, side: PlayerSide) -> &K {
         match side {
             PlayerSide::A => &self.player_a,
@@ -64,7 +85,16 @@ impl<K: PartialEq + Eq + Hash> WaitList<K> for HashSet<K> {
     }
 }
 
-pub type MatchId = usize;
+// Matches pinned at once, further matches are played unpinned
+pub const MAX_PINNED: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PinStats {
+    pub pinned: usize,
+    pub limit: usize,
+    // Matches which were not pinned because of the limit
+    pub refused: u64,
+}
 
 pub struct Matches<K, V, WL = HashSet<K>>
 where
@@ -75,6 +105,10 @@ where
     wait_list: WL,
     match_ids: HashMap<K, MatchId>,
     matches: HashMap<MatchId, Match<K, V>>,
+    // Id for next created match. Ids are not reused after match removal
+    next_match_id: MatchId,
+    pinned: HashSet<MatchId>,
+    pin_refusals: u64,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -95,9 +129,28 @@ where
             wait_list: WL::default(),
             match_ids: HashMap::new(),
             matches: HashMap::new(),
+            next_match_id: MatchId::default(),
+            pinned: HashSet::new(),
+            pin_refusals: 0,
         }
     }
 
+    // Matches with given wait list, e.g. one that needs other state
+    pub fn with_wait_list(wait_list: WL) -> Matches<K, V, WL> {
+        Matches {
+            wait_list,
+            ..Self::new()
+        }
+    }
+
+    pub fn wait_list(&self) -> &WL {
+        &self.wait_list
+    }
+
+    pub fn wait_list_mut(&mut self) -> &mut WL {
+        &mut self.wait_list
+    }
+
     pub fn get_player_status(&self, player: &K) -> PlayerStatus {
         if self.match_ids.contains_key(player) {
             PlayerStatus::Match
@@ -108,23 +161,38 @@ where
         }
     }
 
+    #[allow(dead_code)]
     pub fn find_match(&mut self, player: &K) -> bool {
+        self.find_match_with(player, V::default)
+    }
+
+    // Same as find_match, new match field is created by given function
+    pub fn find_match_with(&mut self, player: &K, create: impl FnOnce() -> V) -> bool {
+        self.find_match_between(player, |_, _| create())
+    }
+
+    // Same as find_match_with, new match field is created for the matched players
+    pub fn find_match_between(&mut self, player: &K, create: impl FnOnce(&K, &K) -> V) -> bool {
         // Check if player is already in match
-        if let Some(_) = self.match_ids.get(player) {
+        if self.match_ids.contains_key(player) {
             true
-        } else if let Some(player_b) = self.wait_list.find_matching_pair(player) {
-            // Matching player found, create a new match
-            let match_id = self.matches.len();
+        } else if let Some(player_b) = self.wait_list.find_matching_pair(player).copied() {
+            // Matching player found, remove both players from wait list and create a new match
+            self.wait_list.remove(player);
+            self.wait_list.remove(&player_b);
+            let match_id = self.next_match_id;
+            self.next_match_id = match_id.next();
             self.matches.insert(
                 match_id,
                 Match {
                     player_a: *player,
-                    player_b: *player_b,
-                    field: V::default(),
+                    player_b,
+                    field: create(player, &player_b),
                 },
             );
             self.match_ids.insert(*player, match_id);
-            self.match_ids.insert(*player_b, match_id);
+            self.match_ids.insert(player_b, match_id);
+            self.pin(match_id);
             true
         } else {
             // Matching player not found, add to wait list
@@ -132,15 +200,78 @@ where
             false
         }
     }
+    // Add match of given players, e.g. restored one. Players must not be in other matches
+    pub fn insert_match(&mut self, player_a: K, player_b: K, field: V) -> MatchId {
+        self.wait_list.remove(&player_a);
+        self.wait_list.remove(&player_b);
+        let match_id = self.next_match_id;
+        self.next_match_id = match_id.next();
+        self.matches.insert(
+            match_id,
+            Match {
+                player_a,
+                player_b,
+                field,
+            },
+        );
+        self.match_ids.insert(player_a, match_id);
+        self.match_ids.insert(player_b, match_id);
+        self.pin(match_id);
+        match_id
+    }
+
     pub fn remove_match(&mut self, match_id: MatchId) {
         if let Some(match_) = self.matches.remove(&match_id) {
             self.match_ids.remove(&match_.player_a);
             self.match_ids.remove(&match_.player_b);
         }
+        self.pinned.remove(&match_id);
+    }
+    // Remove match unless it's pinned, returns false if match is pinned or there is no such match
+    pub fn evict_match(&mut self, match_id: MatchId) -> bool {
+        if self.pinned.contains(&match_id) || !self.matches.contains_key(&match_id) {
+            return false;
+        }
+        self.remove_match(match_id);
+        true
+    }
+    // Protect match from eviction, returns false if there is no such match or limit is reached
+    pub fn pin(&mut self, match_id: MatchId) -> bool {
+        if !self.matches.contains_key(&match_id) {
+            return false;
+        }
+        if self.pinned.len() >= MAX_PINNED && !self.pinned.contains(&match_id) {
+            self.pin_refusals += 1;
+            return false;
+        }
+        self.pinned.insert(match_id);
+        true
+    }
+    pub fn unpin(&mut self, match_id: MatchId) {
+        self.pinned.remove(&match_id);
+    }
+    pub fn is_pinned(&self, match_id: &MatchId) -> bool {
+        self.pinned.contains(match_id)
+    }
+    pub fn pin_stats(&self) -> PinStats {
+        PinStats {
+            pinned: self.pinned.len(),
+            limit: MAX_PINNED,
+            refused: self.pin_refusals,
+        }
     }
     pub fn get_match(&self, match_id: &MatchId) -> Option<&Match<K, V>> {
         self.matches.get(match_id)
     }
+    pub fn get_mut_match(&mut self, match_id: &MatchId) -> Option<&mut Match<K, V>> {
+        self.matches.get_mut(match_id)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (MatchId, &Match<K, V>)> {
+        self.matches.iter().map(|(match_id, m)| (*match_id, m))
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (MatchId, &mut Match<K, V>)> {
+        self.matches.iter_mut().map(|(match_id, m)| (*match_id, m))
+    }
     pub fn get_match_for_player(&self, player: &K) -> Option<(MatchId, &Match<K, V>)> {
         if let Some(match_id) = self.match_ids.get(player) {
             self.matches.get(match_id).map(|m| (*match_id, m))
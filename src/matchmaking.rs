@@ -0,0 +1,168 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::{ids::UserId, matches::WaitList, ratings::Ratings};
+
+//
+// Versus matchmaking queue. Players are matched in order of joining with the first waiting
+// player within their rating search window, which widens the longer they wait. Waiting
+// player keeps the place in queue across reconnects, players who don't poll the queue
+// for QUEUE_TIMEOUT are dropped. Waiting players get "queue" events with their position,
+// estimated wait and search window. Player leaving the queue with /match/cancel gets
+// "cancelled" event and the game stream ends
+//
+
+// Rating difference accepted right after joining, and it's growth per second of waiting
+const BASE_WINDOW: i32 = 100;
+const WINDOW_PER_SECOND: i32 = 25;
+// Waiting player is dropped when it's stream is gone for this long
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+// Weight of the last wait in average wait time
+const AVERAGE_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QueueStatus {
+    // Position in queue, from 1
+    pub position: usize,
+    pub waiting: usize,
+    pub waited_seconds: u64,
+    // Remaining wait by recent waits of matched players, None until the first match
+    pub estimated_wait_seconds: Option<u64>,
+    pub rating: i32,
+    // Accepted opponents' ratings
+    pub window: (i32, i32),
+}
+
+struct QueueEntry {
+    user: UserId,
+    joined: Instant,
+    last_seen: Instant,
+}
+
+pub struct MatchQueue {
+    entries: VecDeque<QueueEntry>,
+    ratings: Ratings,
+    // Average wait of matched players, seconds
+    average_wait: Option<f64>,
+    // Players who left the queue, they don't rejoin it until their new game stream
+    cancelled: HashSet<UserId>,
+}
+
+impl Default for MatchQueue {
+    fn default() -> Self {
+        MatchQueue::new(Ratings::default())
+    }
+}
+
+// Rating difference accepted after waiting for given time
+fn window(waited: Duration) -> i32 {
+    BASE_WINDOW + WINDOW_PER_SECOND * waited.as_secs() as i32
+}
+
+impl MatchQueue {
+    pub fn new(ratings: Ratings) -> MatchQueue {
+        MatchQueue {
+            entries: VecDeque::new(),
+            ratings,
+            average_wait: None,
+            cancelled: HashSet::new(),
+        }
+    }
+
+    fn position(&self, player: &UserId) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.user == *player)
+    }
+
+    pub fn status(&self, player: &UserId) -> Option<QueueStatus> {
+        let position = self.position(player)?;
+        let waited = self.entries[position].joined.elapsed();
+        let rating = self.ratings.get(*player).rating;
+        let window = window(waited);
+        Some(QueueStatus {
+            position: position + 1,
+            waiting: self.entries.len(),
+            waited_seconds: waited.as_secs(),
+            estimated_wait_seconds: self
+                .average_wait
+                .map(|average| (average - waited.as_secs_f64()).max(0.).round() as u64),
+            rating,
+            window: (rating - window, rating + window),
+        })
+    }
+
+    // Leave the queue without a match, returns false when player wasn't waiting
+    pub fn cancel(&mut self, player: &UserId) -> bool {
+        let Some(position) = self.position(player) else {
+            return false;
+        };
+        self.entries.remove(position);
+        self.cancelled.insert(*player);
+        true
+    }
+
+    pub fn is_cancelled(&self, player: &UserId) -> bool {
+        self.cancelled.contains(player)
+    }
+
+    // Allow player to join again
+    pub fn resume(&mut self, player: &UserId) {
+        self.cancelled.remove(player);
+    }
+}
+
+impl WaitList<UserId> for MatchQueue {
+    // Join the queue or refresh the place in it, players gone for QUEUE_TIMEOUT are dropped
+    fn add(&mut self, player: UserId) {
+        if self.cancelled.contains(&player) {
+            return;
+        }
+        let now = Instant::now();
+        self.entries
+            .retain(|entry| entry.user == player || now - entry.last_seen < QUEUE_TIMEOUT);
+        match self.position(&player) {
+            Some(position) => self.entries[position].last_seen = now,
+            None => self.entries.push_back(QueueEntry {
+                user: player,
+                joined: now,
+                last_seen: now,
+            }),
+        }
+    }
+
+    // Removed players are matched, their waits go to average wait
+    fn remove(&mut self, player: &UserId) {
+        let Some(position) = self.position(player) else {
+            return;
+        };
+        if let Some(entry) = self.entries.remove(position) {
+            let waited = entry.joined.elapsed().as_secs_f64();
+            self.average_wait = Some(match self.average_wait {
+                Some(average) => average + (waited - average) * AVERAGE_WEIGHT,
+                None => waited,
+            });
+        }
+    }
+
+    fn exists(&self, player: &UserId) -> bool {
+        self.position(player).is_some()
+    }
+
+    // Longest waiting player within search window of either player
+    fn find_matching_pair(&self, player: &UserId) -> Option<&UserId> {
+        let rating = self.ratings.get(*player).rating;
+        let own_window = self.position(player).map_or(BASE_WINDOW, |position| {
+            window(self.entries[position].joined.elapsed())
+        });
+        self.entries
+            .iter()
+            .filter(|entry| entry.user != *player)
+            .filter(|entry| Instant::now() - entry.last_seen < QUEUE_TIMEOUT)
+            .find(|entry| {
+                let difference = (self.ratings.get(entry.user).rating - rating).abs();
+                difference <= own_window.max(window(entry.joined.elapsed()))
+            })
+            .map(|entry| &entry.user)
+    }
+}
@@ -0,0 +1,293 @@
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use rocket::{get, http::ContentType, routes, Route, State};
+
+use crate::{
+    connections::Connections, dropped_games::DroppedGames, game_mode::GameMode,
+    leaderboard::LeaderboardEntry, roles::Viewer, scheduler::SchedulerMetrics,
+    send_queue::SendQueueMetrics, spotlight::Spotlight, tetris_pair::STEP_MS, write_queue::Write,
+    TetrisMatches,
+};
+
+//
+// Distributions of finished games in OpenMetrics text format at /admin/metrics, for
+// scraping by Prometheus and alike. Session duration and pieces per minute are observed
+// for games of all modes, attack per minute for versus games only, so operators can see
+// how player behavior shifts after balance changes. Games are observed when their results
+// are queued for writing. Histograms are of the whole server, arenas included, and start
+// empty on each restart. Active games dropped from memory are counted alongside, see
+// dropped_games, and so are open event stream connections and stale ones reaped, see
+// connections, frames dropped by spotlight viewer queues, see send_queue, and tick latency
+// and missed ticks of versus games, see scheduler
+//
+
+// Upper bounds of buckets
+const DURATION_BUCKETS: &[f64] = &[
+    10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+];
+const PIECES_BUCKETS: &[f64] = &[10.0, 20.0, 30.0, 45.0, 60.0, 90.0, 120.0, 180.0, 240.0];
+const ATTACK_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 15.0, 20.0, 30.0, 45.0, 60.0];
+
+const MODES: [GameMode; 2] = [GameMode::Versus, GameMode::Sprint];
+
+struct Histogram {
+    bounds: &'static [f64],
+    // Observations per bucket, not cumulative, the last one is above all bounds
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+struct ModeHistograms {
+    duration: Histogram,
+    pieces: Histogram,
+    attack: Histogram,
+}
+
+type Family = (
+    &'static str,
+    Option<&'static str>,
+    &'static str,
+    fn(&ModeHistograms) -> &Histogram,
+    &'static [GameMode],
+);
+
+#[derive(Clone)]
+pub struct GameMetrics(Arc<Mutex<Vec<ModeHistograms>>>);
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn write(&self, out: &mut String, name: &str, mode: &str) {
+        let mut cumulative = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let bound = match self.bounds.get(index) {
+                Some(bound) => format!("{:?}", bound),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{mode=\"{}\",le=\"{}\"}} {}",
+                name, mode, bound, cumulative
+            );
+        }
+        let _ = writeln!(out, "{}_sum{{mode=\"{}\"}} {:?}", name, mode, self.sum);
+        let _ = writeln!(out, "{}_count{{mode=\"{}\"}} {}", name, mode, cumulative);
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "{}_total {}", name, value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn mode_label(mode: GameMode) -> &'static str {
+    match mode {
+        GameMode::Versus => "versus",
+        GameMode::Sprint => "sprint",
+    }
+}
+
+impl GameMetrics {
+    pub fn new() -> GameMetrics {
+        GameMetrics(Arc::new(Mutex::new(
+            MODES
+                .iter()
+                .map(|_| ModeHistograms {
+                    duration: Histogram::new(DURATION_BUCKETS),
+                    pieces: Histogram::new(PIECES_BUCKETS),
+                    attack: Histogram::new(ATTACK_BUCKETS),
+                })
+                .collect(),
+        )))
+    }
+
+    // Observe game, attack is given for versus games. Games without steps are skipped
+    fn observe_game(&self, entry: &LeaderboardEntry, attack_sent: Option<u64>) {
+        if entry.ticks == 0 {
+            return;
+        }
+        let minutes = (entry.ticks * STEP_MS) as f64 / 60_000.0;
+        let index = MODES
+            .iter()
+            .position(|mode| *mode == entry.mode)
+            .unwrap_or(0);
+        let mut histograms = self.0.lock().unwrap();
+        let histograms = &mut histograms[index];
+        histograms.duration.observe(minutes * 60.0);
+        histograms.pieces.observe(entry.pieces as f64 / minutes);
+        if let Some(attack_sent) = attack_sent {
+            histograms.attack.observe(attack_sent as f64 / minutes);
+        }
+    }
+
+    // Observe finished games of the write
+    pub fn observe(&self, write: &Write) {
+        match write {
+            Write::Game { entry, .. } => self.observe_game(entry, None),
+            // Games are in order of the match players
+            Write::Match { record, games, .. } => {
+                for ((entry, _), player) in games.iter().zip(&record.players) {
+                    self.observe_game(entry, Some(player.attack_sent));
+                }
+            }
+        }
+    }
+
+    // Histograms, counters of dropped games, connections, spotlight queues and the tick
+    // scheduler in OpenMetrics text format
+    pub fn export(
+        &self,
+        dropped: u64,
+        connections: &Connections,
+        spotlight: &SendQueueMetrics,
+        scheduler: &SchedulerMetrics,
+    ) -> String {
+        let histograms = self.0.lock().unwrap();
+        let mut out = String::new();
+        // Name, unit, help, histogram and modes having it
+        let families: [Family; 3] = [
+            (
+                "gameserver_session_duration_seconds",
+                Some("seconds"),
+                "Duration of finished games.",
+                |histograms| &histograms.duration,
+                &MODES,
+            ),
+            (
+                "gameserver_pieces_per_minute",
+                None,
+                "Pieces placed per minute of finished games.",
+                |histograms| &histograms.pieces,
+                &MODES,
+            ),
+            (
+                "gameserver_attack_per_minute",
+                None,
+                "Garbage lines sent per minute of finished versus games.",
+                |histograms| &histograms.attack,
+                &[GameMode::Versus],
+            ),
+        ];
+        for (name, unit, help, histogram, modes) in families {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            if let Some(unit) = unit {
+                let _ = writeln!(out, "# UNIT {} {}", name, unit);
+            }
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            for (mode, mode_histograms) in MODES.iter().zip(histograms.iter()) {
+                if modes.contains(mode) {
+                    histogram(mode_histograms).write(&mut out, name, mode_label(*mode));
+                }
+            }
+        }
+        write_counter(
+            &mut out,
+            "gameserver_dropped_active_games",
+            "Active games evicted or removed from memory.",
+            dropped,
+        );
+        let (open, reaped) = connections.counts();
+        write_gauge(
+            &mut out,
+            "gameserver_open_connections",
+            "Open event stream connections.",
+            open,
+        );
+        write_counter(
+            &mut out,
+            "gameserver_reaped_connections",
+            "Stale event stream connections closed.",
+            reaped,
+        );
+        write_counter(
+            &mut out,
+            "gameserver_spotlight_dropped_frames",
+            "Spotlight frames dropped from queues of slow viewers.",
+            spotlight.dropped,
+        );
+        write_counter(
+            &mut out,
+            "gameserver_spotlight_resyncs",
+            "Spotlight viewers resynchronized from keyframe after overflow.",
+            spotlight.resyncs,
+        );
+        write_counter(
+            &mut out,
+            "gameserver_spotlight_disconnected_viewers",
+            "Lagging spotlight viewers disconnected.",
+            spotlight.disconnected,
+        );
+        let latencies = [
+            ("p50", scheduler.p50_latency_us),
+            ("p99", scheduler.p99_latency_us),
+            ("max", scheduler.max_latency_us),
+        ];
+        for (stat, latency) in latencies {
+            write_gauge(
+                &mut out,
+                &format!("gameserver_tick_latency_{}_seconds", stat),
+                &format!("Tick latency of the latest versus steps, {}.", stat),
+                latency as f64 / 1_000_000.0,
+            );
+        }
+        write_counter(
+            &mut out,
+            "gameserver_missed_ticks",
+            "Ticks skipped by workers busy with previous ones.",
+            scheduler.missed_ticks,
+        );
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+// Game distributions for scraping
+#[get("/admin/metrics")]
+fn metrics(
+    _admin: Viewer,
+    metrics: &State<GameMetrics>,
+    dropped: &State<DroppedGames>,
+    connections: &State<Connections>,
+    spotlight: &State<Spotlight>,
+    matches: &State<TetrisMatches>,
+) -> (ContentType, String) {
+    (
+        ContentType::new("application", "openmetrics-text")
+            .with_params([("version", "1.0.0"), ("charset", "utf-8")]),
+        metrics.export(
+            dropped.count(),
+            connections,
+            &spotlight.queue_metrics(),
+            &matches.scheduler.metrics(),
+        ),
+    )
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![metrics]
+}
@@ -0,0 +1,832 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    get,
+    http::CookieJar,
+    post, routes,
+    serde::json::{serde_json, Json},
+    FromFormField, Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admission::Admitted,
+    daily,
+    daily_quotas::{DailyQuota, QuotaKind},
+    error::Error,
+    game_rng::RngKind,
+    games::{GamePlugin, GameType, SessionStatus},
+    ids::UserId,
+    quarantine::{self, Quarantine, QuarantinedGame, QuarantinedMode},
+    stats,
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Minesweeper: player reveals cells of a board with hidden mines, revealed cell shows
+// the number of mines around it. Mines are placed on the first reveal, never on the
+// revealed cell or next to it, so the first reveal opens an area. Reveals are computed
+// here: cells without mines around are opened with their neighbours. Timer starts with
+// the first reveal. Daily board is the same for all players of a day, it's seed comes
+// from daily seeds and it starts with the same safe cell revealed. Won games are kept
+// as personal bests per difficulty. Daily board is played once per user and day: the
+// attempt and it's moves are stored in "minesweeper_daily_attempts", so an attempt in
+// progress is resumed by POST /minesweeper/daily, also after restarts, and a finished one
+// can't be started again. Game whose move panics is quarantined with it's seed and board
+//
+
+const RESULTS_SEGMENT: &str = "minesweeper_results";
+const BY_USER_INDEX: &str = "minesweeper_results_by_user";
+const ATTEMPTS_SEGMENT: &str = "minesweeper_daily_attempts";
+const ATTEMPTS_BY_KEY_INDEX: &str = "minesweeper_daily_attempts_by_key";
+
+const DAILY_GAME: &str = "minesweeper";
+const DAILY_DIFFICULTY: MinesweeperDifficulty = MinesweeperDifficulty::Intermediate;
+
+// Cells of the state: hidden, flagged, 0-8 mines around, mine
+const HIDDEN: i8 = -1;
+const FLAG: i8 = -2;
+const MINE: i8 = 9;
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    FromFormField,
+)]
+pub enum MinesweeperDifficulty {
+    // 9x9, 10 mines
+    #[default]
+    Beginner,
+    // 16x16, 40 mines
+    Intermediate,
+    // 16 rows, 30 columns, 99 mines
+    Expert,
+}
+
+impl MinesweeperDifficulty {
+    // Rows, columns and mines
+    fn board(&self) -> (usize, usize, usize) {
+        match self {
+            MinesweeperDifficulty::Beginner => (9, 9, 10),
+            MinesweeperDifficulty::Intermediate => (16, 16, 40),
+            MinesweeperDifficulty::Expert => (16, 30, 99),
+        }
+    }
+}
+
+pub struct Minesweeper {
+    difficulty: MinesweeperDifficulty,
+    // Day of the daily board
+    day: Option<u64>,
+    seed: u64,
+    rows: usize,
+    cols: usize,
+    mines_count: usize,
+    // Empty until the first reveal
+    mines: Vec<bool>,
+    revealed: Vec<bool>,
+    flagged: Vec<bool>,
+    // Milliseconds since unix epoch
+    started: Option<u64>,
+    finished: Option<u64>,
+    lost: bool,
+}
+
+#[derive(Serialize)]
+pub struct MinesweeperState {
+    pub difficulty: MinesweeperDifficulty,
+    pub day: Option<u64>,
+    // -1 is hidden cell, -2 flag, 0-8 number of mines around, 9 mine shown after a loss
+    pub cells: Vec<Vec<i8>>,
+    pub mines: usize,
+    pub flags: usize,
+    pub elapsed_ms: u64,
+    pub won: bool,
+    pub lost: bool,
+}
+
+// Won game
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MinesweeperResult {
+    pub user: UserId,
+    pub difficulty: MinesweeperDifficulty,
+    pub day: Option<u64>,
+    pub time_ms: u64,
+    // Seconds since unix epoch
+    pub finished: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptStatus {
+    NotStarted,
+    InProgress,
+    Finished,
+}
+
+// Player's change of the board
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Move {
+    Reveal { row: usize, col: usize },
+    Flag { row: usize, col: usize },
+}
+
+// Attempt of the daily board, the board is rebuilt from it's moves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DailyAttempt {
+    user: UserId,
+    day: u64,
+    status: AttemptStatus,
+    // Milliseconds since unix epoch
+    started: u64,
+    moves: Vec<Move>,
+    won: bool,
+}
+
+#[derive(Serialize)]
+pub struct DailyStatus {
+    pub day: u64,
+    pub status: AttemptStatus,
+    pub won: bool,
+}
+
+impl Minesweeper {
+    pub fn new(difficulty: MinesweeperDifficulty, seed: u64) -> Minesweeper {
+        let (rows, cols, mines_count) = difficulty.board();
+        Minesweeper {
+            difficulty,
+            day: None,
+            seed,
+            rows,
+            cols,
+            mines_count,
+            mines: Vec::new(),
+            revealed: vec![false; rows * cols],
+            flagged: vec![false; rows * cols],
+            started: None,
+            finished: None,
+            lost: false,
+        }
+    }
+
+    // Game with random seed. Seed is logged, so the game can be audited
+    pub fn random(difficulty: MinesweeperDifficulty) -> Minesweeper {
+        let seed = rand::random();
+        println!("New minesweeper game: seed {}, {:?}", seed, difficulty);
+        Minesweeper::new(difficulty, seed)
+    }
+
+    // Daily board of the day, starts with a safe cell picked by the seed revealed
+    pub fn daily(day: u64) -> Minesweeper {
+        let seed = daily::seed(DAILY_GAME, day);
+        let mut game = Minesweeper::new(DAILY_DIFFICULTY, seed);
+        game.day = Some(day);
+        let start =
+            RngKind::Std.seeded(seed.rotate_left(32)).next_u32() as usize % (game.rows * game.cols);
+        game.reveal(start / game.cols, start % game.cols)
+            .expect("Start cell is on the board");
+        game
+    }
+
+    // Daily board of the attempt with it's moves made, timer runs from the attempt's start
+    fn resume(attempt: &DailyAttempt) -> Minesweeper {
+        let mut game = Minesweeper::daily(attempt.day);
+        for next in &attempt.moves {
+            if game.apply(*next).is_err() {
+                break;
+            }
+        }
+        game.started = Some(attempt.started);
+        game
+    }
+
+    fn apply(&mut self, next: Move) -> Result<(), String> {
+        match next {
+            Move::Reveal { row, col } => self.reveal(row, col),
+            Move::Flag { row, col } => self.toggle_flag(row, col),
+        }
+    }
+
+    fn neighbours(&self, cell: usize) -> impl Iterator<Item = usize> + '_ {
+        let (row, col) = ((cell / self.cols) as isize, (cell % self.cols) as isize);
+        (-1..=1)
+            .flat_map(move |dr| (-1..=1).map(move |dc| (row + dr, col + dc)))
+            .filter(move |(r, c)| {
+                (*r, *c) != (row, col)
+                    && *r >= 0
+                    && *c >= 0
+                    && *r < self.rows as isize
+                    && *c < self.cols as isize
+            })
+            .map(|(r, c)| r as usize * self.cols + c as usize)
+    }
+
+    // Place mines from the seed, away from the first revealed cell
+    fn place_mines(&mut self, first: usize) {
+        let mut safe = vec![first];
+        safe.extend(self.neighbours(first));
+        let mut candidates = (0..self.rows * self.cols)
+            .filter(|cell| !safe.contains(cell))
+            .collect::<Vec<_>>();
+        let mut rng = RngKind::Std.seeded(self.seed);
+        self.mines = vec![false; self.rows * self.cols];
+        for i in 0..self.mines_count.min(candidates.len()) {
+            let pick = i + rng.next_u32() as usize % (candidates.len() - i);
+            candidates.swap(i, pick);
+            self.mines[candidates[i]] = true;
+        }
+    }
+
+    fn mines_around(&self, cell: usize) -> usize {
+        self.neighbours(cell).filter(|n| self.mines[*n]).count()
+    }
+
+    fn is_over(&self) -> bool {
+        self.finished.is_some()
+    }
+
+    fn is_won(&self) -> bool {
+        self.is_over() && !self.lost
+    }
+
+    fn index(&self, row: usize, col: usize) -> Result<usize, String> {
+        if row >= self.rows || col >= self.cols {
+            return Err(format!("Cell {}, {} is out of the board", row, col));
+        }
+        Ok(row * self.cols + col)
+    }
+
+    // Reveal the cell, cells without mines around open their neighbours too
+    pub fn reveal(&mut self, row: usize, col: usize) -> Result<(), String> {
+        let cell = self.index(row, col)?;
+        if self.is_over() {
+            return Err("Game is over".to_string());
+        }
+        if self.flagged[cell] || self.revealed[cell] {
+            return Ok(());
+        }
+        if self.mines.is_empty() {
+            self.place_mines(cell);
+            self.started = Some(crate::unix_time_ms());
+        }
+        if self.mines[cell] {
+            self.lost = true;
+            self.finished = Some(crate::unix_time_ms());
+            return Ok(());
+        }
+        let mut open = vec![cell];
+        while let Some(cell) = open.pop() {
+            if self.revealed[cell] || self.flagged[cell] {
+                continue;
+            }
+            self.revealed[cell] = true;
+            if self.mines_around(cell) == 0 {
+                open.extend(self.neighbours(cell).filter(|n| !self.revealed[*n]));
+            }
+        }
+        let revealed = self.revealed.iter().filter(|r| **r).count();
+        if revealed == self.rows * self.cols - self.mines_count {
+            self.finished = Some(crate::unix_time_ms());
+        }
+        Ok(())
+    }
+
+    // Put or remove flag of a hidden cell
+    pub fn toggle_flag(&mut self, row: usize, col: usize) -> Result<(), String> {
+        let cell = self.index(row, col)?;
+        if self.is_over() {
+            return Err("Game is over".to_string());
+        }
+        if !self.revealed[cell] {
+            self.flagged[cell] = !self.flagged[cell];
+        }
+        Ok(())
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        match self.started {
+            Some(started) => self
+                .finished
+                .unwrap_or_else(crate::unix_time_ms)
+                .saturating_sub(started),
+            None => 0,
+        }
+    }
+
+    pub fn state(&self) -> MinesweeperState {
+        let cell_view = |cell: usize| {
+            if self.revealed[cell] {
+                self.mines_around(cell) as i8
+            } else if self.lost && self.mines[cell] {
+                MINE
+            } else if self.flagged[cell] {
+                FLAG
+            } else {
+                HIDDEN
+            }
+        };
+        MinesweeperState {
+            difficulty: self.difficulty,
+            day: self.day,
+            cells: (0..self.rows)
+                .map(|row| {
+                    (0..self.cols)
+                        .map(|col| cell_view(row * self.cols + col))
+                        .collect()
+                })
+                .collect(),
+            mines: self.mines_count,
+            flags: self.flagged.iter().filter(|f| **f).count(),
+            elapsed_ms: self.elapsed_ms(),
+            won: self.is_won(),
+            lost: self.lost,
+        }
+    }
+
+    fn result(&self, user: UserId) -> Option<MinesweeperResult> {
+        if !self.is_won() {
+            return None;
+        }
+        Some(MinesweeperResult {
+            user,
+            difficulty: self.difficulty,
+            day: self.day,
+            time_ms: self.elapsed_ms(),
+            finished: crate::unix_time(),
+        })
+    }
+}
+
+// Running games by user and quarantine of games which panicked
+#[derive(Clone, Default)]
+pub struct MinesweeperGames(Arc<RwLock<HashMap<UserId, Minesweeper>>>, Quarantine);
+
+impl MinesweeperGames {
+    pub fn new(quarantine: Quarantine) -> MinesweeperGames {
+        MinesweeperGames(Arc::default(), quarantine)
+    }
+
+    pub fn is_running(&self, user_id: UserId) -> bool {
+        self.0.read().unwrap().contains_key(&user_id)
+    }
+
+    // State of user's running game when it's the daily board of the day
+    fn daily_state(&self, user_id: UserId, day: u64) -> Option<MinesweeperState> {
+        let games = self.0.read().unwrap();
+        let game = games.get(&user_id).filter(|game| game.day == Some(day))?;
+        Some(game.state())
+    }
+
+    // Apply move to user's game, won game is removed and it's result returned
+    fn play(
+        &self,
+        user_id: UserId,
+        next: Move,
+    ) -> Result<(MinesweeperState, Option<MinesweeperResult>), Error> {
+        let mut games = self.0.write().unwrap();
+        let game = games
+            .get_mut(&user_id)
+            .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+        let played = quarantine::guard(|| {
+            game.apply(next)
+                .map(|_| (game.state(), game.result(user_id), game.is_over()))
+        });
+        let (state, result, over) = match played {
+            Ok(played) => played.map_err(Error::InvalidInputError)?,
+            Err(panic) => {
+                self.quarantine(&mut games, user_id, panic, next);
+                return Err(Error::NotFoundError("Game was quarantined".to_string()));
+            }
+        };
+        if over {
+            games.remove(&user_id);
+        }
+        Ok((state, result))
+    }
+
+    // Remove game which panicked, keeping it's seed and board and the failing move
+    fn quarantine(
+        &self,
+        games: &mut HashMap<UserId, Minesweeper>,
+        user_id: UserId,
+        panic: String,
+        next: Move,
+    ) {
+        let Some(game) = games.remove(&user_id) else {
+            return;
+        };
+        let started = game
+            .started
+            .map_or_else(crate::unix_time, |started| started / 1000);
+        self.1.add_answered(QuarantinedGame::without_replays(
+            QuarantinedMode::Minesweeper,
+            vec![user_id],
+            started,
+            || {
+                Some(serde_json::json!({
+                    "seed": game.seed,
+                    "mines": game.mines,
+                    "state": game.state(),
+                }))
+            },
+            Some(format!("{:?}", next)),
+            panic,
+        ));
+    }
+
+    fn start(&self, user_id: UserId, game: Minesweeper) -> MinesweeperState {
+        let state = game.state();
+        self.0.write().unwrap().insert(user_id, game);
+        state
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, RESULTS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Cluster)?;
+    storage::ensure_segment(persy, ATTEMPTS_SEGMENT)?;
+    storage::ensure_index::<String, PersyId>(persy, ATTEMPTS_BY_KEY_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+fn attempt_key(user: UserId, day: u64) -> String {
+    format!("{}/{}", user, day)
+}
+
+fn find_attempt(
+    persy: &Persy,
+    user: UserId,
+    day: u64,
+) -> Result<Option<(PersyId, DailyAttempt)>, Error> {
+    let Some(id) = persy.one::<String, PersyId>(ATTEMPTS_BY_KEY_INDEX, &attempt_key(user, day))?
+    else {
+        return Ok(None);
+    };
+    Ok(storage::read::<DailyAttempt>(persy, ATTEMPTS_SEGMENT, &id)?.map(|attempt| (id, attempt)))
+}
+
+// Add move to user's attempt of the daily board, it's finished with the game
+fn record_move(
+    persy: &Persy,
+    user: UserId,
+    day: u64,
+    next: Move,
+    state: &MinesweeperState,
+) -> Result<(), Error> {
+    let Some((id, mut attempt)) = find_attempt(persy, user, day)? else {
+        return Ok(());
+    };
+    attempt.moves.push(next);
+    if state.won || state.lost {
+        attempt.status = AttemptStatus::Finished;
+        attempt.won = state.won;
+    }
+    storage::update(persy, ATTEMPTS_SEGMENT, &id, &attempt)
+}
+
+fn record(persy: &Persy, result: &MinesweeperResult) -> Result<PersyId, Error> {
+    storage::insert_with(persy, RESULTS_SEGMENT, result, |tx, id| {
+        tx.put(BY_USER_INDEX, result.user.0, *id)?;
+        Ok(())
+    })
+}
+
+// Fastest won game of the user for each difficulty
+pub fn personal_bests(
+    persy: &Persy,
+    user: UserId,
+) -> Result<BTreeMap<MinesweeperDifficulty, MinesweeperResult>, Error> {
+    let mut bests = BTreeMap::<MinesweeperDifficulty, MinesweeperResult>::new();
+    for id in persy.get::<u32, PersyId>(BY_USER_INDEX, &user.0)? {
+        let Some(result) = storage::read::<MinesweeperResult>(persy, RESULTS_SEGMENT, &id)? else {
+            continue;
+        };
+        if bests
+            .get(&result.difficulty)
+            .is_none_or(|best| result.time_ms < best.time_ms)
+        {
+            bests.insert(result.difficulty, result);
+        }
+    }
+    Ok(bests)
+}
+
+// Store won game and moves of daily board, rest of the games don't touch the database
+fn respond(
+    db: &Database,
+    user_id: UserId,
+    next: Move,
+    (state, result): (MinesweeperState, Option<MinesweeperResult>),
+) -> Result<Json<MinesweeperState>, Error> {
+    let persy = &*db.read();
+    if let Some(day) = state.day {
+        record_move(persy, user_id, day, next, &state)?;
+    }
+    if let Some(result) = result {
+        record(persy, &result)?;
+    }
+    Ok(Json(state))
+}
+
+// Start new game, user's running game is dropped. Difficulty defaults to beginner. Games
+// count to the daily quota
+#[post("/minesweeper/new?<difficulty>")]
+fn new_game(
+    _admitted: Admitted,
+    quota: DailyQuota,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<MinesweeperGames>,
+    db: &State<Database>,
+    difficulty: Option<MinesweeperDifficulty>,
+) -> Result<Json<MinesweeperState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    quota.consume(&db.read(), user_id, QuotaKind::Games)?;
+    let game = Minesweeper::random(difficulty.unwrap_or_default());
+    Ok(Json(games.start(user_id, game)))
+}
+
+// Start today's daily board or resume the attempt in progress. Finished attempt
+// can't be restarted
+#[post("/minesweeper/daily")]
+fn daily_game(
+    _admitted: Admitted,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<MinesweeperGames>,
+    db: &State<Database>,
+) -> Result<Json<MinesweeperState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let day = stats::today();
+    let persy = &*db.read();
+    match find_attempt(persy, user_id, day)? {
+        Some((_, attempt)) if attempt.status == AttemptStatus::Finished => {
+            Err(Error::InvalidInputError(
+                "Daily board is played once a day, the next one starts at midnight UTC".to_string(),
+            ))
+        }
+        Some((_, attempt)) => Ok(Json(match games.daily_state(user_id, day) {
+            Some(state) => state,
+            None => games.start(user_id, Minesweeper::resume(&attempt)),
+        })),
+        None => {
+            let game = Minesweeper::daily(day);
+            let attempt = DailyAttempt {
+                user: user_id,
+                day,
+                status: AttemptStatus::InProgress,
+                started: game.started.unwrap_or_else(crate::unix_time_ms),
+                moves: Vec::new(),
+                won: false,
+            };
+            storage::insert_with(persy, ATTEMPTS_SEGMENT, &attempt, |tx, id| {
+                tx.put(ATTEMPTS_BY_KEY_INDEX, attempt_key(user_id, day), *id)?;
+                Ok(())
+            })?;
+            Ok(Json(games.start(user_id, game)))
+        }
+    }
+}
+
+// Whether user has played today's daily board
+#[get("/minesweeper/daily/status")]
+fn daily_status(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<DailyStatus>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let day = stats::today();
+    let attempt = find_attempt(&db.read(), user_id, day)?.map(|(_, attempt)| attempt);
+    Ok(Json(DailyStatus {
+        day,
+        status: attempt
+            .as_ref()
+            .map_or(AttemptStatus::NotStarted, |attempt| attempt.status),
+        won: attempt.is_some_and(|attempt| attempt.won),
+    }))
+}
+
+// State of user's running game
+#[get("/minesweeper/state")]
+fn game_state(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<MinesweeperGames>,
+) -> Result<Json<MinesweeperState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let games = games.0.read().unwrap();
+    let game = games
+        .get(&user_id)
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    Ok(Json(game.state()))
+}
+
+// Reveal cell, game ends when it's a mine or the last safe cell
+#[post("/minesweeper/reveal/<row>/<col>")]
+fn reveal(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<MinesweeperGames>,
+    db: &State<Database>,
+    row: usize,
+    col: usize,
+) -> Result<Json<MinesweeperState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let next = Move::Reveal { row, col };
+    respond(db, user_id, next, games.play(user_id, next)?)
+}
+
+// Put or remove flag
+#[post("/minesweeper/flag/<row>/<col>")]
+fn flag(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    games: &State<MinesweeperGames>,
+    db: &State<Database>,
+    row: usize,
+    col: usize,
+) -> Result<Json<MinesweeperState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let next = Move::Flag { row, col };
+    respond(db, user_id, next, games.play(user_id, next)?)
+}
+
+// User's fastest games by difficulty
+#[get("/minesweeper/bests")]
+fn bests(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<BTreeMap<MinesweeperDifficulty, MinesweeperResult>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(personal_bests(&db.read(), user_id)?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        new_game,
+        daily_game,
+        daily_status,
+        game_state,
+        reveal,
+        flag,
+        bests
+    ]
+}
+
+pub fn plugin() -> GamePlugin {
+    GamePlugin {
+        game_type: GameType {
+            id: "minesweeper",
+            name: "Minesweeper",
+            description: "Clear the board without hitting a mine, new daily board every day",
+            entry: "/minesweeper/new",
+        },
+        init,
+        routes,
+        attach: |rocket| {
+            let quarantine = rocket.state::<Quarantine>().cloned().unwrap_or_default();
+            Ok(rocket.manage(MinesweeperGames::new(quarantine)))
+        },
+        session: |rocket, user_id| {
+            let games = rocket.state::<MinesweeperGames>()?;
+            SessionStatus::of(false, games.is_running(user_id))
+        },
+        state: |rocket, user_id| {
+            let games = rocket.state::<MinesweeperGames>()?.0.read().unwrap();
+            Some(serde_json::to_string(&games.get(&user_id)?.state()).unwrap())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFFICULTIES: [MinesweeperDifficulty; 3] = [
+        MinesweeperDifficulty::Beginner,
+        MinesweeperDifficulty::Intermediate,
+        MinesweeperDifficulty::Expert,
+    ];
+
+    // Corners, edge and center cells of the board
+    fn first_cells(game: &Minesweeper) -> Vec<(usize, usize)> {
+        let (last_row, last_col) = (game.rows - 1, game.cols - 1);
+        vec![
+            (0, 0),
+            (last_row, last_col),
+            (0, last_col / 2),
+            (last_row / 2, last_col / 2),
+        ]
+    }
+
+    #[test]
+    fn first_reveal_is_safe() {
+        for difficulty in DIFFICULTIES {
+            for seed in 0..20 {
+                for (row, col) in first_cells(&Minesweeper::new(difficulty, seed)) {
+                    let mut game = Minesweeper::new(difficulty, seed);
+                    game.reveal(row, col).unwrap();
+                    let first = game.index(row, col).unwrap();
+                    assert!(!game.lost);
+                    assert!(!game.mines[first]);
+                    assert!(game.neighbours(first).all(|cell| !game.mines[cell]));
+                    assert_eq!(
+                        game.mines.iter().filter(|mine| **mine).count(),
+                        game.mines_count
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mines_depend_on_seed_only() {
+        let mines = |seed| {
+            let mut game = Minesweeper::new(MinesweeperDifficulty::Intermediate, seed);
+            game.reveal(3, 4).unwrap();
+            game.mines
+        };
+        assert_eq!(mines(7), mines(7));
+        assert_ne!(mines(7), mines(8));
+    }
+
+    #[test]
+    fn reveal_opens_cells_without_mines_around_with_neighbours() {
+        for seed in 0..20 {
+            let mut game = Minesweeper::new(MinesweeperDifficulty::Intermediate, seed);
+            game.reveal(8, 8).unwrap();
+            let first = game.index(8, 8).unwrap();
+            // First cell has no mines around, so it opens an area
+            assert!(game.neighbours(first).all(|cell| game.revealed[cell]));
+            for cell in 0..game.rows * game.cols {
+                if !game.revealed[cell] {
+                    continue;
+                }
+                assert!(!game.mines[cell]);
+                if game.mines_around(cell) == 0 {
+                    assert!(game.neighbours(cell).all(|n| game.revealed[n]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flags_stop_reveals() {
+        let mut game = Minesweeper::new(MinesweeperDifficulty::Beginner, 1);
+        game.toggle_flag(0, 0).unwrap();
+        game.reveal(0, 0).unwrap();
+        assert!(game.mines.is_empty());
+        game.toggle_flag(0, 0).unwrap();
+        game.reveal(0, 0).unwrap();
+        assert!(game.revealed[0]);
+        // Revealed cells can't be flagged
+        game.toggle_flag(0, 0).unwrap();
+        assert!(!game.flagged[0]);
+        assert!(game.reveal(9, 0).is_err());
+    }
+
+    #[test]
+    fn revealing_all_safe_cells_wins() {
+        for difficulty in DIFFICULTIES {
+            for seed in 0..5 {
+                let mut game = Minesweeper::new(difficulty, seed);
+                game.reveal(0, 0).unwrap();
+                for cell in 0..game.rows * game.cols {
+                    if !game.mines[cell] && !game.revealed[cell] {
+                        assert!(!game.is_over());
+                        game.reveal(cell / game.cols, cell % game.cols).unwrap();
+                    }
+                }
+                let state = game.state();
+                assert!(state.won && !state.lost);
+                assert!(game.result(UserId(1)).is_some());
+                assert!(game.reveal(0, 0).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn revealing_mine_loses() {
+        let mut game = Minesweeper::new(MinesweeperDifficulty::Beginner, 3);
+        game.reveal(4, 4).unwrap();
+        let mine = game.mines.iter().position(|mine| *mine).unwrap();
+        game.reveal(mine / game.cols, mine % game.cols).unwrap();
+        let state = game.state();
+        assert!(state.lost && !state.won);
+        assert_eq!(state.cells[mine / game.cols][mine % game.cols], MINE);
+        assert!(game.result(UserId(1)).is_none());
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{form::Form, get, post, response::Redirect, routes, Config, FromForm, Route, State};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    roles::{Moderator, Viewer},
+    storage::{self, Database},
+};
+
+//
+// Moderation of texts players make public, e.g. puzzle titles. Texts are checked against
+// wordlists of locales, managed by admin and stored in the database. Listed words match
+// whole words of the text, so ordinary words containing them pass. Text and words are
+// normalized before matching, so look-alike digits and symbols, separators between letters
+// and letters spelled apart don't get a word past the filter. Letters repeated in the text
+// match the same letter of the word, but not fewer times than the word has it, so "heeelll"
+// matches "hell" while "as" doesn't match "ass". Locales to check are set with
+// moderation_locales config key, all stored wordlists are checked by default
+//
+
+const WORDLISTS_SEGMENT: &str = "wordlists";
+const BY_LOCALE_INDEX: &str = "wordlists_by_locale";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Wordlist {
+    pub locale: String,
+    pub words: Vec<String>,
+    // Seconds since unix epoch
+    pub updated: u64,
+}
+
+#[derive(FromForm)]
+pub struct WordlistForm {
+    locale: String,
+    // Words separated by whitespace or commas
+    words: String,
+}
+
+#[derive(Serialize)]
+pub struct WordlistItem {
+    pub locale: String,
+    pub words: usize,
+    pub updated: u64,
+    // Checked by the server's locale configuration
+    pub active: bool,
+}
+
+// Letters of normalized word with their repeat counts
+type Runs = Vec<(char, usize)>;
+
+// Normalized words by locale
+#[derive(Clone, Default)]
+pub struct Moderation {
+    wordlists: Arc<RwLock<BTreeMap<String, Vec<Runs>>>>,
+    // Locales checked by the configuration, None for all
+    locales: Option<Vec<String>>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, WORDLISTS_SEGMENT)?;
+    storage::ensure_index::<String, PersyId>(persy, BY_LOCALE_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+// Letters of the word without separators, with look-alike characters replaced. Punctuation
+// around the word, e.g. "!" ending a sentence, is not a letter
+fn normalize(word: &str) -> String {
+    word.trim_matches(|c: char| !c.is_alphanumeric() && c != '@' && c != '$')
+        .chars()
+        .flat_map(char::to_lowercase)
+        .filter_map(|c| match c {
+            '0' => Some('o'),
+            '1' | '!' | '|' => Some('i'),
+            '3' => Some('e'),
+            '4' | '@' => Some('a'),
+            '5' | '$' => Some('s'),
+            '7' => Some('t'),
+            '8' => Some('b'),
+            '9' => Some('g'),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+fn runs(word: &str) -> Runs {
+    let mut runs: Runs = Vec::new();
+    for c in word.chars() {
+        match runs.last_mut() {
+            Some((last, count)) if *last == c => *count += 1,
+            _ => runs.push((c, 1)),
+        }
+    }
+    runs
+}
+
+// Normalized words of the text, split by whitespace. Letters spelled apart, e.g. "f o o",
+// are joined into a word too
+fn words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut letters = String::new();
+    for word in text.split_whitespace().map(normalize) {
+        if word.chars().count() == 1 {
+            letters.push_str(&word);
+            continue;
+        }
+        if letters.chars().count() > 1 {
+            words.push(std::mem::take(&mut letters));
+        }
+        letters.clear();
+        if !word.is_empty() {
+            words.push(word);
+        }
+    }
+    if letters.chars().count() > 1 {
+        words.push(letters);
+    }
+    words
+}
+
+// Word of the text is the listed word with some of it's letters repeated more
+fn matches(word: &Runs, listed: &Runs) -> bool {
+    word.len() == listed.len()
+        && word
+            .iter()
+            .zip(listed)
+            .all(|((c, count), (listed, min))| c == listed && count >= min)
+}
+
+// Locales checked by the configuration, None for all
+fn configured_locales() -> Option<Vec<String>> {
+    Config::figment()
+        .extract_inner::<Vec<String>>("moderation_locales")
+        .ok()
+}
+
+fn valid_locale(locale: &str) -> bool {
+    !locale.is_empty()
+        && locale.len() <= 16
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Moderation {
+    pub fn load(persy: &Persy) -> Result<Moderation, Error> {
+        let moderation = Moderation {
+            locales: configured_locales(),
+            ..Moderation::default()
+        };
+        for (_, wordlist) in storage::scan::<Wordlist>(persy, WORDLISTS_SEGMENT)? {
+            moderation.set(&wordlist.locale, &wordlist.words);
+        }
+        Ok(moderation)
+    }
+
+    fn set(&self, locale: &str, words: &[String]) {
+        let words = words
+            .iter()
+            .map(|word| normalize(word))
+            .filter(|word| !word.is_empty())
+            .map(|word| runs(&word))
+            .collect();
+        self.wordlists
+            .write()
+            .unwrap()
+            .insert(locale.to_string(), words);
+    }
+
+    fn is_active(&self, locale: &str) -> bool {
+        self.locales
+            .as_ref()
+            .is_none_or(|locales| locales.iter().any(|active| active == locale))
+    }
+
+    // Word of the text found in a checked wordlist
+    pub fn find(&self, text: &str) -> Option<String> {
+        let words = words(text);
+        let wordlists = self.wordlists.read().unwrap();
+        let listed = wordlists
+            .iter()
+            .filter(|(locale, _)| self.is_active(locale))
+            .flat_map(|(_, words)| words)
+            .collect::<Vec<_>>();
+        words
+            .into_iter()
+            .find(|word| listed.iter().any(|listed| matches(&runs(word), listed)))
+    }
+
+    // Refuse text with a listed word. The word is not reported back
+    pub fn check(&self, text: &str) -> Result<(), Error> {
+        match self.find(text) {
+            Some(_) => Err(Error::InvalidInputError(
+                "Text contains words which are not allowed".to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn read_wordlist(persy: &Persy, locale: &str) -> Result<Option<(PersyId, Wordlist)>, Error> {
+    let Some(id) = persy.one::<String, PersyId>(BY_LOCALE_INDEX, &locale.to_string())? else {
+        return Ok(None);
+    };
+    Ok(storage::read(persy, WORDLISTS_SEGMENT, &id)?.map(|wordlist| (id, wordlist)))
+}
+
+// Stored wordlists
+#[get("/admin/wordlists")]
+fn admin_wordlists(
+    _admin: Viewer,
+    db: &State<Database>,
+    moderation: &State<Moderation>,
+) -> Result<Template, Error> {
+    let items: Vec<WordlistItem> = storage::scan::<Wordlist>(&db.read(), WORDLISTS_SEGMENT)?
+        .into_iter()
+        .map(|(_, wordlist)| WordlistItem {
+            active: moderation.is_active(&wordlist.locale),
+            words: wordlist.words.len(),
+            updated: wordlist.updated,
+            locale: wordlist.locale,
+        })
+        .collect();
+    Ok(Template::render(
+        "admin/wordlists",
+        context! { wordlists: items, locales: &moderation.locales },
+    ))
+}
+
+// Replace wordlist of the locale
+#[post("/admin/wordlists", data = "<form>")]
+fn set_wordlist(
+    _admin: Moderator,
+    db: &State<Database>,
+    moderation: &State<Moderation>,
+    form: Form<WordlistForm>,
+) -> Result<Redirect, Error> {
+    let form = form.into_inner();
+    let locale = form.locale.trim().to_lowercase();
+    if !valid_locale(&locale) {
+        return Err(Error::InvalidInputError(format!(
+            "Invalid locale {}",
+            locale
+        )));
+    }
+    let mut words = form
+        .words
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    words.sort();
+    words.dedup();
+    let wordlist = Wordlist {
+        locale: locale.clone(),
+        words,
+        updated: crate::unix_time(),
+    };
+    let persy = &*db.read();
+    match read_wordlist(persy, &locale)? {
+        Some((id, _)) => storage::update(persy, WORDLISTS_SEGMENT, &id, &wordlist)?,
+        None => {
+            storage::insert_with(persy, WORDLISTS_SEGMENT, &wordlist, |tx, id| {
+                tx.put(BY_LOCALE_INDEX, locale.clone(), *id)?;
+                Ok(())
+            })?;
+        }
+    }
+    moderation.set(&locale, &wordlist.words);
+    println!("Wordlist {} set, {} words", locale, wordlist.words.len());
+    Ok(Redirect::to("/admin/wordlists"))
+}
+
+#[post("/admin/wordlists/<locale>/delete")]
+fn delete_wordlist(
+    _admin: Moderator,
+    db: &State<Database>,
+    moderation: &State<Moderation>,
+    locale: &str,
+) -> Result<Redirect, Error> {
+    let persy = &*db.read();
+    let (id, _) = read_wordlist(persy, locale)?
+        .ok_or_else(|| Error::NotFoundError(format!("Wordlist {} not found", locale)))?;
+    let mut tx = persy.begin()?;
+    tx.delete(WORDLISTS_SEGMENT, &id)?;
+    tx.remove::<String, PersyId>(BY_LOCALE_INDEX, locale.to_string(), None)?;
+    tx.prepare()?.commit()?;
+    moderation.wordlists.write().unwrap().remove(locale);
+    Ok(Redirect::to("/admin/wordlists"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_wordlists, set_wordlist, delete_wordlist]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moderation(words: &[&str]) -> Moderation {
+        let moderation = Moderation::default();
+        let words = words
+            .iter()
+            .map(|word| word.to_string())
+            .collect::<Vec<_>>();
+        moderation.set("en", &words);
+        moderation
+    }
+
+    #[test]
+    fn evasions_are_caught() {
+        let moderation = moderation(&["ass", "hell", "fool"]);
+        for text in [
+            "you ass",
+            "A$$!",
+            "what the hell!",
+            "a.s.s",
+            "a s s hat",
+            "HEEELLLL yes",
+            "h3ll",
+            "f00l",
+            "fooooool",
+            "f-o-o-l",
+        ] {
+            assert!(moderation.find(text).is_some(), "{} passed", text);
+        }
+    }
+
+    #[test]
+    fn ordinary_words_pass() {
+        let moderation = moderation(&["ass", "hell", "fool"]);
+        for text in [
+            "It was a class act",
+            "pass the puzzle",
+            "hello shell",
+            "as easy as pie",
+            "hel",
+            "foolish assessment",
+            "a b c",
+        ] {
+            assert_eq!(moderation.find(text), None, "{} refused", text);
+        }
+        assert!(moderation.check("Hello world").is_ok());
+        assert!(moderation.check("Go to hell").is_err());
+    }
+
+    #[test]
+    fn inactive_locales_are_not_checked() {
+        let mut moderation = moderation(&["hell"]);
+        moderation.locales = Some(vec!["de".to_string()]);
+        assert_eq!(moderation.find("hell"), None);
+        moderation.locales = Some(vec!["en".to_string()]);
+        assert!(moderation.find("hell").is_some());
+    }
+}
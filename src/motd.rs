@@ -0,0 +1,178 @@
+use std::sync::{Arc, RwLock};
+
+use persy::Persy;
+use pulldown_cmark::{html, Event, Parser};
+use rocket::{
+    fairing::Fairing, form::Form, get, post, response::Redirect, routes, serde::json::Json,
+    FromForm, Route, State,
+};
+use rocket_dyn_templates::{
+    handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext},
+    Template,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    roles::Moderator,
+    storage::{self, Database},
+};
+
+//
+// Message of the day. Admin sets a markdown banner, optionally expiring, it's kept in
+// the database so it survives restarts. Pages render it with {{motd}} helper of templates,
+// single page clients read it from /motd. Raw html of the markdown is escaped
+//
+
+const MOTD_SEGMENT: &str = "motd";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Banner {
+    // Markdown text
+    pub text: String,
+    // Set and expiry time, seconds since unix epoch. Banner without expiry is shown until
+    // it's replaced or cleared
+    pub created: u64,
+    pub expires: Option<u64>,
+}
+
+// Banner for clients, with it's text rendered to html
+#[derive(Serialize)]
+pub struct MotdItem {
+    #[serde(flatten)]
+    pub banner: Banner,
+    pub html: String,
+}
+
+#[derive(FromForm)]
+pub struct MotdForm {
+    // Empty text clears the banner
+    text: String,
+    // Seconds until banner expires
+    expires_in: Option<u64>,
+}
+
+// Current banner, shared with template helper
+#[derive(Clone, Default)]
+pub struct Motd(Arc<RwLock<Option<Banner>>>);
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, MOTD_SEGMENT)
+}
+
+fn render(text: &str) -> String {
+    let events = Parser::new(text).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        event => event,
+    });
+    let mut output = String::new();
+    html::push_html(&mut output, events);
+    output
+}
+
+impl Motd {
+    // Load banner stored by previous run
+    pub fn load(persy: &Persy) -> Result<Motd, Error> {
+        let banner = storage::scan::<Banner>(persy, MOTD_SEGMENT)?
+            .into_iter()
+            .map(|(_, banner)| banner)
+            .max_by_key(|banner| banner.created);
+        Ok(Motd(Arc::new(RwLock::new(banner))))
+    }
+
+    // Banner unless it's expired
+    pub fn current(&self) -> Option<MotdItem> {
+        let banner = self.0.read().unwrap().clone()?;
+        if banner
+            .expires
+            .is_some_and(|expires| expires <= crate::unix_time())
+        {
+            return None;
+        }
+        Some(MotdItem {
+            html: render(&banner.text),
+            banner,
+        })
+    }
+
+    // Replace stored banner, None clears it
+    fn set(&self, persy: &Persy, banner: Option<Banner>) -> Result<(), Error> {
+        let mut tx = persy.begin()?;
+        for (id, _) in storage::scan::<Banner>(persy, MOTD_SEGMENT)? {
+            tx.delete(MOTD_SEGMENT, &id)?;
+        }
+        if let Some(banner) = &banner {
+            storage::insert_in_tx(&mut tx, MOTD_SEGMENT, banner)?;
+        }
+        tx.prepare()?.commit()?;
+        *self.0.write().unwrap() = banner;
+        Ok(())
+    }
+
+    // Set banner of the markdown text expiring in given seconds, empty text clears it
+    pub fn announce(
+        &self,
+        persy: &Persy,
+        text: &str,
+        expires_in: Option<u64>,
+    ) -> Result<(), Error> {
+        let now = crate::unix_time();
+        let banner = (!text.trim().is_empty()).then(|| Banner {
+            text: text.to_string(),
+            created: now,
+            expires: expires_in.map(|expires_in| now + expires_in),
+        });
+        match &banner {
+            Some(banner) => println!("Message of the day set: {:?}", banner),
+            None => println!("Message of the day cleared"),
+        }
+        self.set(persy, banner)
+    }
+
+    // Template fairing with {{motd}} helper, renders current banner or nothing
+    pub fn templates(&self) -> impl Fairing {
+        let motd = self.clone();
+        Template::custom(move |engines| {
+            let motd = motd.clone();
+            engines.handlebars.register_helper(
+                "motd",
+                Box::new(
+                    move |_: &Helper,
+                          _: &Handlebars,
+                          _: &Context,
+                          _: &mut RenderContext,
+                          out: &mut dyn Output|
+                          -> HelperResult {
+                        if let Some(item) = motd.current() {
+                            out.write("<div class=\"motd\">")?;
+                            out.write(&item.html)?;
+                            out.write("</div>")?;
+                        }
+                        Ok(())
+                    },
+                ),
+            );
+        })
+    }
+}
+
+#[get("/motd")]
+fn motd(motd: &State<Motd>) -> Json<Option<MotdItem>> {
+    Json(motd.current())
+}
+
+// Set or clear banner
+#[post("/admin/motd", data = "<form>")]
+fn set_motd(
+    _admin: Moderator,
+    db: &State<Database>,
+    motd: &State<Motd>,
+    form: Form<MotdForm>,
+) -> Result<Redirect, Error> {
+    motd.announce(&db.read(), &form.text, form.expires_in)?;
+    Ok(Redirect::to("/admin"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![motd, set_motd]
+}
@@ -0,0 +1,205 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use rocket::{
+    futures::StreamExt,
+    get,
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::{
+        self,
+        stream::{stream, Event, EventStream},
+        Responder,
+    },
+    routes,
+    serde::json::{serde_json, Json},
+    tokio::time::{self, Duration},
+    Request, Response, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    connections::Connections,
+    error::Error,
+    events::ChannelEvent,
+    ids::{MatchId, UserId},
+    tetris_pair::TetrisPairState,
+    views::{Projection, View},
+    TetrisMatches,
+};
+
+//
+// Multi-board spectating, for overview pages showing several matches at once.
+// /games/state?ids=1,2,3 returns spectator views of the given matches in one response,
+// /games/sse?ids=1,2,3 streams them over one connection: each board is sent when it
+// changes, as default event with it's match id. Matches no longer in memory are sent once
+// without board. Match ids are the ones listed by /live. Changes are found by state hashes
+// of the boards, which are also ETag of /games/state responses, so pollers get 304 Not
+// Modified while no board changes
+//
+
+// Boards per request
+const MAX_BOARDS: usize = 16;
+// Interval of sampling the boards for stream
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Serialize)]
+pub struct Board {
+    pub players: [UserId; 2],
+    pub scores: [usize; 2],
+    pub game_over: bool,
+    pub state: TetrisPairState,
+}
+
+#[derive(Serialize)]
+pub struct BoardView {
+    pub match_id: MatchId,
+    // None when the match is not in memory
+    pub board: Option<Board>,
+}
+
+impl Projection for Board {
+    fn project(self, view: View) -> Board {
+        Board {
+            state: self.state.project(view),
+            ..self
+        }
+    }
+}
+
+// Comma separated match ids, duplicates are dropped
+fn parse_ids(ids: &str) -> Result<Vec<MatchId>, Error> {
+    let mut parsed = Vec::new();
+    for id in ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        let match_id = id
+            .parse::<MatchId>()
+            .map_err(|_| Error::InvalidInputError(format!("Invalid match id {}", id)))?;
+        if !parsed.contains(&match_id) {
+            parsed.push(match_id);
+        }
+    }
+    if parsed.is_empty() {
+        return Err(Error::InvalidInputError("No match ids given".to_string()));
+    }
+    if parsed.len() > MAX_BOARDS {
+        return Err(Error::InvalidInputError(format!(
+            "At most {} boards per request",
+            MAX_BOARDS
+        )));
+    }
+    Ok(parsed)
+}
+
+fn board_views(matches: &TetrisMatches, ids: &[MatchId], view: View) -> Vec<BoardView> {
+    ids.iter()
+        .map(|match_id| BoardView {
+            match_id: *match_id,
+            board: matches.board(*match_id).map(|board| board.project(view)),
+        })
+        .collect()
+}
+
+// Entity tag of the boards in the view, from their state hashes
+fn entity_tag(matches: &TetrisMatches, ids: &[MatchId], view: View) -> String {
+    let mut hasher = DefaultHasher::new();
+    view.hash(&mut hasher);
+    for match_id in ids {
+        (match_id, matches.board_hash(*match_id)).hash(&mut hasher);
+    }
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+// Entity tag the client has, from If-None-Match header
+pub struct IfNoneMatch(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let tag = request
+            .headers()
+            .get_one("If-None-Match")
+            .map(str::to_string);
+        Outcome::Success(IfNoneMatch(tag))
+    }
+}
+
+pub enum BoardsResponse {
+    NotModified,
+    Boards(Json<Vec<BoardView>>, String),
+}
+
+impl<'r> Responder<'r, 'static> for BoardsResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            BoardsResponse::NotModified => Response::build().status(Status::NotModified).ok(),
+            BoardsResponse::Boards(boards, tag) => {
+                Response::build_from(boards.respond_to(request)?)
+                    .raw_header("ETag", tag)
+                    .ok()
+            }
+        }
+    }
+}
+
+// Spectator views of the matches, in order of ids. Upcoming pieces are hidden with hide_queue
+#[get("/games/state?<ids>&<hide_queue>")]
+fn boards(
+    matches: &State<TetrisMatches>,
+    if_none_match: IfNoneMatch,
+    ids: &str,
+    hide_queue: Option<bool>,
+) -> Result<BoardsResponse, Error> {
+    let ids = parse_ids(ids)?;
+    let view = View::spectator(hide_queue.unwrap_or(false));
+    let tag = entity_tag(matches, &ids, view);
+    if if_none_match.0.as_ref() == Some(&tag) {
+        return Ok(BoardsResponse::NotModified);
+    }
+    Ok(BoardsResponse::Boards(
+        Json(board_views(matches, &ids, view)),
+        tag,
+    ))
+}
+
+// Stream of the matches' spectator views, see boards. Stream ends when none of the
+// matches is in memory
+#[get("/games/sse?<ids>&<hide_queue>")]
+fn boards_stream<'b>(
+    matches: &'b State<TetrisMatches>,
+    connections: &State<Connections>,
+    ids: &str,
+    hide_queue: Option<bool>,
+) -> Result<EventStream![Event + 'b], Error> {
+    let ids = parse_ids(ids)?;
+    let view = View::spectator(hide_queue.unwrap_or(false));
+    let watching = ids.clone();
+    let events = stream! {
+        // State hash of last sent board of each match, None for match not in memory
+        let mut sent = vec![None; ids.len()];
+        let mut interval = time::interval(FRAME_INTERVAL);
+        loop {
+            interval.tick().await;
+            let hashes = ids.iter().map(|match_id| matches.board_hash(*match_id)).collect::<Vec<_>>();
+            if hashes.iter().all(Option::is_none) && sent.iter().all(Option::is_some) {
+                break;
+            }
+            for ((match_id, hash), sent) in ids.iter().zip(hashes).zip(sent.iter_mut()) {
+                if *sent != Some(hash) {
+                    *sent = Some(hash);
+                    let view = BoardView {
+                        match_id: *match_id,
+                        board: matches.board(*match_id).map(|board| board.project(view)),
+                    };
+                    yield ChannelEvent::message(serde_json::to_string(&view).unwrap());
+                }
+            }
+        }
+    };
+    let events = connections.track_watching(None, "games", watching, events);
+    Ok(EventStream::from(events.map(ChannelEvent::into_event)))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![boards, boards_stream]
+}
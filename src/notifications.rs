@@ -0,0 +1,277 @@
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    form::Form,
+    futures::Stream,
+    get,
+    http::CookieJar,
+    post,
+    response::stream::stream,
+    routes,
+    serde::json::{serde_json, Json},
+    tokio::sync::broadcast::{self, error::RecvError},
+    FromForm, FromFormField, Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    events::ChannelEvent,
+    ids::UserId,
+    pagination::{self, Page, SortOrder},
+    roles::Moderator,
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Notification inbox of users. Notifications are stored until the inbox overflows,
+// oldest ones are dropped then. Connected users get new notifications in real time
+// on "notifications" channel of /events
+//
+
+const NOTIFICATIONS_SEGMENT: &str = "notifications";
+const BY_USER_INDEX: &str = "notifications_by_user";
+
+// Notifications kept per user
+const MAX_PER_USER: usize = 200;
+// Notifications buffered for slow listeners
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+pub enum NotificationKind {
+    FriendRequest,
+    TournamentInvite,
+    Achievement,
+    // Message from server administration
+    System,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub user: UserId,
+    pub kind: NotificationKind,
+    pub text: String,
+    // Creation time, seconds since unix epoch
+    pub created: u64,
+    pub read: bool,
+}
+
+// Notification with it's database id for listings
+#[derive(Debug, Clone, Serialize)]
+pub struct NotificationItem {
+    pub id: String,
+    #[serde(flatten)]
+    pub notification: Notification,
+}
+
+#[derive(Serialize)]
+pub struct Inbox {
+    pub unread: usize,
+    #[serde(flatten)]
+    pub page: Page<NotificationItem>,
+}
+
+#[derive(FromForm)]
+pub struct InboxQuery<'r> {
+    // Creation time order, latest first by default
+    order: Option<SortOrder>,
+    cursor: Option<&'r str>,
+    limit: Option<usize>,
+}
+
+#[derive(FromForm)]
+pub struct NotificationForm {
+    user: UserId,
+    kind: NotificationKind,
+    text: String,
+}
+
+// Sender of new notifications to connected users
+#[derive(Clone)]
+pub struct Notifications(broadcast::Sender<NotificationItem>);
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, NOTIFICATIONS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+pub fn notifications_of_user(
+    persy: &Persy,
+    user: UserId,
+) -> Result<Vec<(PersyId, Notification)>, Error> {
+    let mut notifications = Vec::new();
+    for id in persy.get::<u32, PersyId>(BY_USER_INDEX, &user.0)? {
+        if let Some(notification) = storage::read(persy, NOTIFICATIONS_SEGMENT, &id)? {
+            notifications.push((id, notification));
+        }
+    }
+    Ok(notifications)
+}
+
+// Store notification, dropping the oldest ones over the limit
+fn store(persy: &Persy, notification: &Notification) -> Result<PersyId, Error> {
+    let mut existing = notifications_of_user(persy, notification.user)?;
+    existing.sort_by_key(|(id, notification)| (notification.created, *id));
+    let overflow = (existing.len() + 1).saturating_sub(MAX_PER_USER);
+    let mut tx = persy.begin()?;
+    for (id, _) in existing.iter().take(overflow) {
+        tx.delete(NOTIFICATIONS_SEGMENT, id)?;
+        tx.remove(BY_USER_INDEX, notification.user.0, Some(*id))?;
+    }
+    let id = storage::insert_in_tx(&mut tx, NOTIFICATIONS_SEGMENT, notification)?;
+    tx.put(BY_USER_INDEX, notification.user.0, id)?;
+    tx.prepare()?.commit()?;
+    Ok(id)
+}
+
+// Page of user's inbox with number of unread notifications
+pub fn inbox(persy: &Persy, user: UserId, query: &InboxQuery) -> Result<Inbox, Error> {
+    let notifications = notifications_of_user(persy, user)?;
+    let unread = notifications
+        .iter()
+        .filter(|(_, notification)| !notification.read)
+        .count();
+    let items = notifications
+        .into_iter()
+        .map(|(id, notification)| {
+            (
+                notification.created,
+                id,
+                NotificationItem {
+                    id: id.to_string(),
+                    notification,
+                },
+            )
+        })
+        .collect();
+    let page = pagination::page_in_memory(
+        items,
+        query.order.unwrap_or(SortOrder::Desc),
+        query.cursor,
+        pagination::limit(query.limit),
+    )?;
+    Ok(Inbox { unread, page })
+}
+
+// Mark user's notifications as read, all of them when id is not given
+fn mark_read(persy: &Persy, user: UserId, id: Option<PersyId>) -> Result<(), Error> {
+    let notifications = notifications_of_user(persy, user)?;
+    if id.is_some_and(|id| !notifications.iter().any(|(other, _)| *other == id)) {
+        return Err(Error::NotFoundError("Notification not found".to_string()));
+    }
+    for (other, mut notification) in notifications {
+        if !notification.read && id.is_none_or(|id| id == other) {
+            notification.read = true;
+            storage::update(persy, NOTIFICATIONS_SEGMENT, &other, &notification)?;
+        }
+    }
+    Ok(())
+}
+
+impl Notifications {
+    pub fn new() -> Notifications {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Notifications(sender)
+    }
+
+    // Store notification for the user and deliver it if the user is connected
+    pub fn notify(
+        &self,
+        persy: &Persy,
+        user: UserId,
+        kind: NotificationKind,
+        text: String,
+    ) -> Result<NotificationItem, Error> {
+        let notification = Notification {
+            user,
+            kind,
+            text,
+            created: crate::unix_time(),
+            read: false,
+        };
+        let id = store(persy, &notification)?;
+        let item = NotificationItem {
+            id: id.to_string(),
+            notification,
+        };
+        let _ = self.0.send(item.clone());
+        Ok(item)
+    }
+
+    // New notifications of the user, as "notification" events
+    pub fn stream(&self, user: UserId) -> impl Stream<Item = ChannelEvent> + Send {
+        let mut receiver = self.0.subscribe();
+        stream! {
+            loop {
+                match receiver.recv().await {
+                    Ok(item) if item.notification.user == user => {
+                        yield ChannelEvent::named("notification", serde_json::to_string(&item).unwrap());
+                    }
+                    Ok(_) => continue,
+                    // Missed notifications are in the inbox
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+}
+
+#[get("/notifications?<query..>")]
+fn notifications(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    query: InboxQuery,
+) -> Result<Json<Inbox>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(inbox(&db.read(), user_id, &query)?))
+}
+
+#[post("/notifications/<id>/read")]
+fn read_notification(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    id: &str,
+) -> Result<(), Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    mark_read(&db.read(), user_id, Some(storage::parse_id(id)?))
+}
+
+#[post("/notifications/read")]
+fn read_all_notifications(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<(), Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    mark_read(&db.read(), user_id, None)
+}
+
+// Send notification to the user
+#[post("/admin/notifications", data = "<form>")]
+fn send_notification(
+    _admin: Moderator,
+    db: &State<Database>,
+    notifications: &State<Notifications>,
+    form: Form<NotificationForm>,
+) -> Result<Json<NotificationItem>, Error> {
+    let form = form.into_inner();
+    Ok(Json(notifications.notify(
+        &db.read(),
+        form.user,
+        form.kind,
+        form.text,
+    )?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        notifications,
+        read_notification,
+        read_all_notifications,
+        send_notification
+    ]
+}
@@ -0,0 +1,320 @@
+use std::fmt::Display;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use persy::{IndexType, Persy, PersyId};
+use rocket::FromFormField;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{error::Error, storage};
+
+//
+// Cursor-based pagination for listings. Cursor points to the last returned entry
+// as "<sort key>.<entry id>", next page starts right after it, so pages stay consistent
+// when new entries are added.
+//
+
+pub const DEFAULT_LIMIT: usize = 20;
+pub const MAX_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // Cursor for next page, absent when the listing is exhausted
+    pub next_cursor: Option<String>,
+}
+
+// Get page size from query parameter
+pub fn limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+}
+
+pub fn encode_cursor<K: Display, I: Display>(key: &K, id: &I) -> String {
+    format!("{}.{}", key, id)
+}
+
+pub fn decode_cursor<K: FromStr, I: FromStr>(cursor: &str) -> Result<(K, I), Error> {
+    let invalid = || Error::InvalidInputError(format!("Invalid cursor {}", cursor));
+    let (key, id) = cursor.rsplit_once('.').ok_or_else(invalid)?;
+    Ok((
+        key.parse().map_err(|_| invalid())?,
+        id.parse().map_err(|_| invalid())?,
+    ))
+}
+
+// Walk index in given order starting after cursor, load records from segment
+// and collect up to limit records accepted by filter. Next cursor is given only when
+// one more accepted record follows
+#[allow(clippy::too_many_arguments)]
+pub fn page_by_index<K, T, R>(
+    persy: &Persy,
+    index: &str,
+    segment: &str,
+    order: SortOrder,
+    bounds: (Bound<K>, Bound<K>),
+    cursor: Option<&str>,
+    limit: usize,
+    filter: impl Fn(&T) -> bool,
+    map: impl Fn(PersyId, T) -> R,
+) -> Result<Page<R>, Error>
+where
+    K: IndexType + Display + FromStr + PartialEq,
+    T: DeserializeOwned,
+{
+    let cursor = cursor.map(decode_cursor::<K, PersyId>).transpose()?;
+    // Narrow index range to start from the cursor key
+    let (start, end) = bounds;
+    let bounds = match (&cursor, order) {
+        (None, _) => (start, end),
+        (Some((key, _)), SortOrder::Asc) => (Bound::Included(key.clone()), end),
+        (Some((key, _)), SortOrder::Desc) => (start, Bound::Included(key.clone())),
+    };
+    let range = persy.range::<K, PersyId, _>(index, bounds)?;
+    let keys: Box<dyn Iterator<Item = _>> = match order {
+        SortOrder::Asc => Box::new(range),
+        SortOrder::Desc => Box::new(range.rev()),
+    };
+    // Entries with the cursor key are skipped up to the cursor entry itself
+    let mut skipping = cursor.is_some();
+    let mut items = Vec::new();
+    let mut last = None;
+    for (key, ids) in keys {
+        let ids: Box<dyn Iterator<Item = PersyId>> = match order {
+            SortOrder::Asc => Box::new(ids),
+            SortOrder::Desc => Box::new(ids.rev()),
+        };
+        for id in ids {
+            if let Some((cursor_key, cursor_id)) = &cursor {
+                if skipping && key == *cursor_key {
+                    skipping = id != *cursor_id;
+                    continue;
+                }
+            }
+            skipping = false;
+            let Some(record) = storage::read::<T>(persy, segment, &id)? else {
+                continue;
+            };
+            if !filter(&record) {
+                continue;
+            }
+            if let Some((last_key, last_id)) = &last {
+                return Ok(Page {
+                    items,
+                    next_cursor: Some(encode_cursor(last_key, last_id)),
+                });
+            }
+            items.push(map(id, record));
+            if items.len() == limit {
+                last = Some((key.clone(), id));
+            }
+        }
+    }
+    Ok(Page {
+        items,
+        next_cursor: None,
+    })
+}
+
+// Same as page_by_index for collections held in memory. Items are (sort key, id, value)
+pub fn page_in_memory<K, I, T>(
+    mut items: Vec<(K, I, T)>,
+    order: SortOrder,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<Page<T>, Error>
+where
+    K: Ord + Display + FromStr,
+    I: Ord + Display + FromStr,
+{
+    let cursor = cursor.map(decode_cursor::<K, I>).transpose()?;
+    items.sort_by(|(ka, ia, _), (kb, ib, _)| (ka, ia).cmp(&(kb, ib)));
+    if order == SortOrder::Desc {
+        items.reverse();
+    }
+    let mut items = items
+        .into_iter()
+        .filter(|(key, id, _)| match &cursor {
+            None => true,
+            Some((cursor_key, cursor_id)) => match order {
+                SortOrder::Asc => (key, id) > (cursor_key, cursor_id),
+                SortOrder::Desc => (key, id) < (cursor_key, cursor_id),
+            },
+        })
+        .take(limit + 1)
+        .collect::<Vec<_>>();
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|(key, id, _)| encode_cursor(key, id))
+    } else {
+        None
+    };
+    Ok(Page {
+        items: items.into_iter().map(|(_, _, value)| value).collect(),
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use persy::{OpenOptions, ValueMode};
+    use serde::Deserialize;
+
+    use super::*;
+
+    const SEGMENT: &str = "entries";
+    const INDEX: &str = "entries_by_key";
+
+    #[derive(Serialize, Deserialize)]
+    struct Entry {
+        key: u64,
+        shown: bool,
+    }
+
+    // Database with entries indexed by key, several entries may share a key
+    fn database(entries: &[(u64, bool)]) -> Persy {
+        let persy = OpenOptions::new().memory().unwrap();
+        storage::ensure_segment(&persy, SEGMENT).unwrap();
+        storage::ensure_index::<u64, PersyId>(&persy, INDEX, ValueMode::Cluster).unwrap();
+        let mut tx = persy.begin().unwrap();
+        for &(key, shown) in entries {
+            let id = storage::insert_in_tx(&mut tx, SEGMENT, &Entry { key, shown }).unwrap();
+            tx.put(INDEX, key, id).unwrap();
+        }
+        tx.prepare().unwrap().commit().unwrap();
+        persy
+    }
+
+    fn page(
+        persy: &Persy,
+        order: SortOrder,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Page<(u64, PersyId)> {
+        page_by_index(
+            persy,
+            INDEX,
+            SEGMENT,
+            order,
+            (Bound::<u64>::Unbounded, Bound::Unbounded),
+            cursor,
+            limit,
+            |entry: &Entry| entry.shown,
+            |id, entry| (entry.key, id),
+        )
+        .unwrap()
+    }
+
+    // Keys of all pages, following cursors until the listing is exhausted
+    fn all_pages(persy: &Persy, order: SortOrder, limit: usize) -> Vec<Vec<u64>> {
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = page(persy, order, cursor.as_deref(), limit);
+            pages.push(page.items.iter().map(|(key, _)| *key).collect());
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn no_cursor_after_last_page() {
+        let persy = database(&[(1, true), (2, true), (3, true), (4, true)]);
+        assert_eq!(
+            all_pages(&persy, SortOrder::Asc, 2),
+            vec![vec![1, 2], vec![3, 4]]
+        );
+        assert!(page(&persy, SortOrder::Asc, None, 4).next_cursor.is_none());
+        assert!(page(&persy, SortOrder::Asc, None, 3).next_cursor.is_some());
+    }
+
+    #[test]
+    fn no_cursor_when_only_filtered_entries_follow() {
+        let persy = database(&[(1, true), (2, true), (3, false), (4, false)]);
+        let first = page(&persy, SortOrder::Asc, None, 2);
+        assert_eq!(first.items.len(), 2);
+        assert!(first.next_cursor.is_none());
+    }
+
+    #[test]
+    fn pages_continue_within_shared_key() {
+        let persy = database(&[
+            (1, true),
+            (2, true),
+            (2, true),
+            (2, false),
+            (2, true),
+            (3, true),
+        ]);
+        assert_eq!(
+            all_pages(&persy, SortOrder::Asc, 2),
+            vec![vec![1, 2], vec![2, 2], vec![3]]
+        );
+        assert_eq!(
+            all_pages(&persy, SortOrder::Desc, 2),
+            vec![vec![3, 2], vec![2, 2], vec![1]]
+        );
+    }
+
+    #[test]
+    fn pages_have_every_entry_once() {
+        let persy = database(&[(5, true), (1, true), (5, true), (3, true), (1, true)]);
+        for limit in 1..=6 {
+            let mut ids = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = page(&persy, SortOrder::Desc, cursor.as_deref(), limit);
+                ids.extend(page.items.iter().map(|(_, id)| *id));
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+            let listed = ids.len();
+            ids.sort();
+            ids.dedup();
+            assert_eq!((listed, ids.len()), (5, 5), "limit {}", limit);
+        }
+    }
+
+    #[test]
+    fn empty_listing_has_no_cursor() {
+        let persy = database(&[]);
+        let page = page(&persy, SortOrder::Desc, None, 2);
+        assert!(page.items.is_empty());
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn in_memory_pages() {
+        let items = vec![(2, 1, "b"), (1, 2, "a"), (2, 3, "c"), (3, 4, "d")];
+        let first = page_in_memory(items.clone(), SortOrder::Asc, None, 2).unwrap();
+        assert_eq!(first.items, vec!["a", "b"]);
+        let second = page_in_memory(
+            items.clone(),
+            SortOrder::Asc,
+            first.next_cursor.as_deref(),
+            2,
+        )
+        .unwrap();
+        assert_eq!(second.items, vec!["c", "d"]);
+        assert!(second.next_cursor.is_none());
+        let desc = page_in_memory(items, SortOrder::Desc, Some("2.3"), 10).unwrap();
+        assert_eq!(desc.items, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn invalid_cursor_is_refused() {
+        assert!(decode_cursor::<u64, u32>("12").is_err());
+        assert!(decode_cursor::<u64, u32>("a.1").is_err());
+        assert_eq!(decode_cursor::<u64, u32>("12.3").unwrap(), (12, 3));
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    admission::Admitted,
+    connections::Connections,
+    error::Error,
+    events::{self, Channel, Sources},
+    ids::UserId,
+    input_sequence::InputSequences,
+    latency::Latency,
+    maintenance::Maintenance,
+    notifications::Notifications,
+    spotlight::Spotlight,
+    visibility::Pauses,
+    write_queue::WriteQueue,
+    TetrisMatches,
+};
+use gameserver_protocol::events::PolledEvent;
+use rocket::{
+    futures::{pin_mut, stream, StreamExt},
+    get,
+    http::{ContentType, CookieJar},
+    routes,
+    serde::json::serde_json,
+    tokio::{
+        self,
+        sync::Notify,
+        task::JoinHandle,
+        time::{self, Duration, Instant},
+    },
+    Config, Route, State,
+};
+
+//
+// Long polling fallback for clients where event streams and websockets are blocked.
+// GET /poll?channels=<channels>&since=<version> subscribes to channels the same way
+// as /events does, but the subscription lives on the server between polls and buffers
+// it's events numbered with versions. Each poll returns events after since as NDJSON,
+// one envelope with version per line, waiting up to poll_timeout_secs for the first one.
+// Events up to since are dropped, so clients pass version of the last event they got.
+// Subscription ends like a closed event stream when it's not polled for IDLE_TIMEOUT
+//
+
+// Wait for events unless configured
+const DEFAULT_POLL_TIMEOUT_SECS: u64 = 25;
+// Subscription not polled for this time is dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+// Events kept for the next poll, the oldest are dropped above it
+const BUFFER_CAPACITY: usize = 1024;
+
+#[derive(Default)]
+struct Buffer {
+    // Versions and lines of events not acknowledged yet
+    events: VecDeque<(u64, String)>,
+    next_version: u64,
+    // Events of the channels ended
+    finished: bool,
+}
+
+// Events of the subscription, filled by it's task
+#[derive(Default)]
+struct Events {
+    buffer: Mutex<Buffer>,
+    notify: Notify,
+}
+
+struct Subscription {
+    events: Arc<Events>,
+    task: JoinHandle<()>,
+    last_poll: Mutex<Instant>,
+}
+
+// Owned state of game streams, kept by subscription's task
+struct PollSources {
+    matches: TetrisMatches,
+    writes: WriteQueue,
+    latency: Latency,
+    sequences: InputSequences,
+    maintenance: Maintenance,
+    pauses: Pauses,
+    spotlight: Spotlight,
+    notifications: Notifications,
+    connections: Connections,
+}
+
+// User and channels of subscription
+type SubscriptionKey = (UserId, String);
+
+// Subscriptions by user and channels
+#[derive(Clone)]
+pub struct Polls {
+    subscriptions: Arc<Mutex<HashMap<SubscriptionKey, Arc<Subscription>>>>,
+    timeout: Duration,
+}
+
+impl Events {
+    fn push(&self, line: impl FnOnce(u64) -> Option<String>) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let version = buffer.next_version + 1;
+        let Some(line) = line(version) else {
+            return;
+        };
+        if buffer.events.len() == BUFFER_CAPACITY {
+            buffer.events.pop_front();
+        }
+        buffer.events.push_back((version, line));
+        buffer.next_version = version;
+        drop(buffer);
+        self.notify.notify_waiters();
+    }
+
+    fn finish(&self) {
+        self.buffer.lock().unwrap().finished = true;
+        self.notify.notify_waiters();
+    }
+
+    // Drop events up to since and return the rest, with whether the events ended.
+    // Version ahead of the subscription is of one which ended, all events are returned then
+    fn after(&self, since: u64) -> (Vec<String>, bool) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if since <= buffer.next_version {
+            while buffer
+                .events
+                .front()
+                .is_some_and(|(version, _)| *version <= since)
+            {
+                buffer.events.pop_front();
+            }
+        }
+        let lines = buffer.events.iter().map(|(_, line)| line.clone()).collect();
+        (lines, buffer.finished)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Polls {
+    pub fn from_config() -> Polls {
+        Polls {
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            timeout: Duration::from_secs(
+                Config::figment()
+                    .extract_inner::<u64>("poll_timeout_secs")
+                    .unwrap_or(DEFAULT_POLL_TIMEOUT_SECS),
+            ),
+        }
+    }
+
+    fn subscribe(
+        &self,
+        user_id: UserId,
+        channels: Vec<Channel>,
+        admitted: bool,
+        hide_queue: bool,
+        sources: impl FnOnce() -> PollSources,
+    ) -> Arc<Subscription> {
+        let key = (
+            user_id,
+            format!("{}/{}", events::connection_name(&channels), hide_queue),
+        );
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let subscription = subscriptions.entry(key).or_insert_with(|| {
+            let events = Arc::new(Events::default());
+            // Not a lifecycle job, the task is aborted when it's subscription is dropped
+            let task = tokio::spawn(Self::run(
+                user_id,
+                channels,
+                admitted,
+                hide_queue,
+                sources(),
+                events.clone(),
+            ));
+            Arc::new(Subscription {
+                events,
+                task,
+                last_poll: Mutex::new(Instant::now()),
+            })
+        });
+        *subscription.last_poll.lock().unwrap() = Instant::now();
+        subscription.clone()
+    }
+
+    // Fill subscription's buffer with events of the channels
+    async fn run(
+        user_id: UserId,
+        channels: Vec<Channel>,
+        admitted: bool,
+        hide_queue: bool,
+        owned: PollSources,
+        events: Arc<Events>,
+    ) {
+        let sources = Sources {
+            matches: &owned.matches,
+            writes: &owned.writes,
+            latency: &owned.latency,
+            sequences: &owned.sequences,
+            maintenance: &owned.maintenance,
+            pauses: &owned.pauses,
+            spotlight: &owned.spotlight,
+            notifications: &owned.notifications,
+        };
+        let mut streams = Vec::new();
+        for channel in channels.iter().copied() {
+            match events::channel_stream(&sources, channel, || user_id, admitted, hide_queue) {
+                Ok(stream) => streams.push(
+                    stream
+                        .map(move |event| event.into_envelope(channel))
+                        .boxed(),
+                ),
+                Err(e) => println!("Poll subscription of user {} failed: {}", user_id, e),
+            }
+        }
+        let polled = owned.connections.track(
+            Some(user_id),
+            format!("poll/{}", events::connection_name(&channels)),
+            stream::select_all(streams),
+        );
+        pin_mut!(polled);
+        while let Some(event) = polled.next().await {
+            events.push(|version| {
+                let envelope = serde_json::from_str(&event.data).ok()?;
+                serde_json::to_string(&PolledEvent { version, envelope }).ok()
+            });
+        }
+        events.finish();
+    }
+
+    // Drop subscription which ended, the next poll starts a new one
+    fn remove_finished(&self, user_id: UserId, subscription: &Arc<Subscription>) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|(user, _), subscribed| {
+                *user != user_id || !Arc::ptr_eq(subscribed, subscription)
+            });
+    }
+
+    // Drop subscriptions not polled for IDLE_TIMEOUT periodically
+    pub async fn reaper_job(self) {
+        let mut interval = time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .retain(|_, subscription| {
+                    subscription.last_poll.lock().unwrap().elapsed() < IDLE_TIMEOUT
+                });
+        }
+    }
+}
+
+// Events of subscribed channels after version since, as NDJSON. Channels are the same
+// as of /events, see events
+#[get("/poll?<channels>&<since>&<hide_queue>")]
+#[allow(clippy::too_many_arguments)]
+async fn poll(
+    cookie_jar: &CookieJar<'_>,
+    polls: &State<Polls>,
+    matches: &State<TetrisMatches>,
+    writes: &State<WriteQueue>,
+    latency: &State<Latency>,
+    sequences: &State<InputSequences>,
+    maintenance: &State<Maintenance>,
+    pauses: &State<Pauses>,
+    spotlight: &State<Spotlight>,
+    notifications: &State<Notifications>,
+    connections: &State<Connections>,
+    admitted: Option<Admitted>,
+    channels: &str,
+    since: Option<u64>,
+    hide_queue: Option<bool>,
+) -> Result<(ContentType, String), Error> {
+    let channels = events::parse_channels(channels)?;
+    let user_id = crate::user_id(cookie_jar, matches);
+    let subscription = polls.subscribe(
+        user_id,
+        channels,
+        admitted.is_some(),
+        hide_queue.unwrap_or(false),
+        || PollSources {
+            matches: matches.inner().clone(),
+            writes: writes.inner().clone(),
+            latency: latency.inner().clone(),
+            sequences: sequences.inner().clone(),
+            maintenance: maintenance.inner().clone(),
+            pauses: pauses.inner().clone(),
+            spotlight: spotlight.inner().clone(),
+            notifications: notifications.inner().clone(),
+            connections: connections.inner().clone(),
+        },
+    );
+    let since = since.unwrap_or(0);
+    let deadline = Instant::now() + polls.timeout;
+    let lines = loop {
+        // Wait is registered before the check, so events pushed in between aren't missed
+        let notified = subscription.events.notify.notified();
+        let (lines, finished) = subscription.events.after(since);
+        if finished && lines.is_empty() {
+            polls.remove_finished(user_id, &subscription);
+        }
+        if !lines.is_empty() || finished {
+            break lines;
+        }
+        if time::timeout_at(deadline, notified).await.is_err() {
+            break Vec::new();
+        }
+    };
+    let mut body = lines.join("\n");
+    if !body.is_empty() {
+        body.push('\n');
+    }
+    Ok((ContentType::new("application", "x-ndjson"), body))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![poll]
+}
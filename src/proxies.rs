@@ -0,0 +1,288 @@
+use std::net::{IpAddr, SocketAddr};
+
+use rocket::{Config, Request};
+
+use crate::{error::Error, handover::Handover};
+
+//
+// Client addresses behind reverse proxies. Forwarding header is believed only when it's
+// added by trusted proxies: the chain of addresses is walked from the connection peer back
+// to the client while the hops are trusted, so the client can't spoof it's address by
+// sending the header itself. Only the header set by the proxies is read, forwarded_header
+// key, X-Forwarded-For by default or Forwarded (RFC 7239): a proxy appending to one of
+// them passes the other one from the client as is. Trusted proxies are configured by
+// trusted_proxies key as list of addresses and CIDR ranges. Peers of connections
+// forwarded from the handover socket are the clients, see handover
+//
+
+// Address or CIDR range of trusted proxies
+#[derive(Debug, Clone, Copy)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    fn parse(range: &str) -> Option<IpRange> {
+        let (address, prefix) = match range.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u32>().ok()?)),
+            None => (range, None),
+        };
+        let network = address.trim().parse::<IpAddr>().ok()?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(IpRange { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // Ipv4 clients of dual stack listener come as mapped ipv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+const DEFAULT_FORWARDED_HEADER: &str = "X-Forwarded-For";
+
+pub struct TrustedProxies {
+    ranges: Vec<IpRange>,
+    // Forwarding header set by the proxies
+    header: String,
+}
+
+impl TrustedProxies {
+    pub fn from_config() -> Result<TrustedProxies, Error> {
+        let figment = Config::figment();
+        let ranges = figment
+            .extract_inner::<Vec<String>>("trusted_proxies")
+            .unwrap_or_default();
+        let header = figment
+            .extract_inner::<String>("forwarded_header")
+            .unwrap_or_else(|_| DEFAULT_FORWARDED_HEADER.to_string());
+        if !ranges.is_empty() {
+            println!("Trusted proxies: {:?}, {} header", ranges, header);
+        }
+        let ranges = ranges
+            .iter()
+            .map(|range| {
+                IpRange::parse(range).ok_or_else(|| {
+                    Error::InvalidInputError(format!("Invalid trusted proxy {}", range))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TrustedProxies { ranges, header })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+
+    // Client address for connection peer and addresses of forwarding header, client first
+    fn resolve(&self, peer: IpAddr, forwarded: &[Option<IpAddr>]) -> IpAddr {
+        let mut client = peer;
+        for hop in forwarded.iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            // Unknown or obfuscated address, the last trusted proxy is the best known
+            let Some(hop) = hop else {
+                break;
+            };
+            client = *hop;
+        }
+        client
+    }
+}
+
+// Address in forwarding header, possibly with port and in brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(address) = node.parse::<SocketAddr>() {
+        return Some(address.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|node| node.parse::<IpAddr>().ok())
+}
+
+// Addresses of "for" parameters of Forwarded header elements (RFC 7239)
+fn forwarded_for<'a>(values: impl Iterator<Item = &'a str>) -> Vec<Option<IpAddr>> {
+    values
+        .flat_map(|header| header.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value))
+            })
+        })
+        .collect()
+}
+
+// Addresses of X-Forwarded-For style header, comma separated
+fn x_forwarded_for<'a>(values: impl Iterator<Item = &'a str>) -> Vec<Option<IpAddr>> {
+    values
+        .flat_map(|header| header.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+// Address of the client making request
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let peer = request.remote()?;
+    // Connections forwarded from the handover socket come from loopback
+    let peer = request
+        .rocket()
+        .state::<Handover>()
+        .and_then(|handover| handover.client_of(peer))
+        .unwrap_or(peer)
+        .ip();
+    let Some(proxies) = request.rocket().state::<TrustedProxies>() else {
+        return Some(peer);
+    };
+    let values = request.headers().get(&proxies.header);
+    let forwarded = if proxies.header.eq_ignore_ascii_case("Forwarded") {
+        forwarded_for(values)
+    } else {
+        x_forwarded_for(values)
+    };
+    Some(proxies.resolve(peer, &forwarded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies(ranges: &[&str]) -> TrustedProxies {
+        TrustedProxies {
+            ranges: ranges.iter().map(|r| IpRange::parse(r).unwrap()).collect(),
+            header: DEFAULT_FORWARDED_HEADER.to_string(),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_nodes() {
+        assert_eq!(parse_node(" 203.0.113.7 "), Some(ip("203.0.113.7")));
+        assert_eq!(parse_node("203.0.113.7:4711"), Some(ip("203.0.113.7")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:4711\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn forwarded_elements() {
+        let values = [
+            "for=192.0.2.60;proto=http, For=\"[2001:db8::1]:4711\"",
+            "for=unknown",
+        ];
+        assert_eq!(
+            forwarded_for(values.into_iter()),
+            [Some(ip("192.0.2.60")), Some(ip("2001:db8::1")), None]
+        );
+        assert_eq!(
+            x_forwarded_for(["6.6.6.6, 203.0.113.7"].into_iter()),
+            [Some(ip("6.6.6.6")), Some(ip("203.0.113.7"))]
+        );
+    }
+
+    #[test]
+    fn untrusted_peer_is_client() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let forwarded = [Some(ip("6.6.6.6"))];
+        assert_eq!(
+            proxies.resolve(ip("203.0.113.7"), &forwarded),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn chain_is_walked_to_untrusted_hop() {
+        let proxies = proxies(&["10.0.0.0/8", "2001:db8:ffff::/48"]);
+        // Client spoofs the first address, its real address is appended by the proxy
+        let forwarded = [
+            Some(ip("6.6.6.6")),
+            Some(ip("203.0.113.7")),
+            Some(ip("10.0.0.2")),
+        ];
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &forwarded),
+            ip("203.0.113.7")
+        );
+        // Untrusted hop in the middle stops the walk, hops before it are not believed
+        let forwarded = [
+            Some(ip("6.6.6.6")),
+            Some(ip("198.51.100.1")),
+            Some(ip("10.0.0.2")),
+        ];
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &forwarded),
+            ip("198.51.100.1")
+        );
+        let forwarded = [x_forwarded_for(["[2001:db8::1]:4711"].into_iter())[0]];
+        assert_eq!(
+            proxies.resolve(ip("2001:db8:ffff::1"), &forwarded),
+            ip("2001:db8::1")
+        );
+        // Unknown address, the last trusted proxy is the best known
+        let forwarded = [Some(ip("6.6.6.6")), None];
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &forwarded), ip("10.0.0.1"));
+        // Ipv4 peer of dual stack listener
+        let forwarded = [Some(ip("203.0.113.7"))];
+        assert_eq!(
+            proxies.resolve(ip("::ffff:10.0.0.1"), &forwarded),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn spoofed_forwarded_is_ignored() {
+        let client = rocket::local::blocking::Client::untracked(
+            rocket::build().manage(proxies(&["127.0.0.1"])),
+        )
+        .unwrap();
+        let mut request = client
+            .get("/")
+            .header(rocket::http::Header::new("Forwarded", "for=6.6.6.6"))
+            .header(rocket::http::Header::new("X-Forwarded-For", "203.0.113.7"));
+        request.set_remote("127.0.0.1:4711".parse().unwrap());
+        assert_eq!(client_ip(request.inner()), Some(ip("203.0.113.7")));
+        // Proxies setting Forwarded
+        let client =
+            rocket::local::blocking::Client::untracked(rocket::build().manage(TrustedProxies {
+                header: "Forwarded".to_string(),
+                ..proxies(&["127.0.0.1"])
+            }))
+            .unwrap();
+        let mut request = client
+            .get("/")
+            .header(rocket::http::Header::new("Forwarded", "for=203.0.113.7"))
+            .header(rocket::http::Header::new("X-Forwarded-For", "6.6.6.6"));
+        request.set_remote("127.0.0.1:4711".parse().unwrap());
+        assert_eq!(client_ip(request.inner()), Some(ip("203.0.113.7")));
+    }
+}
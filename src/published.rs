@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    ids::{MatchId, UserId},
+    matches::PlayerSide,
+    tetris_pair::{TetrisPair, TetrisPairState},
+};
+
+//
+// Read-only states of running matches for spectators. Tick workers step matches under the
+// lock of each match, so readers taking that lock wait for steps and delay them.
+// Instead each step publishes the match state when it changed as an Arc in the match's
+// StateCell, readers clone the latest Arc and never touch the matches lock: live games
+// listing, multiview boards and spotlight read published states. State is published after
+// the step which changed it, so it's at most one step behind the match. Players' own
+// streams still read their games under the lock
+//
+
+// Latest value, swapped as a whole. Loading clones the Arc under a lock held only for that
+pub struct StateCell<T>(RwLock<Arc<T>>);
+
+// Published state of a match, as of spectators
+pub struct MatchView {
+    pub players: [UserId; 2],
+    pub scores: [usize; 2],
+    pub game_over: bool,
+    // Seconds since unix epoch
+    pub started: u64,
+    // Garbage rules of the match
+    pub rules: String,
+    // State hash, unchanged states are not published again
+    pub hash: u64,
+    pub state: TetrisPairState,
+}
+
+// Cells of matches in memory
+#[derive(Clone, Default)]
+pub struct PublishedMatches(Arc<RwLock<HashMap<MatchId, Arc<StateCell<MatchView>>>>>);
+
+impl<T> StateCell<T> {
+    pub fn new(value: T) -> StateCell<T> {
+        StateCell(RwLock::new(Arc::new(value)))
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+impl MatchView {
+    pub fn of(players: [UserId; 2], field: &TetrisPair) -> MatchView {
+        let (score_a, score_b) = field.get_scores();
+        MatchView {
+            players,
+            scores: [score_a, score_b],
+            game_over: field.is_game_over(),
+            started: field.get_started(),
+            rules: field.get_rules().garbage.name.clone(),
+            hash: field.get_state_hash(),
+            state: field.get_player_game_state(PlayerSide::A),
+        }
+    }
+}
+
+impl PublishedMatches {
+    pub fn new() -> PublishedMatches {
+        PublishedMatches::default()
+    }
+
+    // Publish state of the match unless it's published already
+    pub fn publish(&self, match_id: MatchId, players: [UserId; 2], field: &TetrisPair) {
+        let cell = self.0.read().unwrap().get(&match_id).cloned();
+        match cell {
+            Some(cell) => {
+                if cell.load().hash != field.get_state_hash() {
+                    cell.store(MatchView::of(players, field));
+                }
+            }
+            None => {
+                let cell = Arc::new(StateCell::new(MatchView::of(players, field)));
+                self.0.write().unwrap().insert(match_id, cell);
+            }
+        }
+    }
+
+    pub fn remove(&self, match_id: MatchId) {
+        self.0.write().unwrap().remove(&match_id);
+    }
+
+    pub fn get(&self, match_id: MatchId) -> Option<Arc<MatchView>> {
+        Some(self.0.read().unwrap().get(&match_id)?.load())
+    }
+
+    pub fn all(&self) -> Vec<(MatchId, Arc<MatchView>)> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(match_id, cell)| (*match_id, cell.load()))
+            .collect()
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashSet;
+use std::ops::Bound;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    get, http::CookieJar, post, response::Redirect, routes, serde::json::Json, FromForm, Route,
+    State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admission::Admitted,
+    daily_quotas::{DailyQuota, QuotaKind},
+    error::Error,
+    friends::Friends,
+    ids::UserId,
+    moderation::Moderation,
+    pagination::{self, Page, SortOrder},
+    quotas::Quotas,
+    roles::{Moderator, Viewer},
+    storage::{self, Database},
+    tetris::{CellType, Rotation, Tetromino, TetrominoType},
+    TetrisMatches,
+};
+
+//
+// User-created puzzles: initial board, queue of pieces and objective to reach.
+// Puzzles are validated on submission, titles are checked by moderation wordlists. Puzzles
+// appear in public listing only after approval by admin.
+//
+
+const PUZZLES_SEGMENT: &str = "puzzles";
+const BY_CREATED_INDEX: &str = "puzzles_by_created";
+const BY_AUTHOR_INDEX: &str = "puzzles_by_author";
+
+const MIN_COLS: usize = 4;
+const MAX_COLS: usize = 20;
+const MIN_ROWS: usize = 4;
+const MAX_ROWS: usize = 40;
+const MAX_PIECES: usize = 100;
+const MAX_TITLE_LEN: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Objective {
+    // Clear given number of lines
+    ClearLines(usize),
+    // Remove all filled cells from the board
+    ClearBoard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PuzzleStatus {
+    // Waiting for moderation
+    Pending,
+    // Visible in public listing
+    Approved,
+    // Rejected by moderator
+    Rejected,
+}
+
+// Puzzle as submitted by author
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PuzzleDefinition {
+    pub title: String,
+    // Initial board, rows from top to bottom
+    pub board: Vec<Vec<CellType>>,
+    // Pieces in order they are given to player
+    pub pieces: Vec<TetrominoType>,
+    pub objective: Objective,
+}
+
+// Puzzle as stored in database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Puzzle {
+    pub author: UserId,
+    pub created: u64,
+    pub status: PuzzleStatus,
+    pub definition: PuzzleDefinition,
+}
+
+// Puzzle with it's database id for listings
+#[derive(Serialize)]
+pub struct PuzzleEntry {
+    pub id: String,
+    #[serde(flatten)]
+    pub puzzle: Puzzle,
+}
+
+impl PuzzleDefinition {
+    // Check that board is legal and puzzle is not obviously unsolvable
+    pub fn validate(&self) -> Result<(), String> {
+        if self.title.trim().is_empty() || self.title.len() > MAX_TITLE_LEN {
+            return Err(format!(
+                "Title must be non-empty and not longer than {} bytes",
+                MAX_TITLE_LEN
+            ));
+        }
+        // Check board dimensions
+        let rows = self.board.len();
+        if !(MIN_ROWS..=MAX_ROWS).contains(&rows) {
+            return Err(format!(
+                "Board must have from {} to {} rows",
+                MIN_ROWS, MAX_ROWS
+            ));
+        }
+        let cols = self.board[0].len();
+        if !(MIN_COLS..=MAX_COLS).contains(&cols) {
+            return Err(format!(
+                "Board must have from {} to {} columns",
+                MIN_COLS, MAX_COLS
+            ));
+        }
+        if self.board.iter().any(|row| row.len() != cols) {
+            return Err("All board rows must have the same length".to_string());
+        }
+        // Check board legality: no blasted cells and no full lines, they would be removed by game
+        if self
+            .board
+            .iter()
+            .flatten()
+            .any(|cell| *cell == CellType::Blasted)
+        {
+            return Err("Board can't contain blasted cells".to_string());
+        }
+        if self
+            .board
+            .iter()
+            .any(|row| row.iter().all(|cell| *cell != CellType::Empty))
+        {
+            return Err("Board can't contain full lines".to_string());
+        }
+        // Check that first piece can be placed
+        let Some(first) = self.pieces.first() else {
+            return Err("Piece queue can't be empty".to_string());
+        };
+        if self.pieces.len() > MAX_PIECES {
+            return Err(format!("Piece queue can't exceed {} pieces", MAX_PIECES));
+        }
+        if Tetromino::new(*first, Rotation::R0, cols as isize / 2 - 2, 0).intersects(&self.board) {
+            return Err("Spawn area of the board must be free".to_string());
+        }
+        // Check that pieces have enough cells to fulfill objective
+        let available_cells = self.pieces.len() * 4;
+        let empty_cells =
+            |row: &Vec<CellType>| row.iter().filter(|c| **c == CellType::Empty).count();
+        let required_cells: usize = match self.objective {
+            Objective::ClearLines(lines) => {
+                if lines == 0 || lines > rows {
+                    return Err(format!("Lines to clear must be from 1 to {}", rows));
+                }
+                // Best case: the most filled lines are completed
+                let mut empty = self.board.iter().map(empty_cells).collect::<Vec<_>>();
+                empty.sort_unstable();
+                empty.iter().take(lines).sum()
+            }
+            Objective::ClearBoard => {
+                // Every line which has filled cells has to be completed
+                self.board
+                    .iter()
+                    .filter(|row| row.iter().any(|cell| *cell != CellType::Empty))
+                    .map(empty_cells)
+                    .sum()
+            }
+        };
+        if required_cells > available_cells {
+            return Err(format!(
+                "Objective requires at least {} cells, pieces provide only {}",
+                required_cells, available_cells
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(FromForm)]
+pub struct PuzzlesQuery<'r> {
+    author: Option<UserId>,
+    // Puzzles of friends of the signed in user, see friends
+    friends: Option<bool>,
+    // Creation time order, newest first by default
+    order: Option<SortOrder>,
+    cursor: Option<&'r str>,
+    limit: Option<usize>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, PUZZLES_SEGMENT)?;
+    if storage::ensure_index::<u64, PersyId>(persy, BY_CREATED_INDEX, ValueMode::Cluster)? {
+        // Index puzzles created before index was introduced
+        let mut tx = persy.begin()?;
+        for (id, puzzle) in storage::scan::<Puzzle>(persy, PUZZLES_SEGMENT)? {
+            tx.put(BY_CREATED_INDEX, puzzle.created, id)?;
+        }
+        tx.prepare()?.commit()?;
+    }
+    if storage::ensure_index::<u32, PersyId>(persy, BY_AUTHOR_INDEX, ValueMode::Cluster)? {
+        let mut tx = persy.begin()?;
+        for (id, puzzle) in storage::scan::<Puzzle>(persy, PUZZLES_SEGMENT)? {
+            tx.put(BY_AUTHOR_INDEX, puzzle.author.0, id)?;
+        }
+        tx.prepare()?.commit()?;
+    }
+    Ok(())
+}
+
+// Number of puzzles submitted by the user, of any status
+pub fn count_of_author(persy: &Persy, user: UserId) -> Result<usize, Error> {
+    Ok(persy.get::<u32, PersyId>(BY_AUTHOR_INDEX, &user.0)?.count())
+}
+
+// Page of approved puzzles, of the authors only when given
+fn page_puzzles(
+    persy: &Persy,
+    query: &PuzzlesQuery,
+    authors: Option<&HashSet<UserId>>,
+) -> Result<Page<PuzzleEntry>, Error> {
+    pagination::page_by_index(
+        persy,
+        BY_CREATED_INDEX,
+        PUZZLES_SEGMENT,
+        query.order.unwrap_or(SortOrder::Desc),
+        (Bound::<u64>::Unbounded, Bound::Unbounded),
+        query.cursor,
+        pagination::limit(query.limit),
+        |puzzle: &Puzzle| {
+            puzzle.status == PuzzleStatus::Approved
+                && query.author.is_none_or(|author| puzzle.author == author)
+                && authors.is_none_or(|authors| authors.contains(&puzzle.author))
+        },
+        |id, puzzle| PuzzleEntry {
+            id: id.to_string(),
+            puzzle,
+        },
+    )
+}
+
+fn list_puzzles(persy: &Persy, status: PuzzleStatus) -> Result<Vec<PuzzleEntry>, Error> {
+    let mut puzzles = storage::scan::<Puzzle>(persy, PUZZLES_SEGMENT)?
+        .into_iter()
+        .filter(|(_, puzzle)| puzzle.status == status)
+        .map(|(id, puzzle)| PuzzleEntry {
+            id: id.to_string(),
+            puzzle,
+        })
+        .collect::<Vec<_>>();
+    puzzles.sort_by_key(|entry| entry.puzzle.created);
+    Ok(puzzles)
+}
+
+fn set_status(persy: &Persy, id: &str, status: PuzzleStatus) -> Result<(), Error> {
+    let id = storage::parse_id(id)?;
+    let mut puzzle = storage::read::<Puzzle>(persy, PUZZLES_SEGMENT, &id)?
+        .ok_or_else(|| Error::NotFoundError("Puzzle not found".to_string()))?;
+    puzzle.status = status;
+    storage::update(persy, PUZZLES_SEGMENT, &id, &puzzle)
+}
+
+// Submit new puzzle, it's stored as pending until approved by admin. Submissions count to
+// the daily quota
+#[post("/puzzles", data = "<definition>")]
+#[allow(clippy::too_many_arguments)]
+fn submit_puzzle(
+    _admitted: Admitted,
+    daily_quota: DailyQuota,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    moderation: &State<Moderation>,
+    quotas: &State<Quotas>,
+    definition: Json<PuzzleDefinition>,
+) -> Result<Json<PuzzleEntry>, Error> {
+    let persy = &*db.read();
+    let definition = definition.into_inner();
+    definition.validate().map_err(Error::InvalidInputError)?;
+    moderation.check(&definition.title)?;
+    let author = crate::user_id(cookie_jar, matches);
+    quotas.check_puzzles(persy, author)?;
+    let puzzle = Puzzle {
+        author,
+        created: crate::unix_time(),
+        status: PuzzleStatus::Pending,
+        definition,
+    };
+    // Quota is counted in the same transaction, failed submissions don't use it up
+    let id = daily_quota.consume_with(persy, author, QuotaKind::Puzzles, |tx| {
+        let id = storage::insert_in_tx(tx, PUZZLES_SEGMENT, &puzzle)?;
+        tx.put(BY_CREATED_INDEX, puzzle.created, id)?;
+        tx.put(BY_AUTHOR_INDEX, puzzle.author.0, id)?;
+        Ok(id)
+    })?;
+    Ok(Json(PuzzleEntry {
+        id: id.to_string(),
+        puzzle,
+    }))
+}
+
+// Public listing of approved puzzles
+#[get("/puzzles?<query..>")]
+fn puzzles(
+    cookie_jar: &CookieJar,
+    db: &State<Database>,
+    friends: &State<Friends>,
+    query: PuzzlesQuery,
+) -> Result<Json<Page<PuzzleEntry>>, Error> {
+    let persy = &*db.read();
+    let friends = friends.filter(cookie_jar, query.friends);
+    Ok(Json(page_puzzles(persy, &query, friends.as_ref())?))
+}
+
+// Get single puzzle. Not approved puzzles are visible only to their author
+#[get("/puzzles/<id>")]
+fn puzzle(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    id: &str,
+) -> Result<Json<PuzzleEntry>, Error> {
+    let persy = &*db.read();
+    let user_id = crate::user_id(cookie_jar, matches);
+    let persy_id = storage::parse_id(id)?;
+    storage::read::<Puzzle>(persy, PUZZLES_SEGMENT, &persy_id)?
+        .filter(|puzzle| puzzle.status == PuzzleStatus::Approved || puzzle.author == user_id)
+        .map(|puzzle| {
+            Json(PuzzleEntry {
+                id: id.to_string(),
+                puzzle,
+            })
+        })
+        .ok_or_else(|| Error::NotFoundError("Puzzle not found".to_string()))
+}
+
+// Moderation page with puzzles waiting for approval
+#[get("/admin/puzzles")]
+fn admin_puzzles(_admin: Viewer, db: &State<Database>) -> Result<Template, Error> {
+    let persy = &*db.read();
+    let puzzles = list_puzzles(persy, PuzzleStatus::Pending)?;
+    Ok(Template::render("admin/puzzles", context! { puzzles }))
+}
+
+#[post("/admin/puzzles/<id>/approve")]
+fn approve_puzzle(_admin: Moderator, db: &State<Database>, id: &str) -> Result<Redirect, Error> {
+    let persy = &*db.read();
+    set_status(persy, id, PuzzleStatus::Approved)?;
+    Ok(Redirect::to("/admin/puzzles"))
+}
+
+#[post("/admin/puzzles/<id>/reject")]
+fn reject_puzzle(_admin: Moderator, db: &State<Database>, id: &str) -> Result<Redirect, Error> {
+    let persy = &*db.read();
+    set_status(persy, id, PuzzleStatus::Rejected)?;
+    Ok(Redirect::to("/admin/puzzles"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        submit_puzzle,
+        puzzles,
+        puzzle,
+        admin_puzzles,
+        approve_puzzle,
+        reject_puzzle
+    ]
+}
@@ -0,0 +1,270 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use persy::Persy;
+use rocket::{
+    get, routes,
+    serde::json::{serde_json::Value, Json},
+    tokio::{
+        self,
+        time::{self, Duration},
+    },
+    Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    roles::Viewer,
+    storage::{self, Database},
+    tetris::{Action, Replay},
+};
+
+//
+// Quarantine of games whose logic panicked. Steps and inputs of games run under
+// catch_unwind within the locks of games, so a panic in game logic doesn't poison the locks
+// and doesn't take the whole server down: the offending game is removed from memory, it's
+// players get "quarantined" event and their game streams end. Replays of the game as of the
+// panic, which reproduce it up to the failing step, and the inputs of that step are stored
+// in "quarantine" segment for investigation and listed in /admin/quarantine. Games of other
+// types than tetris are kept with their state as of the panic and the failing move instead
+//
+
+const QUARANTINE_SEGMENT: &str = "quarantine";
+
+// Interval of writes of quarantined games
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+// Game type of quarantined game, tetris modes are named as game modes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuarantinedMode {
+    Versus,
+    Sprint,
+    ConnectFour,
+    Game2048,
+    Minesweeper,
+    Tutorial,
+    // Sandbox game of a bot, it has no players
+    BotSandbox,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedGame {
+    pub mode: QuarantinedMode,
+    // Arena of the game, None for games of the server
+    pub arena: Option<String>,
+    pub players: Vec<UserId>,
+    // Start time and time of the panic, seconds since unix epoch
+    pub started: u64,
+    pub quarantined: u64,
+    // Replays of the players' games, empty when they couldn't be taken
+    pub replays: Vec<Replay>,
+    // Inputs of the failing step
+    pub trigger: Vec<(UserId, Action)>,
+    // State and failing move of games without replays, None when state couldn't be taken
+    #[serde(default)]
+    pub state: Option<Value>,
+    #[serde(default)]
+    pub trigger_move: Option<String>,
+    pub panic: String,
+}
+
+#[derive(Serialize)]
+pub struct QuarantineItem {
+    pub id: String,
+    #[serde(flatten)]
+    pub game: QuarantinedGame,
+}
+
+#[derive(Default)]
+struct Pending {
+    // Games not written yet
+    games: Vec<QuarantinedGame>,
+    // Players not notified yet
+    affected: HashSet<UserId>,
+}
+
+#[derive(Clone, Default)]
+pub struct Quarantine {
+    pending: Arc<Mutex<Pending>>,
+    arena: Option<String>,
+}
+
+thread_local! {
+    // Location of the last panic of the thread, set by the panic hook
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Remember locations of panics for the quarantine reports, panics are printed as before
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|location| location.to_string());
+        PANIC_LOCATION.with(|last| *last.borrow_mut() = location);
+        default_hook(info);
+    }));
+}
+
+// Run game logic, returns panic message with it's location when it panics
+pub fn guard<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Unknown panic".to_string());
+        match PANIC_LOCATION.with(|last| last.borrow_mut().take()) {
+            Some(location) => format!("{} at {}", message, location),
+            None => message,
+        }
+    })
+}
+
+// Inputs added right before the failing step of the players' replays
+pub fn step_inputs(players: &[UserId], replays: &[Replay]) -> Vec<(UserId, Action)> {
+    players
+        .iter()
+        .zip(replays)
+        .flat_map(|(player, replay)| {
+            replay
+                .inputs
+                .iter()
+                .filter(|(tick, _)| tick + 1 >= replay.ticks)
+                .map(|(_, action)| (*player, *action))
+        })
+        .collect()
+}
+
+impl QuarantinedGame {
+    // Game without replays, state is taken under guard as the game may be broken
+    pub fn without_replays(
+        mode: QuarantinedMode,
+        players: Vec<UserId>,
+        started: u64,
+        state: impl FnOnce() -> Option<Value>,
+        trigger_move: Option<String>,
+        panic: String,
+    ) -> QuarantinedGame {
+        QuarantinedGame {
+            mode,
+            arena: None,
+            players,
+            started,
+            quarantined: crate::unix_time(),
+            replays: Vec::new(),
+            trigger: Vec::new(),
+            state: guard(state).ok().flatten(),
+            trigger_move,
+            panic,
+        }
+    }
+}
+
+impl Quarantine {
+    pub fn new() -> Quarantine {
+        Quarantine::default()
+    }
+
+    // Quarantine sharing storage of this one, labelling games with the arena
+    pub fn for_arena(&self, name: &str) -> Quarantine {
+        Quarantine {
+            pending: self.pending.clone(),
+            arena: Some(name.to_string()),
+        }
+    }
+
+    // Keep the game removed after panic, it's players get "quarantined" event
+    pub fn add(&self, game: QuarantinedGame) {
+        self.keep(game, true);
+    }
+
+    // Keep the game removed after panic in a request, which tells it's player instead of
+    // a game stream
+    pub fn add_answered(&self, game: QuarantinedGame) {
+        self.keep(game, false);
+    }
+
+    fn keep(&self, mut game: QuarantinedGame, notify: bool) {
+        game.arena = self.arena.clone();
+        println!(
+            "Game of {:?} quarantined after panic: {}",
+            game.players, game.panic
+        );
+        let mut pending = self.pending.lock().unwrap();
+        if notify {
+            pending.affected.extend(game.players.iter().copied());
+        }
+        pending.games.push(game);
+    }
+
+    // Whether the user's game was quarantined since the last call
+    pub fn take_affected(&self, user: UserId) -> bool {
+        self.pending.lock().unwrap().affected.remove(&user)
+    }
+
+    fn take_games(&self) -> Vec<QuarantinedGame> {
+        std::mem::take(&mut self.pending.lock().unwrap().games)
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, QUARANTINE_SEGMENT)
+}
+
+// Write quarantined games periodically
+pub async fn persist_job(db: Database, quarantine: Quarantine) {
+    let mut interval = time::interval(PERSIST_INTERVAL);
+    loop {
+        interval.tick().await;
+        persist(db.clone(), &quarantine).await;
+    }
+}
+
+// Write games quarantined since the last write, also before shutdown
+pub async fn persist(db: Database, quarantine: &Quarantine) {
+    let games = quarantine.take_games();
+    if games.is_empty() {
+        return;
+    }
+    let written = tokio::task::spawn_blocking(move || -> Result<(), Error> {
+        let persy = db.read();
+        for game in &games {
+            storage::insert(&persy, QUARANTINE_SEGMENT, game)?;
+        }
+        Ok(())
+    })
+    .await;
+    match written {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => println!("Failed to store quarantined games: {}", e),
+        Err(e) => println!("Quarantine task failed: {}", e),
+    }
+}
+
+pub fn list(persy: &Persy) -> Result<Vec<QuarantineItem>, Error> {
+    let mut games = storage::scan::<QuarantinedGame>(persy, QUARANTINE_SEGMENT)?
+        .into_iter()
+        .map(|(id, game)| QuarantineItem {
+            id: id.to_string(),
+            game,
+        })
+        .collect::<Vec<_>>();
+    games.sort_by_key(|item| std::cmp::Reverse(item.game.quarantined));
+    Ok(games)
+}
+
+// Quarantined games, the latest first
+#[get("/admin/quarantine")]
+fn admin_quarantine(
+    _admin: Viewer,
+    db: &State<Database>,
+) -> Result<Json<Vec<QuarantineItem>>, Error> {
+    Ok(Json(list(&db.read())?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_quarantine]
+}
@@ -0,0 +1,144 @@
+use persy::Persy;
+use rocket::{get, http::CookieJar, routes, serde::json::Json, Config, Route, State};
+use serde::Serialize;
+
+use crate::{
+    bug_reports, error::Error, game_history, ids::UserId, leaderboard, puzzles, recording,
+    storage::Database, TetrisMatches,
+};
+
+//
+// Storage quotas of each user. Replays over quota_replays are pruned oldest first after
+// new games are written, their leaderboard entries stay without replay. Archived games over
+// quota_archived_games are dropped oldest first when a game is archived. Puzzles over
+// quota_puzzles and bug reports over quota_bug_reports are refused on submission. Users
+// see their usage in /account/usage
+//
+
+// Quotas unless configured
+const DEFAULT_REPLAYS: usize = 200;
+const DEFAULT_ARCHIVED_GAMES: usize = 10;
+const DEFAULT_PUZZLES: usize = 50;
+const DEFAULT_BUG_REPORTS: usize = 20;
+// Pruning frees this share of the quota, so it doesn't run after every game
+const PRUNE_PERCENT: usize = 10;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Quotas {
+    pub replays: usize,
+    pub archived_games: usize,
+    pub puzzles: usize,
+    pub bug_reports: usize,
+}
+
+#[derive(Serialize)]
+pub struct QuotaUsage {
+    pub used: usize,
+    pub quota: usize,
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub replays: QuotaUsage,
+    pub archived_games: QuotaUsage,
+    pub puzzles: QuotaUsage,
+    pub bug_reports: QuotaUsage,
+}
+
+impl Quotas {
+    pub fn from_config() -> Quotas {
+        let figment = Config::figment();
+        let quota = |key: &str, default: usize| {
+            figment
+                .extract_inner::<usize>(key)
+                .map_or(default, |quota| quota.max(1))
+        };
+        Quotas {
+            replays: quota("quota_replays", DEFAULT_REPLAYS),
+            archived_games: quota("quota_archived_games", DEFAULT_ARCHIVED_GAMES),
+            puzzles: quota("quota_puzzles", DEFAULT_PUZZLES),
+            bug_reports: quota("quota_bug_reports", DEFAULT_BUG_REPORTS),
+        }
+    }
+
+    pub fn usage(&self, persy: &Persy, user: UserId) -> Result<Usage, Error> {
+        let replays = leaderboard::entries_of_user(persy, user)?
+            .iter()
+            .filter(|(_, entry)| entry.replay.is_some())
+            .count();
+        Ok(Usage {
+            replays: QuotaUsage {
+                used: replays,
+                quota: self.replays,
+            },
+            archived_games: QuotaUsage {
+                used: game_history::games_of_user(persy, user)?.len(),
+                quota: self.archived_games,
+            },
+            puzzles: QuotaUsage {
+                used: puzzles::count_of_author(persy, user)?,
+                quota: self.puzzles,
+            },
+            bug_reports: QuotaUsage {
+                used: bug_reports::count_of_user(persy, user)?,
+                quota: self.bug_reports,
+            },
+        })
+    }
+
+    // Prune the oldest replays of the user over quota, returns number of pruned replays
+    pub fn enforce(&self, persy: &Persy, user: UserId) -> Result<usize, Error> {
+        let replays = leaderboard::entries_of_user(persy, user)?
+            .iter()
+            .filter(|(_, entry)| entry.replay.is_some())
+            .count();
+        if replays <= self.replays {
+            return Ok(0);
+        }
+        let keep = self.replays - self.replays * PRUNE_PERCENT / 100;
+        let pruned = recording::prune_replays(persy, user, keep)?;
+        println!(
+            "Replay quota of user {} exceeded, {} oldest replays pruned",
+            user, pruned
+        );
+        Ok(pruned)
+    }
+
+    // Refuse new puzzle of the author over quota
+    pub fn check_puzzles(&self, persy: &Persy, user: UserId) -> Result<(), Error> {
+        if puzzles::count_of_author(persy, user)? >= self.puzzles {
+            return Err(Error::InvalidInputError(format!(
+                "Puzzle quota of {} puzzles reached",
+                self.puzzles
+            )));
+        }
+        Ok(())
+    }
+
+    // Refuse new bug report of the user over quota
+    pub fn check_bug_reports(&self, persy: &Persy, user: UserId) -> Result<(), Error> {
+        if bug_reports::count_of_user(persy, user)? >= self.bug_reports {
+            return Err(Error::InvalidInputError(format!(
+                "Bug report quota of {} reports reached",
+                self.bug_reports
+            )));
+        }
+        Ok(())
+    }
+}
+
+// User's stored data and quotas
+#[get("/account/usage")]
+fn usage(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    quotas: &State<Quotas>,
+) -> Result<Json<Usage>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(quotas.usage(&db.read(), user_id)?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![usage]
+}
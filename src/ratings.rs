@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{get, http::CookieJar, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, ids::UserId, storage, TetrisMatches};
+
+//
+// Versus ratings, Elo rating of each player updated after every finished match. Ratings
+// are kept in memory for handicaps of new matches, new ratings are computed when match
+// results are taken and stored with the match record in the same transaction
+//
+
+const RATINGS_SEGMENT: &str = "ratings";
+const BY_USER_INDEX: &str = "ratings_by_user";
+
+// Rating of players without finished matches
+pub const DEFAULT_RATING: i32 = 1500;
+// Maximal rating change per match
+const K_FACTOR: f64 = 32.;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Rating {
+    pub user: UserId,
+    pub rating: i32,
+    pub matches: u64,
+}
+
+// Result of a match for rating update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOutcome {
+    // The first player won
+    Won,
+    Lost,
+    Draw,
+}
+
+#[derive(Clone, Default)]
+pub struct Ratings(Arc<RwLock<HashMap<UserId, Rating>>>);
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, RATINGS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+// Store new rating of the player as part of match record transaction
+pub fn store_in_tx(tx: &mut Transaction, rating: &Rating) -> Result<(), Error> {
+    match tx.one::<u32, PersyId>(BY_USER_INDEX, &rating.user.0)? {
+        Some(id) => storage::update_in_tx(tx, RATINGS_SEGMENT, &id, rating),
+        None => {
+            let id = storage::insert_in_tx(tx, RATINGS_SEGMENT, rating)?;
+            tx.put(BY_USER_INDEX, rating.user.0, id)?;
+            Ok(())
+        }
+    }
+}
+
+impl Ratings {
+    pub fn load(persy: &Persy) -> Result<Ratings, Error> {
+        let ratings = storage::scan::<Rating>(persy, RATINGS_SEGMENT)?
+            .into_iter()
+            .map(|(_, rating)| (rating.user, rating))
+            .collect::<HashMap<_, _>>();
+        println!("Ratings: {} players", ratings.len());
+        Ok(Ratings(Arc::new(RwLock::new(ratings))))
+    }
+
+    pub fn get(&self, user: UserId) -> Rating {
+        self.0
+            .read()
+            .unwrap()
+            .get(&user)
+            .copied()
+            .unwrap_or(Rating {
+                user,
+                rating: DEFAULT_RATING,
+                matches: 0,
+            })
+    }
+
+    // Update ratings of both players by the outcome for the first one, returns new ratings
+    pub fn record_match(&self, players: [UserId; 2], outcome: MatchOutcome) -> [Rating; 2] {
+        let [a, b] = players.map(|user| self.get(user));
+        let expected = 1. / (1. + 10f64.powf((b.rating - a.rating) as f64 / 400.));
+        let score = match outcome {
+            MatchOutcome::Won => 1.,
+            MatchOutcome::Lost => 0.,
+            MatchOutcome::Draw => 0.5,
+        };
+        let change = (K_FACTOR * (score - expected)).round() as i32;
+        let updated = [
+            Rating {
+                rating: a.rating + change,
+                matches: a.matches + 1,
+                ..a
+            },
+            Rating {
+                rating: b.rating - change,
+                matches: b.matches + 1,
+                ..b
+            },
+        ];
+        let mut ratings = self.0.write().unwrap();
+        for rating in updated {
+            ratings.insert(rating.user, rating);
+        }
+        updated
+    }
+}
+
+// Versus rating of the user
+#[get("/rating")]
+fn rating(cookie_jar: &CookieJar, matches: &State<TetrisMatches>) -> Json<Rating> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Json(matches.ratings.get(user_id))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![rating]
+}
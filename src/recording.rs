@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+
+use persy::{Persy, PersyId, Transaction, ValueMode};
+use rocket::{
+    delete, form::Form, get, http::CookieJar, post, routes, serde::json::Json, FromForm, Route,
+    State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::Caches,
+    error::Error,
+    game_history,
+    ids::UserId,
+    leaderboard::{self, LeaderboardEntry, Verification},
+    match_history, replays,
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Recording of replays and inputs, users may opt out of it in account settings. Games of
+// opted out users are stored without replay: leaderboard entries are marked unrecorded,
+// as their score can't be verified, and finished sprints are not kept in game history.
+// Inputs of live versus matches are still journaled for crash recovery, journal doesn't
+// keep them after the match ends. Already stored recordings can be deleted
+//
+
+const SETTINGS_SEGMENT: &str = "recording_settings";
+const BY_USER_INDEX: &str = "recording_settings_by_user";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingSetting {
+    pub user: UserId,
+    pub enabled: bool,
+    // Time of the last change, seconds since unix epoch
+    pub changed: u64,
+}
+
+#[derive(FromForm)]
+pub struct RecordingForm {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+pub struct DeletedRecordings {
+    pub replays: usize,
+    pub archived_games: usize,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, SETTINGS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+fn read_setting(persy: &Persy, user: UserId) -> Result<Option<(PersyId, RecordingSetting)>, Error> {
+    let Some(id) = persy.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? else {
+        return Ok(None);
+    };
+    Ok(storage::read(persy, SETTINGS_SEGMENT, &id)?.map(|setting| (id, setting)))
+}
+
+// Recording is enabled unless the user opted out
+pub fn is_enabled(persy: &Persy, user: UserId) -> Result<bool, Error> {
+    Ok(read_setting(persy, user)?.is_none_or(|(_, setting)| setting.enabled))
+}
+
+// Same within a transaction, for writes of game results
+pub fn is_enabled_in_tx(tx: &mut Transaction, user: UserId) -> Result<bool, Error> {
+    let Some(id) = tx.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? else {
+        return Ok(true);
+    };
+    Ok(
+        storage::read_in_tx::<RecordingSetting>(tx, SETTINGS_SEGMENT, &id)?
+            .is_none_or(|setting| setting.enabled),
+    )
+}
+
+fn store_setting(persy: &Persy, setting: &RecordingSetting) -> Result<(), Error> {
+    match read_setting(persy, setting.user)? {
+        Some((id, _)) => storage::update(persy, SETTINGS_SEGMENT, &id, setting),
+        None => {
+            storage::insert_with(persy, SETTINGS_SEGMENT, setting, |tx, id| {
+                tx.put(BY_USER_INDEX, setting.user.0, *id)?;
+                Ok(())
+            })?;
+            Ok(())
+        }
+    }
+}
+
+// Delete replays of the leaderboard entries, returns ids of deleted replays. Entries stay on
+// the leaderboard, unverified ones become unrecorded
+fn delete_replays_in_tx(
+    tx: &mut Transaction,
+    entries: Vec<(PersyId, LeaderboardEntry)>,
+) -> Result<HashSet<String>, Error> {
+    let mut deleted = HashSet::new();
+    for (id, mut entry) in entries {
+        let Some(replay) = entry.replay.take() else {
+            continue;
+        };
+        replays::delete_in_tx(tx, &storage::parse_id(&replay)?)?;
+        if entry.verification == Verification::Unverified {
+            entry.verification = Verification::Unrecorded;
+        }
+        leaderboard::update_in_tx(tx, &id, &entry)?;
+        deleted.insert(replay);
+    }
+    Ok(deleted)
+}
+
+// Delete replays of user's leaderboard entries and user's archived games
+pub fn delete_recordings(persy: &Persy, user: UserId) -> Result<DeletedRecordings, Error> {
+    let mut tx = persy.begin()?;
+    let deleted = delete_replays_in_tx(&mut tx, leaderboard::entries_of_user(persy, user)?)?;
+    let archived_games = game_history::delete_in_tx(&mut tx, persy, user)?;
+    match_history::forget_replays_in_tx(&mut tx, persy, &deleted)?;
+    tx.prepare()?.commit()?;
+    Ok(DeletedRecordings {
+        replays: deleted.len(),
+        archived_games,
+    })
+}
+
+// Delete replays of user's entries except the latest keep ones, see quotas. Returns number
+// of deleted replays
+pub fn prune_replays(persy: &Persy, user: UserId, keep: usize) -> Result<usize, Error> {
+    let mut entries = leaderboard::entries_of_user(persy, user)?
+        .into_iter()
+        .filter(|(_, entry)| entry.replay.is_some())
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(id, entry)| std::cmp::Reverse((entry.finished, *id)));
+    let oldest = entries.split_off(keep.min(entries.len()));
+    let mut tx = persy.begin()?;
+    let deleted = delete_replays_in_tx(&mut tx, oldest)?;
+    match_history::forget_replays_in_tx(&mut tx, persy, &deleted)?;
+    tx.prepare()?.commit()?;
+    Ok(deleted.len())
+}
+
+// Recording setting of the user
+#[get("/account/recording")]
+fn recording(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<RecordingSetting>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(match read_setting(&db.read(), user_id)? {
+        Some((_, setting)) => setting,
+        None => RecordingSetting {
+            user: user_id,
+            enabled: true,
+            changed: 0,
+        },
+    }))
+}
+
+// Opt in or out of recording, applies to games finished from now on
+#[post("/account/recording", data = "<form>")]
+fn set_recording(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    form: Form<RecordingForm>,
+) -> Result<Json<RecordingSetting>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let setting = RecordingSetting {
+        user: user_id,
+        enabled: form.enabled,
+        changed: crate::unix_time(),
+    };
+    store_setting(&db.read(), &setting)?;
+    Ok(Json(setting))
+}
+
+// Delete all stored recordings of the user
+#[delete("/account/recordings")]
+fn delete_user_recordings(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    caches: &State<Caches>,
+) -> Result<Json<DeletedRecordings>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let deleted = delete_recordings(&db.read(), user_id)?;
+    caches.leaderboard.invalidate();
+    println!(
+        "Recordings of user {} deleted: {} replays, {} archived games",
+        user_id, deleted.replays, deleted.archived_games
+    );
+    Ok(Json(deleted))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![recording, set_recording, delete_user_recordings]
+}
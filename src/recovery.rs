@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+
+use persy::{Persy, PersyId, Transaction};
+use rocket::tokio::{
+    self,
+    time::{self, Duration},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    handicap::Handicap,
+    ids::UserId,
+    storage::{self, Database},
+    tetris::{Action, Replay},
+    TetrisMatches,
+};
+
+//
+// Recovery of games interrupted by server crash or restart. Games are deterministic,
+// so running match is fully described by replays of it's players. Changes of running matches
+// are appended to the journal as events: match start with it's replays so far, new inputs
+// and match end. Snapshot event with replays of all running matches is appended periodically
+// and events before it are removed. On startup state is rebuilt from the last snapshot by
+// replaying the events after it, restored matches continue from the rebuilt replays
+//
+
+const JOURNAL_SEGMENT: &str = "journal";
+// Segments of snapshots and input log written by previous versions, moved to the journal
+const LEGACY_SNAPSHOTS_SEGMENT: &str = "live_snapshots";
+const LEGACY_INPUT_LOG_SEGMENT: &str = "input_log";
+
+// Interval of journal writes
+const LOG_INTERVAL: Duration = Duration::from_millis(100);
+// Snapshot is appended instead of events every this many intervals
+const SNAPSHOT_EVERY: u32 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchSnapshot {
+    pub players: [UserId; 2],
+    // Start time of the match, seconds since unix epoch. Together with players identifies
+    // the match across restarts
+    pub started: u64,
+    pub garbage_rules: String,
+    // Handicaps of players, their starting garbage and gravity are in replays
+    #[serde(default)]
+    pub handicaps: [Handicap; 2],
+    pub replays: [Replay; 2],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputLogEntry {
+    pub players: [UserId; 2],
+    pub started: u64,
+    pub side: usize,
+    // Position of the first input in player's inputs
+    pub from: usize,
+    // Steps performed by the player when inputs were logged
+    pub ticks: u64,
+    pub inputs: Vec<(u64, Action)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JournalEvent {
+    // Match not in the journal yet, with inputs made before it was journaled
+    MatchStarted(Box<MatchSnapshot>),
+    Inputs(InputLogEntry),
+    // Match is over, it isn't restored anymore
+    MatchEnded { players: [UserId; 2], started: u64 },
+    // All running matches, events before it are not needed for rebuilding
+    Snapshot(Vec<MatchSnapshot>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JournalRecord {
+    // Order of events, records are scanned in no particular order
+    seq: u64,
+    event: JournalEvent,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, JOURNAL_SEGMENT)?;
+    migrate_legacy(persy)
+}
+
+// Move snapshots and input log of previous versions to the journal, as a snapshot event
+// followed by their inputs
+fn migrate_legacy(persy: &Persy) -> Result<(), Error> {
+    if !persy.exists_segment(LEGACY_SNAPSHOTS_SEGMENT)? {
+        return Ok(());
+    }
+    let snapshots = storage::scan::<MatchSnapshot>(persy, LEGACY_SNAPSHOTS_SEGMENT)?
+        .into_iter()
+        .map(|(_, snapshot)| snapshot)
+        .collect::<Vec<_>>();
+    let mut entries = Vec::new();
+    if persy.exists_segment(LEGACY_INPUT_LOG_SEGMENT)? {
+        entries = storage::scan::<InputLogEntry>(persy, LEGACY_INPUT_LOG_SEGMENT)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+    }
+    entries.sort_by_key(|entry| entry.from);
+    let events = std::iter::once(JournalEvent::Snapshot(snapshots))
+        .chain(entries.into_iter().map(JournalEvent::Inputs))
+        .collect::<Vec<_>>();
+    let mut tx = persy.begin()?;
+    append(&mut tx, next_seq(persy)?, &events)?;
+    tx.drop_segment(LEGACY_SNAPSHOTS_SEGMENT)?;
+    if persy.exists_segment(LEGACY_INPUT_LOG_SEGMENT)? {
+        tx.drop_segment(LEGACY_INPUT_LOG_SEGMENT)?;
+    }
+    tx.prepare()?.commit()?;
+    println!("Recovery data moved to journal, {} events", events.len());
+    Ok(())
+}
+
+fn records(persy: &Persy) -> Result<Vec<(PersyId, JournalRecord)>, Error> {
+    let mut records = storage::scan::<JournalRecord>(persy, JOURNAL_SEGMENT)?;
+    records.sort_by_key(|(_, record)| record.seq);
+    Ok(records)
+}
+
+// Sequence number of the next appended event
+fn next_seq(persy: &Persy) -> Result<u64, Error> {
+    Ok(records(persy)?
+        .last()
+        .map_or(0, |(_, record)| record.seq + 1))
+}
+
+// Append events numbered from seq, returns seq of the next event
+fn append(tx: &mut Transaction, seq: u64, events: &[JournalEvent]) -> Result<u64, Error> {
+    for (seq, event) in (seq..).zip(events) {
+        let record = JournalRecord {
+            seq,
+            event: event.clone(),
+        };
+        storage::insert_in_tx(tx, JOURNAL_SEGMENT, &record)?;
+    }
+    Ok(seq + events.len() as u64)
+}
+
+fn write_events(persy: &Persy, seq: u64, events: &[JournalEvent]) -> Result<u64, Error> {
+    if events.is_empty() {
+        return Ok(seq);
+    }
+    let mut tx = persy.begin()?;
+    let seq = append(&mut tx, seq, events)?;
+    tx.prepare()?.commit()?;
+    Ok(seq)
+}
+
+// Append snapshot and remove events before it
+fn write_snapshot(persy: &Persy, seq: u64, snapshots: Vec<MatchSnapshot>) -> Result<u64, Error> {
+    let mut tx = persy.begin()?;
+    for (id, record) in records(persy)? {
+        if record.seq < seq {
+            tx.delete(JOURNAL_SEGMENT, &id)?;
+        }
+    }
+    let seq = append(&mut tx, seq, &[JournalEvent::Snapshot(snapshots)])?;
+    tx.prepare()?.commit()?;
+    Ok(seq)
+}
+
+// Apply event to matches keyed by players and start time
+fn apply(state: &mut HashMap<([UserId; 2], u64), MatchSnapshot>, event: JournalEvent) {
+    match event {
+        JournalEvent::Snapshot(snapshots) => {
+            *state = snapshots
+                .into_iter()
+                .map(|snapshot| ((snapshot.players, snapshot.started), snapshot))
+                .collect();
+        }
+        JournalEvent::MatchStarted(snapshot) => {
+            state.insert((snapshot.players, snapshot.started), *snapshot);
+        }
+        JournalEvent::MatchEnded { players, started } => {
+            state.remove(&(players, started));
+        }
+        JournalEvent::Inputs(entry) => {
+            let Some(snapshot) = state.get_mut(&(entry.players, entry.started)) else {
+                return;
+            };
+            let Some(replay) = snapshot.replays.get_mut(entry.side) else {
+                return;
+            };
+            // Entries may overlap with snapshot, only inputs continuing the replay are taken
+            for (position, input) in (entry.from..).zip(entry.inputs) {
+                if position == replay.inputs.len() {
+                    replay.inputs.push(input);
+                }
+            }
+            replay.ticks = replay.ticks.max(entry.ticks);
+        }
+    }
+}
+
+// Running matches rebuilt from the last snapshot and events after it
+pub fn recover(persy: &Persy) -> Result<Vec<MatchSnapshot>, Error> {
+    let records = records(persy)?;
+    let last_snapshot = records
+        .iter()
+        .rposition(|(_, record)| matches!(record.event, JournalEvent::Snapshot(_)))
+        .unwrap_or(0);
+    let mut state = HashMap::new();
+    for (_, record) in records.into_iter().skip(last_snapshot) {
+        apply(&mut state, record.event);
+    }
+    Ok(state.into_values().collect())
+}
+
+// Background job: append events of running matches and periodically their snapshot
+pub async fn recovery_job(db: Database, matches: TetrisMatches) {
+    let mut interval = time::interval(LOG_INTERVAL);
+    let mut iteration = 0u32;
+    // Players and start time of matches in the journal
+    let mut journaled = HashSet::new();
+    let start = db.clone();
+    let mut seq = match tokio::task::spawn_blocking(move || next_seq(&start.read())).await {
+        Ok(Ok(seq)) => seq,
+        Ok(Err(e)) => return println!("Failed to read journal: {}", e),
+        Err(e) => return println!("Recovery task failed: {}", e),
+    };
+    loop {
+        interval.tick().await;
+        iteration = iteration.wrapping_add(1);
+        let db = db.clone();
+        let written = if iteration.is_multiple_of(SNAPSHOT_EVERY) {
+            let snapshots = matches.snapshots();
+            journaled = snapshots
+                .iter()
+                .map(|snapshot| (snapshot.players, snapshot.started))
+                .collect();
+            tokio::task::spawn_blocking(move || write_snapshot(&db.read(), seq, snapshots)).await
+        } else {
+            let events = matches.journal_events(&mut journaled);
+            tokio::task::spawn_blocking(move || write_events(&db.read(), seq, &events)).await
+        };
+        match written {
+            Ok(Ok(next)) => seq = next,
+            Ok(Err(e)) => println!("Failed to write journal: {}", e),
+            Err(e) => println!("Recovery task failed: {}", e),
+        }
+    }
+}
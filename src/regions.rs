@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashSet};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    form::Form, get, http::CookieJar, post, routes, serde::json::Json, FromForm, Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arenas::Arenas,
+    cache::Caches,
+    error::Error,
+    geoip::{self, InferredCountry},
+    ids::UserId,
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Countries of users for regional leaderboards. User sets two letter country code of
+// the profile with POST /account/country, or "auto" to infer it from the address by the geo
+// ip database, see geoip. Reading the country doesn't infer it, users without country are
+// listed in no region until they set one. Leaderboards of the server and arenas take
+// country filter, e.g. /leaderboard?country=DE, which lists entries of users of the
+// country as they're set now. /leaderboard/countries lists countries having users
+//
+
+const COUNTRIES_SEGMENT: &str = "user_countries";
+const BY_USER_INDEX: &str = "user_countries_by_user";
+const BY_COUNTRY_INDEX: &str = "user_countries_by_country";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CountrySource {
+    // Set by the user
+    User,
+    // Inferred from the user's address
+    GeoIp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserCountry {
+    pub user: UserId,
+    pub country: String,
+    pub source: CountrySource,
+    // Time of the last change, seconds since unix epoch
+    pub changed: u64,
+}
+
+#[derive(FromForm)]
+pub struct CountryForm {
+    // Two letter code, "auto" infers it, empty clears it
+    country: String,
+}
+
+#[derive(Serialize)]
+pub struct CountryUsers {
+    pub country: String,
+    pub users: usize,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, COUNTRIES_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    storage::ensure_index::<String, u32>(persy, BY_COUNTRY_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+fn read_country(persy: &Persy, user: UserId) -> Result<Option<(PersyId, UserCountry)>, Error> {
+    let Some(id) = persy.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? else {
+        return Ok(None);
+    };
+    Ok(storage::read(persy, COUNTRIES_SEGMENT, &id)?.map(|country| (id, country)))
+}
+
+// Replace country of the user, None clears it
+fn store_country(persy: &Persy, user: UserId, country: Option<UserCountry>) -> Result<(), Error> {
+    let mut tx = persy.begin()?;
+    if let Some((id, stored)) = read_country(persy, user)? {
+        tx.delete(COUNTRIES_SEGMENT, &id)?;
+        tx.remove(BY_USER_INDEX, user.0, Some(id))?;
+        tx.remove(BY_COUNTRY_INDEX, stored.country, Some(user.0))?;
+    }
+    if let Some(country) = &country {
+        let id = storage::insert_in_tx(&mut tx, COUNTRIES_SEGMENT, country)?;
+        tx.put(BY_USER_INDEX, user.0, id)?;
+        tx.put(BY_COUNTRY_INDEX, country.country.clone(), user.0)?;
+    }
+    tx.prepare()?.commit()?;
+    Ok(())
+}
+
+// Users of the country for leaderboard filter, None without filter
+pub fn users_of(persy: &Persy, country: Option<&str>) -> Result<Option<HashSet<UserId>>, Error> {
+    let Some(country) = country else {
+        return Ok(None);
+    };
+    let country = geoip::country_code(country)
+        .ok_or_else(|| Error::InvalidInputError(format!("Invalid country code {}", country)))?;
+    Ok(Some(
+        persy
+            .get::<String, u32>(BY_COUNTRY_INDEX, &country)?
+            .map(UserId)
+            .collect(),
+    ))
+}
+
+// Leaderboards listing users by their countries are stale after a change
+fn invalidate_leaderboards(caches: &Caches, arenas: &Arenas) {
+    caches.leaderboard.invalidate();
+    for name in arenas.names() {
+        if let Ok(arena) = arenas.get(&name) {
+            arena.leaderboard.invalidate();
+        }
+    }
+}
+
+// Country of the user, None until it's set
+#[get("/account/country")]
+fn country(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<Option<UserCountry>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    Ok(Json(
+        read_country(&db.read(), user_id)?.map(|(_, country)| country),
+    ))
+}
+
+// Set country of the user
+#[post("/account/country", data = "<form>")]
+fn set_country(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    caches: &State<Caches>,
+    arenas: &State<Arenas>,
+    inferred: InferredCountry,
+    form: Form<CountryForm>,
+) -> Result<Json<Option<UserCountry>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let changed = crate::unix_time();
+    let country = match form.country.trim() {
+        "" => None,
+        "auto" => {
+            let country = inferred.0.ok_or_else(|| {
+                Error::NotFoundError("Country of your address is unknown".to_string())
+            })?;
+            Some(UserCountry {
+                user: user_id,
+                country,
+                source: CountrySource::GeoIp,
+                changed,
+            })
+        }
+        code => Some(UserCountry {
+            user: user_id,
+            country: geoip::country_code(code).ok_or_else(|| {
+                Error::InvalidInputError(format!("Invalid country code {}", code))
+            })?,
+            source: CountrySource::User,
+            changed,
+        }),
+    };
+    store_country(&db.read(), user_id, country.clone())?;
+    invalidate_leaderboards(caches, arenas);
+    Ok(Json(country))
+}
+
+// Countries having users, by code
+#[get("/leaderboard/countries")]
+fn countries(db: &State<Database>) -> Result<Json<Vec<CountryUsers>>, Error> {
+    let mut countries = BTreeMap::<String, usize>::new();
+    for (_, country) in storage::scan::<UserCountry>(&db.read(), COUNTRIES_SEGMENT)? {
+        *countries.entry(country.country).or_default() += 1;
+    }
+    Ok(Json(
+        countries
+            .into_iter()
+            .map(|(country, users)| CountryUsers { country, users })
+            .collect(),
+    ))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![country, set_country, countries]
+}
@@ -0,0 +1,196 @@
+use persy::{Persy, PersyId, Transaction};
+use rocket::tokio::{self, sync::mpsc};
+
+use crate::{
+    cache::ResponseCache,
+    discord::Discord,
+    error::Error,
+    ids::GameId,
+    leaderboard::{self, LeaderboardEntry, LeaderboardItem, Verification},
+    recording,
+    storage::{self, Database},
+    tetris::{Replay, ReplayPlayer, Tetris},
+    webhooks::{WebhookEvent, Webhooks},
+};
+
+//
+// Replays of finished games and verification of leaderboard entries by replays.
+// Verification re-simulates the game from the recorded seed and inputs and compares
+// resulting score with the claimed one, game invariants are checked on every step. It runs
+// in a background worker, entries are listed as unverified until checked. Verified record
+// scores are announced to webhooks and Discord
+//
+
+const REPLAYS_SEGMENT: &str = "replays";
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, REPLAYS_SEGMENT)
+}
+
+pub fn read(persy: &Persy, id: &PersyId) -> Result<Option<Replay>, Error> {
+    storage::read(persy, REPLAYS_SEGMENT, id)
+}
+
+pub fn delete_in_tx(tx: &mut Transaction, id: &PersyId) -> Result<(), Error> {
+    if tx.read(REPLAYS_SEGMENT, id)?.is_some() {
+        tx.delete(REPLAYS_SEGMENT, id)?;
+    }
+    Ok(())
+}
+
+// Ids of stored game records, replay is None if the player opted out of recording
+pub struct RecordedGame {
+    pub entry: PersyId,
+    pub replay: Option<PersyId>,
+}
+
+// Store replay and leaderboard entry referring to it. Entry of a player who opted out
+// of recording is stored without replay, as unrecorded
+pub fn record_game(
+    tx: &mut Transaction,
+    mut entry: LeaderboardEntry,
+    replay: &Replay,
+) -> Result<RecordedGame, Error> {
+    let replay_id = if recording::is_enabled_in_tx(tx, entry.user)? {
+        let replay_id = storage::insert_in_tx(tx, REPLAYS_SEGMENT, replay)?;
+        entry.replay = Some(replay_id.to_string());
+        Some(replay_id)
+    } else {
+        entry.replay = None;
+        entry.verification = Verification::Unrecorded;
+        None
+    };
+    Ok(RecordedGame {
+        entry: leaderboard::record_in_tx(tx, &entry)?,
+        replay: replay_id,
+    })
+}
+
+// Verified entry which is a new record score, for high score webhooks
+fn new_record(persy: &Persy, id: &PersyId) -> Result<Option<LeaderboardItem>, Error> {
+    let Some(entry) = leaderboard::read(persy, id)? else {
+        return Ok(None);
+    };
+    let id = GameId(*id);
+    Ok(leaderboard::is_record(persy, id, &entry)?.then_some(LeaderboardItem { id, entry }))
+}
+
+// Check that replay reproduces the score claimed by the entry
+pub fn verify(persy: &Persy, entry: &LeaderboardEntry) -> Result<Verification, Error> {
+    let Some(replay_id) = &entry.replay else {
+        return Ok(Verification::Unrecorded);
+    };
+    let Some(replay) = read(persy, &storage::parse_id(replay_id)?)? else {
+        return Ok(Verification::Rejected);
+    };
+    // Played games are checked for game logic bugs on the way, score is checked regardless
+    let tetris = match ReplayPlayer::new(replay.clone()).check() {
+        Ok(tetris) => tetris,
+        Err((tick, violation)) => {
+            println!(
+                "Replay {} violates game invariant at step {}: {}",
+                replay_id, tick, violation
+            );
+            Tetris::from_replay(&replay)
+        }
+    };
+    if tetris.get_score() as u64 == entry.score && tetris.get_lines() as u64 == entry.lines {
+        Ok(Verification::Verified)
+    } else {
+        Ok(Verification::Rejected)
+    }
+}
+
+fn verify_entry(persy: &Persy, id: &PersyId) -> Result<Verification, Error> {
+    let Some(entry) = leaderboard::read(persy, id)? else {
+        return Ok(Verification::Unverified);
+    };
+    let verification = verify(persy, &entry)?;
+    leaderboard::set_verification(persy, id, verification)?;
+    Ok(verification)
+}
+
+// Handle to queue leaderboard entries for verification
+#[derive(Clone)]
+pub struct ReplayVerifier {
+    sender: mpsc::UnboundedSender<PersyId>,
+    // Leaderboard responses, invalidated when entries are added or verified
+    cache: ResponseCache,
+}
+
+impl ReplayVerifier {
+    // Start verification worker. Entries left unverified by previous run are queued first
+    pub fn start(
+        db: Database,
+        cache: ResponseCache,
+        webhooks: Option<Webhooks>,
+        discord: Option<Discord>,
+    ) -> Result<ReplayVerifier, Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        for id in leaderboard::unverified(&db.read())? {
+            let _ = sender.send(id);
+        }
+        // Not a lifecycle job: write queue reports written entries to the worker while it's
+        // flushed on stop, the worker ends with the last handle. Entries left unverified on
+        // shutdown are queued again on the next start
+        tokio::spawn(Self::worker(db, receiver, cache.clone(), webhooks, discord));
+        Ok(ReplayVerifier { sender, cache })
+    }
+
+    pub fn verify(&self, id: PersyId) {
+        let _ = self.sender.send(id);
+    }
+
+    // New leaderboard entry was stored, queue it for verification
+    pub fn entry_recorded(&self, id: PersyId) {
+        self.cache.invalidate();
+        self.verify(id)
+    }
+
+    async fn worker(
+        db: Database,
+        mut receiver: mpsc::UnboundedReceiver<PersyId>,
+        cache: ResponseCache,
+        webhooks: Option<Webhooks>,
+        discord: Option<Discord>,
+    ) {
+        while let Some(id) = receiver.recv().await {
+            let worker_db = db.clone();
+            // Simulation is cpu-bound and database access is blocking
+            let result =
+                tokio::task::spawn_blocking(move || verify_entry(&worker_db.read(), &id)).await;
+            if matches!(result, Ok(Ok(_))) {
+                cache.invalidate();
+            }
+            match result {
+                Ok(Ok(Verification::Rejected)) => {
+                    println!("Leaderboard entry {} rejected by replay", id)
+                }
+                Ok(Ok(Verification::Verified)) => {
+                    if webhooks.is_none() && discord.is_none() {
+                        continue;
+                    }
+                    let worker_db = db.clone();
+                    match tokio::task::spawn_blocking(move || new_record(&worker_db.read(), &id))
+                        .await
+                    {
+                        Ok(Ok(Some(item))) => {
+                            if let Some(webhooks) = &webhooks {
+                                webhooks.emit(WebhookEvent::HighScore, &item);
+                            }
+                            if let Some(discord) = &discord {
+                                discord.record(&item);
+                            }
+                        }
+                        Ok(Ok(None)) => (),
+                        Ok(Err(e)) => println!("Record check of {} failed: {}", id, e),
+                        Err(e) => println!("Record check task failed: {}", e),
+                    }
+                }
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => println!("Replay verification of {} failed: {}", id, e),
+                Err(e) => println!("Replay verification task failed: {}", e),
+            }
+        }
+    }
+}
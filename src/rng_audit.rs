@@ -0,0 +1,160 @@
+use persy::{Persy, PersyId, Transaction};
+use rocket::{get, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    game_rng::{PieceChain, RngKind, GENERATOR_VERSION},
+    ids::GameId,
+    leaderboard, replays,
+    storage::{self, Database},
+    tetris::{Randomizer, Replay, Tetris},
+};
+
+//
+// Audit trail of random pieces of ranked games, for disputes about rigged pieces. When a
+// versus match is recorded, the seed, random source and generator of each player's game
+// is stored with the hash chain of pieces the game emitted (see game_rng::PieceChain) in
+// "rng_audits" segment, the leaderboard entry refers to it. GET /game/<id>/rng_audit
+// returns the audit and recomputes the chain from the game's replay, anyone may recompute
+// it from the seed and inputs as well. Games without replay are audited by the stored
+// values only
+//
+
+const AUDITS_SEGMENT: &str = "rng_audits";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RngAudit {
+    pub seed: u64,
+    pub rng: RngKind,
+    pub randomizer: Randomizer,
+    pub generator_version: u32,
+    // Pieces emitted, in the queue as well as placed ones
+    pub pieces: u64,
+    // Hex of the final hash of the chain
+    pub chain: String,
+}
+
+// Chain of the game recomputed from it's replay
+#[derive(Serialize)]
+pub struct RecomputedChain {
+    pub pieces: u64,
+    pub chain: String,
+    pub generator_version: u32,
+}
+
+#[derive(Serialize)]
+pub struct RngAuditReport {
+    pub game: GameId,
+    pub audit: RngAudit,
+    // None without replay
+    pub recomputed: Option<RecomputedChain>,
+    pub matches: Option<bool>,
+}
+
+impl RngAudit {
+    pub fn of(replay: &Replay, chain: &PieceChain) -> RngAudit {
+        RngAudit {
+            seed: replay.seed,
+            rng: replay.rng,
+            randomizer: replay.randomizer,
+            generator_version: GENERATOR_VERSION,
+            pieces: chain.pieces,
+            chain: chain.hash(),
+        }
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, AUDITS_SEGMENT)
+}
+
+pub fn record_in_tx(tx: &mut Transaction, audit: &RngAudit) -> Result<PersyId, Error> {
+    storage::insert_in_tx(tx, AUDITS_SEGMENT, audit)
+}
+
+// Audit of leaderboard entry's game with the chain recomputed from it's replay
+pub fn report(persy: &Persy, id: GameId) -> Result<RngAuditReport, Error> {
+    let entry = leaderboard::read(persy, &id.0)?
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    let audit_id = entry
+        .rng_audit
+        .ok_or_else(|| Error::NotFoundError("Game has no RNG audit".to_string()))?;
+    let audit = storage::read::<RngAudit>(persy, AUDITS_SEGMENT, &storage::parse_id(&audit_id)?)?
+        .ok_or_else(|| Error::NotFoundError("RNG audit not found".to_string()))?;
+    let replay = match &entry.replay {
+        Some(replay) => replays::read(persy, &storage::parse_id(replay)?)?,
+        None => None,
+    };
+    let recomputed = replay.map(|replay| {
+        let tetris = Tetris::from_replay(&replay);
+        let chain = tetris.get_piece_chain();
+        RecomputedChain {
+            pieces: chain.pieces,
+            chain: chain.hash(),
+            generator_version: GENERATOR_VERSION,
+        }
+    });
+    let matches = recomputed
+        .as_ref()
+        .map(|recomputed| recomputed.pieces == audit.pieces && recomputed.chain == audit.chain);
+    Ok(RngAuditReport {
+        game: id,
+        audit,
+        recomputed,
+        matches,
+    })
+}
+
+#[get("/game/<id>/rng_audit")]
+fn rng_audit(db: &State<Database>, id: GameId) -> Result<Json<RngAuditReport>, Error> {
+    Ok(Json(report(&db.read(), id)?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![rng_audit]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{difficulty::Difficulty, tetris::Action};
+
+    fn game(seed: u64, rng: RngKind) -> Tetris {
+        let mut tetris = Tetris::new_with_seed(
+            10,
+            20,
+            seed,
+            Randomizer::SevenBag,
+            rng,
+            Some(Difficulty::default()),
+        );
+        for _ in 0..5 {
+            tetris.add_action(Action::Drop);
+            for _ in 0..250 {
+                tetris.step();
+            }
+        }
+        tetris
+    }
+
+    #[test]
+    fn replay_recomputes_audited_chain() {
+        for rng in [RngKind::Std, RngKind::SplitMix] {
+            let tetris = game(7, rng);
+            let replay = tetris.get_replay();
+            let audit = RngAudit::of(&replay, tetris.get_piece_chain());
+            assert!(audit.pieces >= 5);
+            let replayed = Tetris::from_replay(&replay);
+            assert_eq!(replayed.get_piece_chain().pieces, audit.pieces);
+            assert_eq!(replayed.get_piece_chain().hash(), audit.chain);
+        }
+    }
+
+    #[test]
+    fn games_of_other_seeds_have_other_chains() {
+        let chain = |seed| game(seed, RngKind::Std).get_piece_chain().hash();
+        assert_eq!(chain(7), chain(7));
+        assert_ne!(chain(7), chain(8));
+    }
+}
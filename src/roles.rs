@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    form::Form,
+    get,
+    http::Status,
+    post,
+    request::{FromRequest, Outcome},
+    response::Redirect,
+    routes, Config, FromForm, Request, Route, State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    storage::{self, Database},
+};
+
+//
+// Roles of admin accounts. Users listed in admin_owners config are owners, owners assign
+// roles to other users in /admin/roles. Each role may do what the roles below it may:
+// viewers read admin pages, moderators act on players and their content (bans, appeals,
+// puzzles, wordlists, notifications, message of the day), operators run the server
+// (maintenance, settings, matches in memory, database compaction, webhooks, bots,
+// synthetic load, handover) and owners manage roles. Admin routes take the guard of the
+// role they require, the admin is the signed in user, see sessions. Assignments are stored
+// in "admin_roles" segment and kept in memory. Without admin_owners roles are not
+// checked and admin routes are open, as before roles, to be protected by the network
+//
+
+const ROLES_SEGMENT: &str = "admin_roles";
+const BY_USER_INDEX: &str = "admin_roles_by_user";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Role {
+    Viewer,
+    Moderator,
+    Operator,
+    Owner,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleAssignment {
+    pub user: UserId,
+    pub role: Role,
+    // Owner who assigned the role, None when roles were off
+    pub assigned_by: Option<UserId>,
+    // Seconds since unix epoch
+    pub assigned: u64,
+}
+
+#[derive(FromForm)]
+pub struct RoleForm {
+    user: UserId,
+    // Name of the role, "none" or empty removes it
+    role: String,
+}
+
+pub struct AdminRoles {
+    // Configured owners, their role is not stored
+    owners: HashSet<UserId>,
+    roles: RwLock<HashMap<UserId, Role>>,
+}
+
+// Admin guards by required role, Owner keeps the admin's user id, None when roles are off
+pub struct Viewer;
+pub struct Moderator;
+pub struct Operator;
+pub struct Owner(pub Option<UserId>);
+
+impl Role {
+    fn parse(name: &str) -> Option<Role> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "viewer" => Some(Role::Viewer),
+            "moderator" => Some(Role::Moderator),
+            "operator" => Some(Role::Operator),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, ROLES_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+impl AdminRoles {
+    pub fn load(persy: &Persy) -> Result<AdminRoles, Error> {
+        let owners = Config::figment()
+            .extract_inner::<Vec<UserId>>("admin_owners")
+            .unwrap_or_default()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let roles = storage::scan::<RoleAssignment>(persy, ROLES_SEGMENT)?
+            .into_iter()
+            .map(|(_, assignment)| (assignment.user, assignment.role))
+            .collect::<HashMap<_, _>>();
+        if owners.is_empty() {
+            println!("Admin roles are off, admin_owners is not configured");
+        }
+        Ok(AdminRoles {
+            owners,
+            roles: RwLock::new(roles),
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.owners.is_empty()
+    }
+
+    pub fn role_of(&self, user: UserId) -> Option<Role> {
+        if self.owners.contains(&user) {
+            return Some(Role::Owner);
+        }
+        self.roles.read().unwrap().get(&user).copied()
+    }
+
+    // Assign the role to the user, None removes it
+    fn assign(
+        &self,
+        persy: &Persy,
+        user: UserId,
+        role: Option<Role>,
+        by: Option<UserId>,
+    ) -> Result<(), Error> {
+        if self.owners.contains(&user) {
+            return Err(Error::InvalidInputError(format!(
+                "User {} is owner by configuration",
+                user
+            )));
+        }
+        let mut tx = persy.begin()?;
+        if let Some(id) = persy.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? {
+            tx.delete(ROLES_SEGMENT, &id)?;
+            tx.remove(BY_USER_INDEX, user.0, Some(id))?;
+        }
+        if let Some(role) = role {
+            let assignment = RoleAssignment {
+                user,
+                role,
+                assigned_by: by,
+                assigned: crate::unix_time(),
+            };
+            let id = storage::insert_in_tx(&mut tx, ROLES_SEGMENT, &assignment)?;
+            tx.put(BY_USER_INDEX, user.0, id)?;
+        }
+        tx.prepare()?.commit()?;
+        let mut roles = self.roles.write().unwrap();
+        match role {
+            Some(role) => roles.insert(user, role),
+            None => roles.remove(&user),
+        };
+        println!("Admin role of user {} set to {:?} by {:?}", user, role, by);
+        Ok(())
+    }
+}
+
+// Signed in user having the role or a higher one
+fn check(request: &Request<'_>, required: Role) -> Outcome<Option<UserId>, ()> {
+    let Some(roles) = request.rocket().state::<AdminRoles>() else {
+        return Outcome::Success(None);
+    };
+    if !roles.is_enabled() {
+        return Outcome::Success(None);
+    }
+    // Pending value, user id of rejected session is dropped by session check
+    let Some(user) = request
+        .cookies()
+        .get_pending("user_id")
+        .and_then(|cookie| cookie.value().parse::<UserId>().ok())
+    else {
+        return Outcome::Error((Status::Unauthorized, ()));
+    };
+    if roles.role_of(user).is_some_and(|role| role >= required) {
+        Outcome::Success(Some(user))
+    } else {
+        println!(
+            "User {} refused {} {}, {:?} role required",
+            user,
+            request.method(),
+            request.uri(),
+            required
+        );
+        Outcome::Error((Status::Forbidden, ()))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Viewer {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        check(request, Role::Viewer).map(|_| Viewer)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Moderator {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        check(request, Role::Moderator).map(|_| Moderator)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Operator {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        check(request, Role::Operator).map(|_| Operator)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Owner {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        check(request, Role::Owner).map(Owner)
+    }
+}
+
+// Configured owners and stored assignments
+#[get("/admin/roles")]
+fn admin_roles(
+    _owner: Owner,
+    db: &State<Database>,
+    roles: &State<AdminRoles>,
+) -> Result<Template, Error> {
+    let mut owners = roles.owners.iter().copied().collect::<Vec<_>>();
+    owners.sort();
+    let mut assignments = storage::scan::<RoleAssignment>(&db.read(), ROLES_SEGMENT)?
+        .into_iter()
+        .map(|(_, assignment)| assignment)
+        .collect::<Vec<_>>();
+    assignments.sort_by_key(|assignment| (std::cmp::Reverse(assignment.role), assignment.user));
+    Ok(Template::render(
+        "admin/roles",
+        context! { enabled: roles.is_enabled(), owners, assignments },
+    ))
+}
+
+#[post("/admin/roles", data = "<form>")]
+fn set_role(
+    owner: Owner,
+    db: &State<Database>,
+    roles: &State<AdminRoles>,
+    form: Form<RoleForm>,
+) -> Result<Redirect, Error> {
+    let role = match form.role.trim() {
+        "" | "none" => None,
+        name => Some(
+            Role::parse(name)
+                .ok_or_else(|| Error::InvalidInputError(format!("Unknown role {}", name)))?,
+        ),
+    };
+    roles.assign(&db.read(), form.user, role, owner.0)?;
+    Ok(Redirect::to("/admin/roles"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_roles, set_role]
+}
@@ -0,0 +1,322 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rocket::{
+    get, routes,
+    serde::json::Json,
+    tokio::{sync::oneshot, time},
+    Config, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    ids::UserId, roles::Viewer, settings::SettingsWatch, tetris_pair::TetrisPairState,
+    TetrisMatches,
+};
+
+//
+// Tick scheduler of versus games. Game streams don't step games on own timers, they wait
+// for the next tick: at each tick worker threads step all players waiting for it, one
+// batch per worker. Batches take the read lock of matches and the lock of each match they
+// step, so workers only wait for each other on the same match. Players are partitioned
+// across tick_workers workers by user id. A worker done with it's batch takes steps still
+// waiting in queues of the other workers, so players of a busy worker don't miss the tick.
+// Latency of steps, from the tick deadline to the step, and ticks missed by late workers
+// are reported by /admin/scheduler and /admin/metrics. Tick interval is STEP_MS unless
+// configured, see settings
+//
+
+// Latencies of the latest steps kept for percentiles
+const LATENCY_SAMPLES: usize = 4096;
+
+struct PendingStep {
+    user_id: UserId,
+    requested: Instant,
+    reply: oneshot::Sender<Option<TetrisPairState>>,
+}
+
+#[derive(Default)]
+struct Counters {
+    ticks: AtomicU64,
+    missed_ticks: AtomicU64,
+    steps: AtomicU64,
+    stolen_steps: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct SchedulerMetrics {
+    pub workers: usize,
+    pub tick_ms: u64,
+    // Steps waiting for the next tick
+    pub waiting: usize,
+    pub ticks: u64,
+    // Ticks skipped because the worker was still busy with previous ones
+    pub missed_ticks: u64,
+    pub steps: u64,
+    // Steps taken from queues of other workers
+    pub stolen_steps: u64,
+    // Of the latest steps, microseconds
+    pub p50_latency_us: u64,
+    pub p99_latency_us: u64,
+    pub max_latency_us: u64,
+}
+
+// Worker queues, shared by streams and workers
+#[derive(Clone)]
+pub struct TickScheduler {
+    queues: Arc<Vec<Mutex<Vec<PendingStep>>>>,
+    latencies: Arc<Mutex<VecDeque<u64>>>,
+    counters: Arc<Counters>,
+    started: Arc<AtomicBool>,
+    // Tick interval
+    settings: SettingsWatch,
+}
+
+fn configured_workers() -> usize {
+    Config::figment()
+        .extract_inner::<usize>("tick_workers")
+        .ok()
+        .or_else(|| thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(1)
+        .max(1)
+}
+
+// Queue of the worker stepping the user's game
+fn queue_index(user_id: UserId, queues: usize) -> usize {
+    user_id.0 as usize % queues
+}
+
+// Take steps requested before the deadline, later steps are for the next tick
+fn take_due(pending: &mut Vec<PendingStep>, deadline: Instant) -> Vec<PendingStep> {
+    let (due, later) = std::mem::take(pending)
+        .into_iter()
+        .partition(|step| step.requested <= deadline);
+    *pending = later;
+    due
+}
+
+// Percentile of sorted latencies, 0 without latencies
+fn percentile(latencies: &[u64], percent: usize) -> u64 {
+    latencies
+        .get((latencies.len() * percent / 100).min(latencies.len().saturating_sub(1)))
+        .copied()
+        .unwrap_or(0)
+}
+
+impl TickScheduler {
+    pub fn new(settings: SettingsWatch) -> TickScheduler {
+        TickScheduler {
+            queues: Arc::new(
+                (0..configured_workers())
+                    .map(|_| Mutex::new(Vec::new()))
+                    .collect(),
+            ),
+            latencies: Arc::new(Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES))),
+            counters: Arc::new(Counters::default()),
+            started: Arc::new(AtomicBool::new(false)),
+            settings,
+        }
+    }
+
+    fn tick(&self) -> Duration {
+        Duration::from_millis(self.settings.borrow().tick_ms.max(1))
+    }
+
+    // Start worker threads stepping the matches
+    pub fn start(&self, matches: TetrisMatches) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Workers start together, so their ticks have same deadlines
+        let start = Instant::now();
+        for index in 0..self.queues.len() {
+            let scheduler = self.clone();
+            let matches = matches.clone();
+            thread::Builder::new()
+                .name(format!("tick-worker-{}", index))
+                .spawn(move || scheduler.worker(index, start, matches))
+                .expect("Failed to start tick worker");
+        }
+    }
+
+    // Step user's game at the next tick. Without started workers the game is stepped
+    // after a tick on the stream's own timer
+    pub async fn step(&self, matches: &TetrisMatches, user_id: UserId) -> Option<TetrisPairState> {
+        if !self.started.load(Ordering::Relaxed) {
+            time::sleep(self.tick()).await;
+            return matches.step(user_id);
+        }
+        let (reply, receiver) = oneshot::channel();
+        let queue = &self.queues[queue_index(user_id, self.queues.len())];
+        queue.lock().unwrap().push(PendingStep {
+            user_id,
+            requested: Instant::now(),
+            reply,
+        });
+        receiver.await.ok().flatten()
+    }
+
+    fn worker(&self, index: usize, start: Instant, matches: TetrisMatches) {
+        let mut deadline = start;
+        loop {
+            let tick = self.tick();
+            deadline += tick;
+            if let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+            self.counters.ticks.fetch_add(1, Ordering::Relaxed);
+            let own = std::mem::take(&mut *self.queues[index].lock().unwrap());
+            self.run_batch(&matches, deadline, own);
+            // Steps other workers didn't take yet, queues are visited from the next one
+            for offset in 1..self.queues.len() {
+                let other = &self.queues[(index + offset) % self.queues.len()];
+                let stolen = match other.try_lock() {
+                    Ok(mut pending) => take_due(&mut pending, deadline),
+                    Err(_) => continue,
+                };
+                if stolen.is_empty() {
+                    continue;
+                }
+                self.counters
+                    .stolen_steps
+                    .fetch_add(stolen.len() as u64, Ordering::Relaxed);
+                self.run_batch(&matches, deadline, stolen);
+            }
+            // Skip ticks which are already over
+            let missed = (deadline.elapsed().as_micros() / tick.as_micros()) as u32;
+            if missed > 0 {
+                self.counters
+                    .missed_ticks
+                    .fetch_add(missed as u64, Ordering::Relaxed);
+                deadline += tick * missed;
+            }
+        }
+    }
+
+    fn run_batch(&self, matches: &TetrisMatches, deadline: Instant, batch: Vec<PendingStep>) {
+        let users = batch
+            .iter()
+            .map(|pending| pending.user_id)
+            .collect::<Vec<_>>();
+        if users.is_empty() {
+            return;
+        }
+        let states = matches.step_batch(&users);
+        let done = Instant::now();
+        let mut latencies = self.latencies.lock().unwrap();
+        for (pending, state) in batch.into_iter().zip(states) {
+            // Steps requested after the deadline weren't late in that tick
+            let latency = done.duration_since(pending.requested.max(deadline));
+            if latencies.len() == LATENCY_SAMPLES {
+                latencies.pop_front();
+            }
+            latencies.push_back(latency.as_micros() as u64);
+            let _ = pending.reply.send(state);
+        }
+        self.counters
+            .steps
+            .fetch_add(users.len() as u64, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> SchedulerMetrics {
+        let mut latencies = self
+            .latencies
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        latencies.sort_unstable();
+        SchedulerMetrics {
+            workers: self.queues.len(),
+            tick_ms: self.tick().as_millis() as u64,
+            waiting: self
+                .queues
+                .iter()
+                .map(|queue| queue.lock().unwrap().len())
+                .sum(),
+            ticks: self.counters.ticks.load(Ordering::Relaxed),
+            missed_ticks: self.counters.missed_ticks.load(Ordering::Relaxed),
+            steps: self.counters.steps.load(Ordering::Relaxed),
+            stolen_steps: self.counters.stolen_steps.load(Ordering::Relaxed),
+            p50_latency_us: percentile(&latencies, 50),
+            p99_latency_us: percentile(&latencies, 99),
+            max_latency_us: latencies.last().copied().unwrap_or(0),
+        }
+    }
+}
+
+// Tick latency and load of the scheduler of versus games
+#[get("/admin/scheduler")]
+fn admin_scheduler(_admin: Viewer, matches: &State<TetrisMatches>) -> Json<SchedulerMetrics> {
+    Json(matches.scheduler.metrics())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_scheduler]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(user: u32, requested: Instant) -> PendingStep {
+        PendingStep {
+            user_id: UserId(user),
+            requested,
+            reply: oneshot::channel().0,
+        }
+    }
+
+    #[test]
+    fn users_are_spread_evenly_across_queues() {
+        let mut sizes = [0; 4];
+        for user in 0..1000 {
+            sizes[queue_index(UserId(user), sizes.len())] += 1;
+        }
+        assert_eq!(sizes, [250; 4]);
+    }
+
+    #[test]
+    fn user_stays_in_same_queue() {
+        let user_id = UserId(123_456_789);
+        let queue = queue_index(user_id, 3);
+        assert!(queue < 3);
+        assert!((0..10).all(|_| queue_index(user_id, 3) == queue));
+        assert_eq!(queue_index(user_id, 1), 0);
+    }
+
+    #[test]
+    fn steps_after_deadline_are_left_for_next_tick() {
+        let deadline = Instant::now();
+        let before = deadline - Duration::from_millis(5);
+        let after = deadline + Duration::from_millis(5);
+        let mut queue = vec![
+            pending(1, before),
+            pending(2, after),
+            pending(3, deadline),
+            pending(4, after),
+        ];
+        let due = take_due(&mut queue, deadline);
+        let users =
+            |steps: &[PendingStep]| steps.iter().map(|step| step.user_id.0).collect::<Vec<_>>();
+        assert_eq!(users(&due), [1, 3]);
+        assert_eq!(users(&queue), [2, 4]);
+        assert!(take_due(&mut queue, deadline).is_empty());
+        assert_eq!(take_due(&mut queue, after).len(), 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn percentiles_of_sorted_latencies() {
+        let latencies = (1..=100).collect::<Vec<u64>>();
+        assert_eq!(percentile(&latencies, 50), 51);
+        assert_eq!(percentile(&latencies, 99), 100);
+        assert_eq!(percentile(&latencies, 100), 100);
+        assert_eq!(percentile(&[7], 99), 7);
+        assert_eq!(percentile(&[], 99), 0);
+    }
+}
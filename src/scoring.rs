@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rocket::{figment::Figment, get, routes, serde::json::Json, Route, State};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+//
+// Scoring rules: points for line clears, drop bonus and level multiplier. Built-in
+// "classic" ruleset can be extended or overridden by tables of "scoring" config table,
+// one ruleset per key. Versus matches use ruleset selected by scoring_rules key, arenas
+// may select their own. Rules of a game are stored in it's replay, so verification
+// doesn't depend on configuration at the time of check
+//
+
+pub const DEFAULT_RULES: &str = "classic";
+
+// Upper limit of points of a single event, keeps scores far from overflow
+const MAX_POINTS: usize = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScoringRules {
+    // Key of the config table
+    #[serde(default)]
+    pub name: String,
+    // Points for clearing 1, 2, 3 and 4 lines at once
+    pub clear: [usize; 4],
+    // Points for each cell the piece falls while dropped
+    #[serde(default)]
+    pub drop_per_cell: usize,
+    // Clear points are multiplied by level of the game
+    #[serde(default)]
+    pub level_multiplier: bool,
+}
+
+impl ScoringRules {
+    pub fn classic() -> ScoringRules {
+        ScoringRules {
+            name: DEFAULT_RULES.to_string(),
+            clear: [100, 300, 500, 800],
+            drop_per_cell: 0,
+            level_multiplier: false,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("Ruleset name is empty".to_string());
+        }
+        if self.clear.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err("Clearing more lines at once can't give less points".to_string());
+        }
+        if self.clear[3] > MAX_POINTS || self.drop_per_cell > MAX_POINTS {
+            return Err(format!("Points must be at most {}", MAX_POINTS));
+        }
+        Ok(())
+    }
+
+    // Points for lines cleared at once at the level
+    pub fn clear_points(&self, lines: usize, level: usize) -> usize {
+        if lines == 0 {
+            return 0;
+        }
+        let points = self.clear[lines.min(4) - 1];
+        if self.level_multiplier {
+            points * level.max(1)
+        } else {
+            points
+        }
+    }
+}
+
+// Available rulesets by name
+pub struct ScoringRulebook(HashMap<String, Arc<ScoringRules>>);
+
+impl ScoringRulebook {
+    // Built-in ruleset and rulesets of "scoring" table
+    pub fn load(figment: &Figment) -> Result<ScoringRulebook, Error> {
+        let mut rulesets = HashMap::new();
+        let classic = ScoringRules::classic();
+        rulesets.insert(classic.name.clone(), Arc::new(classic));
+        let configured = match figment.find_value("scoring") {
+            Ok(_) => figment
+                .extract_inner::<HashMap<String, ScoringRules>>("scoring")
+                .map_err(|e| Error::InvalidInputError(format!("Invalid scoring table: {}", e)))?,
+            Err(_) => HashMap::new(),
+        };
+        for (name, mut rules) in configured {
+            rules.name = name;
+            rules.validate().map_err(|e| {
+                Error::InvalidInputError(format!("Scoring rules {}: {}", rules.name, e))
+            })?;
+            rulesets.insert(rules.name.clone(), Arc::new(rules));
+        }
+        Ok(ScoringRulebook(rulesets))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<ScoringRules>> {
+        self.0.get(name).cloned()
+    }
+}
+
+// Available scoring rulesets
+#[get("/scoring_rules")]
+fn scoring_rules(rulebook: &State<ScoringRulebook>) -> Json<Vec<ScoringRules>> {
+    let mut rulesets = rulebook
+        .0
+        .values()
+        .map(|rules| ScoringRules::clone(rules))
+        .collect::<Vec<_>>();
+    rulesets.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(rulesets)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![scoring_rules]
+}
@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rocket::tokio::sync::Notify;
+use serde::Serialize;
+
+//
+// Fan-out of events to many subscribers with bounded queue per subscriber. Publisher
+// never waits for subscribers. When subscriber's queue is full it's backlog is dropped
+// and the subscriber is resynchronized from the next keyframe (event carrying full state),
+// events before it are skipped. Subscriber which overflows again and again without catching
+// up is disconnected, so it doesn't hold resources for a stream it can't follow
+//
+
+// Events which can be sent to subscriber after dropped ones
+pub trait Keyframe {
+    fn is_keyframe(&self) -> bool;
+}
+
+// Overflows in a row, without the queue being drained, before subscriber is disconnected
+const MAX_OVERFLOWS: u32 = 5;
+
+struct SubscriberQueue<T> {
+    events: VecDeque<T>,
+    // Events are skipped until next keyframe after overflow
+    awaiting_keyframe: bool,
+    overflows: u32,
+    disconnected: bool,
+}
+
+struct Subscriber<T> {
+    queue: Mutex<SubscriberQueue<T>>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Counters {
+    dropped: AtomicU64,
+    resyncs: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SendQueueMetrics {
+    pub subscribers: usize,
+    // Events dropped from queues of slow subscribers
+    pub dropped: u64,
+    // Subscribers resynchronized from keyframe after overflow
+    pub resyncs: u64,
+    // Lagging subscribers disconnected
+    pub disconnected: u64,
+}
+
+pub struct SendQueues<T> {
+    capacity: usize,
+    subscribers: Mutex<Vec<Arc<Subscriber<T>>>>,
+    counters: Counters,
+}
+
+pub struct QueueReceiver<T>(Arc<Subscriber<T>>);
+
+impl<T: Clone + Keyframe> SendQueues<T> {
+    pub fn new(capacity: usize) -> SendQueues<T> {
+        SendQueues {
+            capacity,
+            subscribers: Mutex::new(Vec::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> QueueReceiver<T> {
+        let subscriber = Arc::new(Subscriber {
+            queue: Mutex::new(SubscriberQueue {
+                events: VecDeque::with_capacity(self.capacity),
+                awaiting_keyframe: false,
+                overflows: 0,
+                disconnected: false,
+            }),
+            notify: Notify::new(),
+        });
+        self.subscribers.lock().unwrap().push(subscriber.clone());
+        QueueReceiver(subscriber)
+    }
+
+    // Subscribers still receiving events. Receivers which are dropped are forgotten
+    pub fn subscriber_count(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| Arc::strong_count(subscriber) > 1);
+        subscribers.len()
+    }
+
+    pub fn publish(&self, event: T) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| Arc::strong_count(subscriber) > 1);
+        subscribers.retain(|subscriber| {
+            let connected = self.push(subscriber, &event);
+            subscriber.notify.notify_one();
+            connected
+        });
+    }
+
+    // Queue event for subscriber, returns false if subscriber is disconnected
+    fn push(&self, subscriber: &Subscriber<T>, event: &T) -> bool {
+        let mut queue = subscriber.queue.lock().unwrap();
+        if queue.awaiting_keyframe && !event.is_keyframe() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        if queue.events.len() >= self.capacity {
+            queue.overflows += 1;
+            let dropped = queue.events.len() as u64;
+            queue.events.clear();
+            self.counters.dropped.fetch_add(dropped, Ordering::Relaxed);
+            if queue.overflows >= MAX_OVERFLOWS {
+                queue.disconnected = true;
+                self.counters.disconnected.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            self.counters.resyncs.fetch_add(1, Ordering::Relaxed);
+            if !event.is_keyframe() {
+                queue.awaiting_keyframe = true;
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+        queue.awaiting_keyframe = false;
+        queue.events.push_back(event.clone());
+        true
+    }
+
+    pub fn metrics(&self) -> SendQueueMetrics {
+        SendQueueMetrics {
+            subscribers: self.subscriber_count(),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            resyncs: self.counters.resyncs.load(Ordering::Relaxed),
+            disconnected: self.counters.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    // Next event, None when subscriber is disconnected
+    pub async fn recv(&self) -> Option<T> {
+        loop {
+            {
+                let mut queue = self.0.queue.lock().unwrap();
+                if let Some(event) = queue.events.pop_front() {
+                    // Subscriber caught up with the stream
+                    if queue.events.is_empty() {
+                        queue.overflows = 0;
+                    }
+                    return Some(event);
+                }
+                if queue.disconnected {
+                    return None;
+                }
+            }
+            self.0.notify.notified().await;
+        }
+    }
+}
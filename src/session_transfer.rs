@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::{
+    connections::Connections,
+    error::Error,
+    ids::UserId,
+    sessions::{self, Device, Sessions},
+    storage::Database,
+    TetrisMatches,
+};
+use gameserver_protocol::session::{TransferCode, TransferStatus};
+use persy::PersyId;
+use rand::Rng;
+use rocket::{
+    form::Form, get, http::CookieJar, post, routes, serde::json::Json, FromForm, Route, State,
+};
+
+//
+// Transfer of user's session and game to another device. POST /session/transfer gives
+// a short code valid for CODE_TTL, client shows it as text and as QR code of the claim url.
+// POST /session/claim with the code on the other device logs it in as the user with new
+// session, see sessions::login, and the game goes on there since games are of the user.
+// Session of the old device is revoked and it's streams are closed, so it loses control.
+// With spectate the old device may follow the game: GET /session/transfer/<code> tells
+// it the match to watch once the code is claimed, until the code expires
+//
+
+// Codes are valid for this time, seconds
+const CODE_TTL: u64 = 300;
+const CODE_LEN: usize = 8;
+// Without similar looking characters, codes are typed in by hand
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+struct Transfer {
+    user: UserId,
+    // Session of the old device, revoked on claim
+    session: Option<PersyId>,
+    spectate: bool,
+    // Seconds since unix epoch
+    expires: u64,
+    claimed: bool,
+}
+
+// Codes not expired yet
+#[derive(Default)]
+pub struct SessionTransfers(RwLock<HashMap<String, Transfer>>);
+
+#[derive(FromForm)]
+pub struct TransferForm {
+    spectate: bool,
+}
+
+#[derive(FromForm)]
+pub struct ClaimForm {
+    code: String,
+}
+
+fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LEN)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+// Codes are accepted in any case and with spaces or dashes in between
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+impl SessionTransfers {
+    pub fn new() -> SessionTransfers {
+        SessionTransfers::default()
+    }
+
+    // New code of the user, replaces unclaimed codes given before
+    fn start(&self, user: UserId, session: Option<PersyId>, spectate: bool) -> TransferCode {
+        let now = crate::unix_time();
+        let mut transfers = self.0.write().unwrap();
+        transfers.retain(|_, transfer| {
+            transfer.expires > now && (transfer.claimed || transfer.user != user)
+        });
+        let mut code = new_code();
+        while transfers.contains_key(&code) {
+            code = new_code();
+        }
+        let expires = now + CODE_TTL;
+        transfers.insert(
+            code.clone(),
+            Transfer {
+                user,
+                session,
+                spectate,
+                expires,
+                claimed: false,
+            },
+        );
+        TransferCode {
+            claim_url: format!("/tetris/client.html?claim={}", code),
+            code,
+            expires,
+        }
+    }
+
+    // Mark code as claimed, returns user and session of the old device
+    fn claim(&self, code: &str) -> Result<(UserId, Option<PersyId>), Error> {
+        let mut transfers = self.0.write().unwrap();
+        let transfer = transfers
+            .get_mut(code)
+            .filter(|transfer| !transfer.claimed && transfer.expires > crate::unix_time())
+            .ok_or_else(|| {
+                Error::NotFoundError("Transfer code is invalid or expired".to_string())
+            })?;
+        transfer.claimed = true;
+        let claimed = (transfer.user, transfer.session);
+        // Nothing to tell the old device which doesn't spectate
+        if !transfer.spectate {
+            transfers.remove(code);
+        }
+        Ok(claimed)
+    }
+
+    fn status(&self, code: &str, matches: &TetrisMatches) -> Result<TransferStatus, Error> {
+        let transfers = self.0.read().unwrap();
+        let transfer = transfers
+            .get(code)
+            .filter(|transfer| transfer.expires > crate::unix_time())
+            .ok_or_else(|| {
+                Error::NotFoundError("Transfer code is invalid or expired".to_string())
+            })?;
+        let watch_url = (transfer.claimed && transfer.spectate)
+            .then(|| matches.match_id(transfer.user))
+            .flatten()
+            .map(|match_id| format!("/games/sse?ids={}", match_id));
+        Ok(TransferStatus {
+            claimed: transfer.claimed,
+            expires: transfer.expires,
+            watch_url,
+        })
+    }
+}
+
+// Code to move the session and the game to another device
+#[post("/session/transfer", data = "<form>")]
+fn transfer(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    transfers: &State<SessionTransfers>,
+    form: Option<Form<TransferForm>>,
+) -> Result<Json<TransferCode>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let session = sessions::current(&db.read(), cookie_jar)?.map(|(id, _)| id);
+    let spectate = form.is_some_and(|form| form.spectate);
+    Ok(Json(transfers.start(user_id, session, spectate)))
+}
+
+// Whether the code is claimed, with the game to watch for the spectating old device
+#[get("/session/transfer/<code>")]
+fn transfer_status(
+    matches: &State<TetrisMatches>,
+    transfers: &State<SessionTransfers>,
+    code: &str,
+) -> Result<Json<TransferStatus>, Error> {
+    Ok(Json(transfers.status(&normalize(code), matches)?))
+}
+
+// Log this device in as the user of the code, the old device loses control
+#[post("/session/claim", data = "<form>")]
+#[allow(clippy::too_many_arguments)]
+fn claim(
+    cookie_jar: &CookieJar,
+    db: &State<Database>,
+    sessions: &State<Sessions>,
+    transfers: &State<SessionTransfers>,
+    connections: &State<Connections>,
+    device: Device,
+    form: Form<ClaimForm>,
+) -> Result<Json<UserId>, Error> {
+    let persy = &*db.read();
+    let (user, session) = transfers.claim(&normalize(&form.code))?;
+    if let Some(session) = session {
+        sessions.revoke(persy, user, &session)?;
+    }
+    let closed = connections.close_user(user);
+    sessions::login(persy, cookie_jar, user, device)?;
+    println!(
+        "Session of user {} transferred, {} connections of the old device closed",
+        user, closed
+    );
+    Ok(Json(user))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![transfer, transfer_status, claim]
+}
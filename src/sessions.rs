@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    delete,
+    fairing::{Fairing, Info, Kind},
+    get,
+    http::{uri::Origin, Cookie, CookieJar, Method, SameSite, Status},
+    request::{FromRequest, Outcome},
+    routes,
+    serde::json::Json,
+    Data, Request, Response, Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Sessions: devices using the same user id. Each device gets own session cookie,
+// sessions can be listed and revoked. Revoked device loses the user id and gets a new one.
+// User id cookie is accepted only with a session of the user, sessions are issued with new
+// user ids and by login. Ids which come without their session, forged ones or ids issued
+// before sessions, are dropped and the device gets a new id. Request whose session can't be
+// checked, e.g. on database error, is refused instead of going on with an unchecked id
+//
+
+const SESSIONS_SEGMENT: &str = "sessions";
+const BY_TOKEN_INDEX: &str = "sessions_by_token";
+const BY_USER_INDEX: &str = "sessions_by_user";
+
+const SESSION_COOKIE: &str = "session";
+
+// Last seen time is updated not more often than this, seconds
+const LAST_SEEN_RESOLUTION: u64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub user: UserId,
+    // Secret value of session cookie
+    token: String,
+    pub user_agent: Option<String>,
+    // Network part of client address
+    pub ip_prefix: Option<String>,
+    pub created: u64,
+    pub last_seen: u64,
+    pub revoked: bool,
+    // Admission challenge is solved, see admission
+    #[serde(default)]
+    pub admitted: bool,
+}
+
+// Session info shown to the user
+#[derive(Serialize)]
+pub struct SessionItem {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip_prefix: Option<String>,
+    pub created: u64,
+    pub last_seen: u64,
+    // Session of the device making request
+    pub current: bool,
+}
+
+// Device making request, as recorded in it's session
+pub struct Device {
+    user_agent: Option<String>,
+    ip_prefix: Option<String>,
+}
+
+impl Device {
+    fn of(request: &Request<'_>) -> Device {
+        Device {
+            user_agent: request.headers().get_one("User-Agent").map(str::to_string),
+            ip_prefix: crate::proxies::client_ip(request).map(ip_prefix),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Device {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(Device::of(request))
+    }
+}
+
+// Revocation list. Tokens of revoked sessions are kept in memory,
+// so the requests of revoked devices are rejected without database lookup
+pub struct Sessions {
+    revoked: RwLock<HashSet<String>>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, SESSIONS_SEGMENT)?;
+    storage::ensure_index::<String, PersyId>(persy, BY_TOKEN_INDEX, ValueMode::Replace)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+pub fn ip_prefix(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+        }
+    }
+}
+
+fn new_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+fn find_by_token(persy: &Persy, token: &str) -> Result<Option<(PersyId, Session)>, Error> {
+    let Some(id) = persy.one::<String, PersyId>(BY_TOKEN_INDEX, &token.to_string())? else {
+        return Ok(None);
+    };
+    Ok(storage::read::<Session>(persy, SESSIONS_SEGMENT, &id)?.map(|session| (id, session)))
+}
+
+pub fn sessions_of_user(persy: &Persy, user: UserId) -> Result<Vec<(PersyId, Session)>, Error> {
+    let mut sessions = Vec::new();
+    for id in persy.get::<u32, PersyId>(BY_USER_INDEX, &user.0)? {
+        if let Some(session) = storage::read::<Session>(persy, SESSIONS_SEGMENT, &id)? {
+            sessions.push((id, session));
+        }
+    }
+    Ok(sessions)
+}
+
+// Store new session of the device, returns it's token
+fn insert(persy: &Persy, user: UserId, device: Device) -> Result<String, Error> {
+    let now = crate::unix_time();
+    let session = Session {
+        user,
+        token: new_token(),
+        user_agent: device.user_agent,
+        ip_prefix: device.ip_prefix,
+        created: now,
+        last_seen: now,
+        revoked: false,
+        admitted: false,
+    };
+    storage::insert_with(persy, SESSIONS_SEGMENT, &session, |tx, id| {
+        tx.put(BY_TOKEN_INDEX, session.token.clone(), *id)?;
+        tx.put(BY_USER_INDEX, session.user.0, *id)?;
+        Ok(())
+    })?;
+    Ok(session.token)
+}
+
+// Create new session of the device, it's token is set as session cookie
+fn create(persy: &Persy, cookies: &CookieJar, user: UserId, device: Device) -> Result<(), Error> {
+    cookies.add(Cookie::new(SESSION_COOKIE, insert(persy, user, device)?));
+    Ok(())
+}
+
+// Session of the device's cookie, also the one created for this request
+pub fn current(persy: &Persy, cookies: &CookieJar) -> Result<Option<(PersyId, Session)>, Error> {
+    let Some(token) = current_token(cookies) else {
+        return Ok(None);
+    };
+    Ok(find_by_token(persy, &token)?.filter(|(_, session)| !session.revoked))
+}
+
+// Session cookie of the device, also the one created for this request
+pub fn current_token(cookies: &CookieJar) -> Option<String> {
+    cookies
+        .get_pending(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+pub fn is_admitted(persy: &Persy, cookies: &CookieJar) -> Result<bool, Error> {
+    Ok(current(persy, cookies)?.is_some_and(|(_, session)| session.admitted))
+}
+
+pub fn set_admitted(persy: &Persy, cookies: &CookieJar) -> Result<(), Error> {
+    let (id, mut session) = current(persy, cookies)?
+        .ok_or_else(|| Error::NotFoundError("Session not found".to_string()))?;
+    session.admitted = true;
+    storage::update(persy, SESSIONS_SEGMENT, &id, &session)
+}
+
+// Log the device in as the user, with new session, e.g. after login link is followed.
+// Previous user id and session of the device are replaced
+pub fn login(
+    persy: &Persy,
+    cookies: &CookieJar,
+    user: UserId,
+    device: Device,
+) -> Result<(), Error> {
+    create(persy, cookies, user, device)?;
+    cookies.add(Cookie::new("user_id", user.to_string()));
+    Ok(())
+}
+
+impl Sessions {
+    pub fn load(persy: &Persy) -> Result<Sessions, Error> {
+        let revoked = storage::scan::<Session>(persy, SESSIONS_SEGMENT)?
+            .into_iter()
+            .filter(|(_, session)| session.revoked)
+            .map(|(_, session)| session.token)
+            .collect();
+        Ok(Sessions {
+            revoked: RwLock::new(revoked),
+        })
+    }
+
+    pub fn is_revoked(&self, token: &str) -> bool {
+        self.revoked.read().unwrap().contains(token)
+    }
+
+    // Revoke user's session. Sessions of other users are reported as not found
+    pub fn revoke(&self, persy: &Persy, user: UserId, id: &PersyId) -> Result<(), Error> {
+        let mut session = storage::read::<Session>(persy, SESSIONS_SEGMENT, id)?
+            .filter(|session| session.user == user)
+            .ok_or_else(|| Error::NotFoundError("Session not found".to_string()))?;
+        session.revoked = true;
+        storage::update(persy, SESSIONS_SEGMENT, id, &session)?;
+        self.revoked.write().unwrap().insert(session.token);
+        Ok(())
+    }
+
+    // Check session of request. Returns false if user id cookie must be dropped
+    fn check(&self, persy: &Persy, request: &Request<'_>, user: UserId) -> Result<bool, Error> {
+        let cookies = request.cookies();
+        let now = crate::unix_time();
+        let device = Device::of(request);
+        if let Some(token) = cookies.get(SESSION_COOKIE).map(|c| c.value().to_string()) {
+            if self.is_revoked(&token) {
+                return Ok(false);
+            }
+            if let Some((id, mut session)) = find_by_token(persy, &token)? {
+                if session.user == user && !session.revoked {
+                    if now >= session.last_seen + LAST_SEEN_RESOLUTION {
+                        session.last_seen = now;
+                        session.user_agent = device.user_agent;
+                        session.ip_prefix = device.ip_prefix;
+                        storage::update(persy, SESSIONS_SEGMENT, &id, &session)?;
+                    }
+                    return Ok(true);
+                }
+            }
+        }
+        // Session isn't issued for an id of the cookie, it may be forged
+        Ok(false)
+    }
+}
+
+// Guards every request by session check and issues sessions of new user ids
+pub struct SessionFairing;
+
+// Route of requests whose session couldn't be checked
+const UNAVAILABLE_PATH: &str = "/session/unavailable";
+
+#[rocket::async_trait]
+impl Fairing for SessionFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Session check",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(user) = request
+            .cookies()
+            .get("user_id")
+            .and_then(|c| c.value().parse::<UserId>().ok())
+        else {
+            return;
+        };
+        let (Some(db), Some(sessions)) = (
+            request.rocket().state::<Database>(),
+            request.rocket().state::<Sessions>(),
+        ) else {
+            return;
+        };
+        let valid = sessions.check(&db.read(), request, user);
+        match valid {
+            Ok(true) => (),
+            Ok(false) => {
+                println!(
+                    "Session of user {} rejected, client {:?}",
+                    user,
+                    crate::proxies::client_ip(request)
+                );
+                request.cookies().remove(Cookie::from("user_id"));
+                request.cookies().remove(Cookie::from(SESSION_COOKIE));
+            }
+            Err(e) => {
+                println!(
+                    "Session check of client {:?} failed: {}",
+                    crate::proxies::client_ip(request),
+                    e
+                );
+                request.set_method(Method::Get);
+                request.set_uri(Origin::parse(UNAVAILABLE_PATH).unwrap());
+            }
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(user) = response
+            .cookies()
+            .find(|c| c.name() == "user_id")
+            .and_then(|c| c.value().parse::<UserId>().ok())
+        else {
+            return;
+        };
+        // Issued with it's session, e.g. by login
+        if response
+            .cookies()
+            .any(|c| c.name() == SESSION_COOKIE && !c.value().is_empty())
+        {
+            return;
+        }
+        let Some(db) = request.rocket().state::<Database>() else {
+            return;
+        };
+        match insert(&db.read(), user, Device::of(request)) {
+            // Cookie jar is sent already, the cookie gets it's defaults here
+            Ok(token) => response.adjoin_header(
+                Cookie::build((SESSION_COOKIE, token))
+                    .path("/")
+                    .same_site(SameSite::Strict)
+                    .secure(request.rocket().config().tls_enabled())
+                    .build(),
+            ),
+            Err(e) => println!("Session of new user {} failed: {}", user, e),
+        }
+    }
+}
+
+// Request refused as it's session couldn't be checked
+#[get("/session/unavailable")]
+fn unavailable() -> Status {
+    Status::ServiceUnavailable
+}
+
+// Active sessions of the user
+#[get("/account/sessions")]
+fn account_sessions(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<Vec<SessionItem>>, Error> {
+    let persy = &*db.read();
+    let user_id = crate::user_id(cookie_jar, matches);
+    let token = cookie_jar
+        .get_pending(SESSION_COOKIE)
+        .map(|c| c.value().to_string());
+    Ok(Json(
+        sessions_of_user(persy, user_id)?
+            .into_iter()
+            .filter(|(_, session)| !session.revoked)
+            .map(|(id, session)| SessionItem {
+                id: id.to_string(),
+                current: token.as_deref() == Some(session.token.as_str()),
+                user_agent: session.user_agent,
+                ip_prefix: session.ip_prefix,
+                created: session.created,
+                last_seen: session.last_seen,
+            })
+            .collect(),
+    ))
+}
+
+// Revoke session, the device will be logged out on it's next request
+#[delete("/account/sessions/<id>")]
+fn revoke_session(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    sessions: &State<Sessions>,
+    id: &str,
+) -> Result<(), Error> {
+    let persy = &*db.read();
+    let user_id = crate::user_id(cookie_jar, matches);
+    sessions.revoke(persy, user_id, &storage::parse_id(id)?)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![account_sessions, revoke_session, unavailable]
+}
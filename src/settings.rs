@@ -0,0 +1,277 @@
+use std::sync::{Arc, RwLock};
+
+use persy::Persy;
+use rocket::{
+    form::Form, get, post, response::Redirect, routes, tokio::sync::watch, Config, FromForm, Route,
+    State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admission, cache,
+    error::Error,
+    input_sequence, maintenance,
+    roles::{Operator, Viewer},
+    storage::{self, Database},
+    tetris_pair::STEP_MS,
+};
+
+//
+// Runtime settings: configuration values which admin may override from /admin/config
+// without restart. Overrides are stored in the database and win over the config file.
+// Settings are published through a watch channel, subsystems hold receivers of it and
+// read current value on each use: tick interval of the scheduler, capacity of response
+// caches, input rate limit, new user ids limit of admission and default maintenance message
+//
+
+const SETTINGS_SEGMENT: &str = "settings";
+
+// Allowed ranges of overrides
+const TICK_MS: (u64, u64) = (1, 100);
+const CACHE_CAPACITY: (usize, usize) = (10, 100_000);
+const INPUT_RATE_LIMIT: (usize, usize) = (1, 1000);
+const NEW_IDENTITY_LIMIT: (usize, usize) = (1, 100_000);
+const MAX_MESSAGE_LEN: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Settings {
+    // Interval between ticks of versus games, milliseconds
+    pub tick_ms: u64,
+    // Entries of a response cache before least recently used ones are evicted
+    pub cache_capacity: usize,
+    // Inputs per user and second
+    pub input_rate_limit: usize,
+    // New user ids per client network and window, see admission
+    pub new_identity_limit: usize,
+    // Message of maintenance turned on without one
+    pub maintenance_message: String,
+}
+
+// Values set by admin, None for the configured value
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Overrides {
+    pub tick_ms: Option<u64>,
+    pub cache_capacity: Option<usize>,
+    pub input_rate_limit: Option<usize>,
+    pub new_identity_limit: Option<usize>,
+    pub maintenance_message: Option<String>,
+}
+
+// Empty fields clear the override
+#[derive(FromForm)]
+pub struct OverridesForm {
+    tick_ms: String,
+    cache_capacity: String,
+    input_rate_limit: String,
+    new_identity_limit: String,
+    maintenance_message: String,
+}
+
+// Subsystems read current settings from it on each use
+pub type SettingsWatch = watch::Receiver<Settings>;
+
+#[derive(Clone)]
+pub struct RuntimeSettings {
+    // Values of the config file
+    defaults: Settings,
+    overrides: Arc<RwLock<Overrides>>,
+    sender: Arc<watch::Sender<Settings>>,
+}
+
+impl Settings {
+    pub fn from_config() -> Settings {
+        let figment = Config::figment();
+        Settings {
+            tick_ms: figment.extract_inner("tick_ms").unwrap_or(STEP_MS),
+            cache_capacity: figment
+                .extract_inner("cache_capacity")
+                .unwrap_or(cache::DEFAULT_CAPACITY),
+            input_rate_limit: figment
+                .extract_inner("input_rate_limit")
+                .unwrap_or(input_sequence::DEFAULT_RATE_LIMIT),
+            new_identity_limit: figment
+                .extract_inner("new_identity_limit")
+                .unwrap_or(admission::DEFAULT_IDENTITY_LIMIT),
+            maintenance_message: figment
+                .extract_inner("maintenance_message")
+                .unwrap_or_else(|_| maintenance::DEFAULT_MESSAGE.to_string()),
+        }
+    }
+
+    fn with(&self, overrides: &Overrides) -> Settings {
+        Settings {
+            tick_ms: overrides.tick_ms.unwrap_or(self.tick_ms),
+            cache_capacity: overrides.cache_capacity.unwrap_or(self.cache_capacity),
+            input_rate_limit: overrides.input_rate_limit.unwrap_or(self.input_rate_limit),
+            new_identity_limit: overrides
+                .new_identity_limit
+                .unwrap_or(self.new_identity_limit),
+            maintenance_message: overrides
+                .maintenance_message
+                .clone()
+                .unwrap_or_else(|| self.maintenance_message.clone()),
+        }
+    }
+}
+
+fn parse_in<T: std::str::FromStr + PartialOrd + std::fmt::Display>(
+    name: &str,
+    value: &str,
+    (min, max): (T, T),
+) -> Result<Option<T>, Error> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse::<T>() {
+        Ok(parsed) if parsed >= min && parsed <= max => Ok(Some(parsed)),
+        _ => Err(Error::InvalidInputError(format!(
+            "{} must be from {} to {}",
+            name, min, max
+        ))),
+    }
+}
+
+impl OverridesForm {
+    // Form of current overrides, so one of them can be changed
+    fn of(overrides: &Overrides) -> OverridesForm {
+        let text = |value: Option<String>| value.unwrap_or_default();
+        OverridesForm {
+            tick_ms: text(overrides.tick_ms.map(|value| value.to_string())),
+            cache_capacity: text(overrides.cache_capacity.map(|value| value.to_string())),
+            input_rate_limit: text(overrides.input_rate_limit.map(|value| value.to_string())),
+            new_identity_limit: text(overrides.new_identity_limit.map(|value| value.to_string())),
+            maintenance_message: text(overrides.maintenance_message.clone()),
+        }
+    }
+
+    fn parse(&self) -> Result<Overrides, Error> {
+        let message = self.maintenance_message.trim();
+        if message.chars().count() > MAX_MESSAGE_LEN {
+            return Err(Error::InvalidInputError(format!(
+                "Maintenance message must not be longer than {} characters",
+                MAX_MESSAGE_LEN
+            )));
+        }
+        Ok(Overrides {
+            tick_ms: parse_in("Tick interval", &self.tick_ms, TICK_MS)?,
+            cache_capacity: parse_in("Cache capacity", &self.cache_capacity, CACHE_CAPACITY)?,
+            input_rate_limit: parse_in(
+                "Input rate limit",
+                &self.input_rate_limit,
+                INPUT_RATE_LIMIT,
+            )?,
+            new_identity_limit: parse_in(
+                "New user ids limit",
+                &self.new_identity_limit,
+                NEW_IDENTITY_LIMIT,
+            )?,
+            maintenance_message: Some(message.to_string()).filter(|message| !message.is_empty()),
+        })
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, SETTINGS_SEGMENT)
+}
+
+// Configured settings which never change, e.g. for benchmark
+pub fn fixed() -> SettingsWatch {
+    watch::channel(Settings::from_config()).1
+}
+
+impl RuntimeSettings {
+    pub fn load(persy: &Persy) -> Result<RuntimeSettings, Error> {
+        let overrides = storage::scan::<Overrides>(persy, SETTINGS_SEGMENT)?
+            .into_iter()
+            .next()
+            .map(|(_, overrides)| overrides)
+            .unwrap_or_default();
+        let defaults = Settings::from_config();
+        let (sender, _) = watch::channel(defaults.with(&overrides));
+        Ok(RuntimeSettings {
+            defaults,
+            overrides: Arc::new(RwLock::new(overrides)),
+            sender: Arc::new(sender),
+        })
+    }
+
+    pub fn current(&self) -> Settings {
+        self.sender.borrow().clone()
+    }
+
+    // Receiver of current settings for a subsystem
+    pub fn subscribe(&self) -> SettingsWatch {
+        self.sender.subscribe()
+    }
+
+    fn set(&self, persy: &Persy, overrides: Overrides) -> Result<(), Error> {
+        match storage::scan::<Overrides>(persy, SETTINGS_SEGMENT)?.first() {
+            Some((id, _)) => storage::update(persy, SETTINGS_SEGMENT, id, &overrides)?,
+            None => {
+                storage::insert(persy, SETTINGS_SEGMENT, &overrides)?;
+            }
+        }
+        let settings = self.defaults.with(&overrides);
+        *self.overrides.write().unwrap() = overrides;
+        println!("Runtime settings changed: {:?}", settings);
+        self.sender.send_if_modified(|current| {
+            let modified = *current != settings;
+            *current = settings;
+            modified
+        });
+        Ok(())
+    }
+
+    // Override one setting by it's name, empty value clears the override
+    pub fn set_one(&self, persy: &Persy, name: &str, value: &str) -> Result<Settings, Error> {
+        let mut form = OverridesForm::of(&self.overrides.read().unwrap());
+        let field = match name {
+            "tick_ms" => &mut form.tick_ms,
+            "cache_capacity" => &mut form.cache_capacity,
+            "input_rate_limit" => &mut form.input_rate_limit,
+            "new_identity_limit" => &mut form.new_identity_limit,
+            "maintenance_message" => &mut form.maintenance_message,
+            _ => {
+                return Err(Error::InvalidInputError(format!(
+                    "Unknown setting {}",
+                    name
+                )))
+            }
+        };
+        *field = value.to_string();
+        self.set(persy, form.parse()?)?;
+        Ok(self.current())
+    }
+}
+
+// Current settings, configured values and overrides
+#[get("/admin/config")]
+fn admin_config(_admin: Viewer, settings: &State<RuntimeSettings>) -> Template {
+    Template::render(
+        "admin/config",
+        context! {
+            settings: settings.current(),
+            defaults: &settings.defaults,
+            overrides: &*settings.overrides.read().unwrap(),
+        },
+    )
+}
+
+// Replace overrides, applied without restart
+#[post("/admin/config", data = "<form>")]
+fn set_config(
+    _admin: Operator,
+    db: &State<Database>,
+    settings: &State<RuntimeSettings>,
+    form: Form<OverridesForm>,
+) -> Result<Redirect, Error> {
+    let overrides = form.parse()?;
+    settings.set(&db.read(), overrides)?;
+    Ok(Redirect::to("/admin/config"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_config, set_config]
+}
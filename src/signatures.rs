@@ -0,0 +1,415 @@
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use persy::Persy;
+use ring::{
+    rand::SystemRandom,
+    signature::{self, Ed25519KeyPair, KeyPair},
+};
+use rocket::{
+    get,
+    http::{ContentType, Status},
+    post, routes,
+    serde::json::{serde_json, Json},
+    Config, Route, State,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    game_mode::GameMode,
+    ids::{GameId, UserId},
+    leaderboard::{self, Verification},
+    match_history,
+    storage::{self, Database},
+};
+
+//
+// Signed results for organizers of external tournaments. The server has an Ed25519 key,
+// generated on the first start and kept in "signing_key" segment. GET /results/match/<id>
+// and /results/game/<id> return the final result of a versus match or a leaderboard game
+// as JSON payload with signature of it's exact bytes. Anyone can check the signature with
+// the public key of /results/key, also as PEM file /results/key.pem, or by POST
+// /results/verify, so results shown on screenshots or pasted as JSON can be confirmed
+// as given by this server
+//
+
+const KEY_SEGMENT: &str = "signing_key";
+const ALGORITHM: &str = "Ed25519";
+// DER prefix of Ed25519 public key in SubjectPublicKeyInfo, followed by 32 key bytes
+const SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    // PKCS#8 document of the key pair, base64
+    pkcs8: String,
+    // Seconds since unix epoch
+    created: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResultPlayer {
+    pub user: UserId,
+    pub score: u64,
+    pub lines: u64,
+    pub forfeited: bool,
+}
+
+// Signed statement of the server
+#[derive(Serialize, Deserialize)]
+pub struct ResultStatement {
+    // Public address of the server when it's configured
+    pub issuer: Option<String>,
+    pub key_id: String,
+    // "match" for versus matches, "game" for leaderboard games
+    pub kind: String,
+    pub id: String,
+    pub mode: GameMode,
+    // Finish and signing time, seconds since unix epoch
+    pub finished: u64,
+    pub signed: u64,
+    // Game duration in steps
+    pub ticks: u64,
+    pub players: Vec<ResultPlayer>,
+    // Replay check of the leaderboard game, None for matches
+    pub verification: Option<Verification>,
+}
+
+// Payload is the statement as signed, verified byte for byte
+#[derive(Serialize, Deserialize)]
+pub struct SignedResult {
+    pub payload: String,
+    pub algorithm: String,
+    pub key_id: String,
+    // Base64
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+pub struct PublicKey {
+    pub algorithm: &'static str,
+    pub key_id: String,
+    // Raw 32 bytes of the key, base64
+    pub public_key: String,
+    pub created: u64,
+}
+
+#[derive(Serialize)]
+pub struct Verified {
+    pub valid: bool,
+    // Statement of valid payload
+    pub result: Option<ResultStatement>,
+}
+
+// Server key pair, shared by the routes
+#[derive(Clone)]
+pub struct ResultSigner {
+    key_pair: Arc<Ed25519KeyPair>,
+    key_id: String,
+    created: u64,
+    issuer: Option<String>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, KEY_SEGMENT)
+}
+
+impl ResultSigner {
+    // Load key of previous runs, new one is generated when there's none
+    pub fn load(persy: &Persy) -> Result<ResultSigner, Error> {
+        let stored = match storage::scan::<StoredKey>(persy, KEY_SEGMENT)?
+            .into_iter()
+            .map(|(_, key)| key)
+            .min_by_key(|key| key.created)
+        {
+            Some(key) => key,
+            None => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())?;
+                let key = StoredKey {
+                    pkcs8: BASE64.encode(pkcs8.as_ref()),
+                    created: crate::unix_time(),
+                };
+                storage::insert(persy, KEY_SEGMENT, &key)?;
+                key
+            }
+        };
+        let pkcs8 = BASE64
+            .decode(&stored.pkcs8)
+            .map_err(|e| Error::SigningError(e.to_string()))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)?;
+        // Short fingerprint of the public key, tells keys apart if the key is ever replaced
+        let key_id = Sha256::digest(key_pair.public_key().as_ref())[..8]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Ok(ResultSigner {
+            key_pair: Arc::new(key_pair),
+            key_id,
+            created: stored.created,
+            issuer: Config::figment()
+                .extract_inner::<String>("public_url")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string()),
+        })
+    }
+
+    fn sign(&self, statement: &ResultStatement) -> Result<SignedResult, Error> {
+        let payload = serde_json::to_string(statement)?;
+        let signature = self.key_pair.sign(payload.as_bytes());
+        Ok(SignedResult {
+            payload,
+            algorithm: ALGORITHM.to_string(),
+            key_id: self.key_id.clone(),
+            signature: BASE64.encode(signature.as_ref()),
+        })
+    }
+
+    fn verify(&self, signed: &SignedResult) -> bool {
+        let Ok(signature) = BASE64.decode(&signed.signature) else {
+            return false;
+        };
+        signed.algorithm == ALGORITHM
+            && signed.key_id == self.key_id
+            && signature::UnparsedPublicKey::new(
+                &signature::ED25519,
+                self.key_pair.public_key().as_ref(),
+            )
+            .verify(signed.payload.as_bytes(), &signature)
+            .is_ok()
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PublicKey {
+            algorithm: ALGORITHM,
+            key_id: self.key_id.clone(),
+            public_key: BASE64.encode(self.key_pair.public_key().as_ref()),
+            created: self.created,
+        }
+    }
+
+    // Public key as SubjectPublicKeyInfo PEM, as read by openssl and most libraries
+    fn public_key_pem(&self) -> String {
+        let der = [&SPKI_PREFIX[..], self.key_pair.public_key().as_ref()].concat();
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            BASE64.encode(der)
+        )
+    }
+
+    fn statement(&self, kind: &str, id: String, mode: GameMode) -> ResultStatement {
+        ResultStatement {
+            issuer: self.issuer.clone(),
+            key_id: self.key_id.clone(),
+            kind: kind.to_string(),
+            id,
+            mode,
+            finished: 0,
+            signed: crate::unix_time(),
+            ticks: 0,
+            players: Vec::new(),
+            verification: None,
+        }
+    }
+}
+
+// Signed final result of versus match
+#[get("/results/match/<id>")]
+fn signed_match(
+    db: &State<Database>,
+    signer: &State<ResultSigner>,
+    id: &str,
+) -> Result<Json<SignedResult>, Error> {
+    let record = match_history::read(&db.read(), &storage::parse_id(id)?)?
+        .ok_or_else(|| Error::NotFoundError("Match not found".to_string()))?;
+    let statement = ResultStatement {
+        finished: record.finished,
+        ticks: record.ticks,
+        players: record
+            .players
+            .iter()
+            .map(|player| ResultPlayer {
+                user: player.user,
+                score: player.score,
+                lines: player.lines,
+                forfeited: player.forfeited,
+            })
+            .collect(),
+        ..signer.statement("match", id.to_string(), GameMode::Versus)
+    };
+    Ok(Json(signer.sign(&statement)?))
+}
+
+// Signed result of leaderboard game
+#[get("/results/game/<id>")]
+fn signed_game(
+    db: &State<Database>,
+    signer: &State<ResultSigner>,
+    id: GameId,
+) -> Result<Json<SignedResult>, Error> {
+    let entry = leaderboard::read(&db.read(), &id.0)?
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    let statement = ResultStatement {
+        finished: entry.finished,
+        ticks: entry.ticks,
+        players: vec![ResultPlayer {
+            user: entry.user,
+            score: entry.score,
+            lines: entry.lines,
+            forfeited: false,
+        }],
+        verification: Some(entry.verification),
+        ..signer.statement("game", id.to_string(), entry.mode)
+    };
+    Ok(Json(signer.sign(&statement)?))
+}
+
+// Public key to check signatures with
+#[get("/results/key")]
+fn public_key(signer: &State<ResultSigner>) -> Json<PublicKey> {
+    Json(signer.public_key())
+}
+
+#[get("/results/key.pem")]
+fn public_key_pem(signer: &State<ResultSigner>) -> (ContentType, String) {
+    (
+        ContentType::new("application", "x-pem-file"),
+        signer.public_key_pem(),
+    )
+}
+
+// Check signed result, invalid signature responds with 422 and valid false
+#[post("/results/verify", data = "<signed>")]
+fn verify(signer: &State<ResultSigner>, signed: Json<SignedResult>) -> (Status, Json<Verified>) {
+    if !signer.verify(&signed) {
+        return (
+            Status::UnprocessableEntity,
+            Json(Verified {
+                valid: false,
+                result: None,
+            }),
+        );
+    }
+    (
+        Status::Ok,
+        Json(Verified {
+            valid: true,
+            result: serde_json::from_str(&signed.payload).ok(),
+        }),
+    )
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        signed_match,
+        signed_game,
+        public_key,
+        public_key_pem,
+        verify
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use persy::OpenOptions;
+
+    use super::*;
+
+    fn signer() -> ResultSigner {
+        let persy = OpenOptions::new().memory().unwrap();
+        init(&persy).unwrap();
+        ResultSigner::load(&persy).unwrap()
+    }
+
+    fn signed(signer: &ResultSigner) -> SignedResult {
+        let statement = ResultStatement {
+            finished: 1000,
+            ticks: 600,
+            players: vec![ResultPlayer {
+                user: UserId(7),
+                score: 1200,
+                lines: 12,
+                forfeited: false,
+            }],
+            ..signer.statement("game", "1".to_string(), GameMode::Sprint)
+        };
+        signer.sign(&statement).unwrap()
+    }
+
+    #[test]
+    fn signed_result_verifies() {
+        let signer = signer();
+        let signed = signed(&signer);
+        assert_eq!(signed.algorithm, ALGORITHM);
+        assert_eq!(signed.key_id, signer.key_id);
+        assert!(signer.verify(&signed));
+        let statement = serde_json::from_str::<ResultStatement>(&signed.payload).unwrap();
+        assert_eq!(statement.key_id, signer.key_id);
+        assert_eq!(statement.players[0].score, 1200);
+    }
+
+    #[test]
+    fn key_is_kept_across_loads() {
+        let persy = OpenOptions::new().memory().unwrap();
+        init(&persy).unwrap();
+        let first = ResultSigner::load(&persy).unwrap();
+        let second = ResultSigner::load(&persy).unwrap();
+        assert_eq!(first.key_id, second.key_id);
+        assert!(second.verify(&signed(&first)));
+        // Key of another server doesn't verify
+        assert!(!signer().verify(&signed(&first)));
+    }
+
+    #[test]
+    fn tampered_results_are_rejected() {
+        let signer = signer();
+        let tampered = |tamper: fn(&mut SignedResult)| {
+            let mut signed = signed(&signer);
+            tamper(&mut signed);
+            signer.verify(&signed)
+        };
+        assert!(!tampered(|signed| {
+            signed.payload = signed.payload.replace("1200", "1300")
+        }));
+        assert!(!tampered(|signed| signed.payload.push(' ')));
+        assert!(!tampered(|signed| {
+            let mut signature = BASE64.decode(&signed.signature).unwrap();
+            signature[0] ^= 1;
+            signed.signature = BASE64.encode(signature);
+        }));
+        assert!(!tampered(
+            |signed| signed.signature = "not base64!".to_string()
+        ));
+        assert!(!tampered(|signed| signed.signature.clear()));
+        assert!(!tampered(
+            |signed| signed.key_id = "0123456789abcdef".to_string()
+        ));
+        assert!(!tampered(|signed| signed.algorithm = "RS256".to_string()));
+    }
+
+    #[test]
+    fn pem_is_spki_of_the_key() {
+        let signer = signer();
+        let pem = signer.public_key_pem();
+        let body = pem
+            .strip_prefix("-----BEGIN PUBLIC KEY-----\n")
+            .and_then(|pem| pem.strip_suffix("\n-----END PUBLIC KEY-----\n"))
+            .unwrap();
+        let der = BASE64.decode(body).unwrap();
+        // SEQUENCE of AlgorithmIdentifier with id-Ed25519 OID 1.3.101.112 and BIT STRING
+        // without unused bits holding the key
+        assert_eq!(der.len(), 44);
+        assert_eq!(der[..2], [0x30, der.len() as u8 - 2]);
+        assert_eq!(der[2..9], [0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70]);
+        assert_eq!(der[9..12], [0x03, 0x21, 0x00]);
+        let key = &der[12..];
+        assert_eq!(BASE64.encode(key), signer.public_key().public_key);
+        let signed = signed(&signer);
+        let signature = BASE64.decode(&signed.signature).unwrap();
+        signature::UnparsedPublicKey::new(&signature::ED25519, key)
+            .verify(signed.payload.as_bytes(), &signature)
+            .unwrap();
+    }
+}
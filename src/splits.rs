@@ -0,0 +1,153 @@
+use persy::{Persy, PersyId, ValueMode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    difficulty::Difficulty,
+    error::Error,
+    game_mode::GameMode,
+    ids::UserId,
+    storage,
+    tetris::{Replay, ReplayPlayer},
+};
+
+//
+// Split times of time attack games. Checkpoint is reached every CHECKPOINT_LINES cleared
+// lines, it's split time is compared with the same checkpoint of player's personal best.
+// Splits of the personal best are stored per user, mode and difficulty, personal bests
+// recorded before splits were stored get their splits from replay
+//
+
+const SPLITS_SEGMENT: &str = "splits";
+const BY_USER_INDEX: &str = "splits_by_user";
+
+pub const CHECKPOINT_LINES: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Splits {
+    pub user: UserId,
+    pub mode: GameMode,
+    pub difficulty: Difficulty,
+    // Game duration in steps
+    pub ticks: u64,
+    // Steps when checkpoints were reached
+    pub checkpoints: Vec<u64>,
+}
+
+// Reached checkpoint, sent as "checkpoint" event
+#[derive(Debug, Clone, Serialize)]
+pub struct Checkpoint {
+    pub lines: usize,
+    pub ticks: u64,
+    // Split of the personal best at this checkpoint
+    pub best: Option<u64>,
+    // Steps behind the personal best, negative when ahead
+    pub delta: Option<i64>,
+}
+
+// Checkpoints reached by a running game
+#[derive(Debug, Clone, Default)]
+pub struct SplitTracker {
+    reached: Vec<u64>,
+    best: Option<Vec<u64>>,
+}
+
+impl SplitTracker {
+    pub fn new(best: Option<Vec<u64>>) -> SplitTracker {
+        SplitTracker {
+            reached: Vec::new(),
+            best,
+        }
+    }
+
+    // Tracker of game continued from replay, checkpoints already reached are not announced
+    pub fn resumed(replay: &Replay, best: Option<Vec<u64>>) -> SplitTracker {
+        SplitTracker {
+            reached: replay_splits(replay),
+            best,
+        }
+    }
+
+    // Check for checkpoint after step, lines cleared so far
+    pub fn update(&mut self, lines: usize, ticks: u64) -> Option<Checkpoint> {
+        if lines < (self.reached.len() + 1) * CHECKPOINT_LINES {
+            return None;
+        }
+        let index = self.reached.len();
+        self.reached.push(ticks);
+        let best = self.best.as_ref().and_then(|best| best.get(index)).copied();
+        Some(Checkpoint {
+            lines: (index + 1) * CHECKPOINT_LINES,
+            ticks,
+            best,
+            delta: best.map(|best| ticks as i64 - best as i64),
+        })
+    }
+
+    pub fn reached(&self) -> &[u64] {
+        &self.reached
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, SPLITS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+// Checkpoints reached by the recorded game
+pub fn replay_splits(replay: &Replay) -> Vec<u64> {
+    let mut player = ReplayPlayer::new(replay.clone());
+    let mut splits = Vec::new();
+    while player.step() {
+        let tetris = player.get_tetris();
+        if tetris.get_lines() >= (splits.len() + 1) * CHECKPOINT_LINES {
+            splits.push(tetris.get_ticks());
+        }
+    }
+    splits
+}
+
+fn stored(
+    persy: &Persy,
+    user: UserId,
+    mode: GameMode,
+    difficulty: Difficulty,
+) -> Result<Option<(PersyId, Splits)>, Error> {
+    for id in persy.get::<u32, PersyId>(BY_USER_INDEX, &user.0)? {
+        if let Some(splits) = storage::read::<Splits>(persy, SPLITS_SEGMENT, &id)? {
+            if splits.mode == mode && splits.difficulty == difficulty {
+                return Ok(Some((id, splits)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Splits of personal best, from it's replay when they're not stored
+pub fn personal_best(
+    persy: &Persy,
+    user: UserId,
+    mode: GameMode,
+    difficulty: Difficulty,
+    best_replay: Option<&Replay>,
+) -> Result<Option<Vec<u64>>, Error> {
+    Ok(match stored(persy, user, mode, difficulty)? {
+        Some((_, splits)) => Some(splits.checkpoints),
+        None => best_replay.map(replay_splits),
+    })
+}
+
+// Store splits of completed game if it's faster than the stored personal best
+pub fn record(persy: &Persy, splits: &Splits) -> Result<(), Error> {
+    match stored(persy, splits.user, splits.mode, splits.difficulty)? {
+        Some((_, best)) if best.ticks <= splits.ticks => Ok(()),
+        Some((id, _)) => storage::update(persy, SPLITS_SEGMENT, &id, splits),
+        None => {
+            storage::insert_with(persy, SPLITS_SEGMENT, splits, |tx, id| {
+                tx.put(BY_USER_INDEX, splits.user.0, *id)?;
+                Ok(())
+            })?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::sync::{Arc, RwLock};
+
+use rocket::{
+    futures::{Stream, StreamExt},
+    get,
+    response::stream::{stream, EventStream},
+    routes,
+    serde::json::{serde_json, Json},
+    tokio::time::{self, Duration},
+    Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    connections::Connections,
+    events::ChannelEvent,
+    ids::MatchId,
+    ids::UserId,
+    roles::Viewer,
+    send_queue::{Keyframe, SendQueueMetrics, SendQueues},
+    tetris_pair::TetrisPairState,
+    views::{Projection, View},
+    TetrisMatches,
+};
+
+//
+// Spotlight: featured stream following the highest-scoring active game. Single background
+// task samples the featured game and broadcasts serialized frames to all viewers,
+// so viewers count doesn't affect the load on matches storage. Each viewer has own
+// bounded queue, slow viewer skips to the latest frame and is disconnected if it
+// keeps falling behind. Frames are broadcast when the game changes and repeated once
+// per KEYFRAME_INTERVAL otherwise, so new viewers get the state of a paused game
+//
+
+// Interval between spotlight frames
+const FRAME_INTERVAL: Duration = Duration::from_millis(50);
+// Interval of repeating unchanged frame
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(1);
+// Frames buffered per viewer
+const QUEUE_CAPACITY: usize = 16;
+
+#[derive(Clone, Serialize)]
+pub struct SpotlightFrame {
+    pub match_id: MatchId,
+    pub players: [UserId; 2],
+    pub scores: [usize; 2],
+    pub state: TetrisPairState,
+    // State hash, unchanged frames are not broadcast
+    #[serde(skip)]
+    pub hash: u64,
+}
+
+// Serialized frame of featured game in spectator views
+struct FrameViews {
+    queue: String,
+    hidden_queue: String,
+}
+
+#[derive(Clone)]
+enum SpotlightEvent {
+    // Serialized frame of featured game
+    Frame(Arc<FrameViews>),
+    // Featured game changed
+    Switched(MatchId),
+    // No active games
+    Idle,
+}
+
+// Frames carry full state of the game, so viewer can continue from any of them
+impl Keyframe for SpotlightEvent {
+    fn is_keyframe(&self) -> bool {
+        !matches!(self, SpotlightEvent::Switched(_))
+    }
+}
+
+impl Projection for SpotlightFrame {
+    fn project(self, view: View) -> SpotlightFrame {
+        SpotlightFrame {
+            state: self.state.project(view),
+            ..self
+        }
+    }
+}
+
+impl FrameViews {
+    // Frame is serialized once per view, not per viewer
+    fn new(frame: SpotlightFrame) -> FrameViews {
+        let serialize = |frame: SpotlightFrame, hide_queue| {
+            serde_json::to_string(&frame.project(View::Spectator { hide_queue })).unwrap()
+        };
+        FrameViews {
+            queue: serialize(frame.clone(), false),
+            hidden_queue: serialize(frame, true),
+        }
+    }
+}
+
+// Viewer queues and the featured match, shared with the broadcaster
+#[derive(Clone)]
+pub struct Spotlight(
+    Arc<SendQueues<SpotlightEvent>>,
+    Arc<RwLock<Option<MatchId>>>,
+);
+
+impl Spotlight {
+    pub fn new() -> Spotlight {
+        Spotlight(
+            Arc::new(SendQueues::new(QUEUE_CAPACITY)),
+            Arc::new(RwLock::new(None)),
+        )
+    }
+
+    // Broadcast frames of the featured match to viewers
+    pub async fn broadcaster_job(self, matches: TetrisMatches) {
+        Self::broadcaster(matches, self.0, self.1).await
+    }
+
+    // Featured match and number of it's spotlight viewers
+    pub fn featured(&self) -> Option<(MatchId, usize)> {
+        let featured = (*self.1.read().unwrap())?;
+        Some((featured, self.0.subscriber_count()))
+    }
+
+    // Viewers and their dropped frames
+    pub fn queue_metrics(&self) -> SendQueueMetrics {
+        self.0.metrics()
+    }
+
+    async fn broadcaster(
+        matches: TetrisMatches,
+        queues: Arc<SendQueues<SpotlightEvent>>,
+        shared: Arc<RwLock<Option<MatchId>>>,
+    ) {
+        let mut interval = time::interval(FRAME_INTERVAL);
+        let mut featured = None;
+        // Hash and time of the last broadcast frame
+        let mut last_frame: Option<(u64, time::Instant)> = None;
+        loop {
+            interval.tick().await;
+            *shared.write().unwrap() = featured;
+            if queues.subscriber_count() == 0 {
+                featured = None;
+                continue;
+            }
+            let event = match matches.featured_game(featured) {
+                Some(frame) if Some(frame.match_id) != featured => {
+                    featured = Some(frame.match_id);
+                    last_frame = None;
+                    SpotlightEvent::Switched(frame.match_id)
+                }
+                Some(frame) => {
+                    let unchanged = last_frame.is_some_and(|(hash, sent)| {
+                        hash == frame.hash && sent.elapsed() < KEYFRAME_INTERVAL
+                    });
+                    if unchanged {
+                        continue;
+                    }
+                    last_frame = Some((frame.hash, time::Instant::now()));
+                    SpotlightEvent::Frame(Arc::new(FrameViews::new(frame)))
+                }
+                None if featured.is_some() => {
+                    featured = None;
+                    SpotlightEvent::Idle
+                }
+                None => continue,
+            };
+            queues.publish(event);
+        }
+    }
+}
+
+// Featured game events. Game frames are default events, "switch" event is sent
+// when the featured game changes and "idle" when there are no active games.
+// Frames are in spectator view, see views
+pub fn spotlight_stream(
+    spotlight: &Spotlight,
+    view: View,
+) -> impl Stream<Item = ChannelEvent> + Send {
+    let receiver = spotlight.0.subscribe();
+    let hide_queue = view == View::Spectator { hide_queue: true };
+    stream! {
+        // Stream ends when the viewer is disconnected for lagging
+        while let Some(event) = receiver.recv().await {
+            match event {
+                SpotlightEvent::Frame(views) => {
+                    let frame = if hide_queue { &views.hidden_queue } else { &views.queue };
+                    yield ChannelEvent::message(frame.clone())
+                }
+                SpotlightEvent::Switched(match_id) => {
+                    yield ChannelEvent::named("switch", match_id.to_string())
+                }
+                SpotlightEvent::Idle => yield ChannelEvent::named("idle", String::new()),
+            }
+        }
+    }
+}
+
+// Featured game stream, see spotlight_stream. Upcoming pieces are hidden with hide_queue
+#[get("/spotlight?<hide_queue>")]
+fn spotlight(
+    spotlight: &State<Spotlight>,
+    connections: &State<Connections>,
+    hide_queue: Option<bool>,
+) -> EventStream![] {
+    let view = View::spectator(hide_queue.unwrap_or(false));
+    let events = connections.track(None, "spotlight", spotlight_stream(spotlight, view));
+    EventStream::from(events.map(ChannelEvent::into_event))
+}
+
+// Viewers and their dropped frames
+#[get("/admin/spotlight")]
+fn admin_spotlight(_admin: Viewer, spotlight: &State<Spotlight>) -> Json<SendQueueMetrics> {
+    Json(spotlight.queue_metrics())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![spotlight, admin_spotlight]
+}
@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use persy::Persy;
+use rocket::{
+    futures::StreamExt,
+    get,
+    http::CookieJar,
+    response::stream::{stream, Event, EventStream},
+    routes,
+    serde::json::serde_json,
+    tokio::time::{self, Duration},
+    Config, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    admission::Admitted,
+    bug_reports::GameTrace,
+    connections::Connections,
+    difficulty::Difficulty,
+    error::Error,
+    events::ChannelEvent,
+    game_history,
+    game_mode::GameMode,
+    game_rng::RngKind,
+    games::{GamePlugin, GameType, SessionStatus},
+    ids::UserId,
+    input_sequence::{InputSequences, InputTarget, StateHistory},
+    leaderboard::{self, LeaderboardEntry, Verification},
+    maintenance::{Maintenance, MaintenanceRefusal},
+    quarantine::{self, Quarantine, QuarantinedGame, QuarantinedMode},
+    replays,
+    splits::{self, Checkpoint, SplitTracker, Splits},
+    storage::{self, Database},
+    tetris::{Action, PieceRules, Randomizer, Replay, ReplayPlayer, Tetris, TetrisGameState},
+    views::{Projection, View},
+    visibility::Pauses,
+    write_queue::{Write, WriteQueue},
+    TetrisMatches,
+};
+
+//
+// Sprint mode: single player clears SPRINT_LINES lines as fast as possible.
+// Player's personal best is played back alongside as a "ghost" in the same event stream,
+// split times of checkpoints are compared with the personal best's ones.
+//
+
+pub const SPRINT_LINES: usize = 40;
+
+struct Sprint {
+    tetris: Tetris,
+    // Personal best replay played in sync with the live game
+    ghost: Option<ReplayPlayer>,
+    splits: SplitTracker,
+    results_taken: bool,
+    // Start time, seconds since unix epoch
+    started: u64,
+}
+
+impl Sprint {
+    fn is_finished(&self) -> bool {
+        self.tetris.get_lines() >= SPRINT_LINES || self.tetris.is_game_over()
+    }
+}
+
+// Sprint replaced by another one, kept in game history
+pub struct ReplacedSprint {
+    pub started: u64,
+    pub finished: bool,
+    pub score: usize,
+    pub lines: usize,
+    pub replay: Replay,
+}
+
+impl From<Sprint> for ReplacedSprint {
+    fn from(sprint: Sprint) -> ReplacedSprint {
+        ReplacedSprint {
+            started: sprint.started,
+            finished: sprint.is_finished(),
+            score: sprint.tetris.get_score(),
+            lines: sprint.tetris.get_lines(),
+            replay: sprint.tetris.get_replay(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SprintState {
+    pub player: TetrisGameState,
+    pub ghost: Option<TetrisGameState>,
+    pub ticks: u64,
+    pub lines_left: usize,
+    pub finished: bool,
+    // Checkpoint reached by this step
+    #[serde(skip)]
+    pub checkpoint: Option<Checkpoint>,
+}
+
+// Sprint games by user id, random source and piece rules of new games and quarantine
+// of sprints which panicked
+pub struct TetrisSprints(
+    Arc<RwLock<HashMap<UserId, Sprint>>>,
+    RngKind,
+    PieceRules,
+    Quarantine,
+);
+
+impl TetrisSprints {
+    pub fn new(rng: RngKind, pieces: PieceRules, quarantine: Quarantine) -> Self {
+        TetrisSprints(
+            Arc::new(RwLock::new(HashMap::new())),
+            rng,
+            pieces,
+            quarantine,
+        )
+    }
+    // Start new sprint for user, returns replaced previous one
+    pub fn start(
+        &self,
+        user_id: UserId,
+        ghost: Option<Replay>,
+        best_splits: Option<Vec<u64>>,
+        randomizer: Randomizer,
+        difficulty: Difficulty,
+    ) -> Option<ReplacedSprint> {
+        let mut sprints = self.0.write().unwrap();
+        sprints
+            .insert(
+                user_id,
+                Sprint {
+                    tetris: Tetris::new_game(10, 20, randomizer, self.1, difficulty)
+                        .with_pieces(self.2),
+                    ghost: ghost.map(ReplayPlayer::new),
+                    splits: SplitTracker::new(best_splits),
+                    results_taken: false,
+                    started: crate::unix_time(),
+                },
+            )
+            .map(ReplacedSprint::from)
+    }
+    // Continue abandoned sprint from it's replay, returns replaced current one.
+    // Ghost is moved to the same time point
+    pub fn resume(
+        &self,
+        user_id: UserId,
+        replay: &Replay,
+        started: u64,
+        ghost: Option<Replay>,
+        best_splits: Option<Vec<u64>>,
+    ) -> Option<ReplacedSprint> {
+        let tetris = Tetris::restore(replay);
+        let ghost = ghost.map(|ghost| {
+            let mut ghost = ReplayPlayer::new(ghost);
+            while ghost.get_tetris().get_ticks() < tetris.get_ticks() && ghost.step() {}
+            ghost
+        });
+        let mut sprints = self.0.write().unwrap();
+        sprints
+            .insert(
+                user_id,
+                Sprint {
+                    tetris,
+                    ghost,
+                    splits: SplitTracker::resumed(replay, best_splits),
+                    results_taken: false,
+                    started,
+                },
+            )
+            .map(ReplacedSprint::from)
+    }
+    // Whether user has sprint which is not finished yet
+    pub fn is_running(&self, user_id: UserId) -> bool {
+        let sprints = self.0.read().unwrap();
+        sprints
+            .get(&user_id)
+            .is_some_and(|sprint| !sprint.is_finished())
+    }
+    // Current state of user's sprint, without stepping it
+    pub fn game_state(&self, user_id: UserId) -> Option<TetrisGameState> {
+        let sprints = self.0.read().unwrap();
+        let sprint = sprints.get(&user_id)?;
+        Some(sprint.tetris.get_game_state().project(View::Player))
+    }
+    // Start time of user's sprint
+    pub fn started(&self, user_id: UserId) -> Option<u64> {
+        let sprints = self.0.read().unwrap();
+        sprints.get(&user_id).map(|sprint| sprint.started)
+    }
+    // Hold and next queue of user's sprint, resumed sprint keeps the rules it was started with
+    pub fn pieces(&self, user_id: UserId) -> Option<PieceRules> {
+        let sprints = self.0.read().unwrap();
+        sprints
+            .get(&user_id)
+            .map(|sprint| sprint.tetris.get_pieces())
+    }
+    // Full state and replay of user's sprint for bug report
+    pub fn debug_trace(&self, user_id: UserId) -> Option<GameTrace> {
+        let sprints = self.0.read().unwrap();
+        let sprint = sprints.get(&user_id)?;
+        let state = sprint.tetris.get_game_state().project(View::Admin);
+        Some(GameTrace {
+            mode: GameMode::Sprint,
+            players: vec![user_id],
+            started: sprint.started,
+            state: serde_json::to_value(state).ok()?,
+            replays: vec![sprint.tetris.get_replay()],
+        })
+    }
+    // Returns version of the game the input is put in, None without running sprint
+    pub fn add_action(&self, user_id: UserId, action: Action) -> Option<u64> {
+        let mut sprints = self.0.write().unwrap();
+        let sprint = sprints.get_mut(&user_id)?;
+        if sprint.is_finished() {
+            return None;
+        }
+        let tetris = &mut sprint.tetris;
+        match quarantine::guard(|| {
+            tetris.add_action(action);
+            tetris.get_ticks()
+        }) {
+            Ok(version) => Some(version),
+            Err(panic) => {
+                self.quarantine(&mut sprints, user_id, panic, Some(action));
+                None
+            }
+        }
+    }
+    // Remove sprint which panicked, keeping it's replay and the triggering input,
+    // which are the inputs of the failing step unless given
+    fn quarantine(
+        &self,
+        sprints: &mut HashMap<UserId, Sprint>,
+        user_id: UserId,
+        panic: String,
+        action: Option<Action>,
+    ) {
+        let Some(sprint) = sprints.remove(&user_id) else {
+            return;
+        };
+        // Game state may be broken by the panic
+        let replays = quarantine::guard(|| vec![sprint.tetris.get_replay()]).unwrap_or_default();
+        let trigger = match action {
+            Some(action) => vec![(user_id, action)],
+            None => quarantine::step_inputs(&[user_id], &replays),
+        };
+        self.3.add(QuarantinedGame {
+            mode: QuarantinedMode::Sprint,
+            arena: None,
+            players: vec![user_id],
+            started: sprint.started,
+            quarantined: crate::unix_time(),
+            replays,
+            trigger,
+            state: None,
+            trigger_move: None,
+            panic,
+        });
+    }
+    // Step live game and ghost together, so both are at the same time point
+    pub fn step(&self, user_id: UserId) -> Option<SprintState> {
+        let mut sprints = self.0.write().unwrap();
+        let sprint = sprints.get_mut(&user_id)?;
+        let stepped = quarantine::guard(|| {
+            let mut checkpoint = None;
+            if !sprint.is_finished() {
+                sprint.tetris.step();
+                if let Some(ghost) = &mut sprint.ghost {
+                    ghost.step();
+                }
+                checkpoint = sprint
+                    .splits
+                    .update(sprint.tetris.get_lines(), sprint.tetris.get_ticks());
+            }
+            SprintState {
+                player: sprint.tetris.get_game_state().project(View::Player),
+                ghost: sprint
+                    .ghost
+                    .as_ref()
+                    .map(|ghost| ghost.get_tetris().get_game_state().project(View::Player)),
+                ticks: sprint.tetris.get_ticks(),
+                lines_left: SPRINT_LINES.saturating_sub(sprint.tetris.get_lines()),
+                finished: sprint.is_finished(),
+                checkpoint,
+            }
+        });
+        match stepped {
+            Ok(state) => Some(state),
+            Err(panic) => {
+                self.quarantine(&mut sprints, user_id, panic, None);
+                None
+            }
+        }
+    }
+    // Whether the user's sprint was quarantined since the last call
+    pub fn take_quarantined(&self, user_id: UserId) -> bool {
+        self.3.take_affected(user_id)
+    }
+    // Splits of user's sprint if it's completed
+    fn completed_splits(&self, user_id: UserId) -> Option<Splits> {
+        let sprints = self.0.read().unwrap();
+        let sprint = sprints.get(&user_id)?;
+        (sprint.tetris.get_lines() >= SPRINT_LINES).then(|| Splits {
+            user: user_id,
+            mode: GameMode::Sprint,
+            difficulty: sprint.tetris.get_difficulty().unwrap_or_default(),
+            ticks: sprint.tetris.get_ticks(),
+            checkpoints: sprint.splits.reached().to_vec(),
+        })
+    }
+    // Take result of finished sprint. Result is given out only once
+    pub fn take_result(&self, user_id: UserId) -> Option<(LeaderboardEntry, Replay)> {
+        let mut sprints = self.0.write().unwrap();
+        let sprint = sprints.get_mut(&user_id)?;
+        if !sprint.is_finished() || sprint.results_taken {
+            return None;
+        }
+        sprint.results_taken = true;
+        let replay = sprint.tetris.get_replay();
+        let entry = LeaderboardEntry {
+            user: user_id,
+            mode: GameMode::Sprint,
+            difficulty: sprint.tetris.get_difficulty(),
+            score: sprint.tetris.get_score() as u64,
+            lines: sprint.tetris.get_lines() as u64,
+            ticks: replay.ticks,
+            pieces: sprint.tetris.get_piece_counts().iter().sum(),
+            finished: crate::unix_time(),
+            opponent: None,
+            replay: None,
+            verification: Default::default(),
+            rng_audit: None,
+        };
+        Some((entry, replay))
+    }
+}
+
+// Replay of user's fastest completed sprint of the difficulty
+pub fn personal_best(
+    persy: &Persy,
+    user_id: UserId,
+    difficulty: Difficulty,
+) -> Result<Option<Replay>, Error> {
+    let best = leaderboard::entries_of_user(persy, user_id)?
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| {
+            entry.mode == GameMode::Sprint
+                && entry.difficulty == Some(difficulty)
+                && entry.lines >= SPRINT_LINES as u64
+                && entry.verification != Verification::Rejected
+        })
+        .filter_map(|entry| Some((entry.ticks, entry.replay?)))
+        .min_by_key(|(ticks, _)| *ticks);
+    match best {
+        Some((_, replay_id)) => replays::read(persy, &storage::parse_id(&replay_id)?),
+        None => Ok(None),
+    }
+}
+
+// Start new sprint and stream it's state. Stream starts with "input_epoch" and "rules"
+// events (see /sse), inputs are acknowledged with "ack" and "correction" events.
+// Personal best ghost state is sent as "ghost" events, split times of every
+// CHECKPOINT_LINES lines as "checkpoint" events,
+// final result is sent as "finished" event before the stream ends.
+// Randomizer defaults to the one of sprint mode, difficulty to Normal. With resume
+// user's unfinished sprint is continued instead, e.g. after reconnect or
+// /games/<id>/resume. Replaced sprint is kept in game history.
+// New sprints are refused during maintenance
+#[get("/sprint/sse?<randomizer>&<difficulty>&<resume>")]
+#[allow(clippy::too_many_arguments)]
+fn sprint_sse<'a>(
+    _admitted: Admitted,
+    randomizer: Option<Randomizer>,
+    difficulty: Option<Difficulty>,
+    resume: Option<bool>,
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &'a State<TetrisSprints>,
+    db: &'a State<Database>,
+    writes: &'a State<WriteQueue>,
+    sequences: &'a State<InputSequences>,
+    maintenance: &State<Maintenance>,
+    pauses: &'a State<Pauses>,
+    connections: &State<Connections>,
+) -> Result<EventStream![Event + 'a], MaintenanceRefusal> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    if !(resume.unwrap_or(false) && sprints.is_running(user_id)) {
+        maintenance.check()?;
+        let difficulty = difficulty.unwrap_or_default();
+        let persy = &*db.read();
+        let ghost = personal_best(persy, user_id, difficulty).unwrap_or_else(|e| {
+            println!("Failed to load personal best: {}", e);
+            None
+        });
+        let best_splits =
+            splits::personal_best(persy, user_id, GameMode::Sprint, difficulty, ghost.as_ref())
+                .unwrap_or_else(|e| {
+                    println!("Failed to load personal best splits: {}", e);
+                    None
+                });
+        let replaced = sprints.start(
+            user_id,
+            ghost,
+            best_splits,
+            randomizer.unwrap_or(GameMode::Sprint.randomizer()),
+            difficulty,
+        );
+        if let Some(replaced) = replaced {
+            if let Err(e) = game_history::archive(persy, user_id, replaced) {
+                println!("Failed to keep replaced sprint: {}", e);
+            }
+        }
+    }
+    let epoch = sequences.new_epoch(user_id, InputTarget::Sprint);
+    let game = sprints.started(user_id).unwrap_or_default();
+    let pieces = sprints.pieces(user_id).unwrap_or_default();
+    let events = stream! {
+        yield ChannelEvent::named("input_epoch", epoch.to_string());
+        yield ChannelEvent::named("rules", serde_json::to_string(&pieces).unwrap());
+        let mut interval = time::interval(Duration::from_millis(10));
+        let mut paused = false;
+        let mut history = StateHistory::default();
+        loop {
+            // Sprint timer stops while the game is paused
+            if pauses.is_paused(user_id, game) != paused {
+                paused = !paused;
+                if let Some(status) = pauses.status(user_id, game) {
+                    yield ChannelEvent::named("pause", serde_json::to_string(&status).unwrap());
+                }
+            }
+            if paused {
+                interval.tick().await;
+                continue;
+            }
+            let Some(state) = sprints.step(user_id) else {
+                if sprints.take_quarantined(user_id) {
+                    yield ChannelEvent::named("quarantined", String::new());
+                }
+                break;
+            };
+            let (acks, rejections) = sequences.take_feedback(user_id);
+            for ack in acks {
+                yield ChannelEvent::named("ack", serde_json::to_string(&ack).unwrap());
+            }
+            history.push(state.player.clone());
+            for correction in history.corrections(&rejections) {
+                yield ChannelEvent::named("correction", serde_json::to_string(&correction).unwrap());
+            }
+            yield ChannelEvent::message(serde_json::to_string(&state.player).unwrap());
+            if let Some(ghost) = &state.ghost {
+                yield ChannelEvent::named("ghost", serde_json::to_string(ghost).unwrap());
+            }
+            if let Some(checkpoint) = &state.checkpoint {
+                yield ChannelEvent::named("checkpoint", serde_json::to_string(checkpoint).unwrap());
+            }
+            if let Some((entry, replay)) = sprints.take_result(user_id) {
+                if let Some(splits) = sprints.completed_splits(user_id) {
+                    if let Err(e) = splits::record(&db.read(), &splits) {
+                        println!("Failed to store splits: {}", e);
+                    }
+                }
+                yield ChannelEvent::named("finished", serde_json::to_string(&entry).unwrap());
+                writes.push(Write::Game { entry, replay }).await;
+                break;
+            }
+            interval.tick().await;
+        }
+    };
+    let events = connections.track(Some(user_id), "sprint", events);
+    Ok(EventStream::from(events.map(ChannelEvent::into_event)))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![sprint_sse]
+}
+
+// Inputs of sprints are versus input routes, see versus_plugin
+pub fn plugin() -> GamePlugin {
+    GamePlugin {
+        game_type: GameType {
+            id: "sprint",
+            name: "Tetris sprint",
+            description: "Clear 40 lines as fast as possible against your best",
+            entry: "/sprint/sse",
+        },
+        init: |_| Ok(()),
+        routes,
+        attach: |rocket| {
+            let figment = Config::figment();
+            let rng = figment.extract_inner::<RngKind>("rng").unwrap_or_default();
+            let pieces = crate::piece_rules(&figment, "sprint_", GameMode::Sprint)?;
+            let quarantine = rocket.state::<Quarantine>().cloned().unwrap_or_default();
+            Ok(rocket.manage(TetrisSprints::new(rng, pieces, quarantine)))
+        },
+        session: |rocket, user_id| {
+            let sprints = rocket.state::<TetrisSprints>()?;
+            SessionStatus::of(false, sprints.is_running(user_id))
+        },
+        state: |rocket, user_id| {
+            let state = rocket.state::<TetrisSprints>()?.game_state(user_id)?;
+            Some(serde_json::to_string(&state).unwrap())
+        },
+    }
+}
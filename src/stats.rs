@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    get, routes,
+    serde::json::Json,
+    tokio::{self, time::Duration},
+    Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    leaderboard,
+    roles::Viewer,
+    storage::{self, Database},
+};
+
+//
+// Daily statistics. Background job aggregates results of each finished day into summary
+// record once, so analytics don't need to go over raw game results on each request
+//
+
+const DAILY_STATS_SEGMENT: &str = "daily_stats";
+const BY_DAY_INDEX: &str = "daily_stats_by_day";
+
+pub const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Maximal number of days returned by analytics request
+const MAX_DAYS: u64 = 366;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct DailyStats {
+    // Days since unix epoch
+    pub day: u64,
+    pub games: u64,
+    pub unique_players: u64,
+    pub average_score: f64,
+    // Share of previous day players who played again this day
+    pub retention: f64,
+}
+
+// Statistics in columnar form, one array per metric, ready to be fed to charts
+#[derive(Default, Serialize)]
+pub struct Analytics {
+    pub days: Vec<u64>,
+    pub games: Vec<u64>,
+    pub unique_players: Vec<u64>,
+    pub average_score: Vec<f64>,
+    pub retention: Vec<f64>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, DAILY_STATS_SEGMENT)?;
+    storage::ensure_index::<u64, PersyId>(persy, BY_DAY_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+pub fn today() -> u64 {
+    crate::unix_time() / SECONDS_PER_DAY
+}
+
+fn players_of_day(persy: &Persy, day: u64) -> Result<HashSet<UserId>, Error> {
+    Ok(
+        leaderboard::finished_between(persy, day * SECONDS_PER_DAY, (day + 1) * SECONDS_PER_DAY)?
+            .iter()
+            .map(|entry| entry.user)
+            .collect(),
+    )
+}
+
+// Compute statistics of one day from game results
+pub fn aggregate_day(persy: &Persy, day: u64) -> Result<DailyStats, Error> {
+    let entries =
+        leaderboard::finished_between(persy, day * SECONDS_PER_DAY, (day + 1) * SECONDS_PER_DAY)?;
+    let players = entries
+        .iter()
+        .map(|entry| entry.user)
+        .collect::<HashSet<_>>();
+    let average_score = if entries.is_empty() {
+        0.
+    } else {
+        entries.iter().map(|entry| entry.score as f64).sum::<f64>() / entries.len() as f64
+    };
+    let previous_players = match day.checked_sub(1) {
+        Some(previous_day) => players_of_day(persy, previous_day)?,
+        None => HashSet::new(),
+    };
+    let retention = if previous_players.is_empty() {
+        0.
+    } else {
+        previous_players.intersection(&players).count() as f64 / previous_players.len() as f64
+    };
+    Ok(DailyStats {
+        day,
+        games: entries.len() as u64,
+        unique_players: players.len() as u64,
+        average_score,
+        retention,
+    })
+}
+
+// Store day statistics, replacing previous record for the same day
+fn store(persy: &Persy, stats: &DailyStats) -> Result<(), Error> {
+    match persy.one::<u64, PersyId>(BY_DAY_INDEX, &stats.day)? {
+        Some(id) => storage::update(persy, DAILY_STATS_SEGMENT, &id, stats),
+        None => storage::insert_with(persy, DAILY_STATS_SEGMENT, stats, |tx, id| {
+            tx.put(BY_DAY_INDEX, stats.day, *id)?;
+            Ok(())
+        })
+        .map(|_| ()),
+    }
+}
+
+// Aggregate all finished days which don't have statistics yet. Returns number of aggregated days
+pub fn aggregate_missing_days(persy: &Persy) -> Result<usize, Error> {
+    let last_aggregated = persy
+        .range::<u64, PersyId, _>(BY_DAY_INDEX, ..)?
+        .next_back()
+        .map(|(day, _)| day);
+    let first_day = match last_aggregated {
+        Some(day) => day + 1,
+        None => match leaderboard::first_finished(persy)? {
+            Some(finished) => finished / SECONDS_PER_DAY,
+            None => return Ok(0),
+        },
+    };
+    // Current day is not finished yet
+    let days = first_day..today();
+    let count = days.clone().count();
+    for day in days {
+        store(persy, &aggregate_day(persy, day)?)?;
+    }
+    Ok(count)
+}
+
+// Requested days range, last 30 days by default
+pub fn days_range(from: Option<u64>, to: Option<u64>) -> Result<(u64, u64), Error> {
+    let to = to.unwrap_or_else(today);
+    let from = from.unwrap_or(to.saturating_sub(30));
+    if from > to || to - from > MAX_DAYS {
+        return Err(Error::InvalidInputError(format!(
+            "Days range must be ordered and not longer than {} days",
+            MAX_DAYS
+        )));
+    }
+    Ok((from, to))
+}
+
+// Stored statistics of days range
+pub fn days(persy: &Persy, from: u64, to: u64) -> Result<Vec<DailyStats>, Error> {
+    let mut days = Vec::new();
+    for (_, ids) in persy.range::<u64, PersyId, _>(BY_DAY_INDEX, from..=to)? {
+        for id in ids {
+            if let Some(stats) = storage::read::<DailyStats>(persy, DAILY_STATS_SEGMENT, &id)? {
+                days.push(stats);
+            }
+        }
+    }
+    Ok(days)
+}
+
+pub fn analytics(persy: &Persy, from: u64, to: u64) -> Result<Analytics, Error> {
+    let mut analytics = Analytics::default();
+    for stats in days(persy, from, to)? {
+        analytics.days.push(stats.day);
+        analytics.games.push(stats.games);
+        analytics.unique_players.push(stats.unique_players);
+        analytics.average_score.push(stats.average_score);
+        analytics.retention.push(stats.retention);
+    }
+    Ok(analytics)
+}
+
+// Background job: aggregate missed days on start, then once after each midnight (UTC)
+pub async fn aggregation_job(db: Database) {
+    loop {
+        let job_db = db.clone();
+        match tokio::task::spawn_blocking(move || aggregate_missing_days(&job_db.read())).await {
+            Ok(Ok(0)) => (),
+            Ok(Ok(days)) => println!("Statistics aggregated for {} days", days),
+            Ok(Err(e)) => println!("Statistics aggregation failed: {}", e),
+            Err(e) => println!("Statistics aggregation task failed: {}", e),
+        }
+        let seconds_to_midnight = SECONDS_PER_DAY - crate::unix_time() % SECONDS_PER_DAY;
+        tokio::time::sleep(Duration::from_secs(seconds_to_midnight + 1)).await;
+    }
+}
+
+// Daily statistics for days range (days since unix epoch), last 30 days by default
+#[get("/admin/analytics?<from>&<to>")]
+fn admin_analytics(
+    _admin: Viewer,
+    db: &State<Database>,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<Json<Analytics>, Error> {
+    let persy = &*db.read();
+    let (from, to) = days_range(from, to)?;
+    Ok(Json(analytics(persy, from, to)?))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_analytics]
+}
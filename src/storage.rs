@@ -0,0 +1,345 @@
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use persy::{IndexType, Persy, PersyId, Transaction, ValueMode};
+use rocket::{serde::json::serde_json, tokio};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::Error;
+
+//
+// Helpers for storing serde-serializable records in Persy segments as json. Records start
+// with a format byte: plain json for small records, deflate compressed json for records of
+// COMPRESS_MIN_LEN bytes and more, like replays and snapshots. Records written before
+// formats were introduced are plain json without format byte, json never starts with
+// format bytes, so they're read as before. Compaction rewrites them in the current format
+//
+
+// Format bytes of records
+const FORMAT_JSON: u8 = 0;
+const FORMAT_DEFLATE_JSON: u8 = 1;
+// Smaller records aren't worth compressing
+const COMPRESS_MIN_LEN: usize = 256;
+
+struct DatabaseInner {
+    path: PathBuf,
+    persy: RwLock<Persy>,
+}
+
+// Shared database handle. Underlying Persy instance can be replaced (see compaction),
+// so it's locked for reading while used and for writing while replaced.
+// Read guard should not be held across await points or taken twice by the same thread
+#[derive(Clone)]
+pub struct Database(Arc<DatabaseInner>);
+
+impl Database {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Database, Error> {
+        let path = path.into();
+        let persy = Persy::open_or_create_with(&path, persy::Config::default(), |_persy| Ok(()))?;
+        Ok(Database(Arc::new(DatabaseInner {
+            path,
+            persy: RwLock::new(persy),
+        })))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0.path
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Persy> {
+        self.0.persy.read().unwrap()
+    }
+
+    // Exclusive access, blocks all other database users until released
+    pub fn write(&self) -> RwLockWriteGuard<'_, Persy> {
+        self.0.persy.write().unwrap()
+    }
+
+    // Wait until transactions in progress are done, before shutdown
+    pub async fn drain(&self) {
+        let db = self.clone();
+        let _ = tokio::task::spawn_blocking(move || drop(db.write())).await;
+    }
+}
+
+// Record bytes of json in the current format
+fn encode_json(json: &[u8]) -> Result<Vec<u8>, Error> {
+    if json.len() < COMPRESS_MIN_LEN {
+        return Ok([&[FORMAT_JSON], json].concat());
+    }
+    let mut encoder = DeflateEncoder::new(vec![FORMAT_DEFLATE_JSON], Compression::default());
+    encoder.write_all(json)?;
+    Ok(encoder.finish()?)
+}
+
+fn encode<T: Serialize>(record: &T) -> Result<Vec<u8>, Error> {
+    encode_json(&serde_json::to_vec(record)?)
+}
+
+// Json of record in any format
+fn json_of(bytes: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    match bytes.first() {
+        Some(&FORMAT_JSON) => Ok(Cow::Borrowed(&bytes[1..])),
+        Some(&FORMAT_DEFLATE_JSON) => {
+            let mut json = Vec::new();
+            DeflateDecoder::new(&bytes[1..]).read_to_end(&mut json)?;
+            Ok(Cow::Owned(json))
+        }
+        // Control characters other than whitespace don't start json
+        Some(format) if *format < b' ' && !b"\t\n\r".contains(format) => {
+            Err(Error::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown record format {}", format),
+            )))
+        }
+        // Records written without format byte
+        _ => Ok(Cow::Borrowed(bytes)),
+    }
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    Ok(serde_json::from_slice(&json_of(bytes)?)?)
+}
+
+// Record bytes in the current format, None if they're in it already
+pub fn recode(bytes: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let json = json_of(bytes)?;
+    let recoded = encode_json(&json)?;
+    Ok((recoded != bytes).then_some(recoded))
+}
+
+// Create segment if it doesn't exist yet. Database may be created by older server version,
+// so segments are checked on each start, not only on database creation
+pub fn ensure_segment(persy: &Persy, segment: &str) -> Result<(), Error> {
+    if !persy.exists_segment(segment)? {
+        let mut tx = persy.begin()?;
+        tx.create_segment(segment)?;
+        tx.prepare()?.commit()?;
+    }
+    Ok(())
+}
+
+// Create index if it doesn't exist yet. Returns true if index was created
+// so the caller can fill it from already existing records
+pub fn ensure_index<K: IndexType, V: IndexType>(
+    persy: &Persy,
+    index: &str,
+    value_mode: ValueMode,
+) -> Result<bool, Error> {
+    if persy.exists_index(index)? {
+        return Ok(false);
+    }
+    let mut tx = persy.begin()?;
+    tx.create_index::<K, V>(index, value_mode)?;
+    tx.prepare()?.commit()?;
+    Ok(true)
+}
+
+// Serialize record and insert it into segment
+pub fn insert<T: Serialize>(persy: &Persy, segment: &str, record: &T) -> Result<PersyId, Error> {
+    let mut tx = persy.begin()?;
+    let id = insert_in_tx(&mut tx, segment, record)?;
+    tx.prepare()?.commit()?;
+    Ok(id)
+}
+
+// Serialize record and insert it into segment as part of bigger transaction
+pub fn insert_in_tx<T: Serialize>(
+    tx: &mut Transaction,
+    segment: &str,
+    record: &T,
+) -> Result<PersyId, Error> {
+    Ok(tx.insert(segment, &encode(record)?)?)
+}
+
+// Serialize record and insert it into segment, index it in the same transaction
+pub fn insert_with<T: Serialize>(
+    persy: &Persy,
+    segment: &str,
+    record: &T,
+    index: impl FnOnce(&mut Transaction, &PersyId) -> Result<(), Error>,
+) -> Result<PersyId, Error> {
+    let mut tx = persy.begin()?;
+    let id = insert_in_tx(&mut tx, segment, record)?;
+    index(&mut tx, &id)?;
+    tx.prepare()?.commit()?;
+    Ok(id)
+}
+
+// Serialize record and replace existing one
+pub fn update<T: Serialize>(
+    persy: &Persy,
+    segment: &str,
+    id: &PersyId,
+    record: &T,
+) -> Result<(), Error> {
+    let mut tx = persy.begin()?;
+    tx.update(segment, id, &encode(record)?)?;
+    tx.prepare()?.commit()?;
+    Ok(())
+}
+
+// Serialize record and replace existing one as part of bigger transaction
+pub fn update_in_tx<T: Serialize>(
+    tx: &mut Transaction,
+    segment: &str,
+    id: &PersyId,
+    record: &T,
+) -> Result<(), Error> {
+    tx.update(segment, id, &encode(record)?)?;
+    Ok(())
+}
+
+// Read and deserialize record, returns None if record doesn't exist
+pub fn read<T: DeserializeOwned>(
+    persy: &Persy,
+    segment: &str,
+    id: &PersyId,
+) -> Result<Option<T>, Error> {
+    match persy.read(segment, id)? {
+        Some(bytes) => Ok(Some(decode(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+// Read and deserialize record as part of bigger transaction, sees it's changes
+pub fn read_in_tx<T: DeserializeOwned>(
+    tx: &mut Transaction,
+    segment: &str,
+    id: &PersyId,
+) -> Result<Option<T>, Error> {
+    match tx.read(segment, id)? {
+        Some(bytes) => Ok(Some(decode(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+// Read and deserialize all records of segment
+pub fn scan<T: DeserializeOwned>(persy: &Persy, segment: &str) -> Result<Vec<(PersyId, T)>, Error> {
+    persy
+        .scan(segment)?
+        .map(|(id, bytes)| Ok((id, decode(&bytes)?)))
+        .collect()
+}
+
+// Parse record id passed in url
+pub fn parse_id(id: &str) -> Result<PersyId, Error> {
+    id.parse::<PersyId>()
+        .map_err(|_| Error::NotFoundError(format!("Invalid id {}", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use persy::OpenOptions;
+    use serde::Deserialize;
+
+    use super::*;
+
+    const SEGMENT: &str = "records";
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        text: String,
+    }
+
+    // Record serialized to json of exactly len bytes
+    fn record(len: usize) -> Record {
+        let overhead = serde_json::to_vec(&Record {
+            text: String::new(),
+        })
+        .unwrap()
+        .len();
+        Record {
+            text: "a".repeat(len - overhead),
+        }
+    }
+
+    #[test]
+    fn small_records_are_plain_json() {
+        let json = serde_json::to_vec(&record(COMPRESS_MIN_LEN - 1)).unwrap();
+        assert_eq!(json.len(), COMPRESS_MIN_LEN - 1);
+        let bytes = encode_json(&json).unwrap();
+        assert_eq!(bytes[0], FORMAT_JSON);
+        assert_eq!(&bytes[1..], json.as_slice());
+        assert_eq!(json_of(&bytes).unwrap(), json.as_slice());
+    }
+
+    #[test]
+    fn large_records_are_deflated() {
+        for len in [
+            COMPRESS_MIN_LEN,
+            COMPRESS_MIN_LEN + 1,
+            10 * COMPRESS_MIN_LEN,
+        ] {
+            let json = serde_json::to_vec(&record(len)).unwrap();
+            assert_eq!(json.len(), len);
+            let bytes = encode_json(&json).unwrap();
+            assert_eq!(bytes[0], FORMAT_DEFLATE_JSON);
+            assert!(bytes.len() < json.len());
+            assert_eq!(json_of(&bytes).unwrap(), json.as_slice());
+            assert_eq!(decode::<Record>(&bytes).unwrap(), record(len));
+        }
+    }
+
+    #[test]
+    fn records_without_format_byte_are_read() {
+        for len in [20, COMPRESS_MIN_LEN + 100] {
+            let legacy = serde_json::to_vec(&record(len)).unwrap();
+            assert_eq!(decode::<Record>(&legacy).unwrap(), record(len));
+            // Json may start with whitespace too
+            let spaced = [b" \n".as_slice(), &legacy].concat();
+            assert_eq!(decode::<Record>(&spaced).unwrap(), record(len));
+        }
+        assert!(json_of(&[7, b'{', b'}']).is_err());
+    }
+
+    #[test]
+    fn recode_converts_to_current_format_once() {
+        for len in [
+            20,
+            COMPRESS_MIN_LEN - 1,
+            COMPRESS_MIN_LEN,
+            COMPRESS_MIN_LEN + 100,
+        ] {
+            let legacy = serde_json::to_vec(&record(len)).unwrap();
+            let recoded = recode(&legacy).unwrap().unwrap();
+            assert_eq!(recoded, encode(&record(len)).unwrap());
+            assert_eq!(recode(&recoded).unwrap(), None);
+            assert_eq!(decode::<Record>(&recoded).unwrap(), record(len));
+        }
+    }
+
+    #[test]
+    fn records_round_trip_through_persy() {
+        let persy = OpenOptions::new().memory().unwrap();
+        ensure_segment(&persy, SEGMENT).unwrap();
+        let small = insert(&persy, SEGMENT, &record(20)).unwrap();
+        let large = insert(&persy, SEGMENT, &record(COMPRESS_MIN_LEN * 4)).unwrap();
+        let legacy = {
+            let mut tx = persy.begin().unwrap();
+            let json = serde_json::to_vec(&record(30)).unwrap();
+            let id = tx.insert(SEGMENT, &json).unwrap();
+            tx.prepare().unwrap().commit().unwrap();
+            id
+        };
+        assert_eq!(
+            read::<Record>(&persy, SEGMENT, &small).unwrap(),
+            Some(record(20))
+        );
+        assert_eq!(
+            read::<Record>(&persy, SEGMENT, &large).unwrap(),
+            Some(record(COMPRESS_MIN_LEN * 4))
+        );
+        assert_eq!(
+            read::<Record>(&persy, SEGMENT, &legacy).unwrap(),
+            Some(record(30))
+        );
+        update(&persy, SEGMENT, &legacy, &record(COMPRESS_MIN_LEN)).unwrap();
+        let bytes = persy.read(SEGMENT, &legacy).unwrap().unwrap();
+        assert_eq!(bytes[0], FORMAT_DEFLATE_JSON);
+        assert_eq!(scan::<Record>(&persy, SEGMENT).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,113 @@
+use rocket::{get, post, response::Redirect, routes, Route, State};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    ids::MatchId,
+    ids::UserId,
+    pagination::{self, SortOrder},
+    roles::{Operator, Viewer},
+    write_queue::WriteQueue,
+    TetrisMatches,
+};
+
+//
+// Admin browser of matches held in memory. Matches can be evicted from memory, results
+// of finished matches which were not yet taken by players' streams can be persisted.
+// Matches being played are pinned and can't be evicted until they're unpinned
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum StoredMatchStatus {
+    // Game is going on
+    Active,
+    // Game is over, results are not stored yet
+    GameOver,
+    // Results are stored, match waits for removal
+    Finished,
+}
+
+#[derive(Serialize)]
+pub struct StoredMatch {
+    pub match_id: MatchId,
+    pub players: [UserId; 2],
+    // Seconds since last step of the match
+    pub idle_secs: u64,
+    pub status: StoredMatchStatus,
+    // Approximate memory used by the match, bytes
+    pub approx_size: usize,
+    pub pinned: bool,
+}
+
+// Page of matches held in memory, by match id
+#[get("/admin/storage?<cursor>&<limit>")]
+fn admin_storage(
+    _admin: Viewer,
+    matches: &State<TetrisMatches>,
+    cursor: Option<&str>,
+    limit: Option<usize>,
+) -> Result<Template, Error> {
+    let (stored, pins) = matches.snapshot();
+    let items = stored
+        .into_iter()
+        .map(|stored| (stored.match_id, stored.match_id, stored))
+        .collect();
+    let page = pagination::page_in_memory(items, SortOrder::Asc, cursor, pagination::limit(limit))?;
+    Ok(Template::render("admin/storage", context! { page, pins }))
+}
+
+#[post("/admin/storage/<match_id>/evict")]
+fn evict(
+    _admin: Operator,
+    matches: &State<TetrisMatches>,
+    match_id: MatchId,
+) -> Result<Redirect, Error> {
+    matches.evict(match_id)?;
+    Ok(Redirect::to("/admin/storage"))
+}
+
+#[post("/admin/storage/<match_id>/pin")]
+fn pin(
+    _admin: Operator,
+    matches: &State<TetrisMatches>,
+    match_id: MatchId,
+) -> Result<Redirect, Error> {
+    if !matches.set_pinned(match_id, true) {
+        return Err(Error::InvalidInputError(
+            "Match not found or pin limit reached".to_string(),
+        ));
+    }
+    Ok(Redirect::to("/admin/storage"))
+}
+
+#[post("/admin/storage/<match_id>/unpin")]
+fn unpin(
+    _admin: Operator,
+    matches: &State<TetrisMatches>,
+    match_id: MatchId,
+) -> Result<Redirect, Error> {
+    if !matches.set_pinned(match_id, false) {
+        return Err(Error::NotFoundError("Match not found".to_string()));
+    }
+    Ok(Redirect::to("/admin/storage"))
+}
+
+// Queue results of finished match for storing
+#[post("/admin/storage/<match_id>/persist")]
+async fn persist(
+    _admin: Operator,
+    matches: &State<TetrisMatches>,
+    writes: &State<WriteQueue>,
+    match_id: MatchId,
+) -> Result<Redirect, Error> {
+    let write = matches
+        .take_match_results(match_id)
+        .ok_or_else(|| Error::NotFoundError("Match has no results to persist".to_string()))?;
+    writes.push(write).await;
+    Ok(Redirect::to("/admin/storage"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_storage, evict, pin, unpin, persist]
+}
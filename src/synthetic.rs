@@ -0,0 +1,308 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use rocket::{
+    delete,
+    form::Form,
+    get, post, routes,
+    serde::json::Json,
+    tokio::{
+        self,
+        task::JoinHandle,
+        time::{self, Duration},
+    },
+    Config, FromForm, Route, State,
+};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    roles::{Operator, Viewer},
+    tetris::Action,
+    write_queue::WriteQueue,
+    TetrisMatches,
+};
+
+//
+// Synthetic load for capacity testing. POST /admin/load starts synthetic players in pairs,
+// each pair plays versus matches against itself, so real players are never matched with
+// them. Players step their games on ticks of the scheduler like game streams do, place
+// pieces chosen by the bot (see Tetris::plan_placement) with think_steps steps between
+// inputs, and write results of finished games through the write queue, so eviction, tick
+// scheduling and persistence see realistic load. Their results go to the leaderboard and
+// match history as of any players. DELETE /admin/load is the off switch: all synthetic
+// players are aborted at once and their matches removed, the same happens after seconds
+// given on start and on shutdown. Players are limited by max_synthetic_players, 0 disables
+// synthetic load
+//
+
+const DEFAULT_MAX_PLAYERS: usize = 1000;
+// Steps between inputs of synthetic players unless given
+const DEFAULT_THINK_STEPS: u64 = 5;
+
+#[derive(Default)]
+struct Counters {
+    steps: AtomicU64,
+    inputs: AtomicU64,
+    games: AtomicU64,
+}
+
+#[derive(Default)]
+struct Run {
+    tasks: Vec<JoinHandle<()>>,
+    // Stops the run after given time
+    timer: Option<JoinHandle<()>>,
+    // Seconds since unix epoch
+    started: Option<u64>,
+    stops_at: Option<u64>,
+}
+
+struct LoadState {
+    matches: TetrisMatches,
+    writes: WriteQueue,
+    max_players: usize,
+    run: Mutex<Run>,
+    // Synthetic players in matches
+    playing: Mutex<HashSet<UserId>>,
+    counters: Counters,
+}
+
+#[derive(Clone)]
+pub struct SyntheticLoad(Arc<LoadState>);
+
+#[derive(FromForm)]
+pub struct LoadForm {
+    // Added to running players, rounded up to pairs
+    players: usize,
+    // Stop after this time, runs until stopped otherwise
+    seconds: Option<u64>,
+    think_steps: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct LoadStatus {
+    pub players: usize,
+    pub max_players: usize,
+    pub started: Option<u64>,
+    pub stops_at: Option<u64>,
+    pub steps: u64,
+    pub inputs: u64,
+    // Finished games written
+    pub games: u64,
+}
+
+// Synthetic player, keeps inputs of the bot's placement of the current piece
+struct Bot {
+    user: UserId,
+    plan: VecDeque<Action>,
+    // Pieces placed when the plan was made, the next plan is for the next piece
+    planned_at: Option<u64>,
+    wait: u64,
+}
+
+impl Bot {
+    fn new(user: UserId) -> Bot {
+        Bot {
+            user,
+            plan: VecDeque::new(),
+            planned_at: None,
+            wait: 0,
+        }
+    }
+
+    // Send the next input when it's time to, inputs refused e.g. during countdown are retried
+    fn act(&mut self, matches: &TetrisMatches, think_steps: u64, counters: &Counters) {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return;
+        }
+        if self.plan.is_empty() {
+            let Some((placed, plan)) = matches.plan_placement(self.user) else {
+                return;
+            };
+            if self.planned_at == Some(placed) {
+                return;
+            }
+            self.planned_at = Some(placed);
+            self.plan = plan.into();
+        }
+        let Some(action) = self.plan.front() else {
+            return;
+        };
+        if matches.add_action(self.user, *action).is_ok() {
+            self.plan.pop_front();
+            self.wait = think_steps;
+            counters.inputs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl SyntheticLoad {
+    pub fn new(matches: TetrisMatches, writes: WriteQueue) -> SyntheticLoad {
+        SyntheticLoad(Arc::new(LoadState {
+            matches,
+            writes,
+            max_players: Config::figment()
+                .extract_inner::<usize>("max_synthetic_players")
+                .unwrap_or(DEFAULT_MAX_PLAYERS),
+            run: Mutex::new(Run::default()),
+            playing: Mutex::new(HashSet::new()),
+            counters: Counters::default(),
+        }))
+    }
+
+    // Matches of one pair of players, a new match starts after each finished or evicted one
+    async fn pair(self, think_steps: u64) {
+        let state = &self.0;
+        loop {
+            let users = [
+                state.matches.get_free_user_id(),
+                state.matches.get_free_user_id(),
+            ];
+            state.playing.lock().unwrap().extend(users);
+            state.matches.start_match(users);
+            let mut bots = users.map(Bot::new);
+            loop {
+                let (a, b) =
+                    tokio::join!(state.matches.tick(users[0]), state.matches.tick(users[1]));
+                if a.is_none() && b.is_none() {
+                    break;
+                }
+                state.counters.steps.fetch_add(2, Ordering::Relaxed);
+                if let Some(write) = state.matches.take_results(users[0]) {
+                    state.writes.push(write).await;
+                    state.counters.games.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                for bot in &mut bots {
+                    bot.act(&state.matches, think_steps, &state.counters);
+                }
+            }
+            let mut playing = state.playing.lock().unwrap();
+            for user in users {
+                playing.remove(&user);
+            }
+        }
+    }
+
+    fn start(&self, form: &LoadForm) -> Result<LoadStatus, Error> {
+        let state = &self.0;
+        if state.max_players == 0 {
+            return Err(Error::NotFoundError(
+                "Synthetic load is disabled".to_string(),
+            ));
+        }
+        let mut run = state.run.lock().unwrap();
+        let pairs = form.players.div_ceil(2);
+        if (run.tasks.len() + pairs) * 2 > state.max_players {
+            return Err(Error::InvalidInputError(format!(
+                "Synthetic players are limited to {}",
+                state.max_players
+            )));
+        }
+        let think_steps = form.think_steps.unwrap_or(DEFAULT_THINK_STEPS);
+        // Players and the timer aren't lifecycle jobs, they're aborted by stop, which is
+        // the stop action of synthetic_load subsystem
+        for _ in 0..pairs {
+            run.tasks.push(tokio::spawn(self.clone().pair(think_steps)));
+        }
+        let now = crate::unix_time();
+        run.started.get_or_insert(now);
+        if let Some(seconds) = form.seconds {
+            if let Some(timer) = run.timer.take() {
+                timer.abort();
+            }
+            let load = self.clone();
+            run.timer = Some(tokio::spawn(async move {
+                time::sleep(Duration::from_secs(seconds)).await;
+                load.stop();
+            }));
+            run.stops_at = Some(now + seconds);
+        }
+        println!(
+            "Synthetic load: {} players started, {} running",
+            pairs * 2,
+            run.tasks.len() * 2
+        );
+        drop(run);
+        Ok(self.status())
+    }
+
+    // Abort all synthetic players and remove their matches
+    pub fn stop(&self) {
+        let state = &self.0;
+        let mut run = state.run.lock().unwrap();
+        if run.tasks.is_empty() {
+            return;
+        }
+        for task in run.tasks.drain(..) {
+            task.abort();
+        }
+        if let Some(timer) = run.timer.take() {
+            timer.abort();
+        }
+        run.started = None;
+        run.stops_at = None;
+        drop(run);
+        let users = std::mem::take(&mut *state.playing.lock().unwrap());
+        // Running matches are pinned, they're removed regardless
+        let mut matches = state.matches.matches.write().unwrap();
+        let match_ids = users
+            .iter()
+            .filter_map(|user| matches.get_match_for_player(user).map(|(id, _)| id))
+            .collect::<HashSet<_>>();
+        for match_id in &match_ids {
+            matches.remove_match(*match_id);
+            state.matches.published.remove(*match_id);
+        }
+        println!(
+            "Synthetic load stopped, {} players aborted, {} matches removed",
+            users.len(),
+            match_ids.len()
+        );
+    }
+
+    fn status(&self) -> LoadStatus {
+        let state = &self.0;
+        let run = state.run.lock().unwrap();
+        LoadStatus {
+            players: run.tasks.len() * 2,
+            max_players: state.max_players,
+            started: run.started,
+            stops_at: run.stops_at,
+            steps: state.counters.steps.load(Ordering::Relaxed),
+            inputs: state.counters.inputs.load(Ordering::Relaxed),
+            games: state.counters.games.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[get("/admin/load")]
+fn load_status(_admin: Viewer, load: &State<SyntheticLoad>) -> Json<LoadStatus> {
+    Json(load.status())
+}
+
+// Start synthetic players
+#[post("/admin/load", data = "<form>")]
+fn start_load(
+    _admin: Operator,
+    load: &State<SyntheticLoad>,
+    form: Form<LoadForm>,
+) -> Result<Json<LoadStatus>, Error> {
+    Ok(Json(load.start(&form)?))
+}
+
+// Stop all synthetic players at once
+#[delete("/admin/load")]
+fn stop_load(_admin: Operator, load: &State<SyntheticLoad>) -> Json<LoadStatus> {
+    load.stop();
+    Json(load.status())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![load_status, start_load, stop_load]
+}
@@ -1,6 +1,14 @@
+use crate::difficulty::{self, Difficulty, GravityCurve};
 use crate::event_regulator::EventRegulator;
-use rocket::serde::Serialize;
+use crate::game_rng::{GameRng, PieceChain, RngKind};
+use crate::scoring::ScoringRules;
+pub use gameserver_protocol::game::{Action, PieceRules};
+use rand::{seq::SliceRandom, Rng};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::FromFormField;
 use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CellType {
@@ -25,9 +33,36 @@ impl serde::Serialize for CellType {
     }
 }
 
+impl<'de> serde::Deserialize<'de> for CellType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = u64::deserialize(deserializer)?;
+        CellType::from_u64(value)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid cell type {}", value)))
+    }
+}
+
 impl CellType {
-    pub fn new_random() -> CellType {
-        match rand::random::<u8>() % 7 {
+    // Get cell type by it's numeric value
+    pub fn from_u64(value: u64) -> Option<CellType> {
+        match value {
+            0 => Some(CellType::Empty),
+            1 => Some(CellType::Blasted),
+            2 => Some(CellType::I),
+            3 => Some(CellType::J),
+            4 => Some(CellType::L),
+            5 => Some(CellType::O),
+            6 => Some(CellType::S),
+            7 => Some(CellType::T),
+            8 => Some(CellType::Z),
+            _ => None,
+        }
+    }
+
+    pub fn new_random(rng: &mut (impl Rng + ?Sized)) -> CellType {
+        match rng.gen::<u8>() % 7 {
             0 => CellType::I,
             1 => CellType::J,
             2 => CellType::L,
@@ -220,7 +255,7 @@ fn get_tetromino_matrix(tetromino_type: &TetrominoType) -> &TetrominoMatrix {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum TetrominoType {
     I,
     J,
@@ -232,11 +267,21 @@ pub enum TetrominoType {
 }
 
 impl TetrominoType {
+    pub const ALL: [TetrominoType; 7] = [
+        TetrominoType::I,
+        TetrominoType::J,
+        TetrominoType::L,
+        TetrominoType::O,
+        TetrominoType::S,
+        TetrominoType::T,
+        TetrominoType::Z,
+    ];
+
     // new method returns new tetromino type
-    pub fn new_random() -> Self {
+    pub fn new_random(rng: &mut (impl Rng + ?Sized)) -> Self {
         // Create new tetromino type
         // Create random number between 0 and 6
-        let random_number = rand::random::<u32>() % 7;
+        let random_number = rng.gen::<u32>() % 7;
         // Return new tetromino type
         match random_number {
             0 => TetrominoType::I,
@@ -301,13 +346,13 @@ impl Tetromino {
         Tetromino {
             tetromino_type,
             rotation,
-            x: x,
-            y: y,
+            x,
+            y,
         }
     }
 
     // Check if tetromino intersects with field borders or other tetrominos
-    pub fn intersects(&self, field: &Vec<Vec<CellType>>) -> bool {
+    pub fn intersects(&self, field: &[Vec<CellType>]) -> bool {
         // Check if tetromino intersects with field borders or other tetrominos
         // Check if tetromino position is positive, otherwise it intersects with field borders
         let x = if self.x >= 0 {
@@ -343,13 +388,19 @@ impl Tetromino {
 
     // Draw tetromino on field. If tetromino intersects with field borders, draw it partially.
     // I.e for any cell position check is it inside field borders and if it is, draw it.
-    pub fn draw(&self, field: &mut Vec<Vec<CellType>>) {
-        // Draw tetromino on field
+    pub fn draw(&self, field: &mut [Vec<CellType>]) {
+        let cell_type = self.tetromino_type.get_cell_type();
+        for (x, y) in self.cells(field[0].len(), field.len()) {
+            field[y][x] = cell_type;
+        }
+    }
+
+    // Positions of tetromino cells inside field of given size
+    fn cells(&self, cols: usize, rows: usize) -> Vec<(usize, usize)> {
         // Get tetromino width and height
         let width = self.tetromino_type.get_width(&self.rotation);
         let height = self.tetromino_type.get_height(&self.rotation);
-        let cell_type = self.tetromino_type.get_cell_type();
-        // Draw tetromino on field
+        let mut cells = Vec::new();
         for cell_y in 0..height {
             for cell_x in 0..width {
                 if self.tetromino_type.get_cell(cell_x, cell_y, &self.rotation) {
@@ -357,25 +408,22 @@ impl Tetromino {
                     // Check resulting positoins are positive and less than field borders
                     let x = self.x + cell_x as isize;
                     let y = self.y + cell_y as isize;
-                    if x >= 0 && x < field[0].len() as isize && y >= 0 && y < field.len() as isize {
-                        field[y as usize][x as usize] = cell_type;
+                    if x >= 0 && x < cols as isize && y >= 0 && y < rows as isize {
+                        cells.push((x as usize, y as usize));
                     }
                 }
             }
         }
+        cells
     }
 }
 
-// Enum with all possible user actions
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
-pub enum Action {
-    MoveLeft,
-    MoveRight,
-    MoveDown,
-    RotateLeft,
-    RotateRight,
-    Drop,
-    BottomRefill,
+// Lines cleared by locked piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineClear {
+    pub lines: usize,
+    // Number of consecutive clearing locks before this one
+    pub combo: usize,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
@@ -390,6 +438,186 @@ pub enum StepResult {
     GameOver,
 }
 
+// Source of next pieces. Generator takes randomness only from the game's seeded random
+// generator, so pieces sequence is reproducible from the seed
+pub trait PieceGenerator: Send + Sync {
+    fn next(&mut self, rng: &mut dyn GameRng) -> TetrominoType;
+}
+
+// Each piece is chosen independently
+pub struct UniformGenerator;
+
+impl PieceGenerator for UniformGenerator {
+    fn next(&mut self, rng: &mut dyn GameRng) -> TetrominoType {
+        TetrominoType::new_random(rng)
+    }
+}
+
+// All 7 pieces are dealt in random order, then the bag is refilled
+#[derive(Default)]
+pub struct BagGenerator {
+    bag: Vec<TetrominoType>,
+}
+
+impl PieceGenerator for BagGenerator {
+    fn next(&mut self, rng: &mut dyn GameRng) -> TetrominoType {
+        if self.bag.is_empty() {
+            self.bag = TetrominoType::ALL.to_vec();
+            self.bag.shuffle(rng);
+        }
+        self.bag.pop().unwrap_or(TetrominoType::I)
+    }
+}
+
+// TGM style: piece is rerolled a few times while it's among last 4 dealt pieces.
+// Game never starts with S, Z or O
+pub struct HistoryGenerator {
+    history: VecDeque<TetrominoType>,
+    first: bool,
+}
+
+impl HistoryGenerator {
+    const HISTORY_SIZE: usize = 4;
+    const ROLLS: usize = 6;
+}
+
+impl Default for HistoryGenerator {
+    fn default() -> Self {
+        HistoryGenerator {
+            history: VecDeque::from([
+                TetrominoType::Z,
+                TetrominoType::S,
+                TetrominoType::Z,
+                TetrominoType::S,
+            ]),
+            first: true,
+        }
+    }
+}
+
+impl PieceGenerator for HistoryGenerator {
+    fn next(&mut self, rng: &mut dyn GameRng) -> TetrominoType {
+        let mut piece = TetrominoType::new_random(rng);
+        if self.first {
+            self.first = false;
+            while matches!(
+                piece,
+                TetrominoType::S | TetrominoType::Z | TetrominoType::O
+            ) {
+                piece = TetrominoType::new_random(rng);
+            }
+        } else {
+            for _ in 1..Self::ROLLS {
+                if !self.history.contains(&piece) {
+                    break;
+                }
+                piece = TetrominoType::new_random(rng);
+            }
+        }
+        self.history.push_back(piece);
+        if self.history.len() > Self::HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        piece
+    }
+}
+
+// Randomizer strategy, stored in replays to recreate the same generator
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, FromFormField,
+)]
+pub enum Randomizer {
+    // Classic independent random pieces
+    #[default]
+    Uniform,
+    // 7-bag
+    SevenBag,
+    // TGM style history based
+    History,
+}
+
+impl Randomizer {
+    pub fn generator(&self) -> Box<dyn PieceGenerator> {
+        match self {
+            Randomizer::Uniform => Box::new(UniformGenerator),
+            Randomizer::SevenBag => Box::<BagGenerator>::default(),
+            Randomizer::History => Box::<HistoryGenerator>::default(),
+        }
+    }
+}
+
+// Everything needed to reproduce the game: random seed and user actions with step numbers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub cols: usize,
+    pub rows: usize,
+    pub seed: u64,
+    // Replays recorded before randomizer became configurable are uniform
+    #[serde(default)]
+    pub randomizer: Randomizer,
+    // Random source seeded by the seed
+    #[serde(default)]
+    pub rng: RngKind,
+    // Replays recorded before difficulty presets have no preset
+    #[serde(default)]
+    pub difficulty: Option<Difficulty>,
+    // Scoring rules of the game, classic scoring when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scoring: Option<ScoringRules>,
+    // Hold and next queue of the game, classic rules when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<PieceRules>,
+    // Gravity percent of versus handicap, full gravity when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gravity_percent: Option<usize>,
+    // Gravity curves replacing the preset's one, with levels they apply from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gravity_curves: Vec<(usize, GravityCurve)>,
+    // Number of steps performed
+    pub ticks: u64,
+    // Actions with number of step before which they were added
+    pub inputs: Vec<(u64, Action)>,
+}
+
+// Hash key of the cell, see Tetris::field_hash. Empty cells have no key, so empty field
+// hashes to 0
+fn cell_key(x: usize, y: usize, cell: CellType) -> u64 {
+    if cell == CellType::Empty {
+        return 0;
+    }
+    // splitmix64 finalizer of the position and cell type
+    let mut z = ((y as u64) << 32 | (x as u64) << 8 | cell as u64).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Rating of the field after placement, higher is better: cleared lines count for it,
+// height, holes under filled cells and bumpiness of the surface against it
+fn placement_score(field: &[Vec<CellType>]) -> i64 {
+    let rows = field.len();
+    let cols = field[0].len();
+    let cleared = field
+        .iter()
+        .filter(|row| row.iter().all(|cell| *cell != CellType::Empty))
+        .count() as i64;
+    let mut heights = vec![0; cols];
+    let mut holes = 0;
+    for (x, height) in heights.iter_mut().enumerate() {
+        if let Some(top) = (0..rows).find(|y| field[*y][x] != CellType::Empty) {
+            *height = (rows - top) as i64;
+            holes += (top..rows)
+                .filter(|y| field[*y][x] == CellType::Empty)
+                .count() as i64;
+        }
+    }
+    let bumpiness = heights
+        .windows(2)
+        .map(|pair| (pair[0] - pair[1]).abs())
+        .sum::<i64>();
+    760 * cleared - 510 * heights.iter().sum::<i64>() - 356 * holes - 184 * bumpiness
+}
+
 pub struct Tetris {
     // Game field size
     cols: usize,
@@ -398,12 +626,19 @@ pub struct Tetris {
     game_over: bool,
     // Game field
     field: Vec<Vec<CellType>>,
+    // Xor of keys of the field cells, updated on each cell change, see set_cell
+    field_hash: u64,
     // Preview field
     preview: Vec<Vec<CellType>>,
     // Current tetromino
     current: Option<Tetromino>,
-    // Next tetromino
-    next: TetrominoType,
+    // Next tetrominoes, the first one is drawn on preview field
+    next: VecDeque<TetrominoType>,
+    // Hold and next queue rules, None for classic rules
+    pieces: Option<PieceRules>,
+    // Held tetromino and whether hold was used by the current one
+    held: Option<TetrominoType>,
+    hold_used: bool,
     // User actions queue
     actions: VecDeque<Action>,
     // Drop state
@@ -416,8 +651,41 @@ pub struct Tetris {
     line_remove_speed: EventRegulator,
     // Delay before line shifting
     line_remove_delay: Option<usize>,
+    // Gravity curve and lock delay, None for games recorded before presets
+    difficulty: Option<Difficulty>,
+    // Gravity is scaled by handicap, None for full gravity
+    gravity_percent: Option<usize>,
+    // Curves replacing gravity of the preset with levels they apply from, in order.
+    // Curve queued for running game applies from the next level
+    gravity_curves: Vec<(usize, GravityCurve)>,
+    queued_curve: Option<GravityCurve>,
+    // Steps left before resting piece is locked
+    lock_timer: Option<u64>,
     // Game score
     score: usize,
+    // Scoring rules, None for classic scoring
+    scoring: Option<Arc<ScoringRules>>,
+    // Number of removed lines
+    lines: usize,
+    // Random generator seed and generator itself
+    seed: u64,
+    rng_kind: RngKind,
+    rng: Box<dyn GameRng>,
+    // Next pieces source
+    randomizer: Randomizer,
+    generator: Box<dyn PieceGenerator>,
+    // Pieces the generator emitted, for audits
+    piece_chain: PieceChain,
+    // Number of consecutive locks with cleared lines
+    combo: usize,
+    // Result of the last lock, until taken
+    last_lock: Option<LineClear>,
+    // Number of pieces placed on the field by type, in TetrominoType::ALL order
+    piece_counts: [u64; 7],
+    // Number of performed steps
+    ticks: u64,
+    // All actions added, for replay
+    inputs: Vec<(u64, Action)>,
 }
 
 impl Default for Tetris {
@@ -428,19 +696,64 @@ impl Default for Tetris {
 
 impl Tetris {
     pub fn new(width: usize, height: usize) -> Self {
+        Self::with_randomizer(width, height, Randomizer::default())
+    }
+
+    pub fn with_randomizer(width: usize, height: usize, randomizer: Randomizer) -> Self {
+        Self::new_game(
+            width,
+            height,
+            randomizer,
+            RngKind::default(),
+            Difficulty::default(),
+        )
+    }
+
+    // Create game with random seed. Seed is logged, so the game can be audited
+    pub fn new_game(
+        width: usize,
+        height: usize,
+        randomizer: Randomizer,
+        rng: RngKind,
+        difficulty: Difficulty,
+    ) -> Self {
+        let seed = rand::random();
+        println!(
+            "New game: seed {}, {:?} random source, {:?} randomizer, {:?} difficulty",
+            seed, rng, randomizer, difficulty
+        );
+        Self::new_with_seed(width, height, seed, randomizer, rng, Some(difficulty))
+    }
+
+    // Create game with given random seed, randomizer and difficulty. Games with same seed,
+    // randomizer, difficulty and same actions are identical
+    pub fn new_with_seed(
+        width: usize,
+        height: usize,
+        seed: u64,
+        randomizer: Randomizer,
+        rng_kind: RngKind,
+        difficulty: Option<Difficulty>,
+    ) -> Self {
         // Create new tetris game
+        // Create random generator
+        let mut rng = rng_kind.seeded(seed);
+        let mut generator = randomizer.generator();
+
         // Create game field, functional style
         let field = (0..height)
             .map(|_| (0..width).map(|_| CellType::Empty).collect())
             .collect();
 
         // Create preview field, functional style
-        let mut preview = (0..4)
+        let mut preview: Vec<Vec<CellType>> = (0..4)
             .map(|_| (0..4).map(|_| CellType::Empty).collect())
             .collect();
 
         // Set next tetromino type
-        let next = Self::create_next_tetromino_type(&mut preview);
+        let next = Self::create_next_tetromino_type(&mut preview, generator.as_mut(), rng.as_mut());
+        let mut piece_chain = PieceChain::new(seed);
+        piece_chain.push(next as u8);
 
         // Create user actions queue
         let actions = VecDeque::new();
@@ -452,27 +765,203 @@ impl Tetris {
         let score = 0;
 
         // Create new tetris game
-        Tetris {
+        let mut tetris = Tetris {
             cols: width,
             rows: height,
             game_over,
             field,
+            field_hash: 0,
             preview,
             current: None,
-            next,
+            next: VecDeque::from([next]),
+            pieces: None,
+            held: None,
+            hold_used: false,
             actions,
             drop: false,
             game_speed: EventRegulator::new(1, 100),
             drop_speed: EventRegulator::new(1, 10),
             line_remove_speed: EventRegulator::new(3, 10),
             line_remove_delay: None,
+            difficulty,
+            gravity_percent: None,
+            gravity_curves: Vec::new(),
+            queued_curve: None,
+            lock_timer: None,
             score,
+            scoring: None,
+            lines: 0,
+            seed,
+            rng_kind,
+            rng,
+            randomizer,
+            generator,
+            piece_chain,
+            combo: 0,
+            last_lock: None,
+            piece_counts: [0; 7],
+            ticks: 0,
+            inputs: Vec::new(),
+        };
+        tetris.update_gravity();
+        tetris
+    }
+
+    // Use scoring rules instead of classic ones, set before the game starts
+    pub fn with_scoring(mut self, scoring: Option<Arc<ScoringRules>>) -> Self {
+        self.scoring = scoring.filter(|scoring| **scoring != ScoringRules::classic());
+        self
+    }
+
+    // Scale gravity of the difficulty preset by handicap, set before the game starts
+    pub fn with_gravity_percent(mut self, percent: usize) -> Self {
+        self.set_gravity_percent(percent);
+        self
+    }
+
+    pub fn set_gravity_percent(&mut self, percent: usize) {
+        self.gravity_percent = Some(percent).filter(|percent| *percent != 100);
+        self.update_gravity();
+    }
+
+    // Replace gravity curve of the difficulty preset from given levels, set before the
+    // game starts
+    pub fn with_gravity_curves(mut self, curves: Vec<(usize, GravityCurve)>) -> Self {
+        self.gravity_curves = curves;
+        self.update_gravity();
+        self
+    }
+
+    // Replace gravity curve of running game from it's next level
+    pub fn queue_gravity_curve(&mut self, curve: GravityCurve) {
+        self.queued_curve = Some(curve);
+    }
+
+    // Use hold and next queue rules instead of classic ones, set before the game starts.
+    // Longer queue is filled from the generator right away
+    pub fn with_pieces(mut self, pieces: PieceRules) -> Self {
+        if pieces == PieceRules::classic() {
+            return self;
+        }
+        while self.next.len() < pieces.next_queue {
+            let piece = self.next_piece();
+            self.next.push_back(piece);
+        }
+        self.pieces = Some(pieces);
+        self
+    }
+
+    pub fn get_pieces(&self) -> PieceRules {
+        self.pieces.unwrap_or_default()
+    }
+
+    // Set gravity and drop speed for current level of the difficulty preset
+    fn update_gravity(&mut self) {
+        let Some(difficulty) = self.difficulty else {
+            return;
+        };
+        let level = self.get_level();
+        let gravity = self
+            .gravity_curves
+            .iter()
+            .rev()
+            .find(|(from, _)| *from <= level)
+            .map_or_else(
+                || difficulty.gravity(level),
+                |(_, curve)| curve.gravity(level),
+            );
+        let gravity = match self.gravity_percent {
+            Some(percent) => (gravity * percent / 100).max(1),
+            None => gravity,
+        };
+        if self.game_speed.get_m() != gravity
+            || self.game_speed.get_n() != difficulty::GRAVITY_STEPS
+        {
+            self.game_speed.set_mn(gravity, difficulty::GRAVITY_STEPS);
+            self.drop_speed.set_mn(
+                gravity.max(difficulty::DROP_SPEED),
+                difficulty::GRAVITY_STEPS,
+            );
+        }
+    }
+
+    // Whether piece which can't move down is locked now. Pieces rest for the lock delay
+    // of difficulty preset before locking, dropped pieces lock immediately
+    fn lock_delay_over(&mut self) -> bool {
+        let Some(difficulty) = self.difficulty.filter(|_| !self.drop) else {
+            return true;
+        };
+        match self.lock_timer {
+            Some(0) => {
+                self.lock_timer = None;
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.lock_timer = Some(difficulty.lock_delay());
+                false
+            }
+        }
+    }
+
+    // Recreate game from replay by performing all recorded actions
+    pub fn from_replay(replay: &Replay) -> Self {
+        let mut player = ReplayPlayer::new(replay.clone());
+        while player.step() {}
+        player.into_tetris()
+    }
+
+    // Restore game from replay of unfinished game. Inputs added after the last step
+    // are queued for the next one
+    pub fn restore(replay: &Replay) -> Self {
+        let mut tetris = Self::from_replay(replay);
+        let applied = tetris.inputs.len();
+        for (_, action) in &replay.inputs[applied..] {
+            tetris.add_action(*action);
         }
+        tetris
+    }
+
+    // Number of pieces placed on the field by type, in TetrominoType::ALL order
+    pub fn get_piece_counts(&self) -> [u64; 7] {
+        self.piece_counts
     }
 
-    // Add user action to actions queue
+    pub fn get_inputs(&self) -> &[(u64, Action)] {
+        &self.inputs
+    }
+
+    // Get replay of the game played so far
+    pub fn get_replay(&self) -> Replay {
+        Replay {
+            cols: self.cols,
+            rows: self.rows,
+            seed: self.seed,
+            randomizer: self.randomizer,
+            rng: self.rng_kind,
+            difficulty: self.difficulty,
+            scoring: self.scoring.as_deref().cloned(),
+            pieces: self.pieces,
+            gravity_percent: self.gravity_percent,
+            gravity_curves: self.gravity_curves.clone(),
+            ticks: self.ticks,
+            inputs: self.inputs.clone(),
+        }
+    }
+
+    // Add user action to actions queue. Garbage is not queued, it's applied immediately,
+    // so it's not lost when queue is cleared on piece lock
     pub fn add_action(&mut self, action: Action) {
-        self.actions.push_back(action);
+        if !self.game_over {
+            self.inputs.push((self.ticks, action));
+        }
+        match action {
+            Action::Garbage { hole } if !self.game_over => {
+                self.add_garbage_line(hole);
+            }
+            Action::Garbage { .. } => (),
+            _ => self.actions.push_back(action),
+        }
     }
 
     // Process single user action
@@ -480,6 +969,7 @@ impl Tetris {
         if self.game_over {
             return StepResult::GameOver;
         }
+        self.ticks += 1;
 
         if let Some(ref mut delay) = self.line_remove_delay {
             if *delay > 0 {
@@ -502,6 +992,14 @@ impl Tetris {
             }
         }
 
+        // Resting piece is checked again when lock delay is over
+        if let Some(left) = self.lock_timer {
+            self.lock_timer = Some(left.saturating_sub(1));
+            if left == 1 {
+                self.actions.push_back(Action::MoveDown);
+            }
+        }
+
         if self.drop {
             for _ in 0..self.drop_speed.step() {
                 self.actions.push_back(Action::MoveDown);
@@ -523,46 +1021,123 @@ impl Tetris {
             Action::RotateRight => self.rotate_right(),
             Action::Drop => self.drop(),
             Action::BottomRefill => self.bottom_refill(),
+            Action::Garbage { hole } => self.add_garbage_line(hole),
+            Action::Hold => self.hold(),
         };
+        if succeed && action == Action::MoveDown {
+            self.lock_timer = None;
+            if self.drop {
+                self.score += self
+                    .scoring
+                    .as_ref()
+                    .map_or(0, |scoring| scoring.drop_per_cell);
+            }
+        }
         // Move down is special case. If it fails, fix current tetromino and blast full lines
-        if !succeed && action == Action::MoveDown {
+        if !succeed && action == Action::MoveDown && self.lock_delay_over() {
             self.fix_current_figure();
-            self.blast_full_lines();
+            let lines = self.blast_full_lines();
+            self.add_score(lines);
+            self.last_lock = Some(LineClear {
+                lines,
+                combo: self.combo,
+            });
+            self.combo = if lines > 0 { self.combo + 1 } else { 0 };
             self.actions.clear();
             self.line_remove_delay = Some(10); // Wait 10 ticks before placing next tetromino to show blast animation
         }
-        return StepResult::ActionPerformed(action, succeed);
+        StepResult::ActionPerformed(action, succeed)
+    }
+
+    // Next piece of the generator, chained for audits
+    fn next_piece(&mut self) -> TetrominoType {
+        let piece = self.generator.next(self.rng.as_mut());
+        self.piece_chain.push(piece as u8);
+        piece
     }
 
     // Create next tetromino type and draw it on preview field
-    fn create_next_tetromino_type(preview: &mut Vec<Vec<CellType>>) -> TetrominoType {
-        // Create next tetromino and draw it on preview field
+    fn create_next_tetromino_type(
+        preview: &mut [Vec<CellType>],
+        generator: &mut dyn PieceGenerator,
+        rng: &mut dyn GameRng,
+    ) -> TetrominoType {
         // Get next tetromino type
-        let tetromino_type = TetrominoType::new_random();
-        // Create new tetromino
-        let tetromino = Tetromino::new(tetromino_type, Rotation::R0, 0, 0);
-        // Draw tetromino on preview field
-        tetromino.draw(preview);
+        let tetromino_type = generator.next(rng);
+        // Draw it on preview field
+        Self::draw_preview(preview, tetromino_type);
         // Get tetromino
         tetromino_type
     }
 
+    // Draw tetromino on cleared preview field
+    fn draw_preview(preview: &mut [Vec<CellType>], tetromino_type: TetrominoType) {
+        // Clear previous tetromino from preview field
+        preview
+            .iter_mut()
+            .flatten()
+            .for_each(|cell| *cell = CellType::Empty);
+        // Create new tetromino and draw it
+        Tetromino::new(tetromino_type, Rotation::R0, 0, 0).draw(preview);
+    }
+
+    // Approximate memory used by the game, bytes
+    pub fn approx_size(&self) -> usize {
+        let cells = self
+            .field
+            .iter()
+            .chain(&self.preview)
+            .map(Vec::len)
+            .sum::<usize>();
+        std::mem::size_of::<Tetris>()
+            + cells * std::mem::size_of::<CellType>()
+            + self.inputs.capacity() * std::mem::size_of::<(u64, Action)>()
+            + self.actions.capacity() * std::mem::size_of::<Action>()
+    }
+
     pub fn get_field(&self) -> &Vec<Vec<CellType>> {
         &self.field
     }
 
+    // All changes of the field go through it to keep the field hash
+    fn set_cell(&mut self, x: usize, y: usize, cell: CellType) {
+        self.field_hash ^= cell_key(x, y, self.field[y][x]) ^ cell_key(x, y, cell);
+        self.field[y][x] = cell;
+    }
+
+    // Hash of the state spectators see: field, falling piece, queue, hold, score and
+    // game over, but not game internals. Equal hashes mean unchanged state, so streams
+    // and caches don't need to serialize and compare states. Computed in constant time
+    pub fn get_state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.field_hash.hash(&mut hasher);
+        self.current.hash(&mut hasher);
+        self.next.hash(&mut hasher);
+        self.held.hash(&mut hasher);
+        (self.score, self.lines, self.game_over).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[allow(dead_code)]
     pub fn get_current(&self) -> &Option<Tetromino> {
         &self.current
     }
 
+    #[allow(dead_code)]
     pub fn get_next(&self) -> &TetrominoType {
-        &self.next
+        &self.next[0]
+    }
+
+    // Tetromino as it enters the field
+    fn spawned(&self, tetromino_type: TetrominoType) -> Tetromino {
+        Tetromino::new(tetromino_type, Rotation::R0, self.cols as isize / 2 - 2, 0)
     }
 
     // Place new tetromino on the field. Return false if it's impossible to place new tetromino
     pub fn place_next_tetromino(&mut self) -> bool {
         // Create new tetromino
-        let new_tetromino = Tetromino::new(self.next, Rotation::R0, self.cols as isize / 2 - 2, 0);
+        let next = self.next[0];
+        let new_tetromino = self.spawned(next);
 
         // Check if new tetromino intersects with field borders or other tetrominos
         if new_tetromino.intersects(&self.field) {
@@ -570,12 +1145,20 @@ impl Tetris {
         }
         // Set new tetromino as current
         self.current = Some(new_tetromino);
+        if let Some(index) = TetrominoType::ALL.iter().position(|t| *t == next) {
+            self.piece_counts[index] += 1;
+        }
 
-        // Set next tetromino type and draw it on preview field
-        self.next = Self::create_next_tetromino_type(&mut self.preview);
+        // Take next tetromino from the queue, add new one and draw the first on preview field
+        self.next.pop_front();
+        let piece = self.next_piece();
+        self.next.push_back(piece);
+        Self::draw_preview(&mut self.preview, self.next[0]);
 
-        // Clear drop flag
+        // Clear drop flag, lock delay and hold of previous piece
         self.drop = false;
+        self.lock_timer = None;
+        self.hold_used = false;
 
         // Return true if new tetromino was placed on the field
         true
@@ -593,15 +1176,15 @@ impl Tetris {
         let new_tetromino = Tetromino::new(
             current.tetromino_type,
             current.rotation + rotation,
-            current.x as isize + x,
-            current.y as isize + y,
+            current.x + x,
+            current.y + y,
         );
         // Check if new tetromino intersects with field borders or other tetrominos
         if new_tetromino.intersects(&self.field) {
             return false;
         }
         *current = new_tetromino;
-        return true;
+        true
     }
 
     // Move current tetromino down, if it's possible
@@ -634,6 +1217,39 @@ impl Tetris {
         self.change_current_tetromino(0, 0, Rotation::R90)
     }
 
+    // Put current tetromino on hold and take the held one, or the next one when nothing
+    // is held. Allowed once per piece, only when the rules have hold
+    pub fn hold(&mut self) -> bool {
+        let Some(current) = &self.current else {
+            return false;
+        };
+        if !self.get_pieces().hold || self.hold_used {
+            return false;
+        }
+        let current_type = current.tetromino_type;
+        match self.held {
+            Some(held) => {
+                let swapped = self.spawned(held);
+                if swapped.intersects(&self.field) {
+                    return false;
+                }
+                self.current = Some(swapped);
+                self.drop = false;
+                self.lock_timer = None;
+            }
+            None => {
+                if self.spawned(self.next[0]).intersects(&self.field) {
+                    return false;
+                }
+                self.current = None;
+                self.place_next_tetromino();
+            }
+        }
+        self.held = Some(current_type);
+        self.hold_used = true;
+        true
+    }
+
     // Set drop flag
     pub fn drop(&mut self) -> bool {
         // Set drop flag
@@ -641,41 +1257,117 @@ impl Tetris {
         true
     }
 
-    // Push all lines up and fill bottom line with random cells with probability of filled cell = 0.5
+    // Push all lines up and fill bottom line with random cells with probability of filled cell = 0.5.
+    // The line has at least one filled and one empty cell, so it's neither cleared nor empty
     pub fn bottom_refill(&mut self) -> bool {
         // Push all lines up
+        self.shift_up();
+        let mut cells = (0..self.cols)
+            .map(|_| {
+                if self.rng.gen::<f32>() < 0.5 {
+                    CellType::new_random(&mut self.rng)
+                } else {
+                    CellType::Empty
+                }
+            })
+            .collect::<Vec<_>>();
+        if cells.iter().all(|cell| *cell == CellType::Empty) {
+            cells[self.rng.gen_range(0..self.cols)] = CellType::new_random(&mut self.rng);
+        } else if !cells.contains(&CellType::Empty) {
+            cells[self.rng.gen_range(0..self.cols)] = CellType::Empty;
+        }
+        for (x, cell) in cells.into_iter().enumerate() {
+            self.set_cell(x, self.rows - 1, cell);
+        }
+        self.lift_current();
+        true
+    }
+
+    // Move all lines one line up, the top line is lost and the bottom one is kept
+    fn shift_up(&mut self) {
         for y in 1..self.rows {
             for x in 0..self.cols {
-                self.field[y - 1][x] = self.field[y][x];
+                self.set_cell(x, y - 1, self.field[y][x]);
             }
         }
-        // Fill bottom line with random cells with probability of filled cell = 0.3
+    }
+
+    // Move falling piece up out of the cells pushed into it. Game is over when the piece
+    // doesn't fit below the top
+    fn lift_current(&mut self) {
+        let Some(current) = &mut self.current else {
+            return;
+        };
+        while current.intersects(&self.field) {
+            if current.y == 0 {
+                self.current = None;
+                self.game_over = true;
+                return;
+            }
+            current.y -= 1;
+        }
+    }
+
+    // Push all lines up and add garbage line with a hole at the bottom
+    pub fn add_garbage_line(&mut self, hole: usize) -> bool {
+        self.shift_up();
+        let cell_type = CellType::new_random(&mut self.rng);
         for x in 0..self.cols {
-            let cell_type = if rand::random::<f32>() < 0.5 {
-                CellType::new_random()
-            } else {
+            let cell = if x == hole {
                 CellType::Empty
+            } else {
+                cell_type
             };
-            self.field[self.rows - 1][x] = cell_type;
+            self.set_cell(x, self.rows - 1, cell);
         }
+        self.lift_current();
         true
     }
 
+    // End the game, e.g. when player leaves
+    pub fn forfeit(&mut self) {
+        self.game_over = true;
+    }
+
+    // Take result of the last piece lock, if piece was locked since previous call
+    pub fn take_lock(&mut self) -> Option<LineClear> {
+        self.last_lock.take()
+    }
+
     // Draw current tetromino on the field
     pub fn fix_current_figure(&mut self) {
         // Draw current tetromino on the field
         // Check if current tetromino exists
         if let Some(current) = self.current.take() {
             // Draw current tetromino on the field
-            current.draw(&mut self.field);
+            let cell_type = current.tetromino_type.get_cell_type();
+            for (x, y) in current.cells(self.cols, self.rows) {
+                self.set_cell(x, y, cell_type);
+            }
         }
     }
 
-    // Blasts full lines and returns true if there were full lines
-    fn blast_full_lines(&mut self) -> bool {
+    // Add score for lines removed at once: more lines at once give more points
+    fn add_score(&mut self, lines: usize) {
+        self.score += match &self.scoring {
+            Some(scoring) => scoring.clear_points(lines, self.get_level()),
+            None => ScoringRules::classic().clear_points(lines, self.get_level()),
+        };
+        let level = self.get_level();
+        self.lines += lines;
+        if self.get_level() > level {
+            if let Some(curve) = self.queued_curve.take() {
+                self.gravity_curves.push((self.get_level(), curve));
+            }
+        }
+        self.update_gravity();
+    }
+
+    // Blasts full lines and returns number of blasted lines
+    fn blast_full_lines(&mut self) -> usize {
         // Iterate over all lines
-        // If line is full, replace it's Empty cells to Blasted cells and set return value to true
-        let mut full_lines = false;
+        // If line is full, replace it's Empty cells to Blasted cells and count it
+        let mut full_lines = 0;
         for y in 0..self.rows {
             let mut full_line = true;
             for x in 0..self.cols {
@@ -685,9 +1377,9 @@ impl Tetris {
                 }
             }
             if full_line {
-                full_lines = true;
+                full_lines += 1;
                 for x in 0..self.cols {
-                    self.field[y][x] = CellType::Blasted;
+                    self.set_cell(x, y, CellType::Blasted);
                 }
             }
         }
@@ -713,12 +1405,12 @@ impl Tetris {
         // Shift all lines above topmost blasted line down to one line
         for y in (0..top_blasted_line).rev() {
             for x in 0..self.cols {
-                self.field[y + 1][x] = self.field[y][x];
+                self.set_cell(x, y + 1, self.field[y][x]);
             }
         }
         // Fill topmost line with Empty cells
         for x in 0..self.cols {
-            self.field[0][x] = CellType::Empty;
+            self.set_cell(x, 0, CellType::Empty);
         }
         // Return true if there were blasted lines
         true
@@ -732,25 +1424,376 @@ impl Tetris {
             current.draw(&mut field);
         }
         let preview = self.preview.clone();
+        // Queue and hold are sent only by games with such rules
+        let (next, hold) = match self.pieces {
+            Some(pieces) => (
+                self.next.iter().copied().collect(),
+                pieces.hold.then_some(self.held),
+            ),
+            None => (Vec::new(), None),
+        };
         TetrisGameState {
             cols: self.cols,
             rows: self.rows,
             field,
             preview,
+            next,
+            hold,
             game_over: self.game_over,
+            score: self.score,
+            lines: self.lines,
+            level: self.get_level(),
+            version: self.ticks,
+            internals: Some(GameInternals {
+                ticks: self.ticks,
+                queued_actions: self.actions.len(),
+                dropping: self.drop,
+                lock_timer: self.lock_timer,
+                combo: self.combo,
+                piece_counts: self.piece_counts,
+            }),
+        }
+    }
+
+    // State for bots, see bots: format byte 1, cols, rows, game over byte, score and lines
+    // as u32 LE, level byte, steps as u64 LE, falling piece as type, rotation, x, y bytes
+    // (type 255 when there's none), held piece type (255 when none), number of next pieces
+    // and their types, then field cells row by row from the top, without the falling piece.
+    // Types are indexes in TetrominoType::ALL, cells are CellType codes
+    pub fn get_binary_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(32 + self.rows * self.cols);
+        state.extend([1, self.cols as u8, self.rows as u8, self.game_over as u8]);
+        state.extend((self.score as u32).to_le_bytes());
+        state.extend((self.lines as u32).to_le_bytes());
+        state.push(self.get_level() as u8);
+        state.extend(self.ticks.to_le_bytes());
+        match &self.current {
+            Some(current) => state.extend([
+                current.tetromino_type as u8,
+                current.rotation as u8,
+                current.x as i8 as u8,
+                current.y as i8 as u8,
+            ]),
+            None => state.extend([u8::MAX, 0, 0, 0]),
+        }
+        state.push(self.held.map_or(u8::MAX, |held| held as u8));
+        state.push(self.next.len() as u8);
+        state.extend(self.next.iter().map(|next| *next as u8));
+        state.extend(self.field.iter().flatten().map(|cell| *cell as u8));
+        state
+    }
+
+    // Inputs of the bot placing the current piece: each rotation and column the piece can
+    // reach by rotating in place and shifting is tried, dropped straight down and rated by
+    // placement_score. Used by synthetic players, see synthetic
+    pub fn plan_placement(&self) -> Vec<Action> {
+        let Some(current) = self.current else {
+            return Vec::new();
+        };
+        let free = |piece: &Tetromino| !piece.intersects(&self.field);
+        let mut best: Option<(i64, Vec<Action>)> = None;
+        let mut rotated = current;
+        for turns in 0..4 {
+            if turns > 0 {
+                rotated.rotation = rotated.rotation.rotate_right();
+                if !free(&rotated) {
+                    break;
+                }
+            }
+            for (shift, step) in [(Action::MoveLeft, -1), (Action::MoveRight, 1)] {
+                let mut moved = rotated;
+                let mut shifts = 0;
+                loop {
+                    let mut landed = moved;
+                    while free(&Tetromino {
+                        y: landed.y + 1,
+                        ..landed
+                    }) {
+                        landed.y += 1;
+                    }
+                    let mut field = self.field.clone();
+                    landed.draw(&mut field);
+                    let score = placement_score(&field);
+                    if best.as_ref().is_none_or(|(best, _)| score > *best) {
+                        let mut actions = vec![Action::RotateRight; turns];
+                        actions.extend(std::iter::repeat_n(shift, shifts));
+                        actions.push(Action::Drop);
+                        best = Some((score, actions));
+                    }
+                    moved.x += step;
+                    if !free(&moved) {
+                        break;
+                    }
+                    shifts += 1;
+                }
+            }
         }
+        best.map(|(_, actions)| actions).unwrap_or_default()
     }
 
     pub fn is_game_over(&self) -> bool {
         self.game_over
     }
+
+    pub fn get_score(&self) -> usize {
+        self.score
+    }
+
+    pub fn get_lines(&self) -> usize {
+        self.lines
+    }
+
+    // Level of difficulty preset, games without preset stay on the first level
+    pub fn get_level(&self) -> usize {
+        self.difficulty
+            .map_or(1, |difficulty| difficulty.level(self.lines))
+    }
+
+    pub fn get_difficulty(&self) -> Option<Difficulty> {
+        self.difficulty
+    }
+
+    pub fn get_ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn get_cols(&self) -> usize {
+        self.cols
+    }
+
+    // Check rules the game state must follow after any sequence of inputs and steps.
+    // Line rules are checked while a piece is falling, lines are cleared before it's placed
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        if self.field.len() != self.rows || self.field.iter().any(|row| row.len() != self.cols) {
+            return Err(InvariantViolation::FieldSize);
+        }
+        let field_hash = self.field.iter().enumerate().fold(0, |hash, (y, cells)| {
+            cells
+                .iter()
+                .enumerate()
+                .fold(hash, |hash, (x, cell)| hash ^ cell_key(x, y, *cell))
+        });
+        if field_hash != self.field_hash {
+            return Err(InvariantViolation::FieldHash);
+        }
+        let Some(current) = &self.current else {
+            return Ok(());
+        };
+        if current.intersects(&self.field) {
+            return Err(InvariantViolation::PieceOverlap);
+        }
+        let mut filled_above = false;
+        for (row, cells) in self.field.iter().enumerate() {
+            if cells.contains(&CellType::Blasted) {
+                return Err(InvariantViolation::BlastedLeft { row });
+            }
+            if !cells.contains(&CellType::Empty) {
+                return Err(InvariantViolation::FullLine { row });
+            }
+            let empty = cells.iter().all(|cell| *cell == CellType::Empty);
+            if empty && filled_above {
+                return Err(InvariantViolation::FloatingCells { row });
+            }
+            filled_above |= !empty;
+        }
+        Ok(())
+    }
+
+    pub fn get_piece_chain(&self) -> &PieceChain {
+        &self.piece_chain
+    }
 }
 
-#[derive(Serialize)]
+// Broken rule of game state, found by Tetris::check_invariants
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum InvariantViolation {
+    // Field doesn't have configured number of rows and columns
+    FieldSize,
+    // Incremental field hash differs from hash of the field
+    FieldHash,
+    // Falling piece is out of field or overlaps locked cells
+    PieceOverlap,
+    // Blasted cells are left on the field after line clear is over
+    BlastedLeft { row: usize },
+    // Full line wasn't cleared when piece was locked
+    FullLine { row: usize },
+    // Empty line below filled cells, clears must shift everything above down
+    FloatingCells { row: usize },
+}
+
+impl std::fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvariantViolation::FieldSize => write!(f, "field size differs from game size"),
+            InvariantViolation::FieldHash => write!(f, "field hash is out of date"),
+            InvariantViolation::PieceOverlap => write!(f, "piece overlaps locked cells"),
+            InvariantViolation::BlastedLeft { row } => {
+                write!(f, "blasted cells left in row {}", row)
+            }
+            InvariantViolation::FullLine { row } => write!(f, "full row {} wasn't cleared", row),
+            InvariantViolation::FloatingCells { row } => {
+                write!(f, "empty row {} below filled cells", row)
+            }
+        }
+    }
+}
+
+// Plays replay step by step, e.g. to show it alongside live game
+pub struct ReplayPlayer {
+    tetris: Tetris,
+    replay: Replay,
+    // Index of next input to apply
+    next_input: usize,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        ReplayPlayer {
+            tetris: Tetris::new_with_seed(
+                replay.cols,
+                replay.rows,
+                replay.seed,
+                replay.randomizer,
+                replay.rng,
+                replay.difficulty,
+            )
+            .with_scoring(replay.scoring.clone().map(Arc::new))
+            .with_pieces(replay.pieces.unwrap_or_default())
+            .with_gravity_percent(replay.gravity_percent.unwrap_or(100))
+            .with_gravity_curves(replay.gravity_curves.clone()),
+            replay,
+            next_input: 0,
+        }
+    }
+
+    // Apply inputs recorded before current step and perform the step.
+    // Returns false when replay is over. Inputs after the last step, e.g. garbage which
+    // ended the game, are applied without the step
+    pub fn step(&mut self) -> bool {
+        if self.tetris.game_over {
+            return false;
+        }
+        while let Some((tick, action)) = self.replay.inputs.get(self.next_input) {
+            if *tick > self.tetris.ticks {
+                break;
+            }
+            self.tetris.add_action(*action);
+            self.next_input += 1;
+        }
+        if self.tetris.ticks >= self.replay.ticks {
+            return false;
+        }
+        self.tetris.step();
+        true
+    }
+
+    // Replay the game checking invariants after every step. Returns the step at which
+    // the first violation was found
+    pub fn check(mut self) -> Result<Tetris, (u64, InvariantViolation)> {
+        while self.step() {
+            self.tetris
+                .check_invariants()
+                .map_err(|violation| (self.tetris.ticks, violation))?;
+        }
+        Ok(self.tetris)
+    }
+
+    pub fn get_tetris(&self) -> &Tetris {
+        &self.tetris
+    }
+
+    // Take result of the last piece lock of the replayed game
+    pub fn take_lock(&mut self) -> Option<LineClear> {
+        self.tetris.take_lock()
+    }
+
+    pub fn into_tetris(self) -> Tetris {
+        self.tetris
+    }
+}
+
+#[derive(Clone, Serialize)]
 pub struct TetrisGameState {
     cols: usize,
     rows: usize,
     field: Vec<Vec<CellType>>,
     preview: Vec<Vec<CellType>>,
+    // Next pieces, first one is on preview field
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    next: Vec<TetrominoType>,
+    // Held piece, present when hold is available
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hold: Option<Option<TetrominoType>>,
+    game_over: bool,
+    score: usize,
+    lines: usize,
+    level: usize,
+    // Steps performed, inputs are acknowledged with it, see input_sequence
+    version: u64,
+    // Present in admin view only, see views
+    #[serde(skip_serializing_if = "Option::is_none")]
+    internals: Option<GameInternals>,
+}
+
+// Game engine state beyond what the player sees
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct GameInternals {
+    ticks: u64,
+    // Actions waiting for the next steps, including gravity moves
+    queued_actions: usize,
+    dropping: bool,
+    // Steps left before resting piece is locked
+    lock_timer: Option<u64>,
+    combo: usize,
+    piece_counts: [u64; 7],
+}
+
+// Game state in compact form, field rows as strings of cell type digits
+#[derive(Serialize)]
+pub struct CompactGameState {
+    rows: Vec<String>,
     game_over: bool,
+    score: usize,
+    lines: usize,
+    level: usize,
+}
+
+impl TetrisGameState {
+    // Hide upcoming pieces: next queue, held piece and preview field
+    pub fn without_queue(mut self) -> TetrisGameState {
+        self.next.clear();
+        self.hold = None;
+        self.preview
+            .iter_mut()
+            .flatten()
+            .for_each(|cell| *cell = CellType::Empty);
+        self
+    }
+
+    pub fn without_internals(mut self) -> TetrisGameState {
+        self.internals = None;
+        self
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn compact(&self) -> CompactGameState {
+        CompactGameState {
+            rows: self
+                .field
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|cell| (b'0' + *cell as u8) as char)
+                        .collect()
+                })
+                .collect(),
+            game_over: self.game_over,
+            score: self.score,
+            lines: self.lines,
+            level: self.level,
+        }
+    }
 }
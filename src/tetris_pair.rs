@@ -1,16 +1,172 @@
+use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use crate::{
+    difficulty::{Difficulty, GravityCurve},
+    game_mode::GameMode,
+    game_rng::{GameRng, PieceChain, RngKind},
+    garbage_rules::GarbageRules,
+    gravity_curves::GravityTuning,
+    handicap::{Handicap, HandicapRules},
     matches::PlayerSide,
-    tetris::{Action, StepResult, Tetris, TetrisGameState},
+    scoring::ScoringRules,
+    tetris::{
+        Action, CellType, LineClear, PieceRules, Randomizer, Replay, Tetris, TetrisGameState,
+    },
 };
+use rand::Rng;
 use serde::Serialize;
 
-#[derive(Serialize)]
+// Duration of one step, milliseconds
+pub const STEP_MS: u64 = 10;
+// Maximal delay added to inputs of the player with lower latency, steps
+const MAX_INPUT_DELAY: u64 = 10;
+// Versus start countdown, unless configured with versus_countdown
+pub const DEFAULT_COUNTDOWN: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Serialize)]
 pub struct TetrisPairState {
     pub player: TetrisGameState,
     pub opponent: TetrisGameState,
+    // Garbage pending for the player and the opponent, oldest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub incoming: Vec<IncomingAttack>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub opponent_incoming: Vec<IncomingAttack>,
+}
+
+impl TetrisPairState {
+    // The same state as of the opponent
+    pub fn swapped(self) -> TetrisPairState {
+        TetrisPairState {
+            player: self.opponent,
+            opponent: self.player,
+            incoming: self.opponent_incoming,
+            opponent_incoming: self.incoming,
+        }
+    }
+}
+
+// Garbage waiting to be received, see GarbageRules::delay
+pub use gameserver_protocol::game::IncomingAttack;
+
+// Attack in pending garbage of the receiving side
+#[derive(Debug, Clone, Copy)]
+struct PendingAttack {
+    id: u64,
+    lines: usize,
+    // Step of the receiver's game from which the attack may be received
+    arrives_at: u64,
+}
+
+// Synchronized start of the match, sent to players as "countdown" events
+pub use gameserver_protocol::game::Countdown;
+
+// Player inactivity limits, in steps
+#[derive(Debug, Clone, Copy)]
+pub struct AfkRules {
+    // Player without inputs for this time is AFK
+    pub timeout: u64,
+    // AFK player forfeits after this time
+    pub grace: u64,
+}
+
+impl Default for AfkRules {
+    fn default() -> Self {
+        // 30 and 15 seconds with 10ms step
+        AfkRules {
+            timeout: 3000,
+            grace: 1500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AfkStatus {
+    Active,
+    // Steps left until forfeit
+    Afk { forfeit_in: u64 },
+    Forfeited,
+}
+
+impl AfkStatus {
+    // Statuses of the same kind, ignoring countdown
+    pub fn same_kind(&self, other: &AfkStatus) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+// Rules of versus match
+#[derive(Debug, Clone)]
+pub struct VersusRules {
+    pub garbage: Arc<GarbageRules>,
+    pub afk: AfkRules,
+    // Random source of players' games and garbage
+    pub rng: RngKind,
+    // Difficulty preset of both players' games, also scales sent garbage
+    pub difficulty: Difficulty,
+    // Time between match creation and it's start, when both players' games start
+    // at the same instant
+    pub countdown: Duration,
+    pub scoring: Arc<ScoringRules>,
+    // Hold and next queue of both players' games
+    pub pieces: PieceRules,
+    // Both players' games share a seed and get the same pieces sequence
+    pub shared_pieces: bool,
+    pub handicap: HandicapRules,
+    // Gravity curve of new matches tuned by admin, shared by clones of the rules
+    pub gravity: GravityTuning,
+}
+
+impl Default for VersusRules {
+    fn default() -> Self {
+        VersusRules {
+            garbage: Arc::new(GarbageRules::classic()),
+            afk: AfkRules::default(),
+            rng: RngKind::default(),
+            difficulty: Difficulty::default(),
+            countdown: DEFAULT_COUNTDOWN,
+            scoring: Arc::new(ScoringRules::classic()),
+            pieces: GameMode::Versus.pieces(),
+            shared_pieces: false,
+            handicap: HandicapRules::default(),
+            gravity: GravityTuning::default(),
+        }
+    }
+}
+
+// Final result of one player
+pub struct PlayerResult {
+    pub side: PlayerSide,
+    pub score: usize,
+    pub lines: usize,
+    // Final field, without the falling piece
+    pub board: Vec<Vec<CellType>>,
+    // Garbage lines sent to opponent, including cancelled ones, and received
+    pub attack_sent: usize,
+    pub garbage_received: usize,
+    // Pieces placed on the field
+    pub pieces: u64,
+    pub forfeited: bool,
+    // Player's game is over, the other player has won unless both games are over
+    pub lost: bool,
+    pub handicap: Handicap,
+    pub replay: Replay,
+    // Pieces the player's generator emitted, see rng_audit
+    pub piece_chain: PieceChain,
+}
+
+// Player inputs not yet written to input log
+pub struct NewInputs {
+    // Position of the first input in player's inputs
+    pub from: usize,
+    // Steps performed by the player
+    pub ticks: u64,
+    pub inputs: Vec<(u64, Action)>,
 }
 
-#[derive(Default)]
 pub struct TetrisPair {
     tetris_a: Tetris,
     tetris_b: Tetris,
@@ -19,20 +175,272 @@ pub struct TetrisPair {
     step_a: bool,
     step_b: bool,
     step_divergence: usize,
+    // Time when game was started, seconds since unix epoch
+    started: u64,
+    // Common start of both games and it's unix time in milliseconds. Countdown begins
+    // when both players step, games don't step and inputs are refused before the start
+    starts_at: Option<(Instant, u64)>,
+    // Results are given out only once after game over
+    results_taken: bool,
+    rules: VersusRules,
+    // Garbage state: attacks waiting to be received and hole column
+    // of the last garbage line, by receiving side
+    pending_garbage: [VecDeque<PendingAttack>; 2],
+    garbage_hole: [usize; 2],
+    // Attacks sent to each side so far, numbers them
+    attacks_sent_to: [u64; 2],
+    // Attack statistics by side
+    attack_sent: [usize; 2],
+    garbage_received: [usize; 2],
+    handicaps: [Handicap; 2],
+    // Garbage holes generator. Holes are recorded in replays as actions
+    rng: Box<dyn GameRng>,
+    // Step of the last input by side, for AFK detection
+    last_input: [u64; 2],
+    forfeited: Option<PlayerSide>,
+    // Paused players don't become AFK
+    paused: [bool; 2],
+    // One-way latency by side, steps. Inputs of the player with lower latency are delayed
+    // by the difference, so both players' inputs take effect equally late
+    latency: [u64; 2],
+    delayed_inputs: [VecDeque<(u64, Action)>; 2],
+    // Time of the last step request of any player
+    last_step: Instant,
+    // Number of inputs of each side already written to recovery journal, None until the
+    // match is in the journal
+    logged_inputs: Option<[usize; 2]>,
+    // Time when results were taken, finished matches are removed some time after
+    finished: Option<Instant>,
+}
+
+impl Default for TetrisPair {
+    fn default() -> Self {
+        TetrisPair::new(10, 20)
+    }
 }
 
 impl TetrisPair {
     pub fn new(width: usize, height: usize) -> TetrisPair {
+        Self::with_randomizer(width, height, GameMode::Versus.randomizer())
+    }
+
+    pub fn with_randomizer(width: usize, height: usize, randomizer: Randomizer) -> TetrisPair {
+        Self::with_rules(width, height, randomizer, VersusRules::default())
+    }
+
+    pub fn with_rules(
+        width: usize,
+        height: usize,
+        randomizer: Randomizer,
+        rules: VersusRules,
+    ) -> TetrisPair {
+        let curves = rules
+            .gravity
+            .current()
+            .map(|curve| vec![(0, curve)])
+            .unwrap_or_default();
+        let with_rules = |tetris: Tetris| {
+            tetris
+                .with_scoring(Some(rules.scoring.clone()))
+                .with_pieces(rules.pieces)
+                .with_gravity_curves(curves.clone())
+        };
+        let new_game = || Tetris::new_game(width, height, randomizer, rules.rng, rules.difficulty);
+        let (tetris_a, tetris_b) = if rules.shared_pieces {
+            // Seed is chosen once at match creation and given to both games
+            let seed = rand::random();
+            println!(
+                "New versus match with shared pieces: seed {}, {:?} random source, \
+                 {:?} randomizer, {:?} difficulty",
+                seed, rules.rng, randomizer, rules.difficulty
+            );
+            let seeded_game = || {
+                Tetris::new_with_seed(
+                    width,
+                    height,
+                    seed,
+                    randomizer,
+                    rules.rng,
+                    Some(rules.difficulty),
+                )
+            };
+            (seeded_game(), seeded_game())
+        } else {
+            (new_game(), new_game())
+        };
+        Self::with_games(with_rules(tetris_a), with_rules(tetris_b), rules)
+    }
+
+    fn with_games(tetris_a: Tetris, tetris_b: Tetris, rules: VersusRules) -> TetrisPair {
+        let width = tetris_a.get_cols();
+        let mut rng = rules.rng.seeded(rand::random());
+        let garbage_hole = [rng.gen_range(0..width), rng.gen_range(0..width)];
         TetrisPair {
-            tetris_a: Tetris::new(width, height),
-            tetris_b: Tetris::new(width, height),
+            tetris_a,
+            tetris_b,
             step_a: false,
             step_b: false,
             step_divergence: 0,
+            started: crate::unix_time(),
+            starts_at: None,
+            results_taken: false,
+            rules,
+            pending_garbage: [VecDeque::new(), VecDeque::new()],
+            garbage_hole,
+            attacks_sent_to: [0, 0],
+            attack_sent: [0, 0],
+            garbage_received: [0, 0],
+            handicaps: [Handicap::default(); 2],
+            rng,
+            last_input: [0, 0],
+            forfeited: None,
+            paused: [false, false],
+            latency: [0, 0],
+            delayed_inputs: [VecDeque::new(), VecDeque::new()],
+            last_step: Instant::now(),
+            logged_inputs: None,
+            finished: None,
+        }
+    }
+
+    // Apply handicaps of both players before the match start. Starting garbage and
+    // gravity are recorded in replays
+    pub fn with_handicaps(mut self, handicaps: [Handicap; 2]) -> TetrisPair {
+        for (side, handicap) in [(PlayerSide::A, handicaps[0]), (PlayerSide::B, handicaps[1])] {
+            self.tetris_mut(side)
+                .set_gravity_percent(handicap.gravity_percent);
+            self.add_garbage_lines(side, handicap.starting_garbage);
+        }
+        self.handicaps = handicaps;
+        self
+    }
+
+    // Restore unfinished match from replays of both players. Restored match counts down
+    // again once both players reconnect
+    pub fn restore(
+        replays: [&Replay; 2],
+        rules: VersusRules,
+        started: u64,
+        handicaps: [Handicap; 2],
+    ) -> TetrisPair {
+        let [replay_a, replay_b] = replays;
+        let mut pair =
+            Self::with_games(Tetris::restore(replay_a), Tetris::restore(replay_b), rules);
+        pair.started = started;
+        pair.handicaps = handicaps;
+        // Players get full AFK timeout after restart
+        pair.last_input = [pair.tetris_a.get_ticks(), pair.tetris_b.get_ticks()];
+        pair.logged_inputs = Some([replay_a.inputs.len(), replay_b.inputs.len()]);
+        pair
+    }
+
+    fn tetris(&self, side: PlayerSide) -> &Tetris {
+        match side {
+            PlayerSide::A => &self.tetris_a,
+            PlayerSide::B => &self.tetris_b,
+        }
+    }
+
+    fn tetris_mut(&mut self, side: PlayerSide) -> &mut Tetris {
+        match side {
+            PlayerSide::A => &mut self.tetris_a,
+            PlayerSide::B => &mut self.tetris_b,
+        }
+    }
+
+    fn side_index(side: PlayerSide) -> usize {
+        match side {
+            PlayerSide::A => 0,
+            PlayerSide::B => 1,
+        }
+    }
+
+    // Add garbage lines to the player's field
+    fn receive_garbage(&mut self, side: PlayerSide, lines: usize) {
+        self.garbage_received[Self::side_index(side)] += lines;
+        self.add_garbage_lines(side, lines);
+    }
+
+    fn add_garbage_lines(&mut self, side: PlayerSide, lines: usize) {
+        let index = Self::side_index(side);
+        for _ in 0..lines {
+            let action = if self.rules.garbage.random_lines {
+                Action::BottomRefill
+            } else {
+                if self.rng.gen::<f64>() < self.rules.garbage.messiness {
+                    let cols = self.tetris_mut(side).get_cols();
+                    self.garbage_hole[index] = self.rng.gen_range(0..cols);
+                }
+                Action::Garbage {
+                    hole: self.garbage_hole[index],
+                }
+            };
+            self.tetris_mut(side).add_action(action);
+        }
+    }
+
+    // Send garbage for player's last lock according to the rules
+    fn exchange_garbage(&mut self, side: PlayerSide) {
+        if let Some(clear) = self.tetris_mut(side).take_lock() {
+            self.resolve_lock(side, clear);
+        }
+    }
+
+    // Send attack of the lock's clear, cancelling own pending garbage first, or receive
+    // pending garbage which arrived when the lock doesn't clear lines
+    fn resolve_lock(&mut self, side: PlayerSide, clear: LineClear) {
+        let index = Self::side_index(side);
+        let opponent = side.opponent();
+        if clear.lines == 0 {
+            // Pending garbage is received when player doesn't clear lines after it's delay
+            let ticks = self.tetris(side).get_ticks();
+            let mut arrived = 0;
+            while let Some(attack) = self.pending_garbage[index].front() {
+                if attack.arrives_at > ticks {
+                    break;
+                }
+                arrived += attack.lines;
+                self.pending_garbage[index].pop_front();
+            }
+            self.receive_garbage(side, arrived);
+            return;
+        }
+        let mut attack = self.handicaps[index].scale_attack(
+            self.rules
+                .difficulty
+                .scale_attack(self.rules.garbage.attack(&clear)),
+        );
+        self.attack_sent[index] += attack;
+        if self.rules.garbage.cancellation {
+            // Oldest pending attacks are cancelled first
+            while attack > 0 {
+                let Some(pending) = self.pending_garbage[index].front_mut() else {
+                    break;
+                };
+                let cancelled = attack.min(pending.lines);
+                pending.lines -= cancelled;
+                attack -= cancelled;
+                if pending.lines == 0 {
+                    self.pending_garbage[index].pop_front();
+                }
+            }
+            if attack > 0 {
+                let opponent_index = Self::side_index(opponent);
+                self.attacks_sent_to[opponent_index] += 1;
+                let arrives_at = self.tetris(opponent).get_ticks() + self.rules.garbage.delay;
+                self.pending_garbage[opponent_index].push_back(PendingAttack {
+                    id: self.attacks_sent_to[opponent_index],
+                    lines: attack,
+                    arrives_at,
+                });
+            }
+        } else {
+            self.receive_garbage(opponent, attack);
         }
     }
 
     pub fn step_player(&mut self, player: PlayerSide) -> usize {
+        self.last_step = Instant::now();
         match player {
             PlayerSide::A => self.step_a = true,
             PlayerSide::B => self.step_b = true,
@@ -41,41 +449,482 @@ impl TetrisPair {
             self.step_divergence = 0;
             self.step_a = false;
             self.step_b = false;
-            let step_result_a = self.tetris_a.step();
-            let step_result_b = self.tetris_b.step();
-            if step_result_a == StepResult::LineRemoved {
-                self.tetris_b.add_action(Action::BottomRefill);
-            }
-            if step_result_b == StepResult::LineRemoved {
-                self.tetris_a.add_action(Action::BottomRefill);
+            if !self.is_started() {
+                if self.starts_at.is_none() {
+                    self.starts_at = Some((
+                        Instant::now() + self.rules.countdown,
+                        crate::unix_time_ms() + self.rules.countdown.as_millis() as u64,
+                    ));
+                }
+                return 0;
             }
+            self.apply_delayed_inputs(PlayerSide::A);
+            self.apply_delayed_inputs(PlayerSide::B);
+            self.tetris_a.step();
+            self.tetris_b.step();
+            self.exchange_garbage(PlayerSide::A);
+            self.exchange_garbage(PlayerSide::B);
+            self.keep_paused_active();
+            self.check_afk(PlayerSide::A);
+            self.check_afk(PlayerSide::B);
         } else {
             self.step_divergence += 1;
         }
         self.step_divergence
     }
 
-    pub fn add_player_action(&mut self, player: PlayerSide, action: Action) {
-        match player {
-            PlayerSide::A => self.tetris_a.add_action(action),
-            PlayerSide::B => self.tetris_b.add_action(action),
+    // Returns version of the player's game at which the action is put into the game,
+    // None before the start
+    pub fn add_player_action(&mut self, player: PlayerSide, action: Action) -> Option<u64> {
+        if !self.is_started() {
+            return None;
+        }
+        let index = Self::side_index(player);
+        let delay = self.input_delay(player);
+        let ticks = self.tetris_mut(player).get_ticks();
+        // Inputs are kept in order when delay decreases
+        let at = self.delayed_inputs[index]
+            .back()
+            .map_or(ticks + delay, |&(last, _)| last.max(ticks + delay));
+        if delay == 0 && self.delayed_inputs[index].is_empty() {
+            self.tetris_mut(player).add_action(action);
+        } else {
+            self.delayed_inputs[index].push_back((at, action));
+        }
+        self.last_input[index] = ticks;
+        Some(at)
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.starts_at
+            .is_some_and(|(starts_at, _)| Instant::now() >= starts_at)
+    }
+
+    // Countdown to the start, None until both players are connected
+    pub fn countdown(&self) -> Option<Countdown> {
+        let (starts_at, starts_at_ms) = self.starts_at?;
+        let left = starts_at.saturating_duration_since(Instant::now());
+        Some(Countdown {
+            starts_at_ms,
+            server_time_ms: crate::unix_time_ms(),
+            seconds_left: left.as_millis().div_ceil(1000) as u64,
+        })
+    }
+
+    // Set measured round trip of the player, milliseconds
+    pub fn set_latency(&mut self, player: PlayerSide, rtt_ms: u64) {
+        self.latency[Self::side_index(player)] = rtt_ms / 2 / STEP_MS;
+    }
+
+    // Steps the player's inputs are delayed for
+    fn input_delay(&self, player: PlayerSide) -> u64 {
+        let own = self.latency[Self::side_index(player)];
+        let opponent = self.latency[Self::side_index(player.opponent())];
+        opponent.saturating_sub(own).min(MAX_INPUT_DELAY)
+    }
+
+    fn apply_delayed_inputs(&mut self, player: PlayerSide) {
+        let index = Self::side_index(player);
+        let ticks = self.tetris_mut(player).get_ticks();
+        while let Some(&(at, action)) = self.delayed_inputs[index].front() {
+            if at > ticks {
+                break;
+            }
+            self.delayed_inputs[index].pop_front();
+            self.tetris_mut(player).add_action(action);
+        }
+    }
+
+    pub fn set_paused(&mut self, player: PlayerSide, paused: bool) {
+        self.paused[Self::side_index(player)] = paused;
+    }
+
+    // AFK time of paused players doesn't grow
+    fn keep_paused_active(&mut self) {
+        for (index, ticks) in [self.tetris_a.get_ticks(), self.tetris_b.get_ticks()]
+            .into_iter()
+            .enumerate()
+        {
+            if self.paused[index] {
+                self.last_input[index] = ticks;
+            }
+        }
+    }
+
+    // Steps since player's last input
+    fn idle(&self, side: PlayerSide) -> u64 {
+        let tetris = match side {
+            PlayerSide::A => &self.tetris_a,
+            PlayerSide::B => &self.tetris_b,
+        };
+        tetris.get_ticks() - self.last_input[Self::side_index(side)]
+    }
+
+    // Steps since the latest input of either player
+    pub fn idle_steps(&self) -> u64 {
+        self.idle(PlayerSide::A).min(self.idle(PlayerSide::B))
+    }
+
+    // Forfeit the match for player who is AFK longer than grace period
+    fn check_afk(&mut self, side: PlayerSide) {
+        if self.is_game_over() {
+            return;
+        }
+        let afk = self.rules.afk;
+        if self.idle(side) >= afk.timeout + afk.grace {
+            self.forfeited = Some(side);
+            self.tetris_mut(side).forfeit();
         }
     }
 
+    pub fn afk_status(&self, side: PlayerSide) -> AfkStatus {
+        if self.forfeited == Some(side) {
+            return AfkStatus::Forfeited;
+        }
+        let afk = self.rules.afk;
+        let idle = self.idle(side);
+        if idle >= afk.timeout && !self.is_game_over() {
+            AfkStatus::Afk {
+                forfeit_in: (afk.timeout + afk.grace).saturating_sub(idle),
+            }
+        } else {
+            AfkStatus::Active
+        }
+    }
+
+    // Time since results of finished game were taken
+    pub fn finished_for(&self) -> Option<Duration> {
+        self.finished.map(|finished| finished.elapsed())
+    }
+
+    pub fn get_replays(&self) -> [Replay; 2] {
+        [self.tetris_a.get_replay(), self.tetris_b.get_replay()]
+    }
+
+    // Inputs since previous call, None until the match is journaled
+    pub fn take_new_inputs(&mut self, side: PlayerSide) -> Option<NewInputs> {
+        let index = Self::side_index(side);
+        let from = self.logged_inputs?[index];
+        let tetris = self.tetris(side);
+        let inputs = tetris.get_inputs()[from..].to_vec();
+        if inputs.is_empty() {
+            return None;
+        }
+        let ticks = tetris.get_ticks();
+        if let Some(logged) = &mut self.logged_inputs {
+            logged[index] = from + inputs.len();
+        }
+        Some(NewInputs {
+            from,
+            ticks,
+            inputs,
+        })
+    }
+
+    // All inputs are in the journal, new inputs are logged from now
+    pub fn mark_inputs_logged(&mut self) {
+        self.logged_inputs = Some([
+            self.tetris_a.get_inputs().len(),
+            self.tetris_b.get_inputs().len(),
+        ]);
+    }
+
+    pub fn is_journaled(&self) -> bool {
+        self.logged_inputs.is_some()
+    }
+
+    // Match end is in the journal, nothing more is logged
+    pub fn mark_journal_ended(&mut self) {
+        self.logged_inputs = None;
+    }
+
+    pub fn get_last_step(&self) -> Instant {
+        self.last_step
+    }
+
+    pub fn are_results_taken(&self) -> bool {
+        self.results_taken
+    }
+
+    // Approximate memory used by the match, bytes
+    pub fn approx_size(&self) -> usize {
+        std::mem::size_of::<TetrisPair>() - 2 * std::mem::size_of::<Tetris>()
+            + self.tetris_a.approx_size()
+            + self.tetris_b.approx_size()
+            + self
+                .delayed_inputs
+                .iter()
+                .map(|inputs| inputs.capacity() * std::mem::size_of::<(u64, Action)>())
+                .sum::<usize>()
+    }
+
     pub fn is_game_over(&self) -> bool {
         self.tetris_a.is_game_over() || self.tetris_b.is_game_over()
     }
 
+    // Replace gravity curve of both games from their next levels
+    pub fn queue_gravity_curve(&mut self, curve: &GravityCurve) {
+        self.tetris_a.queue_gravity_curve(curve.clone());
+        self.tetris_b.queue_gravity_curve(curve.clone());
+    }
+
+    pub fn get_started(&self) -> u64 {
+        self.started
+    }
+
+    pub fn get_handicaps(&self) -> [Handicap; 2] {
+        self.handicaps
+    }
+
+    pub fn get_rules(&self) -> &VersusRules {
+        &self.rules
+    }
+
+    // Players who topped out or forfeited
+    pub fn get_lost(&self) -> [bool; 2] {
+        [self.tetris_a.is_game_over(), self.tetris_b.is_game_over()]
+    }
+
+    pub fn get_scores(&self) -> (usize, usize) {
+        (self.tetris_a.get_score(), self.tetris_b.get_score())
+    }
+
+    fn player_result(&self, side: PlayerSide) -> PlayerResult {
+        let index = Self::side_index(side);
+        let tetris = match side {
+            PlayerSide::A => &self.tetris_a,
+            PlayerSide::B => &self.tetris_b,
+        };
+        PlayerResult {
+            side,
+            score: tetris.get_score(),
+            lines: tetris.get_lines(),
+            board: tetris.get_field().clone(),
+            attack_sent: self.attack_sent[index],
+            garbage_received: self.garbage_received[index],
+            pieces: tetris.get_piece_counts().iter().sum(),
+            forfeited: self.forfeited == Some(side),
+            lost: tetris.is_game_over(),
+            handicap: self.handicaps[index],
+            replay: tetris.get_replay(),
+            piece_chain: tetris.get_piece_chain().clone(),
+        }
+    }
+
+    // Returns results of both players when game is over. Results are returned only once
+    pub fn take_results(&mut self) -> Option<[PlayerResult; 2]> {
+        if !self.is_game_over() || self.results_taken {
+            return None;
+        }
+        self.results_taken = true;
+        self.finished = Some(Instant::now());
+        Some([
+            self.player_result(PlayerSide::A),
+            self.player_result(PlayerSide::B),
+        ])
+    }
+
+    // Hash of spectated state of both games and pending garbage,
+    // see Tetris::get_state_hash
+    pub fn get_state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.tetris_a.get_state_hash().hash(&mut hasher);
+        self.tetris_b.get_state_hash().hash(&mut hasher);
+        for pending in &self.pending_garbage {
+            for attack in pending {
+                (attack.id, attack.lines).hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    // Garbage pending for the side with time left until it arrives
+    pub fn get_incoming(&self, side: PlayerSide) -> Vec<IncomingAttack> {
+        let ticks = self.tetris(side).get_ticks();
+        self.pending_garbage[Self::side_index(side)]
+            .iter()
+            .map(|attack| IncomingAttack {
+                id: attack.id,
+                lines: attack.lines,
+                arrives_in_ms: attack.arrives_at.saturating_sub(ticks) * STEP_MS,
+            })
+            .collect()
+    }
+
+    // Pieces placed by the player and inputs of the bot's placement of the current one
+    pub fn plan_placement(&self, player: PlayerSide) -> (u64, Vec<Action>) {
+        let tetris = self.tetris(player);
+        (
+            tetris.get_piece_counts().iter().sum(),
+            tetris.plan_placement(),
+        )
+    }
+
     pub fn get_player_game_state(&self, player: PlayerSide) -> TetrisPairState {
-        match player {
-            PlayerSide::A => TetrisPairState {
-                player: self.tetris_a.get_game_state(),
-                opponent: self.tetris_b.get_game_state(),
-            },
-            PlayerSide::B => TetrisPairState {
-                player: self.tetris_b.get_game_state(),
-                opponent: self.tetris_a.get_game_state(),
-            },
+        let opponent = player.opponent();
+        TetrisPairState {
+            player: self.tetris(player).get_game_state(),
+            opponent: self.tetris(opponent).get_game_state(),
+            incoming: self.get_incoming(player),
+            opponent_incoming: self.get_incoming(opponent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: PlayerSide = PlayerSide::A;
+    const B: PlayerSide = PlayerSide::B;
+
+    fn pair(cancellation: bool, delay: u64) -> TetrisPair {
+        let garbage = GarbageRules {
+            name: "test".to_string(),
+            attack: [1, 2, 3, 4],
+            combo_bonus: Vec::new(),
+            cancellation,
+            delay,
+            messiness: 0.,
+            random_lines: false,
+        };
+        let rules = VersusRules {
+            garbage: Arc::new(garbage),
+            ..VersusRules::default()
+        };
+        TetrisPair::with_rules(10, 20, GameMode::Versus.randomizer(), rules)
+    }
+
+    fn lock(pair: &mut TetrisPair, side: PlayerSide, lines: usize) {
+        pair.resolve_lock(side, LineClear { lines, combo: 0 });
+    }
+
+    fn steps(pair: &mut TetrisPair, side: PlayerSide, steps: usize) {
+        for _ in 0..steps {
+            pair.tetris_mut(side).step();
         }
     }
+
+    // Lines of pending attacks and time until they arrive
+    fn incoming(pair: &TetrisPair, side: PlayerSide) -> Vec<(usize, u64)> {
+        pair.get_incoming(side)
+            .iter()
+            .map(|attack| (attack.lines, attack.arrives_in_ms))
+            .collect()
+    }
+
+    // Attack sent and garbage received by both sides
+    fn totals(pair: &TetrisPair) -> ([usize; 2], [usize; 2]) {
+        (pair.attack_sent, pair.garbage_received)
+    }
+
+    #[test]
+    fn garbage_is_received_at_once_without_cancellation() {
+        let mut pair = pair(false, 30);
+        lock(&mut pair, A, 4);
+        lock(&mut pair, B, 2);
+        lock(&mut pair, A, 0);
+        assert_eq!(totals(&pair), ([4, 2], [2, 4]));
+        assert!(incoming(&pair, A).is_empty() && incoming(&pair, B).is_empty());
+        // Received lines are on the field at once, each with single hole
+        let garbage_rows = pair
+            .tetris(B)
+            .get_field()
+            .iter()
+            .filter(|row| row.iter().filter(|cell| **cell == CellType::Empty).count() == 1)
+            .count();
+        assert_eq!(garbage_rows, 4);
+    }
+
+    #[test]
+    fn pending_garbage_arrives_after_delay() {
+        let mut pair = pair(true, 30);
+        lock(&mut pair, A, 2);
+        assert_eq!(incoming(&pair, B), [(2, 30 * STEP_MS)]);
+        // Lock before the delay is over doesn't receive it
+        steps(&mut pair, B, 20);
+        lock(&mut pair, B, 0);
+        assert_eq!(totals(&pair), ([2, 0], [0, 0]));
+        lock(&mut pair, A, 3);
+        assert_eq!(incoming(&pair, B), [(2, 10 * STEP_MS), (3, 30 * STEP_MS)]);
+        // Only attacks which arrived are received
+        steps(&mut pair, B, 15);
+        lock(&mut pair, B, 0);
+        assert_eq!(totals(&pair), ([5, 0], [0, 2]));
+        assert_eq!(incoming(&pair, B), [(3, 15 * STEP_MS)]);
+        steps(&mut pair, B, 15);
+        lock(&mut pair, B, 0);
+        assert_eq!(totals(&pair), ([5, 0], [0, 5]));
+        assert!(incoming(&pair, B).is_empty());
+    }
+
+    #[test]
+    fn clears_cancel_oldest_pending_garbage_first() {
+        let mut pair = pair(true, 30);
+        lock(&mut pair, A, 1);
+        lock(&mut pair, A, 2);
+        lock(&mut pair, A, 3);
+        assert_eq!(
+            pair.get_incoming(B)
+                .iter()
+                .map(|attack| (attack.id, attack.lines))
+                .collect::<Vec<_>>(),
+            [(1, 1), (2, 2), (3, 3)]
+        );
+        // Cancels the first attack and one line of the second one
+        lock(&mut pair, B, 2);
+        assert_eq!(
+            pair.get_incoming(B)
+                .iter()
+                .map(|attack| (attack.id, attack.lines))
+                .collect::<Vec<_>>(),
+            [(2, 1), (3, 3)]
+        );
+        assert!(incoming(&pair, A).is_empty());
+        // Lines left after cancelling everything are sent
+        lock(&mut pair, B, 4);
+        lock(&mut pair, B, 2);
+        assert!(incoming(&pair, B).is_empty());
+        assert_eq!(incoming(&pair, A), [(2, 30 * STEP_MS)]);
+        assert_eq!(pair.get_incoming(A)[0].id, 1);
+        // Cancelled lines count as sent, but are never received
+        steps(&mut pair, B, 30);
+        lock(&mut pair, B, 0);
+        assert_eq!(totals(&pair), ([6, 8], [0, 0]));
+        steps(&mut pair, A, 30);
+        lock(&mut pair, A, 0);
+        assert_eq!(totals(&pair), ([6, 8], [2, 0]));
+    }
+
+    #[test]
+    fn attack_is_scaled_by_handicap() {
+        let handicap = Handicap {
+            starting_garbage: 0,
+            attack_percent: 50,
+            gravity_percent: 100,
+        };
+        let mut pair = pair(false, 0).with_handicaps([handicap, Handicap::default()]);
+        lock(&mut pair, A, 4);
+        lock(&mut pair, B, 3);
+        // Rounded down
+        lock(&mut pair, A, 3);
+        lock(&mut pair, A, 1);
+        assert_eq!(totals(&pair), ([3, 3], [3, 3]));
+    }
+
+    #[test]
+    fn cancellation_uses_scaled_attack() {
+        let handicap = Handicap {
+            starting_garbage: 0,
+            attack_percent: 50,
+            gravity_percent: 100,
+        };
+        let mut pair = pair(true, 30).with_handicaps([Handicap::default(), handicap]);
+        lock(&mut pair, A, 3);
+        // Two of four lines are sent, both cancel pending ones
+        lock(&mut pair, B, 4);
+        assert_eq!(incoming(&pair, B), [(1, 30 * STEP_MS)]);
+        assert!(incoming(&pair, A).is_empty());
+        assert_eq!(totals(&pair), ([3, 2], [0, 0]));
+    }
 }
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    form::Form, fs::NamedFile, get, http::CookieJar, post, routes, serde::json::Json, FromForm,
+    Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    ids::UserId,
+    storage::{self, Database},
+    TetrisMatches,
+};
+
+//
+// Piece skins and board themes for frontends. Built-in themes have colors only, more themes
+// are loaded at startup from subdirectories of themes directory: theme.json describes
+// the theme and lists it's asset files (images, sounds) which are served under
+// /themes/<directory>/. Theme chosen by user is stored, so it follows the user
+// to other frontends
+//
+
+pub const THEMES_DIR: &str = "themes";
+const THEME_FILE: &str = "theme.json";
+pub const DEFAULT_PIECES: &str = "classic";
+pub const DEFAULT_BOARD: &str = "classic_board";
+
+const CHOICES_SEGMENT: &str = "theme_choices";
+const BY_USER_INDEX: &str = "theme_choices_by_user";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThemeKind {
+    // Colors and images of pieces by cell type: I, J, L, O, S, T, Z, Blasted
+    Pieces,
+    // Background, empty cells, walls etc.
+    Board,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Theme {
+    pub name: String,
+    pub kind: ThemeKind,
+    // Colors by element name
+    #[serde(default)]
+    pub colors: BTreeMap<String, String>,
+    // Asset file names of theme directory by element name
+    #[serde(default)]
+    pub assets: BTreeMap<String, String>,
+    // Directory the theme is loaded from, None for built-in themes
+    #[serde(skip)]
+    pub dir: Option<String>,
+}
+
+// Theme for listings, with asset urls
+#[derive(Serialize)]
+pub struct ThemeInfo {
+    pub name: String,
+    pub kind: ThemeKind,
+    pub colors: BTreeMap<String, String>,
+    pub assets: BTreeMap<String, String>,
+}
+
+// Themes chosen by user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThemeChoice {
+    pub user: UserId,
+    pub pieces: String,
+    pub board: String,
+}
+
+#[derive(Serialize)]
+pub struct ThemeList {
+    pub themes: Vec<ThemeInfo>,
+    pub pieces: String,
+    pub board: String,
+}
+
+#[derive(FromForm)]
+pub struct ThemeForm {
+    pieces: Option<String>,
+    board: Option<String>,
+}
+
+fn colors(colors: &[(&str, &str)]) -> BTreeMap<String, String> {
+    colors
+        .iter()
+        .map(|(element, color)| (element.to_string(), color.to_string()))
+        .collect()
+}
+
+impl Theme {
+    // Colors of the original client
+    pub fn classic() -> Theme {
+        Theme {
+            name: DEFAULT_PIECES.to_string(),
+            kind: ThemeKind::Pieces,
+            colors: colors(&[
+                ("I", "#00ffff"),
+                ("J", "#0000ff"),
+                ("L", "#ffa500"),
+                ("O", "#ffff00"),
+                ("S", "#00ff00"),
+                ("T", "#800080"),
+                ("Z", "#ff0000"),
+                ("Blasted", "#ff9900"),
+            ]),
+            assets: BTreeMap::new(),
+            dir: None,
+        }
+    }
+
+    pub fn classic_board() -> Theme {
+        Theme {
+            name: DEFAULT_BOARD.to_string(),
+            kind: ThemeKind::Board,
+            colors: colors(&[("background", "#333333"), ("Empty", "#f2f2f2")]),
+            assets: BTreeMap::new(),
+            dir: None,
+        }
+    }
+
+    fn validate(&self, dir: &Path) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("Theme name is empty".to_string());
+        }
+        for file in self.assets.values() {
+            if !valid_file_name(file) {
+                return Err(format!("Invalid asset file name {}", file));
+            }
+            if !dir.join(file).is_file() {
+                return Err(format!("Asset file {} not found", file));
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> ThemeInfo {
+        ThemeInfo {
+            name: self.name.clone(),
+            kind: self.kind,
+            colors: self.colors.clone(),
+            assets: self
+                .assets
+                .iter()
+                .filter_map(|(element, file)| {
+                    let dir = self.dir.as_ref()?;
+                    Some((element.clone(), format!("/themes/{}/{}", dir, file)))
+                })
+                .collect(),
+        }
+    }
+}
+
+// Assets are plain files of theme directory, names are used in urls
+fn valid_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Available themes by name
+pub struct Themes(BTreeMap<String, Theme>);
+
+impl Themes {
+    // Built-in themes and themes of the directory's subdirectories, if it exists
+    pub fn load(dir: &Path) -> Result<Themes, Error> {
+        let mut themes = BTreeMap::new();
+        for theme in [Theme::classic(), Theme::classic_board()] {
+            themes.insert(theme.name.clone(), theme);
+        }
+        if dir.is_dir() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let file = path.join(THEME_FILE);
+                if !file.is_file() {
+                    continue;
+                }
+                let dir_name = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .filter(|name| valid_file_name(name))
+                    .ok_or_else(|| {
+                        Error::InvalidInputError(format!(
+                            "Invalid theme directory name {}",
+                            path.display()
+                        ))
+                    })?
+                    .to_string();
+                let mut theme: Theme =
+                    rocket::serde::json::serde_json::from_slice(&fs::read(&file)?)?;
+                theme
+                    .validate(&path)
+                    .map_err(|e| Error::InvalidInputError(format!("{}: {}", file.display(), e)))?;
+                theme.dir = Some(dir_name);
+                themes.insert(theme.name.clone(), theme);
+            }
+        }
+        Ok(Themes(themes))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.0.keys().map(String::as_str).collect()
+    }
+
+    // Name of theme of given kind, default one if there is no such theme
+    fn resolve(&self, name: &str, kind: ThemeKind) -> String {
+        match self.0.get(name) {
+            Some(theme) if theme.kind == kind => theme.name.clone(),
+            _ => match kind {
+                ThemeKind::Pieces => DEFAULT_PIECES.to_string(),
+                ThemeKind::Board => DEFAULT_BOARD.to_string(),
+            },
+        }
+    }
+
+    fn check(&self, name: &str, kind: ThemeKind) -> Result<String, Error> {
+        match self.0.get(name) {
+            Some(theme) if theme.kind == kind => Ok(theme.name.clone()),
+            _ => Err(Error::InvalidInputError(format!(
+                "Unknown {:?} theme {}",
+                kind, name
+            ))),
+        }
+    }
+
+    // Path of asset file listed by the theme of the directory
+    fn asset_path(&self, dir: &str, file: &str) -> Option<PathBuf> {
+        self.0
+            .values()
+            .filter(|theme| theme.dir.as_deref() == Some(dir))
+            .any(|theme| theme.assets.values().any(|asset| asset == file))
+            .then(|| Path::new(THEMES_DIR).join(dir).join(file))
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, CHOICES_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+fn read_choice(persy: &Persy, user: UserId) -> Result<Option<(PersyId, ThemeChoice)>, Error> {
+    let Some(id) = persy.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? else {
+        return Ok(None);
+    };
+    Ok(storage::read(persy, CHOICES_SEGMENT, &id)?.map(|choice| (id, choice)))
+}
+
+// Themes chosen by user, defaults for themes which are not available anymore
+pub fn choice(persy: &Persy, themes: &Themes, user: UserId) -> Result<ThemeChoice, Error> {
+    let (pieces, board) = match read_choice(persy, user)? {
+        Some((_, choice)) => (choice.pieces, choice.board),
+        None => (DEFAULT_PIECES.to_string(), DEFAULT_BOARD.to_string()),
+    };
+    Ok(ThemeChoice {
+        user,
+        pieces: themes.resolve(&pieces, ThemeKind::Pieces),
+        board: themes.resolve(&board, ThemeKind::Board),
+    })
+}
+
+fn store_choice(persy: &Persy, choice: &ThemeChoice) -> Result<(), Error> {
+    match read_choice(persy, choice.user)? {
+        Some((id, _)) => storage::update(persy, CHOICES_SEGMENT, &id, choice),
+        None => {
+            storage::insert_with(persy, CHOICES_SEGMENT, choice, |tx, id| {
+                tx.put(BY_USER_INDEX, choice.user.0, *id)?;
+                Ok(())
+            })?;
+            Ok(())
+        }
+    }
+}
+
+// Available themes and themes chosen by user
+#[get("/themes")]
+fn list_themes(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    themes: &State<Themes>,
+) -> Result<Json<ThemeList>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let choice = choice(&db.read(), themes, user_id)?;
+    Ok(Json(ThemeList {
+        themes: themes.0.values().map(Theme::info).collect(),
+        pieces: choice.pieces,
+        board: choice.board,
+    }))
+}
+
+// Choose piece skin and/or board theme
+#[post("/themes/select", data = "<form>")]
+fn select_theme(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+    themes: &State<Themes>,
+    form: Form<ThemeForm>,
+) -> Result<Json<ThemeChoice>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let persy = &*db.read();
+    let mut choice = choice(persy, themes, user_id)?;
+    if let Some(pieces) = &form.pieces {
+        choice.pieces = themes.check(pieces, ThemeKind::Pieces)?;
+    }
+    if let Some(board) = &form.board {
+        choice.board = themes.check(board, ThemeKind::Board)?;
+    }
+    store_choice(persy, &choice)?;
+    Ok(Json(choice))
+}
+
+// Asset file of a theme
+#[get("/themes/<dir>/<file>")]
+async fn asset(themes: &State<Themes>, dir: &str, file: &str) -> Option<NamedFile> {
+    NamedFile::open(themes.asset_path(dir, file)?).await.ok()
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list_themes, select_theme, asset]
+}
@@ -0,0 +1,675 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    form::Form,
+    futures::StreamExt,
+    get, post,
+    response::{
+        stream::{stream, Event, EventStream},
+        Redirect,
+    },
+    routes,
+    serde::json::{serde_json, Json},
+    tokio::{
+        self,
+        time::{self, Duration},
+    },
+    FromForm, Route, State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connections::Connections,
+    error::Error,
+    events::ChannelEvent,
+    ids::{MatchId, UserId},
+    multiview::BoardView,
+    roles::{Moderator, Viewer},
+    storage::{self, Database},
+    views::{Projection, View},
+    TetrisMatches,
+};
+
+//
+// Single elimination tournaments of versus matches, with a live overview for casters.
+// Moderator creates a tournament with it's players in /admin/tournaments. Players are
+// seeded by rating, first seeds get byes when the number of players isn't a power of two.
+// Matches of the bracket are started by moderator once both players are known, players'
+// game streams pick them up as any versus match. Watch job follows started matches:
+// winners advance to the next round, knockouts and upsets (wins over a player rated at
+// least UPSET_RATING_GAP higher) are kept as match events. Match which leaves memory
+// without result, e.g. quarantined one, is voided and started again by moderator.
+// GET /tournament/<id> is the overview for polling, GET /tournament/<id>/caster streams
+// spectator views of the bracket's active matches and match events over one connection,
+// for casting overlays. Tournaments are stored in "tournaments" segment and looked up by
+// their own ids, which compaction doesn't change. Started matches and match events are
+// kept in memory only, after restart the matches are started again
+//
+
+const TOURNAMENTS_SEGMENT: &str = "tournaments";
+const BY_ID_INDEX: &str = "tournaments_by_id";
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_PLAYERS: usize = 64;
+// Rating difference of the players which makes a win an upset
+pub const UPSET_RATING_GAP: i32 = 100;
+// Match events kept for the overview and for casters connecting later
+const MAX_EVENTS: usize = 100;
+// Interval of looking for finished bracket matches
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+// Interval of sampling the boards for caster stream
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TournamentStatus {
+    Running,
+    Finished,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BracketMatch {
+    // None until decided by the previous round, or for a bye
+    pub players: [Option<UserId>; 2],
+    // Versus match while it's played, ids of matches in memory are not kept on restart
+    #[serde(skip_deserializing)]
+    pub match_id: Option<MatchId>,
+    // Ratings of the players as the match started
+    pub ratings: Option<[i32; 2]>,
+    pub winner: Option<UserId>,
+    pub scores: Option<[usize; 2]>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tournament {
+    pub id: u32,
+    pub name: String,
+    // Seconds since unix epoch
+    pub created: u64,
+    // In order of seeds
+    pub players: Vec<UserId>,
+    pub status: TournamentStatus,
+    // Matches of each round by their slot, the last round is the final
+    pub rounds: Vec<Vec<BracketMatch>>,
+    pub winner: Option<UserId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind")]
+pub enum MatchEvent {
+    Started {
+        round: usize,
+        slot: usize,
+        match_id: MatchId,
+        players: [UserId; 2],
+    },
+    Knockout {
+        round: usize,
+        slot: usize,
+        winner: UserId,
+        loser: UserId,
+        scores: [usize; 2],
+    },
+    Upset {
+        round: usize,
+        slot: usize,
+        winner: UserId,
+        loser: UserId,
+        rating_gap: i32,
+    },
+    // Match left memory without result
+    Voided {
+        round: usize,
+        slot: usize,
+    },
+    Champion {
+        winner: UserId,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CasterEvent {
+    // Sequence number within the tournament
+    pub seq: u64,
+    // Seconds since unix epoch
+    pub time: u64,
+    pub event: MatchEvent,
+}
+
+// Progress of started bracket match, see TetrisMatches::bracket_progress
+pub enum BracketProgress {
+    Playing,
+    // Players who lost, both lose when they top out at once
+    Over { lost: [bool; 2], scores: [usize; 2] },
+    // Not in memory anymore
+    Gone,
+}
+
+#[derive(Serialize)]
+pub struct ActiveMatch {
+    pub round: usize,
+    pub slot: usize,
+    pub match_id: MatchId,
+    pub players: [UserId; 2],
+    // None when the match is not in memory
+    pub scores: Option<[usize; 2]>,
+}
+
+#[derive(Serialize)]
+pub struct Overview {
+    pub tournament: Tournament,
+    pub active: Vec<ActiveMatch>,
+    // The latest match events, the oldest first
+    pub events: Vec<CasterEvent>,
+}
+
+#[derive(FromForm)]
+pub struct TournamentForm {
+    name: String,
+    // Comma separated user ids
+    players: String,
+}
+
+struct LiveTournament {
+    tournament: Tournament,
+    events: VecDeque<CasterEvent>,
+    next_seq: u64,
+    // Not stored since the last change
+    changed: bool,
+}
+
+// Tournaments by id, kept in memory and stored on changes
+#[derive(Clone, Default)]
+pub struct Tournaments(Arc<RwLock<BTreeMap<u32, LiveTournament>>>);
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, TOURNAMENTS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_ID_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+// Slots of seeds in the first round of bracket of the size. Seed 1 meets the last seed and
+// so on, so the first seeds meet in the final at the earliest
+fn seed_slots(size: usize) -> Vec<usize> {
+    let mut slots = vec![0];
+    while slots.len() < size {
+        let seeds = slots.len() * 2;
+        slots = slots
+            .iter()
+            .flat_map(|seed| [*seed, seeds - 1 - seed])
+            .collect();
+    }
+    slots
+}
+
+impl BracketMatch {
+    fn new(players: [Option<UserId>; 2]) -> BracketMatch {
+        BracketMatch {
+            players,
+            match_id: None,
+            ratings: None,
+            winner: None,
+            scores: None,
+        }
+    }
+}
+
+impl Tournament {
+    // Bracket of the players in order of seeds, byes are decided right away
+    fn new(id: u32, name: String, players: Vec<UserId>) -> Tournament {
+        let size = players.len().next_power_of_two().max(2);
+        let first = seed_slots(size)
+            .chunks(2)
+            .map(|seeds| {
+                BracketMatch::new([
+                    players.get(seeds[0]).copied(),
+                    players.get(seeds[1]).copied(),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let mut rounds = vec![first];
+        while let Some(last) = rounds.last().filter(|round| round.len() > 1) {
+            let matches = last.len() / 2;
+            rounds.push(
+                (0..matches)
+                    .map(|_| BracketMatch::new([None, None]))
+                    .collect(),
+            );
+        }
+        let mut tournament = Tournament {
+            id,
+            name,
+            created: crate::unix_time(),
+            players,
+            status: TournamentStatus::Running,
+            rounds,
+            winner: None,
+        };
+        for slot in 0..tournament.rounds[0].len() {
+            if let [Some(player), None] = tournament.rounds[0][slot].players {
+                tournament.advance(0, slot, player);
+            }
+        }
+        tournament
+    }
+
+    // Winner moves to the next round, winner of the final wins the tournament
+    fn advance(&mut self, round: usize, slot: usize, winner: UserId) {
+        self.rounds[round][slot].winner = Some(winner);
+        match self.rounds.get_mut(round + 1) {
+            Some(next) => next[slot / 2].players[slot % 2] = Some(winner),
+            None => {
+                self.winner = Some(winner);
+                self.status = TournamentStatus::Finished;
+            }
+        }
+    }
+
+    // Matches with both players known which are neither started nor decided
+    fn ready(&self) -> Vec<(usize, usize, [UserId; 2])> {
+        let mut ready = Vec::new();
+        for (round, matches) in self.rounds.iter().enumerate() {
+            for (slot, bracket_match) in matches.iter().enumerate() {
+                if let [Some(player_a), Some(player_b)] = bracket_match.players {
+                    if bracket_match.match_id.is_none() && bracket_match.winner.is_none() {
+                        ready.push((round, slot, [player_a, player_b]));
+                    }
+                }
+            }
+        }
+        ready
+    }
+
+    fn active(&self) -> Vec<(usize, usize, MatchId, [UserId; 2])> {
+        let mut active = Vec::new();
+        for (round, matches) in self.rounds.iter().enumerate() {
+            for (slot, bracket_match) in matches.iter().enumerate() {
+                if let (Some(match_id), [Some(player_a), Some(player_b)], None) = (
+                    bracket_match.match_id,
+                    bracket_match.players,
+                    bracket_match.winner,
+                ) {
+                    active.push((round, slot, match_id, [player_a, player_b]));
+                }
+            }
+        }
+        active
+    }
+}
+
+impl LiveTournament {
+    fn new(tournament: Tournament) -> LiveTournament {
+        LiveTournament {
+            tournament,
+            events: VecDeque::new(),
+            next_seq: 0,
+            changed: false,
+        }
+    }
+
+    fn push(&mut self, event: MatchEvent) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(CasterEvent {
+            seq: self.next_seq,
+            time: crate::unix_time(),
+            event,
+        });
+        self.next_seq += 1;
+        self.changed = true;
+    }
+
+    // Decide the match by it's progress, Over match advances it's winner
+    fn update(&mut self, round: usize, slot: usize, progress: BracketProgress) {
+        let bracket_match = &mut self.tournament.rounds[round][slot];
+        let [Some(player_a), Some(player_b)] = bracket_match.players else {
+            return;
+        };
+        let (lost, scores) = match progress {
+            BracketProgress::Playing => return,
+            BracketProgress::Gone => {
+                bracket_match.match_id = None;
+                self.push(MatchEvent::Voided { round, slot });
+                return;
+            }
+            BracketProgress::Over { lost, scores } => (lost, scores),
+        };
+        // Higher score wins when both top out, the higher seed wins a tie
+        let a_wins = match lost {
+            [false, true] => true,
+            [true, false] => false,
+            _ => scores[0] >= scores[1],
+        };
+        let (winner, loser) = if a_wins {
+            (player_a, player_b)
+        } else {
+            (player_b, player_a)
+        };
+        bracket_match.scores = Some(scores);
+        let rating_gap = bracket_match.ratings.map(|[rating_a, rating_b]| {
+            if a_wins {
+                rating_b - rating_a
+            } else {
+                rating_a - rating_b
+            }
+        });
+        self.tournament.advance(round, slot, winner);
+        self.push(MatchEvent::Knockout {
+            round,
+            slot,
+            winner,
+            loser,
+            scores,
+        });
+        if let Some(rating_gap) = rating_gap.filter(|gap| *gap >= UPSET_RATING_GAP) {
+            self.push(MatchEvent::Upset {
+                round,
+                slot,
+                winner,
+                loser,
+                rating_gap,
+            });
+        }
+        if let Some(winner) = self.tournament.winner {
+            self.push(MatchEvent::Champion { winner });
+        }
+    }
+}
+
+impl Tournaments {
+    pub fn load(persy: &Persy) -> Result<Tournaments, Error> {
+        let tournaments = storage::scan::<Tournament>(persy, TOURNAMENTS_SEGMENT)?
+            .into_iter()
+            .map(|(_, tournament)| (tournament.id, LiveTournament::new(tournament)))
+            .collect::<BTreeMap<_, _>>();
+        Ok(Tournaments(Arc::new(RwLock::new(tournaments))))
+    }
+
+    // Create tournament of the players, they're seeded by their ratings
+    fn create(
+        &self,
+        persy: &Persy,
+        matches: &TetrisMatches,
+        form: &TournamentForm,
+    ) -> Result<u32, Error> {
+        let name = form.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(Error::InvalidInputError(format!(
+                "Name is required, up to {} characters",
+                MAX_NAME_LEN
+            )));
+        }
+        let mut players = Vec::new();
+        for player in form
+            .players
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+        {
+            let player = player
+                .parse::<UserId>()
+                .map_err(|_| Error::InvalidInputError(format!("Invalid user id {}", player)))?;
+            if !players.contains(&player) {
+                players.push(player);
+            }
+        }
+        if !(2..=MAX_PLAYERS).contains(&players.len()) {
+            return Err(Error::InvalidInputError(format!(
+                "Tournament takes 2 to {} players",
+                MAX_PLAYERS
+            )));
+        }
+        // The highest rated as seed 1, stable for equal ratings
+        players.sort_by_key(|player| std::cmp::Reverse(matches.ratings.get(*player).rating));
+        let mut tournaments = self.0.write().unwrap();
+        let id = tournaments.keys().next_back().map_or(1, |id| id + 1);
+        let tournament = Tournament::new(id, name.to_string(), players);
+        storage::insert_with(persy, TOURNAMENTS_SEGMENT, &tournament, |tx, record| {
+            tx.put(BY_ID_INDEX, id, *record)?;
+            Ok(())
+        })?;
+        println!("Tournament {} {} created", id, tournament.name);
+        tournaments.insert(id, LiveTournament::new(tournament));
+        Ok(id)
+    }
+
+    // Start bracket matches whose players are known, returns number of started ones.
+    // Players in other matches are started later
+    fn start_ready(&self, id: u32, matches: &TetrisMatches) -> Result<usize, Error> {
+        let mut tournaments = self.0.write().unwrap();
+        let live = tournaments
+            .get_mut(&id)
+            .ok_or_else(|| Error::NotFoundError("Tournament not found".to_string()))?;
+        let mut started = 0;
+        for (round, slot, players) in live.tournament.ready() {
+            if players.iter().any(|player| matches.has_match(*player)) {
+                println!(
+                    "Tournament {} match of {:?} waits for players in other matches",
+                    id, players
+                );
+                continue;
+            }
+            let match_id = matches.start_match(players);
+            let bracket_match = &mut live.tournament.rounds[round][slot];
+            bracket_match.match_id = Some(match_id);
+            bracket_match.ratings = Some(players.map(|player| matches.ratings.get(player).rating));
+            live.push(MatchEvent::Started {
+                round,
+                slot,
+                match_id,
+                players,
+            });
+            started += 1;
+        }
+        Ok(started)
+    }
+
+    // Decide finished matches, returns tournaments changed since they were stored
+    fn watch(&self, matches: &TetrisMatches) -> Vec<Tournament> {
+        let mut tournaments = self.0.write().unwrap();
+        let mut changed = Vec::new();
+        for live in tournaments.values_mut() {
+            for (round, slot, match_id, _) in live.tournament.active() {
+                live.update(round, slot, matches.bracket_progress(match_id));
+            }
+            if live.changed {
+                live.changed = false;
+                changed.push(live.tournament.clone());
+            }
+        }
+        changed
+    }
+
+    // Background job deciding finished bracket matches and storing changed tournaments
+    pub async fn watch_job(self, db: Database, matches: TetrisMatches) {
+        let mut interval = time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let changed = self.watch(&matches);
+            if changed.is_empty() {
+                continue;
+            }
+            let db = db.clone();
+            let stored = tokio::task::spawn_blocking(move || -> Result<(), Error> {
+                let persy = db.read();
+                for tournament in &changed {
+                    if let Some(record) = persy.one::<u32, PersyId>(BY_ID_INDEX, &tournament.id)? {
+                        storage::update(&persy, TOURNAMENTS_SEGMENT, &record, tournament)?;
+                    }
+                }
+                Ok(())
+            })
+            .await;
+            match stored {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => println!("Failed to store tournaments: {}", e),
+                Err(e) => println!("Tournaments task failed: {}", e),
+            }
+        }
+    }
+
+    fn overview(&self, id: u32, matches: &TetrisMatches) -> Option<Overview> {
+        let tournaments = self.0.read().unwrap();
+        let live = tournaments.get(&id)?;
+        let active = live
+            .tournament
+            .active()
+            .into_iter()
+            .map(|(round, slot, match_id, players)| ActiveMatch {
+                round,
+                slot,
+                match_id,
+                players,
+                scores: matches.board(match_id).map(|board| board.scores),
+            })
+            .collect();
+        Some(Overview {
+            tournament: live.tournament.clone(),
+            active,
+            events: live.events.iter().cloned().collect(),
+        })
+    }
+
+    // Active matches, match events from the sequence number on and whether the tournament
+    // is finished, None when there's no such tournament
+    fn caster_frame(&self, id: u32, from: u64) -> Option<(Vec<MatchId>, Vec<CasterEvent>, bool)> {
+        let tournaments = self.0.read().unwrap();
+        let live = tournaments.get(&id)?;
+        let active = live
+            .tournament
+            .active()
+            .into_iter()
+            .map(|(_, _, match_id, _)| match_id)
+            .collect();
+        let events = live
+            .events
+            .iter()
+            .filter(|event| event.seq >= from)
+            .cloned()
+            .collect();
+        Some((
+            active,
+            events,
+            live.tournament.status == TournamentStatus::Finished,
+        ))
+    }
+
+    fn list(&self) -> Vec<Tournament> {
+        let tournaments = self.0.read().unwrap();
+        tournaments
+            .values()
+            .rev()
+            .map(|live| live.tournament.clone())
+            .collect()
+    }
+}
+
+// Bracket, active matches with their scores and the latest match events, for polling
+#[get("/tournament/<id>")]
+fn overview(
+    tournaments: &State<Tournaments>,
+    matches: &State<TetrisMatches>,
+    id: u32,
+) -> Result<Json<Overview>, Error> {
+    tournaments
+        .overview(id, matches)
+        .map(Json)
+        .ok_or_else(|| Error::NotFoundError("Tournament not found".to_string()))
+}
+
+// Stream for casters: "board" events with spectator views of active bracket matches when
+// they change, as in multiview, and "match_event" events, the kept ones first. Upcoming
+// pieces are hidden with hide_queue. Stream ends after the champion is announced
+#[get("/tournament/<id>/caster?<hide_queue>")]
+fn caster<'b>(
+    tournaments: &'b State<Tournaments>,
+    matches: &'b State<TetrisMatches>,
+    connections: &State<Connections>,
+    id: u32,
+    hide_queue: Option<bool>,
+) -> Result<EventStream![Event + 'b], Error> {
+    let watching = tournaments
+        .caster_frame(id, 0)
+        .map(|(active, _, _)| active)
+        .ok_or_else(|| Error::NotFoundError("Tournament not found".to_string()))?;
+    let view = View::spectator(hide_queue.unwrap_or(false));
+    let events = stream! {
+        let mut next_seq = 0;
+        // State hash of last sent board of each active match
+        let mut sent = HashMap::<MatchId, u64>::new();
+        let mut interval = time::interval(FRAME_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some((active, events, finished)) = tournaments.caster_frame(id, next_seq) else {
+                break;
+            };
+            for event in events {
+                next_seq = event.seq + 1;
+                yield ChannelEvent::named("match_event", serde_json::to_string(&event).unwrap());
+            }
+            sent.retain(|match_id, _| active.contains(match_id));
+            for match_id in active {
+                let Some(hash) = matches.board_hash(match_id) else {
+                    continue;
+                };
+                if sent.insert(match_id, hash) != Some(hash) {
+                    let board = BoardView {
+                        match_id,
+                        board: matches.board(match_id).map(|board| board.project(view)),
+                    };
+                    yield ChannelEvent::named("board", serde_json::to_string(&board).unwrap());
+                }
+            }
+            if finished {
+                break;
+            }
+        }
+    };
+    let events = connections.track_watching(None, "caster", watching, events);
+    Ok(EventStream::from(events.map(ChannelEvent::into_event)))
+}
+
+#[get("/admin/tournaments")]
+fn admin_tournaments(_admin: Viewer, tournaments: &State<Tournaments>) -> Template {
+    Template::render(
+        "admin/tournaments",
+        context! { tournaments: tournaments.list() },
+    )
+}
+
+#[post("/admin/tournaments", data = "<form>")]
+fn create_tournament(
+    _admin: Moderator,
+    db: &State<Database>,
+    tournaments: &State<Tournaments>,
+    matches: &State<TetrisMatches>,
+    form: Form<TournamentForm>,
+) -> Result<Redirect, Error> {
+    tournaments.create(&db.read(), matches, &form)?;
+    Ok(Redirect::to("/admin/tournaments"))
+}
+
+#[post("/admin/tournaments/<id>/start")]
+fn start_matches(
+    _admin: Moderator,
+    tournaments: &State<Tournaments>,
+    matches: &State<TetrisMatches>,
+    id: u32,
+) -> Result<Redirect, Error> {
+    let started = tournaments.start_ready(id, matches)?;
+    println!("Tournament {}: {} matches started", id, started);
+    Ok(Redirect::to("/admin/tournaments"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        overview,
+        caster,
+        admin_tournaments,
+        create_tournament,
+        start_matches
+    ]
+}
@@ -0,0 +1,337 @@
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use rocket::{
+    figment::Figment,
+    futures::Stream,
+    response::stream::stream,
+    serde::json::serde_json,
+    tokio::time::{self, interval},
+};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    events::ChannelEvent,
+    ids::{MatchId, UserId},
+    matches::{Matches, PlayerSide, PlayerStatus},
+    quarantine::{self, Quarantine, QuarantinedGame, QuarantinedMode},
+};
+
+//
+// Turn-based games: two players alternate moves instead of playing on a tick. Rules of
+// a game are implemented with TurnBasedGame, TurnMatch keeps the turn and it's timer:
+// player who doesn't move within the turn timeout loses. Matches don't tick, the timer is
+// checked whenever a match is accessed, so waiting for a move costs nothing. Matchmaking
+// is shared with versus (see matches), finished matches are kept for FINISHED_MATCH_TTL
+// to show the final state. Moves and timer checks run under quarantine::guard, match whose
+// game panics is quarantined. Connect four is the reference game
+//
+
+pub const DEFAULT_TURN_TIMEOUT: Duration = Duration::from_secs(60);
+
+const FINISHED_MATCH_TTL: Duration = Duration::from_secs(10);
+
+// How often streams check the match for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Outcome {
+    // Side won by the rules of the game
+    Won(PlayerSide),
+    Draw,
+    // Side didn't move in time and lost
+    TimedOut(PlayerSide),
+}
+
+// New game is created with default
+pub trait TurnBasedGame: Default + Send + Sync {
+    type Move: Debug;
+    type State: Serialize + Send;
+
+    // Game type of quarantined matches
+    const MODE: QuarantinedMode;
+
+    // Apply move of side on it's turn, error describes why the move is illegal
+    fn play(&mut self, side: PlayerSide, mv: Self::Move) -> Result<(), String>;
+
+    // Side to move after side's move
+    fn next_turn(&self, side: PlayerSide) -> PlayerSide {
+        side.opponent()
+    }
+
+    // Result by the rules of the game, None while it goes on
+    fn outcome(&self) -> Option<Outcome>;
+
+    // State shown to both players
+    fn state(&self) -> Self::State;
+}
+
+pub struct TurnMatch<G> {
+    game: G,
+    turn: PlayerSide,
+    turn_started: Instant,
+    turn_timeout: Duration,
+    moves: usize,
+    outcome: Option<Outcome>,
+    finished: Option<Instant>,
+    // Seconds since unix epoch
+    started: u64,
+}
+
+#[derive(Serialize)]
+pub struct TurnState<S> {
+    pub game: S,
+    pub turn: PlayerSide,
+    // Side of the user the state is for
+    pub side: PlayerSide,
+    pub opponent: UserId,
+    pub moves: usize,
+    // Time left for the current turn, None when game is over
+    pub turn_remaining_ms: Option<u64>,
+    pub outcome: Option<Outcome>,
+}
+
+impl<G: TurnBasedGame> Default for TurnMatch<G> {
+    fn default() -> Self {
+        TurnMatch::new(G::default(), DEFAULT_TURN_TIMEOUT)
+    }
+}
+
+impl<G> TurnMatch<G> {
+    pub fn new(game: G, turn_timeout: Duration) -> TurnMatch<G> {
+        TurnMatch {
+            game,
+            turn: PlayerSide::A,
+            turn_started: Instant::now(),
+            turn_timeout,
+            moves: 0,
+            outcome: None,
+            finished: None,
+            started: crate::unix_time(),
+        }
+    }
+
+    fn finish(&mut self, outcome: Outcome) {
+        self.outcome = Some(outcome);
+        self.finished = Some(Instant::now());
+    }
+
+    fn finished_for(&self) -> Option<Duration> {
+        self.finished.map(|finished| finished.elapsed())
+    }
+
+    // Player whose turn is over loses
+    fn check_timer(&mut self) {
+        if self.outcome.is_none() && self.turn_started.elapsed() > self.turn_timeout {
+            self.finish(Outcome::TimedOut(self.turn));
+        }
+    }
+}
+
+impl<G: TurnBasedGame> TurnMatch<G> {
+    pub fn play(&mut self, side: PlayerSide, mv: G::Move) -> Result<(), String> {
+        self.check_timer();
+        if self.outcome.is_some() {
+            return Err("Game is over".to_string());
+        }
+        if side != self.turn {
+            return Err("Not your turn".to_string());
+        }
+        self.game.play(side, mv)?;
+        self.moves += 1;
+        self.turn = self.game.next_turn(side);
+        self.turn_started = Instant::now();
+        if let Some(outcome) = self.game.outcome() {
+            self.finish(outcome);
+        }
+        Ok(())
+    }
+
+    fn state(&self, side: PlayerSide, opponent: UserId) -> TurnState<G::State> {
+        TurnState {
+            game: self.game.state(),
+            turn: self.turn,
+            side,
+            opponent,
+            moves: self.moves,
+            turn_remaining_ms: match self.outcome {
+                Some(_) => None,
+                None => Some(
+                    self.turn_timeout
+                        .saturating_sub(self.turn_started.elapsed())
+                        .as_millis() as u64,
+                ),
+            },
+            outcome: self.outcome,
+        }
+    }
+}
+
+// Matches of one turn-based game
+pub struct TurnMatches<G: TurnBasedGame> {
+    matches: Arc<RwLock<Matches<UserId, TurnMatch<G>>>>,
+    turn_timeout: Duration,
+    quarantine: Quarantine,
+}
+
+impl<G: TurnBasedGame> Clone for TurnMatches<G> {
+    fn clone(&self) -> Self {
+        TurnMatches {
+            matches: self.matches.clone(),
+            turn_timeout: self.turn_timeout,
+            quarantine: self.quarantine.clone(),
+        }
+    }
+}
+
+impl<G: TurnBasedGame> TurnMatches<G> {
+    pub fn new(turn_timeout: Duration, quarantine: Quarantine) -> TurnMatches<G> {
+        TurnMatches {
+            matches: Arc::new(RwLock::new(Matches::new())),
+            turn_timeout,
+            quarantine,
+        }
+    }
+
+    // Turn timeout is configured in seconds with <prefix>turn_timeout key
+    pub fn from_config(figment: &Figment, prefix: &str, quarantine: Quarantine) -> TurnMatches<G> {
+        let turn_timeout = figment
+            .extract_inner::<u64>(&format!("{}turn_timeout", prefix))
+            .map_or(DEFAULT_TURN_TIMEOUT, Duration::from_secs);
+        TurnMatches::new(turn_timeout, quarantine)
+    }
+
+    // Find opponent for the user, returns true when user is in a match. User's finished
+    // match is left for a new one
+    pub fn join(&self, user_id: UserId) -> bool {
+        let mut matches = self.matches.write().unwrap();
+        let finished = matches
+            .iter()
+            .filter(|(_, turn_match)| {
+                turn_match.field.finished_for().is_some_and(|finished_for| {
+                    finished_for > FINISHED_MATCH_TTL
+                        || turn_match.get_player_side(&user_id).is_some()
+                })
+            })
+            .map(|(match_id, _)| match_id)
+            .collect::<Vec<_>>();
+        for match_id in finished {
+            matches.remove_match(match_id);
+        }
+        let turn_timeout = self.turn_timeout;
+        matches.find_match_with(&user_id, || TurnMatch::new(G::default(), turn_timeout))
+    }
+
+    pub fn is_waiting(&self, user_id: UserId) -> bool {
+        let matches = self.matches.read().unwrap();
+        matches.get_player_status(&user_id) == PlayerStatus::WaitList
+    }
+
+    // User is in a match which is not over
+    pub fn is_playing(&self, user_id: UserId) -> bool {
+        let matches = self.matches.read().unwrap();
+        matches
+            .get_match_for_player(&user_id)
+            .is_some_and(|(_, turn_match)| turn_match.field.outcome.is_none())
+    }
+
+    pub fn play(&self, user_id: UserId, mv: G::Move) -> Result<TurnState<G::State>, Error> {
+        let mut matches = self.matches.write().unwrap();
+        let (match_id, turn_match) = matches
+            .get_mut_match_for_player(&user_id)
+            .ok_or_else(|| Error::NotFoundError("Match not found".to_string()))?;
+        let side = turn_match
+            .get_player_side(&user_id)
+            .ok_or_else(|| Error::NotFoundError("Match not found".to_string()))?;
+        let opponent = *turn_match.get_player(side.opponent());
+        let trigger_move = format!("{:?}", mv);
+        let field = &mut turn_match.field;
+        match quarantine::guard(|| field.play(side, mv).map(|_| field.state(side, opponent))) {
+            Ok(played) => played.map_err(Error::InvalidInputError),
+            Err(panic) => {
+                self.quarantine(&mut matches, match_id, panic, Some(trigger_move));
+                Err(Error::NotFoundError("Match was quarantined".to_string()))
+            }
+        }
+    }
+
+    pub fn state(&self, user_id: UserId) -> Option<TurnState<G::State>> {
+        let mut matches = self.matches.write().unwrap();
+        let (match_id, turn_match) = matches.get_mut_match_for_player(&user_id)?;
+        let side = turn_match.get_player_side(&user_id)?;
+        let opponent = *turn_match.get_player(side.opponent());
+        let field = &mut turn_match.field;
+        match quarantine::guard(|| {
+            field.check_timer();
+            field.state(side, opponent)
+        }) {
+            Ok(state) => Some(state),
+            Err(panic) => {
+                self.quarantine(&mut matches, match_id, panic, None);
+                None
+            }
+        }
+    }
+
+    // Whether the user's match was quarantined since the last call
+    pub fn take_quarantined(&self, user_id: UserId) -> bool {
+        self.quarantine.take_affected(user_id)
+    }
+
+    // Remove match which panicked, keeping state of it's game and the failing move
+    fn quarantine(
+        &self,
+        matches: &mut Matches<UserId, TurnMatch<G>>,
+        match_id: MatchId,
+        panic: String,
+        trigger_move: Option<String>,
+    ) {
+        if let Some(turn_match) = matches.get_match(&match_id) {
+            self.quarantine.add(QuarantinedGame::without_replays(
+                G::MODE,
+                vec![turn_match.player_a, turn_match.player_b],
+                turn_match.field.started,
+                || serde_json::to_value(turn_match.field.game.state()).ok(),
+                trigger_move,
+                panic,
+            ));
+        }
+        matches.remove_match(match_id);
+    }
+}
+
+// Join a match and stream it's state. "waiting" event is sent while there is no opponent,
+// state is sent as default event on start and after every move, stream ends with the game.
+// "quarantined" event ends it when the game panicked
+pub fn turn_stream<G: TurnBasedGame + 'static>(
+    matches: TurnMatches<G>,
+    user_id: UserId,
+) -> impl Stream<Item = ChannelEvent> + Send {
+    stream! {
+        let mut interval = interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        if !matches.join(user_id) {
+            yield ChannelEvent::named("waiting", String::new());
+            while !matches.join(user_id) {
+                interval.tick().await;
+            }
+        }
+        let mut sent = None;
+        while let Some(state) = matches.state(user_id) {
+            let version = (state.moves, state.outcome.is_some());
+            if sent != Some(version) {
+                sent = Some(version);
+                yield ChannelEvent::message(serde_json::to_string(&state).unwrap());
+                if state.outcome.is_some() {
+                    break;
+                }
+            }
+            interval.tick().await;
+        }
+        if matches.take_quarantined(user_id) {
+            yield ChannelEvent::named("quarantined", String::new());
+        }
+    }
+}
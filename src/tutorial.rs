@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    get,
+    http::CookieJar,
+    post, routes,
+    serde::json::{serde_json, Json},
+    Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arenas,
+    error::Error,
+    games::{GamePlugin, GameType, SessionStatus},
+    ids::UserId,
+    quarantine::{self, Quarantine, QuarantinedGame, QuarantinedMode},
+    storage::{self, Database},
+    tetris::Action,
+    TetrisMatches,
+};
+
+//
+// Tutorials: lessons of step by step objectives, each step is a sequence of inputs the
+// player has to make. Inputs are checked against the expected action, a wrong one restarts
+// the step. Lessons completed by the user are stored, so tutorial progress is kept
+// across sessions and frontends. Session whose input panics is quarantined with it's state
+//
+
+const PROGRESS_SEGMENT: &str = "tutorial_progress";
+const BY_USER_INDEX: &str = "tutorial_progress_by_user";
+
+pub struct Step {
+    pub objective: &'static str,
+    pub actions: &'static [Action],
+}
+
+pub struct Lesson {
+    pub id: &'static str,
+    pub title: &'static str,
+    pub steps: &'static [Step],
+}
+
+pub const LESSONS: &[Lesson] = &[
+    Lesson {
+        id: "movement",
+        title: "Moving pieces",
+        steps: &[
+            Step {
+                objective: "Move the piece left",
+                actions: &[Action::MoveLeft],
+            },
+            Step {
+                objective: "Move the piece right twice",
+                actions: &[Action::MoveRight, Action::MoveRight],
+            },
+            Step {
+                objective: "Soft drop the piece one row down",
+                actions: &[Action::MoveDown],
+            },
+            Step {
+                objective: "Hard drop the piece",
+                actions: &[Action::Drop],
+            },
+        ],
+    },
+    Lesson {
+        id: "rotation",
+        title: "Rotating pieces",
+        steps: &[
+            Step {
+                objective: "Rotate the piece clockwise",
+                actions: &[Action::RotateRight],
+            },
+            Step {
+                objective: "Rotate the piece counterclockwise",
+                actions: &[Action::RotateLeft],
+            },
+            Step {
+                objective: "Rotate the T piece twice then hard drop",
+                actions: &[Action::RotateRight, Action::RotateRight, Action::Drop],
+            },
+        ],
+    },
+    Lesson {
+        id: "hold",
+        title: "Holding pieces",
+        steps: &[
+            Step {
+                objective: "Hold the piece",
+                actions: &[Action::Hold],
+            },
+            Step {
+                objective: "Hard drop the next piece",
+                actions: &[Action::Drop],
+            },
+            Step {
+                objective: "Swap the held piece back and hard drop it",
+                actions: &[Action::Hold, Action::Drop],
+            },
+        ],
+    },
+];
+
+// Lessons completed by user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TutorialProgress {
+    pub user: UserId,
+    pub completed: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LessonInfo {
+    pub id: &'static str,
+    pub title: &'static str,
+    pub steps: usize,
+    pub completed: bool,
+}
+
+// Result of the last input
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct InputCheck {
+    pub action: Action,
+    pub correct: bool,
+    // Expected action when the input was wrong
+    pub expected: Option<Action>,
+}
+
+#[derive(Serialize)]
+pub struct TutorialState {
+    pub lesson: &'static str,
+    pub title: &'static str,
+    // Index of the current step and number of steps
+    pub step: usize,
+    pub steps: usize,
+    pub objective: Option<&'static str>,
+    // Correct inputs made of the current step's inputs
+    pub progress: usize,
+    pub inputs: usize,
+    pub mistakes: u64,
+    pub last_input: Option<InputCheck>,
+    pub completed: bool,
+}
+
+// Lesson in progress
+pub struct TutorialSession {
+    lesson: &'static Lesson,
+    step: usize,
+    progress: usize,
+    mistakes: u64,
+    last_input: Option<InputCheck>,
+    // Seconds since unix epoch
+    started: u64,
+}
+
+impl TutorialSession {
+    fn new(lesson: &'static Lesson) -> TutorialSession {
+        TutorialSession {
+            lesson,
+            step: 0,
+            progress: 0,
+            mistakes: 0,
+            last_input: None,
+            started: crate::unix_time(),
+        }
+    }
+
+    fn is_completed(&self) -> bool {
+        self.step >= self.lesson.steps.len()
+    }
+
+    // Check the input against the expected action, step is advanced when all it's inputs
+    // are made and restarted on a wrong one
+    fn input(&mut self, action: Action) {
+        let Some(step) = self.lesson.steps.get(self.step) else {
+            return;
+        };
+        let expected = step.actions[self.progress];
+        let correct = action == expected;
+        if correct {
+            self.progress += 1;
+            if self.progress == step.actions.len() {
+                self.step += 1;
+                self.progress = 0;
+            }
+        } else {
+            self.progress = 0;
+            self.mistakes += 1;
+        }
+        self.last_input = Some(InputCheck {
+            action,
+            correct,
+            expected: (!correct).then_some(expected),
+        });
+    }
+
+    fn state(&self) -> TutorialState {
+        let step = self.lesson.steps.get(self.step);
+        TutorialState {
+            lesson: self.lesson.id,
+            title: self.lesson.title,
+            step: self.step,
+            steps: self.lesson.steps.len(),
+            objective: step.map(|step| step.objective),
+            progress: self.progress,
+            inputs: step.map_or(0, |step| step.actions.len()),
+            mistakes: self.mistakes,
+            last_input: self.last_input,
+            completed: self.is_completed(),
+        }
+    }
+}
+
+// Lessons in progress by user and quarantine of sessions which panicked
+#[derive(Clone, Default)]
+pub struct Tutorials(Arc<RwLock<HashMap<UserId, TutorialSession>>>, Quarantine);
+
+impl Tutorials {
+    pub fn new(quarantine: Quarantine) -> Tutorials {
+        Tutorials(Arc::default(), quarantine)
+    }
+
+    pub fn is_running(&self, user_id: UserId) -> bool {
+        self.0.read().unwrap().contains_key(&user_id)
+    }
+
+    // Remove session which panicked, keeping it's state and the failing input
+    fn quarantine(
+        &self,
+        sessions: &mut HashMap<UserId, TutorialSession>,
+        user_id: UserId,
+        panic: String,
+        action: Action,
+    ) {
+        let Some(session) = sessions.remove(&user_id) else {
+            return;
+        };
+        self.1.add_answered(QuarantinedGame::without_replays(
+            QuarantinedMode::Tutorial,
+            vec![user_id],
+            session.started,
+            || serde_json::to_value(session.state()).ok(),
+            Some(format!("{:?}", action)),
+            panic,
+        ));
+    }
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, PROGRESS_SEGMENT)?;
+    storage::ensure_index::<u32, PersyId>(persy, BY_USER_INDEX, ValueMode::Replace)?;
+    Ok(())
+}
+
+fn read_progress(
+    persy: &Persy,
+    user: UserId,
+) -> Result<Option<(PersyId, TutorialProgress)>, Error> {
+    let Some(id) = persy.one::<u32, PersyId>(BY_USER_INDEX, &user.0)? else {
+        return Ok(None);
+    };
+    Ok(storage::read(persy, PROGRESS_SEGMENT, &id)?.map(|progress| (id, progress)))
+}
+
+pub fn completed(persy: &Persy, user: UserId) -> Result<Vec<String>, Error> {
+    Ok(read_progress(persy, user)?
+        .map(|(_, progress)| progress.completed)
+        .unwrap_or_default())
+}
+
+// Add lesson to user's completed ones
+fn store_completed(persy: &Persy, user: UserId, lesson: &str) -> Result<(), Error> {
+    match read_progress(persy, user)? {
+        Some((_, progress)) if progress.completed.iter().any(|id| id == lesson) => Ok(()),
+        Some((id, mut progress)) => {
+            progress.completed.push(lesson.to_string());
+            storage::update(persy, PROGRESS_SEGMENT, &id, &progress)
+        }
+        None => {
+            let progress = TutorialProgress {
+                user,
+                completed: vec![lesson.to_string()],
+            };
+            storage::insert_with(persy, PROGRESS_SEGMENT, &progress, |tx, id| {
+                tx.put(BY_USER_INDEX, user.0, *id)?;
+                Ok(())
+            })?;
+            Ok(())
+        }
+    }
+}
+
+// Lessons with user's completion
+#[get("/tutorial")]
+fn lessons(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    db: &State<Database>,
+) -> Result<Json<Vec<LessonInfo>>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let completed = completed(&db.read(), user_id)?;
+    Ok(Json(
+        LESSONS
+            .iter()
+            .map(|lesson| LessonInfo {
+                id: lesson.id,
+                title: lesson.title,
+                steps: lesson.steps.len(),
+                completed: completed.iter().any(|id| id == lesson.id),
+            })
+            .collect(),
+    ))
+}
+
+// Start the lesson from the first step, lesson in progress is dropped
+#[post("/tutorial/start/<lesson>")]
+fn start(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    tutorials: &State<Tutorials>,
+    lesson: &str,
+) -> Result<Json<TutorialState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let lesson = LESSONS
+        .iter()
+        .find(|known| known.id == lesson)
+        .ok_or_else(|| Error::NotFoundError(format!("Lesson {} not found", lesson)))?;
+    let session = TutorialSession::new(lesson);
+    let state = session.state();
+    tutorials.0.write().unwrap().insert(user_id, session);
+    Ok(Json(state))
+}
+
+// Current step of user's lesson
+#[get("/tutorial/state")]
+fn tutorial_state(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    tutorials: &State<Tutorials>,
+) -> Result<Json<TutorialState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let tutorials = tutorials.0.read().unwrap();
+    let session = tutorials
+        .get(&user_id)
+        .ok_or_else(|| Error::NotFoundError("Lesson not started".to_string()))?;
+    Ok(Json(session.state()))
+}
+
+// Player's input, the same commands as of game input routes. Completed lesson is stored
+// and it's session ends
+#[post("/tutorial/input/<command>")]
+fn input(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    running: &State<Tutorials>,
+    db: &State<Database>,
+    command: &str,
+) -> Result<Json<TutorialState>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let action = arenas::command_action(command)
+        .ok_or_else(|| Error::InvalidInputError(format!("Unknown input {}", command)))?;
+    let mut tutorials = running.0.write().unwrap();
+    let session = tutorials
+        .get_mut(&user_id)
+        .ok_or_else(|| Error::NotFoundError("Lesson not started".to_string()))?;
+    let state = match quarantine::guard(|| {
+        session.input(action);
+        session.state()
+    }) {
+        Ok(state) => state,
+        Err(panic) => {
+            running.quarantine(&mut tutorials, user_id, panic, action);
+            return Err(Error::NotFoundError("Lesson was quarantined".to_string()));
+        }
+    };
+    if state.completed {
+        let lesson = state.lesson;
+        tutorials.remove(&user_id);
+        drop(tutorials);
+        store_completed(&db.read(), user_id, lesson)?;
+    }
+    Ok(Json(state))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![lessons, start, tutorial_state, input]
+}
+
+pub fn plugin() -> GamePlugin {
+    GamePlugin {
+        game_type: GameType {
+            id: "tutorial",
+            name: "Tutorial",
+            description: "Learn the controls step by step",
+            entry: "/tutorial",
+        },
+        init,
+        routes,
+        attach: |rocket| {
+            let quarantine = rocket.state::<Quarantine>().cloned().unwrap_or_default();
+            Ok(rocket.manage(Tutorials::new(quarantine)))
+        },
+        session: |rocket, user_id| {
+            let tutorials = rocket.state::<Tutorials>()?;
+            SessionStatus::of(false, tutorials.is_running(user_id))
+        },
+        state: |rocket, user_id| {
+            let tutorials = rocket.state::<Tutorials>()?.0.read().unwrap();
+            Some(serde_json::to_string(&tutorials.get(&user_id)?.state()).unwrap())
+        },
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Instant;
+
+use persy::{Persy, PersyId, ValueMode};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    get, routes,
+    serde::json::Json,
+    Request, Response, Route, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    storage::{self, Database},
+};
+
+//
+// Server version and uptime. Git commit and build time are embedded by build.rs. Each
+// start is stored with it's build, so /version shows what ran before the current start,
+// e.g. to see when an upgrade was deployed. Responses carry X-Server-Version header
+// to match client reports with the server build
+//
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+
+const STARTS_SEGMENT: &str = "server_starts";
+const BY_TIME_INDEX: &str = "server_starts_by_time";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStart {
+    pub version: String,
+    pub commit: String,
+    // Seconds since unix epoch
+    pub started: u64,
+}
+
+#[derive(Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    // Build time, seconds since unix epoch
+    pub built: u64,
+    pub started: u64,
+    pub uptime_seconds: u64,
+    // Start before the current one
+    pub previous: Option<ServerStart>,
+}
+
+// Start of running server
+pub struct Uptime {
+    started: u64,
+    instant: Instant,
+    previous: Option<ServerStart>,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, STARTS_SEGMENT)?;
+    storage::ensure_index::<u64, PersyId>(persy, BY_TIME_INDEX, ValueMode::Cluster)?;
+    Ok(())
+}
+
+pub fn built() -> u64 {
+    env!("BUILD_TIMESTAMP").parse().unwrap_or(0)
+}
+
+// Version with commit, as sent in X-Server-Version header
+pub fn full_version() -> String {
+    format!("{}+{}", VERSION, GIT_COMMIT)
+}
+
+impl Uptime {
+    // Store start of the server, last stored start is the previous one
+    pub fn start(db: &Database) -> Result<Uptime, Error> {
+        let persy = db.read();
+        let previous = match persy
+            .range::<u64, PersyId, _>(BY_TIME_INDEX, ..)?
+            .next_back()
+            .and_then(|(_, mut ids)| ids.next())
+        {
+            Some(id) => storage::read(&persy, STARTS_SEGMENT, &id)?,
+            None => None,
+        };
+        let start = ServerStart {
+            version: VERSION.to_string(),
+            commit: GIT_COMMIT.to_string(),
+            started: crate::unix_time(),
+        };
+        storage::insert_with(&persy, STARTS_SEGMENT, &start, |tx, id| {
+            tx.put(BY_TIME_INDEX, start.started, *id)?;
+            Ok(())
+        })?;
+        println!("Server version {}, built {}", full_version(), built());
+        Ok(Uptime {
+            started: start.started,
+            instant: Instant::now(),
+            previous,
+        })
+    }
+
+    pub fn info(&self) -> VersionInfo {
+        VersionInfo {
+            version: VERSION,
+            commit: GIT_COMMIT,
+            built: built(),
+            started: self.started,
+            uptime_seconds: self.instant.elapsed().as_secs(),
+            previous: self.previous.clone(),
+        }
+    }
+}
+
+// Adds X-Server-Version header to every response
+pub struct VersionHeader;
+
+#[rocket::async_trait]
+impl Fairing for VersionHeader {
+    fn info(&self) -> Info {
+        Info {
+            name: "Server version header",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_raw_header("X-Server-Version", full_version());
+    }
+}
+
+// Crate version, git commit, build time and uptime
+#[get("/version")]
+fn version(uptime: &State<Uptime>) -> Json<VersionInfo> {
+    Json(uptime.info())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![version]
+}
@@ -0,0 +1,78 @@
+use rocket::{get, routes, serde::json::Json, Config, Route, State};
+
+use crate::{
+    error::Error, ids::UserId, roles::Viewer, tetris::TetrisGameState,
+    tetris_pair::TetrisPairState, TetrisMatches,
+};
+
+//
+// Views of game state. The same state is projected for each subscriber: players see their
+// own game fully and the opponent's without engine internals, spectators may have upcoming
+// pieces hidden, by their choice or for all of them with spectator_hide_queue config key,
+// admins see engine internals too. States are projected before they leave the server
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum View {
+    Player,
+    Spectator { hide_queue: bool },
+    Admin,
+}
+
+impl View {
+    // Spectator view, queue is hidden when asked or when the server hides it for everyone
+    pub fn spectator(hide_queue: bool) -> View {
+        let forced = Config::figment()
+            .extract_inner::<bool>("spectator_hide_queue")
+            .unwrap_or(false);
+        View::Spectator {
+            hide_queue: hide_queue || forced,
+        }
+    }
+}
+
+pub trait Projection {
+    fn project(self, view: View) -> Self;
+}
+
+impl Projection for TetrisGameState {
+    fn project(self, view: View) -> TetrisGameState {
+        match view {
+            View::Player | View::Spectator { hide_queue: false } => self.without_internals(),
+            View::Spectator { hide_queue: true } => self.without_internals().without_queue(),
+            View::Admin => self,
+        }
+    }
+}
+
+// Player sees opponent's game as a spectator who doesn't hide queues
+impl Projection for TetrisPairState {
+    fn project(self, view: View) -> TetrisPairState {
+        let opponent_view = match view {
+            View::Player => View::Spectator { hide_queue: false },
+            view => view,
+        };
+        TetrisPairState {
+            player: self.player.project(view),
+            opponent: self.opponent.project(opponent_view),
+            ..self
+        }
+    }
+}
+
+// Full state of user's versus match
+#[get("/admin/games/<user_id>")]
+fn admin_game_state(
+    _admin: Viewer,
+    matches: &State<TetrisMatches>,
+    user_id: UserId,
+) -> Result<Json<TetrisPairState>, Error> {
+    let state = matches
+        .game_state(user_id)
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    Ok(Json(state.project(View::Admin)))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_game_state]
+}
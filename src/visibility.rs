@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use rocket::{
+    form::Form, http::CookieJar, post, routes, serde::json::Json, FromForm, Route, State,
+};
+
+use crate::{arenas::Arenas, error::Error, ids::UserId, sprint::TetrisSprints, TetrisMatches};
+
+//
+// Pauses on hidden browser tab. Client reports visibility of the game page, while it's
+// hidden the game is paused within limits per game: sprint timer stops and versus player
+// doesn't become AFK (opponent's game goes on). Pauses over the limits are ignored,
+// pause exceeding total pause time ends by itself
+//
+
+// Pauses per game and total time of them
+const MAX_PAUSES: u32 = 3;
+const MAX_PAUSE_TIME: Duration = Duration::from_secs(60);
+
+struct PauseState {
+    // Game the pauses are counted for, start time of it
+    game: u64,
+    pauses: u32,
+    // Time of finished pauses and start of the current one
+    paused_for: Duration,
+    paused_since: Option<Instant>,
+}
+
+impl PauseState {
+    fn new(game: u64) -> PauseState {
+        PauseState {
+            game,
+            pauses: 0,
+            paused_for: Duration::ZERO,
+            paused_since: None,
+        }
+    }
+
+    // End current pause if total pause time is over
+    fn expire(&mut self) {
+        if let Some(since) = self.paused_since {
+            if self.paused_for + since.elapsed() >= MAX_PAUSE_TIME {
+                self.paused_since = None;
+                self.paused_for = MAX_PAUSE_TIME;
+            }
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Some(since) = self.paused_since.take() {
+            self.paused_for = (self.paused_for + since.elapsed()).min(MAX_PAUSE_TIME);
+        }
+    }
+
+    fn status(&self) -> PauseStatus {
+        let paused_for = self.paused_for
+            + self
+                .paused_since
+                .map_or(Duration::ZERO, |since| since.elapsed());
+        PauseStatus {
+            paused: self.paused_since.is_some(),
+            pauses_left: MAX_PAUSES.saturating_sub(self.pauses),
+            pause_secs_left: MAX_PAUSE_TIME.saturating_sub(paused_for).as_secs(),
+        }
+    }
+}
+
+pub use gameserver_protocol::game::PauseStatus;
+
+#[derive(FromForm)]
+pub struct VisibilityForm {
+    hidden: bool,
+}
+
+// Pause states by user id
+#[derive(Clone, Default)]
+pub struct Pauses(Arc<RwLock<HashMap<UserId, PauseState>>>);
+
+impl Pauses {
+    pub fn new() -> Pauses {
+        Pauses::default()
+    }
+
+    // Apply reported visibility of user's game
+    fn set_hidden(&self, user: UserId, game: u64, hidden: bool) -> PauseStatus {
+        let mut pauses = self.0.write().unwrap();
+        let state = pauses.entry(user).or_insert_with(|| PauseState::new(game));
+        if state.game != game {
+            *state = PauseState::new(game);
+        }
+        state.expire();
+        if !hidden {
+            state.resume();
+        } else if state.paused_since.is_none()
+            && state.pauses < MAX_PAUSES
+            && state.paused_for < MAX_PAUSE_TIME
+        {
+            state.pauses += 1;
+            state.paused_since = Some(Instant::now());
+        }
+        state.status()
+    }
+
+    // Whether user's game is paused now
+    pub fn is_paused(&self, user: UserId, game: u64) -> bool {
+        let mut pauses = self.0.write().unwrap();
+        let Some(state) = pauses.get_mut(&user).filter(|state| state.game == game) else {
+            return false;
+        };
+        state.expire();
+        state.paused_since.is_some()
+    }
+
+    pub fn status(&self, user: UserId, game: u64) -> Option<PauseStatus> {
+        let pauses = self.0.read().unwrap();
+        pauses
+            .get(&user)
+            .filter(|state| state.game == game)
+            .map(PauseState::status)
+    }
+}
+
+// Report visibility of the game page. Applies to user's versus match or, if there is none,
+// to user's sprint
+#[post("/game/visibility", data = "<form>")]
+fn visibility(
+    cookie_jar: &CookieJar,
+    matches: &State<TetrisMatches>,
+    sprints: &State<TetrisSprints>,
+    pauses: &State<Pauses>,
+    form: Form<VisibilityForm>,
+) -> Result<Json<PauseStatus>, Error> {
+    let user_id = crate::user_id(cookie_jar, matches);
+    let game = matches
+        .started(user_id)
+        .or_else(|| sprints.started(user_id))
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    Ok(Json(pauses.set_hidden(user_id, game, form.hidden)))
+}
+
+// Visibility of the game page of arena's versus match
+#[post("/arena/<name>/game/visibility", data = "<form>")]
+fn arena_visibility(
+    cookie_jar: &CookieJar,
+    arenas: &State<Arenas>,
+    pauses: &State<Pauses>,
+    name: &str,
+    form: Form<VisibilityForm>,
+) -> Result<Json<PauseStatus>, Error> {
+    let arena = arenas.get(name)?;
+    let user_id = crate::user_id(cookie_jar, &arena.matches);
+    let game = arena
+        .matches
+        .started(user_id)
+        .ok_or_else(|| Error::NotFoundError("Game not found".to_string()))?;
+    Ok(Json(pauses.set_hidden(user_id, game, form.hidden)))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![visibility, arena_visibility]
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::time::Instant;
+
+use persy::Persy;
+use rocket::{serde::json::serde_json, tokio, Config};
+
+use crate::{
+    access::AccessTimes,
+    cache::ResponseCache,
+    error::Error,
+    game_history,
+    game_mode::GameMode,
+    ids::UserId,
+    leaderboard::{self, LeaderboardQuery},
+    splits, sprint,
+    storage::Database,
+};
+
+//
+// Cold start warmup. After a deploy the first requests of returning players would all read
+// their records from disk. Warmup runs alongside Rocket launch and reads records of the most
+// recently active players, as the first requests of their games do: results, personal
+// bests with their replays and splits, replaced games. Players are ordered by stored access
+// times, or by their latest results when no times are stored yet. Database cache keeps
+// them in memory afterwards. Default leaderboard page is rendered into the response cache
+// as well
+//
+
+// Recently active users preloaded, unless configured with warmup_users. 0 disables warmup
+const WARMUP_USERS: usize = 100;
+// Recent results scanned for active users, per preloaded user
+const RESULTS_PER_USER: usize = 10;
+// Cache key of default leaderboard page, uri of the request
+const LEADERBOARD_KEY: &str = "/leaderboard";
+
+// Users of the latest results, most recent first
+fn recent_users(persy: &Persy, users: usize) -> Result<Vec<UserId>, Error> {
+    let mut seen = HashSet::new();
+    Ok(leaderboard::recent(persy, users * RESULTS_PER_USER)?
+        .into_iter()
+        .map(|(_, entry)| entry.user)
+        .filter(|user| seen.insert(*user))
+        .take(users)
+        .collect())
+}
+
+fn preload_user(persy: &Persy, user: UserId) -> Result<(), Error> {
+    let entries = leaderboard::entries_of_user(persy, user)?;
+    let difficulties: HashSet<_> = entries
+        .iter()
+        .filter(|(_, entry)| entry.mode == GameMode::Sprint)
+        .map(|(_, entry)| entry.difficulty.unwrap_or_default())
+        .collect();
+    for difficulty in difficulties {
+        sprint::personal_best(persy, user, difficulty)?;
+        splits::personal_best(persy, user, GameMode::Sprint, difficulty, None)?;
+    }
+    game_history::games_of_user(persy, user)?;
+    Ok(())
+}
+
+fn warmup(
+    persy: &Persy,
+    cache: &ResponseCache,
+    access: &AccessTimes,
+    users: usize,
+) -> Result<usize, Error> {
+    cache.get_or_insert_with(LEADERBOARD_KEY, || {
+        let query = LeaderboardQuery {
+            mode: None,
+            difficulty: None,
+            verification: None,
+            sort: None,
+            order: None,
+            from: None,
+            to: None,
+            country: None,
+            friends: None,
+            cursor: None,
+            limit: None,
+        };
+        Ok(serde_json::to_string(&leaderboard::list(persy, &query)?)?)
+    })?;
+    let mut recent = access.recent(users);
+    if recent.is_empty() {
+        recent = recent_users(persy, users)?;
+    }
+    let users = recent;
+    for user in &users {
+        preload_user(persy, *user)?;
+    }
+    Ok(users.len())
+}
+
+// Background job started before launch, so it doesn't delay serving requests
+pub async fn warmup_job(db: Database, cache: ResponseCache, access: AccessTimes) {
+    let users = Config::figment()
+        .extract_inner::<usize>("warmup_users")
+        .unwrap_or(WARMUP_USERS);
+    if users == 0 {
+        return;
+    }
+    let started = Instant::now();
+    match tokio::task::spawn_blocking(move || warmup(&db.read(), &cache, &access, users)).await {
+        Ok(Ok(users)) => println!(
+            "Warmup: {} recently active users preloaded in {} ms",
+            users,
+            started.elapsed().as_millis()
+        ),
+        Ok(Err(e)) => println!("Warmup failed: {}", e),
+        Err(e) => println!("Warmup task failed: {}", e),
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use persy::{Persy, PersyId};
+use rand::Rng;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    form::Form,
+    get, post,
+    response::Redirect,
+    routes,
+    serde::json::{serde_json, Value},
+    tokio::{self, sync::mpsc},
+    Config, FromForm, FromFormField, Request, Response, Route, State,
+};
+use rocket_dyn_templates::{context, Template};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    error::Error,
+    roles::{Operator, Viewer},
+    storage::{self, Database},
+};
+
+//
+// Webhooks registered by admins for server events. Event payload is POSTed as json
+// to every webhook registered for the event type by a background worker, failed deliveries
+// are retried with exponential backoff. Payload is signed with webhook's secret:
+// X-Webhook-Signature header is "sha256=" and hex HMAC-SHA256 of "<timestamp>.<body>",
+// where timestamp is X-Webhook-Timestamp header, so receivers can check origin and age
+//
+
+const WEBHOOKS_SEGMENT: &str = "webhooks";
+
+// Delivery attempts of one event, first retry after RETRY_DELAY, doubled after each one
+const MAX_ATTEMPTS: u32 = 6;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Server errors within ERROR_WINDOW seconds reported as spike, unless configured
+// with webhook_error_threshold
+const ERROR_WINDOW: u64 = 60;
+const ERROR_THRESHOLD: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+pub enum WebhookEvent {
+    // Verified game with the best score of it's mode and difficulty
+    HighScore,
+    // Server error responses over the threshold within a minute
+    ErrorRateSpike,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    // Key of payload signatures
+    pub secret: String,
+    // Registration time, seconds since unix epoch
+    pub created: u64,
+}
+
+// Deliveries since server start
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeliveryStats {
+    pub delivered: u64,
+    pub failed: u64,
+    pub last_error: Option<String>,
+}
+
+// Webhook for admin page
+#[derive(Serialize)]
+pub struct WebhookItem {
+    pub id: String,
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub stats: DeliveryStats,
+}
+
+#[derive(FromForm)]
+pub struct WebhookForm {
+    url: String,
+    events: Vec<WebhookEvent>,
+}
+
+#[derive(Serialize)]
+struct Payload {
+    event: WebhookEvent,
+    // Event time, seconds since unix epoch
+    time: u64,
+    data: Value,
+}
+
+struct Delivery {
+    event: WebhookEvent,
+    body: String,
+}
+
+// Server errors of current window
+struct ErrorWindow {
+    start: u64,
+    errors: u64,
+}
+
+// Handle to emit webhook events
+#[derive(Clone)]
+pub struct Webhooks {
+    sender: mpsc::UnboundedSender<Delivery>,
+    stats: Arc<Mutex<HashMap<PersyId, DeliveryStats>>>,
+    errors: Arc<Mutex<ErrorWindow>>,
+    error_threshold: u64,
+}
+
+pub fn init(persy: &Persy) -> Result<(), Error> {
+    storage::ensure_segment(persy, WEBHOOKS_SEGMENT)
+}
+
+fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", signature)
+}
+
+fn new_secret() -> String {
+    rand::thread_rng()
+        .gen::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Webhooks {
+    // Start delivery worker
+    pub fn start(db: Database) -> Result<Webhooks, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let webhooks = Webhooks {
+            sender,
+            stats: Arc::new(Mutex::new(HashMap::new())),
+            errors: Arc::new(Mutex::new(ErrorWindow {
+                start: crate::unix_time(),
+                errors: 0,
+            })),
+            error_threshold: Config::figment()
+                .extract_inner("webhook_error_threshold")
+                .unwrap_or(ERROR_THRESHOLD),
+        };
+        // Not a lifecycle job, the worker ends with the last handle. Events emitted during
+        // shutdown are still delivered while the process runs
+        tokio::spawn(Self::worker(db, client, receiver, webhooks.stats.clone()));
+        Ok(webhooks)
+    }
+
+    // Queue event for delivery to webhooks registered for it
+    pub fn emit<T: Serialize>(&self, event: WebhookEvent, data: &T) {
+        let payload = serde_json::to_value(data).and_then(|data| {
+            serde_json::to_string(&Payload {
+                event,
+                time: crate::unix_time(),
+                data,
+            })
+        });
+        match payload {
+            Ok(body) => {
+                let _ = self.sender.send(Delivery { event, body });
+            }
+            Err(e) => println!("Webhook payload of {:?} failed: {}", event, e),
+        }
+    }
+
+    fn stats(&self, id: &PersyId) -> DeliveryStats {
+        self.stats
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    async fn worker(
+        db: Database,
+        client: reqwest::Client,
+        mut receiver: mpsc::UnboundedReceiver<Delivery>,
+        stats: Arc<Mutex<HashMap<PersyId, DeliveryStats>>>,
+    ) {
+        while let Some(delivery) = receiver.recv().await {
+            let worker_db = db.clone();
+            let webhooks = match tokio::task::spawn_blocking(move || {
+                storage::scan::<Webhook>(&worker_db.read(), WEBHOOKS_SEGMENT)
+            })
+            .await
+            {
+                Ok(Ok(webhooks)) => webhooks,
+                Ok(Err(e)) => {
+                    println!("Reading webhooks failed: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    println!("Reading webhooks task failed: {}", e);
+                    continue;
+                }
+            };
+            for (id, webhook) in webhooks {
+                if webhook.events.contains(&delivery.event) {
+                    // Not a lifecycle job, delivery ends within REQUEST_TIMEOUT
+                    tokio::spawn(deliver(
+                        client.clone(),
+                        id,
+                        webhook,
+                        delivery.event,
+                        delivery.body.clone(),
+                        stats.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    // Count server error, emits spike event when the window's errors reach the threshold
+    fn server_error(&self) {
+        let now = crate::unix_time();
+        let mut window = self.errors.lock().unwrap();
+        if now >= window.start + ERROR_WINDOW {
+            window.start = now;
+            window.errors = 0;
+        }
+        window.errors += 1;
+        if window.errors == self.error_threshold {
+            self.emit(
+                WebhookEvent::ErrorRateSpike,
+                &serde_json::json!({ "errors": window.errors, "window": ERROR_WINDOW }),
+            );
+        }
+    }
+}
+
+// POST payload until it's accepted with 2xx status or attempts are exhausted
+async fn deliver(
+    client: reqwest::Client,
+    id: PersyId,
+    webhook: Webhook,
+    event: WebhookEvent,
+    body: String,
+    stats: Arc<Mutex<HashMap<PersyId, DeliveryStats>>>,
+) {
+    let mut delay = RETRY_DELAY;
+    let mut error = String::new();
+    for attempt in 1..=MAX_ATTEMPTS {
+        let timestamp = crate::unix_time();
+        let result = client
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Event", format!("{:?}", event))
+            .header("X-Webhook-Timestamp", timestamp)
+            .header(
+                "X-Webhook-Signature",
+                sign(&webhook.secret, timestamp, &body),
+            )
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                stats.lock().unwrap().entry(id).or_default().delivered += 1;
+                return;
+            }
+            Ok(response) => error = format!("Status {}", response.status()),
+            Err(e) => error = e.to_string(),
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    println!(
+        "Webhook {:?} delivery to {} failed: {}",
+        event, webhook.url, error
+    );
+    let mut stats = stats.lock().unwrap();
+    let stats = stats.entry(id).or_default();
+    stats.failed += 1;
+    stats.last_error = Some(error);
+}
+
+// Count server error responses for spike events
+#[rocket::async_trait]
+impl Fairing for Webhooks {
+    fn info(&self) -> Info {
+        Info {
+            name: "Webhook error rate",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
+        if response.status().code >= 500 {
+            self.server_error();
+        }
+    }
+}
+
+// Registered webhooks with their deliveries
+#[get("/admin/webhooks")]
+fn admin_webhooks(
+    _admin: Viewer,
+    db: &State<Database>,
+    webhooks: &State<Webhooks>,
+) -> Result<Template, Error> {
+    let items: Vec<WebhookItem> = storage::scan::<Webhook>(&db.read(), WEBHOOKS_SEGMENT)?
+        .into_iter()
+        .map(|(id, webhook)| WebhookItem {
+            id: id.to_string(),
+            stats: webhooks.stats(&id),
+            webhook,
+        })
+        .collect();
+    Ok(Template::render(
+        "admin/webhooks",
+        context! { webhooks: items },
+    ))
+}
+
+// Register webhook for event types, it's secret is generated
+#[post("/admin/webhooks", data = "<form>")]
+fn register_webhook(
+    _admin: Operator,
+    db: &State<Database>,
+    form: Form<WebhookForm>,
+) -> Result<Redirect, Error> {
+    let form = form.into_inner();
+    if !form.url.starts_with("http://") && !form.url.starts_with("https://") {
+        return Err(Error::InvalidInputError(
+            "Webhook url must be http(s) url".to_string(),
+        ));
+    }
+    if form.events.is_empty() {
+        return Err(Error::InvalidInputError(
+            "Webhook needs at least one event type".to_string(),
+        ));
+    }
+    let webhook = Webhook {
+        url: form.url,
+        events: form.events,
+        secret: new_secret(),
+        created: crate::unix_time(),
+    };
+    storage::insert(&db.read(), WEBHOOKS_SEGMENT, &webhook)?;
+    Ok(Redirect::to("/admin/webhooks"))
+}
+
+#[post("/admin/webhooks/<id>/delete")]
+fn delete_webhook(_admin: Operator, db: &State<Database>, id: &str) -> Result<Redirect, Error> {
+    let id = storage::parse_id(id)?;
+    let persy = &*db.read();
+    if storage::read::<Webhook>(persy, WEBHOOKS_SEGMENT, &id)?.is_none() {
+        return Err(Error::NotFoundError("Webhook not found".to_string()));
+    }
+    let mut tx = persy.begin()?;
+    tx.delete(WEBHOOKS_SEGMENT, &id)?;
+    tx.prepare()?.commit()?;
+    Ok(Redirect::to("/admin/webhooks"))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![admin_webhooks, register_webhook, delete_webhook]
+}
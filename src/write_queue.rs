@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+
+use persy::{Persy, PersyId, Transaction};
+use rocket::tokio::{
+    self,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    error::Error,
+    leaderboard::{self, LeaderboardEntry},
+    match_history::{self, MatchRecord},
+    metrics::GameMetrics,
+    quotas::Quotas,
+    replays::{self, ReplayVerifier},
+    rng_audit::RngAudit,
+    storage::Database,
+    tetris::Replay,
+};
+
+//
+// Write-behind queue. Handlers enqueue writes instead of performing them, dedicated task
+// writes them in batches, one Persy transaction per batch. Queue is bounded, handlers wait
+// when it's full. Queue is flushed on shutdown, see lifecycle; writes still queued when the
+// process crashes are lost. Replay quotas of the players are enforced after each batch.
+// Queued games are observed by game metrics, see metrics
+//
+
+const QUEUE_CAPACITY: usize = 1024;
+// Maximal number of writes in one transaction
+const MAX_BATCH: usize = 64;
+
+#[derive(Clone)]
+pub enum Write {
+    // Result of finished game with it's replay
+    Game {
+        entry: LeaderboardEntry,
+        replay: Replay,
+    },
+    // Completed versus match with games of both players and RNG audits of the games
+    Match {
+        record: MatchRecord,
+        games: Vec<(LeaderboardEntry, Replay)>,
+        audits: Vec<RngAudit>,
+    },
+}
+
+enum Message {
+    Write(Box<Write>),
+    // Reply when all writes queued before are done
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Clone)]
+pub struct WriteQueue(mpsc::Sender<Message>, GameMetrics);
+
+// Perform write, returns ids of written leaderboard entries
+fn apply(tx: &mut Transaction, write: Write) -> Result<Vec<PersyId>, Error> {
+    match write {
+        Write::Game { entry, replay } => Ok(vec![replays::record_game(tx, entry, &replay)?.entry]),
+        Write::Match {
+            record,
+            games,
+            audits,
+        } => match_history::record_in_tx(tx, record, games, audits),
+    }
+}
+
+fn apply_batch(persy: &Persy, writes: Vec<Write>) -> Result<Vec<PersyId>, Error> {
+    let mut tx = persy.begin()?;
+    let ids = writes
+        .into_iter()
+        .map(|write| apply(&mut tx, write))
+        .collect::<Result<Vec<_>, _>>()?;
+    tx.prepare()?.commit()?;
+    Ok(ids.into_iter().flatten().collect())
+}
+
+// Write batch in one transaction. If it fails, writes are retried one by one,
+// so a single bad write doesn't discard the whole batch
+fn write_batch(persy: &Persy, writes: Vec<Write>) -> Vec<PersyId> {
+    match apply_batch(persy, writes.clone()) {
+        Ok(ids) => ids,
+        Err(e) => {
+            println!("Batch of {} writes failed: {}", writes.len(), e);
+            writes
+                .into_iter()
+                .filter_map(|write| match apply_batch(persy, vec![write]) {
+                    Ok(ids) => Some(ids),
+                    Err(e) => {
+                        println!("Write failed: {}", e);
+                        None
+                    }
+                })
+                .flatten()
+                .collect()
+        }
+    }
+}
+
+// Prune replays of players of the written entries over their quota
+fn enforce_quotas(persy: &Persy, quotas: &Quotas, ids: &[PersyId]) {
+    let mut users = HashSet::new();
+    for id in ids {
+        match leaderboard::read(persy, id) {
+            Ok(Some(entry)) => {
+                users.insert(entry.user);
+            }
+            Ok(None) => {}
+            Err(e) => println!("Reading written entry failed: {}", e),
+        }
+    }
+    for user in users {
+        if let Err(e) = quotas.enforce(persy, user) {
+            println!("Enforcing quota of user {} failed: {}", user, e);
+        }
+    }
+}
+
+impl WriteQueue {
+    pub fn start(db: Database, verifier: ReplayVerifier, metrics: GameMetrics) -> WriteQueue {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        // Not a lifecycle job, which would be aborted before the stop action: the queue is
+        // flushed through the worker, it ends with the last handle
+        tokio::spawn(Self::worker(db, receiver, verifier));
+        WriteQueue(sender, metrics)
+    }
+
+    // Queue write, waits while the queue is full. Finished games are observed by metrics
+    pub async fn push(&self, write: Write) {
+        self.1.observe(&write);
+        if self.0.send(Message::Write(Box::new(write))).await.is_err() {
+            println!("Write queue is closed, write is lost");
+        }
+    }
+
+    // Wait until all writes queued so far are done
+    pub async fn flush(&self) {
+        let (sender, receiver) = oneshot::channel();
+        if self.0.send(Message::Flush(sender)).await.is_ok() {
+            let _ = receiver.await;
+        }
+    }
+
+    async fn worker(db: Database, mut receiver: mpsc::Receiver<Message>, verifier: ReplayVerifier) {
+        let quotas = Quotas::from_config();
+        while let Some(message) = receiver.recv().await {
+            // Collect batch from messages already waiting in the queue
+            let mut writes = Vec::new();
+            let mut flushes = Vec::new();
+            let mut next = Some(message);
+            while let Some(message) = next.take() {
+                match message {
+                    Message::Write(write) => writes.push(*write),
+                    Message::Flush(reply) => flushes.push(reply),
+                }
+                if writes.len() < MAX_BATCH {
+                    next = receiver.try_recv().ok();
+                }
+            }
+            if !writes.is_empty() {
+                let worker_db = db.clone();
+                let write = move || {
+                    let persy = worker_db.read();
+                    let ids = write_batch(&persy, writes);
+                    enforce_quotas(&persy, &quotas, &ids);
+                    ids
+                };
+                match tokio::task::spawn_blocking(write).await {
+                    Ok(ids) => ids.into_iter().for_each(|id| verifier.entry_recorded(id)),
+                    Err(e) => println!("Write task failed: {}", e),
+                }
+            }
+            for reply in flushes {
+                let _ = reply.send(());
+            }
+        }
+    }
+}
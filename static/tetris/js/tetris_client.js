@@ -16,6 +16,8 @@ class TetrisDisplay {
     rows;
     field = null;
     preview = null;
+    // Pending garbage attacks, oldest first
+    incoming = [];
 
     // Contructor accepts canvas
     constructor(canvas, rows, cols) {
@@ -27,11 +29,12 @@ class TetrisDisplay {
         this.rows = rows;
     }
 
-    update(data) {
+    update(data, incoming = this.incoming) {
         this.cols = data.cols;
         this.rows = data.rows;
         this.field = data.field;
         this.preview = data.preview;
+        this.incoming = incoming || [];
         this.draw();
     }
 
@@ -104,6 +107,14 @@ class TetrisDisplay {
         ctx.fillRect(offsetX + internalWidth + cellSize, 0, cellSize, (rows + 1) * cellSize);
         // draw bottom wall
         ctx.fillRect(offsetX + cellSize, offsetY + rows * cellSize, internalWidth, cellSize);
+        // draw pending garbage meter on the left wall, attacks which are due are brighter
+        let meterY = rows * cellSize;
+        for (const attack of this.incoming) {
+            const height = Math.min(attack.lines * cellSize, meterY);
+            meterY -= height;
+            ctx.fillStyle = attack.arrives_in_ms === 0 ? '#ff0000' : '#ff9999';
+            ctx.fillRect(offsetX + 2, meterY, cellSize - 4, height);
+        }
 
         for (let row = 0; row < rows; row++) {
             for (let col = 0; col < cols; col++) {
@@ -119,6 +130,14 @@ class TetrisClient {
 
     url;
     sse;
+    // Input epoch of current stream and last used input sequence number
+    epoch = null;
+    seq = 0;
+    // Sent inputs not yet included in a received state, with versions of acknowledged ones.
+    // Predicting client replays them on top of the last received state
+    pending = [];
+    // Version of the last received state, inputs are predicted on top of it
+    version = 0;
     display_player;
     display_opponent;
 
@@ -134,38 +153,83 @@ class TetrisClient {
         this.sse = new EventSource(this.url + '/sse');
         this.sse.addEventListener('message', (event) => {
             var data = JSON.parse(event.data);
-            this.display_player.update(data.player);
-            this.display_opponent.update(data.opponent);
+            // State includes inputs acknowledged at lower versions
+            this.pending = this.pending.filter((input) => input.version === null || input.version >= data.player.version);
+            this.version = data.player.version;
+            this.display_player.update(data.player, data.incoming);
+            this.display_opponent.update(data.opponent, data.opponent_incoming);
+        });
+        // Inputs are numbered from 1 in each epoch
+        this.sse.addEventListener('input_epoch', (event) => {
+            this.epoch = event.data;
+            this.seq = 0;
+            this.pending = [];
+        });
+        this.sse.addEventListener('ack', (event) => {
+            const ack = JSON.parse(event.data);
+            const input = this.pending.find((input) => input.seq === ack.seq);
+            if (input) {
+                input.version = ack.version;
+            }
+        });
+        // Rejected input is not in the game, state it was predicted on is shown instead
+        this.sse.addEventListener('correction', (event) => {
+            const correction = JSON.parse(event.data);
+            this.pending = this.pending.filter((input) => input.seq !== correction.seq);
+            this.display_player.update(correction.state);
+        });
+        // Answer pings, so server can measure latency
+        this.sse.addEventListener('ping', (event) => {
+            window.fetch(this.url + '/pong/' + event.data, { method: 'POST' });
         });
+        // Report hidden page, so the game is paused within server limits
+        document.addEventListener('visibilitychange', () => {
+            const body = new URLSearchParams({ hidden: document.hidden });
+            window.fetch(this.url + '/game/visibility', { method: 'POST', body: body });
+        });
+    }
+
+    // Send input command with it's sequence number
+    sendInput(command) {
+        if (this.epoch === null) {
+            return;
+        }
+        this.seq += 1;
+        // Refused inputs are never acknowledged, only recent ones are kept
+        if (this.pending.length >= 64) {
+            this.pending.shift();
+        }
+        this.pending.push({ seq: this.seq, command: command, version: null });
+        window.fetch(this.url + '/' + command + '?epoch=' + this.epoch + '&seq=' + this.seq + '&version=' + this.version, { method: 'POST' });
     }
 
-    // Commands to send to server (to be implemented later)
+    // Commands to send to server
     down() {
-        window.fetch(this.url + '/down', { method: 'POST' });
+        this.sendInput('down');
     }
 
     moveLeft() {
-        window.fetch(this.url + '/left', { method: 'POST' });
+        this.sendInput('left');
     }
 
     moveRight() {
-        window.fetch(this.url + '/right', { method: 'POST' });
+        this.sendInput('right');
     }
 
     rotateLeft() {
-        window.fetch(this.url + '/rotate_left', { method: 'POST' });
+        this.sendInput('rotate_left');
     }
 
     rotateRight() {
-        window.fetch(this.url + '/rotate_right', { method: 'POST' });
+        this.sendInput('rotate_right');
     }
 
     drop() {
-        window.fetch(this.url + '/drop', { method: 'POST' });
+        this.sendInput('drop');
     }
 
     bottom_refill() {
-        window.fetch(this.url + '/bottom_refill', { method: 'POST' });
+        this.sendInput('bottom_refill');
     }
 
     bindButtons(left_id, rotate_left_id, down_id, rotate_right_id, right_id) {